@@ -0,0 +1,338 @@
+//! A built-in SoundFont synthesizer, for keyboards without speakers. It can play the loaded song as it scrolls by,
+//! sound the notes played on the MIDI input, or both, through any `.sf2` file.
+
+use std::{fs::{self, File}, io::BufReader, path::Path, sync::Arc, thread};
+
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::{DetectChanges, DetectChangesMut}, event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Local, Res, ResMut}, world::World}};
+use cpal::{traits::{DeviceTrait, HostTrait, StreamTrait}, FromSample, SampleFormat, SizedSample};
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use rustysynth::{SoundFont, Synthesizer, SynthesizerSettings};
+
+use crate::{diagnostics::profiling::profile_scope, midi::{bus::{NoteBus, NoteSource}, MidiEventKind}, song::{playback::{self, SongPlayback}, Song}, startup::{StartupProgress, StartupTask}, status::{AppError, ErrorSource}};
+
+/// The General MIDI percussion channel, and the wood blocks clicked on the first and the other beats of a count-in.
+const PERCUSSION_CHANNEL: u8 = 9;
+const DOWNBEAT_CLICK: u8 = 76;
+const BEAT_CLICK: u8 = 77;
+
+/// The directory listed when choosing a soundfont in the settings panel.
+pub static SOUNDFONT_DIR: &str = "assets/soundfonts";
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct SynthSettings {
+    pub enabled: bool,
+    /// The path of the `.sf2` file to play with. Changing it reloads the synthesizer.
+    pub soundfont: Option<String>,
+    /// Play the loaded song's notes as playback reaches them.
+    pub play_song: bool,
+    /// Play the notes received on the MIDI input.
+    pub play_live_input: bool,
+    /// The master volume, from 0 to 1.
+    pub volume: f32,
+    /// The General MIDI program (0-127) for each MIDI channel. Mapped channels ignore program changes from the
+    /// keyboard; unmapped ones start on program 0 and follow them.
+    pub programs: [Option<u8>; 16]
+}
+
+impl Default for SynthSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            soundfont: None,
+            play_song: true,
+            play_live_input: true,
+            volume: 0.5,
+            programs: [None; 16]
+        }
+    }
+}
+
+/// Messages from the app to the audio callback, which owns the synthesizer.
+enum SynthCommand {
+    Midi { channel: u8, kind: MidiEventKind },
+    AllNotesOff,
+    Volume(f32)
+}
+
+/// The sending end of the running synthesizer. The audio stream itself is a non-send resource, since cpal streams
+/// can't move between threads on every platform.
+#[derive(Resource, Default)]
+pub struct SynthOutput {
+    /// The soundfont the running output was started with, so unrelated settings changes don't reload it.
+    soundfont: Option<String>,
+    /// The soundfont being parsed on another thread, which can take seconds for large files.
+    loading: Option<(String, Receiver<Result<Arc<SoundFont>, String>>)>,
+    commands: Option<Sender<SynthCommand>>
+}
+
+impl SynthOutput {
+    pub fn is_running(&self) -> bool {
+        self.commands.is_some()
+    }
+
+    fn send(&self, command: SynthCommand) {
+        if let Some(commands) = &self.commands {
+            // The receiver only disconnects when the stream is dropped
+            let _ = commands.send(command);
+        }
+    }
+
+    fn send_midi(&self, channel: u8, kind: MidiEventKind) {
+        self.send(SynthCommand::Midi { channel, kind });
+    }
+}
+
+/// Returns the `.sf2` files in `SOUNDFONT_DIR`.
+pub fn available_soundfonts() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(SOUNDFONT_DIR) else { return Vec::new() };
+    let mut soundfonts: Vec<String> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("sf2")))
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    soundfonts.sort();
+    soundfonts
+}
+
+fn apply_command(synth: &mut Synthesizer, command: SynthCommand) {
+    match command {
+        SynthCommand::Midi { channel, kind } => {
+            let channel = channel as i32 & 15;
+            match kind {
+                MidiEventKind::NoteOn { key, velocity } => synth.note_on(channel, key as i32, velocity as i32),
+                MidiEventKind::NoteOff { key } => synth.note_off(channel, key as i32),
+                MidiEventKind::ControlChange { controller, value } => synth.process_midi_message(channel, 0xB0, controller as i32, value as i32),
+                MidiEventKind::ProgramChange { program } => synth.process_midi_message(channel, 0xC0, program as i32, 0)
+            }
+        }
+        SynthCommand::AllNotesOff => synth.note_off_all(false),
+        SynthCommand::Volume(volume) => synth.set_master_volume(volume)
+    }
+}
+
+fn build_output_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, mut synth: Synthesizer, commands: Receiver<SynthCommand>) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>
+{
+    let channels = config.channels.max(1) as usize;
+    let mut left = Vec::new();
+    let mut right = Vec::new();
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            for command in commands.try_iter() {
+                apply_command(&mut synth, command);
+            }
+
+            let frames = data.len() / channels;
+            left.resize(frames, 0.0);
+            right.resize(frames, 0.0);
+            synth.render(&mut left, &mut right);
+
+            for (index, frame) in data.chunks_mut(channels).enumerate() {
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    let value = match (channels, channel % 2) {
+                        (1, _) => (left[index] + right[index]) / 2.0,
+                        (_, 0) => left[index],
+                        _ => right[index]
+                    };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        |err| eprintln!("Audio output error: {}", err),
+        None
+    )
+}
+
+/// Parses a soundfont on another thread. If the thread can't be started, the sender is dropped and the receiver reports
+/// the load as failed.
+fn load_soundfont(path: &str) -> Receiver<Result<Arc<SoundFont>, String>> {
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let path = path.to_string();
+    let _ = thread::Builder::new()
+        .name("soundfont loading".to_string())
+        .spawn(move || {
+            profile_scope!("load soundfont");
+            let loaded = File::open(&path).map_err(|err| err.to_string())
+                .and_then(|file| SoundFont::new(&mut BufReader::new(file)).map(Arc::new).map_err(|err| err.to_string()));
+            let _ = sender.send(loaded);
+        });
+    receiver
+}
+
+/// Starts playing a loaded soundfont through the default audio output.
+fn start_synth(soundfont_path: &str, soundfont: &Arc<SoundFont>, commands: Receiver<SynthCommand>) -> Result<cpal::Stream, Box<dyn std::error::Error>> {
+    let device = cpal::default_host().default_output_device().ok_or("No audio output device found")?;
+    let supported_config = device.default_output_config()?;
+    let config = supported_config.config();
+    let synth = Synthesizer::new(soundfont, &SynthesizerSettings::new(config.sample_rate.0 as i32))?;
+
+    let stream = match supported_config.sample_format() {
+        SampleFormat::F32 => build_output_stream::<f32>(&device, &config, synth, commands)?,
+        SampleFormat::I16 => build_output_stream::<i16>(&device, &config, synth, commands)?,
+        SampleFormat::U16 => build_output_stream::<u16>(&device, &config, synth, commands)?,
+        format => return Err(format!("Unsupported audio output format {}", format).into())
+    };
+    stream.play()?;
+
+    let name = Path::new(soundfont_path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    println!("Playing through {} on {}", name, device.name().unwrap_or_default());
+    Ok(stream)
+}
+
+/// Starts or stops the synthesizer when it's toggled or given a different soundfont,
+/// and sends the volume and program mapping whenever the settings change.
+fn update_synth(
+    mut commands: Commands,
+    settings: Res<SynthSettings>,
+    mut output: ResMut<SynthOutput>,
+    mut progress: ResMut<StartupProgress>,
+    mut errors: EventWriter<AppError>
+) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    if settings.enabled && settings.soundfont.is_none() {
+        errors.write(AppError::new(ErrorSource::Audio, format!("Choose a soundfont to use the synthesizer. Put .sf2 files in {}", SOUNDFONT_DIR)));
+    }
+    let soundfont = settings.soundfont.clone().filter(|_| settings.enabled);
+    if soundfont != output.soundfont {
+        // Dropping an earlier load's receiver makes it finish unused
+        output.loading = soundfont.as_ref().map(|path| (path.clone(), load_soundfont(path)));
+        if output.loading.is_some() {
+            progress.begin(StartupTask::Soundfont);
+        }
+        output.soundfont = soundfont;
+        output.commands = None;
+        commands.queue(|world: &mut World| {
+            world.remove_non_send_resource::<cpal::Stream>();
+        });
+        return;
+    }
+
+    output.send(SynthCommand::Volume(settings.volume.clamp(0.0, 1.0)));
+    for (channel, program) in settings.programs.iter().enumerate() {
+        if let Some(program) = program {
+            output.send_midi(channel as u8, MidiEventKind::ProgramChange { program: program & 127 });
+        }
+    }
+    // Notes sounding when either source was turned off would otherwise never be released
+    if !settings.play_live_input || !settings.play_song {
+        output.send(SynthCommand::AllNotesOff);
+    }
+}
+
+/// Starts the audio output once the soundfont has been parsed. The stream is created here on the main thread, since it
+/// can't move between threads.
+fn start_loaded_synth(
+    mut commands: Commands,
+    mut output: ResMut<SynthOutput>,
+    mut progress: ResMut<StartupProgress>,
+    mut errors: EventWriter<AppError>
+) {
+    let Some((path, receiver)) = &output.loading else {
+        // Also covers a load abandoned by turning the synthesizer off
+        progress.finish(StartupTask::Soundfont);
+        return;
+    };
+    let loaded = match receiver.try_recv() {
+        Ok(loaded) => loaded,
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => Err("The soundfont loading thread stopped without loading it".to_string())
+    };
+    let path = path.clone();
+    output.loading = None;
+    progress.finish(StartupTask::Soundfont);
+
+    let soundfont = match loaded {
+        Ok(soundfont) => soundfont,
+        Err(err) => {
+            errors.write(AppError::new(ErrorSource::Audio, format!("Failed to start the synthesizer with {}: {}", path, err)));
+            return;
+        }
+    };
+    commands.queue(move |world: &mut World| {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        match start_synth(&path, &soundfont, receiver) {
+            Ok(stream) => {
+                world.insert_non_send_resource(stream);
+                world.resource_mut::<SynthOutput>().commands = Some(sender);
+                // Send the volume and programs now that there's something to receive them
+                world.resource_mut::<SynthSettings>().set_changed();
+            }
+            Err(err) => {
+                world.send_event(AppError::new(ErrorSource::Audio, format!("Failed to start the synthesizer with {}: {}", path, err)));
+            }
+        }
+    });
+}
+
+/// Plays the song notes that playback passed over this frame, and releases the ones that ended.
+fn play_song(
+    settings: Res<SynthSettings>,
+    output: Res<SynthOutput>,
+    bus: Res<NoteBus>
+) {
+    if !settings.play_song || !output.is_running() {
+        return;
+    }
+
+    for event in bus.from_source(NoteSource::Song) {
+        output.send_midi(event.channel, event.kind);
+    }
+}
+
+/// Clicks each beat of the count-in before a loop starts over.
+fn play_count_in(
+    settings: Res<SynthSettings>,
+    output: Res<SynthOutput>,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    mut last_beat: Local<Option<u32>>
+) {
+    let beat = playback.count_in_beat(&song).filter(|_| settings.play_song && output.is_running());
+    if beat == *last_beat {
+        return;
+    }
+
+    let click = |beat: u32| if beat == 0 { DOWNBEAT_CLICK } else { BEAT_CLICK };
+    if let Some(previous) = *last_beat {
+        output.send_midi(PERCUSSION_CHANNEL, MidiEventKind::NoteOff { key: click(previous) });
+    }
+    if let Some(beat) = beat {
+        output.send_midi(PERCUSSION_CHANNEL, MidiEventKind::NoteOn { key: click(beat), velocity: 100 });
+    }
+    *last_beat = beat;
+}
+
+fn play_live_input(
+    settings: Res<SynthSettings>,
+    output: Res<SynthOutput>,
+    bus: Res<NoteBus>
+) {
+    if !settings.play_live_input || !output.is_running() {
+        return;
+    }
+
+    for event in bus.from_source(NoteSource::Live) {
+        let mapped = settings.programs[event.channel as usize & 15].is_some();
+        if mapped && matches!(event.kind, MidiEventKind::ProgramChange { .. }) {
+            continue;
+        }
+        output.send_midi(event.channel, event.kind);
+    }
+}
+
+pub struct SynthPlugin;
+
+impl Plugin for SynthPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<SynthSettings>()
+            .init_resource::<SynthOutput>()
+            .init_resource::<StartupProgress>()
+            .add_systems(Update, (update_synth, start_loaded_synth, play_song, play_count_in, play_live_input).chain().after(playback::publish_song_notes));
+    }
+}
@@ -4,20 +4,32 @@ use bevy::asset::RenderAssetUsages;
 use bevy::image::TextureFormatPixelInfo;
 use bevy::{core_pipeline, prelude::*};
 use bevy::render::extract_resource::{ExtractResource, ExtractResourcePlugin};
+use bevy::ecs::system::SystemParam;
 use bevy::render::render_graph::{Node, RenderGraph, RenderLabel, RenderSubGraph};
 use bevy::render::render_graph::{NodeRunError, RenderGraphContext, SlotInfo};
 use bevy::render::render_resource::{
-    AddressMode, BindGroup, BindGroupEntries, BindGroupLayoutEntry, BindingType, BlendComponent, BlendState, ColorTargetState, ColorWrites, Extent3d, Face, FilterMode, FrontFace, MultisampleState, PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology, RawFragmentState, RawRenderPipelineDescriptor, RawVertexState, RenderPassDescriptor, RenderPipeline, SamplerBindingType, SamplerDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, TexelCopyBufferLayout, TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureViewDescriptor, TextureViewDimension
+    AddressMode, BindGroup, BindGroupEntries, BindGroupLayoutEntry, BindingType, BlendComponent, BufferBindingType, BufferInitDescriptor, BufferUsages, BlendState, ColorTargetState, ColorWrites, Extent3d, Face, FilterMode, FrontFace, MultisampleState, PipelineLayoutDescriptor, PolygonMode, PrimitiveState, PrimitiveTopology, RawFragmentState, RawRenderPipelineDescriptor, RawVertexState, RenderPassDescriptor, RenderPipeline, SamplerBindingType, SamplerDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, TexelCopyBufferLayout, TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureViewDescriptor, TextureViewDimension
 };
 use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
 use bevy::render::view::{ExtractedView, ViewTarget};
 use bevy::render::RenderApp;
-use opencv::core::{AlgorithmHint, Mat, MatTraitConst, MatTraitConstManual};
-use opencv::imgproc;
+use opencv::core::{AlgorithmHint, Mat, MatTraitConst, MatTraitConstManual, Point2d, Point3d, Vector};
+use opencv::{calib3d, imgproc};
 
+use crate::camera_cuts::ShotCamera;
+use crate::diagnostics::{memory::{MemoryCategory, MemoryTracker}, profiling::profile_scope};
+use crate::keyboard::{KeyboardLayout, KeyboardPlane};
+use crate::video::{aruco_camera::{ArucoTrackingData, CameraIntrinsics, FiducialLayout}, gpu_prefilter::UploadedFrame};
 use crate::video::WebcamFrame;
 use crate::VideoDrawSystems;
 
+pub mod framing;
+pub mod light_estimation;
+pub mod occlusion;
+pub mod replacement;
+pub mod style;
+pub mod undistort;
+
 #[derive(Resource, Default)]
 pub struct ConvertedWebcamFrame(pub Mat);
 
@@ -28,10 +40,18 @@ pub struct BackgroundImage(pub Image);
 pub struct BackgroundGraph;
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub(crate) struct BackgroundNodeLabel;
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub(crate) struct HandOcclusionNodeLabel;
+
+/// The bind group for the current frame's background texture, shared with the hand occlusion pass.
+#[derive(Resource, Clone)]
+struct BackgroundBindGroup(BindGroup);
 
 #[derive(Resource)]
 pub struct BackgroundPipeline {
     render_pipeline: RenderPipeline,
+    /// Redraws the masked hands over the virtual content.
+    occlusion_pipeline: RenderPipeline,
 }
 
 impl FromWorld for BackgroundPipeline {
@@ -67,6 +87,16 @@ impl FromWorld for BackgroundPipeline {
                     ty: BindingType::Sampler(SamplerBindingType::Filtering),
                     count: None,
                 },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         );
 
@@ -76,8 +106,8 @@ impl FromWorld for BackgroundPipeline {
             push_constant_ranges: &[],
         });
 
-        let render_pipeline = device.create_render_pipeline(&RawRenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
+        let create_pipeline = |label: &'static str, entry_point: &'static str, blend: BlendState| device.create_render_pipeline(&RawRenderPipelineDescriptor {
+            label: Some(label),
             layout: Some(&render_pipeline_layout),
             vertex: RawVertexState {
                 module: &shader,
@@ -87,13 +117,10 @@ impl FromWorld for BackgroundPipeline {
             },
             fragment: Some(RawFragmentState {
                 module: &shader,
-                entry_point: Some("fs_main"),
+                entry_point: Some(entry_point),
                 targets: &[Some(ColorTargetState {
                     format: TextureFormat::bevy_default(),
-                    blend: Some(BlendState {
-                        color: BlendComponent::REPLACE,
-                        alpha: BlendComponent::REPLACE,
-                    }),
+                    blend: Some(blend),
                     write_mask: ColorWrites::ALL,
                 })],
                 compilation_options: Default::default(),
@@ -122,7 +149,13 @@ impl FromWorld for BackgroundPipeline {
             cache: None,
         });
 
-        Self { render_pipeline }
+        let render_pipeline = create_pipeline("Render Pipeline", "fs_main", BlendState {
+            color: BlendComponent::REPLACE,
+            alpha: BlendComponent::REPLACE,
+        });
+        let occlusion_pipeline = create_pipeline("Hand Occlusion Pipeline", "fs_hand_occlusion", BlendState::ALPHA_BLENDING);
+
+        Self { render_pipeline, occlusion_pipeline }
     }
 }
 
@@ -142,7 +175,7 @@ impl Node for BackgroundPassDriverNode {
 }
 
 pub struct BackgroundNode {
-    query: QueryState<&'static ViewTarget, With<ExtractedView>>,
+    query: QueryState<&'static ViewTarget, (With<ExtractedView>, Without<ShotCamera>)>,
     diffuse_bind_group: Option<BindGroup>,
 }
 
@@ -163,6 +196,7 @@ impl Node for BackgroundNode {
     fn update(&mut self, world: &mut World) {
         self.query.update_archetypes(world);
         if let Some(img) = world.get_resource::<BackgroundImage>() {
+            profile_scope!("upload");
             let device = world.get_resource::<RenderDevice>().unwrap();
             let queue = world.get_resource::<RenderQueue>().unwrap();
 
@@ -182,6 +216,9 @@ impl Node for BackgroundNode {
                 view_formats: &[],
             });
             let format_size = img.texture_descriptor.format.pixel_size();
+            if let Some(tracker) = world.get_resource::<MemoryTracker>() {
+                tracker.set(MemoryCategory::GpuTextures, (size.width * size.height) as u64 * 4);
+            }
             queue.write_texture(
                 texture.as_image_copy(),
                 img.data.as_ref().expect("Image has no data"),
@@ -223,26 +260,47 @@ impl Node for BackgroundNode {
                         ty: BindingType::Sampler(SamplerBindingType::Filtering),
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
             );
 
+            let style = world.get_resource::<style::BackgroundStyle>().map(|style| style.0).unwrap_or_default();
+            let style_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("background_style_buffer"),
+                contents: bytemuck::bytes_of(&style),
+                usage: BufferUsages::UNIFORM,
+            });
+
             let diffuse_bind_group = device.create_bind_group(
                 Some("diffuse_bind_group"),
                 &texture_bind_group_layout,
-                &BindGroupEntries::sequential((&view, &sampler)),
+                &BindGroupEntries::sequential((&view, &sampler, style_buffer.as_entire_binding())),
             );
 
+            world.insert_resource(BackgroundBindGroup(diffuse_bind_group.clone()));
+            world.insert_resource(UploadedFrame { view, width: size.width, height: size.height });
             self.diffuse_bind_group = Some(diffuse_bind_group);
         }
     }
 
     fn run(
         &self,
-        _graph: &mut RenderGraphContext,
+        graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), NodeRunError> {
-        for target in self.query.iter_manual(world) {
+        // Only draw into the view this graph is running for; with several cameras, drawing into the others
+        // would overwrite what they've already rendered.
+        if let Ok(target) = self.query.get_manual(world, graph.view_entity()) {
             let pipeline = world.get_resource::<BackgroundPipeline>().unwrap();
             let pass_descriptor = RenderPassDescriptor {
                 label: Some("background_pass"),
@@ -266,11 +324,76 @@ impl Node for BackgroundNode {
     }
 }
 
+/// Draws the camera pixels of the player's hands back over the virtual content, using the mask in the background image's alpha.
+pub struct HandOcclusionNode {
+    query: QueryState<&'static ViewTarget, (With<ExtractedView>, Without<ShotCamera>)>,
+}
+
+impl HandOcclusionNode {
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for HandOcclusionNode {
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        if !world.get_resource::<occlusion::HandOcclusionSettings>().is_some_and(|settings| settings.enabled) {
+            return Ok(());
+        }
+        let Some(bind_group) = world.get_resource::<BackgroundBindGroup>() else {
+            return Ok(());
+        };
+
+        if let Ok(target) = self.query.get_manual(world, graph.view_entity()) {
+            let pipeline = world.get_resource::<BackgroundPipeline>().unwrap();
+            let pass_descriptor = RenderPassDescriptor {
+                label: Some("hand_occlusion_pass"),
+                color_attachments: &[Some(target.get_color_attachment())],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            };
+
+            let mut render_pass = render_context
+                .command_encoder()
+                .begin_render_pass(&pass_descriptor);
+
+            render_pass.set_pipeline(&pipeline.occlusion_pipeline);
+
+            render_pass.set_bind_group(0, &bind_group.0, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle_background_image(
     mut image: ResMut<BackgroundImage>,
     mut webcam_frame: ResMut<WebcamFrame>,
-    mut converted_webcam_frame: ResMut<ConvertedWebcamFrame>
+    mut converted_webcam_frame: ResMut<ConvertedWebcamFrame>,
+    mut replacement: ResMut<replacement::BackgroundReplacement>,
+    replacement_settings: Res<replacement::BackgroundReplacementSettings>,
+    mut occlusion: ResMut<occlusion::HandOcclusion>,
+    occlusion_settings: Res<occlusion::HandOcclusionSettings>,
+    mut undistortion: ResMut<undistort::Undistortion>,
+    undistort_settings: Res<undistort::UndistortSettings>,
+    intrinsics: Option<Res<CameraIntrinsics>>,
+    keyboard_projection: KeyboardProjection
 ) {
+    profile_scope!("convert");
     // Retrieve the latest frame from the webcam
     let frame = &mut webcam_frame.0;
     let converted_frame = &mut converted_webcam_frame.0;
@@ -280,6 +403,23 @@ pub fn handle_background_image(
         return;
     }
 
+    if let Err(err) = replacement.apply(&replacement_settings, &keyboard_projection, frame, converted_frame) {
+        eprintln!("Failed to replace the background: {}", err);
+    }
+
+    // After replacement, so the backdrop can't overwrite the mask
+    if let Err(err) = occlusion.apply(&occlusion_settings, &keyboard_projection, frame, converted_frame) {
+        eprintln!("Failed to mask the player's hands: {}", err);
+    }
+
+    // Last, since replacement and the hand mask work in the raw camera image like detection does
+    if intrinsics.as_ref().is_some_and(DetectChanges::is_changed) {
+        undistortion.recalibrate();
+    }
+    if let Err(err) = undistortion.apply(&undistort_settings, intrinsics.as_deref(), converted_frame) {
+        eprintln!("Failed to undistort the background: {}", err);
+    }
+
     // Get image dimensions
     let (width, height) = (converted_frame.cols() as u32, converted_frame.rows() as u32);
 
@@ -302,6 +442,77 @@ pub fn handle_background_image(
     image.0 = Image::new(size, dimensions, data, format, asset_usage);
 }
 
+/// Everything needed to project the keyboard into the camera image.
+#[derive(SystemParam)]
+pub struct KeyboardProjection<'w> {
+    tracking_data: Res<'w, ArucoTrackingData>,
+    intrinsics: Option<Res<'w, CameraIntrinsics>>,
+    keyboard_layout: Res<'w, KeyboardLayout>,
+    keyboard_plane: Res<'w, KeyboardPlane>,
+    fiducial_layout: Res<'w, FiducialLayout>,
+    undistort_settings: Res<'w, undistort::UndistortSettings>
+}
+
+impl KeyboardProjection<'_> {
+    /// Projects the keyboard and fiducial strip into the camera image using the latest pose.
+    /// Returns `None` if no pose has been solved yet.
+    pub fn image_region(&self) -> opencv::Result<Option<Vector<Point2d>>> {
+        self.project(self.keyboard_corners(), true)
+    }
+
+    /// Like `image_region`, but in the background image as displayed, which may have been undistorted.
+    pub fn displayed_region(&self) -> opencv::Result<Option<Vector<Point2d>>> {
+        let distorted = !self.undistort_settings.active(self.intrinsics.as_deref());
+        self.project(self.keyboard_corners(), distorted)
+    }
+
+    fn keyboard_corners(&self) -> [Vec3; 4] {
+        let half_width = self.keyboard_layout.width() / 2.0;
+        let back = -self.fiducial_layout.size as f32 / 2.0;
+        let front = self.keyboard_layout.front_z();
+        [
+            Vec3::new(-half_width, 0.0, back),
+            Vec3::new(half_width, 0.0, back),
+            Vec3::new(half_width, 0.0, front),
+            Vec3::new(-half_width, 0.0, front)
+        ]
+    }
+
+    /// Projects the top of a single key into the camera image, like `image_region`.
+    pub fn key_region(&self, key: u8) -> opencv::Result<Option<Vector<Point2d>>> {
+        let center = self.keyboard_layout.key_center(key);
+        let (width, length) = self.keyboard_layout.key_size(key);
+        let (half_width, half_length) = (width / 2.0, length / 2.0);
+        self.project([
+            center + Vec3::new(-half_width, 0.0, -half_length),
+            center + Vec3::new(half_width, 0.0, -half_length),
+            center + Vec3::new(half_width, 0.0, half_length),
+            center + Vec3::new(-half_width, 0.0, half_length)
+        ], true)
+    }
+
+    /// Projects points in the keyboard's frame into the camera image, either the raw one or with its lens distortion removed.
+    fn project(&self, points: [Vec3; 4], distorted: bool) -> opencv::Result<Option<Vector<Point2d>>> {
+        let Some(intrinsics) = &self.intrinsics else { return Ok(None) };
+        if self.tracking_data.last_pose_time().is_none() {
+            return Ok(None);
+        }
+        let (rotation, translation) = self.tracking_data.latest_pose();
+        let rotation = Mat::from_slice(&rotation)?.try_clone()?;
+        let translation = Mat::from_slice(&translation)?.try_clone()?;
+
+        let points: Vector<Point3d> = points.into_iter()
+            .map(|point| self.keyboard_plane.to_world(point))
+            .map(|point| Point3d::new(point.x as f64, point.y as f64, point.z as f64))
+            .collect();
+
+        let mut projected: Vector<Point2d> = Vector::new();
+        let no_distortion = Mat::default();
+        let dist_coeffs = if distorted { &intrinsics.dist_coeffs } else { &no_distortion };
+        calib3d::project_points_def(&points, &rotation, &translation, &intrinsics.camera_matrix, dist_coeffs, &mut projected)?;
+        Ok(Some(projected))
+    }
+}
 
 pub struct CameraBackground;
 
@@ -311,13 +522,40 @@ impl Plugin for CameraBackground {
             .insert_resource(ClearColor(Color::NONE))
             .insert_resource(BackgroundImage(Image::default()))
             .insert_resource(ConvertedWebcamFrame(Mat::default()))
-            .add_plugins(ExtractResourcePlugin::<BackgroundImage>::default())
-            .add_systems(Update, handle_background_image.in_set(VideoDrawSystems));
-
-        let render_app = app.sub_app_mut(RenderApp);
+            .init_resource::<replacement::BackgroundReplacementSettings>()
+            .init_resource::<replacement::BackgroundReplacement>()
+            .init_resource::<style::BackgroundStyleSettings>()
+            .init_resource::<style::BackgroundStyle>()
+            .init_resource::<framing::AutoFramingSettings>()
+            .init_resource::<framing::AutoFraming>()
+            .init_resource::<occlusion::HandOcclusionSettings>()
+            .init_resource::<occlusion::HandOcclusion>()
+            .init_resource::<undistort::UndistortSettings>()
+            .init_resource::<undistort::Undistortion>()
+            .init_resource::<light_estimation::LightEstimationSettings>()
+            .init_resource::<light_estimation::LightEstimator>()
+            .add_plugins((
+                ExtractResourcePlugin::<BackgroundImage>::default(),
+                ExtractResourcePlugin::<style::BackgroundStyle>::default(),
+                ExtractResourcePlugin::<occlusion::HandOcclusionSettings>::default()
+            ))
+            .add_systems(Update, (
+                (handle_background_image, framing::update_auto_framing, style::update_background_style).chain(),
+                light_estimation::estimate_lighting
+            ).in_set(VideoDrawSystems));
+
+        // Share the memory tracker so texture uploads in the render world are counted
+        let memory_tracker = app.world_mut().get_resource_or_init::<MemoryTracker>().clone();
+
+        // There's no render app when running headless
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.insert_resource(memory_tracker);
 
         let background_node_2d = BackgroundNode::new(render_app.world_mut());
         let background_node_3d = BackgroundNode::new(render_app.world_mut());
+        let hand_occlusion_node = HandOcclusionNode::new(render_app.world_mut());
         let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
 
         if let Some(graph_2d) = render_graph.get_sub_graph_mut(core_pipeline::core_2d::graph::Core2d) {
@@ -336,11 +574,21 @@ impl Plugin for CameraBackground {
                 BackgroundNodeLabel,
                 core_pipeline::core_3d::graph::Node3d::MainTransparentPass,
             );
+
+            // Hands are drawn over everything in the main passes, but before post-processing
+            graph_3d.add_node(HandOcclusionNodeLabel, hand_occlusion_node);
+            graph_3d.add_node_edges((
+                core_pipeline::core_3d::graph::Node3d::MainTransparentPass,
+                HandOcclusionNodeLabel,
+                core_pipeline::core_3d::graph::Node3d::EndMainPass,
+            ));
         }
     }
 
     fn finish(&self, app: &mut App) {
-        let render_app = app.sub_app_mut(RenderApp);
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
         render_app.init_resource::<BackgroundPipeline>();
     }
 }
\ No newline at end of file
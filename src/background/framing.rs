@@ -0,0 +1,195 @@
+//! How the camera feed is framed in each output: fitted to the output's aspect ratio with `FitMode`, and optionally
+//! auto-framed, digitally cropping and zooming so the tracked keyboard fills the output wherever the phone happens to be
+//! mounted. The overlay cameras get a matching sub-view so AR content stays aligned.
+
+use bevy::{ecs::{query::Without, resource::Resource, system::{Query, Res, ResMut}}, math::{UVec2, Vec2}, render::camera::{Camera, SubCameraView}, time::Time};
+use opencv::core::MatTraitConst;
+use serde::Deserialize;
+
+use crate::{background::{ConvertedWebcamFrame, KeyboardProjection}, camera_cuts::ShotCamera, render_layers::OutputCamera, video::tracking::TrackingState};
+
+/// The sub-view is specified in integer pixels, so use a finer grid than the frame to avoid visible stepping while zooming.
+const SUB_VIEW_RESOLUTION_SCALE: f32 = 8.0;
+
+/// How the camera frame is fitted to an output with a different aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FitMode {
+    /// Stretch the frame to fill the output.
+    #[default]
+    Fill,
+    /// Show the whole frame, with black bars on the sides or top and bottom.
+    Fit,
+    /// Fill the output without stretching, cutting off the edges of the frame.
+    Crop
+}
+
+impl FitMode {
+    pub const ALL: [FitMode; 3] = [FitMode::Fill, FitMode::Fit, FitMode::Crop];
+
+    /// Must match the FIT_ constants in backgroundShader.wgsl.
+    pub(crate) fn shader_index(&self) -> u32 {
+        match self {
+            FitMode::Fill => 0,
+            FitMode::Fit => 1,
+            FitMode::Crop => 2
+        }
+    }
+
+    /// The part of the image shown across the output, as (x, y, width, height) in texture coordinates. It reaches
+    /// outside the image when fitting, and the background shader mirrors this.
+    pub fn rect(&self, image_aspect: f32, output_aspect: f32) -> [f32; 4] {
+        let wider = output_aspect > image_aspect;
+        let (horizontal, vertical) = match (self, wider) {
+            (FitMode::Fill, _) => (1.0, 1.0),
+            (FitMode::Fit, true) | (FitMode::Crop, false) => (output_aspect / image_aspect, 1.0),
+            (FitMode::Fit, false) | (FitMode::Crop, true) => (1.0, image_aspect / output_aspect)
+        };
+        [(1.0 - horizontal) / 2.0, (1.0 - vertical) / 2.0, horizontal, vertical]
+    }
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct AutoFramingSettings {
+    /// How the (cropped) frame is fitted to each output.
+    pub fit: FitMode,
+    pub enabled: bool,
+    /// Extra space around the keyboard, as a fraction of its size on each side.
+    pub margin: f32,
+    pub max_zoom: f32,
+    /// How quickly the framing follows the keyboard. Higher is faster.
+    pub smoothing: f32
+}
+
+impl Default for AutoFramingSettings {
+    fn default() -> Self {
+        Self {
+            fit: FitMode::Fill,
+            enabled: false,
+            margin: 0.08,
+            max_zoom: 3.0,
+            smoothing: 3.0
+        }
+    }
+}
+
+/// The current crop, smoothed toward the keyboard's bounds.
+#[derive(Resource)]
+pub struct AutoFraming {
+    /// The center of the crop in texture coordinates.
+    pub center: Vec2,
+    /// 1 shows the full frame; 2 shows half the width and height.
+    pub zoom: f32
+}
+
+impl Default for AutoFraming {
+    fn default() -> Self {
+        Self {
+            center: Vec2::splat(0.5),
+            zoom: 1.0
+        }
+    }
+}
+
+impl AutoFraming {
+    /// The crop as (x, y, width, height) in texture coordinates.
+    pub fn crop(&self) -> [f32; 4] {
+        let size = 1.0 / self.zoom;
+        [self.center.x - size / 2.0, self.center.y - size / 2.0, size, size]
+    }
+
+    /// Computes the crop that fits the given keyboard bounds, in texture coordinates.
+    fn target(settings: &AutoFramingSettings, min: Vec2, max: Vec2) -> (Vec2, f32) {
+        let bounds = (max - min) * (1.0 + 2.0 * settings.margin);
+        let zoom = (1.0 / bounds.max_element().max(0.001)).clamp(1.0, settings.max_zoom.max(1.0));
+        // Keep the crop inside the frame
+        let half_size = 0.5 / zoom;
+        let center = ((min + max) / 2.0).clamp(Vec2::splat(half_size), Vec2::splat(1.0 - half_size));
+        (center, zoom)
+    }
+}
+
+pub fn update_auto_framing(
+    time: Res<Time>,
+    settings: Res<AutoFramingSettings>,
+    tracking_state: Res<TrackingState>,
+    converted_webcam_frame: Res<ConvertedWebcamFrame>,
+    keyboard_projection: KeyboardProjection,
+    mut framing: ResMut<AutoFraming>,
+    mut cameras: Query<(&OutputCamera, &mut Camera), Without<ShotCamera>>
+) {
+    let frame = &converted_webcam_frame.0;
+    let frame_size = Vec2::new(frame.cols().max(1) as f32, frame.rows().max(1) as f32);
+
+    // Hold the current framing while tracking is lost rather than zooming somewhere stale
+    let target = if !settings.enabled {
+        Some((Vec2::splat(0.5), 1.0))
+    } else if *tracking_state == TrackingState::Lost {
+        None
+    } else {
+        match keyboard_projection.displayed_region() {
+            Ok(Some(region)) => {
+                let points: Vec<Vec2> = region.iter().map(|point| Vec2::new(point.x as f32, point.y as f32) / frame_size).collect();
+                let min = points.iter().fold(Vec2::splat(f32::MAX), |min, point| min.min(*point));
+                let max = points.iter().fold(Vec2::splat(f32::MIN), |max, point| max.max(*point));
+                Some(AutoFraming::target(&settings, min.max(Vec2::ZERO), max.min(Vec2::ONE)))
+            }
+            Ok(None) => None,
+            Err(err) => {
+                eprintln!("Failed to project the keyboard region: {}", err);
+                None
+            }
+        }
+    };
+
+    if let Some((center, zoom)) = target {
+        let blend = 1.0 - (-settings.smoothing * time.delta_secs()).exp();
+        framing.center = framing.center.lerp(center, blend);
+        framing.zoom += (zoom - framing.zoom) * blend;
+    }
+
+    // Crop and fit the overlay cameras' views the same way so AR content stays on top of the real keys. Each output
+    // has its own aspect ratio, so each gets its own view.
+    let [crop_x, crop_y, crop_width, crop_height] = framing.crop();
+    let image_aspect = frame_size.x / frame_size.y;
+    for (output, mut camera) in cameras.iter_mut() {
+        if !output.shows_camera_feed() {
+            continue;
+        }
+        let viewport_size = camera.physical_viewport_size().filter(|_| !frame.empty());
+        let sub_camera_view = viewport_size.map(|viewport_size| {
+            let [x, y, width, height] = settings.fit.rect(image_aspect, viewport_size.x as f32 / viewport_size.y.max(1) as f32);
+            let full_size = frame_size * SUB_VIEW_RESOLUTION_SCALE;
+            let offset = Vec2::new(crop_x + x * crop_width, crop_y + y * crop_height);
+            let size = Vec2::new(width * crop_width, height * crop_height);
+            SubCameraView {
+                full_size: full_size.as_uvec2(),
+                offset: offset * full_size,
+                size: (size * full_size).as_uvec2().max(UVec2::ONE)
+            }
+        });
+        if camera.sub_camera_view != sub_camera_view {
+            camera.sub_camera_view = sub_camera_view;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_modes_keep_the_image_aspect_ratio() {
+        // A 4:3 frame in a 16:9 output
+        let (image, output) = (4.0 / 3.0, 16.0 / 9.0);
+        assert_eq!(FitMode::Fill.rect(image, output), [0.0, 0.0, 1.0, 1.0]);
+
+        let [x, _, width, height] = FitMode::Fit.rect(image, output);
+        assert!((width / height - output / image).abs() < 1e-5);
+        assert!(x < 0.0 && height == 1.0);
+
+        let [_, y, width, height] = FitMode::Crop.rect(image, output);
+        assert!((width / height - output / image).abs() < 1e-5);
+        assert!(y > 0.0 && width == 1.0);
+    }
+}
@@ -0,0 +1,157 @@
+//! Estimates the room's lighting from the camera feed and drives the scene's ambient light and shadow light with it,
+//! so shadows and lit content roughly match the real keyboard.
+//!
+//! The estimate is deliberately rough: the brightness and color are the frame's average, and the light direction
+//! is guessed from which part of the frame is brightest, tilting the light toward that side.
+
+use bevy::{color::{Color, LinearRgba, Mix}, core_pipeline::core_3d::Camera3d, ecs::{change_detection::DetectChanges, query::With, resource::Resource, system::{Query, Res, ResMut}}, math::{Vec2, Vec3}, pbr::{AmbientLight, DirectionalLight}, time::Time, transform::components::{GlobalTransform, Transform}};
+use opencv::core::{self, AlgorithmHint, Mat, MatTraitConst, Size};
+use opencv::imgproc;
+
+use crate::{keyboard::shadow_catcher::{self, ShadowLight}, render_layers::OutputCamera, video::WebcamFrame};
+
+/// The size frames are shrunk to before sampling. Lighting varies slowly across the frame, so this is plenty.
+const SAMPLE_SIZE: (i32, i32) = (32, 18);
+/// The average brightness, from 0 to 1, at which the lights keep their default strength.
+const REFERENCE_BRIGHTNESS: f32 = 0.5;
+/// How far the light leans toward the brightest side of the frame.
+const DIRECTION_TILT: f32 = 2.0;
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct LightEstimationSettings {
+    pub enabled: bool,
+    /// Seconds between samples.
+    pub interval: f32,
+    /// How far each sample moves the estimate toward it, from 0 to 1, so the lights don't jump when someone walks past.
+    pub responsiveness: f32
+}
+
+impl Default for LightEstimationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 1.0,
+            responsiveness: 0.5
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightEstimate {
+    /// The average brightness of the frame, from 0 to 1.
+    pub brightness: f32,
+    /// The average color, scaled so its brightest channel is 1.
+    pub color: LinearRgba,
+    /// Where the brightness is centered in the frame, from -1 to 1 on each axis, with positive y down.
+    pub offset: Vec2
+}
+
+impl LightEstimate {
+    fn lerp(&self, other: &LightEstimate, t: f32) -> LightEstimate {
+        LightEstimate {
+            brightness: self.brightness + (other.brightness - self.brightness) * t,
+            color: self.color.mix(&other.color, t),
+            offset: self.offset.lerp(other.offset, t)
+        }
+    }
+}
+
+/// The current estimate and the buffers used to sample it.
+#[derive(Resource, Default)]
+pub struct LightEstimator {
+    pub estimate: Option<LightEstimate>,
+    seconds_since_sample: f32,
+    small: Mat,
+    greyscale: Mat
+}
+
+impl LightEstimator {
+    /// Samples a BGR frame. Returns `None` if the frame is empty or completely black.
+    fn sample(&mut self, frame: &Mat) -> opencv::Result<Option<LightEstimate>> {
+        if frame.empty() {
+            return Ok(None);
+        }
+        imgproc::resize(frame, &mut self.small, Size::new(SAMPLE_SIZE.0, SAMPLE_SIZE.1), 0.0, 0.0, imgproc::INTER_AREA)?;
+        imgproc::cvt_color(&self.small, &mut self.greyscale, imgproc::COLOR_BGR2GRAY, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+
+        let moments = imgproc::moments_def(&self.greyscale)?;
+        if moments.m00 <= 0.0 {
+            return Ok(None);
+        }
+        let offset = Vec2::new(
+            (moments.m10 / moments.m00 / SAMPLE_SIZE.0 as f64 * 2.0 - 1.0) as f32,
+            (moments.m01 / moments.m00 / SAMPLE_SIZE.1 as f64 * 2.0 - 1.0) as f32
+        );
+
+        let mean = core::mean_def(&self.small)?;
+        let (blue, green, red) = (mean[0] as f32 / 255.0, mean[1] as f32 / 255.0, mean[2] as f32 / 255.0);
+        let brightest = red.max(green).max(blue).max(f32::EPSILON);
+
+        Ok(Some(LightEstimate {
+            brightness: 0.299 * red + 0.587 * green + 0.114 * blue,
+            color: Color::srgb(red / brightest, green / brightest, blue / brightest).into(),
+            offset
+        }))
+    }
+}
+
+/// Samples the frame every `interval` seconds and applies the estimate to the ambient and shadow lights.
+/// Turning estimation off restores the defaults.
+pub fn estimate_lighting(
+    time: Res<Time>,
+    settings: Res<LightEstimationSettings>,
+    webcam_frame: Res<WebcamFrame>,
+    mut estimator: ResMut<LightEstimator>,
+    cameras: Query<(&GlobalTransform, &OutputCamera), With<Camera3d>>,
+    mut ambient: ResMut<AmbientLight>,
+    mut lights: Query<(&mut DirectionalLight, &mut Transform), With<ShadowLight>>
+) {
+    if !settings.enabled {
+        if settings.is_changed() && estimator.estimate.take().is_some() {
+            *ambient = AmbientLight::default();
+            for (mut light, mut transform) in lights.iter_mut() {
+                light.illuminance = shadow_catcher::SHADOW_LIGHT_ILLUMINANCE;
+                light.color = Color::WHITE;
+                *transform = shadow_catcher::shadow_light_transform(shadow_catcher::DEFAULT_LIGHT_DIRECTION);
+            }
+        }
+        return;
+    }
+
+    estimator.seconds_since_sample += time.delta_secs();
+    if estimator.seconds_since_sample < settings.interval && estimator.estimate.is_some() {
+        return;
+    }
+    estimator.seconds_since_sample = 0.0;
+
+    let sample = match estimator.sample(&webcam_frame.0) {
+        Ok(Some(sample)) => sample,
+        Ok(None) => return,
+        Err(err) => {
+            eprintln!("Failed to estimate the lighting: {}", err);
+            return;
+        }
+    };
+    let estimate = match &estimator.estimate {
+        Some(estimate) => estimate.lerp(&sample, settings.responsiveness.clamp(0.0, 1.0)),
+        None => sample
+    };
+    estimator.estimate = Some(estimate);
+
+    let strength = estimate.brightness / REFERENCE_BRIGHTNESS;
+    ambient.brightness = AmbientLight::default().brightness * strength;
+    ambient.color = estimate.color.into();
+
+    // Lean the light toward the brightest part of the frame, as seen from the main camera
+    let (right, up) = cameras.iter()
+        .find(|(_, output)| **output == OutputCamera::MainWindow)
+        .map(|(transform, _)| (transform.right().with_y(0.0).normalize_or_zero(), transform.up().with_y(0.0).normalize_or_zero()))
+        .unwrap_or((Vec3::X, Vec3::NEG_Z));
+    let toward_light = (Vec3::Y + (right * estimate.offset.x - up * estimate.offset.y) * DIRECTION_TILT).normalize();
+
+    for (mut light, mut transform) in lights.iter_mut() {
+        light.illuminance = shadow_catcher::SHADOW_LIGHT_ILLUMINANCE * strength;
+        light.color = estimate.color.into();
+        *transform = shadow_catcher::shadow_light_transform(toward_light);
+    }
+}
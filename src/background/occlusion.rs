@@ -0,0 +1,87 @@
+//! Hand occlusion: a skin-color mask of the player's hands is stored in the alpha channel of the background image,
+//! and a second pass redraws those camera pixels over the virtual content so notes and highlights appear behind the hands.
+
+use bevy::{ecs::resource::Resource, render::extract_resource::ExtractResource};
+use opencv::core::{self, AlgorithmHint, Mat, MatTraitConst, Point, Scalar, Size, Vector, CV_8UC1};
+use opencv::imgproc;
+
+use crate::background::KeyboardProjection;
+
+#[derive(Resource, ExtractResource, Clone)]
+pub struct HandOcclusionSettings {
+    pub enabled: bool,
+    /// The skin color range in OpenCV's YCrCb space, 0-255. Skin falls in a narrow band of Cr and Cb
+    /// regardless of brightness, so luma isn't constrained.
+    pub min_cr: f64,
+    pub max_cr: f64,
+    pub min_cb: f64,
+    pub max_cb: f64,
+    /// Only mask hands over the tracked keyboard, so skin-colored things elsewhere in the room don't hide the overlays.
+    pub keyboard_only: bool
+}
+
+impl Default for HandOcclusionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_cr: 135.0,
+            max_cr: 180.0,
+            min_cb: 85.0,
+            max_cb: 135.0,
+            keyboard_only: true
+        }
+    }
+}
+
+/// Buffers reused between frames.
+#[derive(Resource, Default)]
+pub struct HandOcclusion {
+    ycrcb: Mat,
+    mask: Mat,
+    cleaned_mask: Mat,
+    region_mask: Mat
+}
+
+impl HandOcclusion {
+    /// Writes the hand mask computed from `frame` (BGR) into the alpha channel of `converted_frame` (RGBA).
+    /// Does nothing when disabled, leaving the frame opaque.
+    pub fn apply(
+        &mut self,
+        settings: &HandOcclusionSettings,
+        keyboard_projection: &KeyboardProjection,
+        frame: &Mat,
+        converted_frame: &mut Mat
+    ) -> opencv::Result<()> {
+        if !settings.enabled {
+            return Ok(());
+        }
+
+        imgproc::cvt_color(frame, &mut self.ycrcb, imgproc::COLOR_BGR2YCrCb, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+        core::in_range(
+            &self.ycrcb,
+            &Scalar::new(0.0, settings.min_cr, settings.min_cb, 0.0),
+            &Scalar::new(255.0, settings.max_cr, settings.max_cb, 0.0),
+            &mut self.mask
+        )?;
+
+        // Remove speckles, then fill small gaps between fingers
+        let kernel = imgproc::get_structuring_element_def(imgproc::MORPH_ELLIPSE, Size::new(5, 5))?;
+        imgproc::morphology_ex_def(&self.mask, &mut self.cleaned_mask, imgproc::MORPH_OPEN, &kernel)?;
+        imgproc::morphology_ex_def(&self.cleaned_mask, &mut self.mask, imgproc::MORPH_CLOSE, &kernel)?;
+
+        if settings.keyboard_only {
+            self.region_mask = Mat::new_size_with_default(self.mask.size()?, CV_8UC1, Scalar::all(0.0))?;
+            if let Some(region) = keyboard_projection.image_region()? {
+                let region: Vector<Point> = region.iter().map(|point| Point::new(point.x as i32, point.y as i32)).collect();
+                imgproc::fill_convex_poly_def(&mut self.region_mask, &region, Scalar::all(255.0))?;
+            }
+            core::bitwise_and_def(&self.mask, &self.region_mask, &mut self.cleaned_mask)?;
+        } else {
+            self.mask.copy_to(&mut self.cleaned_mask)?;
+        }
+
+        // Soften the edge so the cutout doesn't look jagged against the overlays
+        imgproc::gaussian_blur_def(&self.cleaned_mask, &mut self.mask, Size::new(7, 7), 0.0)?;
+        core::insert_channel(&self.mask, converted_frame, 3)
+    }
+}
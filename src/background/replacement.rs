@@ -0,0 +1,160 @@
+//! Replaces the real backdrop behind the piano with a virtual stage, either by chroma-keying a green screen
+//! or by keeping only the moving parts of the frame (the performer). The tracked keyboard is always kept,
+//! so keys and notes stay aligned with the real instrument.
+
+use std::sync::Mutex;
+
+use bevy::ecs::resource::Resource;
+use opencv::core::{self, AlgorithmHint, Mat, MatTraitConst, Point, Ptr, Scalar, Size, Vector, CV_8UC4};
+use opencv::prelude::BackgroundSubtractorTrait;
+use opencv::video::{self, BackgroundSubtractorMOG2};
+use opencv::{imgcodecs, imgproc};
+use serde::Deserialize;
+
+use crate::background::KeyboardProjection;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplacementMode {
+    /// Show the camera feed as-is.
+    Off,
+    /// Replace pixels close to the key color.
+    ChromaKey,
+    /// Replace pixels that haven't moved recently, keeping the performer.
+    MotionMask
+}
+
+impl ReplacementMode {
+    pub const ALL: [ReplacementMode; 3] = [ReplacementMode::Off, ReplacementMode::ChromaKey, ReplacementMode::MotionMask];
+}
+
+#[derive(Resource, Clone)]
+pub struct BackgroundReplacementSettings {
+    pub mode: ReplacementMode,
+    /// The hue of the backdrop, in OpenCV's 0-180 range. Green screens are around 60.
+    pub key_hue: f64,
+    pub hue_tolerance: f64,
+    /// Pixels less saturated or darker than this (0-255) are never keyed, so greys and shadows survive.
+    pub min_saturation: f64,
+    pub min_value: f64,
+    /// The image shown in place of the backdrop. Falls back to `backdrop_color` if it can't be loaded.
+    pub backdrop_path: String,
+    /// The RGB fallback backdrop color, 0-255.
+    pub backdrop_color: [f64; 3],
+    /// Keep the area around the tracked keyboard even if it matches the mask.
+    pub protect_keyboard: bool
+}
+
+impl Default for BackgroundReplacementSettings {
+    fn default() -> Self {
+        Self {
+            mode: ReplacementMode::Off,
+            key_hue: 60.0,
+            hue_tolerance: 15.0,
+            min_saturation: 80.0,
+            min_value: 60.0,
+            backdrop_path: "assets/stage.jpg".to_string(),
+            backdrop_color: [20.0, 12.0, 40.0],
+            protect_keyboard: true
+        }
+    }
+}
+
+/// Buffers reused between frames.
+#[derive(Resource, Default)]
+pub struct BackgroundReplacement {
+    hsv: Mat,
+    mask: Mat,
+    cleaned_mask: Mat,
+    /// The backdrop as RGBA at the current frame size.
+    backdrop: Mat,
+    backdrop_source: Option<String>,
+    /// The color the backdrop was filled with, if its image couldn't be loaded.
+    backdrop_fill: Option<[f64; 3]>,
+    /// The background model for motion masking. Created the first time it's needed.
+    subtractor: Mutex<Option<Ptr<BackgroundSubtractorMOG2>>>
+}
+
+impl BackgroundReplacement {
+    /// Replaces the backdrop in `converted_frame` (RGBA) using the mask computed from `frame` (BGR).
+    pub fn apply(
+        &mut self,
+        settings: &BackgroundReplacementSettings,
+        keyboard_projection: &KeyboardProjection,
+        frame: &Mat,
+        converted_frame: &mut Mat
+    ) -> opencv::Result<()> {
+        match settings.mode {
+            ReplacementMode::Off => {
+                // Drop the background model so it starts fresh if motion masking is turned back on
+                *self.subtractor.lock().expect("Failed to lock background subtractor mutex") = None;
+                return Ok(());
+            }
+            ReplacementMode::ChromaKey => {
+                imgproc::cvt_color(frame, &mut self.hsv, imgproc::COLOR_BGR2HSV, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+                core::in_range(
+                    &self.hsv,
+                    &Scalar::new(settings.key_hue - settings.hue_tolerance, settings.min_saturation, settings.min_value, 0.0),
+                    &Scalar::new(settings.key_hue + settings.hue_tolerance, 255.0, 255.0, 0.0),
+                    &mut self.mask
+                )?;
+            }
+            ReplacementMode::MotionMask => {
+                let mut subtractor = self.subtractor.lock().expect("Failed to lock background subtractor mutex");
+                if subtractor.is_none() {
+                    *subtractor = Some(video::create_background_subtractor_mog2_def()?);
+                }
+                let subtractor = subtractor.as_mut().expect("Background subtractor was just created");
+                subtractor.apply(frame, &mut self.hsv, -1.0)?;
+                // MOG2 marks shadows as 127, so only count confident foreground as the performer
+                imgproc::threshold(&self.hsv, &mut self.cleaned_mask, 200.0, 255.0, imgproc::THRESH_BINARY)?;
+                core::bitwise_not_def(&self.cleaned_mask, &mut self.mask)?;
+            }
+        }
+
+        // Remove speckles so the edge of the mask doesn't flicker
+        let kernel = imgproc::get_structuring_element_def(imgproc::MORPH_ELLIPSE, Size::new(5, 5))?;
+        imgproc::morphology_ex_def(&self.mask, &mut self.cleaned_mask, imgproc::MORPH_OPEN, &kernel)?;
+
+        if settings.protect_keyboard {
+            if let Some(region) = keyboard_projection.image_region()? {
+                let region: Vector<Point> = region.iter().map(|point| Point::new(point.x as i32, point.y as i32)).collect();
+                imgproc::fill_convex_poly_def(&mut self.cleaned_mask, &region, Scalar::all(0.0))?;
+            }
+        }
+
+        self.update_backdrop(settings, converted_frame.size()?)?;
+        self.backdrop.copy_to_masked(converted_frame, &self.cleaned_mask)?;
+        Ok(())
+    }
+
+    /// Loads and resizes the backdrop if the path or frame size changed.
+    fn update_backdrop(&mut self, settings: &BackgroundReplacementSettings, size: Size) -> opencv::Result<()> {
+        if self.backdrop_source.as_ref() == Some(&settings.backdrop_path) && self.backdrop.size()? == size {
+            // The color can change every frame under automation, so refill it without trying the image again
+            if self.backdrop_fill.is_some_and(|fill| fill != settings.backdrop_color) {
+                self.fill_backdrop(settings.backdrop_color, size)?;
+            }
+            return Ok(());
+        }
+        self.backdrop_source = Some(settings.backdrop_path.clone());
+
+        let image = imgcodecs::imread(&settings.backdrop_path, imgcodecs::IMREAD_COLOR)?;
+        if image.empty() {
+            eprintln!("Failed to load backdrop image {}; using a solid color", settings.backdrop_path);
+            return self.fill_backdrop(settings.backdrop_color, size);
+        }
+        self.backdrop_fill = None;
+
+        let mut resized = Mat::default();
+        imgproc::resize(&image, &mut resized, size, 0.0, 0.0, imgproc::INTER_AREA)?;
+        imgproc::cvt_color(&resized, &mut self.backdrop, imgproc::COLOR_BGR2RGBA, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+        Ok(())
+    }
+
+    fn fill_backdrop(&mut self, [r, g, b]: [f64; 3], size: Size) -> opencv::Result<()> {
+        self.backdrop = Mat::new_size_with_default(size, CV_8UC4, Scalar::new(r, g, b, 255.0))?;
+        self.backdrop_fill = Some([r, g, b]);
+        Ok(())
+    }
+}
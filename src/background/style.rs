@@ -0,0 +1,135 @@
+//! Stylized looks for the camera feed, applied in the background shader. The keyboard region is always left natural,
+//! so the real keys stay readable while the rest of the frame makes the overlays stand out.
+
+use bevy::{ecs::{resource::Resource, system::{Res, ResMut}}, render::extract_resource::ExtractResource};
+use bytemuck::{Pod, Zeroable};
+use opencv::core::MatTraitConst;
+use serde::Deserialize;
+
+use crate::background::{framing::{AutoFraming, AutoFramingSettings}, ConvertedWebcamFrame, KeyboardProjection};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StyleMode {
+    Natural,
+    /// Dark ink outlines along edges.
+    InkOutline,
+    /// Reduce each color channel to a few levels.
+    Posterize,
+    /// Dim everything except the keyboard.
+    Spotlight
+}
+
+impl StyleMode {
+    pub const ALL: [StyleMode; 4] = [StyleMode::Natural, StyleMode::InkOutline, StyleMode::Posterize, StyleMode::Spotlight];
+
+    fn shader_index(&self) -> u32 {
+        match self {
+            StyleMode::Natural => 0,
+            StyleMode::InkOutline => 1,
+            StyleMode::Posterize => 2,
+            StyleMode::Spotlight => 3
+        }
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct BackgroundStyleSettings {
+    pub mode: StyleMode,
+    /// The number of levels per channel in posterize mode.
+    pub posterize_levels: f32,
+    /// The brightness outside the keyboard in spotlight mode, from 0 to 1.
+    pub spotlight_dim: f32,
+    /// How dark the ink outlines are drawn.
+    pub edge_strength: f32
+}
+
+impl Default for BackgroundStyleSettings {
+    fn default() -> Self {
+        Self {
+            mode: StyleMode::Natural,
+            posterize_levels: 4.0,
+            spotlight_dim: 0.3,
+            edge_strength: 1.0
+        }
+    }
+}
+
+/// The uniform passed to the background shader. Must match `Style` in backgroundShader.wgsl.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+pub struct StyleUniform {
+    pub mode: u32,
+    pub posterize_levels: f32,
+    pub spotlight_dim: f32,
+    pub edge_strength: f32,
+    /// The size of one pixel in texture coordinates.
+    pub texel_size: [f32; 2],
+    /// Nonzero if `keyboard` holds a valid region.
+    pub has_keyboard: u32,
+    /// How the cropped frame is fitted to each output. See `FitMode::shader_index`.
+    pub fit_mode: u32,
+    /// The keyboard's corners in texture coordinates, two per vec4.
+    pub keyboard: [[f32; 4]; 2],
+    /// The part of the frame shown, as (x, y, width, height) in texture coordinates.
+    pub crop: [f32; 4]
+}
+
+impl Default for StyleUniform {
+    fn default() -> Self {
+        Self {
+            mode: 0,
+            posterize_levels: 4.0,
+            spotlight_dim: 1.0,
+            edge_strength: 0.0,
+            texel_size: [0.0, 0.0],
+            has_keyboard: 0,
+            fit_mode: 0,
+            keyboard: [[0.0; 4]; 2],
+            crop: [0.0, 0.0, 1.0, 1.0]
+        }
+    }
+}
+
+#[derive(Resource, ExtractResource, Clone, Default)]
+pub struct BackgroundStyle(pub StyleUniform);
+
+pub fn update_background_style(
+    settings: Res<BackgroundStyleSettings>,
+    framing: Res<AutoFraming>,
+    framing_settings: Res<AutoFramingSettings>,
+    converted_webcam_frame: Res<ConvertedWebcamFrame>,
+    keyboard_projection: KeyboardProjection,
+    mut style: ResMut<BackgroundStyle>
+) {
+    let frame = &converted_webcam_frame.0;
+    let (width, height) = (frame.cols().max(1) as f32, frame.rows().max(1) as f32);
+
+    let mut uniform = StyleUniform {
+        mode: settings.mode.shader_index(),
+        posterize_levels: settings.posterize_levels.max(2.0),
+        spotlight_dim: settings.spotlight_dim.clamp(0.0, 1.0),
+        edge_strength: settings.edge_strength,
+        texel_size: [1.0 / width, 1.0 / height],
+        fit_mode: framing_settings.fit.shader_index(),
+        crop: framing.crop(),
+        ..Default::default()
+    };
+
+    // Only project the keyboard when a stylized mode needs it
+    if settings.mode != StyleMode::Natural {
+        match keyboard_projection.displayed_region() {
+            Ok(Some(region)) => {
+                let corners: Vec<[f32; 2]> = region.iter().map(|point| [point.x as f32 / width, point.y as f32 / height]).collect();
+                if let [a, b, c, d] = corners[..] {
+                    uniform.keyboard = [[a[0], a[1], b[0], b[1]], [c[0], c[1], d[0], d[1]]];
+                    uniform.has_keyboard = 1;
+                }
+            }
+            Ok(None) => {}
+            Err(err) => eprintln!("Failed to project the keyboard region: {}", err)
+        }
+    }
+
+    style.0 = uniform;
+}
@@ -0,0 +1,141 @@
+//! Removes lens distortion from the background image using the camera calibration, so the camera feed lines up with
+//! the virtual content, which is rendered with an ideal pinhole camera. Without it, overlays drift away from the keys
+//! toward the edges of the frame, where wide-angle webcams distort the most.
+//!
+//! The image is remapped to the same camera matrix, so the pose and projection don't change; only the curvature goes.
+//! Detection still runs on the raw frame, since solving the pose accounts for the distortion itself, so anything found in
+//! the undistorted image (like the GPU prefilter's crops) goes back through the distortion with `distorted_region`.
+
+use bevy::ecs::resource::Resource;
+use opencv::core::{Mat, MatTraitConst, Point2d, Point3d, Rect, Scalar, Size, Vector, BORDER_CONSTANT, CV_16SC2};
+use opencv::{calib3d, imgproc};
+
+use crate::video::aruco_camera::CameraIntrinsics;
+
+/// The points taken along each edge of a region when distorting it, since straight edges come out curved.
+const EDGE_SAMPLES: usize = 8;
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct UndistortSettings {
+    pub enabled: bool
+}
+
+impl Default for UndistortSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true
+        }
+    }
+}
+
+impl UndistortSettings {
+    /// Whether the background image is undistorted, which needs a calibration to undistort with.
+    pub fn active(&self, intrinsics: Option<&CameraIntrinsics>) -> bool {
+        self.enabled && intrinsics.is_some()
+    }
+}
+
+/// The remapping tables, rebuilt whenever the frame size or the calibration changes, and a buffer reused between frames.
+#[derive(Resource, Default)]
+pub struct Undistortion {
+    map_x: Mat,
+    map_y: Mat,
+    /// The frame size the maps were built for.
+    size: Option<Size>,
+    undistorted: Mat
+}
+
+impl Undistortion {
+    /// Rebuilds the maps on the next frame, for a calibration that was loaded or replaced.
+    pub fn recalibrate(&mut self) {
+        self.size = None;
+    }
+
+    /// Undistorts `converted_frame` in place. Does nothing when disabled or without a calibration.
+    pub fn apply(
+        &mut self,
+        settings: &UndistortSettings,
+        intrinsics: Option<&CameraIntrinsics>,
+        converted_frame: &mut Mat
+    ) -> opencv::Result<()> {
+        let Some(intrinsics) = intrinsics.filter(|_| settings.enabled) else {
+            // Free the maps, which are as large as several frames
+            if self.size.take().is_some() {
+                *self = Self::default();
+            }
+            return Ok(());
+        };
+
+        let size = converted_frame.size()?;
+        if self.size != Some(size) {
+            calib3d::init_undistort_rectify_map(
+                &intrinsics.camera_matrix,
+                &intrinsics.dist_coeffs,
+                &Mat::default(),
+                &intrinsics.camera_matrix,
+                size,
+                CV_16SC2,
+                &mut self.map_x,
+                &mut self.map_y
+            )?;
+            self.size = Some(size);
+        }
+
+        // Pixels pulled from outside the frame are left transparent black, which also keeps them out of the hand mask
+        imgproc::remap(converted_frame, &mut self.undistorted, &self.map_x, &self.map_y, imgproc::INTER_LINEAR, BORDER_CONSTANT, Scalar::default())?;
+        std::mem::swap(converted_frame, &mut self.undistorted);
+        Ok(())
+    }
+}
+
+/// The part of the raw frame that `region` of the undistorted image was pulled from.
+pub fn distorted_region(intrinsics: &CameraIntrinsics, region: Rect) -> opencv::Result<Rect> {
+    let camera_matrix = &intrinsics.camera_matrix;
+    let (fx, fy) = (*camera_matrix.at_2d::<f64>(0, 0)?, *camera_matrix.at_2d::<f64>(1, 1)?);
+    let (cx, cy) = (*camera_matrix.at_2d::<f64>(0, 2)?, *camera_matrix.at_2d::<f64>(1, 2)?);
+
+    // The undistorted image uses the same camera matrix, so its pixels are rays through an ideal pinhole
+    let (left, top) = (region.x as f64, region.y as f64);
+    let (width, height) = (region.width as f64, region.height as f64);
+    let mut rays: Vector<Point3d> = Vector::new();
+    for sample in 0..=EDGE_SAMPLES {
+        let along = sample as f64 / EDGE_SAMPLES as f64;
+        for (x, y) in [(left + width * along, top), (left + width * along, top + height), (left, top + height * along), (left + width, top + height * along)] {
+            rays.push(Point3d::new((x - cx) / fx, (y - cy) / fy, 1.0));
+        }
+    }
+
+    let no_motion = Mat::from_slice(&[0.0f64; 3])?.try_clone()?;
+    let mut projected: Vector<Point2d> = Vector::new();
+    calib3d::project_points_def(&rays, &no_motion, &no_motion, camera_matrix, &intrinsics.dist_coeffs, &mut projected)?;
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for point in projected.iter() {
+        (min_x, min_y, max_x, max_y) = (min_x.min(point.x), min_y.min(point.y), max_x.max(point.x), max_y.max(point.y));
+    }
+    let (left, top) = (min_x.floor() as i32, min_y.floor() as i32);
+    Ok(Rect::new(left, top, max_x.ceil() as i32 - left, max_y.ceil() as i32 - top))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intrinsics(k1: f64) -> CameraIntrinsics {
+        CameraIntrinsics {
+            camera_matrix: Mat::from_slice_2d(&[[1000.0, 0.0, 640.0], [0.0, 1000.0, 360.0], [0.0, 0.0, 1.0]]).unwrap(),
+            dist_coeffs: Mat::from_slice(&[k1, 0.0, 0.0, 0.0, 0.0]).unwrap().try_clone().unwrap()
+        }
+    }
+
+    #[test]
+    fn regions_follow_the_distortion_back_to_the_raw_frame() {
+        let corner = Rect::new(0, 0, 100, 100);
+        let unchanged = distorted_region(&intrinsics(0.0), corner).unwrap();
+        assert!((unchanged.x - corner.x).abs() <= 1 && (unchanged.width - corner.width).abs() <= 2);
+
+        // Barrel distortion pulls the edges of the frame in toward the center
+        let barrel = distorted_region(&intrinsics(-0.2), corner).unwrap();
+        assert!(barrel.x > corner.x && barrel.y > corner.y);
+    }
+}
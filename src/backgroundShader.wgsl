@@ -14,12 +14,132 @@ fn vs_main(@builtin(vertex_index) id: u32) -> VertexOutput {
     return out;
 }
 
+// Must match StyleUniform in background/style.rs
+struct Style {
+    mode: u32,
+    posterize_levels: f32,
+    spotlight_dim: f32,
+    edge_strength: f32,
+    texel_size: vec2<f32>,
+    has_keyboard: u32,
+    fit_mode: u32,
+    keyboard: array<vec4<f32>, 2>,
+    // (x, y, width, height) of the part of the frame shown
+    crop: vec4<f32>,
+};
+
+const MODE_INK_OUTLINE: u32 = 1u;
+const MODE_POSTERIZE: u32 = 2u;
+const MODE_SPOTLIGHT: u32 = 3u;
+
+// Must match FitMode::shader_index in background/framing.rs
+const FIT_FIT: u32 = 1u;
+const FIT_CROP: u32 = 2u;
+
 @group(0) @binding(0)
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
+@group(0) @binding(2)
+var<uniform> style: Style;
+
+// Where a point on the output falls in the frame, matching FitMode::rect. The output's aspect ratio comes from how
+// fast the fullscreen coordinates change per pixel, since one bind group is shared by every output.
+fn frame_uv(tex_coords: vec2<f32>) -> vec2<f32> {
+    let output_aspect = abs(dpdy(tex_coords.y) / dpdx(tex_coords.x));
+    let image_size = vec2f(textureDimensions(t_diffuse)) * style.crop.zw;
+    let image_aspect = image_size.x / max(image_size.y, 1.0);
+    var fit = vec2f(1.0, 1.0);
+    let wider = output_aspect > image_aspect;
+    if ((style.fit_mode == FIT_FIT && wider) || (style.fit_mode == FIT_CROP && !wider)) {
+        fit.x = output_aspect / image_aspect;
+    } else if (style.fit_mode == FIT_FIT || style.fit_mode == FIT_CROP) {
+        fit.y = image_aspect / output_aspect;
+    }
+    let fitted = (1.0 - fit) / 2.0 + tex_coords * fit;
+    return style.crop.xy + fitted * style.crop.zw;
+}
+
+// Fitting leaves bars outside the frame
+fn in_frame(uv: vec2<f32>) -> bool {
+    return all(uv >= vec2f(0.0)) && all(uv <= vec2f(1.0));
+}
+
+fn luminance(uv: vec2<f32>) -> f32 {
+    return dot(textureSample(t_diffuse, s_diffuse, uv).rgb, vec3f(0.299, 0.587, 0.114));
+}
+
+fn cross2(a: vec2<f32>, b: vec2<f32>) -> f32 {
+    return a.x * b.y - a.y * b.x;
+}
+
+// Whether the point is inside the projected keyboard quad, which may be wound either way
+fn in_keyboard(uv: vec2<f32>) -> bool {
+    if (style.has_keyboard == 0u) {
+        return false;
+    }
+    var corners = array<vec2<f32>, 4>(style.keyboard[0].xy, style.keyboard[0].zw, style.keyboard[1].xy, style.keyboard[1].zw);
+    var positive = 0;
+    var negative = 0;
+    for (var i = 0; i < 4; i++) {
+        let a = corners[i];
+        let b = corners[(i + 1) % 4];
+        if (cross2(b - a, uv - a) >= 0.0) {
+            positive++;
+        } else {
+            negative++;
+        }
+    }
+    return positive == 4 || negative == 4;
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
-}
\ No newline at end of file
+    let uv = frame_uv(in.tex_coords);
+    // The alpha channel holds the hand occlusion mask, so the background itself is always opaque
+    let color = vec4f(textureSample(t_diffuse, s_diffuse, uv).rgb, 1.0);
+    // Sample the neighbors before branching, since textureSample needs uniform control flow
+    let t = style.texel_size;
+    let tl = luminance(uv + vec2f(-t.x, -t.y));
+    let tc = luminance(uv + vec2f(0.0, -t.y));
+    let tr = luminance(uv + vec2f(t.x, -t.y));
+    let ml = luminance(uv + vec2f(-t.x, 0.0));
+    let mr = luminance(uv + vec2f(t.x, 0.0));
+    let bl = luminance(uv + vec2f(-t.x, t.y));
+    let bc = luminance(uv + vec2f(0.0, t.y));
+    let br = luminance(uv + vec2f(t.x, t.y));
+
+    if (!in_frame(uv)) {
+        return vec4f(0.0, 0.0, 0.0, 1.0);
+    }
+    if (style.mode == 0u || in_keyboard(uv)) {
+        return color;
+    }
+
+    if (style.mode == MODE_INK_OUTLINE) {
+        // Sobel edge magnitude
+        let gx = (tr + 2.0 * mr + br) - (tl + 2.0 * ml + bl);
+        let gy = (bl + 2.0 * bc + br) - (tl + 2.0 * tc + tr);
+        let edge = clamp(length(vec2f(gx, gy)) * style.edge_strength, 0.0, 1.0);
+        return vec4f(mix(color.rgb, vec3f(0.0), edge), color.a);
+    }
+    if (style.mode == MODE_POSTERIZE) {
+        let levels = style.posterize_levels;
+        return vec4f(min(floor(color.rgb * levels), vec3f(levels - 1.0)) / (levels - 1.0), color.a);
+    }
+    if (style.mode == MODE_SPOTLIGHT) {
+        return vec4f(color.rgb * style.spotlight_dim, color.a);
+    }
+    return color;
+}
+
+// Redraws the player's hands over the virtual content. The hand mask is in the alpha channel, see background/occlusion.rs.
+@fragment
+fn fs_hand_occlusion(in: VertexOutput) -> @location(0) vec4<f32> {
+    let uv = frame_uv(in.tex_coords);
+    let color = textureSample(t_diffuse, s_diffuse, uv);
+    if (!in_frame(uv)) {
+        return vec4f(0.0);
+    }
+    return color;
+}
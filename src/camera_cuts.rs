@@ -0,0 +1,258 @@
+//! Virtual camera shots for the spectator and recording outputs. Shots are placed in the keyboard's frame, so they follow
+//! the tracked keyboard, and the view cuts between them when playback passes one of the song's markers or the instrument
+//! sends a program change, which makes multi-angle practice videos without moving the real camera.
+//!
+//! Enabling cuts opens a spectator window showing the current shot, and with `record` set, F11 recordings show the
+//! shots instead of the camera's view. A virtual shot doesn't line up with the camera image, so shot cameras draw the
+//! AR content over a plain background instead of the camera feed.
+
+use bevy::{app::{App, Plugin, Update}, color::Color, core_pipeline::core_3d::Camera3d, ecs::{change_detection::{DetectChanges, DetectChangesMut}, component::Component, entity::Entity, query::{Has, With}, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, math::Vec3, render::{camera::{Camera, ClearColorConfig, PerspectiveProjection, Projection, RenderTarget}, extract_component::{ExtractComponent, ExtractComponentPlugin}}, transform::components::{GlobalTransform, Transform}, utils::default, window::{Window, WindowRef}};
+use serde::Deserialize;
+
+use crate::{keyboard::KeyboardRoot, midi::{bus::{NoteBus, NoteSource}, MidiEventKind}, render_layers::OutputCamera, song::{playback::SongPlayback, Song}, VideoUpdateSystems};
+
+static SPECTATOR_TITLE: &str = "AR Piano Visualizer - Spectator";
+/// Shot cameras have no camera feed behind them, so they clear to this instead.
+const SHOT_BACKGROUND: Color = Color::srgb(0.05, 0.05, 0.07);
+
+/// A virtual camera position around the keyboard.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CameraShot {
+    /// Song markers with this name cut to the shot. Other markers cut to the next shot.
+    pub name: String,
+    /// Where the camera is, in mm in the keyboard's frame: x along the keys from their center, y up from the keys,
+    /// and z toward the player from the line through the fiducials.
+    pub position: [f32; 3],
+    /// The point the camera looks at, in the same frame.
+    pub target: [f32; 3],
+    /// The vertical field of view in degrees.
+    #[serde(default = "default_fov")]
+    pub fov: f32
+}
+
+fn default_fov() -> f32 {
+    45.0
+}
+
+impl CameraShot {
+    fn new(name: &str, position: [f32; 3], target: [f32; 3]) -> Self {
+        Self { name: name.to_string(), position, target, fov: default_fov() }
+    }
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct CameraCutSettings {
+    /// Open the spectator window.
+    pub enabled: bool,
+    /// Show the shots in F11 recordings instead of the camera's view.
+    pub record: bool,
+    pub on_markers: bool,
+    /// Cut to shot N when the instrument sends program change N, wrapping around the shots.
+    pub on_program_changes: bool,
+    pub shots: Vec<CameraShot>
+}
+
+impl Default for CameraCutSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            record: false,
+            on_markers: true,
+            on_program_changes: true,
+            shots: vec![
+                CameraShot::new("Overhead", [0.0, 900.0, 400.0], [0.0, 0.0, 150.0]),
+                CameraShot::new("Player", [0.0, 500.0, 700.0], [0.0, 0.0, 150.0]),
+                CameraShot::new("Left hand", [-900.0, 250.0, 450.0], [-300.0, 0.0, 150.0]),
+                CameraShot::new("Right hand", [900.0, 250.0, 450.0], [300.0, 0.0, 150.0])
+            ]
+        }
+    }
+}
+
+/// The shot being shown, and how far playback had got when markers were last checked.
+#[derive(Resource, Default)]
+struct CameraCuts {
+    current: usize,
+    last_position: f64
+}
+
+/// A camera placed at the current shot instead of the solved camera pose. Extracted so the camera feed isn't drawn
+/// behind it.
+#[derive(Component, Clone, ExtractComponent)]
+pub struct ShotCamera;
+
+#[derive(Component)]
+struct SpectatorWindow;
+
+#[derive(Component)]
+struct SpectatorCamera {
+    window: Entity
+}
+
+/// The shot a marker cuts to: the one with its name, or the one after the current shot.
+fn shot_for_marker(shots: &[CameraShot], current: usize, label: &str) -> usize {
+    shots.iter()
+        .position(|shot| shot.name.eq_ignore_ascii_case(label.trim()))
+        .unwrap_or((current + 1) % shots.len().max(1))
+}
+
+/// Opens or closes the spectator window when the setting changes.
+fn sync_spectator_window(
+    mut commands: Commands,
+    settings: Res<CameraCutSettings>,
+    windows: Query<Entity, With<SpectatorWindow>>,
+    cameras: Query<Entity, With<SpectatorCamera>>
+) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    if !settings.enabled {
+        for entity in windows.iter().chain(cameras.iter()) {
+            commands.entity(entity).despawn();
+        }
+        return;
+    }
+    if !windows.is_empty() {
+        return;
+    }
+
+    let window = commands.spawn((
+        Window {
+            title: SPECTATOR_TITLE.to_string(),
+            ..default()
+        },
+        SpectatorWindow
+    )).id();
+    commands.spawn((
+        Camera3d::default(),
+        Camera {
+            target: RenderTarget::Window(WindowRef::Entity(window)),
+            clear_color: ClearColorConfig::Custom(SHOT_BACKGROUND),
+            ..default()
+        },
+        Transform::default(),
+        OutputCamera::Spectator,
+        ShotCamera,
+        SpectatorCamera { window }
+    ));
+}
+
+/// Cleans up after the user closes the spectator window, and turns it off.
+fn remove_closed_spectator(
+    mut commands: Commands,
+    mut settings: ResMut<CameraCutSettings>,
+    windows: Query<(), With<SpectatorWindow>>,
+    cameras: Query<(Entity, &SpectatorCamera)>
+) {
+    for (entity, camera) in cameras.iter() {
+        if !windows.contains(camera.window) {
+            commands.entity(entity).despawn();
+            if settings.enabled {
+                settings.enabled = false;
+            }
+        }
+    }
+}
+
+/// Makes the recorder camera follow the shots while `record` is set, and gives it back the camera's view otherwise.
+fn sync_recorder_shots(
+    mut commands: Commands,
+    settings: Res<CameraCutSettings>,
+    mut cameras: Query<(Entity, &OutputCamera, &mut Camera, Has<ShotCamera>)>
+) {
+    for (entity, output, mut camera, is_shot) in cameras.iter_mut() {
+        if *output != OutputCamera::Recorder || settings.record == is_shot {
+            continue;
+        }
+        if settings.record {
+            // Auto-framing crops the view to the keyboard in the camera image, which means nothing for a virtual shot
+            camera.sub_camera_view = None;
+            camera.clear_color = ClearColorConfig::Custom(SHOT_BACKGROUND);
+            commands.entity(entity).insert(ShotCamera);
+        } else {
+            camera.clear_color = ClearColorConfig::Default;
+            commands.entity(entity).remove::<ShotCamera>().insert(Projection::default());
+        }
+    }
+}
+
+/// Cuts to another shot on song markers and program changes.
+fn cut_between_shots(
+    settings: Res<CameraCutSettings>,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    bus: Res<NoteBus>,
+    mut cuts: ResMut<CameraCuts>
+) {
+    if settings.shots.is_empty() {
+        return;
+    }
+
+    if settings.on_program_changes {
+        for event in bus.from_source(NoteSource::Live) {
+            if let MidiEventKind::ProgramChange { program } = event.kind {
+                cuts.current = program as usize % settings.shots.len();
+            }
+        }
+    }
+
+    // Only markers passed while playing cut, so seeking or looping back doesn't
+    let (from, to) = (cuts.last_position, playback.position);
+    if settings.on_markers && playback.playing && to > from {
+        for marker in song.bookmarks.iter().filter(|marker| marker.time > from && marker.time <= to) {
+            cuts.current = shot_for_marker(&settings.shots, cuts.current, &marker.label);
+        }
+    }
+    cuts.last_position = to;
+    if cuts.current >= settings.shots.len() {
+        cuts.current = 0;
+    }
+}
+
+/// Places the shot cameras at the current shot, relative to the tracked keyboard.
+fn place_shot_cameras(
+    settings: Res<CameraCutSettings>,
+    cuts: Res<CameraCuts>,
+    keyboard_root: Single<&GlobalTransform, With<KeyboardRoot>>,
+    mut cameras: Query<(&mut Transform, &mut Projection), With<ShotCamera>>
+) {
+    let Some(shot) = settings.shots.get(cuts.current) else { return };
+    let position = keyboard_root.transform_point(Vec3::from(shot.position));
+    let target = keyboard_root.transform_point(Vec3::from(shot.target));
+    let shot_transform = Transform::from_translation(position).looking_at(target, keyboard_root.up());
+    let fov = shot.fov.clamp(5.0, 150.0).to_radians();
+
+    for (mut transform, mut projection) in cameras.iter_mut() {
+        transform.set_if_neq(shot_transform);
+        if !matches!(&*projection, Projection::Perspective(perspective) if perspective.fov == fov) {
+            *projection = Projection::Perspective(PerspectiveProjection { fov, ..default() });
+        }
+    }
+}
+
+pub struct CameraCutPlugin;
+
+impl Plugin for CameraCutPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<CameraCutSettings>()
+            .init_resource::<CameraCuts>()
+            .add_plugins(ExtractComponentPlugin::<ShotCamera>::default())
+            .add_systems(Update, (remove_closed_spectator, sync_spectator_window, sync_recorder_shots).chain())
+            .add_systems(Update, (cut_between_shots, place_shot_cameras).chain().after(VideoUpdateSystems));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markers_cut_to_their_shot_or_the_next() {
+        let shots = CameraCutSettings::default().shots;
+        assert_eq!(shot_for_marker(&shots, 0, " left hand"), 2);
+        assert_eq!(shot_for_marker(&shots, 1, "Chorus"), 2);
+        assert_eq!(shot_for_marker(&shots, 3, "Verse"), 0);
+    }
+}
@@ -0,0 +1,156 @@
+//! Names the chord formed by the currently-held notes and shows it floating above the keyboard.
+
+use bevy::{app::{App, Plugin, Startup, Update}, color::Color, core_pipeline::core_3d::Camera3d, ecs::{change_detection::DetectChangesMut, component::Component, query::With, system::{Commands, Query, Res, Single}}, math::Vec3, render::{camera::Camera, view::Visibility}, text::{JustifyText, TextColor, TextFont, TextLayout}, transform::components::GlobalTransform, ui::{widget::Text, Node, PositionType, Val}, utils::default};
+
+use crate::{keyboard::{KeyboardLayout, KeyboardRoot}, midi::NoteState, render_layers::OutputCamera};
+
+pub static PITCH_CLASS_NAMES: &[&str] = &["C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B"];
+
+/// Chord qualities as (suffix, intervals above the root). Listed most common first, so ambiguous sets get the common name.
+static CHORD_QUALITIES: &[(&str, &[u8])] = &[
+    ("", &[0, 4, 7]),
+    ("m", &[0, 3, 7]),
+    ("7", &[0, 4, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("m7", &[0, 3, 7, 10]),
+    ("dim", &[0, 3, 6]),
+    ("aug", &[0, 4, 8]),
+    ("sus4", &[0, 5, 7]),
+    ("sus2", &[0, 2, 7]),
+    ("m7b5", &[0, 3, 6, 10]),
+    ("dim7", &[0, 3, 6, 9]),
+    ("6", &[0, 4, 7, 9]),
+    ("m6", &[0, 3, 7, 9]),
+    ("mMaj7", &[0, 3, 7, 11]),
+    // Sevenths are often voiced without the fifth
+    ("7", &[0, 4, 10]),
+    ("maj7", &[0, 4, 11]),
+    ("m7", &[0, 3, 10])
+];
+
+/// How high above the keys the chord name floats, in mm.
+const LABEL_HEIGHT: f32 = 150.0;
+/// The label's font size when the camera is `LABEL_REFERENCE_DISTANCE` mm away.
+const LABEL_FONT_SIZE: f32 = 48.0;
+const LABEL_REFERENCE_DISTANCE: f32 = 700.0;
+const LABEL_WIDTH: f32 = 400.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chord {
+    /// The root pitch class, 0 (C) to 11 (B).
+    pub root: u8,
+    pub quality: &'static str,
+    /// The pitch class of the lowest note, if it isn't the root (an inversion).
+    pub bass: Option<u8>
+}
+
+impl Chord {
+    pub fn name(&self) -> String {
+        let mut name = format!("{}{}", PITCH_CLASS_NAMES[self.root as usize], self.quality);
+        if let Some(bass) = self.bass {
+            name.push('/');
+            name.push_str(PITCH_CLASS_NAMES[bass as usize]);
+        }
+        name
+    }
+}
+
+/// Identifies the chord formed by the given MIDI keys, including inversions.
+/// Returns `None` if fewer than three pitch classes are held or the set isn't a known chord.
+pub fn detect_chord(keys: &[u8]) -> Option<Chord> {
+    let bass = keys.iter().min()? % 12;
+    let mut pitch_classes: Vec<u8> = keys.iter().map(|key| key % 12).collect();
+    pitch_classes.sort_unstable();
+    pitch_classes.dedup();
+    if pitch_classes.len() < 3 {
+        return None;
+    }
+
+    for &(quality, intervals) in CHORD_QUALITIES {
+        if intervals.len() != pitch_classes.len() {
+            continue;
+        }
+        // Prefer the bass note as the root so root position chords aren't named as inversions of something else
+        let roots = std::iter::once(bass).chain(pitch_classes.iter().copied().filter(|&pitch_class| pitch_class != bass));
+        for root in roots {
+            let mut relative: Vec<u8> = pitch_classes.iter().map(|pitch_class| (pitch_class + 12 - root) % 12).collect();
+            relative.sort_unstable();
+            if relative == intervals {
+                return Some(Chord {
+                    root,
+                    quality,
+                    bass: (bass != root).then_some(bass)
+                });
+            }
+        }
+    }
+
+    None
+}
+
+#[derive(Component)]
+pub struct ChordLabel;
+
+fn setup(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            width: Val::Px(LABEL_WIDTH),
+            ..default()
+        },
+        Text::new(""),
+        TextFont {
+            font_size: LABEL_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        TextLayout::new_with_justify(JustifyText::Center),
+        ChordLabel
+    ));
+}
+
+/// Moves the label to wherever the top of the keyboard is on screen each frame, so it follows the tracked keyboard.
+fn update_chord_label(
+    note_state: Res<NoteState>,
+    keyboard_layout: Res<KeyboardLayout>,
+    keyboard_root: Single<&GlobalTransform, With<KeyboardRoot>>,
+    cameras: Query<(&Camera, &GlobalTransform, &OutputCamera), With<Camera3d>>,
+    mut labels: Query<(&mut Node, &mut Text, &mut TextFont, &mut Visibility), With<ChordLabel>>
+) {
+    // The label is drawn in the main window, so place it using that window's camera
+    let Some((camera, camera_transform, _)) = cameras.iter().find(|(_, _, output)| **output == OutputCamera::MainWindow) else { return };
+
+    // Anchor the label above the middle of the keyboard's back edge
+    let anchor = keyboard_root.transform_point(Vec3::new(0.0, LABEL_HEIGHT, keyboard_layout.geometry.key_back_z));
+    let viewport_position = camera.world_to_viewport(camera_transform, anchor).ok();
+    // Shrink the text with distance so it reads as part of the scene
+    let font_size = LABEL_FONT_SIZE * LABEL_REFERENCE_DISTANCE / camera_transform.translation().distance(anchor).max(1.0);
+
+    let keys: Vec<u8> = note_state.sounding_keys().collect();
+    let chord_name = detect_chord(&keys).map(|chord| chord.name()).unwrap_or_default();
+
+    for (mut node, mut text, mut font, mut visibility) in labels.iter_mut() {
+        // With the anchor behind the camera there's nowhere to put the label
+        visibility.set_if_neq(if viewport_position.is_some() { Visibility::Inherited } else { Visibility::Hidden });
+        if let Some(position) = viewport_position {
+            node.left = Val::Px(position.x - LABEL_WIDTH / 2.0);
+            node.top = Val::Px(position.y - font_size / 2.0);
+        }
+        if text.0 != chord_name {
+            text.0 = chord_name.clone();
+        }
+        if (font.font_size - font_size).abs() > 0.5 {
+            font.font_size = font_size;
+        }
+    }
+}
+
+pub struct ChordDisplayPlugin;
+
+impl Plugin for ChordDisplayPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_systems(Startup, setup)
+            .add_systems(Update, update_chord_label);
+    }
+}
@@ -0,0 +1,256 @@
+//! The command layer shared by every way of controlling the app: hotkeys, the command palette, the touch controls,
+//! voice commands, and the setlist pedal all send `AppCommand`s, which are applied in one place so they behave the
+//! same however they're given. `COMMANDS` lists every command with its name and hotkey. See `palette` for searching it.
+
+pub mod palette;
+
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::{DetectChanges, DetectChangesMut}, event::{Event, EventReader, EventWriter}, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}, input::{keyboard::KeyCode, ButtonInput}};
+use bevy_egui::input::EguiWantsInput;
+
+use crate::{keyboard::theme::{self, ThemeSettings}, midi::recorder::MidiRecorder, song::{playback::{self, SongPlayback}, LoopRegion, Song}, video::av_sync::AvSyncCalibration, virtual_camera::VirtualCameraSettings};
+
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppCommand {
+    TogglePlayback,
+    Play,
+    Pause,
+    /// Play from the start of the song.
+    Restart,
+    Slower,
+    Faster,
+    /// Repeat the measure playback is in.
+    LoopMeasure,
+    /// Start the loop (marker A) where playback is, keeping its end if that's later.
+    SetLoopStart,
+    /// End the loop (marker B) where playback is, keeping its start if that's earlier.
+    SetLoopEnd,
+    ClearLoop,
+    /// Start a loop where playback is, end it there if one was started, or clear it if one is set. For a single button.
+    CycleLoop,
+    /// Switch to the next theme in `theme::THEME_DIR`, after the default colors.
+    NextTheme,
+    /// Start measuring the audio/visual sync delay again.
+    RecalibrateAvSync,
+    /// Move on to the next song of the setlist. Handled by the setlist, and ignored without one.
+    NextSong,
+    ToggleVirtualCamera,
+    /// Open or close the piano roll of what was just played. Handled by the review window.
+    ToggleReview,
+    /// Start or stop placing decorations. Handled by the decorations.
+    ToggleDecorationPlacement,
+    /// Start or stop recording the keyboard to a MIDI file.
+    ToggleMidiRecording,
+    /// Open or close the visual automation editor. Handled by the editor.
+    ToggleAutomationEditor,
+    /// Open or close the song library browser. Handled by the browser.
+    ToggleLibrary,
+    /// Save crops of the marker candidates rejected in the next detected frame. Handled by the diagnostics.
+    SaveRejectedCandidates,
+    /// Open or close the history of finished practice runs. Handled by the history window.
+    TogglePracticeHistory,
+    /// Start or stop a practice run. Handled by the practice session.
+    TogglePractice,
+    /// Handled by the settings panel.
+    ToggleSettingsPanel,
+    /// Show or hide how long each pipeline stage takes. Handled by the diagnostics.
+    ToggleStageTiming,
+    /// Generate an exercise and load it as the song. Handled by the exercise generator.
+    GenerateScaleExercise,
+    GenerateSightReading,
+    /// Export the keyboard and camera to a glTF file. Handled by the scene export.
+    ExportScene,
+    /// Save the current frame and tracking state for a bug report. Handled by the diagnostics.
+    SaveSnapshot,
+    /// Start or stop recording a session to replay. Handled by the replay recorder.
+    ToggleSessionRecording,
+    /// Start or stop nudging the fiducial layout into place. Handled by the layout tuning.
+    ToggleLayoutTuning,
+    /// Start or stop recording the camera feed with the overlay to a video. Handled by the video recorder.
+    ToggleVideoRecording,
+    /// While placing decorations, change what's placed next and what it's attached to, and turn, resize, or remove
+    /// the last one placed. Handled by the decorations.
+    NextDecorationKind,
+    ToggleDecorationFrame,
+    TurnDecorationLeft,
+    TurnDecorationRight,
+    ShrinkDecoration,
+    GrowDecoration,
+    RemoveLastDecoration,
+    /// Resume or dismiss the session interrupted last time, while it's offered. Handled by the session recovery.
+    ResumeSession,
+    DismissSession
+}
+
+pub struct CommandInfo {
+    pub command: AppCommand,
+    /// What the command is called in the command palette.
+    pub name: &'static str,
+    pub hotkey: Option<KeyCode>
+}
+
+/// Every command, in the order the command palette lists them.
+pub static COMMANDS: [CommandInfo; 41] = [
+    CommandInfo { command: AppCommand::TogglePlayback, name: "Play/pause", hotkey: Some(KeyCode::Space) },
+    CommandInfo { command: AppCommand::Play, name: "Play", hotkey: None },
+    CommandInfo { command: AppCommand::Pause, name: "Pause", hotkey: None },
+    CommandInfo { command: AppCommand::Restart, name: "Restart song", hotkey: Some(KeyCode::Home) },
+    CommandInfo { command: AppCommand::Slower, name: "Slower", hotkey: Some(KeyCode::BracketLeft) },
+    CommandInfo { command: AppCommand::Faster, name: "Faster", hotkey: Some(KeyCode::BracketRight) },
+    CommandInfo { command: AppCommand::LoopMeasure, name: "Loop this measure", hotkey: None },
+    CommandInfo { command: AppCommand::SetLoopStart, name: "Set the loop start (A) here", hotkey: Some(KeyCode::KeyA) },
+    CommandInfo { command: AppCommand::SetLoopEnd, name: "Set the loop end (B) here", hotkey: Some(KeyCode::KeyB) },
+    CommandInfo { command: AppCommand::ClearLoop, name: "Clear loop", hotkey: None },
+    CommandInfo { command: AppCommand::CycleLoop, name: "Start, end, or clear the loop", hotkey: None },
+    CommandInfo { command: AppCommand::NextTheme, name: "Switch to the next theme", hotkey: None },
+    CommandInfo { command: AppCommand::RecalibrateAvSync, name: "Recalibrate audio/visual sync", hotkey: None },
+    CommandInfo { command: AppCommand::NextSong, name: "Next setlist song", hotkey: Some(KeyCode::KeyN) },
+    CommandInfo { command: AppCommand::ToggleVirtualCamera, name: "Start/stop the virtual camera", hotkey: None },
+    CommandInfo { command: AppCommand::ToggleReview, name: "Review what was just played", hotkey: Some(KeyCode::F4) },
+    CommandInfo { command: AppCommand::ToggleDecorationPlacement, name: "Place decorations", hotkey: Some(KeyCode::F2) },
+    CommandInfo { command: AppCommand::ToggleMidiRecording, name: "Start/stop recording to a MIDI file", hotkey: Some(KeyCode::F12) },
+    CommandInfo { command: AppCommand::ToggleAutomationEditor, name: "Edit visual automation", hotkey: None },
+    CommandInfo { command: AppCommand::ToggleLibrary, name: "Browse the song library", hotkey: Some(KeyCode::KeyL) },
+    CommandInfo { command: AppCommand::SaveRejectedCandidates, name: "Save images of rejected marker candidates", hotkey: None },
+    CommandInfo { command: AppCommand::TogglePracticeHistory, name: "Show the practice history", hotkey: Some(KeyCode::KeyH) },
+    CommandInfo { command: AppCommand::TogglePractice, name: "Start/stop a practice run", hotkey: Some(KeyCode::KeyP) },
+    CommandInfo { command: AppCommand::ToggleSettingsPanel, name: "Show/hide the settings panel", hotkey: Some(KeyCode::F1) },
+    CommandInfo { command: AppCommand::ToggleStageTiming, name: "Show/hide pipeline stage timings", hotkey: Some(KeyCode::F3) },
+    CommandInfo { command: AppCommand::GenerateScaleExercise, name: "Generate a scale exercise", hotkey: Some(KeyCode::F5) },
+    CommandInfo { command: AppCommand::GenerateSightReading, name: "Generate a sight-reading exercise", hotkey: Some(KeyCode::F6) },
+    CommandInfo { command: AppCommand::ExportScene, name: "Export the scene to glTF", hotkey: Some(KeyCode::F7) },
+    CommandInfo { command: AppCommand::SaveSnapshot, name: "Save a diagnostic snapshot", hotkey: Some(KeyCode::F8) },
+    CommandInfo { command: AppCommand::ToggleSessionRecording, name: "Start/stop recording a replay session", hotkey: Some(KeyCode::F9) },
+    CommandInfo { command: AppCommand::ToggleLayoutTuning, name: "Tune the fiducial layout", hotkey: Some(KeyCode::F10) },
+    CommandInfo { command: AppCommand::ToggleVideoRecording, name: "Start/stop recording video", hotkey: Some(KeyCode::F11) },
+    CommandInfo { command: AppCommand::NextDecorationKind, name: "Next decoration kind", hotkey: Some(KeyCode::Tab) },
+    CommandInfo { command: AppCommand::ToggleDecorationFrame, name: "Attach decorations to the keyboard/room", hotkey: Some(KeyCode::KeyR) },
+    CommandInfo { command: AppCommand::TurnDecorationLeft, name: "Turn the last decoration left", hotkey: Some(KeyCode::KeyQ) },
+    CommandInfo { command: AppCommand::TurnDecorationRight, name: "Turn the last decoration right", hotkey: Some(KeyCode::KeyE) },
+    CommandInfo { command: AppCommand::ShrinkDecoration, name: "Shrink the last decoration", hotkey: Some(KeyCode::Minus) },
+    CommandInfo { command: AppCommand::GrowDecoration, name: "Grow the last decoration", hotkey: Some(KeyCode::Equal) },
+    CommandInfo { command: AppCommand::RemoveLastDecoration, name: "Remove the last decoration", hotkey: Some(KeyCode::Backspace) },
+    CommandInfo { command: AppCommand::ResumeSession, name: "Resume the interrupted session", hotkey: Some(KeyCode::Enter) },
+    CommandInfo { command: AppCommand::DismissSession, name: "Dismiss the interrupted session", hotkey: Some(KeyCode::Escape) }
+];
+
+/// Where the loop `AppCommand::CycleLoop` started will begin, until it's ended. Forgotten when the song changes.
+#[derive(Resource, Default)]
+pub struct PendingLoopStart(pub Option<f64>);
+
+/// The theme after `current` in the themes found, with `None` for the default colors before the first one.
+fn next_theme(themes: &[String], current: Option<&str>) -> Option<String> {
+    // A theme that's no longer found starts the cycle again from the first one
+    let next = current.and_then(|current| themes.iter().position(|theme| theme == current)).map_or(0, |index| index + 1);
+    themes.get(next).cloned()
+}
+
+fn send_hotkey_commands(
+    keys: Res<ButtonInput<KeyCode>>,
+    egui_input: Option<Res<EguiWantsInput>>,
+    mut commands: EventWriter<AppCommand>
+) {
+    // Typing in a text field isn't a hotkey
+    if egui_input.is_some_and(|input| input.wants_any_keyboard_input()) {
+        return;
+    }
+    // Hotkeys are single keys, so chords like Ctrl+P are left to whatever handles them
+    if keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight, KeyCode::AltLeft, KeyCode::AltRight, KeyCode::SuperLeft, KeyCode::SuperRight]) {
+        return;
+    }
+    for info in COMMANDS.iter() {
+        if info.hotkey.is_some_and(|key| keys.just_pressed(key)) {
+            commands.write(info.command);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_app_commands(
+    mut commands: EventReader<AppCommand>,
+    mut playback: ResMut<SongPlayback>,
+    mut song: ResMut<Song>,
+    mut loop_start: ResMut<PendingLoopStart>,
+    mut theme_settings: ResMut<ThemeSettings>,
+    mut av_sync_calibration: ResMut<AvSyncCalibration>,
+    mut virtual_camera_settings: ResMut<VirtualCameraSettings>,
+    mut midi_recorder: ResMut<MidiRecorder>
+) {
+    if song.is_changed() {
+        loop_start.0 = None;
+    }
+    for command in commands.read() {
+        match command {
+            AppCommand::TogglePlayback => playback.playing = !playback.playing,
+            AppCommand::Play => playback.playing = true,
+            AppCommand::Pause => playback.playing = false,
+            AppCommand::Restart => playback.restart(),
+            AppCommand::Slower => playback.step_speed(-1.0),
+            AppCommand::Faster => playback.step_speed(1.0),
+            // Changing the loop isn't a new song, so don't let playback reset for it
+            AppCommand::LoopMeasure => {
+                let measure = song.tempo_map.measure_at_seconds(playback.position);
+                let start = song.tempo_map.measure_to_seconds(measure);
+                let end = song.tempo_map.measure_to_seconds(measure + 1);
+                song.bypass_change_detection().loop_region = Some(LoopRegion { start, end });
+            }
+            AppCommand::SetLoopStart => {
+                let start = playback.position.max(0.0);
+                let end = song.loop_region.map(|region| region.end).filter(|&end| end > start).unwrap_or(song.duration);
+                song.bypass_change_detection().loop_region = Some(LoopRegion { start, end });
+            }
+            AppCommand::SetLoopEnd => {
+                let end = playback.position;
+                let start = song.loop_region.map(|region| region.start).filter(|&start| start < end).unwrap_or(0.0);
+                song.bypass_change_detection().loop_region = Some(LoopRegion { start, end });
+            }
+            AppCommand::ClearLoop => song.bypass_change_detection().loop_region = None,
+            AppCommand::CycleLoop => {
+                let song = song.bypass_change_detection();
+                if song.loop_region.is_some() {
+                    song.loop_region = None;
+                } else if let Some(start) = loop_start.0.take() {
+                    let end = playback.position;
+                    song.loop_region = Some(LoopRegion { start: start.min(end), end: start.max(end) });
+                } else {
+                    loop_start.0 = Some(playback.position);
+                }
+            }
+            AppCommand::NextTheme => theme_settings.path = next_theme(&theme::available_themes(), theme_settings.path.as_deref()),
+            AppCommand::RecalibrateAvSync => av_sync_calibration.start(),
+            AppCommand::NextSong | AppCommand::ToggleReview | AppCommand::ToggleDecorationPlacement | AppCommand::ToggleAutomationEditor | AppCommand::ToggleLibrary
+                | AppCommand::SaveRejectedCandidates | AppCommand::TogglePracticeHistory | AppCommand::TogglePractice | AppCommand::ToggleSettingsPanel
+                | AppCommand::ToggleStageTiming | AppCommand::GenerateScaleExercise | AppCommand::GenerateSightReading | AppCommand::ExportScene
+                | AppCommand::SaveSnapshot | AppCommand::ToggleSessionRecording | AppCommand::ToggleLayoutTuning | AppCommand::ToggleVideoRecording
+                | AppCommand::NextDecorationKind | AppCommand::ToggleDecorationFrame | AppCommand::TurnDecorationLeft | AppCommand::TurnDecorationRight
+                | AppCommand::ShrinkDecoration | AppCommand::GrowDecoration | AppCommand::RemoveLastDecoration | AppCommand::ResumeSession
+                | AppCommand::DismissSession => {}
+            AppCommand::ToggleVirtualCamera => virtual_camera_settings.enabled = !virtual_camera_settings.enabled,
+            AppCommand::ToggleMidiRecording => midi_recorder.recording = !midi_recorder.recording
+        }
+    }
+}
+
+pub struct CommandPlugin;
+
+impl Plugin for CommandPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<AppCommand>()
+            .init_resource::<PendingLoopStart>()
+            .add_systems(Update, (send_hotkey_commands, run_app_commands).chain().before(playback::advance_playback));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_theme_cycles_through_the_default_colors() {
+        let themes = ["a.json".to_string(), "b.json".to_string()];
+        assert_eq!(next_theme(&themes, None), Some("a.json".to_string()));
+        assert_eq!(next_theme(&themes, Some("a.json")), Some("b.json".to_string()));
+        assert_eq!(next_theme(&themes, Some("b.json")), None);
+        assert_eq!(next_theme(&themes, Some("removed.json")), Some("a.json".to_string()));
+    }
+}
@@ -0,0 +1,136 @@
+//! A searchable list of every command, opened with Ctrl+P. Typing filters the commands by name, the arrow keys choose
+//! one, and Enter runs it. Escape closes the palette. Hotkeys are blocked while it's open, so typing doesn't trigger them.
+
+use bevy::{app::{App, Plugin, PreUpdate, Update}, ecs::{event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}, input::{keyboard::KeyCode, ButtonInput, InputSystem}};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use super::{run_app_commands, AppCommand, CommandInfo, COMMANDS};
+
+#[derive(Resource, Default)]
+pub struct CommandPalette {
+    pub open: bool,
+    query: String,
+    /// The index of the chosen command among the ones matching the query.
+    selected: usize
+}
+
+/// Whether every word of the query is part of the command's name, ignoring case.
+fn matches_query(name: &str, query: &str) -> bool {
+    let name = name.to_lowercase();
+    query.to_lowercase().split_whitespace().all(|word| name.contains(word))
+}
+
+fn open_command_palette(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut palette: ResMut<CommandPalette>
+) {
+    let control = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    if control && keys.just_pressed(KeyCode::KeyP) && !palette.open {
+        *palette = CommandPalette { open: true, ..Default::default() };
+    }
+}
+
+/// Clears the keyboard input while the palette is open, before any hotkeys see it. egui reads its own input events.
+fn block_hotkeys(
+    palette: Res<CommandPalette>,
+    mut keys: ResMut<ButtonInput<KeyCode>>
+) {
+    if palette.open {
+        keys.reset_all();
+    }
+}
+
+fn draw_command_palette(
+    mut contexts: EguiContexts,
+    mut palette: ResMut<CommandPalette>,
+    mut commands: EventWriter<AppCommand>
+) {
+    if !palette.open {
+        return;
+    }
+    let ctx = contexts.ctx_mut();
+    let palette = palette.as_mut();
+
+    // Taken before the text field sees them, so they move the selection instead of the cursor
+    let (up, down, enter, escape) = ctx.input_mut(|input| (
+        input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+        input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+        input.consume_key(egui::Modifiers::NONE, egui::Key::Enter),
+        input.consume_key(egui::Modifiers::NONE, egui::Key::Escape)
+    ));
+
+    let matching: Vec<&CommandInfo> = COMMANDS.iter().filter(|info| matches_query(info.name, &palette.query)).collect();
+    if down {
+        palette.selected += 1;
+    }
+    if up {
+        palette.selected = palette.selected.saturating_sub(1);
+    }
+    palette.selected = palette.selected.min(matching.len().saturating_sub(1));
+
+    let mut chosen = None;
+    egui::Window::new("Commands")
+        .title_bar(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 80.0])
+        .show(ctx, |ui| {
+            let search = ui.add(egui::TextEdit::singleline(&mut palette.query).hint_text("Type a command").desired_width(360.0));
+            search.request_focus();
+            if search.changed() {
+                palette.selected = 0;
+            }
+
+            ui.separator();
+            if matching.is_empty() {
+                ui.label("No matching commands");
+            }
+            for (index, info) in matching.iter().enumerate() {
+                let label = match info.hotkey {
+                    Some(key) => format!("{}  ({:?})", info.name, key),
+                    None => info.name.to_string()
+                };
+                if ui.selectable_label(index == palette.selected, label).clicked() {
+                    chosen = Some(info.command);
+                }
+            }
+        });
+
+    if enter {
+        chosen = matching.get(palette.selected).map(|info| info.command);
+    }
+    if let Some(command) = chosen {
+        commands.write(command);
+        palette.open = false;
+    }
+    if escape {
+        palette.open = false;
+    }
+}
+
+pub struct CommandPalettePlugin;
+
+impl Plugin for CommandPalettePlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+
+        app
+            .init_resource::<CommandPalette>()
+            .add_systems(PreUpdate, block_hotkeys.after(InputSystem))
+            .add_systems(Update, (open_command_palette, draw_command_palette).chain().before(run_app_commands));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_word_of_the_query_must_match() {
+        assert!(matches_query("Loop this measure", ""));
+        assert!(matches_query("Loop this measure", "loop MEAS"));
+        assert!(matches_query("Recalibrate audio/visual sync", "sync recal"));
+        assert!(!matches_query("Clear loop", "loop measure"));
+    }
+}
@@ -0,0 +1,896 @@
+//! Loads settings from `config.toml` at startup and re-applies them whenever the file changes,
+//! so tuning doesn't require restarting the app or the OpenCV stream.
+
+use std::{error::Error, fs, path::Path, time::SystemTime};
+
+use bevy::{app::{App, Plugin, Update}, color::{Color, Srgba}, ecs::{resource::Resource, system::{Commands, Res, ResMut}, world::World}, time::Time};
+use serde::Deserialize;
+
+use crate::{audio::SynthSettings, background::{framing::{AutoFramingSettings, FitMode}, undistort::UndistortSettings}, camera_cuts::{CameraCutSettings, CameraShot}, decorations::physics::PhysicsDecorationSettings, dual_output::DualOutputSettings, keyboard::{clutter::ClutterSettings, guide::{self, ChordShape, GuideMode, KeyGuideSettings, Scale}, hands::{HandDetection, HandSplitSettings}, profile::{InstrumentProfile, KeyboardSize}, theme::ThemeSettings, trails::{NoteTrailSettings, MIN_TRAIL_SPEED}, zones::{RegisterZone, RegisterZoneSettings}, KeyPalette}, midi::{latency::LatencyCompensation, output::MidiOutputSettings, MidiInputSettings}, practice::PracticeSettings, recording::CompositeRecordingSettings, remote::RemoteControlSettings, song::{fingering::{FingerHintMode, FingerHintSettings}, playback::{self, SongPlayback}, waterfall::{self, WaterfallSettings}, Hand}, touch_controls::TouchControlSettings, updates::UpdateCheckSettings, video::{aruco_camera::{DetectionSettings, FiducialLayout, MarkerDictionary, PnpMethod}, gpu_prefilter::GpuPrefilterSettings, DropPolicy, FrameQueueSettings, VideoSource, VideoSourceConfig}, virtual_camera::VirtualCameraSettings, voice::VoiceCommandSettings};
+
+pub static CONFIG_PATH: &str = "config.toml";
+/// How often the config file's modification time is checked, in seconds.
+const POLL_INTERVAL: f32 = 1.0;
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub camera: CameraConfig,
+    pub fiducials: FiducialConfig,
+    pub detection: DetectionConfig,
+    pub midi: MidiConfig,
+    pub colors: ColorConfig,
+    pub keyboard: KeyboardConfig,
+    pub output: OutputConfig,
+    pub song: SongConfig,
+    pub audio: AudioConfig,
+    pub touch: TouchConfig,
+    pub recording: RecordingConfig,
+    pub voice: VoiceConfig,
+    pub virtual_camera: VirtualCameraConfig,
+    pub remote: RemoteConfig,
+    pub updates: UpdatesConfig,
+    pub physics: PhysicsConfig,
+    pub camera_cuts: CameraCutsConfig,
+    pub trails: TrailsConfig,
+    pub hands: HandsConfig,
+    pub guide: GuideConfig,
+    pub zones: ZonesConfig,
+    pub clutter: ClutterConfig
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CameraConfig {
+    /// A stream URL, or the path of a recorded video file.
+    pub source: Option<String>,
+    /// Whether video files restart when they end.
+    pub looping: bool,
+    /// The capture mode to request from local cameras.
+    #[serde(flatten)]
+    pub mode: VideoSourceConfig,
+    /// What happens to frames when the app can't keep up. See `FrameQueueSettings`.
+    pub frame_policy: DropPolicy,
+    pub frame_queue_capacity: usize,
+    pub keep_all_while_recording: bool,
+    /// Remove lens distortion from the background using the calibration. See `background::undistort`.
+    pub undistort: bool
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        let queue = FrameQueueSettings::default();
+        Self {
+            source: None,
+            looping: true,
+            mode: VideoSourceConfig::default(),
+            frame_policy: queue.policy,
+            frame_queue_capacity: queue.capacity,
+            keep_all_while_recording: queue.keep_all_while_recording,
+            undistort: UndistortSettings::default().enabled
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FiducialConfig {
+    /// The path of a JSON fiducial layout. See `FiducialLayout::load`.
+    pub layout: Option<String>,
+    /// The marker dictionary, overriding the layout's.
+    pub dictionary: Option<MarkerDictionary>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct DetectionConfig {
+    /// Refine detected corners to subpixel accuracy before solving the pose.
+    pub subpixel_refinement: Option<bool>,
+    /// Scan part of very wide frames per detection. See `DetectionSettings::time_slicing`.
+    pub time_slicing: Option<bool>,
+    pub slices: Option<usize>,
+    pub slice_min_width: Option<i32>,
+    /// Find marker candidates on the GPU and only search around them. See `video::gpu_prefilter`.
+    pub gpu_prefilter: Option<bool>,
+    /// The `solvePnP` method when one marker is detected: "iterative", "epnp", "sqpnp", "ippe", or "ippe_square".
+    pub pnp_method: Option<PnpMethod>,
+    /// The `solvePnP` method when several markers are detected.
+    pub board_pnp_method: Option<PnpMethod>,
+    /// Reject outlying corners with RANSAC when several markers are detected.
+    pub ransac: Option<bool>,
+    pub ransac_iterations: Option<i32>,
+    /// The inlier threshold in pixels.
+    pub ransac_reprojection_error: Option<f32>,
+    pub ransac_confidence: Option<f64>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct MidiConfig {
+    /// Part of the name of the MIDI input port to use.
+    pub port: Option<String>,
+    /// Play the song on a MIDI output, like the digital piano itself.
+    pub play_to_output: bool,
+    /// Part of the name of the MIDI output port to use. Defaults to the first available port.
+    pub output_port: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ColorConfig {
+    /// Hex colors, like "#33ccff".
+    pub pressed: Option<String>,
+    pub sustained: Option<String>,
+    /// The path of a JSON color theme. See `keyboard::theme`.
+    pub theme: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct KeyboardConfig {
+    /// The path of a JSON instrument profile. See `InstrumentProfile::load`.
+    pub profile: Option<String>,
+    /// A single keyboard with this many keys: 88, 76, 61, or 49. Ignored when `profile` is set.
+    pub size: Option<KeyboardSize>,
+    /// Override the key range of the profile's lowest manual.
+    pub lowest_key: Option<u8>,
+    pub highest_key: Option<u8>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Open separate clean feed and overlay feed windows.
+    pub dual: bool,
+    /// How the camera feed is fitted to outputs with a different aspect ratio.
+    pub fit: Option<FitMode>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SongConfig {
+    /// Seconds of upcoming notes shown in the waterfall.
+    pub look_ahead: Option<f64>,
+    /// The playback speed multiplier, from 0.25 to 2.
+    pub speed: Option<f64>,
+    /// Beats counted in before each repetition of a loop.
+    pub count_in: Option<u32>,
+    /// Wait at each note while practicing until it's played.
+    pub wait_for_input: Option<bool>,
+    /// Seconds to delay the waterfall by, to match the camera's delay. Calibrate it from the settings panel.
+    pub video_delay: Option<f64>,
+    /// Draw measure and beat lines in the waterfall.
+    pub beat_lines: Option<bool>,
+    /// When to show finger numbers on the notes: "off", "practice", or "always".
+    pub finger_hints: Option<FingerHintMode>
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// The `.sf2` soundfont for the built-in synthesizer. The synthesizer is off without one.
+    pub soundfont: Option<String>,
+    pub play_song: Option<bool>,
+    pub play_live_input: Option<bool>,
+    pub volume: Option<f32>,
+    /// Fixed programs for some MIDI channels.
+    pub programs: Vec<ProgramMapping>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TouchConfig {
+    /// Show large transport, tempo, and loop buttons for a touch monitor.
+    pub enabled: bool,
+    pub button_size: f32
+}
+
+impl Default for TouchConfig {
+    fn default() -> Self {
+        let settings = TouchControlSettings::default();
+        Self {
+            enabled: settings.enabled,
+            button_size: settings.button_size
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// The size of videos recorded with F11, in pixels.
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        let settings = CompositeRecordingSettings::default();
+        Self {
+            width: settings.width,
+            height: settings.height,
+            fps: settings.fps
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct VoiceConfig {
+    /// Listen for spoken commands. Needs the `voice` feature.
+    pub enabled: bool,
+    /// The directory of the Vosk speech model.
+    pub model: String
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        let settings = VoiceCommandSettings::default();
+        Self {
+            enabled: settings.enabled,
+            model: settings.model
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct VirtualCameraConfig {
+    /// Send the composited output to a virtual webcam.
+    pub enabled: bool,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    /// The v4l2loopback device on Linux.
+    pub device: String
+}
+
+impl Default for VirtualCameraConfig {
+    fn default() -> Self {
+        let settings = VirtualCameraSettings::default();
+        Self {
+            enabled: settings.enabled,
+            width: settings.width,
+            height: settings.height,
+            fps: settings.fps,
+            device: settings.device
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RemoteConfig {
+    /// Serve the remote control page.
+    pub enabled: bool,
+    /// Let phones and other devices on the network open the page. Anyone on the network can then control the app.
+    pub lan: bool,
+    pub port: u16
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        let settings = RemoteControlSettings::default();
+        Self {
+            enabled: settings.enabled,
+            lan: settings.lan,
+            port: settings.port
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct UpdatesConfig {
+    /// Check the release feed for a newer version at startup.
+    pub check: bool,
+    pub feed: String
+}
+
+impl Default for UpdatesConfig {
+    fn default() -> Self {
+        let settings = UpdateCheckSettings::default();
+        Self {
+            check: settings.enabled,
+            feed: settings.feed
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PhysicsConfig {
+    /// Scatter toys on the keyboard lid that bounce to the notes played.
+    pub enabled: bool,
+    pub count: usize,
+    pub bounce: f32,
+    pub reach: f32
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        let settings = PhysicsDecorationSettings::default();
+        Self {
+            enabled: settings.enabled,
+            count: settings.count,
+            bounce: settings.bounce,
+            reach: settings.reach
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct CameraCutsConfig {
+    /// Open a spectator window that cuts between virtual shots of the keyboard.
+    pub enabled: bool,
+    /// Record the shots with F11 instead of the camera's view.
+    pub record: bool,
+    pub on_markers: bool,
+    pub on_program_changes: bool,
+    pub shots: Vec<CameraShot>
+}
+
+impl Default for CameraCutsConfig {
+    fn default() -> Self {
+        let settings = CameraCutSettings::default();
+        Self {
+            enabled: settings.enabled,
+            record: settings.record,
+            on_markers: settings.on_markers,
+            on_program_changes: settings.on_program_changes,
+            shots: settings.shots
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TrailsConfig {
+    /// Glowing bars that rise from the played keys and fade.
+    pub enabled: bool,
+    pub speed: f32,
+    pub height: f32
+}
+
+impl Default for TrailsConfig {
+    fn default() -> Self {
+        let settings = NoteTrailSettings::default();
+        Self {
+            enabled: settings.enabled,
+            speed: settings.speed,
+            height: settings.height
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HandsConfig {
+    /// Color notes by the hand playing them.
+    pub enabled: bool,
+    pub detection: HandDetection,
+    pub split_key: u8,
+    pub left_channel: u8,
+    /// Hex colors, like "#ff8c33".
+    pub left_color: Option<String>,
+    pub right_color: Option<String>,
+    /// "left" or "right" to practice one hand alone, with the other hand's notes played down.
+    pub focus: Option<String>
+}
+
+impl Default for HandsConfig {
+    fn default() -> Self {
+        let settings = HandSplitSettings::default();
+        Self {
+            enabled: settings.enabled,
+            detection: settings.detection,
+            split_key: settings.split_key,
+            left_channel: settings.left_channel,
+            left_color: None,
+            right_color: None,
+            focus: None
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ZonesConfig {
+    /// Mark the register zones on the keyboard, and color and scale effects by them.
+    pub enabled: bool,
+    /// The zones, replacing the default bass, middle, and treble ones.
+    pub zones: Option<Vec<ZoneConfig>>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ZoneConfig {
+    pub name: String,
+    /// MIDI key numbers, where 60 is middle C.
+    pub lowest_key: u8,
+    pub highest_key: u8,
+    /// A hex color, like "#ff8033".
+    pub color: String,
+    #[serde(default = "default_zone_intensity")]
+    pub intensity: f32
+}
+
+fn default_zone_intensity() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ClutterConfig {
+    /// Merge and fade the notes of passages denser than `max_notes_per_second`.
+    pub enabled: bool,
+    pub max_notes_per_second: f32
+}
+
+impl Default for ClutterConfig {
+    fn default() -> Self {
+        let settings = ClutterSettings::default();
+        Self {
+            enabled: settings.enabled,
+            max_notes_per_second: settings.max_notes_per_second
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct GuideConfig {
+    pub mode: GuideMode,
+    /// The root's name, like "C", "F#", or "Bb".
+    pub root: String,
+    pub scale: Scale,
+    pub chord: ChordShape,
+    pub octave: u8,
+    /// Hex colors with alpha, like "#4de68059".
+    pub color: Option<String>,
+    pub root_color: Option<String>
+}
+
+impl Default for GuideConfig {
+    fn default() -> Self {
+        let settings = KeyGuideSettings::default();
+        Self {
+            mode: settings.mode,
+            root: "C".to_string(),
+            scale: settings.scale,
+            chord: settings.chord,
+            octave: settings.octave,
+            color: None,
+            root_color: None
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ProgramMapping {
+    /// The MIDI channel, 0-15.
+    pub channel: u8,
+    /// The General MIDI program, 0-127.
+    pub program: u8
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Loads `CONFIG_PATH`, falling back to the defaults if it's missing or invalid.
+    pub fn load_or_default() -> Config {
+        let path = Path::new(CONFIG_PATH);
+        if !path.exists() {
+            return Config::default();
+        }
+        Config::load(path).unwrap_or_else(|err| {
+            eprintln!("Failed to load {}: {}", CONFIG_PATH, err);
+            Config::default()
+        })
+    }
+
+    /// Applies the config to the world's resources, only touching resources whose values actually change.
+    /// With a previous config, only sections that differ from it are applied, so values set some other way
+    /// (like command line flags) are kept until that section of the file is edited.
+    /// Without one, resources that already exist are left alone for the same reason.
+    pub fn apply(&self, previous: Option<&Config>, world: &mut World) {
+        let should_apply = |section_changed: bool, exists: bool| match previous {
+            Some(_) => section_changed,
+            None => !exists
+        };
+
+        if let Some(source) = &self.camera.source {
+            if should_apply(previous.is_none_or(|previous| previous.camera != self.camera), world.contains_resource::<VideoSource>()) {
+                let source = if Path::new(source).is_file() {
+                    VideoSource::File { path: source.clone(), looping: self.camera.looping }
+                } else {
+                    VideoSource::Stream(source.clone())
+                };
+                set_if_different(world, source);
+            }
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.camera.mode != self.camera.mode), world.contains_resource::<VideoSourceConfig>()) {
+            set_if_different(world, self.camera.mode.clone());
+        }
+
+        let queue = FrameQueueSettings {
+            policy: self.camera.frame_policy,
+            capacity: self.camera.frame_queue_capacity.max(1),
+            keep_all_while_recording: self.camera.keep_all_while_recording
+        };
+        let queue_changed = previous.is_none_or(|previous| {
+            (previous.camera.frame_policy, previous.camera.frame_queue_capacity, previous.camera.keep_all_while_recording)
+                != (self.camera.frame_policy, self.camera.frame_queue_capacity, self.camera.keep_all_while_recording)
+        });
+        if should_apply(queue_changed, world.contains_resource::<FrameQueueSettings>()) {
+            set_if_different(world, queue);
+        }
+        if should_apply(previous.is_none_or(|previous| previous.camera.undistort != self.camera.undistort), world.contains_resource::<UndistortSettings>()) {
+            set_if_different(world, UndistortSettings { enabled: self.camera.undistort });
+        }
+
+        let fiducials_set = self.fiducials.layout.is_some() || self.fiducials.dictionary.is_some();
+        if fiducials_set && should_apply(previous.is_none_or(|previous| previous.fiducials != self.fiducials), world.contains_resource::<FiducialLayout>()) {
+            let layout = match &self.fiducials.layout {
+                Some(path) => FiducialLayout::load(path)
+                    .inspect_err(|err| eprintln!("Failed to load fiducial layout {}: {}", path, err))
+                    .ok(),
+                None => Some(world.get_resource::<FiducialLayout>().cloned().unwrap_or_default())
+            };
+            if let Some(mut layout) = layout {
+                layout.dictionary = self.fiducials.dictionary.unwrap_or(layout.dictionary);
+                set_if_different(world, layout);
+            }
+        }
+
+        if let Some(enabled) = self.detection.gpu_prefilter {
+            if should_apply(previous.is_none_or(|previous| previous.detection.gpu_prefilter != self.detection.gpu_prefilter), world.contains_resource::<GpuPrefilterSettings>()) {
+                let mut settings = world.get_resource::<GpuPrefilterSettings>().cloned().unwrap_or_default();
+                settings.enabled = enabled;
+                set_if_different(world, settings);
+            }
+        }
+
+        if self.detection != DetectionConfig::default() && should_apply(previous.is_none_or(|previous| previous.detection != self.detection), world.contains_resource::<DetectionSettings>()) {
+            let mut settings = world.get_resource::<DetectionSettings>().cloned().unwrap_or_default();
+            settings.subpixel_refinement = self.detection.subpixel_refinement.unwrap_or(settings.subpixel_refinement);
+            settings.time_slicing = self.detection.time_slicing.unwrap_or(settings.time_slicing);
+            settings.slices = self.detection.slices.map_or(settings.slices, |slices| slices.max(1));
+            settings.slice_min_width = self.detection.slice_min_width.unwrap_or(settings.slice_min_width);
+            let pnp = &mut settings.pnp;
+            pnp.single_marker_method = self.detection.pnp_method.unwrap_or(pnp.single_marker_method);
+            pnp.board_method = self.detection.board_pnp_method.unwrap_or(pnp.board_method);
+            pnp.ransac = self.detection.ransac.unwrap_or(pnp.ransac);
+            pnp.ransac_iterations = self.detection.ransac_iterations.map_or(pnp.ransac_iterations, |iterations| iterations.max(1));
+            pnp.ransac_reprojection_error = self.detection.ransac_reprojection_error.map_or(pnp.ransac_reprojection_error, |error| error.max(0.1));
+            pnp.ransac_confidence = self.detection.ransac_confidence.map_or(pnp.ransac_confidence, |confidence| confidence.clamp(0.0, 1.0));
+            set_if_different(world, settings);
+        }
+
+        if self.midi.port.is_some() && should_apply(previous.is_none_or(|previous| previous.midi != self.midi), world.contains_resource::<MidiInputSettings>()) {
+            set_if_different(world, MidiInputSettings { port_name: self.midi.port.clone() });
+        }
+
+        if should_apply(previous.is_none_or(|previous| (previous.midi.play_to_output, &previous.midi.output_port) != (self.midi.play_to_output, &self.midi.output_port)), world.contains_resource::<MidiOutputSettings>()) {
+            set_if_different(world, MidiOutputSettings { enabled: self.midi.play_to_output, port_name: self.midi.output_port.clone() });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.colors != self.colors), world.contains_resource::<KeyPalette>()) {
+            let mut palette = world.get_resource::<KeyPalette>().cloned().unwrap_or_default();
+            if let Some(color) = self.colors.pressed.as_deref().and_then(parse_color) {
+                palette.pressed = color;
+            }
+            if let Some(color) = self.colors.sustained.as_deref().and_then(parse_color) {
+                palette.sustained = color;
+            }
+            set_if_different(world, palette);
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.colors.theme != self.colors.theme), world.contains_resource::<ThemeSettings>()) {
+            set_if_different(world, ThemeSettings { path: self.colors.theme.clone() });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.keyboard != self.keyboard), world.contains_resource::<InstrumentProfile>()) {
+            let profile = match (&self.keyboard.profile, self.keyboard.size) {
+                (Some(path), _) => InstrumentProfile::load(path).map_err(|err| eprintln!("Failed to load instrument profile {}: {}", path, err)).ok(),
+                (None, Some(size)) => Some(InstrumentProfile::preset(size)),
+                (None, None) => Some(InstrumentProfile::default())
+            };
+            if let Some(mut profile) = profile {
+                match profile.manuals.first_mut() {
+                    Some(manual) => {
+                        manual.lowest_key = self.keyboard.lowest_key.unwrap_or(manual.lowest_key);
+                        manual.highest_key = self.keyboard.highest_key.unwrap_or(manual.highest_key);
+                        if manual.lowest_key < manual.highest_key && manual.highest_key < 128 {
+                            set_if_different(world, profile);
+                        } else {
+                            eprintln!("Ignoring invalid keyboard range {}-{} in {}", manual.lowest_key, manual.highest_key, CONFIG_PATH);
+                        }
+                    }
+                    // A drum-only profile has no key range to override
+                    None => set_if_different(world, profile)
+                }
+            }
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.output != self.output), world.contains_resource::<DualOutputSettings>()) {
+            set_if_different(world, DualOutputSettings { enabled: self.output.dual });
+        }
+        if let Some(fit) = self.output.fit {
+            if should_apply(previous.is_none_or(|previous| previous.output.fit != self.output.fit), world.contains_resource::<AutoFramingSettings>()) {
+                let mut settings = world.get_resource::<AutoFramingSettings>().cloned().unwrap_or_default();
+                settings.fit = fit;
+                set_if_different(world, settings);
+            }
+        }
+
+        // Both settings share a resource, so they're applied together
+        let waterfall_changed = previous.is_none_or(|previous| (previous.song.look_ahead, previous.song.beat_lines) != (self.song.look_ahead, self.song.beat_lines));
+        if (self.song.look_ahead.is_some() || self.song.beat_lines.is_some()) && should_apply(waterfall_changed, world.contains_resource::<WaterfallSettings>()) {
+            let mut settings = world.get_resource::<WaterfallSettings>().cloned().unwrap_or_default();
+            if let Some(look_ahead) = self.song.look_ahead {
+                settings.look_ahead = look_ahead.clamp(*waterfall::LOOK_AHEAD.start(), *waterfall::LOOK_AHEAD.end());
+            }
+            settings.beat_lines = self.song.beat_lines.unwrap_or(settings.beat_lines);
+            set_if_different(world, settings);
+        }
+
+        if let Some(speed) = self.song.speed {
+            if should_apply(previous.is_none_or(|previous| previous.song.speed != self.song.speed), world.contains_resource::<SongPlayback>()) {
+                let speed = speed.clamp(playback::MIN_SPEED, playback::MAX_SPEED);
+                match world.get_resource_mut::<SongPlayback>() {
+                    Some(mut playback) => playback.speed = speed,
+                    None => world.insert_resource(SongPlayback { speed, ..Default::default() })
+                }
+            }
+        }
+
+        if let Some(count_in_beats) = self.song.count_in {
+            if should_apply(previous.is_none_or(|previous| previous.song.count_in != self.song.count_in), world.contains_resource::<SongPlayback>()) {
+                match world.get_resource_mut::<SongPlayback>() {
+                    Some(mut playback) => playback.count_in_beats = count_in_beats,
+                    None => world.insert_resource(SongPlayback { count_in_beats, ..Default::default() })
+                }
+            }
+        }
+
+        if let Some(wait_for_input) = self.song.wait_for_input {
+            if should_apply(previous.is_none_or(|previous| previous.song.wait_for_input != self.song.wait_for_input), world.contains_resource::<PracticeSettings>()) {
+                let mut settings = world.get_resource::<PracticeSettings>().cloned().unwrap_or_default();
+                settings.wait_for_input = wait_for_input;
+                set_if_different(world, settings);
+            }
+        }
+
+        if let Some(video_delay) = self.song.video_delay {
+            if should_apply(previous.is_none_or(|previous| previous.song.video_delay != self.song.video_delay), world.contains_resource::<LatencyCompensation>()) {
+                let mut compensation = world.get_resource::<LatencyCompensation>().cloned().unwrap_or_default();
+                compensation.video = video_delay.max(0.0);
+                set_if_different(world, compensation);
+            }
+        }
+
+        if let Some(mode) = self.song.finger_hints {
+            if should_apply(previous.is_none_or(|previous| previous.song.finger_hints != self.song.finger_hints), world.contains_resource::<FingerHintSettings>()) {
+                set_if_different(world, FingerHintSettings { mode });
+            }
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.audio != self.audio), world.contains_resource::<SynthSettings>()) {
+            let mut settings = world.get_resource::<SynthSettings>().cloned().unwrap_or_default();
+            settings.enabled = self.audio.soundfont.is_some();
+            settings.soundfont = self.audio.soundfont.clone();
+            settings.play_song = self.audio.play_song.unwrap_or(settings.play_song);
+            settings.play_live_input = self.audio.play_live_input.unwrap_or(settings.play_live_input);
+            settings.volume = self.audio.volume.map_or(settings.volume, |volume| volume.clamp(0.0, 1.0));
+            settings.programs = [None; 16];
+            for mapping in &self.audio.programs {
+                if mapping.channel < 16 && mapping.program < 128 {
+                    settings.programs[mapping.channel as usize] = Some(mapping.program);
+                } else {
+                    eprintln!("Ignoring invalid program mapping {:?} in {}", mapping, CONFIG_PATH);
+                }
+            }
+            set_if_different(world, settings);
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.touch != self.touch), world.contains_resource::<TouchControlSettings>()) {
+            set_if_different(world, TouchControlSettings { enabled: self.touch.enabled, button_size: self.touch.button_size });
+        }
+
+        // Takes effect the next time recording starts
+        if should_apply(previous.is_none_or(|previous| previous.recording != self.recording), world.contains_resource::<CompositeRecordingSettings>()) {
+            set_if_different(world, CompositeRecordingSettings { width: self.recording.width, height: self.recording.height, fps: self.recording.fps });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.voice != self.voice), world.contains_resource::<VoiceCommandSettings>()) {
+            set_if_different(world, VoiceCommandSettings { enabled: self.voice.enabled, model: self.voice.model.clone() });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.virtual_camera != self.virtual_camera), world.contains_resource::<VirtualCameraSettings>()) {
+            let virtual_camera = &self.virtual_camera;
+            set_if_different(world, VirtualCameraSettings {
+                enabled: virtual_camera.enabled,
+                width: virtual_camera.width,
+                height: virtual_camera.height,
+                fps: virtual_camera.fps,
+                device: virtual_camera.device.clone()
+            });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.remote != self.remote), world.contains_resource::<RemoteControlSettings>()) {
+            set_if_different(world, RemoteControlSettings { enabled: self.remote.enabled, lan: self.remote.lan, port: self.remote.port });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.updates != self.updates), world.contains_resource::<UpdateCheckSettings>()) {
+            set_if_different(world, UpdateCheckSettings { enabled: self.updates.check, feed: self.updates.feed.clone() });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.physics != self.physics), world.contains_resource::<PhysicsDecorationSettings>()) {
+            let physics = &self.physics;
+            set_if_different(world, PhysicsDecorationSettings { enabled: physics.enabled, count: physics.count, bounce: physics.bounce, reach: physics.reach });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.camera_cuts != self.camera_cuts), world.contains_resource::<CameraCutSettings>()) {
+            let cuts = &self.camera_cuts;
+            set_if_different(world, CameraCutSettings {
+                enabled: cuts.enabled,
+                record: cuts.record,
+                on_markers: cuts.on_markers,
+                on_program_changes: cuts.on_program_changes,
+                shots: cuts.shots.clone()
+            });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.hands != self.hands), world.contains_resource::<HandSplitSettings>()) {
+            let hands = &self.hands;
+            let defaults = HandSplitSettings::default();
+            let focus = match hands.focus.as_deref() {
+                Some("left") => Some(Hand::Left),
+                Some("right") => Some(Hand::Right),
+                Some("both") | None => None,
+                Some(other) => {
+                    eprintln!("Unknown hand {:?} in {}, expected \"left\" or \"right\"", other, CONFIG_PATH);
+                    None
+                }
+            };
+            set_if_different(world, HandSplitSettings {
+                enabled: hands.enabled,
+                detection: hands.detection,
+                split_key: hands.split_key.min(127),
+                left_channel: hands.left_channel.min(15),
+                left_color: hands.left_color.as_deref().and_then(parse_color).unwrap_or(defaults.left_color),
+                right_color: hands.right_color.as_deref().and_then(parse_color).unwrap_or(defaults.right_color),
+                focus
+            });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.trails != self.trails), world.contains_resource::<NoteTrailSettings>()) {
+            set_if_different(world, NoteTrailSettings { enabled: self.trails.enabled, speed: self.trails.speed.max(MIN_TRAIL_SPEED), height: self.trails.height });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.guide != self.guide), world.contains_resource::<KeyGuideSettings>()) {
+            let config = &self.guide;
+            let defaults = KeyGuideSettings::default();
+            let root = guide::parse_pitch_class(&config.root).unwrap_or_else(|| {
+                eprintln!("Unknown guide root {:?} in {}, expected a note name like \"C\" or \"F#\"", config.root, CONFIG_PATH);
+                defaults.root
+            });
+            set_if_different(world, KeyGuideSettings {
+                mode: config.mode,
+                root,
+                scale: config.scale,
+                chord: config.chord,
+                octave: config.octave.min(8),
+                color: config.color.as_deref().and_then(parse_color).unwrap_or(defaults.color),
+                root_color: config.root_color.as_deref().and_then(parse_color).unwrap_or(defaults.root_color)
+            });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.zones != self.zones), world.contains_resource::<RegisterZoneSettings>()) {
+            let zones = match &self.zones.zones {
+                Some(zones) => zones.iter()
+                    .filter_map(|zone| {
+                        if zone.lowest_key > zone.highest_key {
+                            eprintln!("Ignoring the {} zone in {}: its lowest key is above its highest", zone.name, CONFIG_PATH);
+                            return None;
+                        }
+                        let color = parse_color(&zone.color)?;
+                        Some(RegisterZone::new(&zone.name, zone.lowest_key.min(127), zone.highest_key.min(127), color, zone.intensity.clamp(0.0, 3.0)))
+                    })
+                    .collect(),
+                None => RegisterZoneSettings::default().zones
+            };
+            set_if_different(world, RegisterZoneSettings { enabled: self.zones.enabled, zones });
+        }
+
+        if should_apply(previous.is_none_or(|previous| previous.clutter != self.clutter), world.contains_resource::<ClutterSettings>()) {
+            set_if_different(world, ClutterSettings { enabled: self.clutter.enabled, max_notes_per_second: self.clutter.max_notes_per_second.max(1.0) });
+        }
+    }
+}
+
+fn parse_color(hex: &str) -> Option<Color> {
+    match Srgba::hex(hex) {
+        Ok(color) => Some(color.into()),
+        Err(err) => {
+            eprintln!("Invalid color {:?} in {}: {}", hex, CONFIG_PATH, err);
+            None
+        }
+    }
+}
+
+/// Inserts the resource unless it already has this value, so change detection only fires on real changes.
+fn set_if_different<R: Resource + PartialEq>(world: &mut World, value: R) {
+    if world.get_resource::<R>() != Some(&value) {
+        world.insert_resource(value);
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[derive(Resource)]
+pub struct ConfigWatcher {
+    /// The config as last applied.
+    pub config: Config,
+    modified: Option<SystemTime>,
+    seconds_since_poll: f32
+}
+
+fn watch_config(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut watcher: ResMut<ConfigWatcher>
+) {
+    watcher.seconds_since_poll += time.delta_secs();
+    if watcher.seconds_since_poll < POLL_INTERVAL {
+        return;
+    }
+    watcher.seconds_since_poll = 0.0;
+
+    let path = Path::new(CONFIG_PATH);
+    let modified = modified_time(path);
+    if modified == watcher.modified {
+        return;
+    }
+    watcher.modified = modified;
+
+    // A missing file means nothing to apply; keep the current settings
+    if modified.is_none() {
+        return;
+    }
+    match Config::load(path) {
+        Ok(config) => {
+            println!("Reloaded {}", CONFIG_PATH);
+            let previous = std::mem::replace(&mut watcher.config, config.clone());
+            commands.queue(move |world: &mut World| config.apply(Some(&previous), world));
+        }
+        // Keep the last good config while the file is mid-edit
+        Err(err) => eprintln!("Failed to reload {}: {}", CONFIG_PATH, err)
+    }
+}
+
+/// Add this before the plugins whose settings it configures, so they start with the configured values.
+pub struct ConfigPlugin;
+
+impl Plugin for ConfigPlugin {
+    fn build(&self, app: &mut App) {
+        let path = Path::new(CONFIG_PATH);
+        let config = Config::load_or_default();
+        config.apply(None, app.world_mut());
+
+        app
+            .insert_resource(ConfigWatcher {
+                config,
+                modified: modified_time(path),
+                seconds_since_poll: 0.0
+            })
+            .add_systems(Update, watch_config);
+    }
+}
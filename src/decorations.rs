@@ -0,0 +1,426 @@
+//! Decorative virtual objects, like plants, lamps, and signs, placed around the keyboard or the room and kept with the
+//! instrument profile, so a piano that's set up once looks the same every session.
+//!
+//! F2 (or the command palette) toggles placement mode. Clicking places the selected decoration where the pointer meets
+//! the keyboard's plane, or the marker plane when placing in the room frame, so room decorations stay put when the
+//! keyboard's markers are moved. Tab picks the next decoration, R switches frames, Q/E turn and -/= resize the last one
+//! placed, and Backspace removes it. Every change is saved right away to `DECORATIONS_DIR/<profile name>.json`.
+//! See `physics` for toys that bounce to the music.
+
+pub mod physics;
+
+use std::{error::Error, fs, path::PathBuf};
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle}, color::{Color, LinearRgba}, ecs::{change_detection::{DetectChanges, DetectChangesMut}, component::Component, entity::Entity, event::{EventReader, EventWriter}, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, input::{mouse::MouseButton, ButtonInput}, math::{primitives::{Cone, Cuboid, Cylinder, InfinitePlane3d, Sphere}, Quat, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::{mesh::{Mesh, Mesh3d}, view::Visibility}, transform::components::{GlobalTransform, Transform}, ui::{widget::Text, Node, UiRect, Val}, window::{PrimaryWindow, Window}};
+use bevy_egui::input::EguiWantsInput;
+use serde::{Deserialize, Serialize};
+
+use crate::{command::{self, AppCommand}, hud::{self, HudStack}, keyboard::{picking::{KeyPicker, KeyPickingSettings}, profile::InstrumentProfile, KeyboardRoot}, status::{AppError, ErrorSource}, video::{layout_tuning::LayoutTuning, tracking::FadeWithTracking}};
+
+static DECORATIONS_DIR: &str = "decorations";
+/// How far Q and E turn a decoration, in degrees.
+const TURN_STEP: f32 = 15.0;
+/// How much - and = resize a decoration.
+const SCALE_STEP: f32 = 1.1;
+const HUD_FONT_SIZE: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecorationKind {
+    Plant,
+    Lamp,
+    Sign
+}
+
+impl DecorationKind {
+    pub const ALL: [DecorationKind; 3] = [DecorationKind::Plant, DecorationKind::Lamp, DecorationKind::Sign];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            DecorationKind::Plant => "Plant",
+            DecorationKind::Lamp => "Lamp",
+            DecorationKind::Sign => "Sign"
+        }
+    }
+
+    fn next(self) -> Self {
+        let index = DecorationKind::ALL.iter().position(|kind| *kind == self).unwrap_or(0);
+        DecorationKind::ALL[(index + 1) % DecorationKind::ALL.len()]
+    }
+}
+
+/// The coordinate frame a decoration is placed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecorationFrame {
+    /// The lowest manual's frame, so the decoration moves with the keyboard.
+    Keyboard,
+    /// The fiducial frame.
+    Room
+}
+
+/// One placed decoration, as it's saved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Decoration {
+    pub kind: DecorationKind,
+    pub frame: DecorationFrame,
+    /// Where the decoration's base is in its frame, in mm.
+    pub translation: [f32; 3],
+    /// The turn about the frame's up axis, in degrees.
+    #[serde(default)]
+    pub yaw: f32,
+    #[serde(default = "default_scale")]
+    pub scale: f32
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+impl Decoration {
+    fn transform(&self) -> Transform {
+        Transform::from_translation(Vec3::from(self.translation))
+            .with_rotation(Quat::from_rotation_y(self.yaw.to_radians()))
+            .with_scale(Vec3::splat(self.scale))
+    }
+}
+
+/// The file a profile's decorations are saved in. Characters that can't be in a file name are replaced.
+fn decorations_path(profile_name: &str) -> PathBuf {
+    let file_name: String = profile_name.chars()
+        .map(|character| if character.is_alphanumeric() || character == '-' || character == '_' { character } else { '_' })
+        .collect();
+    PathBuf::from(DECORATIONS_DIR).join(format!("{}.json", file_name))
+}
+
+/// The current profile's decorations.
+#[derive(Resource, Default)]
+pub struct Decorations {
+    /// The name of the profile they belong to.
+    profile: String,
+    pub placed: Vec<Decoration>
+}
+
+impl Decorations {
+    /// Loads the profile's decorations, or none if it doesn't have any yet.
+    fn load(profile: &str) -> Result<Self, Box<dyn Error>> {
+        let path = decorations_path(profile);
+        let placed = if path.exists() { serde_json::from_str(&fs::read_to_string(&path)?)? } else { Vec::new() };
+        Ok(Self { profile: profile.to_string(), placed })
+    }
+
+    fn save(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let path = decorations_path(&self.profile);
+        fs::create_dir_all(DECORATIONS_DIR)?;
+        fs::write(&path, serde_json::to_string_pretty(&self.placed)?)?;
+        Ok(path)
+    }
+}
+
+#[derive(Resource)]
+pub struct DecorationPlacement {
+    pub active: bool,
+    pub kind: DecorationKind,
+    pub frame: DecorationFrame,
+    /// Whether clicking keys played them before placement turned it off, so it can be restored.
+    click_to_play_before: bool
+}
+
+impl Default for DecorationPlacement {
+    fn default() -> Self {
+        Self {
+            active: false,
+            kind: DecorationKind::Plant,
+            frame: DecorationFrame::Keyboard,
+            click_to_play_before: true
+        }
+    }
+}
+
+/// The parent of one spawned decoration's meshes.
+#[derive(Component)]
+struct DecorationRoot;
+
+#[derive(Component)]
+struct DecorationPlacementHud;
+
+#[derive(Resource)]
+struct DecorationAssets {
+    pot: Handle<Mesh>,
+    foliage: Handle<Mesh>,
+    stand: Handle<Mesh>,
+    pole: Handle<Mesh>,
+    shade: Handle<Mesh>,
+    board: Handle<Mesh>,
+    terracotta: Handle<StandardMaterial>,
+    leaves: Handle<StandardMaterial>,
+    metal: Handle<StandardMaterial>,
+    glow: Handle<StandardMaterial>,
+    sign: Handle<StandardMaterial>
+}
+
+impl DecorationAssets {
+    /// The meshes a decoration is built from, with their materials and offsets from its base, in mm.
+    fn parts(&self, kind: DecorationKind) -> Vec<(&Handle<Mesh>, &Handle<StandardMaterial>, Vec3)> {
+        match kind {
+            DecorationKind::Plant => vec![
+                (&self.pot, &self.terracotta, Vec3::new(0.0, 20.0, 0.0)),
+                (&self.foliage, &self.leaves, Vec3::new(0.0, 80.0, 0.0))
+            ],
+            DecorationKind::Lamp => vec![
+                (&self.stand, &self.metal, Vec3::new(0.0, 3.0, 0.0)),
+                (&self.pole, &self.metal, Vec3::new(0.0, 80.0, 0.0)),
+                (&self.shade, &self.glow, Vec3::new(0.0, 165.0, 0.0))
+            ],
+            DecorationKind::Sign => vec![
+                (&self.stand, &self.metal, Vec3::new(0.0, 3.0, 0.0)),
+                (&self.pole, &self.metal, Vec3::new(0.0, 80.0, 0.0)),
+                (&self.board, &self.sign, Vec3::new(0.0, 160.0, 0.0))
+            ]
+        }
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    stack: Single<Entity, With<HudStack>>
+) {
+    let material = |color: Color| StandardMaterial {
+        base_color: color,
+        perceptual_roughness: 0.8,
+        ..Default::default()
+    };
+
+    commands.insert_resource(DecorationAssets {
+        pot: meshes.add(Cylinder::new(30.0, 40.0)),
+        foliage: meshes.add(Sphere::new(45.0)),
+        stand: meshes.add(Cylinder::new(35.0, 6.0)),
+        pole: meshes.add(Cylinder::new(4.0, 160.0)),
+        shade: meshes.add(Cone { radius: 40.0, height: 50.0 }),
+        board: meshes.add(Cuboid::new(120.0, 70.0, 6.0)),
+        terracotta: materials.add(material(Color::srgb(0.7, 0.35, 0.2))),
+        leaves: materials.add(material(Color::srgb(0.2, 0.55, 0.2))),
+        metal: materials.add(material(Color::srgb(0.3, 0.3, 0.32))),
+        glow: materials.add(StandardMaterial {
+            base_color: Color::srgb(1.0, 0.9, 0.6),
+            emissive: LinearRgba::rgb(4.0, 3.2, 1.6),
+            ..Default::default()
+        }),
+        sign: materials.add(material(Color::srgb(0.95, 0.95, 0.9)))
+    });
+
+    commands.spawn((
+        hud::panel(HUD_FONT_SIZE, UiRect::all(Val::Px(6.0)), Color::srgba(0.0, 0.0, 0.0, 0.75)),
+        DecorationPlacementHud,
+        ChildOf(*stack)
+    ));
+}
+
+/// Loads the profile's decorations when the profile changes.
+fn load_decorations(
+    profile: Res<InstrumentProfile>,
+    mut decorations: ResMut<Decorations>,
+    mut errors: EventWriter<AppError>
+) {
+    if !profile.is_changed() || decorations.profile == profile.name {
+        return;
+    }
+    *decorations = Decorations::load(&profile.name).unwrap_or_else(|err| {
+        errors.write(AppError::new(ErrorSource::Decorations, format!("Failed to load the decorations of the {} profile: {}", profile.name, err)));
+        Decorations { profile: profile.name.clone(), placed: Vec::new() }
+    });
+}
+
+/// Respawns the decorations whenever they change.
+fn spawn_decorations(
+    mut commands: Commands,
+    decorations: Res<Decorations>,
+    assets: Res<DecorationAssets>,
+    keyboard_root: Single<Entity, With<KeyboardRoot>>,
+    existing: Query<Entity, With<DecorationRoot>>
+) {
+    if !decorations.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    for decoration in &decorations.placed {
+        let mut root = commands.spawn((decoration.transform(), Visibility::default(), DecorationRoot));
+        if decoration.frame == DecorationFrame::Keyboard {
+            root.insert(ChildOf(*keyboard_root));
+        }
+        let root = root.id();
+        for (mesh, material, offset) in assets.parts(decoration.kind) {
+            commands.spawn((
+                Mesh3d(mesh.clone()),
+                MeshMaterial3d(material.clone()),
+                Transform::from_translation(offset),
+                FadeWithTracking,
+                ChildOf(root)
+            ));
+        }
+    }
+}
+
+fn toggle_decoration_placement(
+    mut commands: EventReader<AppCommand>,
+    mut placement: ResMut<DecorationPlacement>,
+    mut picking_settings: ResMut<KeyPickingSettings>
+) {
+    for command in commands.read() {
+        if *command != AppCommand::ToggleDecorationPlacement {
+            continue;
+        }
+        placement.active = !placement.active;
+        // Clicks place decorations instead of playing keys
+        if placement.active {
+            placement.click_to_play_before = picking_settings.click_to_play;
+            picking_settings.click_to_play = false;
+        } else {
+            picking_settings.click_to_play = placement.click_to_play_before;
+        }
+    }
+}
+
+/// Places, turns, resizes, and removes decorations in placement mode, saving after every change.
+#[allow(clippy::too_many_arguments)]
+fn place_decorations(
+    mut commands: EventReader<AppCommand>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    egui_input: Option<Res<EguiWantsInput>>,
+    picker: KeyPicker,
+    keyboard_root: Single<&GlobalTransform, With<KeyboardRoot>>,
+    layout_tuning: Option<Res<LayoutTuning>>,
+    mut placement: ResMut<DecorationPlacement>,
+    mut decorations: ResMut<Decorations>,
+    mut errors: EventWriter<AppError>
+) {
+    if !placement.active {
+        commands.clear();
+        return;
+    }
+    // Layout tuning uses some of the same keys, so they're left to it while both modes are on. It isn't there when
+    // rendering a video
+    if layout_tuning.is_some_and(|tuning| tuning.active) {
+        commands.clear();
+    }
+
+    let mut changed = false;
+    for command in commands.read() {
+        let (turn, scale) = match command {
+            AppCommand::NextDecorationKind => {
+                placement.kind = placement.kind.next();
+                continue;
+            }
+            AppCommand::ToggleDecorationFrame => {
+                placement.frame = match placement.frame {
+                    DecorationFrame::Keyboard => DecorationFrame::Room,
+                    DecorationFrame::Room => DecorationFrame::Keyboard
+                };
+                continue;
+            }
+            AppCommand::RemoveLastDecoration => {
+                changed |= decorations.placed.pop().is_some();
+                continue;
+            }
+            AppCommand::TurnDecorationLeft => (TURN_STEP, 1.0),
+            AppCommand::TurnDecorationRight => (-TURN_STEP, 1.0),
+            AppCommand::ShrinkDecoration => (0.0, 1.0 / SCALE_STEP),
+            AppCommand::GrowDecoration => (0.0, SCALE_STEP),
+            _ => continue
+        };
+        if let Some(last) = decorations.bypass_change_detection().placed.last_mut() {
+            last.yaw = (last.yaw + turn).rem_euclid(360.0);
+            last.scale *= scale;
+            changed = true;
+        }
+    }
+
+    let clicked = mouse.just_pressed(MouseButton::Left) && !egui_input.is_some_and(|input| input.wants_any_pointer_input());
+    let ray = windows.iter().next().and_then(Window::cursor_position).filter(|_| clicked).and_then(|position| picker.ray(position));
+    if let Some(ray) = ray {
+        // Intersect the frame's y = 0 plane, then bring the point into the frame
+        let to_world = match placement.frame {
+            DecorationFrame::Keyboard => **keyboard_root,
+            DecorationFrame::Room => GlobalTransform::IDENTITY
+        };
+        if let Some(distance) = ray.intersect_plane(to_world.translation(), InfinitePlane3d::new(to_world.up())) {
+            let point = to_world.affine().inverse().transform_point3(ray.get_point(distance));
+            decorations.placed.push(Decoration {
+                kind: placement.kind,
+                frame: placement.frame,
+                translation: point.to_array(),
+                yaw: 0.0,
+                scale: 1.0
+            });
+            changed = true;
+        }
+    }
+
+    if changed {
+        decorations.set_changed();
+        if let Err(err) = decorations.save() {
+            errors.write(AppError::new(ErrorSource::Decorations, format!("Failed to save the decorations: {}", err)));
+        }
+    }
+}
+
+fn update_placement_hud(
+    placement: Res<DecorationPlacement>,
+    decorations: Res<Decorations>,
+    panel: Single<(&mut Node, &mut Text), With<DecorationPlacementHud>>
+) {
+    if !placement.is_changed() && !decorations.is_changed() {
+        return;
+    }
+    let (mut node, mut text) = panel.into_inner();
+    hud::show_panel(&mut node, placement.active);
+    if !placement.active {
+        return;
+    }
+    let frame = match placement.frame {
+        DecorationFrame::Keyboard => "keyboard",
+        DecorationFrame::Room => "room"
+    };
+    text.0 = format!(
+        "Placing decorations: {} in the {} frame ({} placed)\nClick: place, Tab: next decoration, R: switch frame, Q/E: turn, -/=: resize, Backspace: remove the last one",
+        placement.kind.label(),
+        frame,
+        decorations.placed.len()
+    );
+}
+
+pub struct DecorationPlugin;
+
+impl Plugin for DecorationPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Decorations>()
+            .init_resource::<DecorationPlacement>()
+            .add_plugins(physics::PhysicsDecorationPlugin)
+            .add_systems(Startup, setup)
+            .add_systems(Update, (
+                toggle_decoration_placement.after(command::run_app_commands),
+                place_decorations,
+                load_decorations,
+                spawn_decorations,
+                update_placement_hud
+            ).chain());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_names_become_file_names() {
+        assert_eq!(decorations_path("88-key keyboard"), PathBuf::from(DECORATIONS_DIR).join("88-key_keyboard.json"));
+        assert_eq!(decorations_path("../Organ"), PathBuf::from(DECORATIONS_DIR).join("___Organ.json"));
+    }
+}
@@ -0,0 +1,60 @@
+//! Small toys on the keyboard's lid, behind the keys, that bounce when nearby notes are played: the harder the note,
+//! the higher they jump. Enable them with `[physics] enabled = true` in a build with `--features physics`, which
+//! simulates them with avian.
+//!
+//! The toys are simulated in the keyboard's own frame, where the lid never moves, and only drawn on the tracked
+//! keyboard, so tracking jitter can't knock them over.
+
+#[cfg(feature = "physics")]
+mod simulation;
+
+use bevy::{app::{App, Plugin}, ecs::resource::Resource};
+#[cfg(not(feature = "physics"))]
+use bevy::{app::Update, ecs::{change_detection::DetectChanges, event::EventWriter, system::Res}};
+
+#[cfg(not(feature = "physics"))]
+use crate::status::{AppError, ErrorSource};
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct PhysicsDecorationSettings {
+    pub enabled: bool,
+    /// How many toys are scattered on the lid.
+    pub count: usize,
+    /// The upward speed a note at full velocity gives the toys right behind its key, in mm/s.
+    pub bounce: f32,
+    /// How far along the keyboard from a note's key toys still bounce, in mm.
+    pub reach: f32
+}
+
+impl Default for PhysicsDecorationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            count: 12,
+            bounce: 600.0,
+            reach: 150.0
+        }
+    }
+}
+
+#[cfg(not(feature = "physics"))]
+fn report_missing_physics(
+    settings: Res<PhysicsDecorationSettings>,
+    mut errors: EventWriter<AppError>
+) {
+    if settings.is_changed() && settings.enabled {
+        errors.write(AppError::new(ErrorSource::Decorations, "This build doesn't include physics. Rebuild with --features physics"));
+    }
+}
+
+pub struct PhysicsDecorationPlugin;
+
+impl Plugin for PhysicsDecorationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PhysicsDecorationSettings>();
+        #[cfg(feature = "physics")]
+        simulation::build(app);
+        #[cfg(not(feature = "physics"))]
+        app.add_systems(Update, report_missing_physics);
+    }
+}
@@ -0,0 +1,146 @@
+use avian3d::prelude::{Collider, Gravity, LinearVelocity, PhysicsPlugins, Restitution, RigidBody};
+use bevy::{app::{App, Update}, asset::Assets, color::Color, ecs::{change_detection::{DetectChanges, DetectChangesMut}, component::Component, entity::Entity, hierarchy::ChildOf, query::{Or, With, Without}, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, math::{primitives::{Cuboid, Sphere}, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::mesh::{Mesh, Mesh3d}, transform::components::Transform};
+use rand::Rng;
+
+use crate::{keyboard::{KeyboardLayout, KeyboardRoot}, midi::{bus::{NoteBus, NoteSource}, MidiEventKind}, seed::RandomSeed, video::tracking::FadeWithTracking};
+
+use super::PhysicsDecorationSettings;
+
+/// The size of a toy in mm, which is also the length unit the simulation is tuned for.
+const TOY_SIZE: f32 = 20.0;
+/// Gravity in mm/s².
+const GRAVITY: f32 = 9810.0;
+/// The height of the walls around the lid that keep toys from falling off, in mm.
+const WALL_HEIGHT: f32 = 80.0;
+const WALL_THICKNESS: f32 = 10.0;
+/// How much of a bounce pushes toys sideways, away from the key.
+const SIDEWAYS_PUSH: f32 = 0.2;
+
+/// A simulated toy. Its position is in the keyboard's frame.
+#[derive(Component)]
+struct PhysicsToy;
+
+/// The lid and walls the toys rest on.
+#[derive(Component)]
+struct PhysicsLid;
+
+/// Draws a toy on the tracked keyboard.
+#[derive(Component)]
+struct ToyProxy(Entity);
+
+/// Scatters new toys on the lid when the settings or the keyboard change, or removes them when turned off.
+#[allow(clippy::too_many_arguments)]
+fn spawn_physics_toys(
+    mut commands: Commands,
+    settings: Res<PhysicsDecorationSettings>,
+    keyboard_layout: Res<KeyboardLayout>,
+    seed: Res<RandomSeed>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    keyboard_root: Single<Entity, With<KeyboardRoot>>,
+    existing: Query<Entity, Or<(With<PhysicsToy>, With<PhysicsLid>, With<ToyProxy>)>>
+) {
+    if !settings.is_changed() && !keyboard_layout.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+    if !settings.enabled {
+        return;
+    }
+
+    // The lid runs the width of the keyboard, from the marker line to the back of the keys
+    let width = keyboard_layout.width();
+    let depth = keyboard_layout.geometry.key_back_z;
+    commands.spawn((RigidBody::Static, Collider::cuboid(width, WALL_THICKNESS, depth), Transform::from_xyz(0.0, -WALL_THICKNESS / 2.0, depth / 2.0), PhysicsLid));
+    for (size, position) in [
+        (Vec3::new(WALL_THICKNESS, WALL_HEIGHT, depth), Vec3::new(-(width + WALL_THICKNESS) / 2.0, WALL_HEIGHT / 2.0, depth / 2.0)),
+        (Vec3::new(WALL_THICKNESS, WALL_HEIGHT, depth), Vec3::new((width + WALL_THICKNESS) / 2.0, WALL_HEIGHT / 2.0, depth / 2.0)),
+        (Vec3::new(width, WALL_HEIGHT, WALL_THICKNESS), Vec3::new(0.0, WALL_HEIGHT / 2.0, -WALL_THICKNESS / 2.0)),
+        (Vec3::new(width, WALL_HEIGHT, WALL_THICKNESS), Vec3::new(0.0, WALL_HEIGHT / 2.0, depth + WALL_THICKNESS / 2.0))
+    ] {
+        commands.spawn((RigidBody::Static, Collider::cuboid(size.x, size.y, size.z), Transform::from_translation(position), PhysicsLid));
+    }
+
+    let mut rng = seed.rng("physics decorations");
+    let sphere = meshes.add(Sphere::new(TOY_SIZE / 2.0));
+    let cube = meshes.add(Cuboid::from_length(TOY_SIZE));
+    // Keyboards narrower than two toys get theirs down the middle, rather than an empty range to pick from
+    let half_span = (width / 2.0 - TOY_SIZE).max(0.0);
+    for index in 0..settings.count {
+        let position = Vec3::new(
+            rng.gen_range(-half_span..=half_span),
+            rng.gen_range(TOY_SIZE..WALL_HEIGHT / 2.0),
+            rng.gen_range(TOY_SIZE.min(depth / 2.0)..(depth - TOY_SIZE).max(depth / 2.0 + 0.1))
+        );
+        let (mesh, collider) = if index % 2 == 0 {
+            (sphere.clone(), Collider::sphere(TOY_SIZE / 2.0))
+        } else {
+            (cube.clone(), Collider::cuboid(TOY_SIZE, TOY_SIZE, TOY_SIZE))
+        };
+        let material = materials.add(StandardMaterial {
+            base_color: Color::hsl(rng.gen_range(0.0..360.0), 0.7, 0.6),
+            ..Default::default()
+        });
+
+        // The body has no mesh, so only its proxy on the keyboard is drawn
+        let body = commands.spawn((RigidBody::Dynamic, collider, Restitution::new(0.5), Transform::from_translation(position), PhysicsToy)).id();
+        commands.spawn((
+            Mesh3d(mesh),
+            MeshMaterial3d(material),
+            Transform::from_translation(position),
+            ToyProxy(body),
+            FadeWithTracking,
+            ChildOf(*keyboard_root)
+        ));
+    }
+}
+
+/// Throws the toys near each played key upward, by how hard it was played.
+fn bounce_toys(
+    settings: Res<PhysicsDecorationSettings>,
+    keyboard_layout: Res<KeyboardLayout>,
+    bus: Res<NoteBus>,
+    mut toys: Query<(&Transform, &mut LinearVelocity), With<PhysicsToy>>
+) {
+    if !settings.enabled {
+        return;
+    }
+    for event in bus.from_source(NoteSource::Live) {
+        let MidiEventKind::NoteOn { key, velocity } = event.kind else { continue };
+        if !keyboard_layout.keys().contains(&key) {
+            continue;
+        }
+        let key_x = keyboard_layout.key_center_x(key);
+        let strength = settings.bounce * velocity as f32 / 127.0;
+        for (transform, mut linear_velocity) in toys.iter_mut() {
+            let offset = transform.translation.x - key_x;
+            let falloff = 1.0 - offset.abs() / settings.reach.max(1.0);
+            if falloff <= 0.0 {
+                continue;
+            }
+            linear_velocity.y += strength * falloff;
+            linear_velocity.x += strength * falloff * SIDEWAYS_PUSH * offset.signum();
+        }
+    }
+}
+
+/// Moves each toy's proxy to where the simulation has it.
+fn sync_toy_proxies(
+    toys: Query<&Transform, With<PhysicsToy>>,
+    mut proxies: Query<(&ToyProxy, &mut Transform), Without<PhysicsToy>>
+) {
+    for (proxy, mut transform) in proxies.iter_mut() {
+        if let Ok(toy) = toys.get(proxy.0) {
+            transform.set_if_neq(*toy);
+        }
+    }
+}
+
+pub(super) fn build(app: &mut App) {
+    app
+        .add_plugins(PhysicsPlugins::default().with_length_unit(TOY_SIZE))
+        .insert_resource(Gravity(Vec3::NEG_Y * GRAVITY))
+        .add_systems(Update, (spawn_physics_toys, bounce_toys, sync_toy_proxies).chain());
+}
@@ -0,0 +1,102 @@
+//! Tools for understanding what the app is doing, especially when something goes wrong on someone else's setup.
+
+use std::collections::VecDeque;
+
+use bevy::{app::{App, Plugin}, ecs::resource::Resource};
+
+pub mod benchmark;
+pub mod memory;
+pub mod pose_extraction;
+pub mod rejected_candidates;
+pub mod tracking_comparison;
+pub mod profiling;
+pub mod snapshot;
+pub mod soak;
+pub mod stage_timing;
+
+/// How many recent errors are kept.
+const RECENT_ERROR_LIMIT: usize = 50;
+
+/// The mean, median, 95th percentile, and maximum of some measurements.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Summary {
+    pub mean: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub max: f64
+}
+
+impl Summary {
+    /// Summarizes the values, with all zeros for none.
+    pub fn of(values: &[f64]) -> Summary {
+        if values.is_empty() {
+            return Summary::default();
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Summary {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            median: percentile(&sorted, 0.5),
+            p95: percentile(&sorted, 0.95),
+            max: sorted[sorted.len() - 1]
+        }
+    }
+}
+
+/// The value a fraction of the way through sorted values, by nearest rank, or 0 for none.
+pub fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    sorted[((sorted.len() - 1) as f64 * fraction).round() as usize]
+}
+
+/// A rolling log of recent error messages, included in state snapshots.
+#[derive(Resource, Default)]
+pub struct RecentErrors {
+    /// Messages with the Unix time in seconds they were reported at, oldest first.
+    pub errors: VecDeque<(f64, String)>,
+    /// The number of errors reported since startup, including ones no longer kept.
+    pub total: u64
+}
+
+impl RecentErrors {
+    /// Prints the message to stderr and remembers it.
+    pub fn report(&mut self, message: impl Into<String>) {
+        let message = message.into();
+        eprintln!("{}", message);
+
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_secs_f64())
+            .unwrap_or_default();
+        if self.errors.len() >= RECENT_ERROR_LIMIT {
+            self.errors.pop_front();
+        }
+        self.errors.push_back((timestamp, message));
+        self.total += 1;
+    }
+}
+
+pub struct DiagnosticsPlugin;
+
+impl Plugin for DiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<RecentErrors>()
+            .add_plugins((memory::MemoryTrackingPlugin, profiling::ProfilingPlugin, rejected_candidates::RejectedCandidatesPlugin, snapshot::SnapshotPlugin, soak::SoakTestPlugin, stage_timing::StageTimingPlugin));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summaries_use_nearest_rank_percentiles() {
+        let values: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(Summary::of(&values), Summary { mean: 50.5, median: 51.0, p95: 95.0, max: 100.0 });
+        assert_eq!(Summary::of(&[]), Summary::default());
+        assert_eq!(percentile(&values, 0.01), 2.0);
+    }
+}
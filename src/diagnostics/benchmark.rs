@@ -0,0 +1,134 @@
+//! A headless tracking benchmark, run with `--benchmark <VIDEO>`. Every frame of a recorded video goes through capture,
+//! greyscale conversion, marker detection, and pose estimation as fast as possible, without a window or GPU, and the
+//! time each stage took is printed at the end. Useful for comparing detection settings or machines, e.g. in CI.
+//!
+//! The calibration, fiducial layout, and detection settings come from the config and command line like a normal run.
+//! Frames are processed one at a time on the calling thread, so the numbers are per frame rather than the app's
+//! pipelined throughput, and detection runs on every frame regardless of `interval`.
+
+use std::{error::Error, path::Path, time::{Duration, Instant}};
+
+use bevy::ecs::world::World;
+use opencv::{core::{AlgorithmHint, Mat, MatTraitConst}, imgproc, videoio::{self, VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst}};
+
+use crate::video::aruco_camera::{CalibrationFile, CameraIntrinsics, DetectionSettings, FiducialLayout, FrameDetection, FrameDetector};
+
+use super::Summary;
+
+static STAGES: [&str; 6] = ["capture", "convert", "detect", "refine", "pnp", "total"];
+
+/// Summarizes some durations in milliseconds.
+fn summarize(samples: &[Duration]) -> Summary {
+    let milliseconds: Vec<f64> = samples.iter().map(|sample| sample.as_secs_f64() * 1000.0).collect();
+    Summary::of(&milliseconds)
+}
+
+/// One frame of a video, detected by `detect_video_frames`.
+pub struct DetectedFrame {
+    /// The frame's timestamp in the video, in seconds.
+    pub time: f64,
+    /// How long reading the frame took.
+    pub capture: Duration,
+    /// How long converting it to greyscale took.
+    pub convert: Duration,
+    /// How long the frame took from reading to a solved pose.
+    pub total: Duration,
+    pub detection: FrameDetection
+}
+
+/// Reads every frame of the video, converts it to greyscale, and detects markers and solves the pose with the
+/// settings in the world, one frame at a time on the calling thread. Returns the number of frames.
+pub fn detect_video_frames(video: &Path, world: &World, mut on_frame: impl FnMut(DetectedFrame)) -> Result<usize, Box<dyn Error>> {
+    let calibration = world.get_resource::<CalibrationFile>().cloned().unwrap_or_default();
+    let intrinsics = CameraIntrinsics::load(&calibration.0).map_err(|err| format!("Failed to load {}: {}", calibration.0, err))?;
+    let layout = world.get_resource::<FiducialLayout>().cloned().unwrap_or_default();
+    let settings = world.get_resource::<DetectionSettings>().cloned().unwrap_or_default();
+
+    let mut capture = VideoCapture::from_file(&video.to_string_lossy(), videoio::CAP_ANY)?;
+    if !capture.is_opened()? {
+        return Err(format!("Failed to open {}", video.display()).into());
+    }
+    let mut detector = FrameDetector::new(&layout)?;
+
+    let mut frames = 0;
+    let mut frame = Mat::default();
+    loop {
+        let frame_started = Instant::now();
+        if !capture.read(&mut frame)? || frame.empty() {
+            break;
+        }
+        let captured = Instant::now();
+        let mut greyscale = Mat::default();
+        imgproc::cvt_color(&frame, &mut greyscale, imgproc::COLOR_BGR2GRAY, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+        let converted = Instant::now();
+        let detection = detector.detect(greyscale, &layout, &settings, &intrinsics)?;
+        let total = frame_started.elapsed();
+        on_frame(DetectedFrame {
+            time: capture.get(videoio::CAP_PROP_POS_MSEC)? / 1000.0,
+            capture: captured - frame_started,
+            convert: converted - captured,
+            total,
+            detection
+        });
+        frames += 1;
+    }
+    if frames == 0 {
+        return Err(format!("{} has no frames", video.display()).into());
+    }
+    Ok(frames)
+}
+
+/// Runs the benchmark on the video with the settings in the world, and prints the results.
+pub fn run(video: &Path, world: &World) -> Result<(), Box<dyn Error>> {
+    println!("Benchmarking tracking on {}", video.display());
+    let mut samples: [Vec<Duration>; 6] = Default::default();
+    let (mut markers, mut solved) = (0, 0);
+    let mut reprojection_errors = Vec::new();
+    let started = Instant::now();
+    let frames = detect_video_frames(video, world, |frame| {
+        let detection = frame.detection;
+        for (stage, time) in samples.iter_mut().zip([
+            frame.capture,
+            frame.convert,
+            detection.times.detect,
+            detection.times.refine,
+            detection.times.pnp,
+            frame.total
+        ]) {
+            stage.push(time);
+        }
+        markers += detection.markers;
+        if detection.solved {
+            solved += 1;
+        }
+        reprojection_errors.extend(detection.reprojection_error.map(|error| error.rms));
+    })?;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    println!("{} frames in {:.1}s ({:.1} fps)", frames, elapsed, frames as f64 / elapsed);
+    println!("{:<10}{:>10}{:>10}{:>10}{:>10}", "stage", "mean ms", "p50 ms", "p95 ms", "max ms");
+    for (stage, stage_samples) in STAGES.iter().zip(samples.iter()) {
+        let Summary { mean, median, p95, max } = summarize(stage_samples);
+        println!("{:<10}{:>10.2}{:>10.2}{:>10.2}{:>10.2}", stage, mean, median, p95, max);
+    }
+    println!("Markers per frame: {:.1}", markers as f64 / frames as f64);
+    println!("Pose solved on {} of {} frames ({:.0}%)", solved, frames, solved as f64 / frames as f64 * 100.0);
+    if !reprojection_errors.is_empty() {
+        println!("Mean RMS reprojection error: {:.2} px", reprojection_errors.iter().sum::<f64>() / reprojection_errors.len() as f64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_uses_nearest_rank_percentiles() {
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        let Summary { mean, median, p95, max } = summarize(&samples);
+        assert!((mean - 50.5).abs() < 1e-9);
+        assert_eq!((median, p95, max), (51.0, 95.0, 100.0));
+        assert_eq!(summarize(&[]), Summary::default());
+    }
+}
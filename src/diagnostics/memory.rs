@@ -0,0 +1,132 @@
+//! Tracks how much memory the video path holds in OpenCV Mats and GPU textures, so changes can be checked against a budget.
+
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use bevy::{app::{App, Plugin, Update}, diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic}, ecs::{resource::Resource, system::{Res, ResMut}}};
+use opencv::core::{Mat, MatTraitConst};
+
+use crate::{background::ConvertedWebcamFrame, video::{aruco_camera::ArucoTrackingData, WebcamFrame}};
+
+use super::RecentErrors;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryCategory {
+    /// Frames captured from the camera and converted for display.
+    FrameMats,
+    /// Greyscale and downscaled buffers used by marker detection.
+    TrackingMats,
+    /// Textures uploaded to the GPU for the background.
+    GpuTextures
+}
+
+impl MemoryCategory {
+    pub const ALL: [MemoryCategory; 3] = [MemoryCategory::FrameMats, MemoryCategory::TrackingMats, MemoryCategory::GpuTextures];
+
+    pub fn diagnostic_path(&self) -> DiagnosticPath {
+        match self {
+            MemoryCategory::FrameMats => DiagnosticPath::const_new("memory/frame_mats"),
+            MemoryCategory::TrackingMats => DiagnosticPath::const_new("memory/tracking_mats"),
+            MemoryCategory::GpuTextures => DiagnosticPath::const_new("memory/gpu_textures")
+        }
+    }
+}
+
+#[derive(Default)]
+struct CategoryCounters {
+    current: AtomicU64,
+    peak: AtomicU64
+}
+
+/// Current and peak bytes per category. Cloning shares the same counters,
+/// so the render world can report texture allocations into the same tracker.
+#[derive(Resource, Clone, Default)]
+pub struct MemoryTracker(Arc<[CategoryCounters; 3]>);
+
+impl MemoryTracker {
+    fn counters(&self, category: MemoryCategory) -> &CategoryCounters {
+        &self.0[category as usize]
+    }
+
+    /// Records how many bytes a category currently holds.
+    pub fn set(&self, category: MemoryCategory, bytes: u64) {
+        let counters = self.counters(category);
+        counters.current.store(bytes, Ordering::Relaxed);
+        counters.peak.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    pub fn current(&self, category: MemoryCategory) -> u64 {
+        self.counters(category).current.load(Ordering::Relaxed)
+    }
+
+    pub fn peak(&self, category: MemoryCategory) -> u64 {
+        self.counters(category).peak.load(Ordering::Relaxed)
+    }
+
+    pub fn total_current(&self) -> u64 {
+        MemoryCategory::ALL.iter().map(|category| self.current(*category)).sum()
+    }
+}
+
+/// The total number of bytes the tracked categories may hold before a warning is reported.
+#[derive(Resource, Clone)]
+pub struct MemoryBudget {
+    pub max_bytes: u64,
+    exceeded: bool
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self {
+            // Enough for a handful of 4K RGBA frames
+            max_bytes: 256 * 1024 * 1024,
+            exceeded: false
+        }
+    }
+}
+
+/// The number of bytes of pixel data a Mat holds.
+pub fn mat_bytes(mat: &Mat) -> u64 {
+    (mat.total() * mat.elem_size().unwrap_or(0)) as u64
+}
+
+fn measure_memory(
+    tracker: Res<MemoryTracker>,
+    mut budget: ResMut<MemoryBudget>,
+    webcam_frame: Res<WebcamFrame>,
+    converted_frame: Res<ConvertedWebcamFrame>,
+    tracking_data: Res<ArucoTrackingData>,
+    mut diagnostics: Diagnostics,
+    mut recent_errors: ResMut<RecentErrors>
+) {
+    tracker.set(MemoryCategory::FrameMats, mat_bytes(&webcam_frame.0) + mat_bytes(&converted_frame.0));
+    // The tracking buffers are on loan to the detection task part of the time, so only count them while they're home
+    let tracking_bytes = tracking_data.buffer_bytes();
+    if tracking_bytes > 0 {
+        tracker.set(MemoryCategory::TrackingMats, tracking_bytes);
+    }
+
+    for category in MemoryCategory::ALL {
+        diagnostics.add_measurement(&category.diagnostic_path(), || tracker.current(category) as f64 / 1024.0 / 1024.0);
+    }
+
+    let total = tracker.total_current();
+    if total > budget.max_bytes && !budget.exceeded {
+        recent_errors.report(format!("Video memory use ({} MiB) exceeded the budget ({} MiB)", total / 1024 / 1024, budget.max_bytes / 1024 / 1024));
+    }
+    budget.exceeded = total > budget.max_bytes;
+}
+
+pub struct MemoryTrackingPlugin;
+
+impl Plugin for MemoryTrackingPlugin {
+    fn build(&self, app: &mut App) {
+        for category in MemoryCategory::ALL {
+            app.register_diagnostic(Diagnostic::new(category.diagnostic_path()).with_suffix(" MiB"));
+        }
+
+        app
+            .init_resource::<MemoryTracker>()
+            .init_resource::<MemoryBudget>()
+            .add_systems(Update, measure_memory);
+    }
+}
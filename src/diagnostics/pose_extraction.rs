@@ -0,0 +1,141 @@
+//! Offline pose extraction, run with `--extract-poses <VIDEO> --poses-output <FILE>`. Every frame of a recorded video
+//! goes through the same detection and pose estimation as the benchmark, without a window or GPU, and the markers found
+//! and the solved pose of each frame are written to a JSON or CSV file, picked by the output's extension. Useful for
+//! comparing tracking quality across lighting conditions or setups in a spreadsheet or script.
+//!
+//! Poses are those of the fiducial frame, which the keyboard's frame is fitted into: `rotation` and `translation` are
+//! OpenCV's rotation vector and translation in mm of the fiducials in camera space, and `camera_position` and
+//! `camera_rotation` are where the app puts its 3D camera in the fiducial frame, as a position in mm and an xyzw
+//! quaternion.
+
+use std::{error::Error, fmt::Write as _, fs, path::Path};
+
+use bevy::ecs::world::World;
+use serde::Serialize;
+
+use crate::video::aruco_camera::FrameDetection;
+
+use super::benchmark;
+
+#[derive(Serialize)]
+struct DetectedMarker {
+    id: i32,
+    /// Pixels, clockwise from the top left.
+    corners: [[f32; 2]; 4]
+}
+
+#[derive(Serialize)]
+struct FramePose {
+    frame: usize,
+    /// The frame's timestamp in the video, in seconds.
+    time: f64,
+    markers: Vec<DetectedMarker>,
+    solved: bool,
+    rotation: Option<[f64; 3]>,
+    translation: Option<[f64; 3]>,
+    camera_position: Option<[f32; 3]>,
+    camera_rotation: Option<[f32; 4]>,
+    /// The RMS reprojection error in pixels.
+    reprojection_error: Option<f64>
+}
+
+impl FramePose {
+    fn new(frame: usize, time: f64, detection: FrameDetection) -> Self {
+        Self {
+            frame,
+            time,
+            markers: detection.detected.into_iter().map(|(id, corners)| DetectedMarker { id, corners }).collect(),
+            solved: detection.solved,
+            rotation: detection.pose.map(|(rotation, _)| rotation),
+            translation: detection.pose.map(|(_, translation)| translation),
+            camera_position: detection.camera.map(|camera| camera.translation.to_array()),
+            camera_rotation: detection.camera.map(|camera| camera.rotation.to_array()),
+            reprojection_error: detection.reprojection_error.map(|error| error.rms)
+        }
+    }
+}
+
+static CSV_HEADER: &str = "frame,time,markers,marker_ids,solved,rotation_x,rotation_y,rotation_z,translation_x,translation_y,translation_z,camera_x,camera_y,camera_z,camera_qx,camera_qy,camera_qz,camera_qw,reprojection_error";
+
+/// Writes one row per frame. Marker corners don't fit a row, so only the ids are listed, separated by spaces.
+fn to_csv(frames: &[FramePose]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    let join = |values: Option<Vec<String>>, count: usize| values.unwrap_or_else(|| vec![String::new(); count]).join(",");
+    for frame in frames {
+        let ids: Vec<String> = frame.markers.iter().map(|marker| marker.id.to_string()).collect();
+        let _ = writeln!(
+            csv,
+            "{},{:.4},{},{},{},{},{},{},{},{}",
+            frame.frame,
+            frame.time,
+            frame.markers.len(),
+            ids.join(" "),
+            frame.solved,
+            join(frame.rotation.map(|values| values.iter().map(f64::to_string).collect()), 3),
+            join(frame.translation.map(|values| values.iter().map(f64::to_string).collect()), 3),
+            join(frame.camera_position.map(|values| values.iter().map(f32::to_string).collect()), 3),
+            join(frame.camera_rotation.map(|values| values.iter().map(f32::to_string).collect()), 4),
+            frame.reprojection_error.map(|error| error.to_string()).unwrap_or_default()
+        );
+    }
+    csv
+}
+
+/// Extracts the poses from every frame of the video with the settings in the world, and writes them to the output.
+pub fn run(video: &Path, output: &Path, world: &World) -> Result<(), Box<dyn Error>> {
+    println!("Extracting poses from {}", video.display());
+    let mut frames = Vec::new();
+    benchmark::detect_video_frames(video, world, |frame| frames.push(FramePose::new(frames.len(), frame.time, frame.detection)))?;
+
+    let is_csv = output.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let contents = if is_csv { to_csv(&frames) } else { serde_json::to_string_pretty(&frames)? };
+    fs::write(output, contents).map_err(|err| format!("Failed to write {}: {}", output.display(), err))?;
+
+    if frames.is_empty() {
+        println!("Wrote an empty pose list to {}; no frames could be read from the video", output.display());
+        return Ok(());
+    }
+    let solved = frames.iter().filter(|frame| frame.solved).count();
+    println!("Wrote {} frames to {}, with the pose solved on {} ({:.0}%)", frames.len(), output.display(), solved, solved as f64 / frames.len() as f64 * 100.0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsolved_frames_leave_pose_columns_empty() {
+        let frames = [
+            FramePose {
+                frame: 0,
+                time: 0.0,
+                markers: vec![DetectedMarker { id: 3, corners: [[0.0; 2]; 4] }, DetectedMarker { id: 7, corners: [[0.0; 2]; 4] }],
+                solved: true,
+                rotation: Some([0.1, 0.2, 0.3]),
+                translation: Some([1.0, 2.0, 3.0]),
+                camera_position: Some([4.0, 5.0, 6.0]),
+                camera_rotation: Some([0.0, 0.0, 0.0, 1.0]),
+                reprojection_error: Some(0.5)
+            },
+            FramePose {
+                frame: 1,
+                time: 0.0333,
+                markers: Vec::new(),
+                solved: false,
+                rotation: None,
+                translation: None,
+                camera_position: None,
+                camera_rotation: None,
+                reprojection_error: None
+            }
+        ];
+        let csv = to_csv(&frames);
+        let rows: Vec<&str> = csv.lines().collect();
+        let columns = CSV_HEADER.split(',').count();
+        assert_eq!(rows[1], "0,0.0000,2,3 7,true,0.1,0.2,0.3,1,2,3,4,5,6,0,0,0,1,0.5");
+        assert_eq!(rows[2], format!("1,0.0333,0,,false{}", ",".repeat(columns - 5)));
+        assert!(rows.iter().all(|row| row.split(',').count() == columns));
+    }
+}
@@ -0,0 +1,50 @@
+//! Opt-in frame profiling of the AR pipeline in external tools. Nothing here is compiled in unless a feature is enabled:
+//!
+//! - `--features tracy` records spans for every system plus the pipeline stages below. Connect with Tracy.
+//! - `--features puffin` records the pipeline stages and serves them on `PUFFIN_ADDRESS`. Connect with `puffin_viewer`.
+//!
+//! Build in release mode when profiling, or the numbers mostly measure the debug build.
+
+use bevy::app::{App, Plugin};
+
+/// Where the puffin server listens.
+#[cfg(feature = "puffin")]
+static PUFFIN_ADDRESS: &str = "127.0.0.1:8585";
+
+/// Times the rest of the enclosing block as a named pipeline stage, in whichever profilers are enabled.
+/// Works on any thread, like the capture and detection threads.
+macro_rules! profile_scope {
+    ($name:literal) => {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!($name);
+        #[cfg(feature = "tracy")]
+        let _span = bevy::log::info_span!($name).entered();
+    };
+}
+pub(crate) use profile_scope;
+
+#[cfg(feature = "puffin")]
+fn start_puffin_frame() {
+    puffin::GlobalProfiler::lock().new_frame();
+}
+
+pub struct ProfilingPlugin;
+
+impl Plugin for ProfilingPlugin {
+    #[allow(unused_variables)]
+    fn build(&self, app: &mut App) {
+        #[cfg(feature = "puffin")]
+        {
+            puffin::set_scopes_on(true);
+            match puffin_http::Server::new(PUFFIN_ADDRESS) {
+                Ok(server) => {
+                    println!("Serving puffin profiling data on {}", PUFFIN_ADDRESS);
+                    // The server stops when dropped, so keep it for the life of the app
+                    app.insert_non_send_resource(server);
+                }
+                Err(err) => eprintln!("Failed to start the puffin server on {}: {}", PUFFIN_ADDRESS, err)
+            }
+            app.add_systems(bevy::app::First, start_puffin_frame);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Saves crops of the marker candidates the detector rejected in the latest frame, each labeled with the stage that
+//! rejected it, for figuring out why a marker isn't detected. The stages are redone here the way OpenCV's ArUco
+//! detector does them, since it only reports which candidates failed and not why:
+//!
+//! - "low-contrast": the candidate is too evenly lit to tell black from white cells, usually from lighting or glare.
+//! - "border": too many cells of the black border read as white, usually a blurry, washed out, or partly covered print.
+//! - "unknown-code": the cells don't spell any marker in the dictionary, usually print quality, blur, or the wrong dictionary.
+//! - "decoded": it reads fine here, so it was dropped later, usually as a duplicate of a detected marker.
+//!
+//! Candidates with few pixels per cell are too small in the image to read reliably whatever their stage.
+
+use std::{error::Error, fs, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{app::{App, Plugin, Update}, ecs::{event::EventReader, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
+use opencv::{core::{self, Mat, MatTraitConst, MatTraitConstManual, Point2f, Point2i, Rect, Scalar, Size, Vector}, imgcodecs, imgproc, objdetect::Dictionary, prelude::DictionaryTraitConst};
+use serde_json::json;
+
+use crate::{command::AppCommand, video::aruco_camera::{self, ArucoTrackingData, FiducialLayout}};
+
+use super::snapshot::SNAPSHOT_DIR;
+
+/// `DetectorParameters` defaults, which the app's detector uses.
+const BORDER_BITS: i32 = 1;
+const PIXELS_PER_CELL: i32 = 4;
+const MIN_OTSU_STD_DEV: f64 = 5.0;
+const MAX_ERRONEOUS_BORDER_RATE: f64 = 0.35;
+const ERROR_CORRECTION_RATE: f64 = 0.6;
+/// Below this many pixels per cell in the camera image, a marker is too small to read reliably.
+const MIN_IMAGE_PIXELS_PER_CELL: f32 = 3.0;
+/// Crops are scaled up to at least this size, so small candidates can be seen.
+const MIN_CROP_SIZE: i32 = 160;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RejectionStage {
+    LowContrast,
+    Border,
+    UnknownCode,
+    Decoded
+}
+
+impl RejectionStage {
+    const ALL: [RejectionStage; 4] = [RejectionStage::LowContrast, RejectionStage::Border, RejectionStage::UnknownCode, RejectionStage::Decoded];
+
+    fn name(self) -> &'static str {
+        match self {
+            RejectionStage::LowContrast => "low-contrast",
+            RejectionStage::Border => "border",
+            RejectionStage::UnknownCode => "unknown-code",
+            RejectionStage::Decoded => "decoded"
+        }
+    }
+}
+
+/// What redoing the detector's checks on one candidate found.
+#[derive(Debug, Clone, Copy)]
+struct Inspection {
+    stage: RejectionStage,
+    /// The mean side length in pixels.
+    side: f32,
+    pixels_per_cell: f32,
+    /// The standard deviation of the brightness inside the candidate.
+    contrast: f64,
+    border_errors: usize
+}
+
+/// Rectifies the candidate into a grid of cells and reads its bits, like `cv::aruco::_extractBits`.
+fn inspect(greyscale_image: &Mat, corners: &Vector<Point2f>, dictionary: &Dictionary) -> opencv::Result<Inspection> {
+    let marker_size = dictionary.marker_size();
+    let cells = marker_size + 2 * BORDER_BITS;
+    let size = cells * PIXELS_PER_CELL;
+
+    let points = corners.to_vec();
+    let side = (0..points.len()).map(|index| {
+        let (a, b) = (points[index], points[(index + 1) % points.len()]);
+        ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+    }).sum::<f32>() / points.len().max(1) as f32;
+    let pixels_per_cell = side / cells as f32;
+
+    let last = (size - 1) as f32;
+    let square: Vector<Point2f> = Vector::from_iter([Point2f::new(0.0, 0.0), Point2f::new(last, 0.0), Point2f::new(last, last), Point2f::new(0.0, last)]);
+    let transform = imgproc::get_perspective_transform(corners, &square, core::DECOMP_LU)?;
+    let mut warped = Mat::default();
+    imgproc::warp_perspective(greyscale_image, &mut warped, &transform, Size::new(size, size), imgproc::INTER_NEAREST, core::BORDER_CONSTANT, Scalar::default())?;
+
+    // The contrast is measured inside the outer half cell, like the detector does
+    let warped_pixels = warped.data_bytes()?;
+    let inner = PIXELS_PER_CELL / 2..size - PIXELS_PER_CELL / 2;
+    let inner_pixels: Vec<f64> = inner.clone()
+        .flat_map(|y| inner.clone().map(move |x| warped_pixels[(y * size + x) as usize] as f64))
+        .collect();
+    let mean = inner_pixels.iter().sum::<f64>() / inner_pixels.len() as f64;
+    let contrast = (inner_pixels.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / inner_pixels.len() as f64).sqrt();
+    if contrast < MIN_OTSU_STD_DEV {
+        return Ok(Inspection { stage: RejectionStage::LowContrast, side, pixels_per_cell, contrast, border_errors: 0 });
+    }
+
+    let mut binary = Mat::default();
+    imgproc::threshold(&warped, &mut binary, 125.0, 255.0, imgproc::THRESH_BINARY | imgproc::THRESH_OTSU)?;
+    let pixels = binary.data_bytes()?;
+    let bit = |row: i32, column: i32| {
+        let white = (0..PIXELS_PER_CELL)
+            .flat_map(|y| (0..PIXELS_PER_CELL).map(move |x| ((row * PIXELS_PER_CELL + y) * size + column * PIXELS_PER_CELL + x) as usize))
+            .filter(|&index| pixels[index] != 0)
+            .count();
+        white as i32 > PIXELS_PER_CELL * PIXELS_PER_CELL / 2
+    };
+
+    // The border should be all black
+    let is_border = |row: i32, column: i32| row < BORDER_BITS || column < BORDER_BITS || row >= cells - BORDER_BITS || column >= cells - BORDER_BITS;
+    let border_errors = (0..cells)
+        .flat_map(|row| (0..cells).map(move |column| (row, column)))
+        .filter(|&(row, column)| is_border(row, column) && bit(row, column))
+        .count();
+    if border_errors as f64 > (marker_size * marker_size) as f64 * MAX_ERRONEOUS_BORDER_RATE {
+        return Ok(Inspection { stage: RejectionStage::Border, side, pixels_per_cell, contrast, border_errors });
+    }
+
+    let bits: Vec<Vec<u8>> = (BORDER_BITS..cells - BORDER_BITS)
+        .map(|row| (BORDER_BITS..cells - BORDER_BITS).map(|column| bit(row, column) as u8).collect())
+        .collect();
+    let (mut id, mut rotation) = (0, 0);
+    let stage = if dictionary.identify(&Mat::from_slice_2d(&bits)?, &mut id, &mut rotation, ERROR_CORRECTION_RATE)? {
+        RejectionStage::Decoded
+    } else {
+        RejectionStage::UnknownCode
+    };
+    Ok(Inspection { stage, side, pixels_per_cell, contrast, border_errors })
+}
+
+/// Cuts the candidate out of the frame with some margin, outlines it, and scales it up if it's small.
+fn crop(greyscale_image: &Mat, corners: &Vector<Point2f>) -> opencv::Result<Mat> {
+    let (xs, ys): (Vec<f32>, Vec<f32>) = corners.iter().map(|point| (point.x, point.y)).unzip();
+    let (left, right) = (xs.iter().copied().fold(f32::MAX, f32::min), xs.iter().copied().fold(f32::MIN, f32::max));
+    let (top, bottom) = (ys.iter().copied().fold(f32::MAX, f32::min), ys.iter().copied().fold(f32::MIN, f32::max));
+    let margin = ((right - left).max(bottom - top) * 0.25).max(4.0);
+    let left = ((left - margin) as i32).max(0);
+    let top = ((top - margin) as i32).max(0);
+    let right = ((right + margin) as i32).min(greyscale_image.cols());
+    let bottom = ((bottom + margin) as i32).min(greyscale_image.rows());
+
+    let region = Mat::roi(greyscale_image, Rect::new(left, top, (right - left).max(1), (bottom - top).max(1)))?;
+    let mut image = Mat::default();
+    imgproc::cvt_color(&region, &mut image, imgproc::COLOR_GRAY2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT)?;
+    let scale = (MIN_CROP_SIZE as f32 / image.cols().min(image.rows()) as f32).max(1.0);
+    if scale > 1.0 {
+        let mut scaled = Mat::default();
+        imgproc::resize(&image, &mut scaled, Size::new(0, 0), scale as f64, scale as f64, imgproc::INTER_NEAREST)?;
+        image = scaled;
+    }
+
+    let outline: Vector<Vector<Point2i>> = Vector::from_iter([corners.iter()
+        .map(|point| Point2i::new(((point.x - left as f32) * scale) as i32, ((point.y - top as f32) * scale) as i32))
+        .collect::<Vector<Point2i>>()]);
+    imgproc::polylines(&mut image, &outline, true, Scalar::new(0.0, 0.0, 255.0, 0.0), 1, imgproc::LINE_AA, 0)?;
+    Ok(image)
+}
+
+fn save_candidates(directory: &Path, greyscale_image: &Mat, rejected: &Vector<Vector<Point2f>>, dictionary: &Dictionary) -> Result<Vec<Inspection>, Box<dyn Error>> {
+    fs::create_dir_all(directory)?;
+    let mut inspections = Vec::new();
+    let mut described = Vec::new();
+    for (index, corners) in rejected.iter().enumerate() {
+        let inspection = inspect(greyscale_image, &corners, dictionary)?;
+        let name = format!("{:03}-{}.png", index, inspection.stage.name());
+        imgcodecs::imwrite(&directory.join(&name).to_string_lossy(), &crop(greyscale_image, &corners)?, &Vector::new())?;
+        described.push(json!({
+            "image": name,
+            "stage": inspection.stage.name(),
+            "corners": corners.iter().map(|point| [point.x, point.y]).collect::<Vec<_>>(),
+            "side_pixels": inspection.side,
+            "pixels_per_cell": inspection.pixels_per_cell,
+            "too_small": inspection.pixels_per_cell < MIN_IMAGE_PIXELS_PER_CELL,
+            "contrast": inspection.contrast,
+            "border_errors": inspection.border_errors
+        }));
+        inspections.push(inspection);
+    }
+    fs::write(directory.join("candidates.json"), serde_json::to_string_pretty(&described)?)?;
+    Ok(inspections)
+}
+
+/// Whether a capture was asked for and is waiting for a frame between detections.
+#[derive(Resource, Default)]
+struct RejectedCandidateCapture {
+    pending: bool
+}
+
+fn capture_rejected_candidates(
+    mut commands: EventReader<AppCommand>,
+    mut capture: ResMut<RejectedCandidateCapture>,
+    tracking_data: Res<ArucoTrackingData>,
+    fiducial_layout: Res<FiducialLayout>
+) {
+    for command in commands.read() {
+        if *command == AppCommand::SaveRejectedCandidates {
+            capture.pending = true;
+        }
+    }
+    if !capture.pending {
+        return;
+    }
+    let Some((greyscale_image, rejected)) = tracking_data.rejected_candidates() else { return };
+    capture.pending = false;
+
+    if rejected.is_empty() {
+        println!("No marker candidates were rejected in the latest frame");
+        return;
+    }
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+    let directory = Path::new(SNAPSHOT_DIR).join(format!("rejected-{}", timestamp));
+    let saved = fiducial_layout.dictionary.dictionary()
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|dictionary| save_candidates(&directory, greyscale_image, rejected, &dictionary));
+    match saved {
+        Ok(inspections) => {
+            let counts = RejectionStage::ALL.iter()
+                .map(|&stage| (stage, inspections.iter().filter(|inspection| inspection.stage == stage).count()))
+                .filter(|&(_, count)| count > 0)
+                .map(|(stage, count)| format!("{} {}", count, stage.name()))
+                .collect::<Vec<_>>();
+            let small = inspections.iter().filter(|inspection| inspection.pixels_per_cell < MIN_IMAGE_PIXELS_PER_CELL).count();
+            println!("Saved {} rejected marker candidates to {} ({}; {} too small in the image)", inspections.len(), directory.display(), counts.join(", "), small);
+        }
+        Err(err) => eprintln!("Failed to save rejected marker candidates to {}: {}", directory.display(), err)
+    }
+}
+
+pub struct RejectedCandidatesPlugin;
+
+impl Plugin for RejectedCandidatesPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<RejectedCandidateCapture>()
+            // The frame is only here between detections
+            .add_systems(Update, capture_rejected_candidates.after(aruco_camera::finish_marker_detection).before(aruco_camera::start_marker_detection));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opencv::objdetect::{self, PredefinedDictionaryType};
+
+    use super::*;
+
+    #[test]
+    fn rejection_stages_follow_the_detector() -> opencv::Result<()> {
+        let dictionary = objdetect::get_predefined_dictionary(PredefinedDictionaryType::DICT_APRILTAG_25h9)?;
+        let mut marker = Mat::default();
+        dictionary.generate_image_marker(3, 140, &mut marker, BORDER_BITS)?;
+        let mut image = Mat::default();
+        core::copy_make_border(&marker, &mut image, 50, 50, 50, 50, core::BORDER_CONSTANT, Scalar::all(255.0))?;
+        let corners: Vector<Point2f> = Vector::from_iter([Point2f::new(50.0, 50.0), Point2f::new(189.0, 50.0), Point2f::new(189.0, 189.0), Point2f::new(50.0, 189.0)]);
+
+        assert_eq!(inspect(&image, &corners, &dictionary)?.stage, RejectionStage::Decoded);
+
+        // Washing out the top and left of the border
+        let mut washed_out = image.try_clone()?;
+        imgproc::rectangle(&mut washed_out, Rect::new(50, 50, 140, 20), Scalar::all(255.0), imgproc::FILLED, imgproc::LINE_8, 0)?;
+        imgproc::rectangle(&mut washed_out, Rect::new(50, 50, 20, 140), Scalar::all(255.0), imgproc::FILLED, imgproc::LINE_8, 0)?;
+        assert_eq!(inspect(&washed_out, &corners, &dictionary)?.stage, RejectionStage::Border);
+
+        let flat = Mat::new_rows_cols_with_default(240, 240, core::CV_8UC1, Scalar::all(128.0))?;
+        assert_eq!(inspect(&flat, &corners, &dictionary)?.stage, RejectionStage::LowContrast);
+        Ok(())
+    }
+}
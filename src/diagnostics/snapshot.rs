@@ -0,0 +1,137 @@
+//! A "dump state" hotkey that saves a zip of everything needed to debug tracking issues remotely:
+//! the camera pose, tracking data, configuration, recent errors, and a thumbnail of the current frame.
+
+use std::{error::Error, fs::{self, File}, io::Write, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{app::{App, Plugin, Update}, core_pipeline::core_3d::Camera3d, ecs::{event::EventReader, query::{Has, With}, system::{Query, Res}}, transform::components::Transform};
+use opencv::{core::{Mat, MatTraitConst, Size, Vector}, imgcodecs, imgproc};
+use serde_json::json;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{camera_cuts::ShotCamera, command::AppCommand, render_layers::OutputCamera, video::{aruco_camera::{ArucoTrackingData, CalibrationFile, FiducialLayout}, WebcamFrame}};
+
+use super::RecentErrors;
+
+pub static SNAPSHOT_DIR: &str = "snapshots";
+const THUMBNAIL_WIDTH: i32 = 480;
+
+/// Encodes a downscaled JPEG of the frame, or `None` if there is no frame.
+fn encode_thumbnail(frame: &Mat) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    if frame.empty() {
+        return Ok(None);
+    }
+
+    let scale = THUMBNAIL_WIDTH as f64 / frame.cols() as f64;
+    let mut thumbnail = Mat::default();
+    imgproc::resize(frame, &mut thumbnail, Size::new(THUMBNAIL_WIDTH, (frame.rows() as f64 * scale) as i32), 0.0, 0.0, imgproc::INTER_AREA)?;
+
+    let mut encoded = Vector::<u8>::new();
+    imgcodecs::imencode(".jpg", &thumbnail, &mut encoded, &Vector::new())?;
+    Ok(Some(encoded.to_vec()))
+}
+
+fn write_snapshot(
+    path: &Path,
+    state: &serde_json::Value,
+    calibration_path: &str,
+    thumbnail: Option<&[u8]>
+) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file("state.json", options)?;
+    zip.write_all(serde_json::to_string_pretty(state)?.as_bytes())?;
+
+    if let Ok(calibration) = fs::read(calibration_path) {
+        zip.start_file("calibration.json", options)?;
+        zip.write_all(&calibration)?;
+    }
+
+    if let Some(thumbnail) = thumbnail {
+        zip.start_file("frame.jpg", SimpleFileOptions::default().compression_method(CompressionMethod::Stored))?;
+        zip.write_all(thumbnail)?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+fn dump_state_hotkey(
+    mut commands: EventReader<AppCommand>,
+    webcam_frame: Res<WebcamFrame>,
+    tracking_data: Res<ArucoTrackingData>,
+    fiducial_layout: Res<FiducialLayout>,
+    calibration_file: Res<CalibrationFile>,
+    recent_errors: Res<RecentErrors>,
+    cameras: Query<(&Transform, Option<&OutputCamera>, Has<ShotCamera>), With<Camera3d>>
+) {
+    let mut requested = false;
+    for command in commands.read() {
+        requested |= *command == AppCommand::SaveSnapshot;
+    }
+    if !requested {
+        return;
+    }
+
+    let frame = &webcam_frame.0;
+    let (rotation_vector, translation_vector) = tracking_data.latest_pose();
+    let state = json!({
+        "camera_transforms": cameras.iter().map(|(transform, output, shot)| {
+            // Camera cut shots are labeled apart from the output they're shown in
+            let camera = if shot { "Shot".to_string() } else { output.map_or("Unknown".to_string(), |output| format!("{:?}", output)) };
+            json!({
+                "camera": camera,
+                "translation": transform.translation.to_array(),
+                "rotation": transform.rotation.to_array()
+            })
+        }).collect::<Vec<_>>(),
+        "tracking": {
+            "detected_ids": tracking_data.detected_ids(),
+            "rejected_candidates": tracking_data.rejected_count(),
+            "rotation_vector": rotation_vector,
+            "translation_vector": translation_vector
+        },
+        "frame": {
+            "width": frame.cols(),
+            "height": frame.rows(),
+            "channels": frame.channels()
+        },
+        "config": {
+            "fiducial_size": fiducial_layout.size,
+            "fiducials": fiducial_layout.fiducials.iter().map(|fiducial| json!({
+                "id": fiducial.id,
+                "x_offset": fiducial.x_offset,
+                "y_offset": fiducial.y_offset,
+                "z_offset": fiducial.z_offset
+            })).collect::<Vec<_>>()
+        },
+        "recent_errors": recent_errors.errors.iter().map(|(time, message)| json!({
+            "time": time,
+            "message": message
+        })).collect::<Vec<_>>()
+    });
+
+    let thumbnail = encode_thumbnail(frame).unwrap_or_else(|err| {
+        eprintln!("Failed to encode frame thumbnail: {}", err);
+        None
+    });
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+    let path = Path::new(SNAPSHOT_DIR).join(format!("snapshot-{}.zip", timestamp));
+    match write_snapshot(&path, &state, &calibration_file.0, thumbnail.as_deref()) {
+        Ok(()) => println!("Saved state snapshot to {}", path.display()),
+        Err(err) => eprintln!("Failed to save state snapshot to {}: {}", path.display(), err)
+    }
+}
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, dump_state_hotkey);
+    }
+}
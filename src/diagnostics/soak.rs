@@ -0,0 +1,140 @@
+//! Soak test mode: runs the full pipeline for a fixed time, usually on a looping recorded session,
+//! and exits nonzero if memory grows, errors pile up, or the frame rate drops.
+//! This catches the per-frame allocation leaks a Mat-heavy video path is prone to.
+
+use std::time::Duration;
+
+use bevy::{app::{App, AppExit, Plugin, Update}, ecs::{event::EventWriter, resource::Resource, system::{Res, ResMut}}, time::{Real, Time}};
+
+use super::{percentile, RecentErrors};
+
+/// Memory and errors aren't measured until the pipeline has warmed up.
+const WARMUP: Duration = Duration::from_secs(30);
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct SoakThresholds {
+    /// The maximum resident memory growth after warmup, in bytes.
+    pub max_memory_growth: u64,
+    pub max_errors_per_minute: f64,
+    /// The 1st percentile FPS must stay at or above this.
+    pub min_low_fps: f64
+}
+
+impl Default for SoakThresholds {
+    fn default() -> Self {
+        Self {
+            max_memory_growth: 64 * 1024 * 1024,
+            max_errors_per_minute: 30.0,
+            min_low_fps: 15.0
+        }
+    }
+}
+
+/// Present only when the app was started in soak test mode.
+#[derive(Resource)]
+pub struct SoakTest {
+    pub duration: Duration,
+    pub thresholds: SoakThresholds,
+    frame_times: Vec<f32>,
+    memory_samples: Vec<u64>,
+    last_memory_sample: Duration,
+    errors_at_warmup: Option<u64>
+}
+
+impl SoakTest {
+    pub fn new(minutes: f64) -> Self {
+        Self {
+            duration: Duration::from_secs_f64(minutes * 60.0),
+            thresholds: SoakThresholds::default(),
+            frame_times: Vec::new(),
+            memory_samples: Vec::new(),
+            last_memory_sample: Duration::ZERO,
+            errors_at_warmup: None
+        }
+    }
+}
+
+/// Returns the resident set size of this process in bytes, where supported.
+pub fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(resident_pages * 4096)
+}
+
+fn run_soak_test(
+    soak: Option<ResMut<SoakTest>>,
+    recent_errors: Res<RecentErrors>,
+    time: Res<Time<Real>>,
+    mut exit: EventWriter<AppExit>
+) {
+    let Some(mut soak) = soak else { return };
+    let elapsed = time.elapsed();
+
+    if elapsed < WARMUP {
+        return;
+    }
+    if soak.errors_at_warmup.is_none() {
+        soak.errors_at_warmup = Some(recent_errors.total);
+    }
+
+    soak.frame_times.push(time.delta_secs());
+    if elapsed - soak.last_memory_sample >= MEMORY_SAMPLE_INTERVAL {
+        soak.last_memory_sample = elapsed;
+        if let Some(memory) = resident_memory() {
+            soak.memory_samples.push(memory);
+        }
+    }
+
+    if elapsed < WARMUP + soak.duration {
+        return;
+    }
+
+    // Report and exit
+    let mut fps: Vec<f64> = soak.frame_times.iter().filter(|&&delta| delta > 0.0).map(|&delta| 1.0 / delta as f64).collect();
+    fps.sort_by(f64::total_cmp);
+    let low_fps = percentile(&fps, 0.01);
+    let median_fps = percentile(&fps, 0.5);
+
+    let memory_growth = match (soak.memory_samples.first(), soak.memory_samples.last()) {
+        (Some(first), Some(last)) => last.saturating_sub(*first),
+        _ => 0
+    };
+    let errors = recent_errors.total - soak.errors_at_warmup.unwrap_or_default();
+    let errors_per_minute = errors as f64 / soak.duration.as_secs_f64().max(1.0) * 60.0;
+
+    println!("Soak test finished after {:.1} minutes", soak.duration.as_secs_f64() / 60.0);
+    println!("  FPS: median {:.1}, 1st percentile {:.1}", median_fps, low_fps);
+    println!("  Memory growth: {:.1} MiB over {} samples", memory_growth as f64 / 1024.0 / 1024.0, soak.memory_samples.len());
+    println!("  Errors: {} ({:.1}/minute)", errors, errors_per_minute);
+
+    let thresholds = &soak.thresholds;
+    let mut failures = Vec::new();
+    if memory_growth > thresholds.max_memory_growth {
+        failures.push(format!("memory grew by {} bytes (limit {})", memory_growth, thresholds.max_memory_growth));
+    }
+    if errors_per_minute > thresholds.max_errors_per_minute {
+        failures.push(format!("{:.1} errors/minute (limit {:.1})", errors_per_minute, thresholds.max_errors_per_minute));
+    }
+    if low_fps < thresholds.min_low_fps {
+        failures.push(format!("1st percentile FPS {:.1} (minimum {:.1})", low_fps, thresholds.min_low_fps));
+    }
+
+    if failures.is_empty() {
+        println!("Soak test passed");
+        exit.write(AppExit::Success);
+    } else {
+        for failure in &failures {
+            eprintln!("Soak test regression: {}", failure);
+        }
+        exit.write(AppExit::from_code(1));
+    }
+}
+
+pub struct SoakTestPlugin;
+
+impl Plugin for SoakTestPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, run_soak_test);
+    }
+}
@@ -0,0 +1,161 @@
+//! How long each stage of the video pipeline takes, recorded as Bevy diagnostics and shown in a HUD toggled with F3.
+//!
+//! Capture, tracking, and upload are the time from the start to the end of `VideoCaptureSystems`,
+//! `VideoUpdateSystems`, and `VideoDrawSystems` each frame. Convert is the greyscale conversion before detection, and
+//! detect and PnP are measured on the detection thread, so they only update on frames that ran detection.
+
+use std::time::{Duration, Instant};
+
+use bevy::{app::{App, Plugin, Startup, Update}, color::Color, diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, DiagnosticsStore, RegisterDiagnostic}, ecs::{component::Component, event::EventReader, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Res, ResMut, Single}}, text::{TextColor, TextFont}, ui::{widget::Text, BackgroundColor, Display, Node, PositionType, UiRect, Val}, utils::default};
+
+use crate::{command::AppCommand, VideoCaptureSystems, VideoDrawSystems, VideoUpdateSystems};
+
+const HUD_FONT_SIZE: f32 = 14.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineStage {
+    /// Taking the latest frame from the capture thread.
+    Capture,
+    /// Converting the frame to greyscale for detection.
+    Convert,
+    /// Finding the markers, including corner refinement.
+    Detect,
+    /// Solving the camera pose from the markers.
+    Pnp,
+    /// Collecting detections and updating the tracking state.
+    Tracking,
+    /// Converting the frame for display and uploading it to the GPU.
+    Upload
+}
+
+impl PipelineStage {
+    pub const ALL: [PipelineStage; 6] = [PipelineStage::Capture, PipelineStage::Convert, PipelineStage::Detect, PipelineStage::Pnp, PipelineStage::Tracking, PipelineStage::Upload];
+
+    pub fn diagnostic_path(&self) -> DiagnosticPath {
+        match self {
+            PipelineStage::Capture => DiagnosticPath::const_new("pipeline/capture"),
+            PipelineStage::Convert => DiagnosticPath::const_new("pipeline/convert"),
+            PipelineStage::Detect => DiagnosticPath::const_new("pipeline/detect"),
+            PipelineStage::Pnp => DiagnosticPath::const_new("pipeline/pnp"),
+            PipelineStage::Tracking => DiagnosticPath::const_new("pipeline/tracking"),
+            PipelineStage::Upload => DiagnosticPath::const_new("pipeline/upload")
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PipelineStage::Capture => "Capture",
+            PipelineStage::Convert => "Convert",
+            PipelineStage::Detect => "Detect",
+            PipelineStage::Pnp => "PnP",
+            PipelineStage::Tracking => "Tracking",
+            PipelineStage::Upload => "Upload"
+        }
+    }
+
+    /// Records one measurement of the stage.
+    pub fn record(self, diagnostics: &mut Diagnostics, time: Duration) {
+        diagnostics.add_measurement(&self.diagnostic_path(), || time.as_secs_f64() * 1000.0);
+    }
+}
+
+/// When each system set's span started this frame.
+#[derive(Resource, Default)]
+struct StageSpans([Option<Instant>; 6]);
+
+fn begin_span(stage: PipelineStage) -> impl FnMut(ResMut<StageSpans>) {
+    move |mut spans: ResMut<StageSpans>| spans.0[stage as usize] = Some(Instant::now())
+}
+
+fn end_span(stage: PipelineStage) -> impl FnMut(ResMut<StageSpans>, Diagnostics) {
+    move |mut spans: ResMut<StageSpans>, mut diagnostics: Diagnostics| {
+        if let Some(started) = spans.0[stage as usize].take() {
+            stage.record(&mut diagnostics, started.elapsed());
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct StageTimingHud {
+    pub visible: bool
+}
+
+#[derive(Component)]
+struct StageTimingText;
+
+fn spawn_stage_timing_hud(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            right: Val::Px(8.0),
+            padding: UiRect::axes(Val::Px(8.0), Val::Px(4.0)),
+            display: Display::None,
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.5)),
+        Text::new(""),
+        TextFont {
+            font_size: HUD_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        StageTimingText
+    ));
+}
+
+fn update_stage_timing_hud(
+    mut commands: EventReader<AppCommand>,
+    mut hud: ResMut<StageTimingHud>,
+    store: Res<DiagnosticsStore>,
+    text: Single<(&mut Node, &mut Text), With<StageTimingText>>
+) {
+    for command in commands.read() {
+        if *command == AppCommand::ToggleStageTiming {
+            hud.visible = !hud.visible;
+        }
+    }
+    let (mut node, mut text) = text.into_inner();
+    let display = if hud.visible { Display::Flex } else { Display::None };
+    if node.display != display {
+        node.display = display;
+    }
+    if !hud.visible {
+        return;
+    }
+
+    let lines: Vec<String> = PipelineStage::ALL.iter().map(|stage| {
+        match store.get(&stage.diagnostic_path()).and_then(|diagnostic| diagnostic.smoothed()) {
+            Some(milliseconds) => format!("{:<9}{:>6.2} ms", stage.label(), milliseconds),
+            None => format!("{:<9}{:>9}", stage.label(), "-")
+        }
+    }).collect();
+    let label = lines.join("\n");
+    if text.0 != label {
+        text.0 = label;
+    }
+}
+
+pub struct StageTimingPlugin;
+
+impl Plugin for StageTimingPlugin {
+    fn build(&self, app: &mut App) {
+        for stage in PipelineStage::ALL {
+            app.register_diagnostic(Diagnostic::new(stage.diagnostic_path()).with_suffix(" ms"));
+        }
+
+        app
+            .init_resource::<StageSpans>()
+            .init_resource::<StageTimingHud>()
+            .add_systems(Startup, spawn_stage_timing_hud)
+            .add_systems(Update, (
+                begin_span(PipelineStage::Capture).before(VideoCaptureSystems),
+                end_span(PipelineStage::Capture).after(VideoCaptureSystems),
+                begin_span(PipelineStage::Tracking).before(VideoUpdateSystems).after(VideoCaptureSystems),
+                end_span(PipelineStage::Tracking).after(VideoUpdateSystems),
+                begin_span(PipelineStage::Upload).before(VideoDrawSystems).after(VideoUpdateSystems),
+                end_span(PipelineStage::Upload).after(VideoDrawSystems),
+                update_stage_timing_hud
+            ));
+    }
+}
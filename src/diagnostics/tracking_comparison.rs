@@ -0,0 +1,328 @@
+//! A/B comparison of two tracking configurations, run with `--compare-tracking <VIDEO> --compare-config <TOML>`.
+//! Every frame of a recorded video or F9 session goes through detection and pose estimation twice: with the usual
+//! config (A) and with the other config file (B), each with its own calibration, fiducial layout, detection settings,
+//! and keyboard profile. The two overlays are drawn side by side with how far the poses diverge, shown in a window or
+//! written to a video with `--compare-output`, and the divergence over the whole recording is printed at the end.
+//!
+//! Divergence is only measured on frames where both configurations solved the pose: how far apart the two camera
+//! positions are, the angle between their rotations, and how far apart the keyboard's corners land in the image. Each
+//! side's poses are smoothed with its own tracking settings first, like the app smooths the camera, so the comparison
+//! is of where the overlays would actually be drawn.
+
+use std::{error::Error, path::{Path, PathBuf}};
+
+use bevy::{ecs::world::World, math::{Mat3, Vec3}, transform::components::Transform};
+use opencv::{calib3d, core::{self, AlgorithmHint, Mat, MatTraitConst, Point, Point2f, Point3f, Scalar, Vector}, highgui, imgcodecs, imgproc, videoio::{self, VideoCapture, VideoCaptureTrait, VideoCaptureTraitConst, VideoWriter, VideoWriterTrait}};
+
+use crate::{keyboard::{profile::InstrumentProfile, KeyboardPlane}, recording, replay, video::{aruco_camera::{CalibrationFile, CameraIntrinsics, DetectionSettings, FiducialLayout, FrameDetection, FrameDetector}, tracking::{smoothing::PoseFilter, TrackingSettings}}};
+
+use super::Summary;
+
+static WINDOW_TITLE: &str = "AR Piano Visualizer - Tracking comparison";
+/// The length of the drawn pose axes in mm.
+const AXIS_LENGTH: f32 = 50.0;
+
+/// Where the frames come from: a video file, or the frames of a session recorded with F9.
+enum FrameSource {
+    Video(VideoCapture),
+    Session { dir: PathBuf, next: u32 }
+}
+
+impl FrameSource {
+    fn open(path: &Path) -> Result<FrameSource, Box<dyn Error>> {
+        if path.is_dir() {
+            return Ok(FrameSource::Session { dir: path.to_path_buf(), next: 0 });
+        }
+        let capture = VideoCapture::from_file(&path.to_string_lossy(), videoio::CAP_ANY)?;
+        if !capture.is_opened()? {
+            return Err(format!("Failed to open {}", path.display()).into());
+        }
+        Ok(FrameSource::Video(capture))
+    }
+
+    fn fps(&self) -> f64 {
+        match self {
+            FrameSource::Video(capture) => capture.get(videoio::CAP_PROP_FPS).ok().filter(|fps| *fps > 0.0).unwrap_or(30.0),
+            FrameSource::Session { .. } => 30.0
+        }
+    }
+
+    /// Reads the next frame, returning false at the end.
+    fn read(&mut self, frame: &mut Mat) -> Result<bool, Box<dyn Error>> {
+        match self {
+            FrameSource::Video(capture) => Ok(capture.read(frame)? && !frame.empty()),
+            FrameSource::Session { dir, next } => {
+                let path = replay::frame_path(dir, *next);
+                if !path.exists() {
+                    return Ok(false);
+                }
+                *frame = imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_COLOR)?;
+                *next += 1;
+                Ok(!frame.empty())
+            }
+        }
+    }
+}
+
+/// One tracking configuration and what it found in the current frame.
+struct Side {
+    label: String,
+    color: Scalar,
+    intrinsics: CameraIntrinsics,
+    layout: FiducialLayout,
+    settings: DetectionSettings,
+    tracking: TrackingSettings,
+    filter: PoseFilter,
+    /// The corners of the lowest manual's keys in the fiducial frame.
+    keyboard_outline: Vector<Point3f>,
+    detector: FrameDetector,
+    solved: usize,
+    reprojection_errors: Vec<f64>
+}
+
+impl Side {
+    fn new(label: String, color: Scalar, world: &World) -> Result<Side, Box<dyn Error>> {
+        let calibration = world.get_resource::<CalibrationFile>().cloned().unwrap_or_default();
+        let intrinsics = CameraIntrinsics::load(&calibration.0).map_err(|err| format!("Failed to load {}: {}", calibration.0, err))?;
+        let layout = world.get_resource::<FiducialLayout>().cloned().unwrap_or_default();
+        let profile = world.get_resource::<InstrumentProfile>().cloned().unwrap_or_default();
+
+        let mut keyboard_outline = Vector::new();
+        if let Some(manual) = profile.manuals.first() {
+            let keys = manual.layout();
+            let corners = if manual.markers.is_empty() { layout.corner_positions() } else { layout.corner_positions_of(&manual.markers) };
+            let plane = KeyboardPlane::fit_layout_corners(&corners, manual.center_x).unwrap_or_default();
+            let (left, right) = (-keys.width() / 2.0, keys.width() / 2.0);
+            for corner in [Vec3::new(left, 0.0, keys.geometry.key_back_z), Vec3::new(right, 0.0, keys.geometry.key_back_z), Vec3::new(right, 0.0, keys.front_z()), Vec3::new(left, 0.0, keys.front_z())] {
+                let corner = plane.to_world(corner);
+                keyboard_outline.push(Point3f::new(corner.x, corner.y, corner.z));
+            }
+        }
+
+        Ok(Side {
+            label,
+            color,
+            intrinsics,
+            detector: FrameDetector::new(&layout)?,
+            layout,
+            settings: world.get_resource::<DetectionSettings>().cloned().unwrap_or_default(),
+            tracking: world.get_resource::<TrackingSettings>().cloned().unwrap_or_default(),
+            filter: PoseFilter::default(),
+            keyboard_outline,
+            solved: 0,
+            reprojection_errors: Vec::new()
+        })
+    }
+
+    /// Detects the pose in a frame `time` seconds into the recording, and replaces the camera with the smoothed one.
+    fn detect(&mut self, greyscale: Mat, time: f64) -> opencv::Result<FrameDetection> {
+        let mut detection = self.detector.detect(greyscale, &self.layout, &self.settings, &self.intrinsics)?;
+        if detection.solved {
+            self.solved += 1;
+        }
+        self.reprojection_errors.extend(detection.reprojection_error.map(|error| error.rms));
+        detection.camera = detection.camera.map(|camera| self.filter.update(time, camera, &self.tracking));
+        Ok(detection)
+    }
+
+    /// Where the keyboard's corners land in the image with the smoothed camera.
+    fn project_keyboard(&self, detection: &FrameDetection) -> opencv::Result<Option<Vector<Point2f>>> {
+        let Some(camera) = detection.camera else { return Ok(None) };
+        if self.keyboard_outline.is_empty() {
+            return Ok(None);
+        }
+        let (rotation, translation) = opencv_pose(&camera)?;
+        let mut projected = Vector::new();
+        calib3d::project_points_def(&self.keyboard_outline, &rotation, &translation, &self.intrinsics.camera_matrix, &self.intrinsics.dist_coeffs, &mut projected)?;
+        Ok(Some(projected))
+    }
+
+    /// Draws the detected markers, the pose's axes, and the keyboard outline over a copy of the frame.
+    fn draw(&self, frame: &Mat, detection: &FrameDetection, keyboard: Option<&Vector<Point2f>>) -> opencv::Result<Mat> {
+        let mut image = frame.try_clone()?;
+        let markers: Vector<Vector<Point>> = detection.detected.iter()
+            .map(|(_, corners)| corners.iter().map(|corner| Point::new(corner[0] as i32, corner[1] as i32)).collect())
+            .collect();
+        imgproc::polylines(&mut image, &markers, true, self.color, 2, imgproc::LINE_AA, 0)?;
+        if let Some(keyboard) = keyboard {
+            let outline: Vector<Vector<Point>> = Vector::from_iter([keyboard.iter().map(|corner| Point::new(corner.x as i32, corner.y as i32)).collect::<Vector<Point>>()]);
+            imgproc::polylines(&mut image, &outline, true, self.color, 2, imgproc::LINE_AA, 0)?;
+        }
+        if let Some(camera) = detection.camera {
+            let (rotation, translation) = opencv_pose(&camera)?;
+            calib3d::draw_frame_axes(&mut image, &self.intrinsics.camera_matrix, &self.intrinsics.dist_coeffs, &rotation, &translation, AXIS_LENGTH, 2)?;
+        }
+
+        let status = match &detection.reprojection_error {
+            Some(error) => format!("{}: {} markers, {:.2} px RMS", self.label, detection.markers, error.rms),
+            None => format!("{}: {} markers, no pose", self.label, detection.markers)
+        };
+        draw_text(&mut image, &status, 30, self.color)?;
+        Ok(image)
+    }
+}
+
+/// Converts a camera's transform in the fiducial frame back into an OpenCV board pose (rotation vector, translation),
+/// undoing `FrameDetection::camera`.
+fn opencv_pose(camera: &Transform) -> opencv::Result<(Mat, Mat)> {
+    // Bevy cameras look down -z with +y up, and OpenCV cameras down +z with +y down the image
+    let rotation = Mat3::from_diagonal(Vec3::new(1.0, -1.0, -1.0)) * Mat3::from_quat(camera.rotation).transpose();
+    let translation = -(rotation * camera.translation);
+
+    let rows = [0, 1, 2].map(|row| rotation.row(row).to_array().map(f64::from));
+    let mut rotation_vector = Mat::default();
+    calib3d::rodrigues_def(&Mat::from_slice_2d(&rows)?, &mut rotation_vector)?;
+    Ok((rotation_vector, Mat::from_slice(&translation.to_array().map(f64::from))?.try_clone()?))
+}
+
+fn draw_text(image: &mut Mat, text: &str, y: i32, color: Scalar) -> opencv::Result<()> {
+    // A dark outline keeps the text readable over bright frames
+    imgproc::put_text(image, text, Point::new(10, y), imgproc::FONT_HERSHEY_SIMPLEX, 0.7, Scalar::all(0.0), 4, imgproc::LINE_AA, false)?;
+    imgproc::put_text(image, text, Point::new(10, y), imgproc::FONT_HERSHEY_SIMPLEX, 0.7, color, 2, imgproc::LINE_AA, false)
+}
+
+/// How far apart two camera poses are: the distance between them in mm and the angle between them in degrees.
+fn pose_divergence(a: &Transform, b: &Transform) -> (f64, f64) {
+    (a.translation.distance(b.translation) as f64, a.rotation.angle_between(b.rotation).to_degrees() as f64)
+}
+
+/// The mean distance in pixels between corresponding points.
+fn mean_distance(a: &Vector<Point2f>, b: &Vector<Point2f>) -> f64 {
+    let distances: Vec<f64> = a.iter().zip(b.iter()).map(|(a, b)| ((a.x - b.x) as f64).hypot((a.y - b.y) as f64)).collect();
+    distances.iter().sum::<f64>() / distances.len().max(1) as f64
+}
+
+/// Compares the configs in `world_a` and `world_b` on every frame of the video, and prints how far they diverged.
+pub fn run(video: &Path, label_b: &str, world_a: &World, world_b: &World, output: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mut sides = [
+        Side::new("A".to_string(), Scalar::new(80.0, 220.0, 80.0, 0.0), world_a)?,
+        Side::new(format!("B ({})", label_b), Scalar::new(230.0, 80.0, 230.0, 0.0), world_b)?
+    ];
+    let mut source = FrameSource::open(video)?;
+    let mut writer: Option<VideoWriter> = None;
+
+    match output {
+        Some(path) => println!("Comparing tracking on {}, writing the overlays to {}", video.display(), path.display()),
+        None => println!("Comparing tracking on {}; press Space to pause and Q or Esc to stop", video.display())
+    }
+    let (mut positions, mut rotations, mut pixels) = (Vec::new(), Vec::new(), Vec::new());
+    let mut frames = 0;
+    let mut paused = false;
+    let mut frame = Mat::default();
+    let fps = source.fps();
+    while source.read(&mut frame)? {
+        let time = frames as f64 / fps;
+        frames += 1;
+        let mut greyscale = Mat::default();
+        imgproc::cvt_color(&frame, &mut greyscale, imgproc::COLOR_BGR2GRAY, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+        let detections = [sides[0].detect(greyscale.try_clone()?, time)?, sides[1].detect(greyscale, time)?];
+        let keyboards = [sides[0].project_keyboard(&detections[0])?, sides[1].project_keyboard(&detections[1])?];
+
+        let divergence = match (detections[0].camera, detections[1].camera) {
+            (Some(a), Some(b)) => {
+                let (position, rotation) = pose_divergence(&a, &b);
+                positions.push(position);
+                rotations.push(rotation);
+                let pixel = match (&keyboards[0], &keyboards[1]) {
+                    (Some(a), Some(b)) => {
+                        let pixel = mean_distance(a, b);
+                        pixels.push(pixel);
+                        format!(", keyboard corners {:.1} px apart", pixel)
+                    }
+                    _ => String::new()
+                };
+                format!("Frame {}: cameras {:.1} mm and {:.2} deg apart{}", frames, position, rotation, pixel)
+            }
+            _ => format!("Frame {}: not solved by both", frames)
+        };
+
+        let mut combined = Mat::default();
+        core::hconcat2(&sides[0].draw(&frame, &detections[0], keyboards[0].as_ref())?, &sides[1].draw(&frame, &detections[1], keyboards[1].as_ref())?, &mut combined)?;
+        let bottom = combined.rows() - 15;
+        draw_text(&mut combined, &divergence, bottom, Scalar::all(255.0))?;
+
+        match output {
+            Some(path) => {
+                if writer.is_none() {
+                    writer = Some(recording::open_video_writer(path, combined.cols() as u32, combined.rows() as u32, fps)?);
+                }
+                if let Some(writer) = writer.as_mut() {
+                    writer.write(&combined)?;
+                }
+            }
+            None => {
+                highgui::imshow(WINDOW_TITLE, &combined)?;
+                if !wait_for_next_frame(&mut paused)? {
+                    break;
+                }
+            }
+        }
+    }
+    match writer {
+        Some(mut writer) => writer.release()?,
+        None => highgui::destroy_all_windows()?
+    }
+    if frames == 0 {
+        return Err(format!("{} has no frames", video.display()).into());
+    }
+
+    println!("Compared {} frames", frames);
+    for side in &sides {
+        let rms = Summary::of(&side.reprojection_errors).mean;
+        println!("  {}: pose solved on {} ({:.0}%), {:.2} px mean RMS reprojection error", side.label, side.solved, side.solved as f64 / frames as f64 * 100.0, rms);
+    }
+    if positions.is_empty() {
+        println!("  No frame was solved by both, so there's no divergence to report");
+        return Ok(());
+    }
+    println!("  Divergence over the {} frames solved by both (mean / 95th percentile / max):", positions.len());
+    for (name, values, unit) in [("Camera position", &positions, "mm"), ("Camera rotation", &rotations, "deg"), ("Keyboard corners", &pixels, "px")] {
+        if !values.is_empty() {
+            let Summary { mean, p95, max, .. } = Summary::of(values);
+            println!("    {}: {:.2} / {:.2} / {:.2} {}", name, mean, p95, max, unit);
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the window's keys, returning false to stop. Space pauses and resumes, and Q or Esc stops.
+fn wait_for_next_frame(paused: &mut bool) -> opencv::Result<bool> {
+    loop {
+        match highgui::wait_key(if *paused { 50 } else { 1 })? {
+            27 | 113 => return Ok(false),
+            32 => *paused = !*paused,
+            _ => {}
+        }
+        if !*paused {
+            return Ok(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Quat;
+
+    use super::*;
+
+    #[test]
+    fn divergence_measures_distance_and_angle() {
+        let a = Transform::from_xyz(0.0, 300.0, 400.0);
+        let b = Transform::from_xyz(3.0, 304.0, 400.0).with_rotation(Quat::from_rotation_y(2f32.to_radians()));
+        let (distance, angle) = pose_divergence(&a, &b);
+        assert!((distance - 5.0).abs() < 1e-4);
+        assert!((angle - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn opencv_poses_round_trip_through_the_camera_transform() {
+        let camera = Transform::from_xyz(20.0, 300.0, 400.0).looking_at(Vec3::ZERO, Vec3::Y);
+        let (rotation, translation) = opencv_pose(&camera).unwrap();
+        let mut projected: Vector<Point2f> = Vector::new();
+        let camera_matrix = Mat::from_slice_2d(&[[1000.0, 0.0, 640.0], [0.0, 1000.0, 360.0], [0.0, 0.0, 1.0]]).unwrap();
+        calib3d::project_points_def(&Vector::<Point3f>::from_iter([Point3f::new(0.0, 0.0, 0.0)]), &rotation, &translation, &camera_matrix, &Mat::default(), &mut projected).unwrap();
+        // The camera looks straight at the origin, so it lands in the middle of the image
+        let center = projected.get(0).unwrap();
+        assert!((center.x - 640.0).abs() < 0.01 && (center.y - 360.0).abs() < 0.01);
+    }
+}
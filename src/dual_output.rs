@@ -0,0 +1,97 @@
+//! Dual-output composition: opens two extra windows, one with the clean camera feed and one with the AR overlay drawn on it,
+//! so producers can capture both and mix them externally. The main window keeps its debug visuals and settings panel.
+
+use bevy::{app::{App, Plugin, Update}, core_pipeline::core_3d::Camera3d, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut}}, math::Vec3, render::camera::{Camera, RenderTarget}, transform::components::Transform, utils::default, window::{Window, WindowRef}};
+
+use crate::render_layers::OutputCamera;
+
+/// The feeds opened when dual output is enabled, with their window titles.
+static FEEDS: [(OutputCamera, &str); 2] = [
+    (OutputCamera::CleanFeed, "AR Piano Visualizer - Clean feed"),
+    (OutputCamera::OverlayFeed, "AR Piano Visualizer - Overlay feed")
+];
+
+#[derive(Resource, Clone, Default, PartialEq)]
+pub struct DualOutputSettings {
+    pub enabled: bool
+}
+
+#[derive(Component)]
+struct FeedWindow;
+
+#[derive(Component)]
+struct FeedCamera {
+    window: Entity
+}
+
+/// Opens or closes the feed windows when the setting changes.
+fn sync_feed_windows(
+    mut commands: Commands,
+    settings: Res<DualOutputSettings>,
+    windows: Query<Entity, With<FeedWindow>>,
+    cameras: Query<Entity, With<FeedCamera>>
+) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    if !settings.enabled {
+        for entity in windows.iter().chain(cameras.iter()) {
+            commands.entity(entity).despawn();
+        }
+        return;
+    }
+    if !windows.is_empty() {
+        return;
+    }
+
+    for (output, title) in FEEDS {
+        let window = commands.spawn((
+            Window {
+                title: title.to_string(),
+                ..default()
+            },
+            FeedWindow
+        )).id();
+        // The pose is applied to every 3D camera, so these follow the main camera without extra work
+        commands.spawn((
+            Camera3d::default(),
+            Camera {
+                target: RenderTarget::Window(WindowRef::Entity(window)),
+                ..default()
+            },
+            Transform::from_xyz(0.0, 500.0, 500.0).looking_at(Vec3::ZERO, Vec3::Y),
+            output,
+            FeedCamera { window }
+        ));
+    }
+}
+
+/// Cleans up after feed windows the user closed, and turns dual output off once they're all gone.
+fn remove_closed_feeds(
+    mut commands: Commands,
+    mut settings: ResMut<DualOutputSettings>,
+    windows: Query<(), With<FeedWindow>>,
+    cameras: Query<(Entity, &FeedCamera)>
+) {
+    let mut removed = false;
+    for (entity, camera) in cameras.iter() {
+        if !windows.contains(camera.window) {
+            commands.entity(entity).despawn();
+            removed = true;
+        }
+    }
+    if removed && windows.is_empty() && settings.enabled {
+        settings.enabled = false;
+    }
+}
+
+pub struct DualOutputPlugin;
+
+impl Plugin for DualOutputPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<DualOutputSettings>()
+            .add_systems(Update, (remove_closed_feeds, sync_feed_windows).chain());
+    }
+}
@@ -0,0 +1,58 @@
+//! The bottom-left corner shared by the small text panels for tracking quality and the edit modes. Panels are stacked
+//! in one column, so any that are shown together sit above each other instead of overlapping.
+
+use bevy::{app::{App, Plugin, PreStartup}, color::Color, ecs::{bundle::Bundle, component::Component, system::Commands}, text::{TextColor, TextFont}, ui::{widget::Text, AlignItems, BackgroundColor, Display, FlexDirection, Node, PositionType, UiRect, Val}, utils::default};
+
+/// The column the panels are children of.
+#[derive(Component)]
+pub struct HudStack;
+
+/// A panel for the stack, initially hidden. Panels are shown and hidden with `show_panel`, since a panel hidden by
+/// `Visibility` still takes up room in the column.
+pub fn panel(font_size: f32, padding: UiRect, background: Color) -> impl Bundle {
+    (
+        Node {
+            display: Display::None,
+            padding,
+            ..default()
+        },
+        BackgroundColor(background),
+        Text::new(""),
+        TextFont {
+            font_size,
+            ..default()
+        },
+        TextColor(Color::WHITE)
+    )
+}
+
+pub fn show_panel(node: &mut Node, shown: bool) {
+    let display = if shown { Display::Flex } else { Display::None };
+    if node.display != display {
+        node.display = display;
+    }
+}
+
+// Spawned before `Startup`, so the panels' setup systems can add themselves to it
+fn setup(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(8.0),
+            left: Val::Px(8.0),
+            flex_direction: FlexDirection::Column,
+            align_items: AlignItems::FlexStart,
+            row_gap: Val::Px(4.0),
+            ..default()
+        },
+        HudStack
+    ));
+}
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PreStartup, setup);
+    }
+}
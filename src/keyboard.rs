@@ -0,0 +1,483 @@
+//! Geometry of the physical keyboard, and the AR key highlights drawn on top of it.
+//! All dimensions are in mm. In the keyboard's own frame, its center is at x = 0, the key tops are at y = 0,
+//! and keys extend from the marker line toward the player along positive z.
+//! `KeyboardPlane` places that frame in the fiducial frame, tilted to match the heights and depths the fiducial layout
+//! gives the markers, so a keyboard that isn't level can be described in the layout.
+//! Instruments with several manuals (see `profile`) get one such frame per manual, each fitted through its own markers.
+
+use std::ops::RangeInclusive;
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle}, color::{Alpha, Color}, ecs::{change_detection::{DetectChanges, DetectChangesMut, Ref}, component::Component, entity::Entity, hierarchy::ChildOf, query::{Or, With, Without}, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, math::{primitives::{Cuboid, Cylinder}, DMat3, DVec3, Mat3, Quat, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::{mesh::{Mesh, Mesh3d}, view::Visibility}, transform::components::Transform};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{diagnostics::profiling::profile_scope, midi::{KeyState, NoteState}, video::{aruco_camera::FiducialLayout, tracking::FadeWithTracking}};
+
+pub mod clutter;
+pub mod guide;
+pub mod hands;
+pub mod pads;
+pub mod picking;
+pub mod profile;
+pub mod shadow_catcher;
+pub mod theme;
+pub mod trails;
+pub mod zones;
+
+use hands::HandSplitSettings;
+use profile::InstrumentProfile;
+use theme::{Theme, ThemeMaterials};
+use zones::RegisterZoneSettings;
+
+/// The lowest key on a full-size keyboard (A0).
+pub const LOWEST_KEY: u8 = 21;
+/// The highest key on a full-size keyboard (C8).
+pub const HIGHEST_KEY: u8 = 108;
+
+/// The key dimensions of a standard piano, used unless an instrument profile says otherwise.
+pub const WHITE_KEY_WIDTH: f32 = 23.5;
+pub const WHITE_KEY_LENGTH: f32 = 150.0;
+pub const BLACK_KEY_WIDTH: f32 = 13.7;
+pub const BLACK_KEY_LENGTH: f32 = 95.0;
+/// The distance from the line through the fiducial centers to the back edge of the keys.
+pub const KEY_BACK_Z: f32 = 80.0;
+
+pub fn is_black_key(key: u8) -> bool {
+    matches!(key % 12, 1 | 3 | 6 | 8 | 10)
+}
+
+/// The physical size of a keyboard's keys, in mm. Synths and organs often have narrower or shorter keys than a piano.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyGeometry {
+    pub white_key_width: f32,
+    pub white_key_length: f32,
+    pub black_key_width: f32,
+    pub black_key_length: f32,
+    /// The distance from the line through the keyboard's fiducial centers to the back edge of the keys.
+    pub key_back_z: f32
+}
+
+impl Default for KeyGeometry {
+    fn default() -> Self {
+        Self {
+            white_key_width: WHITE_KEY_WIDTH,
+            white_key_length: WHITE_KEY_LENGTH,
+            black_key_width: BLACK_KEY_WIDTH,
+            black_key_length: BLACK_KEY_LENGTH,
+            key_back_z: KEY_BACK_Z
+        }
+    }
+}
+
+/// The range and size of the keys on one physical keyboard, which is centered on x = 0.
+/// Defaults to a full-size piano. For instruments with several manuals, this is the lowest one.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct KeyboardLayout {
+    pub lowest_key: u8,
+    pub highest_key: u8,
+    pub geometry: KeyGeometry
+}
+
+impl Default for KeyboardLayout {
+    fn default() -> Self {
+        Self {
+            lowest_key: LOWEST_KEY,
+            highest_key: HIGHEST_KEY,
+            geometry: KeyGeometry::default()
+        }
+    }
+}
+
+impl KeyboardLayout {
+    pub fn keys(&self) -> RangeInclusive<u8> {
+        self.lowest_key..=self.highest_key
+    }
+
+    /// The (width, length) of the given key's top surface.
+    pub fn key_size(&self, key: u8) -> (f32, f32) {
+        if is_black_key(key) {
+            (self.geometry.black_key_width, self.geometry.black_key_length)
+        } else {
+            (self.geometry.white_key_width, self.geometry.white_key_length)
+        }
+    }
+
+    /// The z coordinate of the front edge of the white keys.
+    pub fn front_z(&self) -> f32 {
+        self.geometry.key_back_z + self.geometry.white_key_length
+    }
+
+    /// The number of white keys below the given key, starting from the lowest key.
+    fn white_keys_below(&self, key: u8) -> u32 {
+        (self.lowest_key..key).filter(|&k| !is_black_key(k)).count() as u32
+    }
+
+    pub fn width(&self) -> f32 {
+        (self.white_keys_below(self.highest_key) + 1) as f32 * self.geometry.white_key_width
+    }
+
+    /// The x coordinate of the center of the given key.
+    pub fn key_center_x(&self, key: u8) -> f32 {
+        let white_key_width = self.geometry.white_key_width;
+        let left_edge = -self.width() / 2.0 + self.white_keys_below(key) as f32 * white_key_width;
+        if is_black_key(key) {
+            // Black keys sit on the boundary between the white keys around them
+            left_edge
+        } else {
+            left_edge + white_key_width / 2.0
+        }
+    }
+
+    /// The x coordinates of the given key's left and right edges.
+    pub fn key_edges_x(&self, key: u8) -> (f32, f32) {
+        let (width, _) = self.key_size(key);
+        let center = self.key_center_x(key);
+        (center - width / 2.0, center + width / 2.0)
+    }
+
+    /// The center of the given key's top surface.
+    pub fn key_center(&self, key: u8) -> Vec3 {
+        let (_, length) = self.key_size(key);
+        Vec3::new(self.key_center_x(key), 0.0, self.geometry.key_back_z + length / 2.0)
+    }
+}
+
+/// Where the keyboard's frame sits in the fiducial frame. Fitted through the fiducial corners whenever the layout changes,
+/// so markers given different heights or depths in the layout tilt the keyboard with them. Only the configured offsets
+/// count: a tilt the markers have but the layout doesn't describe isn't picked up from the solved marker poses.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Default)]
+pub struct KeyboardPlane {
+    pub transform: Transform
+}
+
+impl KeyboardPlane {
+    /// Fits the plane y = a·x + b·z + c by least squares through fiducial corners as the layout places them.
+    /// The keyboard's x axis stays as close to the fiducial frame's x axis as the plane allows, its center sits at
+    /// `center_x`, and its marker line at the points' mean z. Returns `None` if the points don't span a plane.
+    pub fn fit_layout_corners(points: &[Vec3], center_x: f32) -> Option<KeyboardPlane> {
+        // Solve the normal equations in f64, since the sums of squared millimeters get large
+        let mut normal_matrix = DMat3::ZERO;
+        let mut rhs = DVec3::ZERO;
+        for point in points {
+            let row = DVec3::new(point.x as f64, point.z as f64, 1.0);
+            normal_matrix += DMat3::from_cols(row * row.x, row * row.y, row * row.z);
+            rhs += row * point.y as f64;
+        }
+        let scale = normal_matrix.x_axis.x.max(normal_matrix.y_axis.y).max(normal_matrix.z_axis.z);
+        if normal_matrix.determinant().abs() <= scale.powi(3) * 1e-12 {
+            return None;
+        }
+        let DVec3 { x: a, y: b, z: c } = normal_matrix.inverse() * rhs;
+        let center_x = center_x as f64;
+        let mean_z = points.iter().map(|point| point.z as f64).sum::<f64>() / points.len() as f64;
+
+        let normal = Vec3::new(-a as f32, 1.0, -b as f32).normalize();
+        let x_axis = (Vec3::X - normal * normal.dot(Vec3::X)).normalize();
+        let z_axis = x_axis.cross(normal);
+        Some(KeyboardPlane {
+            transform: Transform::from_xyz(center_x as f32, (a * center_x + b * mean_z + c) as f32, mean_z as f32).with_rotation(Quat::from_mat3(&Mat3::from_cols(x_axis, normal, z_axis)))
+        })
+    }
+
+    /// Converts a point in the keyboard's frame to the fiducial frame.
+    pub fn to_world(&self, point: Vec3) -> Vec3 {
+        self.transform.transform_point(point)
+    }
+}
+
+/// The parent of all AR content aligned with the lowest manual, positioned by `KeyboardPlane`.
+#[derive(Component)]
+pub struct KeyboardRoot;
+
+/// The parent of one manual's AR content, by index into `InstrumentProfile::manuals`. The lowest manual's root is the `KeyboardRoot`.
+#[derive(Component)]
+pub struct ManualRoot(pub usize);
+
+/// Where each manual's frame sits in the fiducial frame, by index into `InstrumentProfile::manuals`.
+/// The first is the same as `KeyboardPlane`.
+#[derive(Resource, Debug, Clone, PartialEq, Default)]
+pub struct ManualPlanes(pub Vec<KeyboardPlane>);
+
+#[derive(Component)]
+pub struct KeyHighlight {
+    pub key: u8,
+    pub manual: usize
+}
+
+#[derive(Component)]
+pub struct SustainPedalIndicator;
+
+/// The colors used for key highlights, shared by the AR overlay and physical light outputs.
+#[derive(Resource, Clone, PartialEq)]
+pub struct KeyPalette {
+    pub pressed: Color,
+    pub sustained: Color
+}
+
+impl Default for KeyPalette {
+    fn default() -> Self {
+        Self {
+            pressed: Color::srgb(0.2, 0.8, 1.0),
+            sustained: Color::srgb(0.1, 0.35, 0.5)
+        }
+    }
+}
+
+impl KeyPalette {
+    /// Returns the color a key in the given state should be lit with, or `None` if it shouldn't be lit.
+    pub fn color_for(&self, state: KeyState) -> Option<Color> {
+        match state {
+            KeyState::Up => None,
+            KeyState::Pressed { .. } => Some(self.pressed),
+            KeyState::Sustained { .. } => Some(self.sustained)
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct KeyHighlightMaterials {
+    pub pressed: Handle<StandardMaterial>,
+    pub sustained: Handle<StandardMaterial>,
+    pub pedal_down: Handle<StandardMaterial>,
+    pub pedal_up: Handle<StandardMaterial>
+}
+
+fn setup(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    palette: Res<KeyPalette>
+) {
+    let highlight_material = |color: Color| StandardMaterial {
+        base_color: color,
+        unlit: true,
+        ..Default::default()
+    };
+
+    commands.insert_resource(KeyHighlightMaterials {
+        pressed: materials.add(highlight_material(palette.pressed)),
+        sustained: materials.add(highlight_material(palette.sustained)),
+        pedal_down: materials.add(highlight_material(Color::srgb(1.0, 0.8, 0.2))),
+        pedal_up: materials.add(highlight_material(Color::srgb(0.25, 0.25, 0.25)))
+    });
+
+    commands.spawn((Transform::default(), Visibility::default(), KeyboardRoot, ManualRoot(0)));
+}
+
+/// Keeps `KeyboardLayout` in step with the profile's lowest manual.
+fn sync_keyboard_layout(
+    profile: Res<InstrumentProfile>,
+    mut keyboard_layout: ResMut<KeyboardLayout>
+) {
+    if !profile.is_changed() {
+        return;
+    }
+    if let Some(manual) = profile.manuals.first() {
+        keyboard_layout.set_if_neq(manual.layout());
+    }
+}
+
+/// Hides content that follows the keys while the profile has no manuals, like a drum kit's, since the keyboard it was
+/// made for isn't there. Schedule it after the systems that spawn `T`.
+pub fn hide_without_manuals<T: Component>(profile: Res<InstrumentProfile>, mut content: Query<(&mut Visibility, Ref<T>)>) {
+    let visibility = if profile.manuals.is_empty() { Visibility::Hidden } else { Visibility::Inherited };
+    for (mut content_visibility, item) in content.iter_mut() {
+        if profile.is_changed() || item.is_added() {
+            content_visibility.set_if_neq(visibility);
+        }
+    }
+}
+
+/// Refits each manual's plane through its markers when the fiducial layout or the profile changes.
+fn update_keyboard_planes(
+    profile: Res<InstrumentProfile>,
+    fiducial_layout: Res<FiducialLayout>,
+    mut manual_planes: ResMut<ManualPlanes>,
+    mut keyboard_plane: ResMut<KeyboardPlane>
+) {
+    if !profile.is_changed() && !fiducial_layout.is_changed() {
+        return;
+    }
+
+    let planes: Vec<KeyboardPlane> = profile.manuals.iter().enumerate()
+        .map(|(index, manual)| {
+            let corners = if manual.markers.is_empty() {
+                fiducial_layout.corner_positions()
+            } else {
+                fiducial_layout.corner_positions_of(&manual.markers)
+            };
+            KeyboardPlane::fit_layout_corners(&corners, manual.center_x).unwrap_or_else(|| {
+                eprintln!("The markers of the {} manual don't define a plane; keeping its current plane", manual.name);
+                manual_planes.0.get(index).copied().unwrap_or_default()
+            })
+        })
+        .collect();
+
+    if let Some(primary) = planes.first() {
+        keyboard_plane.set_if_neq(*primary);
+    }
+    manual_planes.set_if_neq(ManualPlanes(planes));
+}
+
+fn move_manual_roots(
+    manual_planes: Res<ManualPlanes>,
+    mut roots: Query<(&ManualRoot, &mut Transform)>
+) {
+    // Roots are respawned when the profile changes, so this can't only run when the planes change
+    for (root, mut transform) in roots.iter_mut() {
+        if let Some(plane) = manual_planes.0.get(root.0) {
+            transform.set_if_neq(plane.transform);
+        }
+    }
+}
+
+/// Spawns each manual's key highlights and the pedal indicator, replacing the old ones whenever the profile changes.
+#[allow(clippy::too_many_arguments)]
+fn spawn_key_highlights(
+    mut commands: Commands,
+    profile: Res<InstrumentProfile>,
+    keyboard_layout: Res<KeyboardLayout>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    highlight_materials: Res<KeyHighlightMaterials>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    upper_roots: Query<Entity, (With<ManualRoot>, Without<KeyboardRoot>)>,
+    existing: Query<Entity, Or<(With<KeyHighlight>, With<SustainPedalIndicator>)>>
+) {
+    if !profile.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+    // The lowest manual's root also holds other content like the waterfall, so it's kept
+    for entity in upper_roots.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    for (index, manual) in profile.manuals.iter().enumerate() {
+        let manual_root = if index == 0 {
+            *root
+        } else {
+            commands.spawn((Transform::default(), Visibility::default(), ManualRoot(index))).id()
+        };
+
+        let layout = manual.layout();
+        for key in layout.keys() {
+            let (width, length) = layout.key_size(key);
+            // Black keys stand above the white keys, so raise their highlights slightly
+            let height = if is_black_key(key) { 12.0 } else { 2.0 };
+            commands.spawn((
+                Mesh3d(meshes.add(Cuboid::new(width * 0.9, 2.0, length * 0.95))),
+                MeshMaterial3d(highlight_materials.pressed.clone()),
+                Transform::from_translation(layout.key_center(key).with_y(height)),
+                Visibility::Hidden,
+                KeyHighlight { key, manual: index },
+                FadeWithTracking,
+                ChildOf(manual_root)
+            ));
+        }
+    }
+
+    // Drum-only profiles have no keyboard to put the pedal indicator on
+    if profile.manuals.is_empty() {
+        return;
+    }
+    // Place the pedal indicator just in front of the lowest manual's lowest keys
+    commands.spawn((
+        Mesh3d(meshes.add(Cylinder::new(15.0, 4.0))),
+        MeshMaterial3d(highlight_materials.pedal_up.clone()),
+        Transform::from_xyz(-keyboard_layout.width() / 2.0 + 20.0, 0.0, keyboard_layout.front_z() + 30.0),
+        SustainPedalIndicator,
+        FadeWithTracking,
+        ChildOf(*root)
+    ));
+}
+
+/// Recolors the highlight materials when the palette changes.
+fn update_highlight_palette(
+    palette: Res<KeyPalette>,
+    highlight_materials: Res<KeyHighlightMaterials>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+) {
+    if !palette.is_changed() || palette.is_added() {
+        return;
+    }
+    for (handle, color) in [(&highlight_materials.pressed, palette.pressed), (&highlight_materials.sustained, palette.sustained)] {
+        if let Some(material) = materials.get_mut(handle) {
+            // Keep the alpha, since tracking loss may be fading the highlights
+            material.base_color = color.with_alpha(material.base_color.alpha());
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_key_highlights(
+    note_state: Res<NoteState>,
+    profile: Res<InstrumentProfile>,
+    palette: Res<KeyPalette>,
+    theme: Res<Theme>,
+    hands: Res<HandSplitSettings>,
+    zones: Res<RegisterZoneSettings>,
+    highlight_materials: Res<KeyHighlightMaterials>,
+    mut theme_materials: ResMut<ThemeMaterials>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut highlights: Query<(&KeyHighlight, &mut MeshMaterial3d<StandardMaterial>, &mut Visibility)>,
+    mut pedal_indicators: Query<&mut MeshMaterial3d<StandardMaterial>, (With<SustainPedalIndicator>, Without<KeyHighlight>)>
+) {
+    if !note_state.is_changed() && !theme.is_changed() && !hands.is_changed() && !zones.is_changed() && !highlights.iter().any(|(_, _, visibility)| visibility.is_added()) {
+        return;
+    }
+    profile_scope!("key highlights");
+
+    for (highlight, mut material, mut visibility) in highlights.iter_mut() {
+        // Manuals on their own channel only light the notes played on them
+        let on_manual = profile.manuals.get(highlight.manual).is_none_or(|manual| manual.plays(&note_state, highlight.key));
+        let state = if on_manual { note_state.keys[highlight.key as usize] } else { KeyState::Up };
+        let channel = note_state.channels[highlight.key as usize];
+        let theme_color = theme.note_color(highlight.key, channel, None);
+        // Keys in a register zone take its accent where nothing else colors them
+        let accent = hands.enabled.then(|| hands.color(hands.hand(highlight.key, channel, None)))
+            .or_else(|| zones.accent(highlight.key).filter(|_| theme_color.is_none()));
+        let new_material = match (state, accent, theme_color) {
+            (KeyState::Up, _, _) => None,
+            (_, Some(color), _) => Some(theme_materials.get(&mut materials, theme::shade_for_state(color, state))),
+            (_, None, Some(_)) => theme.key_color(&palette, highlight.key, channel, state).map(|color| theme_materials.get(&mut materials, color)),
+            (KeyState::Pressed { .. }, None, None) => Some(highlight_materials.pressed.clone()),
+            (KeyState::Sustained { .. }, None, None) => Some(highlight_materials.sustained.clone())
+        };
+        let new_visibility = if new_material.is_some() { Visibility::Inherited } else { Visibility::Hidden };
+        if let Some(new_material) = new_material {
+            material.0 = new_material;
+        }
+        visibility.set_if_neq(new_visibility);
+    }
+
+    for mut material in pedal_indicators.iter_mut() {
+        material.0 = if note_state.sustain_pedal {
+            highlight_materials.pedal_down.clone()
+        } else {
+            highlight_materials.pedal_up.clone()
+        };
+    }
+}
+
+pub struct KeyboardPlugin;
+
+impl Plugin for KeyboardPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<KeyPalette>()
+            .init_resource::<KeyboardLayout>()
+            .init_resource::<KeyboardPlane>()
+            .init_resource::<InstrumentProfile>()
+            .init_resource::<ManualPlanes>()
+            .init_resource::<HandSplitSettings>()
+            .add_plugins((clutter::ClutterPlugin, pads::DrumPadPlugin, picking::KeyPickingPlugin, shadow_catcher::ShadowCatcherPlugin, theme::ThemePlugin, trails::NoteTrailPlugin, guide::KeyGuidePlugin, zones::RegisterZonePlugin))
+            .add_systems(Startup, setup)
+            .add_systems(Update, (
+                (sync_keyboard_layout, update_keyboard_planes, move_manual_roots).chain(),
+                // After syncing, so the pedal indicator is placed for the new layout
+                (spawn_key_highlights, update_highlight_palette, update_key_highlights).chain().after(sync_keyboard_layout).after(theme::update_theme)
+            ));
+    }
+}
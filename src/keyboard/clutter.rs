@@ -0,0 +1,166 @@
+//! Clutter management for very dense passages, like tremolos and glissandi, where drawing every note would bury the
+//! keys under overlapping bars and spawn more entities than the frame rate can keep up with. Past
+//! `ClutterSettings::max_notes_per_second`, repeated notes on a key are merged into one bar in the waterfall and one
+//! trail above the keys, and the bars and trails of the notes left fade the further over the limit the passage is, which
+//! is what thins out runs across neighboring keys like glissandi.
+
+use std::collections::{HashMap, VecDeque};
+
+use bevy::{app::{App, Plugin}, ecs::resource::Resource};
+
+use crate::song::SongNote;
+
+/// The span the note rate is measured over, in seconds.
+const DENSITY_WINDOW: f64 = 1.0;
+/// How long a key can rest between repeated notes in a dense passage for them to still be merged, in seconds.
+pub const MERGE_GAP: f64 = 0.3;
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct ClutterSettings {
+    pub enabled: bool,
+    /// The most notes per second drawn one by one. Denser passages are merged and faded.
+    pub max_notes_per_second: f32
+}
+
+impl Default for ClutterSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_notes_per_second: 40.0
+        }
+    }
+}
+
+impl ClutterSettings {
+    pub fn is_dense(&self, notes_per_second: f32) -> bool {
+        self.enabled && notes_per_second > self.max_notes_per_second
+    }
+
+    /// How bright to draw a note in a passage of the given density, from 1 up to the limit down toward 0 past it.
+    pub fn fade(&self, notes_per_second: f32) -> f32 {
+        if self.is_dense(notes_per_second) { self.max_notes_per_second.max(1.0) / notes_per_second } else { 1.0 }
+    }
+}
+
+/// Counts live notes over the last `DENSITY_WINDOW`.
+#[derive(Default)]
+pub struct NoteRate(VecDeque<f64>);
+
+impl NoteRate {
+    /// Counts a note played at `time`, in seconds, and returns the notes per second up to it.
+    pub fn add(&mut self, time: f64) -> f32 {
+        self.0.push_back(time);
+        while self.0.front().is_some_and(|&oldest| oldest <= time - DENSITY_WINDOW) {
+            self.0.pop_front();
+        }
+        (self.0.len() as f64 / DENSITY_WINDOW) as f32
+    }
+}
+
+/// How the waterfall draws one of the song's notes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteBar {
+    /// Where the bar ends, in seconds, which is past the note's own end if later notes were merged into it.
+    pub end: f64,
+    /// From `ClutterSettings::fade` for the passage the note is in.
+    pub brightness: f32
+}
+
+/// The bar the waterfall draws for each of the song's notes, by index into `notes`, or `None` for notes merged into an
+/// earlier bar on their key. Notes in passages denser than the limit are merged into the bar before them on the same
+/// key and channel if it ends less than `MERGE_GAP` before they start, and dimmed. Bars don't run across `loop_start`,
+/// since the waterfall only draws the notes starting inside the loop while it's active.
+pub fn merge_dense_notes(notes: &[SongNote], settings: &ClutterSettings, loop_start: Option<f64>) -> Vec<Option<NoteBar>> {
+    let mut bars: Vec<Option<NoteBar>> = notes.iter().map(|note| Some(NoteBar { end: note.end(), brightness: 1.0 })).collect();
+    if !settings.enabled {
+        return bars;
+    }
+
+    // The bar each key and channel's next note could be merged into
+    let mut open_bars: HashMap<(u8, u8), usize> = HashMap::new();
+    let (mut low, mut high) = (0, 0);
+    for (index, note) in notes.iter().enumerate() {
+        // Notes are sorted by start time, so the notes around this one are a sliding window
+        while notes[low].start < note.start - DENSITY_WINDOW / 2.0 {
+            low += 1;
+        }
+        while high < notes.len() && notes[high].start < note.start + DENSITY_WINDOW / 2.0 {
+            high += 1;
+        }
+        let notes_per_second = ((high - low) as f64 / DENSITY_WINDOW) as f32;
+
+        let slot = (note.channel, note.key);
+        let in_loop = |start: f64| loop_start.is_some_and(|loop_start| start >= loop_start);
+        let merge_into = open_bars.get(&slot).copied()
+            .filter(|_| settings.is_dense(notes_per_second))
+            .filter(|&bar| in_loop(notes[bar].start) == in_loop(note.start))
+            .filter(|&bar| bars[bar].is_some_and(|bar| note.start - bar.end < MERGE_GAP));
+        match merge_into {
+            Some(bar) => {
+                if let Some(bar) = bars[bar].as_mut() {
+                    bar.end = bar.end.max(note.end());
+                }
+                bars[index] = None;
+            }
+            None => {
+                if let Some(bar) = bars[index].as_mut() {
+                    bar.brightness = settings.fade(notes_per_second);
+                }
+                open_bars.insert(slot, index);
+            }
+        }
+    }
+    bars
+}
+
+pub struct ClutterPlugin;
+
+impl Plugin for ClutterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClutterSettings>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(key: u8, start: f64) -> SongNote {
+        SongNote { key, velocity: 100, channel: 0, track: 0, start, duration: 0.05, hand: None, finger: None }
+    }
+
+    #[test]
+    fn dense_repeated_notes_are_merged() {
+        let settings = ClutterSettings { enabled: true, max_notes_per_second: 10.0 };
+        // A tremolo between two keys, 20 notes a second, then the same keys slowly
+        let mut notes: Vec<SongNote> = (0..20).map(|index| note(if index % 2 == 0 { 60 } else { 64 }, index as f64 * 0.05)).collect();
+        notes.push(note(60, 3.0));
+        notes.push(note(60, 3.2));
+
+        let bars = merge_dense_notes(&notes, &settings, None);
+        assert!((bars[0].unwrap().end - notes[18].end()).abs() < 1e-9);
+        assert!((bars[1].unwrap().end - notes[19].end()).abs() < 1e-9);
+        assert!(bars[2..20].iter().all(Option::is_none));
+        assert_eq!(bars[20..].iter().map(|bar| bar.map(|bar| bar.end)).collect::<Vec<_>>(), [Some(notes[20].end()), Some(notes[21].end())]);
+
+        let disabled = ClutterSettings { enabled: false, ..settings };
+        assert!(merge_dense_notes(&notes, &disabled, None).iter().all(Option::is_some));
+        assert_eq!(settings.fade(20.0), 0.5);
+    }
+
+    #[test]
+    fn glissandi_fade_and_loops_start_new_bars() {
+        let settings = ClutterSettings { enabled: true, max_notes_per_second: 10.0 };
+        // A glissando up 20 keys in a second has no repeats to merge, so its bars are dimmed instead
+        let glissando: Vec<SongNote> = (0..20).map(|index| note(60 + index as u8, index as f64 * 0.05)).collect();
+        let bars = merge_dense_notes(&glissando, &settings, None);
+        assert!(bars.iter().all(Option::is_some));
+        assert!(bars[5..15].iter().all(|bar| bar.is_some_and(|bar| bar.brightness < 1.0)));
+
+        // A tremolo on one key, looped from partway through, keeps a bar starting inside the loop
+        let tremolo: Vec<SongNote> = (0..20).map(|index| note(60, index as f64 * 0.05)).collect();
+        let bars = merge_dense_notes(&tremolo, &settings, Some(0.49));
+        assert!(bars[0].is_some() && bars[10].is_some());
+        assert!(bars[1..10].iter().chain(&bars[11..]).all(Option::is_none));
+    }
+}
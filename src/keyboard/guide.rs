@@ -0,0 +1,314 @@
+//! Scale and chord guides for theory practice. The keys of a chosen scale, or the shape of a chosen chord, are tinted
+//! on the keyboard with the root stronger, so what to play is visible before any key is pressed. Scales are shown over
+//! the whole keyboard, and chords in root position from the root in one octave.
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle}, color::{Alpha, Color}, ecs::{change_detection::{DetectChanges, DetectChangesMut}, component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, math::primitives::Cuboid, pbr::{MeshMaterial3d, StandardMaterial}, render::{alpha::AlphaMode, mesh::{Mesh, Mesh3d}, view::Visibility}, transform::components::Transform};
+use serde::Deserialize;
+
+use crate::{chord::PITCH_CLASS_NAMES, video::tracking::TrackingFade};
+
+use super::{is_black_key, KeyboardLayout, KeyboardRoot};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GuideMode {
+    #[default]
+    Off,
+    Scale,
+    Chord
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scale {
+    #[default]
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues
+}
+
+impl Scale {
+    pub const ALL: [Scale; 6] = [Scale::Major, Scale::NaturalMinor, Scale::HarmonicMinor, Scale::MajorPentatonic, Scale::MinorPentatonic, Scale::Blues];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Scale::Major => "Major",
+            Scale::NaturalMinor => "Natural minor",
+            Scale::HarmonicMinor => "Harmonic minor",
+            Scale::MajorPentatonic => "Major pentatonic",
+            Scale::MinorPentatonic => "Minor pentatonic",
+            Scale::Blues => "Blues"
+        }
+    }
+
+    /// Semitones above the root.
+    fn intervals(self) -> &'static [u8] {
+        match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Blues => &[0, 3, 5, 6, 7, 10]
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChordShape {
+    #[default]
+    Major,
+    Minor,
+    Dominant7,
+    Major7,
+    Minor7,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4
+}
+
+impl ChordShape {
+    pub const ALL: [ChordShape; 9] = [
+        ChordShape::Major,
+        ChordShape::Minor,
+        ChordShape::Dominant7,
+        ChordShape::Major7,
+        ChordShape::Minor7,
+        ChordShape::Diminished,
+        ChordShape::Augmented,
+        ChordShape::Sus2,
+        ChordShape::Sus4
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ChordShape::Major => "Major",
+            ChordShape::Minor => "Minor",
+            ChordShape::Dominant7 => "Dominant 7th",
+            ChordShape::Major7 => "Major 7th",
+            ChordShape::Minor7 => "Minor 7th",
+            ChordShape::Diminished => "Diminished",
+            ChordShape::Augmented => "Augmented",
+            ChordShape::Sus2 => "Sus2",
+            ChordShape::Sus4 => "Sus4"
+        }
+    }
+
+    /// Semitones above the root, in root position.
+    fn intervals(self) -> &'static [u8] {
+        match self {
+            ChordShape::Major => &[0, 4, 7],
+            ChordShape::Minor => &[0, 3, 7],
+            ChordShape::Dominant7 => &[0, 4, 7, 10],
+            ChordShape::Major7 => &[0, 4, 7, 11],
+            ChordShape::Minor7 => &[0, 3, 7, 10],
+            ChordShape::Diminished => &[0, 3, 6],
+            ChordShape::Augmented => &[0, 4, 8],
+            ChordShape::Sus2 => &[0, 2, 7],
+            ChordShape::Sus4 => &[0, 5, 7]
+        }
+    }
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct KeyGuideSettings {
+    pub mode: GuideMode,
+    /// The pitch class of the root, 0 for C up to 11 for B.
+    pub root: u8,
+    pub scale: Scale,
+    pub chord: ChordShape,
+    /// The octave chords are shown in, where octave 4 starts at middle C.
+    pub octave: u8,
+    pub color: Color,
+    pub root_color: Color
+}
+
+impl Default for KeyGuideSettings {
+    fn default() -> Self {
+        Self {
+            mode: GuideMode::Off,
+            root: 0,
+            scale: Scale::Major,
+            chord: ChordShape::Major,
+            octave: 4,
+            color: Color::srgba(0.3, 0.9, 0.5, 0.35),
+            root_color: Color::srgba(1.0, 0.85, 0.2, 0.6)
+        }
+    }
+}
+
+impl KeyGuideSettings {
+    /// Whether the guide tints a key, and if so whether it's a root.
+    pub fn guide_for(&self, key: u8) -> Option<bool> {
+        match self.mode {
+            GuideMode::Off => None,
+            GuideMode::Scale => {
+                let interval = (key + 12 - self.root % 12) % 12;
+                self.scale.intervals().contains(&interval).then_some(interval == 0)
+            }
+            GuideMode::Chord => {
+                let root = (self.octave as u16 + 1) * 12 + (self.root % 12) as u16;
+                let interval = u8::try_from((key as u16).checked_sub(root)?).ok()?;
+                self.chord.intervals().contains(&interval).then_some(interval == 0)
+            }
+        }
+    }
+
+    /// The guide's name, like "D major" or "F# minor 7th".
+    pub fn describe(&self) -> String {
+        let root = PITCH_CLASS_NAMES[(self.root % 12) as usize];
+        match self.mode {
+            GuideMode::Off => "Off".to_string(),
+            GuideMode::Scale => format!("{} {}", root, self.scale.label().to_lowercase()),
+            GuideMode::Chord => format!("{} {}", root, self.chord.label().to_lowercase())
+        }
+    }
+}
+
+/// Parses a pitch class name like "C", "F#", or "Bb" into 0 for C up to 11 for B.
+pub fn parse_pitch_class(name: &str) -> Option<u8> {
+    let mut chars = name.trim().chars();
+    let base: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None
+    };
+    let mut pitch = base;
+    for accidental in chars {
+        pitch += match accidental {
+            '#' | '♯' => 1,
+            'b' | '♭' => -1,
+            _ => return None
+        };
+    }
+    Some(pitch.rem_euclid(12) as u8)
+}
+
+#[derive(Component)]
+struct GuideTint(u8);
+
+#[derive(Resource)]
+struct GuideMaterials {
+    tone: Handle<StandardMaterial>,
+    root: Handle<StandardMaterial>
+}
+
+fn setup(mut commands: Commands, mut materials: ResMut<Assets<StandardMaterial>>) {
+    let tint = |materials: &mut Assets<StandardMaterial>| materials.add(StandardMaterial {
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        ..Default::default()
+    });
+    commands.insert_resource(GuideMaterials { tone: tint(&mut materials), root: tint(&mut materials) });
+}
+
+/// Puts a hidden tint on every key, replacing the old ones when the layout changes.
+fn spawn_guide_tints(
+    mut commands: Commands,
+    keyboard_layout: Res<KeyboardLayout>,
+    guide_materials: Res<GuideMaterials>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    existing: Query<Entity, With<GuideTint>>
+) {
+    if !keyboard_layout.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    for key in keyboard_layout.keys() {
+        let (width, length) = keyboard_layout.key_size(key);
+        // Just under the key highlights, so a pressed key still shows its highlight
+        let height = if is_black_key(key) { 10.5 } else { 0.5 };
+        commands.spawn((
+            Mesh3d(meshes.add(Cuboid::new(width * 0.9, 0.5, length * 0.95))),
+            MeshMaterial3d(guide_materials.tone.clone()),
+            Transform::from_translation(keyboard_layout.key_center(key).with_y(height)),
+            Visibility::Hidden,
+            GuideTint(key),
+            ChildOf(*root)
+        ));
+    }
+}
+
+/// Shows the tints of the guide's keys, and fades them with the rest of the content while tracking is lost. The tints
+/// are translucent, so they're faded here rather than with `FadeWithTracking`, which assumes opaque materials.
+fn update_guide_tints(
+    settings: Res<KeyGuideSettings>,
+    fade: Res<TrackingFade>,
+    guide_materials: Res<GuideMaterials>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut tints: Query<(&GuideTint, &mut MeshMaterial3d<StandardMaterial>, &mut Visibility)>
+) {
+    if settings.is_changed() || fade.is_changed() {
+        for (handle, color) in [(&guide_materials.tone, settings.color), (&guide_materials.root, settings.root_color)] {
+            if let Some(material) = materials.get_mut(handle) {
+                material.base_color = color.with_alpha(color.alpha() * fade.0);
+            }
+        }
+    }
+
+    for (tint, mut material, mut visibility) in tints.iter_mut() {
+        let (shown, handle) = match settings.guide_for(tint.0) {
+            Some(is_root) => (Visibility::Inherited, if is_root { &guide_materials.root } else { &guide_materials.tone }),
+            None => (Visibility::Hidden, &guide_materials.tone)
+        };
+        visibility.set_if_neq(shown);
+        if material.0 != *handle {
+            material.0 = handle.clone();
+        }
+    }
+}
+
+pub struct KeyGuidePlugin;
+
+impl Plugin for KeyGuidePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<KeyGuideSettings>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (spawn_guide_tints, update_guide_tints).chain());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_cover_every_octave_and_chords_one() {
+        let mut settings = KeyGuideSettings { mode: GuideMode::Scale, root: 2, ..Default::default() };
+        // D major
+        assert_eq!(settings.guide_for(62), Some(true));
+        assert_eq!(settings.guide_for(30), Some(false));
+        assert_eq!(settings.guide_for(65), None);
+
+        settings.mode = GuideMode::Chord;
+        settings.chord = ChordShape::Minor;
+        assert_eq!(settings.guide_for(62), Some(true));
+        assert_eq!(settings.guide_for(65), Some(false));
+        assert_eq!(settings.guide_for(74), None);
+        assert_eq!(settings.guide_for(50), None);
+    }
+
+    #[test]
+    fn pitch_classes_are_parsed_with_accidentals() {
+        assert_eq!(parse_pitch_class("C"), Some(0));
+        assert_eq!(parse_pitch_class("f#"), Some(6));
+        assert_eq!(parse_pitch_class("Bb"), Some(10));
+        assert_eq!(parse_pitch_class("Cb"), Some(11));
+        assert_eq!(parse_pitch_class("H"), None);
+    }
+}
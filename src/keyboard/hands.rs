@@ -0,0 +1,88 @@
+//! Left/right hand split for hands-separate practice. Each note is given to a hand by the song when it says, or else
+//! by a split key or its MIDI channel, and the waterfall and key highlights color it by hand, overriding the theme.
+//! With a focus hand set, the other hand's notes are drawn thin and dim so the part being practiced stands out.
+
+use bevy::{color::{Color, Luminance}, ecs::resource::Resource};
+use serde::Deserialize;
+
+use crate::song::Hand;
+
+/// How much darker the notes of the hand that isn't being practiced are.
+const UNFOCUSED_DARKENING: f32 = 0.35;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandDetection {
+    /// Keys below the split key are the left hand's.
+    #[default]
+    Split,
+    /// Notes on the left channel are the left hand's, like songs with a track per hand.
+    Channel
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct HandSplitSettings {
+    pub enabled: bool,
+    pub detection: HandDetection,
+    pub split_key: u8,
+    pub left_channel: u8,
+    pub left_color: Color,
+    pub right_color: Color,
+    /// The hand being practiced alone, if any.
+    pub focus: Option<Hand>
+}
+
+impl Default for HandSplitSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            detection: HandDetection::Split,
+            split_key: 60,
+            left_channel: 1,
+            left_color: Color::srgb(1.0, 0.55, 0.2),
+            right_color: Color::srgb(0.2, 0.7, 1.0),
+            focus: None
+        }
+    }
+}
+
+impl HandSplitSettings {
+    /// The hand a note is played with: the song's, if it says, or else by the split key or channel.
+    pub fn hand(&self, key: u8, channel: u8, known: Option<Hand>) -> Hand {
+        known.unwrap_or(match self.detection {
+            HandDetection::Split if key < self.split_key => Hand::Left,
+            HandDetection::Channel if channel == self.left_channel => Hand::Left,
+            _ => Hand::Right
+        })
+    }
+
+    /// Whether the hand's notes are played down because the other hand is being practiced.
+    pub fn is_unfocused(&self, hand: Hand) -> bool {
+        self.focus.is_some_and(|focus| focus != hand)
+    }
+
+    pub fn color(&self, hand: Hand) -> Color {
+        let color = match hand {
+            Hand::Left => self.left_color,
+            Hand::Right => self.right_color
+        };
+        if self.is_unfocused(hand) { color.darker(UNFOCUSED_DARKENING) } else { color }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notes_are_split_by_key_or_channel_unless_the_song_says() {
+        let mut settings = HandSplitSettings::default();
+        assert_eq!(settings.hand(59, 0, None), Hand::Left);
+        assert_eq!(settings.hand(60, 1, None), Hand::Right);
+        assert_eq!(settings.hand(40, 0, Some(Hand::Right)), Hand::Right);
+
+        settings.detection = HandDetection::Channel;
+        assert_eq!(settings.hand(80, 1, None), Hand::Left);
+        assert_eq!(settings.hand(40, 0, None), Hand::Right);
+    }
+}
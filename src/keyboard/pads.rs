@@ -0,0 +1,100 @@
+//! Drum pad controllers from the instrument profile. Each pad gets a highlight that flashes when its note is hit,
+//! placed by the controller's own markers the same way manuals are.
+
+use bevy::{app::{App, Plugin, Update}, asset::Assets, ecs::{change_detection::{DetectChanges, DetectChangesMut}, component::Component, entity::Entity, hierarchy::ChildOf, query::With, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut}}, math::primitives::{Cuboid, Cylinder}, pbr::MeshMaterial3d, render::{mesh::{Mesh, Mesh3d}, view::Visibility}, time::Time, transform::components::Transform};
+
+use crate::{midi::{bus::{NoteBus, NoteSource}, MidiEventKind}, video::{aruco_camera::FiducialLayout, tracking::FadeWithTracking}};
+
+use super::{profile::InstrumentProfile, KeyHighlightMaterials, KeyboardPlane};
+
+/// How long a pad stays lit after it's hit, in seconds. Drum notes are usually released right away,
+/// so following the note state alone would only light a pad for a frame.
+const HIT_DURATION: f64 = 0.15;
+
+/// The parent of one pad controller's highlights, by index into `InstrumentProfile::pad_controllers`.
+#[derive(Component)]
+pub struct PadControllerRoot(pub usize);
+
+#[derive(Component)]
+struct PadHighlight {
+    controller: usize,
+    note: u8,
+    /// When the pad was last hit, in seconds since startup.
+    last_hit: Option<f64>
+}
+
+/// Spawns the pad highlights, replacing the old ones whenever the profile or fiducial layout changes.
+fn spawn_pad_highlights(
+    mut commands: Commands,
+    profile: Res<InstrumentProfile>,
+    fiducial_layout: Res<FiducialLayout>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    highlight_materials: Res<KeyHighlightMaterials>,
+    existing: Query<Entity, With<PadControllerRoot>>
+) {
+    if !profile.is_changed() && !fiducial_layout.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    for (index, controller) in profile.pad_controllers.iter().enumerate() {
+        let plane = KeyboardPlane::fit_layout_corners(&fiducial_layout.corner_positions_of(&controller.markers), controller.center_x).unwrap_or_else(|| {
+            eprintln!("The markers of the {} pad controller don't define a plane", controller.name);
+            KeyboardPlane::default()
+        });
+        let root = commands.spawn((plane.transform, Visibility::default(), PadControllerRoot(index))).id();
+
+        for pad in &controller.pads {
+            let mesh = if pad.round {
+                meshes.add(Cylinder::new(pad.width / 2.0, 2.0))
+            } else {
+                meshes.add(Cuboid::new(pad.width, 2.0, pad.depth))
+            };
+            commands.spawn((
+                Mesh3d(mesh),
+                MeshMaterial3d(highlight_materials.pressed.clone()),
+                Transform::from_xyz(pad.x, 2.0, pad.z),
+                Visibility::Hidden,
+                PadHighlight { controller: index, note: pad.note, last_hit: None },
+                FadeWithTracking,
+                ChildOf(root)
+            ));
+        }
+    }
+}
+
+fn update_pad_highlights(
+    time: Res<Time>,
+    profile: Res<InstrumentProfile>,
+    bus: Res<NoteBus>,
+    mut pads: Query<(&mut PadHighlight, &mut Visibility)>
+) {
+    let now = time.elapsed_secs_f64();
+    let hits: Vec<(u8, u8)> = bus.from_source(NoteSource::Live)
+        .filter_map(|event| match event.kind {
+            MidiEventKind::NoteOn { key, .. } => Some((event.channel, key)),
+            _ => None
+        })
+        .collect();
+
+    for (mut pad, mut visibility) in pads.iter_mut() {
+        let Some(controller) = profile.pad_controllers.get(pad.controller) else { continue };
+        let hit = hits.iter().any(|&(channel, key)| key == pad.note && controller.channel.is_none_or(|expected| expected == channel));
+        if hit {
+            pad.last_hit = Some(now);
+        }
+
+        let lit = pad.last_hit.is_some_and(|last_hit| now - last_hit < HIT_DURATION);
+        visibility.set_if_neq(if lit { Visibility::Inherited } else { Visibility::Hidden });
+    }
+}
+
+pub struct DrumPadPlugin;
+
+impl Plugin for DrumPadPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (spawn_pad_highlights, update_pad_highlights).chain());
+    }
+}
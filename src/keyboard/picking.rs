@@ -0,0 +1,200 @@
+//! A collision volume for every key, and picking them from a screen position by casting a ray through the main window's
+//! camera, which follows the calibrated camera pose. Clicking or touching a key plays its note like the instrument would,
+//! which also makes a handy test of the calibration: the key that lights up should be the one under the pointer.
+
+use std::collections::HashMap;
+
+use bevy::{app::{App, Plugin, Update}, core_pipeline::core_3d::Camera3d, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, event::EventWriter, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Local, Query, Res, SystemParam}}, input::{mouse::MouseButton, touch::Touches, ButtonInput}, math::{Ray3d, Vec2, Vec3}, render::camera::Camera, transform::components::{GlobalTransform, Transform}, window::{PrimaryWindow, Window}};
+use bevy_egui::input::EguiWantsInput;
+
+use crate::{midi::{input_clock, MidiEvent, MidiEventKind}, render_layers::OutputCamera};
+
+use super::{is_black_key, profile::InstrumentProfile, ManualRoot};
+
+/// How far below the key tops the white key volumes reach, in mm.
+const WHITE_KEY_DEPTH: f32 = 20.0;
+/// How far black keys stand above the white key tops, in mm.
+const BLACK_KEY_HEIGHT: f32 = 12.0;
+
+/// A key's collision volume: a box centered on the entity's transform, in its manual's frame.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct KeyCollider {
+    pub key: u8,
+    /// The index into `InstrumentProfile::manuals` of the key's manual.
+    pub manual: usize,
+    pub half_extents: Vec3
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct KeyPickingSettings {
+    /// Play a key's note while it's clicked or touched.
+    pub click_to_play: bool,
+    pub velocity: u8
+}
+
+impl Default for KeyPickingSettings {
+    fn default() -> Self {
+        Self {
+            click_to_play: true,
+            velocity: 80
+        }
+    }
+}
+
+/// A key under a screen position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyHit {
+    pub entity: Entity,
+    pub key: u8,
+    pub manual: usize,
+    /// Where the ray entered the key's volume, in the fiducial frame.
+    pub point: Vec3,
+    /// The distance along the ray to `point`.
+    pub distance: f32
+}
+
+/// Finds the nearest point where the ray enters an axis-aligned box centered on the origin.
+fn ray_box_distance(origin: Vec3, direction: Vec3, half_extents: Vec3) -> Option<f32> {
+    // Slab test: the ray is inside the box between the last entry into a slab and the first exit from one
+    let inverse = direction.recip();
+    let near = (-half_extents - origin) * inverse;
+    let far = (half_extents - origin) * inverse;
+    let entry = near.min(far).max_element();
+    let exit = near.max(far).min_element();
+    (exit >= entry.max(0.0)).then_some(entry.max(0.0))
+}
+
+/// Picks keys from screen positions in the main window.
+#[derive(SystemParam)]
+pub struct KeyPicker<'w, 's> {
+    cameras: Query<'w, 's, (&'static Camera, &'static GlobalTransform, &'static OutputCamera), With<Camera3d>>,
+    colliders: Query<'w, 's, (Entity, &'static KeyCollider, &'static GlobalTransform)>
+}
+
+impl KeyPicker<'_, '_> {
+    /// The ray through the given position in the main window, in logical pixels from the top left.
+    pub fn ray(&self, screen_position: Vec2) -> Option<Ray3d> {
+        let (camera, transform, _) = self.cameras.iter().find(|(_, _, output)| **output == OutputCamera::MainWindow)?;
+        camera.viewport_to_world(transform, screen_position).ok()
+    }
+
+    /// The nearest key the ray passes through.
+    pub fn pick_ray(&self, ray: Ray3d) -> Option<KeyHit> {
+        self.colliders.iter()
+            .filter_map(|(entity, collider, transform)| {
+                // Test in the key's own frame, where its volume is axis-aligned
+                let to_local = transform.affine().inverse();
+                let origin = to_local.transform_point3(ray.origin);
+                let direction = to_local.transform_vector3(*ray.direction);
+                let local_distance = ray_box_distance(origin, direction, collider.half_extents)?;
+                let point = transform.transform_point(origin + direction * local_distance);
+                Some(KeyHit { entity, key: collider.key, manual: collider.manual, point, distance: point.distance(ray.origin) })
+            })
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// The nearest key under the given position in the main window, in logical pixels from the top left.
+    pub fn pick(&self, screen_position: Vec2) -> Option<KeyHit> {
+        self.pick_ray(self.ray(screen_position)?)
+    }
+}
+
+/// Spawns a collider for every key of every manual, replacing the old ones whenever the profile changes.
+fn spawn_key_colliders(
+    mut commands: Commands,
+    profile: Res<InstrumentProfile>,
+    roots: Query<(Entity, &ManualRoot)>,
+    existing: Query<Entity, With<KeyCollider>>
+) {
+    if !profile.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    for (root, manual_root) in roots.iter() {
+        let Some(manual) = profile.manuals.get(manual_root.0) else { continue };
+        let layout = manual.layout();
+        for key in layout.keys() {
+            let (width, length) = layout.key_size(key);
+            // White keys reach down into the keybed, and black keys stand above them
+            let (bottom, top) = if is_black_key(key) { (0.0, BLACK_KEY_HEIGHT) } else { (-WHITE_KEY_DEPTH, 0.0) };
+            commands.spawn((
+                Transform::from_translation(layout.key_center(key).with_y((bottom + top) / 2.0)),
+                KeyCollider { key, manual: manual_root.0, half_extents: Vec3::new(width, top - bottom, length) / 2.0 },
+                ChildOf(root)
+            ));
+        }
+    }
+}
+
+/// Plays the note of the key under the mouse or each touch until it's released.
+#[allow(clippy::too_many_arguments)]
+fn play_picked_keys(
+    settings: Res<KeyPickingSettings>,
+    profile: Res<InstrumentProfile>,
+    picker: KeyPicker,
+    mouse: Res<ButtonInput<MouseButton>>,
+    touches: Res<Touches>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    egui_input: Option<Res<EguiWantsInput>>,
+    mut held: Local<HashMap<Option<u64>, (u8, u8)>>,
+    mut midi_events: EventWriter<MidiEvent>
+) {
+    let timestamp = input_clock();
+    let mut send = |channel: u8, kind: MidiEventKind| {
+        midi_events.write(MidiEvent { timestamp, channel, kind });
+    };
+
+    // Held keys are tracked by touch id, or `None` for the mouse
+    let mut released: Vec<Option<u64>> = touches.iter_just_released().chain(touches.iter_just_canceled()).map(|touch| Some(touch.id())).collect();
+    if mouse.just_released(MouseButton::Left) {
+        released.push(None);
+    }
+    for pointer in released {
+        if let Some((channel, key)) = held.remove(&pointer) {
+            send(channel, MidiEventKind::NoteOff { key });
+        }
+    }
+
+    if !settings.click_to_play || egui_input.is_some_and(|input| input.wants_any_pointer_input()) {
+        return;
+    }
+    let mut pressed: Vec<(Option<u64>, Vec2)> = touches.iter_just_pressed().map(|touch| (Some(touch.id()), touch.position())).collect();
+    if mouse.just_pressed(MouseButton::Left) {
+        if let Some(position) = windows.iter().next().and_then(Window::cursor_position) {
+            pressed.push((None, position));
+        }
+    }
+    for (pointer, position) in pressed {
+        let Some(hit) = picker.pick(position) else { continue };
+        // Played on the manual's channel, so hand splits, themes, and recordings treat it like the instrument's own
+        let channel = profile.manuals.get(hit.manual).and_then(|manual| manual.channel).unwrap_or(0);
+        send(channel, MidiEventKind::NoteOn { key: hit.key, velocity: settings.velocity });
+        held.insert(pointer, (channel, hit.key));
+    }
+}
+
+pub struct KeyPickingPlugin;
+
+impl Plugin for KeyPickingPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<KeyPickingSettings>()
+            .add_systems(Update, (spawn_key_colliders.after(super::spawn_key_highlights), play_picked_keys));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ray_hits_box_at_its_near_face() {
+        let half_extents = Vec3::new(10.0, 5.0, 50.0);
+        assert_eq!(ray_box_distance(Vec3::new(0.0, 100.0, 0.0), Vec3::NEG_Y, half_extents), Some(95.0));
+        assert_eq!(ray_box_distance(Vec3::new(20.0, 100.0, 0.0), Vec3::NEG_Y, half_extents), None);
+        assert_eq!(ray_box_distance(Vec3::new(0.0, 100.0, 0.0), Vec3::Y, half_extents), None);
+    }
+}
@@ -0,0 +1,244 @@
+//! Instrument profiles describe the keyboards being tracked: a single piano, a synth with narrower keys, or an organ
+//! with several stacked manuals. Each manual has its own key range and size, and is placed by its own set of markers.
+//! Profiles can also include drum pad controllers, whose pads light up when their notes are hit.
+//! A single keyboard of a common size can use a `KeyboardSize` preset instead of a profile file.
+
+use std::fs;
+
+use bevy::ecs::resource::Resource;
+use serde::{Deserialize, Serialize};
+
+use crate::midi::NoteState;
+
+use super::{KeyGeometry, KeyboardLayout, HIGHEST_KEY, LOWEST_KEY};
+
+/// One keyboard of an instrument.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manual {
+    pub name: String,
+    pub lowest_key: u8,
+    pub highest_key: u8,
+    #[serde(default)]
+    pub geometry: KeyGeometry,
+    /// The ids of the fiducials this manual's plane is fitted through. Empty means every marker in the layout.
+    /// All markers still share one coordinate frame, so every manual helps solve the camera pose.
+    #[serde(default)]
+    pub markers: Vec<i32>,
+    /// The x coordinate of the manual's center in the fiducial frame, for manuals that aren't centered on x = 0.
+    #[serde(default)]
+    pub center_x: f32,
+    /// The MIDI channel (0-15) this manual sends on. Without one, the manual lights every note in its range.
+    #[serde(default)]
+    pub channel: Option<u8>
+}
+
+impl Manual {
+    pub fn layout(&self) -> KeyboardLayout {
+        KeyboardLayout {
+            lowest_key: self.lowest_key,
+            highest_key: self.highest_key,
+            geometry: self.geometry
+        }
+    }
+
+    /// Whether the given key's latest note was played on this manual.
+    pub fn plays(&self, note_state: &NoteState, key: u8) -> bool {
+        self.channel.is_none_or(|channel| note_state.channels[key as usize & 127] == channel)
+    }
+}
+
+/// Common keyboard sizes, for a single keyboard that doesn't need its own profile.
+/// In config files they're written as the number of keys, like `size = 61`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u16")]
+pub enum KeyboardSize {
+    Keys88,
+    Keys76,
+    Keys61,
+    Keys49
+}
+
+impl TryFrom<u16> for KeyboardSize {
+    type Error = String;
+
+    fn try_from(keys: u16) -> Result<Self, Self::Error> {
+        match keys {
+            88 => Ok(KeyboardSize::Keys88),
+            76 => Ok(KeyboardSize::Keys76),
+            61 => Ok(KeyboardSize::Keys61),
+            49 => Ok(KeyboardSize::Keys49),
+            _ => Err(format!("There's no preset for {} keys; use 88, 76, 61, or 49", keys))
+        }
+    }
+}
+
+impl KeyboardSize {
+    pub fn key_count(self) -> u16 {
+        match self {
+            KeyboardSize::Keys88 => 88,
+            KeyboardSize::Keys76 => 76,
+            KeyboardSize::Keys61 => 61,
+            KeyboardSize::Keys49 => 49
+        }
+    }
+
+    /// The lowest and highest keys. 88 keys run A0-C8, 76 keys E1-G7, 61 keys C2-C7, and 49 keys C2-C6.
+    pub fn key_range(self) -> (u8, u8) {
+        match self {
+            KeyboardSize::Keys88 => (LOWEST_KEY, HIGHEST_KEY),
+            KeyboardSize::Keys76 => (28, 103),
+            KeyboardSize::Keys61 => (36, 96),
+            KeyboardSize::Keys49 => (36, 84)
+        }
+    }
+
+    /// 88 and 76 key keyboards usually have full-length piano keys. Smaller ones usually have synth-action keys,
+    /// which are as wide but noticeably shorter.
+    pub fn geometry(self) -> KeyGeometry {
+        match self {
+            KeyboardSize::Keys88 | KeyboardSize::Keys76 => KeyGeometry::default(),
+            KeyboardSize::Keys61 | KeyboardSize::Keys49 => KeyGeometry {
+                white_key_length: 135.0,
+                black_key_length: 85.0,
+                ..KeyGeometry::default()
+            }
+        }
+    }
+}
+
+/// The General MIDI percussion channel (channel 10, counting from 1).
+pub const DRUM_CHANNEL: u8 = 9;
+
+fn default_drum_channel() -> Option<u8> {
+    Some(DRUM_CHANNEL)
+}
+
+/// One pad, in its controller's frame: centered on the controller's markers, with the pad tops at y = 0
+/// and positive z toward the player.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pad {
+    /// The drum note the pad sends.
+    pub note: u8,
+    pub x: f32,
+    pub z: f32,
+    /// The pad's size in mm. Round pads use the width as their diameter.
+    pub width: f32,
+    pub depth: f32,
+    #[serde(default)]
+    pub round: bool
+}
+
+/// A drum pad controller, placed by its own markers like a manual.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PadController {
+    pub name: String,
+    /// The ids of the fiducials on the controller. Unlike manuals, these can't be empty.
+    pub markers: Vec<i32>,
+    #[serde(default)]
+    pub center_x: f32,
+    /// The MIDI channel the controller sends on. Defaults to the General MIDI drum channel; `null` accepts any channel.
+    #[serde(default = "default_drum_channel")]
+    pub channel: Option<u8>,
+    pub pads: Vec<Pad>
+}
+
+/// The instrument being played. The first manual is the lowest, and is the one described by `KeyboardLayout`
+/// and `KeyboardPlane` for features that only follow one keyboard, like the waterfall.
+/// A drum-only profile has no manuals, just pad controllers.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstrumentProfile {
+    pub name: String,
+    #[serde(default)]
+    pub manuals: Vec<Manual>,
+    #[serde(default)]
+    pub pad_controllers: Vec<PadController>
+}
+
+impl Default for InstrumentProfile {
+    fn default() -> Self {
+        Self {
+            name: "Piano".to_string(),
+            manuals: vec![Manual {
+                name: "Piano".to_string(),
+                lowest_key: LOWEST_KEY,
+                highest_key: HIGHEST_KEY,
+                geometry: KeyGeometry::default(),
+                markers: Vec::new(),
+                center_x: 0.0,
+                channel: None
+            }],
+            pad_controllers: Vec::new()
+        }
+    }
+}
+
+impl InstrumentProfile {
+    /// A single keyboard of the given size.
+    pub fn preset(size: KeyboardSize) -> Self {
+        let name = format!("{}-key keyboard", size.key_count());
+        let (lowest_key, highest_key) = size.key_range();
+        Self {
+            name: name.clone(),
+            manuals: vec![Manual {
+                name,
+                lowest_key,
+                highest_key,
+                geometry: size.geometry(),
+                markers: Vec::new(),
+                center_x: 0.0,
+                channel: None
+            }],
+            pad_controllers: Vec::new()
+        }
+    }
+
+    /// Loads a profile from a JSON file like
+    /// `{ "name": "Organ", "manuals": [{ "name": "Great", "lowest_key": 36, "highest_key": 96, "markers": [0, 1, 2, 3] }, ...] }`.
+    /// `geometry`, `markers`, `center_x`, and `channel` are optional. Pad controllers go in `pad_controllers`, like
+    /// `{ "name": "Pads", "markers": [4, 5], "pads": [{ "note": 38, "x": -60, "z": 80, "width": 50, "depth": 50 }, ...] }`.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let profile: InstrumentProfile = serde_json::from_str(&fs::read_to_string(path)?)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.manuals.is_empty() && self.pad_controllers.is_empty() {
+            return Err(format!("The {} profile has no manuals or pad controllers", self.name));
+        }
+        for manual in &self.manuals {
+            if manual.lowest_key >= manual.highest_key || manual.highest_key >= 128 {
+                return Err(format!("Invalid key range {}-{} for the {} manual", manual.lowest_key, manual.highest_key, manual.name));
+            }
+            if manual.channel.is_some_and(|channel| channel >= 16) {
+                return Err(format!("Invalid MIDI channel for the {} manual", manual.name));
+            }
+        }
+        for controller in &self.pad_controllers {
+            if controller.markers.is_empty() {
+                return Err(format!("The {} pad controller has no markers", controller.name));
+            }
+            if controller.channel.is_some_and(|channel| channel >= 16) {
+                return Err(format!("Invalid MIDI channel for the {} pad controller", controller.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_sizes_span_their_key_count() {
+        for keys in [88, 76, 61, 49] {
+            let size = KeyboardSize::try_from(keys).unwrap();
+            let (lowest, highest) = size.key_range();
+            assert_eq!(size.key_count(), keys);
+            assert_eq!((highest - lowest) as u16 + 1, keys);
+        }
+        assert_eq!(KeyboardSize::Keys61.key_range(), (36, 96));
+        assert!(KeyboardSize::try_from(66).is_err());
+    }
+}
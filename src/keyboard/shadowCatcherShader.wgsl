@@ -0,0 +1,31 @@
+// Draws only the shadows falling on the keyboard, so virtual content looks like it's casting shadows onto the real keys.
+// Everywhere else the plane is fully transparent.
+
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::{view, lights},
+    mesh_view_types::DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT,
+    shadows::fetch_directional_shadow,
+}
+
+// Must match ShadowCatcherMaterial in keyboard/shadow_catcher.rs
+@group(2) @binding(0)
+var<uniform> shadow_color: vec4<f32>;
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let view_z = dot(vec4<f32>(
+        view.view_from_world[0].z,
+        view.view_from_world[1].z,
+        view.view_from_world[2].z,
+        view.view_from_world[3].z
+    ), in.world_position);
+
+    var shadow = 0.0;
+    for (var i = 0u; i < lights.n_directional_lights; i++) {
+        if ((lights.directional_lights[i].flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            shadow = max(shadow, 1.0 - fetch_directional_shadow(i, in.world_position, in.world_normal, view_z));
+        }
+    }
+    return vec4<f32>(shadow_color.rgb, shadow_color.a * shadow);
+}
@@ -0,0 +1,161 @@
+//! A shadow catcher over the real keys: an otherwise invisible plane that only draws the shadows cast onto it,
+//! lit by a light above the keyboard, so falling notes look like they're casting shadows onto the physical instrument.
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{load_internal_asset, weak_handle, Asset, Assets, Handle}, color::{Alpha, Color, LinearRgba}, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, math::{primitives::Plane3d, Vec3}, pbr::{light_consts, CascadeShadowConfigBuilder, DirectionalLight, Material, MaterialPlugin, MeshMaterial3d, NotShadowCaster}, reflect::TypePath, render::{alpha::AlphaMode, mesh::{Mesh, Mesh3d, Meshable}, render_resource::{AsBindGroup, Shader, ShaderRef}}, transform::components::Transform};
+
+use crate::video::tracking::TrackingFade;
+
+use super::{KeyboardLayout, KeyboardRoot};
+
+const SHADOW_CATCHER_SHADER_HANDLE: Handle<Shader> = weak_handle!("6f1d7a52-3c8e-4b0f-9e27-5d4a1c9b8e31");
+/// How far the plane extends past the keys, in mm, so shadows of content beside the keyboard aren't cut off.
+const MARGIN: f32 = 100.0;
+/// The shadow light's brightness, unless lighting estimation changes it.
+pub const SHADOW_LIGHT_ILLUMINANCE: f32 = light_consts::lux::OVERCAST_DAY;
+/// The direction toward the shadow light, unless lighting estimation changes it: above and slightly behind the keyboard,
+/// so shadows fall toward the player where the keys are.
+pub const DEFAULT_LIGHT_DIRECTION: Vec3 = Vec3::new(0.0, 1000.0, -300.0);
+
+/// Points a directional light so it shines from the given direction.
+pub fn shadow_light_transform(toward_light: Vec3) -> Transform {
+    // The light is never horizontal, so z is a safe up vector
+    Transform::IDENTITY.looking_to(-toward_light, Vec3::Z)
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct ShadowCatcherSettings {
+    pub enabled: bool,
+    /// How dark the shadows are, from 0 to 1.
+    pub strength: f32
+}
+
+impl Default for ShadowCatcherSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            strength: 0.4
+        }
+    }
+}
+
+/// Draws the shadows that fall on the mesh and nothing else.
+#[derive(Asset, TypePath, AsBindGroup, Clone)]
+pub struct ShadowCatcherMaterial {
+    /// The color of the shadows. The alpha is the opacity of a fully shadowed point.
+    #[uniform(0)]
+    pub shadow_color: LinearRgba
+}
+
+impl Material for ShadowCatcherMaterial {
+    fn fragment_shader() -> ShaderRef {
+        SHADOW_CATCHER_SHADER_HANDLE.into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Blend
+    }
+}
+
+#[derive(Component)]
+struct ShadowCatcher;
+
+/// The light whose shadows are caught. Everything else in the scene is unlit, so it only affects the shadows
+/// and the debug visuals.
+#[derive(Component)]
+pub struct ShadowLight;
+
+#[derive(Resource)]
+struct ShadowCatcherAssets {
+    material: Handle<ShadowCatcherMaterial>
+}
+
+fn setup(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ShadowCatcherMaterial>>
+) {
+    commands.insert_resource(ShadowCatcherAssets {
+        material: materials.add(ShadowCatcherMaterial { shadow_color: LinearRgba::NONE })
+    });
+
+    // The scene is in mm, so the shadow cascades need to reach much further than the defaults
+    commands.spawn((
+        DirectionalLight {
+            illuminance: SHADOW_LIGHT_ILLUMINANCE,
+            shadows_enabled: true,
+            ..Default::default()
+        },
+        shadow_light_transform(DEFAULT_LIGHT_DIRECTION),
+        CascadeShadowConfigBuilder {
+            num_cascades: 2,
+            first_cascade_far_bound: 1500.0,
+            maximum_distance: 4000.0,
+            ..Default::default()
+        }.build(),
+        ShadowLight
+    ));
+}
+
+/// Spawns the plane over the keys, replacing it whenever the keyboard layout changes.
+fn spawn_shadow_catcher(
+    mut commands: Commands,
+    layout: Res<KeyboardLayout>,
+    assets: Res<ShadowCatcherAssets>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    existing: Query<Entity, With<ShadowCatcher>>
+) {
+    if !layout.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let depth = layout.front_z() + MARGIN;
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::default().mesh().size(layout.width() + MARGIN * 2.0, depth))),
+        MeshMaterial3d(assets.material.clone()),
+        Transform::from_xyz(0.0, 0.0, depth / 2.0),
+        NotShadowCaster,
+        ShadowCatcher,
+        ChildOf(*root)
+    ));
+}
+
+/// Applies the settings, and fades the shadows along with the content casting them while tracking is lost.
+fn update_shadow_catcher(
+    settings: Res<ShadowCatcherSettings>,
+    fade: Res<TrackingFade>,
+    assets: Res<ShadowCatcherAssets>,
+    mut materials: ResMut<Assets<ShadowCatcherMaterial>>,
+    mut lights: Query<&mut DirectionalLight, With<ShadowLight>>
+) {
+    if !settings.is_changed() && !fade.is_changed() {
+        return;
+    }
+
+    let opacity = if settings.enabled { settings.strength.clamp(0.0, 1.0) * fade.0 } else { 0.0 };
+    if let Some(material) = materials.get_mut(&assets.material) {
+        material.shadow_color = Color::BLACK.with_alpha(opacity).into();
+    }
+    // Rendering shadow maps isn't free, so skip it when nothing shows them
+    for mut light in lights.iter_mut() {
+        if light.shadows_enabled != settings.enabled {
+            light.shadows_enabled = settings.enabled;
+        }
+    }
+}
+
+pub struct ShadowCatcherPlugin;
+
+impl Plugin for ShadowCatcherPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(app, SHADOW_CATCHER_SHADER_HANDLE, "shadowCatcherShader.wgsl", Shader::from_wgsl);
+
+        app
+            .add_plugins(MaterialPlugin::<ShadowCatcherMaterial>::default())
+            .init_resource::<ShadowCatcherSettings>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (spawn_shadow_catcher, update_shadow_catcher).chain());
+    }
+}
@@ -0,0 +1,207 @@
+//! Color themes for notes. A theme colors each note by its pitch class, octave, MIDI channel, or hand, and applies to
+//! the key highlights, the waterfall, and the LED strip. Themes are JSON files in `THEME_DIR`, like
+//! `{ "name": "Rainbow", "mode": "pitch_class", "colors": ["#ff4040", "#ff8c40", ...] }`.
+//! `mode` is one of `uniform`, `pitch_class`, `octave`, `channel`, or `hand`. Colors are indexed by pitch class (C first),
+//! MIDI octave (key / 12), channel, or hand (left, then right), and wrap around when there are fewer of them.
+//! In `hand` mode, keys below `split_key` (default 60, middle C) are the left hand's, unless the song says otherwise.
+
+use std::{collections::HashMap, error::Error, fs};
+
+use bevy::{app::{App, Plugin, Update}, asset::{Assets, Handle}, color::{Color, Luminance, Srgba}, ecs::{change_detection::DetectChanges, resource::Resource, system::{Res, ResMut}}, pbr::StandardMaterial};
+use serde::{Deserialize, Serialize};
+
+use crate::{midi::KeyState, song::Hand};
+
+use super::KeyPalette;
+
+/// The directory listed when choosing a theme in the settings panel.
+pub static THEME_DIR: &str = "assets/themes";
+/// How much darker sustained notes are than pressed ones.
+const SUSTAINED_DARKENING: f32 = 0.25;
+
+fn default_split_key() -> u8 {
+    60
+}
+
+/// What a theme's colors are indexed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    /// Every note uses the key palette, and the waterfall's own colors.
+    #[default]
+    Uniform,
+    PitchClass,
+    Octave,
+    Channel,
+    Hand
+}
+
+/// A theme as written in its file.
+#[derive(Deserialize)]
+struct ThemeFile {
+    name: String,
+    mode: ColorMode,
+    #[serde(default)]
+    colors: Vec<String>,
+    #[serde(default = "default_split_key")]
+    split_key: u8
+}
+
+/// The theme in use, loaded from the file in `ThemeSettings`.
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub name: String,
+    pub mode: ColorMode,
+    pub colors: Vec<Color>,
+    /// In `Hand` mode, notes below this key are played by the left hand unless a song note's hand is known.
+    pub split_key: u8
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            name: "Default".to_string(),
+            mode: ColorMode::Uniform,
+            colors: Vec::new(),
+            split_key: default_split_key()
+        }
+    }
+}
+
+impl Theme {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(json: &str) -> Result<Self, Box<dyn Error>> {
+        let file: ThemeFile = serde_json::from_str(json)?;
+        let colors = file.colors.iter()
+            .map(|hex| Srgba::hex(hex).map(Color::from).map_err(|err| format!("Invalid color {:?}: {}", hex, err)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if colors.is_empty() && file.mode != ColorMode::Uniform {
+            return Err(format!("The {} theme has no colors", file.name).into());
+        }
+        Ok(Theme { name: file.name, mode: file.mode, colors, split_key: file.split_key.min(127) })
+    }
+
+    /// Returns the theme's color for a note, or `None` if the default colors should be used.
+    pub fn note_color(&self, key: u8, channel: u8, hand: Option<Hand>) -> Option<Color> {
+        let index = match self.mode {
+            ColorMode::Uniform => return None,
+            ColorMode::PitchClass => key % 12,
+            ColorMode::Octave => key / 12,
+            ColorMode::Channel => channel,
+            ColorMode::Hand => match hand.unwrap_or(if key < self.split_key { Hand::Left } else { Hand::Right }) {
+                Hand::Left => 0,
+                Hand::Right => 1
+            }
+        } as usize;
+        (!self.colors.is_empty()).then(|| self.colors[index % self.colors.len()])
+    }
+
+    /// Returns the color a key in the given state should be lit with, or `None` if it shouldn't be lit.
+    /// Sustained notes are darker than pressed ones, like in the palette.
+    pub fn key_color(&self, palette: &KeyPalette, key: u8, channel: u8, state: KeyState) -> Option<Color> {
+        let default = palette.color_for(state)?;
+        Some(self.note_color(key, channel, None).map_or(default, |color| shade_for_state(color, state)))
+    }
+}
+
+/// The color a key lit with a note's color is drawn in. Sustained notes are darker than pressed ones.
+pub fn shade_for_state(color: Color, state: KeyState) -> Color {
+    match state {
+        KeyState::Sustained { .. } => color.darker(SUSTAINED_DARKENING),
+        _ => color
+    }
+}
+
+/// Returns the `.json` files in `THEME_DIR`.
+pub fn available_themes() -> Vec<String> {
+    let Ok(entries) = fs::read_dir(THEME_DIR) else { return Vec::new() };
+    let mut themes: Vec<String> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")))
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    themes.sort();
+    themes
+}
+
+#[derive(Resource, Clone, Default, PartialEq)]
+pub struct ThemeSettings {
+    /// The path of the theme file to use, or `None` for the default colors. Changing it loads the theme.
+    pub path: Option<String>
+}
+
+/// Unlit materials for the theme's colors, shared by everything colored by the theme.
+/// Cleared when the theme changes, so colors that are no longer used don't pile up.
+#[derive(Resource, Default)]
+pub struct ThemeMaterials(HashMap<[u8; 4], Handle<StandardMaterial>>);
+
+impl ThemeMaterials {
+    pub fn get(&mut self, materials: &mut Assets<StandardMaterial>, color: Color) -> Handle<StandardMaterial> {
+        self.0.entry(color.to_srgba().to_u8_array())
+            .or_insert_with(|| materials.add(StandardMaterial {
+                base_color: color,
+                unlit: true,
+                ..Default::default()
+            }))
+            .clone()
+    }
+}
+
+/// Loads the theme when a different one is chosen, and drops the materials of the old one.
+pub fn update_theme(
+    settings: Res<ThemeSettings>,
+    mut theme: ResMut<Theme>,
+    mut theme_materials: ResMut<ThemeMaterials>
+) {
+    if settings.is_changed() {
+        match &settings.path {
+            Some(path) => match Theme::load(path) {
+                Ok(loaded) => {
+                    println!("Using the {} theme", loaded.name);
+                    *theme = loaded;
+                }
+                Err(err) => eprintln!("Failed to load theme {}: {}", path, err)
+            },
+            None => *theme = Theme::default()
+        }
+    }
+    if theme.is_changed() {
+        theme_materials.0.clear();
+    }
+}
+
+pub struct ThemePlugin;
+
+impl Plugin for ThemePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<ThemeSettings>()
+            .init_resource::<Theme>()
+            .init_resource::<ThemeMaterials>()
+            .add_systems(Update, update_theme);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn themes_load_and_wrap_their_colors() {
+        let theme = Theme::parse(r#"{ "name": "Pairs", "mode": "pitch_class", "colors": ["#ff0000", "#0000ff"] }"#).unwrap();
+        assert_eq!(theme.split_key, 60);
+        assert_eq!(theme.note_color(60, 0, None), Some(Color::from(Srgba::RED)));
+        assert_eq!(theme.note_color(61, 0, None), Some(Color::from(Srgba::BLUE)));
+        assert_eq!(theme.note_color(62, 0, None), Some(Color::from(Srgba::RED)));
+
+        let hands = Theme::parse(r#"{ "name": "Hands", "mode": "hand", "colors": ["#ff0000", "#0000ff"], "split_key": 200 }"#).unwrap();
+        assert_eq!(hands.split_key, 127);
+        assert_eq!(hands.note_color(100, 0, Some(Hand::Right)), Some(Color::from(Srgba::BLUE)));
+
+        assert!(Theme::parse(r#"{ "name": "Empty", "mode": "octave" }"#).is_err());
+        assert!(Theme::parse(r#"{ "name": "Broken", "mode": "octave", "colors": ["red"] }"#).is_err());
+    }
+}
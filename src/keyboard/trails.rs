@@ -0,0 +1,216 @@
+//! Note trails, a reverse waterfall of what's been played: each played note spawns a glowing bar at its key that grows
+//! while the key is held, then rises away from the keys and fades, leaving a short visual history of the performance.
+//! In passages denser than `ClutterSettings::max_notes_per_second`, a key played again soon after it came up carries on
+//! its last trail rather than starting a new one, and new trails start dimmer.
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle}, color::Alpha, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Local, Query, Res, ResMut, Single}}, math::{primitives::Cuboid, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::{alpha::AlphaMode, mesh::{Mesh, Mesh3d}}, time::Time, transform::components::Transform};
+
+use crate::{midi::{bus::{NoteBus, NoteSource}, MidiEventKind}, video::tracking::FadeWithTracking};
+
+use super::{clutter::{ClutterSettings, NoteRate, MERGE_GAP}, hands::HandSplitSettings, theme::{self, Theme}, zones::RegisterZoneSettings, KeyPalette, KeyboardLayout, KeyboardRoot};
+
+/// The depth of the trails in mm.
+const TRAIL_DEPTH: f32 = 10.0;
+/// The shortest a trail is drawn, in mm, so quick notes still show.
+const MIN_TRAIL_LENGTH: f32 = 2.0;
+/// The slowest trails rise, in mm/s. Trails that didn't rise would never fade out.
+pub const MIN_TRAIL_SPEED: f32 = 20.0;
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct NoteTrailSettings {
+    pub enabled: bool,
+    /// How fast trails rise, in mm/s.
+    pub speed: f32,
+    /// The height above the keys where trails have faded out, in mm.
+    pub height: f32
+}
+
+impl Default for NoteTrailSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            speed: 150.0,
+            height: 400.0
+        }
+    }
+}
+
+#[derive(Component)]
+struct NoteTrail {
+    key: u8,
+    channel: u8,
+    /// When the key went down and came up, in seconds since startup. The trail grows from the key until it comes up.
+    start: f64,
+    end: Option<f64>,
+    /// How bright the trail starts, from how hard the note was played.
+    brightness: f32,
+    /// How far the trail rises before it's faded out, relative to `NoteTrailSettings::height`, from its register zone.
+    reach: f32,
+    /// Each trail has its own material, so it can fade on its own.
+    material: Handle<StandardMaterial>
+}
+
+#[derive(Resource)]
+struct TrailMesh(Handle<Mesh>);
+
+fn setup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>) {
+    commands.insert_resource(TrailMesh(meshes.add(Cuboid::new(1.0, 1.0, 1.0))));
+}
+
+/// The bottom and top of a trail above the keys at `now`, in mm.
+fn trail_extent(start: f64, end: Option<f64>, now: f64, speed: f32) -> (f32, f32) {
+    let bottom = end.map_or(0.0, |end| (now - end).max(0.0) as f32 * speed);
+    let top = (now - start).max(0.0) as f32 * speed;
+    (bottom, top.max(bottom + MIN_TRAIL_LENGTH))
+}
+
+/// Starts a trail for each played note and lets go of it when the key comes up.
+#[allow(clippy::too_many_arguments)]
+fn spawn_note_trails(
+    mut commands: Commands,
+    settings: Res<NoteTrailSettings>,
+    bus: Res<NoteBus>,
+    theme: Res<Theme>,
+    hands: Res<HandSplitSettings>,
+    zones: Res<RegisterZoneSettings>,
+    clutter: Res<ClutterSettings>,
+    mut rate: Local<NoteRate>,
+    palette: Res<KeyPalette>,
+    mesh: Res<TrailMesh>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    mut trails: Query<(Entity, &mut NoteTrail)>
+) {
+    if settings.is_changed() && !settings.enabled {
+        for (entity, _) in trails.iter() {
+            commands.entity(entity).despawn();
+        }
+    }
+    if !settings.enabled {
+        return;
+    }
+
+    // Notes can start and end in the same frame, so new trails are spawned after this frame's events
+    let mut new_trails: Vec<NoteTrail> = Vec::new();
+    for event in bus.from_source(NoteSource::Live) {
+        match event.kind {
+            MidiEventKind::NoteOn { key, velocity } => {
+                let notes_per_second = rate.add(event.time);
+                if clutter.is_dense(notes_per_second) {
+                    // Carry on the key's last trail if it came up only just now
+                    let recent = |trail: &NoteTrail| trail.key == key && trail.channel == event.channel && trail.end.is_some_and(|end| event.time - end < MERGE_GAP);
+                    if let Some(trail) = new_trails.iter_mut().find(|trail| recent(trail)) {
+                        trail.end = None;
+                        continue;
+                    }
+                    if let Some((_, mut trail)) = trails.iter_mut().find(|(_, trail)| recent(trail)) {
+                        trail.end = None;
+                        continue;
+                    }
+                }
+
+                let color = if hands.enabled {
+                    hands.color(hands.hand(key, event.channel, None))
+                } else {
+                    theme.note_color(key, event.channel, None).or_else(|| zones.accent(key)).unwrap_or(palette.pressed)
+                };
+                let intensity = zones.intensity(key);
+                // Additive blending makes the trails glow over the camera image and each other
+                let material = materials.add(StandardMaterial {
+                    base_color: color,
+                    alpha_mode: AlphaMode::Add,
+                    unlit: true,
+                    ..Default::default()
+                });
+                new_trails.push(NoteTrail {
+                    key,
+                    channel: event.channel,
+                    start: event.time,
+                    end: None,
+                    brightness: ((0.4 + 0.6 * velocity as f32 / 127.0) * intensity).min(1.0) * clutter.fade(notes_per_second),
+                    reach: intensity,
+                    material
+                });
+            }
+            MidiEventKind::NoteOff { key } => {
+                let held = |trail: &NoteTrail| trail.key == key && trail.channel == event.channel && trail.end.is_none();
+                for trail in new_trails.iter_mut().filter(|trail| held(trail)) {
+                    trail.end = Some(event.time);
+                }
+                for (_, mut trail) in trails.iter_mut() {
+                    if held(&trail) {
+                        trail.end = Some(event.time);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for trail in new_trails {
+        commands.spawn((
+            Mesh3d(mesh.0.clone()),
+            MeshMaterial3d(trail.material.clone()),
+            // Placed by `update_note_trails`
+            Transform::from_scale(Vec3::ZERO),
+            trail,
+            FadeWithTracking,
+            ChildOf(*root)
+        ));
+    }
+}
+
+/// Raises and fades the trails, and removes the ones that have faded out.
+fn update_note_trails(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<NoteTrailSettings>,
+    keyboard_layout: Res<KeyboardLayout>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut trails: Query<(Entity, &NoteTrail, &mut Transform)>
+) {
+    let now = time.elapsed_secs_f64();
+    for (entity, trail, mut transform) in trails.iter_mut() {
+        let height = (settings.height * trail.reach).max(1.0);
+        let (bottom, top) = trail_extent(trail.start, trail.end, now, settings.speed);
+        if bottom >= height || !keyboard_layout.keys().contains(&trail.key) {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let top = top.min(height);
+        let (width, _) = keyboard_layout.key_size(trail.key);
+        // Trails start just above the key tops, like the waterfall's bars end there
+        let base = if super::is_black_key(trail.key) { 12.0 } else { 2.0 };
+        *transform = Transform::from_translation(keyboard_layout.key_center(trail.key).with_y(base + (bottom + top) / 2.0))
+            .with_scale(Vec3::new(width * 0.8, top - bottom, TRAIL_DEPTH));
+
+        if let Some(material) = materials.get_mut(&trail.material) {
+            let fade = 1.0 - (bottom + top) / 2.0 / height;
+            material.base_color.set_alpha(trail.brightness * fade.clamp(0.0, 1.0));
+        }
+    }
+}
+
+pub struct NoteTrailPlugin;
+
+impl Plugin for NoteTrailPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<NoteTrailSettings>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (spawn_note_trails.after(theme::update_theme), update_note_trails, super::hide_without_manuals::<NoteTrail>).chain());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trails_grow_while_held_then_rise() {
+        assert_eq!(trail_extent(1.0, None, 2.0, 100.0), (0.0, 100.0));
+        assert_eq!(trail_extent(1.0, Some(1.5), 2.0, 100.0), (50.0, 100.0));
+        assert_eq!(trail_extent(1.0, Some(1.0), 1.0, 100.0), (0.0, MIN_TRAIL_LENGTH));
+    }
+}
@@ -0,0 +1,198 @@
+//! Register zones: spans of the keyboard, like the bass, middle, and treble, each with its own accent color and effect
+//! intensity, so the register a passage is in reads at a glance in pieces that cover the whole keyboard. Each zone is
+//! marked by a colored strip behind its keys, and notes in it take its accent color where the theme doesn't color them.
+//! Trails from notes in a zone rise higher and glow brighter with its intensity.
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle}, color::{Alpha, Color}, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, math::{primitives::Cuboid, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::{alpha::AlphaMode, mesh::{Mesh, Mesh3d}}, transform::components::Transform};
+
+use crate::video::tracking::TrackingFade;
+
+use super::{KeyboardLayout, KeyboardRoot};
+
+/// How far the strips reach back from the keys toward the markers, in mm.
+const STRIP_DEPTH: f32 = 12.0;
+/// The space left between neighboring strips, in mm.
+const STRIP_GAP: f32 = 4.0;
+/// The opacity of a strip in a zone of intensity 1.
+const STRIP_ALPHA: f32 = 0.5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterZone {
+    pub name: String,
+    pub lowest_key: u8,
+    pub highest_key: u8,
+    pub color: Color,
+    /// How strongly effects show in the zone, where 1 is normal.
+    pub intensity: f32
+}
+
+impl RegisterZone {
+    pub fn new(name: &str, lowest_key: u8, highest_key: u8, color: Color, intensity: f32) -> Self {
+        Self { name: name.to_string(), lowest_key, highest_key, color, intensity }
+    }
+
+    pub fn contains(&self, key: u8) -> bool {
+        (self.lowest_key..=self.highest_key).contains(&key)
+    }
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct RegisterZoneSettings {
+    pub enabled: bool,
+    /// The zones from the bottom of the keyboard up. Where zones overlap, the first one wins.
+    pub zones: Vec<RegisterZone>
+}
+
+impl Default for RegisterZoneSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            zones: vec![
+                // Below C3, C3 to B4 around middle C, and from C5 up
+                RegisterZone::new("Bass", 21, 47, Color::srgb(1.0, 0.5, 0.2), 1.3),
+                RegisterZone::new("Middle", 48, 71, Color::srgb(0.4, 0.9, 0.5), 1.0),
+                RegisterZone::new("Treble", 72, 108, Color::srgb(0.5, 0.55, 1.0), 0.8)
+            ]
+        }
+    }
+}
+
+impl RegisterZoneSettings {
+    /// The zone the key is in, if zones are enabled and one covers it.
+    pub fn zone_for(&self, key: u8) -> Option<&RegisterZone> {
+        self.zones.iter().filter(|_| self.enabled).find(|zone| zone.contains(key))
+    }
+
+    pub fn accent(&self, key: u8) -> Option<Color> {
+        self.zone_for(key).map(|zone| zone.color)
+    }
+
+    /// How strongly effects show for the key, where 1 is normal.
+    pub fn intensity(&self, key: u8) -> f32 {
+        self.zone_for(key).map_or(1.0, |zone| zone.intensity.max(0.0))
+    }
+}
+
+/// A zone's strip. Each has its own material, since its opacity comes from its zone's intensity.
+#[derive(Component)]
+struct ZoneStrip {
+    material: Handle<StandardMaterial>,
+    alpha: f32
+}
+
+#[derive(Resource)]
+struct StripMesh(Handle<Mesh>);
+
+fn setup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>) {
+    commands.insert_resource(StripMesh(meshes.add(Cuboid::new(1.0, 1.0, 1.0))));
+}
+
+/// The left and right ends of the zone's strip on the keyboard, or `None` if none of its keys are on it.
+fn strip_extent(zone: &RegisterZone, keyboard_layout: &KeyboardLayout) -> Option<(f32, f32)> {
+    let lowest = zone.lowest_key.max(keyboard_layout.lowest_key);
+    let highest = zone.highest_key.min(keyboard_layout.highest_key);
+    if lowest > highest {
+        return None;
+    }
+    let (left, _) = keyboard_layout.key_edges_x(lowest);
+    let (_, right) = keyboard_layout.key_edges_x(highest);
+    (right - left > STRIP_GAP).then_some((left + STRIP_GAP / 2.0, right - STRIP_GAP / 2.0))
+}
+
+/// Replaces the strips whenever the zones or the keyboard change.
+#[allow(clippy::too_many_arguments)]
+fn spawn_zone_strips(
+    mut commands: Commands,
+    settings: Res<RegisterZoneSettings>,
+    keyboard_layout: Res<KeyboardLayout>,
+    fade: Res<TrackingFade>,
+    mesh: Res<StripMesh>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    existing: Query<Entity, With<ZoneStrip>>
+) {
+    if !settings.is_changed() && !keyboard_layout.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+    if !settings.enabled {
+        return;
+    }
+
+    for zone in &settings.zones {
+        let Some((left, right)) = strip_extent(zone, &keyboard_layout) else { continue };
+        let alpha = (STRIP_ALPHA * zone.intensity).clamp(0.0, 1.0);
+        // Translucent, so faded here rather than with `FadeWithTracking`, like the key guide
+        let material = materials.add(StandardMaterial {
+            base_color: zone.color.with_alpha(alpha * fade.0),
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..Default::default()
+        });
+        commands.spawn((
+            Mesh3d(mesh.0.clone()),
+            MeshMaterial3d(material.clone()),
+            // On the key bed just behind the keys, under the waterfall
+            Transform::from_xyz((left + right) / 2.0, 0.5, keyboard_layout.geometry.key_back_z - STRIP_DEPTH / 2.0)
+                .with_scale(Vec3::new(right - left, 1.0, STRIP_DEPTH)),
+            ZoneStrip { material, alpha },
+            ChildOf(*root)
+        ));
+    }
+}
+
+/// Fades the strips with the rest of the content while tracking is lost.
+fn fade_zone_strips(
+    fade: Res<TrackingFade>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    strips: Query<&ZoneStrip>
+) {
+    if !fade.is_changed() {
+        return;
+    }
+    for strip in strips.iter() {
+        if let Some(material) = materials.get_mut(&strip.material) {
+            material.base_color.set_alpha(strip.alpha * fade.0);
+        }
+    }
+}
+
+pub struct RegisterZonePlugin;
+
+impl Plugin for RegisterZonePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<RegisterZoneSettings>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (spawn_zone_strips, fade_zone_strips, super::hide_without_manuals::<ZoneStrip>).chain());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_take_the_first_zone_covering_them() {
+        let mut settings = RegisterZoneSettings::default();
+        assert_eq!(settings.zone_for(60), None);
+        assert_eq!(settings.intensity(30), 1.0);
+
+        settings.enabled = true;
+        settings.zones.insert(0, RegisterZone::new("Melody", 60, 64, Color::WHITE, 2.0));
+        assert_eq!(settings.zone_for(30).map(|zone| zone.name.as_str()), Some("Bass"));
+        assert_eq!(settings.zone_for(62).map(|zone| zone.name.as_str()), Some("Melody"));
+        assert_eq!(settings.zone_for(65).map(|zone| zone.name.as_str()), Some("Middle"));
+        assert_eq!(settings.intensity(100), 0.8);
+        assert_eq!(settings.zone_for(120), None);
+
+        // A 61-key keyboard starts at C2, so the bass strip starts at its left end
+        let keyboard_layout = KeyboardLayout { lowest_key: 36, highest_key: 96, ..Default::default() };
+        let (left, right) = strip_extent(&settings.zones[1], &keyboard_layout).unwrap();
+        assert!((left - (-keyboard_layout.width() / 2.0 + STRIP_GAP / 2.0)).abs() < 0.001);
+        assert!(right < keyboard_layout.key_center_x(48));
+        assert_eq!(strip_extent(&RegisterZone::new("Sub", 21, 30, Color::WHITE, 1.0), &keyboard_layout), None);
+    }
+}
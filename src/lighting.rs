@@ -0,0 +1,33 @@
+//! Physical light outputs that mirror the AR visuals, like LED strips above the keys and DMX stage fixtures.
+
+use std::net::UdpSocket;
+
+use bevy::app::{App, Plugin};
+
+pub mod artnet;
+pub mod dmx;
+pub mod led_strip;
+pub mod sacn;
+
+/// Binds a non-blocking UDP socket for sending light data. Returns `None` and logs if binding fails.
+pub fn bind_output_socket() -> Option<UdpSocket> {
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("Failed to bind lighting output socket: {}", err);
+            return None;
+        }
+    };
+    if let Err(err) = socket.set_nonblocking(true) {
+        eprintln!("Failed to make lighting output socket non-blocking: {}", err);
+    }
+    Some(socket)
+}
+
+pub struct LightingPlugin;
+
+impl Plugin for LightingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((led_strip::LedStripPlugin, dmx::DmxPlugin));
+    }
+}
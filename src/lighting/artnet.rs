@@ -0,0 +1,26 @@
+//! Minimal Art-Net (ArtDMX) packet encoding.
+
+/// The UDP port Art-Net nodes listen on.
+pub const ARTNET_PORT: u16 = 6454;
+/// The number of channels in one DMX universe.
+pub const UNIVERSE_SIZE: usize = 512;
+
+/// Encodes one ArtDMX packet carrying up to 512 channels for the given 15-bit port address.
+pub fn artdmx_packet(universe: u16, sequence: u8, channels: &[u8]) -> Vec<u8> {
+    let channels = &channels[..channels.len().min(UNIVERSE_SIZE)];
+    // The data length must be even
+    let length = channels.len() + channels.len() % 2;
+
+    let mut packet = Vec::with_capacity(18 + length);
+    packet.extend_from_slice(b"Art-Net\0");
+    packet.extend_from_slice(&0x5000u16.to_le_bytes()); // OpDmx
+    packet.extend_from_slice(&14u16.to_be_bytes()); // Protocol version
+    packet.push(sequence);
+    packet.push(0); // Physical port
+    packet.push((universe & 0xFF) as u8); // SubUni
+    packet.push(((universe >> 8) & 0x7F) as u8); // Net
+    packet.extend_from_slice(&(length as u16).to_be_bytes());
+    packet.extend_from_slice(channels);
+    packet.resize(18 + length, 0);
+    packet
+}
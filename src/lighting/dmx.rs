@@ -0,0 +1,201 @@
+//! Drives DMX stage fixtures from the music: brightness follows note energy and color follows the harmony being played.
+
+use std::{f32::consts::TAU, net::UdpSocket};
+
+use bevy::{app::{App, Plugin, Update}, color::{Color, Hsva}, ecs::{change_detection::DetectChanges, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}, time::Time};
+
+use crate::midi::{bus::{NoteBus, NoteSource}, MidiEventKind, NoteState};
+
+use super::{artnet, bind_output_socket, sacn};
+
+/// How many DMX frames are sent per second.
+const DMX_FRAME_RATE: f32 = 30.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmxProtocol {
+    ArtNet,
+    Sacn
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureKind {
+    /// Three channels: red, green, blue.
+    Rgb,
+    /// Four channels: master dimmer, red, green, blue.
+    DimmerRgb,
+    /// One channel: dimmer only.
+    Dimmer
+}
+
+impl FixtureKind {
+    fn channel_count(&self) -> usize {
+        match self {
+            FixtureKind::Rgb => 3,
+            FixtureKind::DimmerRgb => 4,
+            FixtureKind::Dimmer => 1
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DmxFixture {
+    /// The fixture's first DMX channel, 1-based like on the fixture's address display.
+    pub address: u16,
+    pub kind: FixtureKind,
+    /// Shifts this fixture's hue away from the harmonic color, in degrees, so several fixtures can form a color spread.
+    pub hue_offset: f32
+}
+
+#[derive(Resource, Clone)]
+pub struct DmxSettings {
+    pub enabled: bool,
+    pub protocol: DmxProtocol,
+    /// The IP address or host name of the node.
+    pub host: String,
+    /// Art-Net's 15-bit port address, or the sACN universe from 1 to 63999.
+    pub universe: u16,
+    pub fixtures: Vec<DmxFixture>,
+    /// How quickly note energy fades, as the fraction remaining after one second.
+    pub energy_decay: f32,
+    /// The brightness fixtures rest at when nothing is playing, from 0 to 1.
+    pub base_level: f32
+}
+
+impl Default for DmxSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocol: DmxProtocol::ArtNet,
+            host: "192.168.1.60".to_string(),
+            // Valid for both protocols, where sACN has no universe 0
+            universe: 1,
+            fixtures: vec![
+                DmxFixture { address: 1, kind: FixtureKind::DimmerRgb, hue_offset: 0.0 },
+                DmxFixture { address: 5, kind: FixtureKind::DimmerRgb, hue_offset: 30.0 }
+            ],
+            energy_decay: 0.15,
+            base_level: 0.1
+        }
+    }
+}
+
+/// Musical features the fixtures are driven from.
+#[derive(Resource, Default)]
+pub struct MusicLighting {
+    /// Recent playing intensity, from 0 to 1.
+    pub energy: f32,
+    /// The hue of the current harmony in degrees, or `None` when nothing is sounding.
+    pub hue: Option<f32>
+}
+
+#[derive(Resource, Default)]
+pub struct DmxOutput {
+    socket: Option<UdpSocket>,
+    seconds_since_send: f32,
+    sequence: u8
+}
+
+/// Maps the sounding pitch classes onto the circle of fifths and averages them,
+/// so closely related harmonies get similar hues.
+pub fn harmonic_hue(note_state: &NoteState) -> Option<f32> {
+    let (mut x, mut y) = (0.0, 0.0);
+    for key in note_state.sounding_keys() {
+        let fifths_position = (key % 12) as f32 * 7.0 % 12.0;
+        let angle = fifths_position / 12.0 * TAU;
+        x += angle.cos();
+        y += angle.sin();
+    }
+    if x == 0.0 && y == 0.0 {
+        return None;
+    }
+    Some(y.atan2(x).to_degrees().rem_euclid(360.0))
+}
+
+fn update_music_lighting(
+    bus: Res<NoteBus>,
+    note_state: Res<NoteState>,
+    settings: Res<DmxSettings>,
+    mut lighting: ResMut<MusicLighting>,
+    time: Res<Time>
+) {
+    lighting.energy *= settings.energy_decay.powf(time.delta_secs());
+    for event in bus.from_source(NoteSource::Live) {
+        if let MidiEventKind::NoteOn { velocity, .. } = event.kind {
+            lighting.energy = (lighting.energy + velocity as f32 / 127.0 * 0.25).min(1.0);
+        }
+    }
+
+    if note_state.is_changed() {
+        if let Some(hue) = harmonic_hue(&note_state) {
+            lighting.hue = Some(hue);
+        }
+    }
+}
+
+fn send_dmx_frame(
+    settings: Res<DmxSettings>,
+    lighting: Res<MusicLighting>,
+    mut output: ResMut<DmxOutput>,
+    time: Res<Time>
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    output.seconds_since_send += time.delta_secs();
+    if output.seconds_since_send < 1.0 / DMX_FRAME_RATE {
+        return;
+    }
+    output.seconds_since_send = 0.0;
+
+    if output.socket.is_none() {
+        output.socket = bind_output_socket();
+    }
+    let Some(socket) = output.socket.as_ref() else { return };
+
+    let level = settings.base_level + (1.0 - settings.base_level) * lighting.energy;
+    let mut channels = [0u8; artnet::UNIVERSE_SIZE];
+    for fixture in &settings.fixtures {
+        let color = match lighting.hue {
+            Some(hue) => Color::from(Hsva::new((hue + fixture.hue_offset).rem_euclid(360.0), 0.8, 1.0, 1.0)).to_srgba(),
+            None => Color::WHITE.to_srgba()
+        };
+        let to_dmx = |value: f32| (value.clamp(0.0, 1.0) * 255.0) as u8;
+        let values: Vec<u8> = match fixture.kind {
+            FixtureKind::Rgb => vec![to_dmx(color.red * level), to_dmx(color.green * level), to_dmx(color.blue * level)],
+            FixtureKind::DimmerRgb => vec![to_dmx(level), to_dmx(color.red), to_dmx(color.green), to_dmx(color.blue)],
+            FixtureKind::Dimmer => vec![to_dmx(level)]
+        };
+
+        let start = fixture.address.max(1) as usize - 1;
+        if start + fixture.kind.channel_count() > channels.len() {
+            eprintln!("DMX fixture at address {} doesn't fit in the universe", fixture.address);
+            continue;
+        }
+        channels[start..start + values.len()].copy_from_slice(&values);
+    }
+
+    let (packet, port) = match settings.protocol {
+        DmxProtocol::ArtNet => (artnet::artdmx_packet(settings.universe, output.sequence, &channels), artnet::ARTNET_PORT),
+        DmxProtocol::Sacn => (sacn::data_packet(settings.universe, output.sequence, &channels), sacn::SACN_PORT)
+    };
+    if let Err(err) = socket.send_to(&packet, (settings.host.as_str(), port)) {
+        if err.kind() != std::io::ErrorKind::WouldBlock {
+            eprintln!("Failed to send DMX data: {}", err);
+        }
+    }
+
+    output.sequence = output.sequence.wrapping_add(1).max(1);
+}
+
+pub struct DmxPlugin;
+
+impl Plugin for DmxPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<DmxSettings>()
+            .init_resource::<MusicLighting>()
+            .init_resource::<DmxOutput>()
+            .add_systems(Update, (update_music_lighting, send_dmx_frame).chain());
+    }
+}
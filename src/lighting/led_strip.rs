@@ -0,0 +1,198 @@
+//! Mirrors the key highlights onto an addressable LED strip mounted above the keys, via WLED's UDP realtime protocol or Art-Net.
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::DetectChanges, resource::Resource, system::{Res, ResMut}}, time::Time};
+
+use crate::{keyboard::{is_black_key, theme::Theme, KeyPalette, KeyboardLayout}, midi::NoteState};
+
+use super::{artnet, bind_output_socket};
+
+/// The UDP port WLED listens on for realtime data.
+const WLED_REALTIME_PORT: u16 = 21324;
+/// WLED's DNRGB protocol carries at most 489 LEDs per packet.
+const WLED_LEDS_PER_PACKET: usize = 489;
+/// How long WLED stays in realtime mode after the last packet, in seconds.
+const WLED_TIMEOUT_SECONDS: u8 = 2;
+/// Frames are resent at least this often so receivers don't time out while nothing changes.
+const KEEP_ALIVE_SECONDS: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedStripProtocol {
+    Wled,
+    /// Art-Net starting at the given universe. Strips longer than 170 LEDs continue into the following universes.
+    ArtNet { universe: u16 }
+}
+
+#[derive(Resource, Clone)]
+pub struct LedStripSettings {
+    pub enabled: bool,
+    pub protocol: LedStripProtocol,
+    /// The IP address or host name of the controller.
+    pub host: String,
+    pub led_count: usize,
+    /// The x coordinates in the keyboard frame (mm) of the first and last LED.
+    /// If the first LED is on the right, `start_x` is greater than `end_x`.
+    pub start_x: f32,
+    pub end_x: f32,
+    /// Scales all colors, from 0 to 1.
+    pub brightness: f32
+}
+
+impl Default for LedStripSettings {
+    fn default() -> Self {
+        let half_width = KeyboardLayout::default().width() / 2.0;
+        Self {
+            enabled: false,
+            protocol: LedStripProtocol::Wled,
+            host: "192.168.1.50".to_string(),
+            // A common 144 LEDs/m strip cut to the length of a full-size keyboard
+            led_count: 176,
+            start_x: -half_width,
+            end_x: half_width,
+            brightness: 0.6
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct LedStripOutput {
+    socket: Option<UdpSocket>,
+    /// The controller's address, looked up when the settings change.
+    address: Option<SocketAddr>,
+    seconds_since_send: f32,
+    sequence: u8
+}
+
+/// Computes the RGB value of every LED from the current key states.
+pub fn led_colors(settings: &LedStripSettings, layout: &KeyboardLayout, note_state: &NoteState, palette: &KeyPalette, theme: &Theme) -> Vec<[u8; 3]> {
+    let mut colors = vec![[0; 3]; settings.led_count];
+    if settings.led_count < 2 {
+        return colors;
+    }
+
+    let spacing = (settings.end_x - settings.start_x) / (settings.led_count - 1) as f32;
+    let led_at = |x: f32| ((x - settings.start_x) / spacing).round();
+
+    // Light white keys first so black keys take priority where they overlap
+    let keys = layout.keys().filter(|&key| !is_black_key(key))
+        .chain(layout.keys().filter(|&key| is_black_key(key)));
+    for key in keys {
+        let Some(color) = theme.key_color(palette, key, note_state.channels[key as usize], note_state.keys[key as usize]) else { continue };
+        let color = color.to_srgba();
+        let rgb = [color.red, color.green, color.blue].map(|channel| (channel * settings.brightness * 255.0).clamp(0.0, 255.0) as u8);
+
+        let (width, _) = layout.key_size(key);
+        let center = layout.key_center_x(key);
+        let (a, b) = (led_at(center - width / 2.0), led_at(center + width / 2.0));
+        let (low, high) = (a.min(b), a.max(b));
+        // Keys past either end of the strip have no LEDs to light
+        if high < 0.0 || low > (settings.led_count - 1) as f32 {
+            continue;
+        }
+        let (first, last) = (low.max(0.0) as usize, high as usize);
+        for led in colors.iter_mut().take(last + 1).skip(first) {
+            *led = rgb;
+        }
+    }
+
+    colors
+}
+
+/// Looks up the controller's address, logging why if it can't be found.
+fn resolve_address(host: &str, port: u16) -> Option<SocketAddr> {
+    match (host, port).to_socket_addrs() {
+        Ok(mut addresses) => {
+            let address = addresses.next();
+            if address.is_none() {
+                eprintln!("The LED strip controller {} has no address", host);
+            }
+            address
+        }
+        Err(err) => {
+            eprintln!("Failed to look up the LED strip controller {}: {}", host, err);
+            None
+        }
+    }
+}
+
+fn send_led_strip_frame(
+    settings: Res<LedStripSettings>,
+    layout: Res<KeyboardLayout>,
+    note_state: Res<NoteState>,
+    palette: Res<KeyPalette>,
+    theme: Res<Theme>,
+    mut output: ResMut<LedStripOutput>,
+    time: Res<Time>
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    output.seconds_since_send += time.delta_secs();
+    let changed = note_state.is_changed() || palette.is_changed() || theme.is_changed() || settings.is_changed() || layout.is_changed();
+    if !changed && output.seconds_since_send < KEEP_ALIVE_SECONDS {
+        return;
+    }
+    output.seconds_since_send = 0.0;
+
+    // A host name can take a DNS lookup to resolve, so it's only done again when the settings change
+    if settings.is_changed() {
+        let port = match settings.protocol {
+            LedStripProtocol::Wled => WLED_REALTIME_PORT,
+            LedStripProtocol::ArtNet { .. } => artnet::ARTNET_PORT
+        };
+        output.address = resolve_address(&settings.host, port);
+    }
+    let Some(address) = output.address else { return };
+
+    if output.socket.is_none() {
+        output.socket = bind_output_socket();
+    }
+    let Some(socket) = output.socket.as_ref() else { return };
+
+    let colors = led_colors(&settings, &layout, &note_state, &palette, &theme);
+    let mut packets = Vec::new();
+    match settings.protocol {
+        LedStripProtocol::Wled => {
+            for (chunk_index, chunk) in colors.chunks(WLED_LEDS_PER_PACKET).enumerate() {
+                let start = (chunk_index * WLED_LEDS_PER_PACKET) as u16;
+                // DNRGB: protocol, timeout, start index (big endian), then RGB triplets
+                let mut packet = vec![4, WLED_TIMEOUT_SECONDS];
+                packet.extend_from_slice(&start.to_be_bytes());
+                packet.extend(chunk.iter().flatten());
+                packets.push(packet);
+            }
+        }
+        LedStripProtocol::ArtNet { universe } => {
+            let channels: Vec<u8> = colors.iter().flatten().copied().collect();
+            // Only whole LEDs fit in a universe, so 170 LEDs (510 channels) are sent per universe
+            for (index, chunk) in channels.chunks(artnet::UNIVERSE_SIZE / 3 * 3).enumerate() {
+                packets.push(artnet::artdmx_packet(universe + index as u16, output.sequence, chunk));
+            }
+        }
+    }
+
+    for packet in packets {
+        // Dropped packets are fine; the next frame or keep-alive replaces them
+        if let Err(err) = socket.send_to(&packet, address) {
+            if err.kind() != std::io::ErrorKind::WouldBlock {
+                eprintln!("Failed to send LED strip data: {}", err);
+            }
+        }
+    }
+
+    // Art-Net reserves sequence 0 for "disabled"
+    output.sequence = output.sequence.wrapping_add(1).max(1);
+}
+
+pub struct LedStripPlugin;
+
+impl Plugin for LedStripPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<LedStripSettings>()
+            .init_resource::<LedStripOutput>()
+            .add_systems(Update, send_led_strip_frame);
+    }
+}
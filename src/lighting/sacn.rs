@@ -0,0 +1,50 @@
+//! Minimal sACN (ANSI E1.31) data packet encoding.
+
+/// The UDP port sACN receivers listen on.
+pub const SACN_PORT: u16 = 5568;
+/// sACN universes go from 1 to 63999. Receivers ignore data for any other universe.
+pub const UNIVERSES: std::ops::RangeInclusive<u16> = 1..=63999;
+
+/// Identifies this source to receivers. Any fixed UUID works as long as it stays the same between packets.
+const SOURCE_CID: [u8; 16] = *b"ARPianoVisualizr";
+const SOURCE_NAME: &[u8] = b"ARPianoVisualizer";
+
+/// Encodes one E1.31 data packet carrying up to 512 slots for the given universe, clamped into `UNIVERSES`.
+pub fn data_packet(universe: u16, sequence: u8, slots: &[u8]) -> Vec<u8> {
+    let universe = universe.clamp(*UNIVERSES.start(), *UNIVERSES.end());
+    let slots = &slots[..slots.len().min(512)];
+    let length = 126 + slots.len();
+    let flags_and_length = |start: usize| (0x7000 | (length - start) as u16).to_be_bytes();
+
+    let mut packet = Vec::with_capacity(length);
+    // Root layer
+    packet.extend_from_slice(&0x0010u16.to_be_bytes()); // Preamble size
+    packet.extend_from_slice(&0x0000u16.to_be_bytes()); // Postamble size
+    packet.extend_from_slice(b"ASC-E1.17\0\0\0");
+    packet.extend_from_slice(&flags_and_length(16));
+    packet.extend_from_slice(&0x0000_0004u32.to_be_bytes()); // VECTOR_ROOT_E131_DATA
+    packet.extend_from_slice(&SOURCE_CID);
+
+    // Framing layer
+    packet.extend_from_slice(&flags_and_length(38));
+    packet.extend_from_slice(&0x0000_0002u32.to_be_bytes()); // VECTOR_E131_DATA_PACKET
+    let mut source_name = [0u8; 64];
+    source_name[..SOURCE_NAME.len()].copy_from_slice(SOURCE_NAME);
+    packet.extend_from_slice(&source_name);
+    packet.push(100); // Priority
+    packet.extend_from_slice(&0u16.to_be_bytes()); // Synchronization address
+    packet.push(sequence);
+    packet.push(0); // Options
+    packet.extend_from_slice(&universe.to_be_bytes());
+
+    // DMP layer
+    packet.extend_from_slice(&flags_and_length(115));
+    packet.push(0x02); // VECTOR_DMP_SET_PROPERTY
+    packet.push(0xA1); // Address and data type
+    packet.extend_from_slice(&0u16.to_be_bytes()); // First property address
+    packet.extend_from_slice(&1u16.to_be_bytes()); // Address increment
+    packet.extend_from_slice(&(slots.len() as u16 + 1).to_be_bytes());
+    packet.push(0); // DMX start code
+    packet.extend_from_slice(slots);
+    packet
+}
@@ -1,6 +1,9 @@
+use std::{path::{Path, PathBuf}, time::Duration};
+
 use bevy::{
-    app::{App, Startup, Update}, asset::Assets, color::{palettes::css::SILVER, Color}, ecs::{schedule::{IntoScheduleConfigs, SystemSet}, system::{Commands, ResMut}}, math::primitives::Plane3d, pbr::{MeshMaterial3d, StandardMaterial}, prelude::PluginGroup, render::{mesh::{Mesh, Mesh3d, Meshable}, texture::ImagePlugin}, transform::components::Transform, DefaultPlugins
+    app::{App, AppExit, ScheduleRunnerPlugin, Startup, Update}, asset::Assets, color::{palettes::css::SILVER, Color}, ecs::{schedule::{IntoScheduleConfigs, SystemSet}, system::{Commands, ResMut}, world::World}, math::primitives::Plane3d, pbr::{MeshMaterial3d, StandardMaterial}, prelude::PluginGroup, render::{mesh::{Mesh, Mesh3d, Meshable}, settings::{RenderCreation, WgpuSettings}, texture::ImagePlugin, RenderPlugin}, transform::components::Transform, window::{ExitCondition, PresentMode, Window, WindowPlugin}, winit::WinitPlugin, DefaultPlugins
 };
+use clap::Parser;
 
 /// Systems that capture video frames from the camera.
 #[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
@@ -14,8 +17,45 @@ pub struct VideoUpdateSystems;
 #[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
 pub struct VideoDrawSystems;
 
-mod video;
+/// Systems that turn MIDI input into events and note state.
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
+pub struct MidiInputSystems;
+
+mod audio;
 mod background;
+mod camera_cuts;
+mod chord;
+mod command;
+mod config;
+mod decorations;
+mod diagnostics;
+mod dual_output;
+mod hud;
+mod keyboard;
+mod lighting;
+mod midi;
+mod offline_render;
+mod portable;
+mod practice;
+mod recording;
+mod recovery;
+mod remote;
+mod render_layers;
+mod replay;
+mod review;
+mod scene_export;
+mod seed;
+mod setlist;
+mod settings_panel;
+mod setup_bundle;
+mod song;
+mod startup;
+mod status;
+mod touch_controls;
+mod updates;
+mod video;
+mod virtual_camera;
+mod voice;
 pub mod testing;
 
 fn setup(
@@ -32,11 +72,286 @@ fn setup(
     // ));
 }
 
+/// Command line options. Anything given here overrides config.toml.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// The camera stream URL, or a path to a video file (like .mp4 or .mkv) to play in a loop at its own frame rate.
+    #[arg(long)]
+    camera: Option<String>,
+    /// The name of the MIDI input port to connect to.
+    #[arg(long)]
+    midi_port: Option<String>,
+    /// The MIDI file or MusicXML score to load as the song.
+    #[arg(long)]
+    song: Option<PathBuf>,
+    /// A setlist JSON file of songs to perform one after another.
+    #[arg(long)]
+    setlist: Option<PathBuf>,
+    /// The theme file to color the notes with.
+    #[arg(long)]
+    theme: Option<String>,
+    /// The camera calibration JSON file.
+    #[arg(long)]
+    calibration: Option<String>,
+    /// Run without a window or GPU, e.g. for soak tests on a server.
+    #[arg(long)]
+    headless: bool,
+    /// Run a soak test for this many minutes, then exit with a report. Combine with `--camera` to replay a recorded session.
+    #[arg(long, value_name = "MINUTES")]
+    soak: Option<f64>,
+    /// Run tracking on every frame of this video as fast as possible without a window, print how long each stage took,
+    /// and exit.
+    #[arg(long, value_name = "VIDEO", conflicts_with_all = ["soak", "replay"])]
+    benchmark: Option<PathBuf>,
+    /// Run tracking on every frame of this video without a window, write the markers and pose found in each frame to
+    /// `--poses-output`, and exit.
+    #[arg(long, value_name = "VIDEO", conflicts_with_all = ["soak", "replay", "benchmark"])]
+    extract_poses: Option<PathBuf>,
+    /// Where `--extract-poses` writes the poses: a .csv file, or JSON otherwise. Defaults to `<VIDEO>.poses.json`.
+    #[arg(long, value_name = "FILE", requires = "extract_poses")]
+    poses_output: Option<PathBuf>,
+    /// Run tracking on every frame of this video or F9 session twice, with the usual config and with `--compare-config`,
+    /// show both overlays side by side with how far their poses diverge, print a summary, and exit.
+    #[arg(long, value_name = "VIDEO", requires = "compare_config", conflicts_with_all = ["soak", "replay", "benchmark", "extract_poses"])]
+    compare_tracking: Option<PathBuf>,
+    /// The config file to compare against with `--compare-tracking`.
+    #[arg(long, value_name = "TOML", requires = "compare_tracking")]
+    compare_config: Option<PathBuf>,
+    /// Write the side-by-side overlays of `--compare-tracking` to this video instead of showing them in a window.
+    #[arg(long, value_name = "VIDEO", requires = "compare_tracking")]
+    compare_output: Option<PathBuf>,
+    /// Save the config, camera calibration, fiducial layout, and printable marker sheets to this setup bundle, and exit.
+    #[arg(long, value_name = "ZIP", conflicts_with = "import_setup")]
+    export_setup: Option<PathBuf>,
+    /// Install the setup from a bundle made with `--export-setup`, keeping replaced files as .bak, and exit.
+    #[arg(long, value_name = "ZIP")]
+    import_setup: Option<PathBuf>,
+    /// Play back a session recorded with F9 instead of using the live camera.
+    #[arg(long, value_name = "DIR")]
+    replay: Option<PathBuf>,
+    /// Seed generated exercises and randomized visuals, to reproduce a previous session's content.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Start with only the local webcam and the virtual piano: config.toml, session recording, and crash recovery are skipped,
+    /// so the network stream, audio, lighting, and hand occlusion all stay off. Use it to find which feature breaks a setup.
+    #[arg(long, conflicts_with_all = ["camera", "replay"])]
+    safe_mode: bool,
+    /// Keep config.toml, layouts, recordings, and everything else the app saves next to the executable instead of in
+    /// the directory it was started from, e.g. to run it off a USB stick.
+    #[arg(long)]
+    portable: bool,
+    /// Render the song's falling notes to this video file without the camera, as fast as the GPU allows, then exit.
+    #[arg(long, value_name = "VIDEO", requires = "song", conflicts_with_all = ["headless", "soak", "replay", "setlist", "benchmark"])]
+    render_video: Option<PathBuf>,
+    /// The width of the rendered video in pixels.
+    #[arg(long, default_value_t = 1920)]
+    render_width: u32,
+    /// The height of the rendered video in pixels.
+    #[arg(long, default_value_t = 1080)]
+    render_height: u32,
+    /// The frame rate of the rendered video.
+    #[arg(long, default_value_t = 60.0)]
+    render_fps: f64,
+    /// Draw a virtual piano under the rendered notes instead of black.
+    #[arg(long)]
+    render_piano: bool
+}
+
+impl Args {
+    /// Makes the paths given on the command line absolute, so they're still relative to where the app was started from.
+    fn resolve_paths(&mut self) {
+        for path in [&mut self.song, &mut self.setlist, &mut self.benchmark, &mut self.extract_poses, &mut self.poses_output, &mut self.compare_tracking, &mut self.compare_config, &mut self.compare_output, &mut self.export_setup, &mut self.import_setup, &mut self.replay, &mut self.render_video].into_iter().flatten() {
+            portable::resolve_argument(path);
+        }
+        for argument in [&mut self.camera, &mut self.calibration, &mut self.theme].into_iter().flatten() {
+            portable::resolve_argument_string(argument);
+        }
+    }
+}
+
+/// Frame rate of the update loop when running without a window.
+static HEADLESS_FRAME_RATE: f64 = 60.0;
+
 fn main() -> opencv::Result<()> {
+    let mut args = Args::parse();
+
+    if args.portable {
+        args.resolve_paths();
+        match portable::enter_portable_mode() {
+            Ok(dir) => println!("Running in portable mode from {}", dir.display()),
+            Err(err) => {
+                eprintln!("Failed to start in portable mode: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Setup bundles only move files around
+    if args.export_setup.is_some() || args.import_setup.is_some() {
+        let calibration = args.calibration.clone().map(video::aruco_camera::CalibrationFile).unwrap_or_default();
+        let result = match (&args.export_setup, &args.import_setup) {
+            (Some(path), _) => setup_bundle::export(path, &calibration).map_err(|err| format!("Failed to export the setup: {}", err)),
+            (None, Some(path)) => setup_bundle::import(path, &calibration).map_err(|err| format!("Failed to import the setup: {}", err)),
+            (None, None) => Ok(())
+        };
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // The benchmark, pose extraction, and tracking comparison don't need the app at all, only the settings it would have used
+    if args.benchmark.is_some() || args.extract_poses.is_some() || args.compare_tracking.is_some() {
+        let new_world = || {
+            let mut world = World::new();
+            if let Some(calibration) = args.calibration.clone() {
+                world.insert_resource(video::aruco_camera::CalibrationFile(calibration));
+            }
+            world
+        };
+        let mut world = new_world();
+        if !args.safe_mode {
+            config::Config::load_or_default().apply(None, &mut world);
+        }
+        let result = match (args.benchmark, args.extract_poses, args.compare_tracking, args.compare_config) {
+            (Some(video), _, _, _) => diagnostics::benchmark::run(&video, &world).map_err(|err| format!("Benchmark failed: {}", err)),
+            (None, Some(video), _, _) => {
+                let output = args.poses_output.unwrap_or_else(|| video.with_extension("poses.json"));
+                diagnostics::pose_extraction::run(&video, &output, &world).map_err(|err| format!("Pose extraction failed: {}", err))
+            }
+            (None, None, Some(video), Some(config_b)) => config::Config::load(&config_b)
+                .map_err(|err| format!("Failed to load {}: {}", config_b.display(), err))
+                .and_then(|config| {
+                    let mut world_b = new_world();
+                    config.apply(None, &mut world_b);
+                    let label = config_b.file_name().unwrap_or_default().to_string_lossy();
+                    diagnostics::tracking_comparison::run(&video, &label, &world, &world_b, args.compare_output.as_deref())
+                        .map_err(|err| format!("Tracking comparison failed: {}", err))
+                }),
+            _ => Ok(())
+        };
+        if let Err(err) = result {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut app = App::new();
 
-    App::new()
-        .add_plugins(DefaultPlugins.set(ImagePlugin::default_nearest()))
-        .add_plugins((background::CameraBackground, video::VideoCapturePlugin, video::aruco_camera::ArUcoCameraPlugin, testing::TestingPlugin))
+    // These are inserted before the config plugin runs, which leaves resources that already exist alone
+    if args.safe_mode {
+        println!("Starting in safe mode: using the local webcam and ignoring {}", config::CONFIG_PATH);
+        app.insert_resource(video::VideoSource::local_camera());
+    }
+    if let Some(camera) = args.camera {
+        if Path::new(&camera).is_file() {
+            app.insert_resource(video::VideoSource::File { path: camera, looping: true });
+        } else {
+            app.insert_resource(video::VideoSource::Stream(camera));
+        }
+    }
+    if let Some(port_name) = args.midi_port {
+        app.insert_resource(midi::MidiInputSettings { port_name: Some(port_name) });
+    }
+    if let Some(song) = args.song {
+        app.insert_resource(song::SongFile(song));
+    }
+    if let Some(theme) = args.theme {
+        app.insert_resource(keyboard::theme::ThemeSettings { path: Some(theme) });
+    }
+    if let Some(setlist) = args.setlist {
+        app.insert_resource(setlist::SetlistFile(setlist));
+    }
+    if let Some(calibration) = args.calibration {
+        app.insert_resource(video::aruco_camera::CalibrationFile(calibration));
+    }
+    if let Some(minutes) = args.soak {
+        app.insert_resource(diagnostics::soak::SoakTest::new(minutes));
+    }
+    if let Some(seed) = args.seed {
+        app.insert_resource(seed::RandomSeed(seed));
+    }
+    if let Some(session) = args.replay {
+        app.insert_resource(replay::ReplaySession(session));
+    }
+    let rendering = args.render_video.is_some();
+    if let Some(path) = args.render_video {
+        app.insert_resource(offline_render::OfflineRender {
+            path,
+            width: args.render_width,
+            height: args.render_height,
+            fps: args.render_fps,
+            piano: args.render_piano
+        });
+    }
+
+    if args.headless {
+        app
+            .add_plugins(DefaultPlugins
+                .set(ImagePlugin::default_nearest())
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    ..Default::default()
+                })
+                .set(RenderPlugin {
+                    render_creation: RenderCreation::Automatic(WgpuSettings { backends: None, ..Default::default() }),
+                    ..Default::default()
+                })
+                .disable::<WinitPlugin>())
+            .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / HEADLESS_FRAME_RATE)));
+    } else if rendering {
+        // The window only previews the render, so frames don't wait for the display
+        app.add_plugins(DefaultPlugins
+            .set(ImagePlugin::default_nearest())
+            .set(WindowPlugin {
+                primary_window: Some(Window {
+                    present_mode: PresentMode::AutoNoVsync,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }));
+    } else {
+        app.add_plugins(DefaultPlugins.set(ImagePlugin::default_nearest()));
+    }
+
+    if !args.safe_mode {
+        app.add_plugins(config::ConfigPlugin);
+    }
+    if rendering {
+        // A render doesn't use the camera, the instrument, or the speakers, so only what the visualization reads from
+        // them is added
+        app
+            .insert_resource(video::WebcamFrame::default())
+            .insert_resource(video::aruco_camera::ArucoTrackingData::default())
+            .init_resource::<video::aruco_camera::FiducialLayout>()
+            .init_resource::<video::aruco_camera::CalibrationFile>()
+            .init_resource::<video::av_sync::AvSyncCalibration>()
+            .insert_resource(midi::NoteState::default())
+            .init_resource::<midi::latency::LatencyCompensation>()
+            .init_resource::<midi::recorder::MidiRecorder>()
+            .add_event::<midi::MidiEvent>()
+            .add_plugins((midi::bus::NoteBusPlugin, video::tracking::TrackingPlugin));
+    } else {
+        app.add_plugins((video::VideoCapturePlugin, video::aruco_camera::ArUcoCameraPlugin, midi::MidiInputPlugin, audio::SynthPlugin));
+    }
+    app
+        .add_plugins((seed::RandomSeedPlugin, startup::StartupPlugin, status::StatusPlugin, hud::HudPlugin, background::CameraBackground, render_layers::RenderLayersPlugin, dual_output::DualOutputPlugin, testing::TestingPlugin))
+        .add_plugins((song::SongPlugin, keyboard::KeyboardPlugin, lighting::LightingPlugin, practice::PracticePlugin, chord::ChordDisplayPlugin, scene_export::SceneExportPlugin, diagnostics::DiagnosticsPlugin, setlist::SetlistPlugin, touch_controls::TouchControlsPlugin, recording::CompositeRecordingPlugin, command::CommandPlugin, voice::VoiceCommandPlugin, virtual_camera::VirtualCameraPlugin))
+        .add_plugins((remote::RemoteControlPlugin, decorations::DecorationPlugin, camera_cuts::CameraCutPlugin, offline_render::OfflineRenderPlugin));
+    // A render plays the song on its own, so there's no session to record or recover
+    if !args.safe_mode && !rendering {
+        app.add_plugins((replay::ReplayPlugin, recovery::SessionRecoveryPlugin));
+    }
+    // egui needs a window to draw into, and the panels control the camera and instrument a render doesn't have
+    if !args.headless && !rendering {
+        app.add_plugins((settings_panel::SettingsPanelPlugin, command::palette::CommandPalettePlugin, updates::UpdateCheckPlugin, review::PianoRollReviewPlugin, song::automation::editor::AutomationEditorPlugin, song::library::SongLibraryPlugin, practice::summary::PracticeSummaryPlugin, practice::history::PracticeHistoryWindowPlugin));
+    }
+
+    let exit = app
         .add_systems(Startup, setup)
         .configure_sets(Update, (
             VideoCaptureSystems,
@@ -44,6 +359,15 @@ fn main() -> opencv::Result<()> {
             VideoDrawSystems.after(VideoUpdateSystems)
         ))
         .run();
+    // Reaching here means the app wasn't killed, so there's nothing to recover next time.
+    // Safe mode and renders never offered the saved session, so it's kept for the next normal start
+    if !args.safe_mode && !rendering {
+        recovery::discard();
+    }
+
+    if let AppExit::Error(code) = exit {
+        std::process::exit(code.get() as i32);
+    }
 
     Ok(())
 }
\ No newline at end of file
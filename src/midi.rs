@@ -1,40 +1,271 @@
+use std::{sync::OnceLock, time::Instant};
 
-//! Illustrates bloom post-processing using HDR and emissive materials.
-
-use bevy::{
-    core_pipeline::{
-        bloom::{Bloom, BloomCompositeMode},
-        tonemapping::Tonemapping,
-    },
-    math::ops,
-    prelude::*,
-};
-use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-};
-
-fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_systems(Startup, setup_scene)
-        .add_systems(Update, (update_bloom_settings, bounce_spheres))
-        .run();
-}
-
-fn setup_scene(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+use bevy::{app::{App, Plugin, PreUpdate}, ecs::{change_detection::DetectChanges, event::{Event, EventReader, EventWriter}, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Res, ResMut}, world::World}, time::Time};
+use crossbeam_channel::{Receiver, Sender};
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use midly::{live::LiveEvent, MidiMessage};
+use serde::{Deserialize, Serialize};
+
+use crate::{diagnostics::profiling::profile_scope, status::{self, AppError, ErrorSource}, MidiInputSystems};
+
+use bus::{NoteBus, NoteSource};
+
+pub mod bus;
+pub mod history;
+pub mod latency;
+pub mod output;
+pub mod recorder;
+
+#[derive(Resource, Clone, Default, PartialEq)]
+pub struct MidiInputSettings {
+    /// If set, the first input port whose name contains this string is used. Otherwise, the first available port is used.
+    /// Changing this reconnects.
+    pub port_name: Option<String>
+}
+
+/// The controller number of the sustain (damper) pedal.
+pub const SUSTAIN_PEDAL_CONTROLLER: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MidiEventKind {
+    NoteOn { key: u8, velocity: u8 },
+    NoteOff { key: u8 },
+    ControlChange { controller: u8, value: u8 },
+    ProgramChange { program: u8 }
+}
+
+/// The clock MIDI input is timestamped with, in microseconds since it was first read. Unlike the backend's own
+/// timestamps, it's shared by every input, including clicked keys, and doesn't restart when the device reconnects.
+pub fn input_clock() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// A MIDI message received from the input device.
+#[derive(Event, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MidiEvent {
+    /// When the message arrived, on `input_clock`. Only meaningful relative to other events.
+    pub timestamp: u64,
+    pub channel: u8,
+    pub kind: MidiEventKind
+}
+
+impl MidiEvent {
+    /// Parses a raw MIDI message. Returns `None` for messages we don't handle.
+    pub fn parse(timestamp: u64, bytes: &[u8]) -> Option<MidiEvent> {
+        let LiveEvent::Midi { channel, message } = LiveEvent::parse(bytes).ok()? else {
+            return None;
+        };
+
+        let kind = match message {
+            // A note-on with zero velocity is a note-off
+            MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => MidiEventKind::NoteOn { key: key.as_int(), velocity: vel.as_int() },
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => MidiEventKind::NoteOff { key: key.as_int() },
+            MidiMessage::Controller { controller, value } => MidiEventKind::ControlChange { controller: controller.as_int(), value: value.as_int() },
+            MidiMessage::ProgramChange { program } => MidiEventKind::ProgramChange { program: program.as_int() },
+            _ => return None
+        };
+
+        Some(MidiEvent { timestamp, channel: channel.as_int(), kind })
+    }
+}
+
+/// Raw messages forwarded from the MIDI backend's callback thread.
+#[derive(Resource)]
+pub struct MidiInputReceiver(pub Receiver<MidiEvent>);
+
+/// Kept so new connections can forward into the same channel.
+#[derive(Resource)]
+struct MidiInputSender(Sender<MidiEvent>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyState {
+    #[default]
+    Up,
+    /// The key is physically held down.
+    Pressed { velocity: u8 },
+    /// The key was released, but the sustain pedal keeps the note sounding.
+    Sustained { velocity: u8 }
+}
+
+impl KeyState {
+    pub fn is_sounding(&self) -> bool {
+        !matches!(self, KeyState::Up)
+    }
+}
+
+/// The state of every key and the sustain pedal, driven by incoming MIDI events.
+#[derive(Resource)]
+pub struct NoteState {
+    pub keys: [KeyState; 128],
+    /// The channel of each key's latest note on, so instruments with several manuals can tell them apart.
+    pub channels: [u8; 128],
+    pub sustain_pedal: bool
+}
+
+impl Default for NoteState {
+    fn default() -> Self {
+        Self {
+            keys: [KeyState::Up; 128],
+            channels: [0; 128],
+            sustain_pedal: false
+        }
+    }
+}
+
+impl NoteState {
+    pub fn apply(&mut self, channel: u8, kind: MidiEventKind) {
+        match kind {
+            MidiEventKind::NoteOn { key, velocity } => {
+                self.keys[key as usize & 127] = KeyState::Pressed { velocity };
+                self.channels[key as usize & 127] = channel;
+            }
+            MidiEventKind::NoteOff { key } => {
+                let state = &mut self.keys[key as usize & 127];
+                *state = match *state {
+                    KeyState::Pressed { velocity } if self.sustain_pedal => KeyState::Sustained { velocity },
+                    KeyState::Sustained { velocity } => KeyState::Sustained { velocity },
+                    _ => KeyState::Up
+                };
+            }
+            MidiEventKind::ControlChange { controller: SUSTAIN_PEDAL_CONTROLLER, value } => {
+                self.sustain_pedal = value >= 64;
+                if !self.sustain_pedal {
+                    for state in self.keys.iter_mut() {
+                        if matches!(state, KeyState::Sustained { .. }) {
+                            *state = KeyState::Up;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the keys that are currently sounding, either pressed or sustained.
+    pub fn sounding_keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keys.iter().enumerate()
+            .filter(|(_, state)| state.is_sounding())
+            .map(|(key, _)| key as u8)
+    }
+}
+
+fn receive_midi_input(
+    receiver: Res<MidiInputReceiver>,
+    mut events: EventWriter<MidiEvent>
+) {
+    for event in receiver.0.try_iter() {
+        events.write(event);
+    }
+}
+
+/// Publishes the instrument's input, including replayed input, to the note bus.
+fn publish_live_notes(
+    time: Res<Time>,
+    mut events: EventReader<MidiEvent>,
+    mut bus: ResMut<NoteBus>
+) {
+    let now = time.elapsed_secs_f64();
+    for event in events.read() {
+        bus.publish(now, event.timestamp, NoteSource::Live, event.channel, event.kind);
+    }
+}
+
+fn update_note_state(
+    bus: Res<NoteBus>,
+    mut note_state: ResMut<NoteState>
 ) {
-    commands.spawn((
-        Camera3d::default(),
-        Camera {
-            clear_color: ClearColorConfig::Custom(Color::BLACK),
-            ..default()
+    profile_scope!("note update");
+    for event in bus.from_source(NoteSource::Live) {
+        note_state.apply(event.channel, event.kind);
+    }
+}
+
+/// Returns the names of the available MIDI input ports.
+pub fn available_midi_ports() -> Vec<String> {
+    let Ok(midi_in) = MidiInput::new("ARPianoVisualizer port list") else { return Vec::new() };
+    midi_in.ports().iter()
+        .filter_map(|port| midi_in.port_name(port).ok())
+        .collect()
+}
+
+fn connect_midi_input(port_name: Option<&str>, sender: Sender<MidiEvent>) -> Result<MidiInputConnection<()>, Box<dyn std::error::Error>> {
+    let mut midi_in = MidiInput::new("ARPianoVisualizer input")?;
+    midi_in.ignore(Ignore::None);
+
+    let ports = midi_in.ports();
+    let port = ports.iter()
+        .find(|port| match port_name {
+            Some(name) => midi_in.port_name(port).is_ok_and(|port_name| port_name.contains(name)),
+            None => true
+        })
+        .ok_or("No MIDI input port found")?;
+
+    println!("Using MIDI input port: {}", midi_in.port_name(port)?);
+
+    let connection = midi_in.connect(
+        port,
+        "ARPianoVisualizer-input",
+        move |_, message, _| {
+            // Stamped on arrival rather than with the backend's timestamp, which restarts with each connection
+            if let Some(event) = MidiEvent::parse(input_clock(), message) {
+                // The receiver only disconnects when the app is shutting down
+                let _ = sender.send(event);
+            }
         },
-        Tonemapping::TonyMcMapface, // 1. Using a tonemapper that desaturates to white is recommended
-        Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
-        Bloom::NATURAL, // 2. Enable bloom for the camera
-    ));
-}
\ No newline at end of file
+        ()
+    )?;
+
+    Ok(connection)
+}
+
+/// Reconnects to the selected port when the MIDI settings change.
+fn reconnect_midi_input(
+    mut commands: Commands,
+    settings: Res<MidiInputSettings>,
+    sender: Res<MidiInputSender>
+) {
+    if !settings.is_changed() || settings.is_added() {
+        return;
+    }
+
+    let port_name = settings.port_name.clone();
+    let sender = sender.0.clone();
+    commands.queue(move |world: &mut World| {
+        // Close the old connection first, since some backends only allow one connection per port
+        if let Some(connection) = world.remove_non_send_resource::<MidiInputConnection<()>>() {
+            connection.close();
+        }
+        match connect_midi_input(port_name.as_deref(), sender) {
+            Ok(connection) => world.insert_non_send_resource(connection),
+            Err(err) => {
+                world.send_event(AppError::new(ErrorSource::Midi, format!("Failed to open MIDI input: {}", err)));
+            }
+        }
+    });
+}
+
+pub struct MidiInputPlugin;
+
+impl Plugin for MidiInputPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let settings = app.world_mut().get_resource_or_init::<MidiInputSettings>().clone();
+
+        // The app still works without a keyboard connected; it just won't receive any notes
+        match connect_midi_input(settings.port_name.as_deref(), sender.clone()) {
+            Ok(connection) => {
+                app.insert_non_send_resource(connection);
+            }
+            Err(err) => status::report_startup_error(app, AppError::new(ErrorSource::Midi, format!("Failed to open MIDI input: {}", err)))
+        }
+
+        app
+            .add_event::<MidiEvent>()
+            .insert_resource(MidiInputReceiver(receiver))
+            .insert_resource(MidiInputSender(sender))
+            .insert_resource(NoteState::default())
+            .add_plugins((bus::NoteBusPlugin, history::MidiHistoryPlugin, latency::LatencyMeasurementPlugin, output::MidiOutputPlugin, recorder::MidiRecorderPlugin))
+            .add_systems(PreUpdate, (reconnect_midi_input, receive_midi_input, publish_live_notes, update_note_state).chain().in_set(MidiInputSystems));
+    }
+}
@@ -0,0 +1,63 @@
+//! One stream of timestamped note events from every note source, so visuals, scoring, lighting, and outputs read the
+//! same API instead of each handling live input and song playback separately. Sources publish into `NoteBus` as they
+//! produce notes, and the bus is emptied at the start of every frame, so a consumer sees everything published this
+//! frame as long as it runs after the sources it cares about: live input is published in `MidiInputSystems`, and song
+//! notes by `playback::publish_song_notes`.
+
+use bevy::{app::{App, First, Plugin}, ecs::{resource::Resource, system::ResMut}};
+
+use super::MidiEventKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteSource {
+    /// The connected instrument, or a replay of it.
+    Live,
+    /// The song's notes, as playback passes over them.
+    Song
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteEvent {
+    /// When the event was published, in seconds since startup. Never earlier than any event published before it.
+    pub time: f64,
+    /// When the event happened, on `midi::input_clock`. Live input keeps the time each message arrived, so events handled
+    /// in the same frame keep their spacing; song notes are stamped as they're published.
+    pub timestamp: u64,
+    pub source: NoteSource,
+    pub channel: u8,
+    pub kind: MidiEventKind
+}
+
+#[derive(Resource, Default)]
+pub struct NoteBus {
+    /// This frame's events, in the order they were published.
+    events: Vec<NoteEvent>,
+    /// The latest time published so far, which earlier timestamps are raised to so time never goes backwards.
+    latest: f64
+}
+
+impl NoteBus {
+    pub fn publish(&mut self, time: f64, timestamp: u64, source: NoteSource, channel: u8, kind: MidiEventKind) {
+        self.latest = self.latest.max(time);
+        self.events.push(NoteEvent { time: self.latest, timestamp, source, channel, kind });
+    }
+
+    /// The events from one source published this frame, in order.
+    pub fn from_source(&self, source: NoteSource) -> impl Iterator<Item = &NoteEvent> + '_ {
+        self.events.iter().filter(move |event| event.source == source)
+    }
+}
+
+fn clear_note_bus(mut bus: ResMut<NoteBus>) {
+    bus.events.clear();
+}
+
+pub struct NoteBusPlugin;
+
+impl Plugin for NoteBusPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<NoteBus>()
+            .add_systems(First, clear_note_bus);
+    }
+}
@@ -0,0 +1,115 @@
+//! A rolling buffer of the notes received from the instrument, so what was just played can be looked back on.
+
+use std::collections::VecDeque;
+
+use bevy::{app::{App, Plugin, PreUpdate}, ecs::{resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
+
+use crate::MidiInputSystems;
+
+use super::{bus::{NoteBus, NoteSource}, MidiEventKind};
+
+/// How many seconds of input are kept.
+pub const HISTORY_SECONDS: f64 = 300.0;
+
+/// A note from the history, with its times in seconds since startup like `NoteEvent::time`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayedNote {
+    pub key: u8,
+    pub velocity: u8,
+    pub start: f64,
+    /// When the key was released, or `None` if it's still held.
+    pub end: Option<f64>
+}
+
+#[derive(Resource, Default)]
+pub struct MidiHistory {
+    /// Received events with the time they were published at, oldest first.
+    events: VecDeque<(f64, MidiEventKind)>
+}
+
+impl MidiHistory {
+    pub fn push(&mut self, time: f64, kind: MidiEventKind) {
+        self.events.push_back((time, kind));
+        while self.events.front().is_some_and(|(oldest, _)| *oldest < time - HISTORY_SECONDS) {
+            self.events.pop_front();
+        }
+    }
+
+    /// The notes that were sounding at any point since `since`, in the order they started. The sustain pedal is
+    /// ignored, so notes end when their key was released.
+    pub fn notes_since(&self, since: f64) -> Vec<PlayedNote> {
+        let mut notes: Vec<PlayedNote> = Vec::new();
+        let mut held: [Option<usize>; 128] = [None; 128];
+        for &(time, kind) in &self.events {
+            match kind {
+                MidiEventKind::NoteOn { key, velocity } => {
+                    let key = key & 127;
+                    // A repeated note on without a release in between ends the earlier note
+                    if let Some(index) = held[key as usize] {
+                        notes[index].end = Some(time);
+                    }
+                    held[key as usize] = Some(notes.len());
+                    notes.push(PlayedNote { key, velocity, start: time, end: None });
+                }
+                MidiEventKind::NoteOff { key } => {
+                    if let Some(index) = held[key as usize & 127].take() {
+                        notes[index].end = Some(time);
+                    }
+                }
+                _ => {}
+            }
+        }
+        notes.retain(|note| note.end.is_none_or(|end| end >= since));
+        notes
+    }
+}
+
+fn record_midi_history(
+    bus: Res<NoteBus>,
+    mut history: ResMut<MidiHistory>
+) {
+    for event in bus.from_source(NoteSource::Live) {
+        history.push(event.time, event.kind);
+    }
+}
+
+pub struct MidiHistoryPlugin;
+
+impl Plugin for MidiHistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<MidiHistory>()
+            .add_systems(PreUpdate, record_midi_history.after(super::publish_live_notes).in_set(MidiInputSystems));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_paired_into_notes() {
+        let mut history = MidiHistory::default();
+        history.push(1.0, MidiEventKind::NoteOn { key: 60, velocity: 80 });
+        history.push(1.5, MidiEventKind::NoteOff { key: 60 });
+        history.push(2.0, MidiEventKind::NoteOn { key: 64, velocity: 90 });
+        history.push(2.5, MidiEventKind::NoteOn { key: 64, velocity: 70 });
+        history.push(3.0, MidiEventKind::NoteOn { key: 67, velocity: 60 });
+
+        let notes = history.notes_since(1.6);
+        assert_eq!(notes, vec![
+            PlayedNote { key: 64, velocity: 90, start: 2.0, end: Some(2.5) },
+            PlayedNote { key: 64, velocity: 70, start: 2.5, end: None },
+            PlayedNote { key: 67, velocity: 60, start: 3.0, end: None }
+        ]);
+    }
+
+    #[test]
+    fn old_events_are_dropped() {
+        let mut history = MidiHistory::default();
+        history.push(0.0, MidiEventKind::NoteOn { key: 60, velocity: 80 });
+        history.push(HISTORY_SECONDS + 1.0, MidiEventKind::NoteOff { key: 60 });
+        // The note on is gone, so the release has nothing to end
+        assert!(history.notes_since(0.0).is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+//! Loopback latency measurement: plays a note through the MIDI output, listens for the synth's sound on the default audio input,
+//! and reports the round trip. Connect the synth's audio output to the computer's input (or hold a microphone near it) first.
+
+use std::{error::Error, thread, time::{Duration, Instant}};
+
+use bevy::{app::{App, Plugin, Update}, ecs::{event::EventWriter, resource::Resource, system::ResMut}};
+use cpal::{traits::{DeviceTrait, HostTrait, StreamTrait}, FromSample, Sample, SampleFormat, SizedSample};
+use crossbeam_channel::{Receiver, Sender};
+use midir::MidiOutput;
+
+use crate::status::{AppError, ErrorSource};
+
+/// Middle C, which every synth can play.
+static TEST_KEY: u8 = 60;
+static TEST_VELOCITY: u8 = 110;
+const TRIALS: usize = 5;
+/// How long to wait for the previous test note to die away.
+const SETTLE_TIME: Duration = Duration::from_millis(600);
+/// How long the input is listened to before each note to find the noise floor.
+const NOISE_WINDOW: Duration = Duration::from_millis(200);
+/// How long to wait for the note to be heard before giving up.
+const ONSET_TIMEOUT: Duration = Duration::from_secs(1);
+/// The onset is the first sample this many times louder than the noise floor...
+const NOISE_MARGIN: f32 = 4.0;
+/// ...and at least this loud, so a silent input doesn't trigger on dither.
+const MIN_THRESHOLD: f32 = 0.02;
+
+/// Latency values used to line up timing with what the player hears.
+#[derive(Resource, Clone, Default, PartialEq)]
+pub struct LatencyCompensation {
+    /// Seconds from a note being sent to it being heard. Players line up the sound with the beat,
+    /// so their key presses land this much early.
+    pub audio_output: f64,
+    /// Seconds the camera image lags behind MIDI input. The waterfall is drawn this far behind the song,
+    /// so notes reach the keys when they're seen going down.
+    pub video: f64
+}
+
+impl LatencyCompensation {
+    /// When a note due at `time` has to be pressed to be heard on time. Positive latency moves it earlier.
+    pub fn press_time(&self, time: f64) -> f64 {
+        time - self.audio_output
+    }
+}
+
+/// The peak level of each audio frame in a buffer from the input device.
+struct AudioChunk {
+    received: Instant,
+    peaks: Vec<f32>
+}
+
+enum MeasurementMessage {
+    Trial(Duration),
+    Finished,
+    Failed(String)
+}
+
+#[derive(Resource, Default)]
+pub struct LatencyMeasurement {
+    receiver: Option<Receiver<MeasurementMessage>>,
+    /// The round trip of each note heard in the current or last measurement.
+    pub trials: Vec<Duration>
+}
+
+impl LatencyMeasurement {
+    pub fn is_running(&self) -> bool {
+        self.receiver.is_some()
+    }
+
+    /// Starts measuring on a background thread. The MIDI output port is chosen the same way as the input port,
+    /// since keyboards with a built-in synth usually name both the same.
+    pub fn start(&mut self, port_name: Option<String>) {
+        if self.is_running() {
+            return;
+        }
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.receiver = Some(receiver);
+        self.trials.clear();
+
+        thread::spawn(move || {
+            let message = match run_measurement(port_name.as_deref(), &sender) {
+                Ok(()) => MeasurementMessage::Finished,
+                Err(err) => MeasurementMessage::Failed(err.to_string())
+            };
+            let _ = sender.send(message);
+        });
+    }
+
+    /// The median round trip, which ignores the odd trial delayed by a scheduling hiccup.
+    pub fn median(&self) -> Option<Duration> {
+        let mut trials = self.trials.clone();
+        trials.sort();
+        trials.get(trials.len() / 2).copied()
+    }
+}
+
+fn build_input_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, sender: Sender<AudioChunk>) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>
+{
+    let channels = config.channels.max(1) as usize;
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let received = Instant::now();
+            let peaks = data.chunks(channels)
+                .map(|frame| frame.iter().map(|sample| sample.to_sample::<f32>().abs()).fold(0.0, f32::max))
+                .collect();
+            // The measurement thread stops listening once it's done
+            let _ = sender.send(AudioChunk { received, peaks });
+        },
+        |err| eprintln!("Audio input error: {}", err),
+        None
+    )
+}
+
+/// Waits for the first frame after `sent` that crosses the threshold, and returns how long after `sent` it was captured.
+fn wait_for_onset(audio: &Receiver<AudioChunk>, sent: Instant, threshold: f32, sample_rate: f64) -> Option<Duration> {
+    while sent.elapsed() < ONSET_TIMEOUT {
+        let Ok(chunk) = audio.recv_timeout(ONSET_TIMEOUT) else { break };
+        // The callback runs when the buffer is full, so earlier frames were captured before it was received
+        let frames = chunk.peaks.len();
+        for (index, peak) in chunk.peaks.iter().enumerate() {
+            let age = Duration::from_secs_f64((frames - index) as f64 / sample_rate);
+            let Some(captured) = chunk.received.checked_sub(age) else { continue };
+            if captured > sent && *peak >= threshold {
+                return Some(captured - sent);
+            }
+        }
+    }
+    None
+}
+
+fn run_measurement(port_name: Option<&str>, sender: &Sender<MeasurementMessage>) -> Result<(), Box<dyn Error>> {
+    let midi_out = MidiOutput::new("ARPianoVisualizer latency test")?;
+    let ports = midi_out.ports();
+    let port = ports.iter()
+        .find(|port| match port_name {
+            Some(name) => midi_out.port_name(port).is_ok_and(|port_name| port_name.contains(name)),
+            None => true
+        })
+        .ok_or("No MIDI output port found")?;
+    println!("Measuring latency through MIDI output port: {}", midi_out.port_name(port)?);
+    let mut connection = midi_out.connect(port, "ARPianoVisualizer-latency")
+        .map_err(|err| err.to_string())?;
+
+    let device = cpal::default_host().default_input_device().ok_or("No audio input device found")?;
+    let supported_config = device.default_input_config()?;
+    let sample_rate = supported_config.sample_rate().0 as f64;
+    let config = supported_config.config();
+    let (audio_sender, audio) = crossbeam_channel::unbounded();
+    let stream = match supported_config.sample_format() {
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &config, audio_sender)?,
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &config, audio_sender)?,
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &config, audio_sender)?,
+        format => return Err(format!("Unsupported audio input format {}", format).into())
+    };
+    stream.play()?;
+
+    for _ in 0..TRIALS {
+        thread::sleep(SETTLE_TIME);
+        while audio.try_recv().is_ok() {}
+
+        let noise_start = Instant::now();
+        let mut noise_floor = 0.0f32;
+        while noise_start.elapsed() < NOISE_WINDOW {
+            if let Ok(chunk) = audio.recv_timeout(NOISE_WINDOW) {
+                noise_floor = chunk.peaks.iter().fold(noise_floor, |max, peak| max.max(*peak));
+            }
+        }
+        let threshold = (noise_floor * NOISE_MARGIN).max(MIN_THRESHOLD);
+
+        let sent = Instant::now();
+        connection.send(&[0x90, TEST_KEY, TEST_VELOCITY])?;
+        let onset = wait_for_onset(&audio, sent, threshold, sample_rate);
+        connection.send(&[0x80, TEST_KEY, 0])?;
+
+        let latency = onset.ok_or("The test note wasn't heard on the audio input. Check that the synth is audible and not too quiet")?;
+        sender.send(MeasurementMessage::Trial(latency))?;
+    }
+
+    connection.close();
+    Ok(())
+}
+
+fn poll_latency_measurement(
+    mut measurement: ResMut<LatencyMeasurement>,
+    mut compensation: ResMut<LatencyCompensation>,
+    mut errors: EventWriter<AppError>
+) {
+    let Some(receiver) = measurement.receiver.clone() else { return };
+
+    for message in receiver.try_iter() {
+        match message {
+            MeasurementMessage::Trial(latency) => measurement.trials.push(latency),
+            MeasurementMessage::Finished => {
+                measurement.receiver = None;
+                if let Some(median) = measurement.median() {
+                    println!("Measured round-trip audio latency: {:.1} ms", median.as_secs_f64() * 1000.0);
+                    compensation.audio_output = median.as_secs_f64();
+                }
+            }
+            MeasurementMessage::Failed(err) => {
+                measurement.receiver = None;
+                errors.write(AppError::new(ErrorSource::Midi, format!("Latency measurement failed: {}", err)));
+            }
+        }
+    }
+}
+
+pub struct LatencyMeasurementPlugin;
+
+impl Plugin for LatencyMeasurementPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<LatencyCompensation>()
+            .init_resource::<LatencyMeasurement>()
+            .add_systems(Update, poll_latency_measurement);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_latency_moves_presses_earlier() {
+        let compensation = LatencyCompensation { audio_output: 0.25, ..Default::default() };
+        assert_eq!(compensation.press_time(2.0), 1.75);
+        assert_eq!(LatencyCompensation::default().press_time(2.0), 2.0);
+    }
+}
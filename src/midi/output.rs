@@ -0,0 +1,118 @@
+//! Sends the song to an external MIDI output, so the instrument itself plays it while the visualizer shows the notes.
+//! This is the "listen" step of a lesson: hear the song on the real piano before practicing it.
+
+use std::error::Error;
+
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::DetectChanges, event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, NonSendMut, Res}, world::World}};
+use midir::{MidiOutput, MidiOutputConnection};
+
+use crate::{song::playback, status::{AppError, ErrorSource}};
+
+use super::{bus::{NoteBus, NoteSource}, MidiEventKind};
+
+/// The controller that silences every note on a channel.
+const ALL_NOTES_OFF_CONTROLLER: u8 = 123;
+
+#[derive(Resource, Clone, Default, PartialEq)]
+pub struct MidiOutputSettings {
+    /// Play the song on the output as it scrolls by. Changing this or the port reconnects.
+    pub enabled: bool,
+    /// If set, the first output port whose name contains this string is used. Otherwise, the first available port is used.
+    pub port_name: Option<String>
+}
+
+fn encode(channel: u8, kind: MidiEventKind) -> Vec<u8> {
+    let channel = channel & 15;
+    match kind {
+        MidiEventKind::NoteOn { key, velocity } => vec![0x90 | channel, key & 127, velocity & 127],
+        MidiEventKind::NoteOff { key } => vec![0x80 | channel, key & 127, 0],
+        MidiEventKind::ControlChange { controller, value } => vec![0xB0 | channel, controller & 127, value & 127],
+        MidiEventKind::ProgramChange { program } => vec![0xC0 | channel, program & 127]
+    }
+}
+
+/// Returns the names of the available MIDI output ports.
+pub fn available_midi_output_ports() -> Vec<String> {
+    let Ok(midi_out) = MidiOutput::new("ARPianoVisualizer output port list") else { return Vec::new() };
+    midi_out.ports().iter()
+        .filter_map(|port| midi_out.port_name(port).ok())
+        .collect()
+}
+
+fn connect_midi_output(port_name: Option<&str>) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    let midi_out = MidiOutput::new("ARPianoVisualizer output")?;
+    let ports = midi_out.ports();
+    let port = ports.iter()
+        .find(|port| match port_name {
+            Some(name) => midi_out.port_name(port).is_ok_and(|port_name| port_name.contains(name)),
+            None => true
+        })
+        .ok_or("No MIDI output port found")?;
+
+    println!("Using MIDI output port: {}", midi_out.port_name(port)?);
+    midi_out.connect(port, "ARPianoVisualizer-output").map_err(|err| err.to_string().into())
+}
+
+/// Silences the instrument and closes the connection, so notes that were sounding don't hang.
+fn close(mut connection: MidiOutputConnection) {
+    for channel in 0..16 {
+        let _ = connection.send(&encode(channel, MidiEventKind::ControlChange { controller: ALL_NOTES_OFF_CONTROLLER, value: 0 }));
+    }
+    connection.close();
+}
+
+/// Opens or closes the output when the settings change.
+fn reconnect_midi_output(
+    mut commands: Commands,
+    settings: Res<MidiOutputSettings>
+) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    let settings = settings.clone();
+    commands.queue(move |world: &mut World| {
+        if let Some(connection) = world.remove_non_send_resource::<MidiOutputConnection>() {
+            close(connection);
+        }
+        if !settings.enabled {
+            return;
+        }
+        match connect_midi_output(settings.port_name.as_deref()) {
+            Ok(connection) => world.insert_non_send_resource(connection),
+            Err(err) => {
+                world.send_event(AppError::new(ErrorSource::Midi, format!("Failed to open MIDI output: {}", err)));
+            }
+        }
+    });
+}
+
+/// Plays the song notes that playback passed over this frame on the output.
+fn play_song_on_output(
+    mut commands: Commands,
+    bus: Res<NoteBus>,
+    connection: Option<NonSendMut<MidiOutputConnection>>,
+    mut errors: EventWriter<AppError>
+) {
+    let Some(mut connection) = connection else { return };
+    let failure = bus.from_source(NoteSource::Song)
+        .find_map(|event| connection.send(&encode(event.channel, event.kind)).err());
+
+    // The device was most likely unplugged, so stop sending to it until it's reconnected from the settings
+    if let Some(err) = failure {
+        errors.write(AppError::new(ErrorSource::Midi, format!("Failed to send to the MIDI output: {}", err)));
+        commands.queue(|world: &mut World| {
+            world.remove_non_send_resource::<MidiOutputConnection>();
+        });
+    }
+}
+
+pub struct MidiOutputPlugin;
+
+impl Plugin for MidiOutputPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<MidiOutputSettings>()
+            .add_systems(Update, (reconnect_midi_output, play_song_on_output).chain().after(playback::publish_song_notes));
+    }
+}
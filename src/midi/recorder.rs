@@ -0,0 +1,187 @@
+//! Records live playing to a Standard MIDI File. F12, the command palette, or the settings panel start and stop a take,
+//! which is saved to `RECORDING_DIR/take-<time>.mid` with the notes, pedals, and program changes as they arrived,
+//! timed by when they arrived (see `midi::input_clock`) rather than the frame they were handled in.
+//!
+//! The file's tempo is the loaded song's at the playback position, scaled by the playback speed, so a take played
+//! along with a song lines up with its bars in a sequencer. Without a song it's `DEFAULT_TEMPO`.
+
+use std::{collections::BTreeSet, error::Error, fs, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{app::{App, Plugin, Update}, ecs::{event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
+use midly::{num::{u15, u24, u28, u4, u7}, Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::{command, song::{playback::SongPlayback, Song}, status::{AppError, ErrorSource}};
+
+use super::{bus::{NoteBus, NoteSource}, MidiEventKind};
+
+static RECORDING_DIR: &str = "recordings";
+const TICKS_PER_BEAT: u16 = 480;
+/// 120 BPM, in microseconds per beat.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// One recording, with event times in microseconds since its first event.
+struct Take {
+    first_timestamp: Option<u64>,
+    events: Vec<(u64, u8, MidiEventKind)>,
+    /// Microseconds per beat.
+    tempo: u32,
+    /// The numerator and denominator, if the song has one.
+    time_signature: Option<(u8, u8)>
+}
+
+impl Take {
+    fn record(&mut self, timestamp: u64, channel: u8, kind: MidiEventKind) {
+        let first = *self.first_timestamp.get_or_insert(timestamp);
+        self.events.push((timestamp.saturating_sub(first), channel, kind));
+    }
+
+    fn to_smf(&self) -> Smf<'static> {
+        let mut track = vec![
+            TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::TrackName(b"Live recording")) },
+            TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(self.tempo))) }
+        ];
+        if let Some((numerator, denominator)) = self.time_signature {
+            let denominator_power = denominator.max(1).trailing_zeros() as u8;
+            track.push(TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator_power, 24, 8)) });
+        }
+
+        // Clicked keys are handled a frame after they're stamped, so they can arrive slightly out of order
+        let mut events = self.events.clone();
+        events.sort_by_key(|(time, _, _)| *time);
+
+        let mut last_tick = 0;
+        let mut held = BTreeSet::new();
+        let mut push = |track: &mut Vec<TrackEvent<'static>>, time: u64, channel: u8, message: MidiMessage| {
+            let tick = microseconds_to_ticks(time, self.tempo);
+            track.push(TrackEvent { delta: u28::new((tick - last_tick) as u32), kind: TrackEventKind::Midi { channel: u4::new(channel), message } });
+            last_tick = tick;
+        };
+        for &(time, channel, kind) in &events {
+            let message = match kind {
+                MidiEventKind::NoteOn { key, velocity } => {
+                    held.insert((channel, key));
+                    MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(velocity) }
+                }
+                MidiEventKind::NoteOff { key } => {
+                    held.remove(&(channel, key));
+                    MidiMessage::NoteOff { key: u7::new(key), vel: u7::new(64) }
+                }
+                MidiEventKind::ControlChange { controller, value } => MidiMessage::Controller { controller: u7::new(controller), value: u7::new(value) },
+                MidiEventKind::ProgramChange { program } => MidiMessage::ProgramChange { program: u7::new(program) }
+            };
+            push(&mut track, time, channel, message);
+        }
+        // End notes still held when recording stopped, so they don't hang in a sequencer
+        let end = events.last().map_or(0, |(time, _, _)| *time);
+        for (channel, key) in held {
+            push(&mut track, end, channel, MidiMessage::NoteOff { key: u7::new(key), vel: u7::new(64) });
+        }
+        track.push(TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+
+        Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(u15::new(TICKS_PER_BEAT))),
+            tracks: vec![track]
+        }
+    }
+
+    fn save(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        fs::create_dir_all(RECORDING_DIR)?;
+        let path = Path::new(RECORDING_DIR).join(format!("take-{}.mid", timestamp));
+        self.to_smf().save(&path)?;
+        Ok(path)
+    }
+}
+
+fn microseconds_to_ticks(microseconds: u64, tempo: u32) -> u64 {
+    (microseconds as u128 * TICKS_PER_BEAT as u128 / tempo.max(1) as u128) as u64
+}
+
+#[derive(Resource, Default)]
+pub struct MidiRecorder {
+    /// Set to start a take, and cleared to stop and save it.
+    pub recording: bool,
+    take: Option<Take>
+}
+
+impl MidiRecorder {
+    /// The number of events in the current take.
+    pub fn recorded_events(&self) -> usize {
+        self.take.as_ref().map_or(0, |take| take.events.len())
+    }
+}
+
+/// Starts and stops takes, and records the input into the current one.
+fn record_midi_input(
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    mut recorder: ResMut<MidiRecorder>,
+    bus: Res<NoteBus>,
+    mut errors: EventWriter<AppError>
+) {
+    if recorder.recording && recorder.take.is_none() {
+        let (tempo, time_signature) = if song.notes.is_empty() {
+            (DEFAULT_TEMPO, None)
+        } else {
+            let tick = song.tempo_map.seconds_to_tick(playback.position);
+            let tempo = song.tempo_map.tempos.iter().rev().find(|(change, _)| *change <= tick).map_or(DEFAULT_TEMPO, |(_, tempo)| *tempo);
+            let signature = song.tempo_map.time_signatures.iter().rev().find(|signature| signature.tick <= tick);
+            (((tempo as f64 / playback.speed.max(0.01)) as u32).min(0xFF_FFFF), signature.map(|signature| (signature.numerator, signature.denominator)))
+        };
+        println!("Recording MIDI input at {:.1} BPM", 60_000_000.0 / tempo as f64);
+        recorder.take = Some(Take { first_timestamp: None, events: Vec::new(), tempo, time_signature });
+    }
+
+    if let Some(take) = recorder.take.as_mut() {
+        for event in bus.from_source(NoteSource::Live) {
+            take.record(event.timestamp, event.channel, event.kind);
+        }
+    }
+
+    if !recorder.recording {
+        let Some(take) = recorder.take.take() else { return };
+        if take.events.is_empty() {
+            println!("Nothing was played, so the MIDI take wasn't saved");
+            return;
+        }
+        match take.save() {
+            Ok(path) => println!("Saved {} MIDI events to {}", take.events.len(), path.display()),
+            Err(err) => {
+                errors.write(AppError::new(ErrorSource::Midi, format!("Failed to save the MIDI recording: {}", err)));
+            }
+        }
+    }
+}
+
+pub struct MidiRecorderPlugin;
+
+impl Plugin for MidiRecorderPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<MidiRecorder>()
+            .add_systems(Update, record_midi_input.after(command::run_app_commands));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_are_written_with_their_timing() {
+        let mut take = Take { first_timestamp: None, events: Vec::new(), tempo: DEFAULT_TEMPO, time_signature: Some((3, 4)) };
+        for (timestamp, kind) in [
+            (1_000_000, MidiEventKind::NoteOn { key: 60, velocity: 90 }),
+            (1_250_000, MidiEventKind::NoteOff { key: 60 }),
+            (1_500_000, MidiEventKind::NoteOn { key: 64, velocity: 80 })
+        ] {
+            take.record(timestamp, 0, kind);
+        }
+
+        let smf = take.to_smf();
+        let deltas: Vec<u32> = smf.tracks[0].iter().map(|event| event.delta.as_int()).collect();
+        // Name, tempo, and time signature, then a quarter of a second is half a beat at 120 BPM, and the held note ends with the take
+        assert_eq!(deltas, vec![0, 0, 0, 0, 240, 240, 0, 0]);
+        assert!(matches!(smf.tracks[0][7].kind, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+    }
+}
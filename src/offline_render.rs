@@ -0,0 +1,263 @@
+//! Renders the falling-note visualization of a song to a video file without the camera, for making tutorial videos.
+//! Started with `--render-video`, which plays the song once from the start and exits when it ends.
+//!
+//! Time is stepped by exactly one video frame per update instead of following the clock, so the video plays at the
+//! song's speed however long each frame takes to render, and every frame is encoded rather than dropped when encoding
+//! falls behind. The view comes from the camera shots (see `camera_cuts`), over black, or over a virtual piano whose keys
+//! light up as the song plays them. The keyboard isn't tracked, so it stays where the fiducial layout puts it.
+
+use std::{error::Error, path::PathBuf, thread::{self, JoinHandle}, time::Duration};
+
+use bevy::{app::{App, AppExit, Plugin, Update}, asset::{Assets, Handle}, color::Color, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, event::EventWriter, hierarchy::ChildOf, observer::Trigger, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, image::Image, math::primitives::Cuboid, pbr::{MeshMaterial3d, StandardMaterial}, render::{camera::{Camera, ClearColorConfig}, gpu_readback::{Readback, ReadbackComplete}, mesh::{Mesh, Mesh3d}}, time::TimeUpdateStrategy, transform::components::Transform};
+use crossbeam_channel::{Receiver, Sender};
+use opencv::{core::Mat, imgproc, videoio::VideoWriterTrait};
+
+use crate::{camera_cuts::ShotCamera, keyboard::{hands::HandSplitSettings, is_black_key, theme::{self, Theme, ThemeMaterials}, KeyPalette, KeyboardLayout, KeyboardRoot}, midi::{bus::{NoteBus, NoteSource}, MidiEventKind}, recording, render_layers::OutputCamera, song::{playback::{self, SongPlayback}, Song}, startup::{StartupProgress, StartupTask}, video::tracking::TrackingSettings};
+
+/// Rendered frames waiting to be encoded. Rendering waits for the encoder when it's full, so nothing is dropped.
+const MAX_QUEUED_FRAMES: usize = 4;
+/// The height of the virtual piano's keys, in mm. Black keys stand this far above the white keys' tops.
+const KEY_HEIGHT: f32 = 20.0;
+const BLACK_KEY_RISE: f32 = 10.0;
+
+/// What to render, from the command line.
+#[derive(Resource, Clone)]
+pub struct OfflineRender {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    /// Draw a virtual piano under the notes instead of leaving it black.
+    pub piano: bool
+}
+
+struct ActiveRender {
+    sender: Option<Sender<Vec<u8>>>,
+    writer: JoinHandle<Result<u64, String>>,
+    /// The entity reading the frames back from the GPU.
+    readback: Entity,
+    /// How many frames have been rendered for the video, and how many of them have been read back so far. Readback
+    /// takes a few frames, so the last ones arrive after the song has ended.
+    requested: u64,
+    received: u64,
+    /// Whether the song has ended, so no more frames are rendered for the video.
+    ending: bool
+}
+
+/// The render in progress, from when the song starts playing until it ends.
+#[derive(Resource, Default)]
+struct OfflineRenderer {
+    active: Option<ActiveRender>,
+    finished: bool
+}
+
+#[derive(Component)]
+struct VirtualKey {
+    key: u8,
+    /// The material the key has when it isn't being played.
+    rest: Handle<StandardMaterial>
+}
+
+fn write_video(path: PathBuf, width: u32, height: u32, fps: f64, frames: Receiver<Vec<u8>>) -> Result<u64, Box<dyn Error>> {
+    let mut writer = recording::open_video_writer(&path, width, height, fps)?;
+    let mut bgr = Mat::default();
+    let mut written = 0;
+    for frame in frames {
+        if recording::convert_readback_frame(&frame, width, height, imgproc::COLOR_RGBA2BGR, &mut bgr)? {
+            writer.write(&bgr)?;
+            written += 1;
+        }
+    }
+    writer.release()?;
+    Ok(written)
+}
+
+fn receive_rendered_frame(trigger: Trigger<ReadbackComplete>, mut renderer: ResMut<OfflineRenderer>) {
+    let Some(render) = renderer.active.as_mut() else { return };
+    render.received += 1;
+    let Some(sender) = render.sender.as_ref() else { return };
+    // Blocks while the encoder catches up. An error means it stopped, which `finish_render` reports
+    let _ = sender.send(trigger.event().0.clone());
+}
+
+/// Starts the song and the encoder once the song has loaded.
+#[allow(clippy::too_many_arguments)]
+fn start_render(
+    mut commands: Commands,
+    settings: Res<OfflineRender>,
+    song: Res<Song>,
+    progress: Res<StartupProgress>,
+    mut playback: ResMut<SongPlayback>,
+    mut renderer: ResMut<OfflineRenderer>,
+    mut images: ResMut<Assets<Image>>,
+    mut exit: EventWriter<AppExit>
+) {
+    // The song loads in the background
+    if renderer.active.is_some() || renderer.finished || progress.is_running(StartupTask::Song) {
+        return;
+    }
+    if song.path.is_none() || song.notes.is_empty() {
+        eprintln!("Nothing to render: load a song with --song");
+        renderer.finished = true;
+        exit.write(AppExit::error());
+        return;
+    }
+
+    let (width, height) = (settings.width.max(16), settings.height.max(16));
+    let (path, fps) = (settings.path.clone(), settings.fps);
+    let (sender, frames) = crossbeam_channel::bounded(MAX_QUEUED_FRAMES);
+    let writer = thread::Builder::new()
+        .name("offline render".to_string())
+        .spawn(move || write_video(path, width, height, fps, frames).map_err(|err| err.to_string()));
+    let writer = match writer {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("Failed to start rendering: {}", err);
+            renderer.finished = true;
+            exit.write(AppExit::error());
+            return;
+        }
+    };
+
+    // A shot camera is placed by the camera cuts, and has no camera feed drawn behind it
+    let (camera, target) = recording::spawn_offscreen_output(&mut commands, &mut images, width, height);
+    commands.entity(camera).insert((OutputCamera::Spectator, ShotCamera));
+    commands.entity(camera).entry::<Camera>().and_modify(|mut camera| camera.clear_color = ClearColorConfig::Custom(Color::BLACK));
+    let readback = commands.spawn(Readback::texture(target)).observe(receive_rendered_frame).id();
+
+    println!("Rendering '{}' ({:.0} s) to {} at {}x{}, {} fps", song.title, song.duration, settings.path.display(), width, height, fps);
+    playback.restart();
+    renderer.active = Some(ActiveRender { sender: Some(sender), writer, readback, requested: 0, received: 0, ending: false });
+}
+
+/// Finishes the video and exits once the song has ended and every frame rendered for it has been read back.
+fn finish_render(
+    mut commands: Commands,
+    settings: Res<OfflineRender>,
+    playback: Res<SongPlayback>,
+    mut renderer: ResMut<OfflineRenderer>,
+    mut exit: EventWriter<AppExit>
+) {
+    let Some(render) = renderer.active.as_mut() else { return };
+    let encoder_stopped = render.writer.is_finished();
+    if !render.ending {
+        if playback.playing && !encoder_stopped {
+            // The readback is still there when this frame renders, so it's part of the video
+            render.requested += 1;
+            return;
+        }
+        // Stop reading back new frames, but keep the entity so the ones still on their way arrive
+        render.ending = true;
+        commands.entity(render.readback).remove::<Readback>();
+    }
+    if render.received < render.requested && !encoder_stopped {
+        return;
+    }
+    let Some(mut render) = renderer.active.take() else { return };
+    renderer.finished = true;
+
+    // Dropping the sender lets the encoder finish the file
+    render.sender.take();
+    match render.writer.join() {
+        Ok(Ok(frames)) => {
+            println!("Rendered {} frames to {}", frames, settings.path.display());
+            exit.write(AppExit::Success);
+        }
+        Ok(Err(err)) => {
+            eprintln!("Rendering {} failed: {}", settings.path.display(), err);
+            exit.write(AppExit::error());
+        }
+        Err(_) => {
+            eprintln!("The encoder for {} crashed", settings.path.display());
+            exit.write(AppExit::error());
+        }
+    }
+}
+
+/// Nothing is tracked while rendering, so content mustn't fade out for lost tracking.
+fn keep_content_visible(mut tracking: ResMut<TrackingSettings>) {
+    if tracking.fade_when_lost {
+        tracking.fade_when_lost = false;
+    }
+}
+
+fn spawn_virtual_piano(
+    mut commands: Commands,
+    keyboard_layout: Res<KeyboardLayout>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    existing: Query<Entity, With<VirtualKey>>
+) {
+    if !keyboard_layout.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let white = materials.add(StandardMaterial { base_color: Color::srgb(0.92, 0.92, 0.9), unlit: true, ..Default::default() });
+    let black = materials.add(StandardMaterial { base_color: Color::srgb(0.08, 0.08, 0.08), unlit: true, ..Default::default() });
+    for key in keyboard_layout.keys() {
+        let (width, length) = keyboard_layout.key_size(key);
+        // The key tops sit just under the highlights, white keys at zero and black keys raised above them
+        let (top, rest) = if is_black_key(key) { (BLACK_KEY_RISE, &black) } else { (0.0, &white) };
+        commands.spawn((
+            Mesh3d(meshes.add(Cuboid::new(width * 0.94, KEY_HEIGHT, length))),
+            MeshMaterial3d(rest.clone()),
+            Transform::from_translation(keyboard_layout.key_center(key).with_y(top - KEY_HEIGHT / 2.0)),
+            VirtualKey { key, rest: rest.clone() },
+            ChildOf(*root)
+        ));
+    }
+}
+
+/// Lights the virtual piano's keys while the song plays them, in the colors the waterfall gives their notes.
+fn play_virtual_piano(
+    bus: Res<NoteBus>,
+    theme: Res<Theme>,
+    hands: Res<HandSplitSettings>,
+    palette: Res<KeyPalette>,
+    mut theme_materials: ResMut<ThemeMaterials>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut keys: Query<(&VirtualKey, &mut MeshMaterial3d<StandardMaterial>)>
+) {
+    for event in bus.from_source(NoteSource::Song) {
+        let (played, material) = match event.kind {
+            MidiEventKind::NoteOn { key, .. } => {
+                let color = if hands.enabled {
+                    hands.color(hands.hand(key, event.channel, None))
+                } else {
+                    theme.note_color(key, event.channel, None).unwrap_or(palette.pressed)
+                };
+                (key, Some(theme_materials.get(&mut materials, color)))
+            }
+            MidiEventKind::NoteOff { key } => (key, None),
+            _ => continue
+        };
+        for (virtual_key, mut key_material) in keys.iter_mut().filter(|(virtual_key, _)| virtual_key.key == played) {
+            key_material.0 = material.clone().unwrap_or_else(|| virtual_key.rest.clone());
+        }
+    }
+}
+
+pub struct OfflineRenderPlugin;
+
+impl Plugin for OfflineRenderPlugin {
+    fn build(&self, app: &mut App) {
+        let Some(settings) = app.world().get_resource::<OfflineRender>().cloned() else { return };
+        let fps = settings.fps.clamp(1.0, 240.0);
+
+        app
+            .insert_resource(OfflineRender { fps, ..settings.clone() })
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(1.0 / fps)))
+            .init_resource::<OfflineRenderer>()
+            .add_systems(Update, (keep_content_visible, (start_render, finish_render).chain().after(playback::advance_playback)));
+        if settings.piano {
+            app.add_systems(Update, (
+                spawn_virtual_piano,
+                play_virtual_piano.after(playback::publish_song_notes).after(theme::update_theme)
+            ));
+        }
+    }
+}
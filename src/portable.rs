@@ -0,0 +1,42 @@
+//! Portable mode, started with `--portable`, for running the visualizer off a USB stick at different pianos.
+//!
+//! Everything the app reads and writes (config.toml, fiducial layouts, the session recovery file, recordings,
+//! snapshots, exports, downloads, and the song and theme libraries) uses paths relative to the working directory,
+//! so portable mode makes the executable's directory the working directory before anything is loaded. Bevy already
+//! loads `assets/` from next to the executable outside of `cargo run`.
+
+use std::{io, path::{self, Path, PathBuf}};
+
+/// The directory the executable is in, following symlinks so a link on the desktop still finds the stick.
+fn executable_dir() -> io::Result<PathBuf> {
+    let executable = std::env::current_exe()?.canonicalize()?;
+    executable.parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the executable has no parent directory"))
+}
+
+/// Makes a path given on the command line absolute, so it still points at the same file after portable mode changes
+/// the working directory.
+pub fn resolve_argument(path: &mut PathBuf) {
+    if let Ok(absolute) = path::absolute(path.as_path()) {
+        *path = absolute;
+    }
+}
+
+/// Like `resolve_argument`, for arguments that may be a URL or a device index instead of a path. Only existing files
+/// are changed.
+pub fn resolve_argument_string(argument: &mut String) {
+    if !Path::new(argument.as_str()).exists() {
+        return;
+    }
+    if let Ok(absolute) = path::absolute(argument.as_str()) {
+        *argument = absolute.to_string_lossy().to_string();
+    }
+}
+
+/// Moves the working directory next to the executable, and returns it.
+pub fn enter_portable_mode() -> io::Result<PathBuf> {
+    let dir = executable_dir()?;
+    std::env::set_current_dir(&dir)?;
+    Ok(dir)
+}
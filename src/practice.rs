@@ -0,0 +1,427 @@
+//! Practice mode: compares live MIDI input against the loaded song and judges every expected note.
+//! If tracking is lost mid-run (say the camera gets bumped), the run pauses until the markers are found again,
+//! then picks up from the measure before the one that was interrupted.
+//! With `PracticeSettings::wait_for_input`, playback instead waits at each chord until the player has played all of its
+//! notes, so the song goes at the player's pace and nothing is missed for being late.
+//! See `summary` for the score shown when a run ends, and `history` for the record kept of finished runs.
+
+pub mod history;
+pub mod summary;
+
+use bevy::{app::{App, Plugin, Startup, Update}, color::Color, ecs::{change_detection::DetectChanges, component::Component, event::{Event, EventReader, EventWriter}, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut}}, render::view::Visibility, text::{JustifyText, TextColor, TextFont, TextLayout}, ui::{widget::Text, BackgroundColor, Node, PositionType, UiRect, Val}, utils::default};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{command::{self, AppCommand}, midi::{bus::{NoteBus, NoteSource}, latency::LatencyCompensation, MidiEventKind}, song::{playback::{self, SongPlayback}, Song}, video::tracking::TrackingState};
+
+const PROMPT_FONT_SIZE: f32 = 28.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Judgment {
+    /// Played within the hit window.
+    Hit,
+    /// Played too early, but within the acceptance window.
+    Early,
+    /// Played too late, but within the acceptance window.
+    Late,
+    /// Never played within the acceptance window.
+    Miss,
+    /// Played when no matching note was expected.
+    Extra
+}
+
+/// Emitted once for every expected note, and for every played note that didn't match one.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct NoteJudgment {
+    /// The index into `Song::notes`, or `None` for extra notes.
+    pub note_index: Option<usize>,
+    pub key: u8,
+    pub judgment: Judgment,
+    /// How far the played note was from the expected time in seconds. Negative is early.
+    pub offset: f64
+}
+
+/// Emitted when a run reaches the end of the song, rather than being stopped.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PracticeFinished;
+
+/// The judgments so far in the current run.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PracticeScore {
+    pub hits: u32,
+    pub early: u32,
+    pub late: u32,
+    pub misses: u32,
+    pub extras: u32
+}
+
+impl PracticeScore {
+    fn count(&mut self, judgment: Judgment) {
+        let count = match judgment {
+            Judgment::Hit => &mut self.hits,
+            Judgment::Early => &mut self.early,
+            Judgment::Late => &mut self.late,
+            Judgment::Miss => &mut self.misses,
+            Judgment::Extra => &mut self.extras
+        };
+        *count += 1;
+    }
+
+    pub fn judged(&self) -> u32 {
+        self.hits + self.early + self.late + self.misses + self.extras
+    }
+
+    /// The share of the notes played right, from 0 to 1, with early and late notes counting half and extra notes
+    /// counting against it. `None` before anything has been judged.
+    pub fn accuracy(&self) -> Option<f32> {
+        let judged = self.judged();
+        (judged > 0).then(|| (self.hits as f32 + (self.early + self.late) as f32 * 0.5) / judged as f32)
+    }
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct PracticeSettings {
+    /// Notes played within this many seconds of the expected time count as hits.
+    pub hit_window: f64,
+    /// Notes played within this many seconds count as early or late instead of missed.
+    pub acceptance_window: f64,
+    /// Pause the run while tracking is lost, instead of judging notes the player can't see.
+    pub pause_on_tracking_loss: bool,
+    /// Wait at each note until it's played instead of moving on and counting it missed.
+    pub wait_for_input: bool
+}
+
+impl Default for PracticeSettings {
+    fn default() -> Self {
+        Self {
+            hit_window: 0.08,
+            acceptance_window: 0.25,
+            pause_on_tracking_loss: true,
+            wait_for_input: false
+        }
+    }
+}
+
+struct ExpectedNote {
+    note_index: usize,
+    key: u8,
+    time: f64
+}
+
+#[derive(Resource, Default)]
+pub struct PracticeSession {
+    pub active: bool,
+    /// The index of the next song note that hasn't entered the acceptance window yet.
+    next_note: usize,
+    /// Notes inside the acceptance window that haven't been played yet.
+    pending: Vec<ExpectedNote>,
+    /// Where to resume from once tracking comes back, if the run is paused for tracking loss.
+    paused_for_tracking: Option<f64>,
+    /// Notes aren't judged before this song position. After resuming, the player gets a run-up through
+    /// the part they've already been judged on.
+    judging_from: f64,
+    /// The playback position last frame, to notice playback moving backwards.
+    last_position: f64
+}
+
+impl PracticeSession {
+    pub fn start(&mut self, playback: &mut SongPlayback) {
+        self.active = true;
+        self.next_note = 0;
+        self.pending.clear();
+        self.paused_for_tracking = None;
+        self.judging_from = 0.0;
+        self.last_position = 0.0;
+        playback.restart();
+    }
+
+    pub fn stop(&mut self) {
+        self.active = false;
+        self.pending.clear();
+        self.paused_for_tracking = None;
+    }
+
+    pub fn is_paused_for_tracking(&self) -> bool {
+        self.paused_for_tracking.is_some()
+    }
+
+    /// Rewinds the expected note queue to the given song position, e.g. after seeking or looping.
+    pub fn seek(&mut self, song: &Song, position: f64, settings: &PracticeSettings) {
+        self.pending.clear();
+        self.next_note = song.notes.partition_point(|note| note.start < position - settings.acceptance_window);
+        self.judging_from = position;
+        self.last_position = position;
+    }
+}
+
+fn toggle_practice(
+    mut commands: EventReader<AppCommand>,
+    mut session: ResMut<PracticeSession>,
+    mut playback: ResMut<SongPlayback>,
+    mut score: ResMut<PracticeScore>
+) {
+    for command in commands.read() {
+        if *command != AppCommand::TogglePractice {
+            continue;
+        }
+        if session.active {
+            session.stop();
+        } else {
+            session.start(&mut playback);
+            *score = PracticeScore::default();
+        }
+    }
+}
+
+fn tally_score(
+    mut judgments: EventReader<NoteJudgment>,
+    mut score: ResMut<PracticeScore>
+) {
+    for judgment in judgments.read() {
+        score.count(judgment.judgment);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn judge_notes(
+    song: Res<Song>,
+    mut playback: ResMut<SongPlayback>,
+    settings: Res<PracticeSettings>,
+    latency: Res<LatencyCompensation>,
+    mut session: ResMut<PracticeSession>,
+    bus: Res<NoteBus>,
+    mut judgments: EventWriter<NoteJudgment>,
+    mut finished: EventWriter<PracticeFinished>
+) {
+    if song.is_changed() {
+        session.stop();
+    }
+    if !session.active {
+        if playback.hold_at.is_some() {
+            playback.hold_at = None;
+        }
+        return;
+    }
+
+    let session = session.as_mut();
+    let position = playback.position;
+    // Playback going back, like restarting or looping, plays those notes again, so they're expected again
+    if position < session.last_position {
+        session.seek(&song, position, &settings);
+    }
+    session.last_position = position;
+    if position < session.judging_from {
+        return;
+    }
+
+    // Queue notes that are now close enough to be played. While looping, only the loop's notes are played
+    let region = song.active_loop();
+    while let Some(note) = song.notes.get(session.next_note) {
+        if note.start - settings.acceptance_window > position {
+            break;
+        }
+        if region.is_none_or(|region| region.contains(note.start)) {
+            session.pending.push(ExpectedNote { note_index: session.next_note, key: note.key, time: note.start });
+        }
+        session.next_note += 1;
+    }
+
+    for event in bus.from_source(NoteSource::Live) {
+        let MidiEventKind::NoteOn { key, .. } = event.kind else { continue };
+
+        // Match the closest pending note on the same key
+        let closest = session.pending.iter().enumerate()
+            .filter(|(_, expected)| expected.key == key)
+            .min_by(|(_, a), (_, b)| (a.time - position).abs().total_cmp(&(b.time - position).abs()))
+            .map(|(index, _)| index);
+
+        let Some(index) = closest else {
+            judgments.write(NoteJudgment { note_index: None, key, judgment: Judgment::Extra, offset: 0.0 });
+            continue;
+        };
+
+        let expected = session.pending.remove(index);
+        // Judge against when the key has to be pressed for the note to sound on time. Notes playback waited at have no
+        // timing to judge.
+        let waited = settings.wait_for_input && position >= expected.time;
+        let offset = if waited { 0.0 } else { position - latency.press_time(expected.time) };
+        let judgment = if offset.abs() <= settings.hit_window {
+            Judgment::Hit
+        } else if offset < 0.0 {
+            Judgment::Early
+        } else {
+            Judgment::Late
+        };
+        judgments.write(NoteJudgment { note_index: Some(expected.note_index), key, judgment, offset });
+    }
+
+    // Anything that has left the acceptance window was missed, unless playback waits for it
+    session.pending.retain(|expected| {
+        if settings.wait_for_input || position - expected.time <= settings.acceptance_window {
+            return true;
+        }
+        judgments.write(NoteJudgment { note_index: Some(expected.note_index), key: expected.key, judgment: Judgment::Miss, offset: 0.0 });
+        false
+    });
+
+    // Waiting at the earliest unplayed note holds at each chord until all of it has been played; the rest of the
+    // chord is already queued, so it's matched when played together
+    let hold = session.pending.iter().filter(|_| settings.wait_for_input).map(|expected| expected.time).min_by(f64::total_cmp);
+    if playback.hold_at != hold {
+        playback.hold_at = hold;
+    }
+
+    if !playback.playing && session.pending.is_empty() && session.next_note >= song.notes.len() {
+        session.active = false;
+        finished.write(PracticeFinished);
+    }
+}
+
+/// Pauses the run when tracking is lost, and resumes it from the previous measure once tracking is back.
+fn pause_on_tracking_loss(
+    song: Res<Song>,
+    settings: Res<PracticeSettings>,
+    tracking_state: Res<TrackingState>,
+    mut session: ResMut<PracticeSession>,
+    mut playback: ResMut<SongPlayback>
+) {
+    if !session.active {
+        return;
+    }
+
+    match session.paused_for_tracking {
+        None => {
+            if settings.pause_on_tracking_loss && playback.playing && *tracking_state == TrackingState::Lost {
+                let measure = song.tempo_map.measure_at_seconds(playback.position);
+                let resume_from = song.tempo_map.measure_to_seconds(measure.saturating_sub(1).max(1));
+                println!("Tracking lost; pausing the run until the markers are visible again");
+                playback.playing = false;
+                session.judging_from = playback.position;
+                session.paused_for_tracking = Some(resume_from);
+            }
+        }
+        // Resuming by hand keeps playing from where the run stopped
+        Some(_) if playback.playing => session.paused_for_tracking = None,
+        Some(resume_from) => {
+            if *tracking_state == TrackingState::Tracking {
+                println!("Tracking reacquired; resuming the run");
+                playback.position = resume_from;
+                // The run-up is already judged up to `judging_from`, so going back for it isn't a seek
+                session.last_position = resume_from;
+                playback.playing = true;
+                session.paused_for_tracking = None;
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+struct TrackingPausePrompt;
+
+fn setup(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Percent(40.0),
+            left: Val::Percent(20.0),
+            right: Val::Percent(20.0),
+            padding: UiRect::all(Val::Px(12.0)),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.75)),
+        Text::new("Tracking lost. Point the camera at the markers to resume."),
+        TextFont {
+            font_size: PROMPT_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        TextLayout::new_with_justify(JustifyText::Center),
+        Visibility::Hidden,
+        TrackingPausePrompt
+    ));
+}
+
+fn update_tracking_pause_prompt(
+    session: Res<PracticeSession>,
+    mut prompts: Query<&mut Visibility, With<TrackingPausePrompt>>
+) {
+    if !session.is_changed() {
+        return;
+    }
+    let visibility = if session.is_paused_for_tracking() { Visibility::Inherited } else { Visibility::Hidden };
+    for mut prompt in prompts.iter_mut() {
+        *prompt = visibility;
+    }
+}
+
+pub struct PracticePlugin;
+
+impl Plugin for PracticePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<PracticeSettings>()
+            .init_resource::<PracticeSession>()
+            .init_resource::<PracticeScore>()
+            .add_event::<NoteJudgment>()
+            .add_event::<PracticeFinished>()
+            .add_plugins(history::PracticeHistoryPlugin)
+            .add_systems(Startup, setup)
+            .add_systems(Update, (toggle_practice, judge_notes, tally_score, pause_on_tracking_loss, update_tracking_pause_prompt).chain().after(command::run_app_commands).after(playback::advance_playback));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::change_detection::Mut;
+
+    use crate::song::{LoopRegion, SongNote};
+
+    use super::*;
+
+    fn note(key: u8, start: f64) -> SongNote {
+        SongNote { key, velocity: 64, channel: 0, track: 0, start, duration: 0.25, hand: None, finger: None }
+    }
+
+    /// Moves playback to `position`, plays `key` there if given, and judges the frame.
+    fn play(app: &mut App, position: f64, key: Option<u8>) {
+        app.world_mut().resource_mut::<SongPlayback>().position = position;
+        let mut bus = NoteBus::default();
+        if let Some(key) = key {
+            bus.publish(position, 0, NoteSource::Live, 0, MidiEventKind::NoteOn { key, velocity: 64 });
+        }
+        app.insert_resource(bus);
+        app.update();
+    }
+
+    #[test]
+    fn every_loop_pass_is_judged() {
+        let mut song = Song { duration: 4.0, ..Default::default() };
+        song.notes = vec![note(60, 0.5), note(62, 1.5), note(64, 2.5)];
+        song.loop_region = Some(LoopRegion { start: 1.0, end: 3.0 });
+
+        let mut app = App::new();
+        app
+            .insert_resource(song)
+            .init_resource::<SongPlayback>()
+            .init_resource::<PracticeSettings>()
+            .init_resource::<PracticeSession>()
+            .init_resource::<PracticeScore>()
+            .init_resource::<LatencyCompensation>()
+            .init_resource::<NoteBus>()
+            .add_event::<NoteJudgment>()
+            .add_event::<PracticeFinished>()
+            .add_systems(Update, (judge_notes, tally_score).chain());
+        // The first frame sees a new song, which stops any run, so the run starts after it
+        app.update();
+        app.world_mut().resource_scope(|world, mut session: Mut<PracticeSession>| session.start(&mut world.resource_mut::<SongPlayback>()));
+
+        // Playing jumps straight to the loop, so the note before it isn't expected
+        for _ in 0..2 {
+            play(&mut app, 1.0, None);
+            play(&mut app, 1.5, Some(62));
+            play(&mut app, 2.5, Some(64));
+            play(&mut app, 2.95, None);
+        }
+        assert_eq!(*app.world().resource::<PracticeScore>(), PracticeScore { hits: 4, ..Default::default() });
+    }
+}
@@ -0,0 +1,356 @@
+//! Practice history: every finished run is saved to `HISTORY_PATH` with its song, date, accuracy, speed, and how long
+//! it took, so progress can be followed over weeks. The history window (H, or the command palette) shows a song's runs
+//! day by day, with a chart of its accuracy. Dates are in local time.
+
+use std::{error::Error, fs, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{app::{App, Plugin, Startup, Update}, ecs::{event::EventReader, resource::Resource, schedule::IntoScheduleConfigs, system::{Local, Res, ResMut}}, time::Time};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{command::{self, AppCommand}, song::{playback::SongPlayback, Song}};
+
+use super::{PracticeFinished, PracticeScore, PracticeSession};
+
+static HISTORY_PATH: &str = "practice_history.json";
+const CHART_HEIGHT: f32 = 120.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PracticeRecord {
+    /// The song's path, or its title if it wasn't loaded from a file. Runs of the same song share it.
+    pub song: String,
+    pub title: String,
+    /// When the run finished, in seconds since the Unix epoch.
+    pub finished_at: u64,
+    pub score: PracticeScore,
+    /// From 0 to 1. See `PracticeScore::accuracy`.
+    pub accuracy: f32,
+    /// The playback speed the run was played at, where 1 is the song's own tempo.
+    pub speed: f64,
+    /// How long the run took, in seconds.
+    pub duration: f64
+}
+
+/// A song's runs on one day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PracticeDay {
+    /// Like "2025-03-14".
+    pub date: String,
+    pub runs: u32,
+    pub best_accuracy: f32,
+    pub average_accuracy: f32,
+    pub fastest_speed: f64,
+    /// The time spent on the runs, in seconds.
+    pub practice_time: f64
+}
+
+#[derive(Resource, Default)]
+pub struct PracticeHistory {
+    pub records: Vec<PracticeRecord>
+}
+
+impl PracticeHistory {
+    fn load() -> Result<PracticeHistory, Box<dyn Error>> {
+        if !Path::new(HISTORY_PATH).exists() {
+            return Ok(PracticeHistory::default());
+        }
+        Ok(PracticeHistory { records: serde_json::from_str(&fs::read_to_string(HISTORY_PATH)?)? })
+    }
+
+    /// Writes to a temporary file first, so losing power mid-write doesn't lose the history.
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let temporary_path = Path::new(HISTORY_PATH).with_extension("json.tmp");
+        fs::write(&temporary_path, serde_json::to_string_pretty(&self.records)?)?;
+        fs::rename(&temporary_path, HISTORY_PATH)?;
+        Ok(())
+    }
+
+    /// The songs with saved runs as (song, title), most recently practiced first.
+    pub fn songs(&self) -> Vec<(String, String)> {
+        let mut songs: Vec<(String, String)> = Vec::new();
+        for record in self.records.iter().rev() {
+            if !songs.iter().any(|(song, _)| *song == record.song) {
+                songs.push((record.song.clone(), record.title.clone()));
+            }
+        }
+        songs
+    }
+
+    /// The song's runs grouped by local day, oldest first.
+    pub fn days(&self, song: &str) -> Vec<PracticeDay> {
+        self.days_with_offset(song, local_utc_offset)
+    }
+
+    /// The song's runs grouped by day, in the time zone given by `utc_offset` for each run's timestamp.
+    fn days_with_offset(&self, song: &str, utc_offset: impl Fn(u64) -> i64) -> Vec<PracticeDay> {
+        let mut days: Vec<PracticeDay> = Vec::new();
+        for record in self.records.iter().filter(|record| record.song == song) {
+            let date = format_date(record.finished_at as i64 + utc_offset(record.finished_at));
+            let day = match days.iter_mut().find(|day| day.date == date) {
+                Some(day) => day,
+                None => {
+                    days.push(PracticeDay { date, runs: 0, best_accuracy: 0.0, average_accuracy: 0.0, fastest_speed: 0.0, practice_time: 0.0 });
+                    days.last_mut().unwrap()
+                }
+            };
+            // Keep a running average, so it doesn't need the day's runs kept separately
+            day.average_accuracy = (day.average_accuracy * day.runs as f32 + record.accuracy) / (day.runs + 1) as f32;
+            day.runs += 1;
+            day.best_accuracy = day.best_accuracy.max(record.accuracy);
+            day.fastest_speed = day.fastest_speed.max(record.speed);
+            day.practice_time += record.duration;
+        }
+        days.sort_by(|a, b| a.date.cmp(&b.date));
+        days
+    }
+}
+
+/// The local time zone's offset from UTC at a Unix timestamp, in seconds. It can differ between timestamps because of
+/// daylight saving time.
+fn local_utc_offset(unix_seconds: u64) -> i64 {
+    DateTime::from_timestamp(unix_seconds as i64, 0)
+        .map_or(0, |time| i64::from(time.with_timezone(&chrono::Local).offset().local_minus_utc()))
+}
+
+/// The date of a timestamp in seconds since 1970-01-01 00:00, like "2025-03-14". The timestamp is in whichever time
+/// zone the date should be in.
+fn format_date(seconds: i64) -> String {
+    // Howard Hinnant's days-to-civil conversion, counting from 0000-03-01 so leap days fall at the end of the year
+    let days = seconds.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[derive(Resource, Default)]
+struct HistoryWindow {
+    open: bool,
+    /// The song shown, from `PracticeRecord::song`.
+    selected: Option<String>
+}
+
+fn setup(mut history: ResMut<PracticeHistory>) {
+    *history = PracticeHistory::load().unwrap_or_else(|err| {
+        eprintln!("Failed to load the practice history from {}: {}", HISTORY_PATH, err);
+        PracticeHistory::default()
+    });
+}
+
+/// Saves each finished run. A run's time is counted from when the session became active.
+#[allow(clippy::too_many_arguments)]
+fn record_finished_runs(
+    time: Res<Time>,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    session: Res<PracticeSession>,
+    score: Res<PracticeScore>,
+    mut finished: EventReader<PracticeFinished>,
+    mut history: ResMut<PracticeHistory>,
+    mut started_at: Local<Option<f64>>
+) {
+    let now = time.elapsed_secs_f64();
+    if session.active && started_at.is_none() {
+        *started_at = Some(now);
+    }
+
+    for _ in finished.read() {
+        let started = started_at.take().unwrap_or(now);
+        // A run without any notes judged says nothing about progress
+        let Some(accuracy) = score.accuracy() else { continue };
+        history.records.push(PracticeRecord {
+            song: song.path.as_ref().map_or(song.title.clone(), |path| path.display().to_string()),
+            title: song.title.clone(),
+            finished_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default(),
+            score: *score,
+            accuracy,
+            speed: playback.speed,
+            duration: now - started
+        });
+        if let Err(err) = history.save() {
+            eprintln!("Failed to save the practice history to {}: {}", HISTORY_PATH, err);
+        }
+    }
+
+    if !session.active {
+        *started_at = None;
+    }
+}
+
+fn toggle_history(
+    mut commands: EventReader<AppCommand>,
+    song: Res<Song>,
+    history: Res<PracticeHistory>,
+    mut window: ResMut<HistoryWindow>
+) {
+    for command in commands.read() {
+        if *command == AppCommand::TogglePracticeHistory {
+            window.open = !window.open;
+            // Show the current song if it's been practiced, or else the one practiced last
+            let songs = history.songs();
+            let current = song.path.as_ref().map_or(song.title.clone(), |path| path.display().to_string());
+            window.selected = songs.iter().find(|(song, _)| *song == current).or(songs.first()).map(|(song, _)| song.clone());
+        }
+    }
+}
+
+/// A line for the best and the average accuracy of each day, from 0% at the bottom to 100% at the top.
+fn draw_accuracy_chart(ui: &mut egui::Ui, days: &[PracticeDay]) {
+    let size = egui::vec2(ui.available_width().max(300.0), CHART_HEIGHT);
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 2.0, egui::Color32::from_gray(24));
+    for percent in [0.25, 0.5, 0.75] {
+        let y = rect.bottom() - percent * rect.height();
+        painter.line_segment([egui::pos2(rect.left(), y), egui::pos2(rect.right(), y)], egui::Stroke::new(1.0, egui::Color32::from_gray(45)));
+    }
+
+    let point = |index: usize, accuracy: f32| {
+        let x = if days.len() > 1 { rect.left() + index as f32 / (days.len() - 1) as f32 * rect.width() } else { rect.center().x };
+        egui::pos2(x, rect.bottom() - accuracy.clamp(0.0, 1.0) * rect.height())
+    };
+    let average: Vec<egui::Pos2> = days.iter().enumerate().map(|(index, day)| point(index, day.average_accuracy)).collect();
+    let best: Vec<egui::Pos2> = days.iter().enumerate().map(|(index, day)| point(index, day.best_accuracy)).collect();
+    painter.add(egui::Shape::line(average, egui::Stroke::new(1.5, egui::Color32::from_rgb(60, 120, 80))));
+    painter.add(egui::Shape::line(best.clone(), egui::Stroke::new(2.0, egui::Color32::from_rgb(77, 230, 128))));
+    for position in best {
+        painter.circle_filled(position, 3.0, egui::Color32::from_rgb(77, 230, 128));
+    }
+}
+
+fn draw_history_window(
+    mut contexts: EguiContexts,
+    history: Res<PracticeHistory>,
+    mut window: ResMut<HistoryWindow>
+) {
+    if !window.open {
+        return;
+    }
+    let window = window.as_mut();
+    let mut open = true;
+
+    egui::Window::new("Practice history")
+        .open(&mut open)
+        .default_width(520.0)
+        .show(contexts.ctx_mut(), |ui| {
+            let songs = history.songs();
+            if songs.is_empty() {
+                ui.label("No practice runs have been finished yet. Press P to start one.");
+                return;
+            }
+
+            let selected_title = songs.iter().find(|(song, _)| window.selected.as_ref() == Some(song)).map_or("", |(_, title)| title.as_str());
+            egui::ComboBox::from_label("Song")
+                .selected_text(selected_title)
+                .show_ui(ui, |ui| {
+                    for (song, title) in &songs {
+                        ui.selectable_value(&mut window.selected, Some(song.clone()), title);
+                    }
+                });
+            let Some(selected) = &window.selected else { return };
+            let days = history.days(selected);
+
+            ui.label("Best (bright) and average accuracy of each day");
+            draw_accuracy_chart(ui, &days);
+
+            egui::ScrollArea::vertical().max_height(240.0).show(ui, |ui| {
+                egui::Grid::new("history_days").striped(true).show(ui, |ui| {
+                    for heading in ["Date", "Runs", "Best", "Average", "Fastest speed", "Time practiced"] {
+                        ui.strong(heading);
+                    }
+                    ui.end_row();
+                    for day in days.iter().rev() {
+                        ui.label(&day.date);
+                        ui.label(day.runs.to_string());
+                        ui.label(format!("{:.0}%", day.best_accuracy * 100.0));
+                        ui.label(format!("{:.0}%", day.average_accuracy * 100.0));
+                        ui.label(format!("{:.2}x", day.fastest_speed));
+                        ui.label(format_duration(day.practice_time));
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+
+    window.open = open;
+}
+
+/// Loads the history and records finished runs in it.
+pub struct PracticeHistoryPlugin;
+
+impl Plugin for PracticeHistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<PracticeHistory>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, record_finished_runs.after(super::tally_score));
+    }
+}
+
+/// The history window, which needs egui and `PracticeHistoryPlugin`.
+pub struct PracticeHistoryWindowPlugin;
+
+impl Plugin for PracticeHistoryWindowPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+
+        app
+            .init_resource::<HistoryWindow>()
+            .add_systems(Update, (toggle_history, draw_history_window).chain().after(command::run_app_commands));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(finished_at: u64, accuracy: f32, speed: f64) -> PracticeRecord {
+        PracticeRecord {
+            song: "songs/etude.mid".to_string(),
+            title: "Etude".to_string(),
+            finished_at,
+            score: PracticeScore::default(),
+            accuracy,
+            speed,
+            duration: 60.0
+        }
+    }
+
+    #[test]
+    fn runs_are_grouped_by_day() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951_782_400), "2000-02-29");
+        assert_eq!(format_date(1_700_000_000), "2023-11-14");
+
+        let mut history = PracticeHistory::default();
+        history.records.push(record(1_700_000_000, 0.5, 0.75));
+        history.records.push(record(1_700_003_600, 0.9, 0.5));
+        history.records.push(record(1_700_100_000, 0.8, 1.0));
+        history.records.push(PracticeRecord { song: "songs/other.mid".to_string(), ..record(1_700_000_000, 1.0, 1.0) });
+
+        let days = history.days_with_offset("songs/etude.mid", |_| 0);
+        assert_eq!(days.len(), 2);
+        assert_eq!((days[0].date.as_str(), days[0].runs, days[0].best_accuracy, days[0].fastest_speed), ("2023-11-14", 2, 0.9, 0.75));
+        assert!((days[0].average_accuracy - 0.7).abs() < 1e-6);
+        assert_eq!(days[0].practice_time, 120.0);
+        assert_eq!(days[1].date, "2023-11-16");
+        assert_eq!(history.songs()[0].0, "songs/other.mid");
+
+        // 2023-11-14 23:13 UTC is already the next day at UTC+1
+        let days = history.days_with_offset("songs/etude.mid", |_| 3600);
+        assert_eq!(days.iter().map(|day| (day.date.as_str(), day.runs)).collect::<Vec<_>>(), [("2023-11-14", 1), ("2023-11-15", 1), ("2023-11-16", 1)]);
+    }
+}
@@ -0,0 +1,260 @@
+//! The end-of-song summary: when a practice run reaches the end of the song, its judgments are totaled into an
+//! accuracy, the longest streak of notes played without a miss or an extra note, and the average timing, broken down
+//! by section, along with the measures that went worst. Sections start at the song's bookmarks, or every
+//! `SECTION_MEASURES` measures in songs without any.
+
+use std::collections::BTreeMap;
+
+use bevy::{app::{App, Plugin, Update}, ecs::{event::EventReader, resource::Resource, schedule::IntoScheduleConfigs, system::{Local, Res, ResMut}}};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use crate::song::{playback::SongPlayback, Song};
+
+use super::{Judgment, NoteJudgment, PracticeFinished, PracticeScore, PracticeSession};
+
+/// How many measures a section is in songs without bookmarks.
+const SECTION_MEASURES: u32 = 8;
+/// How many of the worst measures the summary lists.
+const WORST_MEASURES: usize = 3;
+
+/// A judged note, at the song time it was expected, or played if it was extra.
+#[derive(Debug, Clone, Copy)]
+struct JudgedNote {
+    time: f64,
+    judgment: Judgment,
+    offset: f64
+}
+
+/// The judgments of the current run, kept for the summary.
+#[derive(Resource, Default)]
+struct RunJudgments(Vec<JudgedNote>);
+
+#[derive(Debug, Clone)]
+pub struct SectionScore {
+    pub label: String,
+    pub score: PracticeScore
+}
+
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    pub score: PracticeScore,
+    /// The most notes in a row played without a miss or an extra note.
+    pub longest_streak: u32,
+    /// How far from the expected time the played notes were on average, in seconds. Negative is early.
+    pub mean_offset: Option<f64>,
+    /// The sections with judged notes, in song order.
+    pub sections: Vec<SectionScore>,
+    /// The measures played least accurately as (measure, score), worst first. Measures played perfectly aren't listed.
+    pub worst_measures: Vec<(u32, PracticeScore)>
+}
+
+#[derive(Resource, Default)]
+struct PracticeSummary {
+    open: bool,
+    title: String,
+    summary: Option<RunSummary>
+}
+
+/// The label and start time of each section of the song.
+fn section_starts(song: &Song) -> Vec<(String, f64)> {
+    if song.bookmarks.is_empty() {
+        let last = song.tempo_map.measure_at_seconds(song.duration);
+        return (1..=last).step_by(SECTION_MEASURES as usize)
+            .map(|measure| (format!("Measures {}-{}", measure, (measure + SECTION_MEASURES - 1).min(last)), song.tempo_map.measure_to_seconds(measure)))
+            .collect();
+    }
+
+    let mut starts = Vec::new();
+    if song.bookmarks[0].time > 0.0 {
+        starts.push(("Start".to_string(), 0.0));
+    }
+    starts.extend(song.bookmarks.iter().map(|bookmark| (bookmark.label.clone(), bookmark.time)));
+    starts
+}
+
+fn summarize(song: &Song, judged: &[JudgedNote]) -> RunSummary {
+    let mut notes = judged.to_vec();
+    notes.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+    let starts = section_starts(song);
+    let mut score = PracticeScore::default();
+    let mut sections: Vec<PracticeScore> = vec![PracticeScore::default(); starts.len().max(1)];
+    let mut measures: BTreeMap<u32, PracticeScore> = BTreeMap::new();
+    let (mut streak, mut longest_streak) = (0, 0);
+    let (mut offset_total, mut played) = (0.0, 0);
+
+    for note in &notes {
+        score.count(note.judgment);
+        // Extra notes in a count-in come before the first section
+        let section = starts.partition_point(|(_, start)| *start <= note.time).saturating_sub(1);
+        sections[section].count(note.judgment);
+        measures.entry(song.tempo_map.measure_at_seconds(note.time.max(0.0))).or_default().count(note.judgment);
+
+        match note.judgment {
+            Judgment::Hit | Judgment::Early | Judgment::Late => {
+                streak += 1;
+                longest_streak = longest_streak.max(streak);
+                offset_total += note.offset;
+                played += 1;
+            }
+            Judgment::Miss | Judgment::Extra => streak = 0
+        }
+    }
+
+    let mut worst_measures: Vec<(u32, PracticeScore)> = measures.into_iter()
+        .filter(|(_, score)| score.accuracy().is_some_and(|accuracy| accuracy < 1.0))
+        .collect();
+    // Sorting is stable, so measures that went equally badly stay in song order
+    worst_measures.sort_by(|(_, a), (_, b)| a.accuracy().unwrap_or(1.0).total_cmp(&b.accuracy().unwrap_or(1.0)));
+    worst_measures.truncate(WORST_MEASURES);
+
+    RunSummary {
+        score,
+        longest_streak,
+        mean_offset: (played > 0).then(|| offset_total / played as f64),
+        sections: starts.into_iter().zip(sections)
+            .filter(|(_, score)| score.judged() > 0)
+            .map(|((label, _), score)| SectionScore { label, score })
+            .collect(),
+        worst_measures
+    }
+}
+
+fn percent(score: &PracticeScore) -> String {
+    score.accuracy().map_or("-".to_string(), |accuracy| format!("{:.0}%", accuracy * 100.0))
+}
+
+/// Keeps the judgments of the current run, starting over whenever a run starts.
+fn record_judgments(
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    session: Res<PracticeSession>,
+    mut judgments: EventReader<NoteJudgment>,
+    mut run: ResMut<RunJudgments>,
+    mut was_active: Local<bool>
+) {
+    if session.active && !*was_active {
+        run.0.clear();
+    }
+    *was_active = session.active;
+
+    for judgment in judgments.read() {
+        let time = judgment.note_index.and_then(|index| song.notes.get(index)).map_or(playback.position, |note| note.start);
+        run.0.push(JudgedNote { time, judgment: judgment.judgment, offset: judgment.offset });
+    }
+}
+
+fn show_summary(
+    song: Res<Song>,
+    mut finished: EventReader<PracticeFinished>,
+    run: Res<RunJudgments>,
+    mut summary: ResMut<PracticeSummary>
+) {
+    for _ in finished.read() {
+        summary.title = song.title.clone();
+        summary.summary = Some(summarize(&song, &run.0));
+        summary.open = true;
+    }
+}
+
+fn draw_summary_window(
+    mut contexts: EguiContexts,
+    mut summary: ResMut<PracticeSummary>
+) {
+    if !summary.open {
+        return;
+    }
+    let summary = summary.as_mut();
+    let Some(run) = &summary.summary else { return };
+    let mut open = true;
+
+    egui::Window::new("Run summary")
+        .open(&mut open)
+        .default_width(360.0)
+        .show(contexts.ctx_mut(), |ui| {
+            if !summary.title.is_empty() {
+                ui.heading(&summary.title);
+            }
+            ui.label(egui::RichText::new(format!("{} accuracy", percent(&run.score))).size(24.0).strong());
+            let score = run.score;
+            ui.label(format!("{} hit, {} early, {} late, {} missed, {} extra", score.hits, score.early, score.late, score.misses, score.extras));
+            ui.label(format!("Longest streak: {} notes", run.longest_streak));
+            if let Some(offset) = run.mean_offset {
+                let direction = if offset < 0.0 { "early" } else { "late" };
+                ui.label(format!("Timing: {:.0} ms {} on average", offset.abs() * 1000.0, direction));
+            }
+
+            ui.separator();
+            egui::Grid::new("summary_sections").striped(true).show(ui, |ui| {
+                ui.strong("Section");
+                ui.strong("Accuracy");
+                ui.strong("Missed");
+                ui.end_row();
+                for section in &run.sections {
+                    ui.label(&section.label);
+                    ui.label(percent(&section.score));
+                    ui.label(section.score.misses.to_string());
+                    ui.end_row();
+                }
+            });
+
+            if !run.worst_measures.is_empty() {
+                ui.separator();
+                ui.label("Measures to practice:");
+                for (measure, score) in &run.worst_measures {
+                    ui.label(format!("Measure {}: {}, {} missed", measure, percent(score), score.misses));
+                }
+            }
+        });
+
+    summary.open = open;
+}
+
+pub struct PracticeSummaryPlugin;
+
+impl Plugin for PracticeSummaryPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+
+        app
+            .init_resource::<RunJudgments>()
+            .init_resource::<PracticeSummary>()
+            .add_systems(Update, (record_judgments, show_summary, draw_summary_window).chain().after(super::judge_notes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::Bookmark;
+
+    fn judged(time: f64, judgment: Judgment, offset: f64) -> JudgedNote {
+        JudgedNote { time, judgment, offset }
+    }
+
+    #[test]
+    fn runs_are_broken_down_by_section_and_measure() {
+        // At the default 120 bpm in 4/4, a measure is two seconds
+        let mut song = Song { duration: 8.0, ..Default::default() };
+        song.bookmarks.push(Bookmark { time: 4.0, label: "Chorus".to_string() });
+        let run = summarize(&song, &[
+            judged(0.0, Judgment::Hit, 0.0),
+            judged(1.0, Judgment::Early, -0.1),
+            judged(2.5, Judgment::Miss, 0.0),
+            judged(0.5, Judgment::Hit, 0.02),
+            judged(4.0, Judgment::Hit, 0.0),
+            judged(5.0, Judgment::Extra, 0.0),
+            judged(6.0, Judgment::Late, 0.12)
+        ]);
+
+        assert_eq!(run.score.judged(), 7);
+        assert_eq!(run.longest_streak, 3);
+        assert!((run.mean_offset.unwrap() - 0.008).abs() < 1e-9);
+        let sections: Vec<(&str, u32)> = run.sections.iter().map(|section| (section.label.as_str(), section.score.judged())).collect();
+        assert_eq!(sections, vec![("Start", 4), ("Chorus", 3)]);
+        let worst: Vec<u32> = run.worst_measures.iter().map(|(measure, _)| *measure).collect();
+        assert_eq!(worst, vec![2, 3, 4]);
+    }
+}
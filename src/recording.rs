@@ -0,0 +1,228 @@
+//! Records the composited output, the camera feed with the AR content drawn over it, to an MP4 file, so performances can
+//! be saved without external screen capture. F11 starts and stops recording.
+//!
+//! A `Recorder` output camera renders offscreen at the recording size, and each frame is read back from the GPU and
+//! encoded with OpenCV's `VideoWriter` on a background thread. Frames are repeated or dropped to keep the video in
+//! real time when the app runs slower or faster than the recording's frame rate. Screen-space UI like the settings
+//! panel and HUD text belongs to the main window and isn't recorded.
+
+use std::{error::Error, fs, path::{Path, PathBuf}, thread, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{app::{App, Plugin, Update}, asset::{Assets, Handle, RenderAssetUsages}, core_pipeline::core_3d::Camera3d, ecs::{entity::Entity, event::{EventReader, EventWriter}, observer::Trigger, resource::Resource, system::{Commands, Res, ResMut}}, image::{BevyDefault, Image}, math::Vec3, render::{camera::{Camera, RenderTarget}, gpu_readback::{Readback, ReadbackComplete}, render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages}}, time::Time, transform::components::Transform, utils::default};
+use crossbeam_channel::{Receiver, Sender};
+use opencv::{core::{AlgorithmHint, Mat, MatTraitConst, Rect, Size}, imgproc, videoio::{VideoWriter, VideoWriterTrait, VideoWriterTraitConst}};
+
+use crate::{command::AppCommand, render_layers::OutputCamera, status::{AppError, ErrorSource}};
+
+static RECORDING_DIR: &str = "recordings";
+/// Frames waiting to be encoded before new ones are dropped, which bounds memory use if encoding falls behind.
+const MAX_QUEUED_FRAMES: usize = 8;
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct CompositeRecordingSettings {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64
+}
+
+impl Default for CompositeRecordingSettings {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            fps: 30.0
+        }
+    }
+}
+
+/// A frame read back from the recorder camera, as RGBA rows padded to the GPU's copy alignment.
+struct RecordedFrame {
+    /// Seconds since recording started.
+    time: f64,
+    data: Vec<u8>
+}
+
+struct ActiveRecording {
+    path: PathBuf,
+    /// When recording started, in seconds since startup.
+    started: f64,
+    frames: u32,
+    /// The recorder camera and the readback of its target, despawned when recording stops.
+    entities: [Entity; 2],
+    sender: Sender<RecordedFrame>,
+    /// Encoding failures reported by the writer thread.
+    errors: Receiver<String>
+}
+
+#[derive(Resource, Default)]
+pub struct CompositeRecorder {
+    active: Option<ActiveRecording>
+}
+
+impl CompositeRecorder {
+    pub fn is_recording(&self) -> bool {
+        self.active.is_some()
+    }
+
+    fn start(
+        &mut self,
+        now: f64,
+        settings: &CompositeRecordingSettings,
+        commands: &mut Commands,
+        images: &mut Assets<Image>
+    ) -> Result<(), Box<dyn Error>> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        fs::create_dir_all(RECORDING_DIR)?;
+        let path = Path::new(RECORDING_DIR).join(format!("performance-{}.mp4", timestamp));
+        let (width, height) = (settings.width.max(16), settings.height.max(16));
+        let fps = settings.fps.clamp(1.0, 120.0);
+
+        // Open the file before spawning anything, so a missing codec is reported right away
+        let writer = open_video_writer(&path, width, height, fps)?;
+
+        let (sender, frames) = crossbeam_channel::bounded(MAX_QUEUED_FRAMES);
+        let (error_sender, errors) = crossbeam_channel::unbounded();
+        thread::Builder::new()
+            .name("composite recorder".to_string())
+            .spawn(move || {
+                // Runs until the recording is stopped and the sender dropped, then finishes the file
+                if let Err(err) = write_video(writer, width, height, fps, frames) {
+                    let _ = error_sender.send(err.to_string());
+                }
+            })?;
+
+        let (camera, target) = spawn_offscreen_output(commands, images, width, height);
+        let readback = commands.spawn(Readback::texture(target)).observe(receive_recorded_frame).id();
+
+        println!("Recording video to {}", path.display());
+        self.active = Some(ActiveRecording { path, started: now, frames: 0, entities: [camera, readback], sender, errors });
+        Ok(())
+    }
+
+    fn stop(&mut self, commands: &mut Commands) {
+        if let Some(recording) = self.active.take() {
+            for entity in recording.entities {
+                commands.entity(entity).despawn();
+            }
+            println!("Saved {} frames to {}", recording.frames, recording.path.display());
+        }
+    }
+}
+
+/// Opens an MP4 file for writing BGR frames of the given size.
+pub fn open_video_writer(path: &Path, width: u32, height: u32, fps: f64) -> Result<VideoWriter, Box<dyn Error>> {
+    let writer = VideoWriter::new(&path.to_string_lossy(), VideoWriter::fourcc('m', 'p', '4', 'v')?, fps, Size::new(width as i32, height as i32), true)?;
+    if !writer.is_opened()? {
+        return Err(format!("Failed to open {} for writing", path.display()).into());
+    }
+    Ok(writer)
+}
+
+/// Spawns a `Recorder` output camera rendering offscreen at the given size, and returns it and its target image for
+/// reading back. The pose is applied to every 3D camera, so it follows the main camera without extra work.
+pub fn spawn_offscreen_output(commands: &mut Commands, images: &mut Assets<Image>, width: u32, height: u32) -> (Entity, Handle<Image>) {
+    let mut target = Image::new_uninit(
+        Extent3d { width, height, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        TextureFormat::bevy_default(),
+        RenderAssetUsages::RENDER_WORLD
+    );
+    target.texture_descriptor.usage |= TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC;
+    let target = images.add(target);
+
+    let camera = commands.spawn((
+        Camera3d::default(),
+        Camera {
+            target: RenderTarget::Image(target.clone().into()),
+            ..default()
+        },
+        Transform::from_xyz(0.0, 500.0, 500.0).looking_at(Vec3::ZERO, Vec3::Y),
+        OutputCamera::Recorder
+    )).id();
+    (camera, target)
+}
+
+/// Converts a frame read back from an offscreen output, as RGBA rows padded to the GPU's copy alignment, with the
+/// given OpenCV color conversion. Returns false without converting if the frame isn't the expected size.
+pub fn convert_readback_frame(data: &[u8], width: u32, height: u32, code: i32, converted: &mut Mat) -> opencv::Result<bool> {
+    let bytes_per_row = data.len() / height as usize;
+    if bytes_per_row < width as usize * 4 || data.len() % height as usize != 0 {
+        return Ok(false);
+    }
+    let data = Mat::from_slice(data)?;
+    let rows = data.reshape(4, height as i32)?;
+    let image = Mat::roi(&rows, Rect::new(0, 0, width as i32, height as i32))?;
+    imgproc::cvt_color(&image, converted, code, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+    Ok(true)
+}
+
+fn write_video(mut writer: VideoWriter, width: u32, height: u32, fps: f64, frames: Receiver<RecordedFrame>) -> Result<(), Box<dyn Error>> {
+    let mut bgr = Mat::default();
+    let mut written: u64 = 0;
+    for frame in frames {
+        if !convert_readback_frame(&frame.data, width, height, imgproc::COLOR_RGBA2BGR, &mut bgr)? {
+            continue;
+        }
+
+        // Write the frame as many times as the video is behind, which is zero when frames arrive faster than the frame rate
+        let due = (frame.time * fps) as u64 + 1;
+        while written < due {
+            writer.write(&bgr)?;
+            written += 1;
+        }
+    }
+    writer.release()?;
+    Ok(())
+}
+
+fn receive_recorded_frame(
+    trigger: Trigger<ReadbackComplete>,
+    time: Res<Time>,
+    mut recorder: ResMut<CompositeRecorder>
+) {
+    let Some(recording) = recorder.active.as_mut() else { return };
+    let frame = RecordedFrame { time: time.elapsed_secs_f64() - recording.started, data: trigger.event().0.clone() };
+    // A dropped frame is covered by repeating the previous one
+    if recording.sender.try_send(frame).is_ok() {
+        recording.frames += 1;
+    }
+}
+
+/// Toggles recording on `AppCommand::ToggleVideoRecording` (F11), and stops it if encoding fails.
+fn record_composite_video(
+    mut commands: Commands,
+    mut app_commands: EventReader<AppCommand>,
+    time: Res<Time>,
+    settings: Res<CompositeRecordingSettings>,
+    mut images: ResMut<Assets<Image>>,
+    mut recorder: ResMut<CompositeRecorder>,
+    mut errors: EventWriter<AppError>
+) {
+    for command in app_commands.read() {
+        if *command != AppCommand::ToggleVideoRecording {
+            continue;
+        }
+        if recorder.is_recording() {
+            recorder.stop(&mut commands);
+        } else if let Err(err) = recorder.start(time.elapsed_secs_f64(), &settings, &mut commands, &mut images) {
+            errors.write(AppError::new(ErrorSource::Recording, format!("Failed to start recording: {}", err)));
+        }
+    }
+
+    let failure = recorder.active.as_ref().and_then(|recording| recording.errors.try_recv().ok());
+    if let Some(err) = failure {
+        errors.write(AppError::new(ErrorSource::Recording, format!("Recording stopped: {}", err)));
+        recorder.stop(&mut commands);
+    }
+}
+
+pub struct CompositeRecordingPlugin;
+
+impl Plugin for CompositeRecordingPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<CompositeRecordingSettings>()
+            .init_resource::<CompositeRecorder>()
+            .add_systems(Update, record_composite_video);
+    }
+}
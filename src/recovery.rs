@@ -0,0 +1,229 @@
+//! Crash recovery. The session (song, position, loop, and practice score) is saved every few seconds,
+//! and the file is removed when the app exits normally. If it's still there on the next start,
+//! the app crashed or lost power, so it offers to pick up where the player left off.
+
+use std::{error::Error, fs, path::{Path, PathBuf}};
+
+use bevy::{app::{App, Plugin, PostUpdate, Startup, Update}, color::Color, ecs::{change_detection::DetectChangesMut, component::Component, entity::Entity, event::EventReader, query::With, resource::Resource, system::{Commands, Query, Res, ResMut}}, text::{JustifyText, TextColor, TextFont, TextLayout}, time::Time, ui::{widget::Text, BackgroundColor, Node, PositionType, UiRect, Val}, utils::default};
+use serde::{Deserialize, Serialize};
+
+use crate::{command::AppCommand, practice::{PracticeScore, PracticeSession, PracticeSettings}, song::{playback::SongPlayback, LoopRegion, Song}};
+
+static RECOVERY_PATH: &str = "session_recovery.json";
+/// How often the session is saved, in seconds.
+const SAVE_INTERVAL: f32 = 5.0;
+/// Sessions that hadn't got further than this into the song, in seconds, aren't worth offering to resume.
+const MIN_RESUME_POSITION: f64 = 5.0;
+const PROMPT_FONT_SIZE: f32 = 22.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionState {
+    pub song: PathBuf,
+    pub position: f64,
+    /// The loop's start and end in seconds.
+    pub loop_region: Option<(f64, f64)>,
+    /// The score so far, if a practice run was in progress.
+    pub practice: Option<PracticeScore>
+}
+
+impl SessionState {
+    fn load() -> Result<Option<SessionState>, Box<dyn Error>> {
+        if !Path::new(RECOVERY_PATH).exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&fs::read_to_string(RECOVERY_PATH)?)?))
+    }
+
+    /// Writes to a temporary file first, so losing power mid-write doesn't leave a truncated file.
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let temporary_path = Path::new(RECOVERY_PATH).with_extension("json.tmp");
+        fs::write(&temporary_path, serde_json::to_string(self)?)?;
+        fs::rename(&temporary_path, RECOVERY_PATH)?;
+        Ok(())
+    }
+}
+
+/// Removes the saved session. Call this when the app exits normally.
+pub fn discard() {
+    if Path::new(RECOVERY_PATH).exists() {
+        if let Err(err) = fs::remove_file(RECOVERY_PATH) {
+            eprintln!("Failed to remove {}: {}", RECOVERY_PATH, err);
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct SessionRecovery {
+    /// The interrupted session, while the player hasn't decided whether to resume it.
+    /// Saving is paused until then, so the session isn't overwritten.
+    offer: Option<SessionState>,
+    /// Set for a frame after loading the interrupted session's song, so playback has reset for the new song
+    /// before the position is restored.
+    loading: bool,
+    seconds_since_save: f32
+}
+
+impl SessionRecovery {
+    /// Whether the prompt to resume the interrupted session is showing. It takes Enter and Escape while it is.
+    pub fn is_prompting(&self) -> bool {
+        self.offer.is_some()
+    }
+}
+
+#[derive(Component)]
+struct RecoveryPrompt;
+
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn setup(
+    mut commands: Commands,
+    mut recovery: ResMut<SessionRecovery>
+) {
+    let state = match SessionState::load() {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("Failed to read the interrupted session from {}: {}", RECOVERY_PATH, err);
+            None
+        }
+    };
+    let Some(state) = state.filter(|state| state.position >= MIN_RESUME_POSITION && state.song.exists()) else { return };
+
+    let name = state.song.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let mode = if state.practice.is_some() { "practicing" } else { "playing" };
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Percent(30.0),
+            left: Val::Percent(20.0),
+            right: Val::Percent(20.0),
+            padding: UiRect::all(Val::Px(12.0)),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.75)),
+        Text::new(format!(
+            "The last session ended unexpectedly while {} {} at {}.\nPress Enter to resume, or Escape to start fresh.",
+            mode, name, format_time(state.position)
+        )),
+        TextFont {
+            font_size: PROMPT_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        TextLayout::new_with_justify(JustifyText::Center),
+        RecoveryPrompt
+    ));
+    recovery.offer = Some(state);
+}
+
+/// Saves the session every `SAVE_INTERVAL` seconds.
+fn save_session(
+    time: Res<Time>,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    session: Res<PracticeSession>,
+    score: Res<PracticeScore>,
+    mut recovery: ResMut<SessionRecovery>
+) {
+    if recovery.offer.is_some() {
+        return;
+    }
+    recovery.seconds_since_save += time.delta_secs();
+    if recovery.seconds_since_save < SAVE_INTERVAL {
+        return;
+    }
+    recovery.seconds_since_save = 0.0;
+
+    // Generated exercises that were never saved can't be reloaded
+    let Some(path) = &song.path else { return };
+    let state = SessionState {
+        song: path.clone(),
+        position: playback.position,
+        loop_region: song.loop_region.map(|region| (region.start, region.end)),
+        practice: session.active.then_some(*score)
+    };
+    if let Err(err) = state.save() {
+        eprintln!("Failed to save the session to {}: {}", RECOVERY_PATH, err);
+    }
+}
+
+/// Resumes or dismisses the interrupted session. Runs after `Update`, so systems that reset playback
+/// when the song changes have already done so before the position is restored.
+fn handle_recovery_prompt(
+    mut commands: Commands,
+    mut app_commands: EventReader<AppCommand>,
+    mut recovery: ResMut<SessionRecovery>,
+    mut song: ResMut<Song>,
+    mut playback: ResMut<SongPlayback>,
+    practice: (ResMut<PracticeSession>, ResMut<PracticeScore>, Res<PracticeSettings>),
+    prompts: Query<Entity, With<RecoveryPrompt>>
+) {
+    let (mut resume, mut dismiss) = (false, false);
+    for command in app_commands.read() {
+        resume |= *command == AppCommand::ResumeSession;
+        dismiss |= *command == AppCommand::DismissSession;
+    }
+    let Some(state) = recovery.offer.clone() else { return };
+    let (mut session, mut score, practice_settings) = practice;
+
+    if !recovery.loading {
+        if dismiss {
+            recovery.offer = None;
+        } else if resume {
+            if song.path.as_ref() != Some(&state.song) {
+                match Song::load_with_metadata(&state.song) {
+                    Ok(loaded) => {
+                        *song = loaded;
+                        recovery.loading = true;
+                        return;
+                    }
+                    Err(err) => {
+                        eprintln!("Failed to load {} to resume the session: {}", state.song.display(), err);
+                        recovery.offer = None;
+                    }
+                }
+            } else {
+                recovery.loading = true;
+            }
+        }
+    }
+
+    if recovery.loading {
+        recovery.loading = false;
+        recovery.offer = None;
+        // Marking the song as changed would reset playback again
+        if let Some((start, end)) = state.loop_region {
+            song.bypass_change_detection().loop_region = Some(LoopRegion { start, end });
+        }
+        if let Some(saved_score) = state.practice {
+            session.start(&mut playback);
+            session.seek(&song, state.position, &practice_settings);
+            *score = saved_score;
+        }
+        // Leave it paused so the player can get their hands back on the keys
+        playback.position = state.position;
+        playback.playing = false;
+        println!("Resumed {} at {}", state.song.display(), format_time(state.position));
+    }
+
+    if recovery.offer.is_none() {
+        for entity in prompts.iter() {
+            commands.entity(entity).despawn();
+        }
+        discard();
+    }
+}
+
+pub struct SessionRecoveryPlugin;
+
+impl Plugin for SessionRecoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<SessionRecovery>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, save_session)
+            .add_systems(PostUpdate, handle_recovery_prompt);
+    }
+}
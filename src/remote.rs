@@ -0,0 +1,218 @@
+//! A remote control page served over HTTP, so a phone on the music stand can start and stop songs, change the tempo,
+//! loop, and pick a song from the library while the visualizer runs on the PC. Enable it with `[remote] enabled = true`,
+//! then open `http://localhost:<port>` on the PC. The page has no authentication, so it only listens on other networks
+//! with `[remote] lan = true`; then open `http://<the PC's address>:<port>` on a phone on the same network.
+//!
+//! The page polls `GET /api/status`, lists the library with `GET /api/songs`, and sends `POST /api/command/<name>` for
+//! the commands in `REMOTE_COMMANDS` and `POST /api/song` with a file name from the list. Requests are answered on a
+//! background thread and handed to the app as `AppCommand`s and `LoadSong`s.
+
+use std::{error::Error, io::{Cursor, Read}, path::PathBuf, sync::{Arc, Mutex}, thread};
+
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::DetectChanges, event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
+use crossbeam_channel::{Receiver, Sender};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{command::{self, AppCommand}, song::{self, playback::SongPlayback, LoadSong, Song}, status::{AppError, ErrorSource}};
+
+static PAGE: &str = include_str!("remoteControl.html");
+
+/// The commands the page can send, by the name in their URL.
+static REMOTE_COMMANDS: [(&str, AppCommand); 7] = [
+    ("toggle", AppCommand::TogglePlayback),
+    ("restart", AppCommand::Restart),
+    ("slower", AppCommand::Slower),
+    ("faster", AppCommand::Faster),
+    ("loop", AppCommand::LoopMeasure),
+    ("clear-loop", AppCommand::ClearLoop),
+    ("next-song", AppCommand::NextSong)
+];
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct RemoteControlSettings {
+    pub enabled: bool,
+    /// Accept connections from other devices, not just this computer.
+    pub lan: bool,
+    pub port: u16
+}
+
+impl Default for RemoteControlSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lan: false,
+            port: 8765
+        }
+    }
+}
+
+/// What the page shows, updated by the app every frame.
+#[derive(Serialize, Default, Clone)]
+struct RemoteStatus {
+    title: String,
+    playing: bool,
+    speed: f64,
+    position: f64,
+    duration: f64,
+    looping: bool
+}
+
+enum RemoteRequest {
+    Command(AppCommand),
+    LoadSong(PathBuf)
+}
+
+/// The running server, which stops accepting requests when dropped.
+struct RemoteServer {
+    server: Arc<Server>,
+    requests: Receiver<RemoteRequest>,
+    status: Arc<Mutex<RemoteStatus>>
+}
+
+impl Drop for RemoteServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+#[derive(Resource, Default)]
+struct RemoteControl(Option<RemoteServer>);
+
+fn respond(request: Request, response: Response<Cursor<Vec<u8>>>, content_type: &str) {
+    let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).expect("content types are valid headers");
+    let _ = request.respond(response.with_header(header));
+}
+
+fn handle_request(mut request: Request, status: &Mutex<RemoteStatus>, requests: &Sender<RemoteRequest>) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    match (method, url.as_str()) {
+        (Method::Get, "/") => respond(request, Response::from_string(PAGE), "text/html; charset=utf-8"),
+        (Method::Get, "/api/status") => {
+            let status = status.lock().map(|status| status.clone()).unwrap_or_default();
+            respond(request, Response::from_string(serde_json::to_string(&status).unwrap_or_default()), "application/json");
+        }
+        (Method::Get, "/api/songs") => {
+            let names: Vec<String> = song::library_songs().iter()
+                .filter_map(|path| path.file_name().map(|name| name.to_string_lossy().to_string()))
+                .collect();
+            respond(request, Response::from_string(serde_json::to_string(&names).unwrap_or_default()), "application/json");
+        }
+        (Method::Post, "/api/song") => {
+            let mut name = String::new();
+            let _ = request.as_reader().read_to_string(&mut name);
+            // Only songs from the library can be loaded, so the page can't be used to read other files
+            let path = song::library_songs().into_iter().find(|path| path.file_name().is_some_and(|file_name| file_name.to_string_lossy() == name.trim()));
+            match path {
+                Some(path) => {
+                    let _ = requests.send(RemoteRequest::LoadSong(path));
+                    respond(request, Response::from_string("ok"), "text/plain");
+                }
+                None => respond(request, Response::from_string("No such song").with_status_code(404), "text/plain")
+            }
+        }
+        (Method::Post, path) => {
+            let command = path.strip_prefix("/api/command/")
+                .and_then(|name| REMOTE_COMMANDS.iter().find(|(command_name, _)| *command_name == name))
+                .map(|(_, command)| *command);
+            match command {
+                Some(command) => {
+                    let _ = requests.send(RemoteRequest::Command(command));
+                    respond(request, Response::from_string("ok"), "text/plain");
+                }
+                None => respond(request, Response::from_string("Unknown command").with_status_code(404), "text/plain")
+            }
+        }
+        _ => respond(request, Response::from_string("Not found").with_status_code(404), "text/plain")
+    }
+}
+
+fn start_server(port: u16, lan: bool) -> Result<RemoteServer, Box<dyn Error>> {
+    // Anyone who can reach the page can control the app, so other devices are only let in when asked for
+    let address = if lan { "0.0.0.0" } else { "127.0.0.1" };
+    let server = Arc::new(Server::http((address, port)).map_err(|err| err.to_string())?);
+    let status = Arc::new(Mutex::new(RemoteStatus::default()));
+    let (sender, requests) = crossbeam_channel::unbounded();
+
+    let thread_server = server.clone();
+    let thread_status = status.clone();
+    thread::Builder::new()
+        .name("remote control".to_string())
+        .spawn(move || {
+            // Ends when the server is unblocked
+            for request in thread_server.incoming_requests() {
+                handle_request(request, &thread_status, &sender);
+            }
+        })?;
+
+    if lan {
+        println!("Remote control page at http://<this computer's address>:{}", port);
+    } else {
+        println!("Remote control page at http://localhost:{}", port);
+    }
+    Ok(RemoteServer { server, requests, status })
+}
+
+/// Starts or stops the server when the settings change.
+fn update_remote_server(
+    settings: Res<RemoteControlSettings>,
+    mut remote: ResMut<RemoteControl>,
+    mut errors: EventWriter<AppError>
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    remote.0 = None;
+    if !settings.enabled {
+        return;
+    }
+    match start_server(settings.port, settings.lan) {
+        Ok(server) => remote.0 = Some(server),
+        Err(err) => {
+            errors.write(AppError::new(ErrorSource::Remote, format!("Failed to start the remote control server on port {}: {}", settings.port, err)));
+        }
+    }
+}
+
+fn run_remote_requests(
+    remote: Res<RemoteControl>,
+    playback: Res<SongPlayback>,
+    song: Res<Song>,
+    mut commands: EventWriter<AppCommand>,
+    mut load_song: EventWriter<LoadSong>
+) {
+    let Some(server) = &remote.0 else { return };
+    for request in server.requests.try_iter() {
+        match request {
+            RemoteRequest::Command(command) => {
+                commands.write(command);
+            }
+            RemoteRequest::LoadSong(path) => {
+                load_song.write(LoadSong(path));
+            }
+        }
+    }
+
+    if let Ok(mut status) = server.status.lock() {
+        *status = RemoteStatus {
+            title: song.title.clone(),
+            playing: playback.playing,
+            speed: playback.speed,
+            position: playback.position,
+            duration: song.duration,
+            looping: song.loop_region.is_some()
+        };
+    }
+}
+
+pub struct RemoteControlPlugin;
+
+impl Plugin for RemoteControlPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<RemoteControlSettings>()
+            .init_resource::<RemoteControl>()
+            .add_systems(Update, (update_remote_server, run_remote_requests).chain().before(command::run_app_commands));
+    }
+}
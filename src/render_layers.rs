@@ -0,0 +1,99 @@
+//! Render layer assignments, so AR content, debug visuals, and HUD elements can be shown or hidden per output.
+//! Entities without a `RenderLayers` component are on the AR layer.
+
+use bevy::{app::{App, Plugin, Startup, Update}, ecs::{component::Component, entity::Entity, resource::Resource, system::{Commands, Query, Res, ResMut}}, gizmos::config::{DefaultGizmoConfigGroup, GizmoConfigStore}, render::view::{Layer, RenderLayers}};
+
+/// Virtual content aligned with the real keyboard.
+pub const AR_LAYER: Layer = 0;
+/// Tracking and alignment debug visuals, like fiducial planes and gizmos.
+pub const DEBUG_LAYER: Layer = 1;
+/// World-anchored status and HUD elements.
+pub const HUD_LAYER: Layer = 2;
+
+/// The different places the scene can be rendered to.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputCamera {
+    MainWindow,
+    Projector,
+    Recorder,
+    Spectator,
+    /// The camera feed with nothing drawn over it, for mixing externally.
+    CleanFeed,
+    /// The camera feed with AR content but no debug visuals or settings panel.
+    OverlayFeed
+}
+
+impl OutputCamera {
+    /// Whether this output shows the camera feed, so it should be framed the same way as the main window.
+    pub fn shows_camera_feed(self) -> bool {
+        matches!(self, OutputCamera::MainWindow | OutputCamera::Recorder | OutputCamera::CleanFeed | OutputCamera::OverlayFeed)
+    }
+}
+
+/// Which layers each output renders. Cameras tagged with `OutputCamera` are kept in sync with this.
+#[derive(Resource, Clone)]
+pub struct OutputLayers {
+    pub main_window: RenderLayers,
+    pub projector: RenderLayers,
+    pub recorder: RenderLayers,
+    pub spectator: RenderLayers,
+    pub clean_feed: RenderLayers,
+    pub overlay_feed: RenderLayers
+}
+
+impl Default for OutputLayers {
+    fn default() -> Self {
+        Self {
+            main_window: RenderLayers::from_layers(&[AR_LAYER, DEBUG_LAYER, HUD_LAYER]),
+            // A projector shines onto the real keys, so it only needs the AR content
+            projector: RenderLayers::layer(AR_LAYER),
+            recorder: RenderLayers::from_layers(&[AR_LAYER, HUD_LAYER]),
+            spectator: RenderLayers::from_layers(&[AR_LAYER, HUD_LAYER]),
+            // The background is drawn for every camera regardless of layers
+            clean_feed: RenderLayers::none(),
+            overlay_feed: RenderLayers::from_layers(&[AR_LAYER, HUD_LAYER])
+        }
+    }
+}
+
+impl OutputLayers {
+    pub fn for_output(&self, output: OutputCamera) -> &RenderLayers {
+        match output {
+            OutputCamera::MainWindow => &self.main_window,
+            OutputCamera::Projector => &self.projector,
+            OutputCamera::Recorder => &self.recorder,
+            OutputCamera::Spectator => &self.spectator,
+            OutputCamera::CleanFeed => &self.clean_feed,
+            OutputCamera::OverlayFeed => &self.overlay_feed
+        }
+    }
+}
+
+fn configure_gizmo_layers(mut config_store: ResMut<GizmoConfigStore>) {
+    let (config, _) = config_store.config_mut::<DefaultGizmoConfigGroup>();
+    config.render_layers = RenderLayers::layer(DEBUG_LAYER);
+}
+
+fn sync_output_layers(
+    mut commands: Commands,
+    output_layers: Res<OutputLayers>,
+    cameras: Query<(Entity, &OutputCamera, Option<&RenderLayers>)>
+) {
+    for (entity, output, layers) in cameras.iter() {
+        let wanted = output_layers.for_output(*output);
+        if layers != Some(wanted) {
+            commands.entity(entity).insert(wanted.clone());
+        }
+    }
+}
+
+pub struct RenderLayersPlugin;
+
+impl Plugin for RenderLayersPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<OutputLayers>()
+            .add_systems(Startup, configure_gizmo_layers)
+            .add_systems(Update, sync_output_layers);
+    }
+}
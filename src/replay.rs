@@ -0,0 +1,304 @@
+//! Records webcam frames and live MIDI input to disk, and plays them back through `WebcamFrame` and the note bus
+//! so tracking and visualization changes can be tested without a camera or keyboard.
+//! A session is a directory with one JPEG per frame and an `events.jsonl` log, timestamped in seconds since recording started.
+
+use std::{error::Error, fs::{self, File}, io::{BufRead, BufReader, BufWriter, Write}, path::{Path, PathBuf}, thread, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{app::{App, Plugin, PreUpdate, Update}, ecs::{event::{EventReader, EventWriter}, resource::Resource, schedule::{common_conditions::{not, resource_exists}, IntoScheduleConfigs}, system::{Res, ResMut}}, time::Time};
+use crossbeam_channel::{Receiver, Sender};
+use opencv::{core::{Mat, MatTraitConst, Vector}, imgcodecs};
+use serde::{Deserialize, Serialize};
+
+use crate::{command::{self, AppCommand}, midi::{bus::{NoteBus, NoteSource}, input_clock, MidiEvent}, seed::RandomSeed, status::{self, AppError, ErrorSource}, video::{capture, WebcamFrame}, MidiInputSystems, VideoCaptureSystems, VideoUpdateSystems};
+
+static RECORDING_DIR: &str = "recordings";
+static EVENTS_FILE: &str = "events.jsonl";
+static FRAMES_DIR: &str = "frames";
+const FRAME_QUALITY: i32 = 95;
+
+/// One line of a session's event log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum ReplayEntry {
+    /// A webcam frame, stored as `frames/<index>.jpg`.
+    Frame { time: f64, index: u32 },
+    Midi { time: f64, event: MidiEvent },
+    /// The random seed, logged first so replays generate the same random content.
+    Seed { seed: u64 }
+}
+
+pub fn frame_path(session: &Path, index: u32) -> PathBuf {
+    session.join(FRAMES_DIR).join(format!("{:06}.jpg", index))
+}
+
+/// The session to play back instead of the live camera. Insert this before adding `ReplayPlugin`, e.g. with `--replay`.
+#[derive(Resource, Clone, Debug)]
+pub struct ReplaySession(pub PathBuf);
+
+enum RecorderMessage {
+    Frame { index: u32, frame: Mat },
+    Entry(ReplayEntry)
+}
+
+struct ActiveRecording {
+    session: PathBuf,
+    /// When recording started, in seconds since startup.
+    started: f64,
+    frames: u32,
+    /// The number of the last frame recorded, so each captured frame is saved once.
+    last_frame: u64,
+    sender: Sender<RecorderMessage>,
+    /// Write failures reported by the writer thread.
+    errors: Receiver<String>
+}
+
+/// Encodes frames and writes the log on a background thread, so recording doesn't slow down the app.
+#[derive(Resource, Default)]
+pub struct ReplayRecorder {
+    active: Option<ActiveRecording>
+}
+
+impl ReplayRecorder {
+    pub fn is_recording(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Starts recording with the given seed, which should be freshly applied so random content from here on
+    /// depends only on it.
+    fn start(&mut self, now: f64, seed: RandomSeed) -> Result<(), Box<dyn Error>> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let session = Path::new(RECORDING_DIR).join(format!("session-{}", timestamp));
+        fs::create_dir_all(session.join(FRAMES_DIR))?;
+        let events = BufWriter::new(File::create(session.join(EVENTS_FILE))?);
+
+        let (sender, messages) = crossbeam_channel::unbounded();
+        let (error_sender, errors) = crossbeam_channel::unbounded();
+        let thread_session = session.clone();
+        thread::Builder::new()
+            .name("replay recorder".to_string())
+            .spawn(move || {
+                // Runs until the recording is stopped and the sender dropped
+                if let Err(err) = write_session(&thread_session, events, messages) {
+                    let _ = error_sender.send(err.to_string());
+                }
+            })?;
+
+        let _ = sender.send(RecorderMessage::Entry(ReplayEntry::Seed { seed: seed.0 }));
+        println!("Recording session to {}", session.display());
+        self.active = Some(ActiveRecording { session, started: now, frames: 0, last_frame: 0, sender, errors });
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(recording) = self.active.take() {
+            println!("Saved {} frames to {}", recording.frames, recording.session.display());
+        }
+    }
+}
+
+fn write_session(session: &Path, mut events: BufWriter<File>, messages: Receiver<RecorderMessage>) -> Result<(), Box<dyn Error>> {
+    let params = Vector::from_slice(&[imgcodecs::IMWRITE_JPEG_QUALITY, FRAME_QUALITY]);
+    for message in messages {
+        match message {
+            RecorderMessage::Frame { index, frame } => {
+                let path = frame_path(session, index);
+                if !imgcodecs::imwrite(&path.to_string_lossy(), &frame, &params)? {
+                    return Err(format!("Failed to write {}", path.display()).into());
+                }
+            }
+            RecorderMessage::Entry(entry) => {
+                serde_json::to_writer(&mut events, &entry)?;
+                events.write_all(b"\n")?;
+            }
+        }
+    }
+    events.flush()?;
+    Ok(())
+}
+
+/// Toggles recording on `AppCommand::ToggleSessionRecording`, and records the frame captured this update plus any MIDI events.
+fn record_session(
+    mut commands: EventReader<AppCommand>,
+    time: Res<Time>,
+    webcam_frame: Res<WebcamFrame>,
+    bus: Res<NoteBus>,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut seed: ResMut<RandomSeed>,
+    mut errors: EventWriter<AppError>
+) {
+    let now = time.elapsed_secs_f64();
+    for command in commands.read() {
+        if *command != AppCommand::ToggleSessionRecording {
+            continue;
+        }
+        if recorder.is_recording() {
+            recorder.stop();
+        } else {
+            // Reseed, so what was generated before the recording doesn't affect what's generated during it
+            let new_seed = RandomSeed::default();
+            match recorder.start(now, new_seed) {
+                Ok(()) => *seed = new_seed,
+                Err(err) => {
+                    errors.write(AppError::new(ErrorSource::Replay, format!("Failed to start recording: {}", err)));
+                }
+            }
+        }
+    }
+
+    let Some(recording) = recorder.active.as_mut() else { return };
+    let time = now - recording.started;
+
+    if let Ok(err) = recording.errors.try_recv() {
+        errors.write(AppError::new(ErrorSource::Replay, format!("Recording stopped: {}", err)));
+        recorder.active = None;
+        return;
+    }
+
+    for event in bus.from_source(NoteSource::Live) {
+        let event = MidiEvent { timestamp: event.timestamp, channel: event.channel, kind: event.kind };
+        let _ = recording.sender.send(RecorderMessage::Entry(ReplayEntry::Midi { time, event }));
+    }
+
+    if webcam_frame.1 != recording.last_frame && !webcam_frame.0.empty() {
+        recording.last_frame = webcam_frame.1;
+        let frame = match webcam_frame.0.try_clone() {
+            Ok(frame) => frame,
+            Err(err) => {
+                errors.write(AppError::new(ErrorSource::Replay, format!("Failed to copy the frame: {}", err)));
+                return;
+            }
+        };
+        let index = recording.frames;
+        recording.frames += 1;
+        let _ = recording.sender.send(RecorderMessage::Frame { index, frame });
+        let _ = recording.sender.send(RecorderMessage::Entry(ReplayEntry::Frame { time, index }));
+    }
+}
+
+/// A loaded session being played back. Each update shows the next recorded frame along with the MIDI events logged before it,
+/// so a session always plays out the same way no matter how fast the app runs.
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    session: PathBuf,
+    entries: Vec<ReplayEntry>,
+    next_entry: usize,
+    /// The frame to show this update, if one is due.
+    frame_due: Option<u32>,
+    /// What to add to the recorded timestamps to bring them onto this run's `midi::input_clock`.
+    clock_offset: Option<i64>
+}
+
+impl ReplayPlayback {
+    pub fn load(session: &Path) -> Result<ReplayPlayback, Box<dyn Error>> {
+        let file = File::open(session.join(EVENTS_FILE))?;
+        let entries = BufReader::new(file).lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<Result<Vec<ReplayEntry>, Box<dyn Error>>>()?;
+
+        Ok(ReplayPlayback {
+            session: session.to_path_buf(),
+            entries,
+            next_entry: 0,
+            frame_due: None,
+            clock_offset: None
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_entry >= self.entries.len()
+    }
+}
+
+/// Publishes the MIDI events logged up to the next frame as live input. Runs before the MIDI input systems so note state
+/// sees them this update.
+fn advance_replay(
+    time: Res<Time>,
+    mut playback: ResMut<ReplayPlayback>,
+    mut bus: ResMut<NoteBus>
+) {
+    if playback.is_finished() {
+        return;
+    }
+
+    let playback = playback.as_mut();
+    while let Some(entry) = playback.entries.get(playback.next_entry) {
+        playback.next_entry += 1;
+        match entry {
+            ReplayEntry::Midi { event, .. } => {
+                // Keep the recorded spacing, starting from now
+                let offset = *playback.clock_offset.get_or_insert(input_clock() as i64 - event.timestamp as i64);
+                let timestamp = (event.timestamp as i64 + offset).max(0) as u64;
+                bus.publish(time.elapsed_secs_f64(), timestamp, NoteSource::Live, event.channel, event.kind);
+            }
+            ReplayEntry::Frame { index, .. } => {
+                playback.frame_due = Some(*index);
+                break;
+            }
+            // Applied when the session is loaded
+            ReplayEntry::Seed { .. } => {}
+        }
+    }
+
+    if playback.is_finished() {
+        println!("Replay of {} finished", playback.session.display());
+    }
+}
+
+/// Loads the due frame into `WebcamFrame`, in place of the live capture.
+fn show_replay_frame(
+    mut playback: ResMut<ReplayPlayback>,
+    mut webcam_frame: ResMut<WebcamFrame>,
+    mut errors: EventWriter<AppError>
+) {
+    let Some(index) = playback.frame_due.take() else { return };
+    let path = frame_path(&playback.session, index);
+    match imgcodecs::imread(&path.to_string_lossy(), imgcodecs::IMREAD_COLOR) {
+        Ok(frame) if !frame.empty() => {
+            webcam_frame.0 = frame;
+            webcam_frame.1 = capture::next_frame_number();
+        }
+        Ok(_) => {
+            errors.write(AppError::new(ErrorSource::Replay, format!("Missing frame {}", path.display())));
+        }
+        Err(err) => {
+            errors.write(AppError::new(ErrorSource::Replay, format!("Failed to read {}: {}", path.display(), err)));
+        }
+    }
+}
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        if let Some(session) = app.world().get_resource::<ReplaySession>().cloned() {
+            match ReplayPlayback::load(&session.0) {
+                Ok(playback) => {
+                    println!("Replaying {} ({} entries)", session.0.display(), playback.entries.len());
+                    // Sessions recorded before seeds were logged just use a random one
+                    let seed = playback.entries.iter().find_map(|entry| match entry {
+                        ReplayEntry::Seed { seed } => Some(RandomSeed(*seed)),
+                        _ => None
+                    });
+                    if let Some(seed) = seed {
+                        app.insert_resource(seed);
+                    }
+                    app.insert_resource(playback);
+                }
+                Err(err) => status::report_startup_error(app, AppError::new(
+                    ErrorSource::Replay,
+                    format!("Failed to load replay {}: {}", session.0.display(), err)
+                ))
+            }
+        }
+
+        app
+            .init_resource::<ReplayRecorder>()
+            // The live camera is ignored while a replay is loaded
+            .configure_sets(Update, VideoCaptureSystems.run_if(not(resource_exists::<ReplayPlayback>)))
+            .add_systems(PreUpdate, advance_replay.run_if(resource_exists::<ReplayPlayback>).before(MidiInputSystems))
+            .add_systems(Update, (
+                show_replay_frame.run_if(resource_exists::<ReplayPlayback>),
+                record_session.after(command::run_app_commands)
+            ).chain().after(VideoCaptureSystems).before(VideoUpdateSystems));
+    }
+}
@@ -0,0 +1,177 @@
+//! A piano roll of the last few seconds of playing, for looking back on what was just played. Open it with F4 or the
+//! command palette. The roll is taken from `MidiHistory` when it opens and holds still while it's read, until it's
+//! refreshed.
+
+use bevy::{app::{App, Plugin, Update}, ecs::{event::EventReader, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}, time::Time};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use crate::{chord::PITCH_CLASS_NAMES, command::{self, AppCommand}, keyboard, midi::history::{MidiHistory, PlayedNote, HISTORY_SECONDS}};
+
+/// The height of one key's row in the roll, in points.
+const ROW_HEIGHT: f32 = 6.0;
+/// The fewest keys the roll shows, so a few notes aren't stretched over the whole window.
+const MIN_KEYS: u8 = 24;
+
+#[derive(Resource)]
+struct PianoRollReview {
+    open: bool,
+    /// How many seconds back the roll goes.
+    seconds: f64,
+    notes: Vec<PlayedNote>,
+    /// When the notes were taken from the history, which is the right edge of the roll.
+    taken_at: f64
+}
+
+impl Default for PianoRollReview {
+    fn default() -> Self {
+        Self {
+            open: false,
+            seconds: 30.0,
+            notes: Vec::new(),
+            taken_at: 0.0
+        }
+    }
+}
+
+impl PianoRollReview {
+    fn refresh(&mut self, history: &MidiHistory, now: f64) {
+        self.notes = history.notes_since(now - self.seconds);
+        self.taken_at = now;
+    }
+}
+
+/// The range of keys to show for the notes, widened evenly to at least `MIN_KEYS`.
+fn key_range(notes: &[PlayedNote]) -> (u8, u8) {
+    let lowest = notes.iter().map(|note| note.key).min().unwrap_or(60);
+    let highest = notes.iter().map(|note| note.key).max().unwrap_or(60);
+    let missing = MIN_KEYS.saturating_sub(highest - lowest + 1);
+    let low = lowest.saturating_sub(missing / 2);
+    let high = (low + MIN_KEYS - 1).max(highest).min(127);
+    (high.saturating_sub(MIN_KEYS - 1).min(low), high)
+}
+
+fn toggle_review(
+    mut commands: EventReader<AppCommand>,
+    time: Res<Time>,
+    history: Res<MidiHistory>,
+    mut review: ResMut<PianoRollReview>
+) {
+    for command in commands.read() {
+        if *command == AppCommand::ToggleReview {
+            review.open = !review.open;
+            if review.open {
+                review.refresh(&history, time.elapsed_secs_f64());
+            }
+        }
+    }
+}
+
+fn draw_piano_roll(ui: &mut egui::Ui, notes: &[PlayedNote], start: f64, end: f64) {
+    let (low, high) = key_range(notes);
+    let rows = (high - low + 1) as f32;
+    let size = egui::vec2(ui.available_width().max(400.0), rows * ROW_HEIGHT);
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+    let rect = response.rect;
+    let x_at = |time: f64| rect.left() + ((time - start) / (end - start)).clamp(0.0, 1.0) as f32 * rect.width();
+    let row = |key: u8| {
+        let bottom = rect.bottom() - (key - low) as f32 * ROW_HEIGHT;
+        egui::Rect::from_x_y_ranges(rect.x_range(), (bottom - ROW_HEIGHT)..=bottom)
+    };
+
+    for key in low..=high {
+        let shade = if keyboard::is_black_key(key) { 18 } else { 32 };
+        painter.rect_filled(row(key), 0.0, egui::Color32::from_gray(shade));
+        if key % 12 == 0 {
+            painter.text(row(key).left_center(), egui::Align2::LEFT_CENTER, format!("C{}", key as i32 / 12 - 1), egui::FontId::monospace(ROW_HEIGHT * 1.5), egui::Color32::GRAY);
+        }
+    }
+    // A line every second, so durations can be read off the roll
+    for second in (start.ceil() as i64)..=(end.floor() as i64) {
+        let x = x_at(second as f64);
+        painter.line_segment([egui::pos2(x, rect.top()), egui::pos2(x, rect.bottom())], egui::Stroke::new(1.0, egui::Color32::from_gray(45)));
+    }
+
+    let mut hovered = None;
+    for note in notes {
+        let note_rect = egui::Rect::from_x_y_ranges(x_at(note.start)..=x_at(note.end.unwrap_or(end)).max(x_at(note.start) + 2.0), row(note.key).shrink2(egui::vec2(0.0, 0.5)).y_range());
+        // Louder notes are brighter
+        let brightness = 0.4 + 0.6 * note.velocity as f32 / 127.0;
+        painter.rect_filled(note_rect, 1.0, egui::Color32::from_rgb((77.0 * brightness) as u8, (230.0 * brightness) as u8, (128.0 * brightness) as u8));
+        if response.hover_pos().is_some_and(|position| note_rect.contains(position)) {
+            hovered = Some(note);
+        }
+    }
+
+    if let Some(note) = hovered {
+        let length = note.end.unwrap_or(end) - note.start;
+        response.on_hover_text(format!(
+            "{}{}, velocity {}, {:.2}s long, {:.1}s ago",
+            PITCH_CLASS_NAMES[note.key as usize % 12], note.key as i32 / 12 - 1, note.velocity, length, end - note.start
+        ));
+    }
+}
+
+fn draw_review_window(
+    mut contexts: EguiContexts,
+    time: Res<Time>,
+    history: Res<MidiHistory>,
+    mut review: ResMut<PianoRollReview>
+) {
+    if !review.open {
+        return;
+    }
+    let review = review.as_mut();
+    let mut open = true;
+
+    egui::Window::new("Review")
+        .open(&mut open)
+        .default_width(600.0)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Last");
+                ui.add(egui::DragValue::new(&mut review.seconds).range(5.0..=HISTORY_SECONDS).speed(1.0).suffix(" s"));
+                if ui.button("Refresh").clicked() {
+                    review.refresh(&history, time.elapsed_secs_f64());
+                }
+            });
+            if review.notes.is_empty() {
+                ui.label(format!("Nothing was played in the {:.0} seconds before this was opened.", review.seconds));
+                return;
+            }
+            ui.label(format!("{} notes", review.notes.len()));
+            draw_piano_roll(ui, &review.notes, review.taken_at - review.seconds, review.taken_at);
+        });
+
+    review.open = open;
+}
+
+pub struct PianoRollReviewPlugin;
+
+impl Plugin for PianoRollReviewPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+
+        app
+            .init_resource::<PianoRollReview>()
+            .add_systems(Update, (toggle_review, draw_review_window).chain().after(command::run_app_commands));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(key: u8) -> PlayedNote {
+        PlayedNote { key, velocity: 64, start: 0.0, end: None }
+    }
+
+    #[test]
+    fn key_range_is_widened_around_the_notes() {
+        assert_eq!(key_range(&[note(60), note(64)]), (51, 74));
+        assert_eq!(key_range(&[note(1)]), (0, 23));
+        assert_eq!(key_range(&[note(126)]), (104, 127));
+        assert_eq!(key_range(&[note(21), note(108)]), (21, 108));
+    }
+}
@@ -0,0 +1,176 @@
+//! Exports the reconstructed static scene (keyboard, fiducial markers, and the tracked camera) as glTF,
+//! for documentation, debugging, or importing into other 3D tools.
+
+use std::{error::Error, fs, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::{app::{App, Plugin, Update}, core_pipeline::core_3d::Camera3d, ecs::{event::EventReader, query::{With, Without}, system::{Query, Res}}, math::{Quat, Vec3}, render::camera::Projection, transform::components::Transform};
+use serde_json::{json, Value};
+
+use crate::{camera_cuts::ShotCamera, command::AppCommand, keyboard::{KeyboardLayout, KeyboardPlane}, render_layers::OutputCamera, video::aruco_camera::FiducialLayout};
+
+static EXPORT_DIR: &str = "exports";
+
+/// glTF uses meters, while the scene is in mm.
+const MM_TO_METERS: f32 = 0.001;
+
+/// A camera pose and projection to include in the export.
+pub struct ExportedCamera {
+    pub transform: Transform,
+    /// The vertical field of view in radians.
+    pub fov: f32,
+    pub aspect_ratio: f32,
+    pub near: f32
+}
+
+/// A unit quad in the XZ plane facing +Y: positions, normals, then u16 indices.
+fn unit_quad_buffer() -> Vec<u8> {
+    let positions: [[f32; 3]; 4] = [[-0.5, 0.0, -0.5], [0.5, 0.0, -0.5], [0.5, 0.0, 0.5], [-0.5, 0.0, 0.5]];
+    let normals: [[f32; 3]; 4] = [[0.0, 1.0, 0.0]; 4];
+    let indices: [u16; 6] = [0, 2, 1, 0, 3, 2];
+
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(bytemuck::cast_slice(&positions));
+    buffer.extend_from_slice(bytemuck::cast_slice(&normals));
+    buffer.extend_from_slice(bytemuck::cast_slice(&indices));
+    buffer
+}
+
+fn quad_node(name: &str, translation: Vec3, rotation: Quat, size: (f32, f32), material: usize) -> Value {
+    json!({
+        "name": name,
+        "mesh": material,
+        "translation": (translation * MM_TO_METERS).to_array(),
+        "rotation": rotation.to_array(),
+        "scale": [size.0 * MM_TO_METERS, 1.0, size.1 * MM_TO_METERS]
+    })
+}
+
+/// Writes `<path>.gltf` and its `<path>.bin` buffer.
+pub fn export_scene(path: &Path, keyboard_layout: &KeyboardLayout, keyboard_plane: &KeyboardPlane, fiducial_layout: &FiducialLayout, camera: Option<&ExportedCamera>) -> Result<(), Box<dyn Error>> {
+    let buffer = unit_quad_buffer();
+    let bin_path = path.with_extension("bin");
+    let bin_name = bin_path.file_name().ok_or("Invalid export path")?.to_string_lossy().to_string();
+
+    let mut nodes = Vec::new();
+
+    let keyboard_length = keyboard_layout.geometry.white_key_length;
+    nodes.push(quad_node(
+        "Keyboard",
+        keyboard_plane.to_world(Vec3::new(0.0, 0.0, keyboard_layout.geometry.key_back_z + keyboard_length / 2.0)),
+        keyboard_plane.transform.rotation,
+        (keyboard_layout.width(), keyboard_length),
+        0
+    ));
+
+    for fiducial in &fiducial_layout.fiducials {
+        nodes.push(quad_node(
+            &format!("Marker {}", fiducial.id),
+            fiducial.center(),
+            Quat::IDENTITY,
+            (fiducial_layout.size as f32, fiducial_layout.size as f32),
+            1
+        ));
+    }
+
+    let mut cameras = Vec::new();
+    if let Some(camera) = camera {
+        cameras.push(json!({
+            "type": "perspective",
+            "perspective": {
+                "yfov": camera.fov,
+                "aspectRatio": camera.aspect_ratio,
+                "znear": (camera.near * MM_TO_METERS).max(0.001)
+            }
+        }));
+        // Both Bevy and glTF cameras look down their local -Z axis, so the rotation carries over directly
+        nodes.push(json!({
+            "name": "Tracked camera",
+            "camera": 0,
+            "translation": (camera.transform.translation * MM_TO_METERS).to_array(),
+            "rotation": camera.transform.rotation.to_array()
+        }));
+    }
+
+    let quad_primitive = |material: usize| json!({
+        "primitives": [{
+            "attributes": { "POSITION": 0, "NORMAL": 1 },
+            "indices": 2,
+            "material": material
+        }]
+    });
+
+    let mut gltf = json!({
+        "asset": { "version": "2.0", "generator": "ARPianoVisualizer" },
+        "scene": 0,
+        "scenes": [{ "name": "Tracked setup", "nodes": (0..nodes.len()).collect::<Vec<_>>() }],
+        "nodes": nodes,
+        "meshes": [quad_primitive(0), quad_primitive(1)],
+        "materials": [
+            { "name": "Keyboard", "pbrMetallicRoughness": { "baseColorFactor": [0.05, 0.05, 0.05, 1.0], "metallicFactor": 0.0 }, "doubleSided": true },
+            { "name": "Marker", "pbrMetallicRoughness": { "baseColorFactor": [1.0, 1.0, 1.0, 1.0], "metallicFactor": 0.0 }, "doubleSided": true }
+        ],
+        "buffers": [{ "uri": bin_name, "byteLength": buffer.len() }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 48, "target": 34962 },
+            { "buffer": 0, "byteOffset": 48, "byteLength": 48, "target": 34962 },
+            { "buffer": 0, "byteOffset": 96, "byteLength": 12, "target": 34963 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3", "min": [-0.5, 0.0, -0.5], "max": [0.5, 0.0, 0.5] },
+            { "bufferView": 1, "componentType": 5126, "count": 4, "type": "VEC3" },
+            { "bufferView": 2, "componentType": 5123, "count": 6, "type": "SCALAR" }
+        ]
+    });
+    if !cameras.is_empty() {
+        gltf["cameras"] = Value::Array(cameras);
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&bin_path, &buffer)?;
+    fs::write(path, serde_json::to_string_pretty(&gltf)?)?;
+    Ok(())
+}
+
+fn export_scene_hotkey(
+    mut commands: EventReader<AppCommand>,
+    keyboard_layout: Res<KeyboardLayout>,
+    keyboard_plane: Res<KeyboardPlane>,
+    fiducial_layout: Res<FiducialLayout>,
+    cameras: Query<(&Transform, &Projection, &OutputCamera), (With<Camera3d>, Without<ShotCamera>)>
+) {
+    let mut requested = false;
+    for command in commands.read() {
+        requested |= *command == AppCommand::ExportScene;
+    }
+    if !requested {
+        return;
+    }
+
+    // The tracked camera, rather than a camera cut's shot or another output's camera
+    let Some((transform, projection, _)) = cameras.iter().find(|(_, _, output)| **output == OutputCamera::MainWindow) else {
+        eprintln!("Failed to export the scene: there's no tracked camera to export");
+        return;
+    };
+    let (fov, aspect_ratio, near) = match projection {
+        Projection::Perspective(perspective) => (perspective.fov, perspective.aspect_ratio, perspective.near),
+        _ => (std::f32::consts::FRAC_PI_4, 16.0 / 9.0, 0.1)
+    };
+    let camera = ExportedCamera { transform: *transform, fov, aspect_ratio, near };
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+    let path = Path::new(EXPORT_DIR).join(format!("scene-{}.gltf", timestamp));
+    match export_scene(&path, &keyboard_layout, &keyboard_plane, &fiducial_layout, Some(&camera)) {
+        Ok(()) => println!("Exported scene to {}", path.display()),
+        Err(err) => eprintln!("Failed to export scene to {}: {}", path.display(), err)
+    }
+}
+
+pub struct SceneExportPlugin;
+
+impl Plugin for SceneExportPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, export_scene_hotkey);
+    }
+}
@@ -0,0 +1,40 @@
+//! The seed behind everything random, so generated exercises and randomized visuals can be reproduced exactly.
+//! It's printed at startup, can be set with `--seed`, and is recorded into replay sessions.
+//!
+//! Each consumer draws from its own stream derived from the seed and a name, so adding randomness somewhere new
+//! doesn't change what existing consumers produce for the same seed.
+
+use bevy::{app::{App, Plugin, Startup}, ecs::{resource::Resource, system::Res}};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RandomSeed(pub u64);
+
+impl Default for RandomSeed {
+    fn default() -> Self {
+        RandomSeed(rand::thread_rng().gen())
+    }
+}
+
+impl RandomSeed {
+    /// Returns the random number generator for the named stream.
+    pub fn rng(&self, stream: &str) -> StdRng {
+        // FNV-1a, since std's hashers aren't guaranteed to stay the same between Rust versions
+        let hash = stream.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3));
+        StdRng::seed_from_u64(self.0 ^ hash)
+    }
+}
+
+fn print_seed(seed: Res<RandomSeed>) {
+    println!("Random seed: {} (pass --seed {} to reproduce this session)", seed.0, seed.0);
+}
+
+pub struct RandomSeedPlugin;
+
+impl Plugin for RandomSeedPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<RandomSeed>()
+            .add_systems(Startup, print_seed);
+    }
+}
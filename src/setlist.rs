@@ -0,0 +1,271 @@
+//! Setlists for live performance: songs played one after another, either advancing on their own after a gap
+//! or waiting for a foot pedal, with a small HUD showing where the set is up to.
+//!
+//! A setlist is a JSON file like
+//! `{ "name": "Spring recital", "advance": "pedal", "gap": 5, "songs": [{ "path": "first.mid" }, { "path": "second.mid", "gap": 10 }] }`.
+//! Song paths are relative to the setlist. `advance` is `auto` (the default) or `pedal`; the pedal is the MIDI controller
+//! `advance_controller` (the soft pedal by default) or a page turner set to send N, which both give the next song
+//! command, as does the command palette. The pedal can also cut a gap short.
+//!
+//! With `"qr_code": true`, a QR code beside the keyboard links the audience to each song's `url`, falling back to the
+//! setlist's own `url`, such as the performer's channel. See `qr`.
+
+pub mod qr;
+
+use std::{error::Error, fs, path::{Path, PathBuf}};
+
+use bevy::{app::{App, Plugin, Startup, Update}, color::Color, ecs::{component::Component, event::{EventReader, EventWriter}, query::With, resource::Resource, schedule::{common_conditions::resource_exists, IntoScheduleConfigs}, system::{Commands, Res, ResMut, Single}}, text::{TextColor, TextFont}, time::Time, ui::{widget::Text, BackgroundColor, Node, PositionType, UiRect, Val}, utils::default};
+use serde::Deserialize;
+
+use crate::{command::AppCommand, midi::{bus::{NoteBus, NoteSource}, MidiEventKind}, song::{playback::{self, SongPlayback}, Song}};
+
+/// The soft (una corda) pedal, which songs rarely need.
+const SOFT_PEDAL_CONTROLLER: u8 = 67;
+const HUD_FONT_SIZE: f32 = 18.0;
+/// The width of the QR code, in millimeters, which is large enough to scan from a few rows back on a recording.
+const DEFAULT_QR_SIZE: f32 = 120.0;
+
+/// The setlist to perform. Insert this before adding `SetlistPlugin`, e.g. with `--setlist`.
+#[derive(Resource, Clone, Debug)]
+pub struct SetlistFile(pub PathBuf);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Advance {
+    /// Start the next song once the gap after the current one has passed.
+    #[default]
+    Auto,
+    /// Wait for the pedal after each song.
+    Pedal
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SetlistEntry {
+    pub path: PathBuf,
+    /// Seconds of silence after this song, overriding the setlist's gap.
+    pub gap: Option<f64>,
+    /// A page about this song, shown as a QR code while it plays.
+    pub url: Option<String>
+}
+
+fn default_gap() -> f64 {
+    3.0
+}
+
+fn default_advance_controller() -> u8 {
+    SOFT_PEDAL_CONTROLLER
+}
+
+fn default_qr_size() -> f32 {
+    DEFAULT_QR_SIZE
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Setlist {
+    pub name: String,
+    pub songs: Vec<SetlistEntry>,
+    #[serde(default)]
+    pub advance: Advance,
+    /// Seconds between songs.
+    #[serde(default = "default_gap")]
+    pub gap: f64,
+    #[serde(default = "default_advance_controller")]
+    pub advance_controller: u8,
+    /// The link shown for songs without their own, like the performer's channel.
+    pub url: Option<String>,
+    /// Show the current link as a QR code beside the keyboard.
+    #[serde(default)]
+    pub qr_code: bool,
+    /// The width of the QR code, in millimeters.
+    #[serde(default = "default_qr_size")]
+    pub qr_size: f32
+}
+
+impl Setlist {
+    pub fn load(path: &Path) -> Result<Setlist, Box<dyn Error>> {
+        let mut setlist: Setlist = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if setlist.songs.is_empty() {
+            return Err(format!("The {} setlist has no songs", setlist.name).into());
+        }
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for entry in &mut setlist.songs {
+            entry.path = directory.join(&entry.path);
+        }
+        Ok(setlist)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// The song was just loaded, and starts playing next frame, once playback has reset for it.
+    Starting,
+    Playing,
+    /// Waiting between songs until the given time, in seconds since startup.
+    Gap { until: f64 },
+    WaitingForPedal,
+    Finished
+}
+
+#[derive(Resource)]
+pub struct SetlistState {
+    pub setlist: Setlist,
+    /// The index of the current song.
+    pub current: usize,
+    phase: Phase,
+    /// Set to load the current song on the next frame.
+    load_pending: bool
+}
+
+impl SetlistState {
+    /// The link to show for the current song, if the QR code is enabled.
+    pub fn current_url(&self) -> Option<&str> {
+        if !self.setlist.qr_code {
+            return None;
+        }
+        self.setlist.songs[self.current].url.as_deref().or(self.setlist.url.as_deref())
+    }
+}
+
+#[derive(Component)]
+struct SetlistHud;
+
+fn setup(
+    mut commands: Commands,
+    file: Option<Res<SetlistFile>>
+) {
+    let Some(file) = file else { return };
+    let setlist = match Setlist::load(&file.0) {
+        Ok(setlist) => setlist,
+        Err(err) => {
+            eprintln!("Failed to load setlist {}: {}", file.0.display(), err);
+            return;
+        }
+    };
+    println!("Performing the {} setlist ({} songs)", setlist.name, setlist.songs.len());
+
+    commands.insert_resource(SetlistState { setlist, current: 0, phase: Phase::Starting, load_pending: true });
+    // The bottom-right corner, since errors show in the top left and the HUD column in the bottom left
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(8.0),
+            right: Val::Px(8.0),
+            padding: UiRect::axes(Val::Px(8.0), Val::Px(4.0)),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.5)),
+        Text::new(""),
+        TextFont {
+            font_size: HUD_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        SetlistHud
+    ));
+}
+
+/// Sends the next song command when the setlist's pedal is pressed. Page turners are covered by the command's hotkey.
+fn send_pedal_commands(
+    bus: Res<NoteBus>,
+    state: Res<SetlistState>,
+    mut commands: EventWriter<AppCommand>
+) {
+    let controller = state.setlist.advance_controller;
+    if bus.from_source(NoteSource::Live)
+        .any(|event| matches!(event.kind, MidiEventKind::ControlChange { controller: pressed, value } if pressed == controller && value >= 64)) {
+        commands.write(AppCommand::NextSong);
+    }
+}
+
+/// Loads each song in turn, and moves on when it ends and the gap passes or the pedal is pressed.
+fn advance_setlist(
+    time: Res<Time>,
+    mut commands: EventReader<AppCommand>,
+    mut state: ResMut<SetlistState>,
+    mut song: ResMut<Song>,
+    mut playback: ResMut<SongPlayback>
+) {
+    let now = time.elapsed_secs_f64();
+    let pedal_pressed = commands.read().filter(|command| **command == AppCommand::NextSong).count() > 0;
+
+    if state.load_pending {
+        state.load_pending = false;
+        let path = state.setlist.songs[state.current].path.clone();
+        match Song::load_with_metadata(&path) {
+            Ok(loaded) => {
+                println!("Setlist: playing {} ({}/{})", loaded.title, state.current + 1, state.setlist.songs.len());
+                *song = loaded;
+                state.phase = Phase::Starting;
+            }
+            Err(err) => {
+                // Keep the show going with the next song rather than stopping the set
+                eprintln!("Failed to load {} from the setlist: {}", path.display(), err);
+                state.phase = Phase::Gap { until: now };
+            }
+        }
+        return;
+    }
+
+    let ended = !playback.playing && playback.position >= song.duration;
+    let gap = state.setlist.songs[state.current].gap.unwrap_or(state.setlist.gap);
+    let last = state.current + 1 >= state.setlist.songs.len();
+    let phase = state.phase;
+    state.phase = match phase {
+        Phase::Starting => {
+            playback.playing = true;
+            Phase::Playing
+        }
+        Phase::Playing if ended && last => Phase::Finished,
+        Phase::Playing if ended => match state.setlist.advance {
+            Advance::Auto => Phase::Gap { until: now + gap.max(0.0) },
+            Advance::Pedal => Phase::WaitingForPedal
+        },
+        Phase::Gap { until } if now >= until || pedal_pressed => {
+            if last {
+                Phase::Finished
+            } else {
+                state.current += 1;
+                state.load_pending = true;
+                Phase::Gap { until }
+            }
+        }
+        Phase::WaitingForPedal if pedal_pressed => Phase::Gap { until: now },
+        phase => phase
+    };
+}
+
+fn update_setlist_hud(
+    time: Res<Time>,
+    state: Res<SetlistState>,
+    song: Res<Song>,
+    mut hud: Single<&mut Text, With<SetlistHud>>
+) {
+    let setlist = &state.setlist;
+    let position = format!("{} {}/{}", setlist.name, state.current + 1, setlist.songs.len());
+    let next = setlist.songs.get(state.current + 1)
+        .and_then(|entry| entry.path.file_stem())
+        .map(|stem| stem.to_string_lossy().to_string());
+    let status = match (state.phase, next) {
+        (Phase::Finished, _) => "End of set".to_string(),
+        (Phase::Gap { until }, Some(next)) => format!("Next: {} in {:.0}s", next, (until - time.elapsed_secs_f64()).max(0.0).ceil()),
+        (Phase::WaitingForPedal, Some(next)) => format!("Next: {} (press the pedal)", next),
+        (_, Some(next)) => format!("Next: {}", next),
+        (_, None) => "Last song".to_string()
+    };
+    let text = format!("{}: {}\n{}", position, song.title, status);
+    if hud.0 != text {
+        hud.0 = text;
+    }
+}
+
+pub struct SetlistPlugin;
+
+impl Plugin for SetlistPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_systems(Startup, setup)
+            .add_systems(Update, (send_pedal_commands, advance_setlist, update_setlist_hud, qr::update_qr_overlay).chain()
+                .after(playback::advance_playback)
+                .run_if(resource_exists::<SetlistState>));
+    }
+}
@@ -0,0 +1,94 @@
+//! A QR code beside the keyboard linking the audience to the current piece, or to the performer's channel when the piece
+//! has no link of its own. It's on the HUD layer, so it shows on the recorder and spectator outputs but isn't projected
+//! onto the keys.
+
+use std::error::Error;
+
+use bevy::{asset::{Assets, RenderAssetUsages}, color::Color, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, hierarchy::ChildOf, query::With, system::{Commands, Local, Query, Res, ResMut, Single}}, image::Image, math::primitives::Rectangle, pbr::{MeshMaterial3d, StandardMaterial}, render::{mesh::{Mesh, Mesh3d}, render_resource::{Extent3d, TextureDimension, TextureFormat}, view::RenderLayers}, transform::components::Transform};
+use qrcode::QrCode;
+
+use crate::{keyboard::{KeyboardLayout, KeyboardRoot}, render_layers::HUD_LAYER, video::tracking::FadeWithTracking};
+
+use super::SetlistState;
+
+/// The blank border scanners need around the code, in modules.
+const QUIET_ZONE: usize = 4;
+/// The gap between the end of the keyboard and the code, in millimeters.
+const MARGIN: f32 = 20.0;
+
+#[derive(Component)]
+struct QrOverlay;
+
+/// Renders a QR code for the given text as an image, one pixel per module.
+fn qr_image(text: &str) -> Result<Image, Box<dyn Error>> {
+    let code = QrCode::new(text.as_bytes())?;
+    let width = code.width();
+    let size = width + QUIET_ZONE * 2;
+    let modules = code.to_colors();
+
+    let mut data = vec![255; size * size * 4];
+    for (index, module) in modules.iter().enumerate() {
+        if *module != qrcode::Color::Dark {
+            continue;
+        }
+        let (x, y) = (index % width + QUIET_ZONE, index / width + QUIET_ZONE);
+        let offset = (y * size + x) * 4;
+        data[offset..offset + 3].fill(0);
+    }
+
+    let size = Extent3d {
+        width: size as u32,
+        height: size as u32,
+        depth_or_array_layers: 1
+    };
+    Ok(Image::new(size, TextureDimension::D2, data, TextureFormat::Rgba8UnormSrgb, RenderAssetUsages::default()))
+}
+
+/// Regenerates the code when the setlist moves to a piece with a different link, and moves it with the keyboard layout.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn update_qr_overlay(
+    mut commands: Commands,
+    state: Res<SetlistState>,
+    layout: Res<KeyboardLayout>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    existing: Query<Entity, With<QrOverlay>>,
+    mut shown: Local<Option<String>>,
+    mut images: ResMut<Assets<Image>>,
+    assets: (ResMut<Assets<Mesh>>, ResMut<Assets<StandardMaterial>>)
+) {
+    let url = state.current_url().map(str::to_string);
+    if url == *shown && !layout.is_changed() {
+        return;
+    }
+    *shown = url.clone();
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let Some(url) = url else { return };
+    let image = match qr_image(&url) {
+        Ok(image) => image,
+        Err(err) => {
+            eprintln!("Failed to generate a QR code for {}: {}", url, err);
+            return;
+        }
+    };
+
+    let (mut meshes, mut materials) = assets;
+    let size = state.setlist.qr_size;
+    commands.spawn((
+        Mesh3d(meshes.add(Rectangle::new(size, size))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::WHITE,
+            base_color_texture: Some(images.add(image)),
+            unlit: true,
+            ..Default::default()
+        })),
+        // Standing up just past the highest key, facing the player like the waterfall
+        Transform::from_xyz(layout.width() / 2.0 + MARGIN + size / 2.0, size / 2.0, layout.geometry.key_back_z),
+        RenderLayers::layer(HUD_LAYER),
+        QrOverlay,
+        FadeWithTracking,
+        ChildOf(*root)
+    ));
+}
@@ -0,0 +1,597 @@
+//! A dockable egui panel for changing settings at runtime and watching live diagnostics. Toggle it with F1.
+
+use bevy::{app::{App, Plugin, Startup, Update}, diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin}, ecs::{event::EventReader, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use crate::{audio::{self, SynthSettings}, background::{framing::{AutoFramingSettings, FitMode}, light_estimation::LightEstimationSettings, occlusion::HandOcclusionSettings, replacement::{BackgroundReplacementSettings, ReplacementMode}, style::{BackgroundStyleSettings, StyleMode}, undistort::UndistortSettings}, chord, command::{self, AppCommand}, diagnostics::{memory::MemoryTracker, RecentErrors}, dual_output::DualOutputSettings, keyboard::{clutter::ClutterSettings, guide::{ChordShape, GuideMode, KeyGuideSettings, Scale}, hands::{HandDetection, HandSplitSettings}, shadow_catcher::ShadowCatcherSettings, theme::{self, ColorMode, Theme, ThemeSettings}, trails::{NoteTrailSettings, MIN_TRAIL_SPEED}, zones::RegisterZoneSettings}, midi::{self, latency::{LatencyCompensation, LatencyMeasurement}, output::{self, MidiOutputSettings}, recorder::MidiRecorder, MidiInputSettings}, practice::PracticeSettings, song::{fingering::{FingerHintMode, FingerHintSettings}, playback::{self, SongPlayback}, waterfall::{self, WaterfallSettings}, Hand}, video::{aruco_camera::{ArucoTrackingData, DetectionSettings, FiducialLayout, MarkerDictionary, PnpMethod}, av_sync::AvSyncCalibration, gpu_prefilter::GpuPrefilterSettings, tracking::{TrackingSettings, TrackingState}, CaptureConnection, DropPolicy, FrameQueueSettings, VideoSource}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanelSide {
+    Left,
+    Right
+}
+
+#[derive(Resource)]
+pub struct SettingsPanel {
+    pub visible: bool,
+    pub side: PanelSide,
+    /// The video source being edited. Only applied when the user presses Apply, since opening a source blocks.
+    source_path: String,
+    source_is_file: bool,
+    source_looping: bool,
+    /// Listing ports opens the MIDI backend, so only refresh when asked.
+    midi_ports: Vec<String>,
+    midi_output_ports: Vec<String>,
+    soundfonts: Vec<String>,
+    themes: Vec<String>
+}
+
+impl Default for SettingsPanel {
+    fn default() -> Self {
+        Self {
+            visible: true,
+            side: PanelSide::Right,
+            source_path: String::new(),
+            source_is_file: false,
+            source_looping: true,
+            midi_ports: Vec::new(),
+            midi_output_ports: Vec::new(),
+            soundfonts: Vec::new(),
+            themes: Vec::new()
+        }
+    }
+}
+
+fn setup(
+    mut panel: ResMut<SettingsPanel>,
+    source: Res<VideoSource>
+) {
+    panel.source_path = source.path().to_string();
+    if let VideoSource::File { looping, .. } = *source {
+        panel.source_is_file = true;
+        panel.source_looping = looping;
+    }
+    panel.midi_ports = midi::available_midi_ports();
+    panel.midi_output_ports = output::available_midi_output_ports();
+    panel.soundfonts = audio::available_soundfonts();
+    panel.themes = theme::available_themes();
+}
+
+fn toggle_settings_panel(
+    mut commands: EventReader<AppCommand>,
+    mut panel: ResMut<SettingsPanel>
+) {
+    for command in commands.read() {
+        if *command == AppCommand::ToggleSettingsPanel {
+            panel.visible = !panel.visible;
+        }
+    }
+}
+
+/// Settings are cloned, edited, and only written back if a widget changed,
+/// so systems that react to changes (like reopening the video source) don't run every frame.
+#[allow(clippy::too_many_arguments)]
+fn draw_settings_panel(
+    mut contexts: EguiContexts,
+    mut panel: ResMut<SettingsPanel>,
+    video: (ResMut<VideoSource>, ResMut<FrameQueueSettings>),
+    detection: (ResMut<DetectionSettings>, ResMut<FiducialLayout>, ResMut<GpuPrefilterSettings>),
+    mut tracking_settings: ResMut<TrackingSettings>,
+    mut replacement_settings: ResMut<BackgroundReplacementSettings>,
+    mut style_settings: ResMut<BackgroundStyleSettings>,
+    mut framing_settings: ResMut<AutoFramingSettings>,
+    compositing: (ResMut<HandOcclusionSettings>, ResMut<ShadowCatcherSettings>, ResMut<LightEstimationSettings>, ResMut<UndistortSettings>),
+    midi: (ResMut<MidiInputSettings>, ResMut<MidiOutputSettings>, ResMut<MidiRecorder>),
+    mut dual_output_settings: ResMut<DualOutputSettings>,
+    song: (ResMut<SongPlayback>, ResMut<WaterfallSettings>, ResMut<NoteTrailSettings>, ResMut<FingerHintSettings>, ResMut<PracticeSettings>, ResMut<ClutterSettings>),
+    mut synth_settings: ResMut<SynthSettings>,
+    colors: (ResMut<ThemeSettings>, ResMut<Theme>, ResMut<HandSplitSettings>, ResMut<KeyGuideSettings>, ResMut<RegisterZoneSettings>),
+    latency: (ResMut<LatencyMeasurement>, ResMut<LatencyCompensation>, ResMut<AvSyncCalibration>),
+    diagnostics: (Res<DiagnosticsStore>, Res<CaptureConnection>, Res<ArucoTrackingData>, Res<TrackingState>, Res<RecentErrors>, Res<MemoryTracker>)
+) {
+    if !panel.visible {
+        return;
+    }
+    let (mut source, mut frame_queue_settings) = video;
+    let (mut detection_settings, mut fiducial_layout, mut prefilter_settings) = detection;
+    let (mut occlusion_settings, mut shadow_settings, mut light_estimation_settings, mut undistort_settings) = compositing;
+    let (mut midi_settings, mut midi_output_settings, mut midi_recorder) = midi;
+    let (mut song_playback, mut waterfall_settings, mut trail_settings, mut finger_hint_settings, mut practice_settings, mut clutter_settings) = song;
+    let (mut theme_settings, mut active_theme, mut hand_settings, mut guide_settings, mut zone_settings) = colors;
+    let (mut latency_measurement, mut latency_compensation, mut av_sync_calibration) = latency;
+    let (diagnostics_store, capture_connection, tracking_data, tracking_state, recent_errors, memory_tracker) = diagnostics;
+    let side = panel.side;
+
+    let panel_contents = |ui: &mut egui::Ui| {
+        let panel = panel.as_mut();
+        ui.horizontal(|ui| {
+            ui.heading("Settings");
+            let dock_label = match panel.side {
+                PanelSide::Left => "Dock right",
+                PanelSide::Right => "Dock left"
+            };
+            if ui.small_button(dock_label).clicked() {
+                panel.side = match panel.side {
+                    PanelSide::Left => PanelSide::Right,
+                    PanelSide::Right => PanelSide::Left
+                };
+            }
+        });
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::CollapsingHeader::new("Video source").default_open(true).show(ui, |ui| {
+                ui.checkbox(&mut panel.source_is_file, "Video file");
+                ui.text_edit_singleline(&mut panel.source_path);
+                if panel.source_is_file {
+                    ui.checkbox(&mut panel.source_looping, "Loop");
+                }
+                if ui.button("Apply").clicked() {
+                    *source = if panel.source_is_file {
+                        VideoSource::File { path: panel.source_path.clone(), looping: panel.source_looping }
+                    } else {
+                        VideoSource::Stream(panel.source_path.clone())
+                    };
+                }
+                let mut frame_queue = frame_queue_settings.clone();
+                egui::ComboBox::from_label("When behind")
+                    .selected_text(format!("{:?}", frame_queue.policy))
+                    .show_ui(ui, |ui| {
+                        for policy in DropPolicy::ALL {
+                            ui.selectable_value(&mut frame_queue.policy, policy, format!("{:?}", policy));
+                        }
+                    })
+                    .response
+                    .on_hover_text("KeepLatest drops frames for the lowest latency; KeepAll shows every frame, late if need be");
+                ui.add(egui::Slider::new(&mut frame_queue.capacity, 1..=30).text("Frame queue"));
+                ui.checkbox(&mut frame_queue.keep_all_while_recording, "Keep every frame while recording");
+                if frame_queue != *frame_queue_settings {
+                    *frame_queue_settings = frame_queue;
+                }
+            });
+
+            egui::CollapsingHeader::new("Detection").default_open(true).show(ui, |ui| {
+                let mut detection = detection_settings.clone();
+                let mut changed = false;
+                changed |= ui.add(egui::Slider::new(&mut detection.scale, 0.1..=1.0).text("Scale")).changed();
+                changed |= ui.add(egui::Slider::new(&mut detection.interval, 1..=10).text("Every N frames")).changed();
+                changed |= ui.checkbox(&mut detection.marker_axes, "Per-marker axes").on_hover_text("Draws each marker's own pose next to where the layout places it").changed();
+                changed |= ui.checkbox(&mut detection.subpixel_refinement, "Subpixel corners").on_hover_text("Refines the detected corners on the full-resolution frame, which reduces jitter").changed();
+                if detection.subpixel_refinement {
+                    changed |= ui.add(egui::Slider::new(&mut detection.subpixel_window, 2..=10).text("Refinement window (px)")).changed();
+                }
+                changed |= ui.checkbox(&mut detection.time_slicing, "Time-sliced detection").on_hover_text("On very wide frames, scans one strip per detection plus the areas around tracked markers").changed();
+                if detection.time_slicing {
+                    changed |= ui.add(egui::Slider::new(&mut detection.slices, 2..=4).text("Strips")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut detection.slice_min_width, 640..=7680).text("From width (px)")).changed();
+                }
+                let format_error = |error: Option<f64>| error.map(|error| format!("{:.2} px", error)).unwrap_or_else(|| "-".to_string());
+                ui.label(format!("Reprojection error: {} (average {})", format_error(tracking_data.reprojection_error()), format_error(tracking_data.average_reprojection_error())));
+                let pnp = &mut detection.pnp;
+                for (label, method) in [("Single marker solver", &mut pnp.single_marker_method), ("Board solver", &mut pnp.board_method)] {
+                    egui::ComboBox::from_label(label)
+                        .selected_text(format!("{:?}", method))
+                        .show_ui(ui, |ui| {
+                            for option in PnpMethod::ALL {
+                                changed |= ui.selectable_value(method, option, format!("{:?}", option)).changed();
+                            }
+                        });
+                }
+                changed |= ui.checkbox(&mut pnp.ransac, "RANSAC").on_hover_text("Rejects outlying corners when several markers are detected").changed();
+                ui.add_enabled_ui(pnp.ransac, |ui| {
+                    changed |= ui.add(egui::Slider::new(&mut pnp.ransac_iterations, 10..=500).text("RANSAC iterations")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut pnp.ransac_reprojection_error, 1.0..=20.0).text("Inlier threshold (px)")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut pnp.ransac_confidence, 0.9..=0.999).text("RANSAC confidence")).changed();
+                });
+                if changed {
+                    *detection_settings = detection;
+                }
+                let mut dictionary = fiducial_layout.dictionary;
+                egui::ComboBox::from_label("Marker dictionary")
+                    .selected_text(format!("{:?}", dictionary))
+                    .show_ui(ui, |ui| {
+                        for option in MarkerDictionary::ALL {
+                            ui.selectable_value(&mut dictionary, option, format!("{:?}", option));
+                        }
+                    });
+                if dictionary != fiducial_layout.dictionary {
+                    fiducial_layout.dictionary = dictionary;
+                }
+                let mut prefilter = prefilter_settings.clone();
+                ui.checkbox(&mut prefilter.enabled, "GPU prefilter").on_hover_text("Finds likely marker areas on the GPU, so detection only searches those");
+                if prefilter.enabled {
+                    ui.add(egui::Slider::new(&mut prefilter.tile_size, 16..=128).text("Tile size (px)"));
+                    ui.add(egui::Slider::new(&mut prefilter.min_contrast, 0.05..=1.0).text("Min contrast"));
+                }
+                if prefilter != *prefilter_settings {
+                    *prefilter_settings = prefilter;
+                }
+            });
+
+            egui::CollapsingHeader::new("Tracking").show(ui, |ui| {
+                let mut tracking = tracking_settings.clone();
+                let mut changed = false;
+                changed |= ui.add(egui::Slider::new(&mut tracking.min_markers, 1..=4).text("Min markers")).changed();
+                changed |= ui.add(egui::Slider::new(&mut tracking.degraded_after, 0.0..=2.0).text("Degraded after (s)")).changed();
+                changed |= ui.add(egui::Slider::new(&mut tracking.lost_after, 0.0..=5.0).text("Lost after (s)")).changed();
+                changed |= ui.checkbox(&mut tracking.fade_when_lost, "Fade when lost").changed();
+                changed |= ui.checkbox(&mut tracking.show_quality, "Show tracking quality").changed();
+                changed |= ui.add(egui::Slider::new(&mut tracking.good_error, 0.1..=5.0).text("Good below (px)")).changed();
+                changed |= ui.add(egui::Slider::new(&mut tracking.poor_error, 0.5..=10.0).text("Poor above (px)")).changed();
+                changed |= ui.checkbox(&mut tracking.adaptive_smoothing, "Adaptive smoothing").on_hover_text("Steadies the pose while the camera is still, without lagging behind when it moves").changed();
+                if tracking.adaptive_smoothing {
+                    changed |= ui.add(egui::Slider::new(&mut tracking.still_cutoff, 0.1..=5.0).text("Still cutoff (Hz)")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut tracking.moving_cutoff, 1.0..=30.0).text("Moving cutoff (Hz)")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut tracking.moving_speed, 10.0..=1000.0).text("Moving above (mm/s)")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut tracking.moving_angular_speed, 1.0..=180.0).text("Moving above (°/s)")).changed();
+                }
+                if changed {
+                    *tracking_settings = tracking;
+                }
+            });
+
+            egui::CollapsingHeader::new("Background").show(ui, |ui| {
+                let mut replacement = replacement_settings.clone();
+                let mut style = style_settings.clone();
+                egui::ComboBox::from_label("Replacement")
+                    .selected_text(format!("{:?}", replacement.mode))
+                    .show_ui(ui, |ui| {
+                        for mode in ReplacementMode::ALL {
+                            ui.selectable_value(&mut replacement.mode, mode, format!("{:?}", mode));
+                        }
+                    });
+                egui::ComboBox::from_label("Style")
+                    .selected_text(format!("{:?}", style.mode))
+                    .show_ui(ui, |ui| {
+                        for mode in StyleMode::ALL {
+                            ui.selectable_value(&mut style.mode, mode, format!("{:?}", mode));
+                        }
+                    });
+                let mut undistort = undistort_settings.clone();
+                if ui.checkbox(&mut undistort.enabled, "Undistort the camera feed").on_hover_text("Removes lens distortion using the calibration, so overlays line up near the edges").changed() {
+                    *undistort_settings = undistort;
+                }
+                let mut framing = framing_settings.clone();
+                egui::ComboBox::from_label("Fit")
+                    .selected_text(format!("{:?}", framing.fit))
+                    .show_ui(ui, |ui| {
+                        for fit in FitMode::ALL {
+                            ui.selectable_value(&mut framing.fit, fit, format!("{:?}", fit));
+                        }
+                    });
+                ui.checkbox(&mut framing.enabled, "Auto-frame the keyboard");
+                if framing != *framing_settings {
+                    *framing_settings = framing;
+                }
+                let mut occlusion = occlusion_settings.clone();
+                let mut changed = ui.checkbox(&mut occlusion.enabled, "Hands occlude notes").on_hover_text("Masks out virtual content behind skin-colored pixels").changed();
+                if occlusion.enabled {
+                    changed |= ui.checkbox(&mut occlusion.keyboard_only, "Only over the keyboard").changed();
+                    changed |= ui.add(egui::Slider::new(&mut occlusion.min_cr, 0.0..=255.0).text("Min Cr")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut occlusion.max_cr, 0.0..=255.0).text("Max Cr")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut occlusion.min_cb, 0.0..=255.0).text("Min Cb")).changed();
+                    changed |= ui.add(egui::Slider::new(&mut occlusion.max_cb, 0.0..=255.0).text("Max Cb")).changed();
+                }
+                if changed {
+                    *occlusion_settings = occlusion;
+                }
+                let mut shadows = shadow_settings.clone();
+                let mut changed = ui.checkbox(&mut shadows.enabled, "Shadows on the keys").changed();
+                if shadows.enabled {
+                    changed |= ui.add(egui::Slider::new(&mut shadows.strength, 0.0..=1.0).text("Shadow strength")).changed();
+                }
+                if changed {
+                    *shadow_settings = shadows;
+                }
+                let mut light_estimation = light_estimation_settings.clone();
+                if ui.checkbox(&mut light_estimation.enabled, "Match room lighting").on_hover_text("Sets the virtual lights' brightness, color, and direction from the camera feed").changed() {
+                    *light_estimation_settings = light_estimation;
+                }
+                if replacement.mode != replacement_settings.mode {
+                    *replacement_settings = replacement;
+                }
+                if style.mode != style_settings.mode {
+                    *style_settings = style;
+                }
+            });
+
+            egui::CollapsingHeader::new("Outputs").show(ui, |ui| {
+                let mut dual_output = dual_output_settings.clone();
+                if ui.checkbox(&mut dual_output.enabled, "Clean and overlay feed windows").changed() {
+                    *dual_output_settings = dual_output;
+                }
+            });
+
+            egui::CollapsingHeader::new("Song").show(ui, |ui| {
+                let mut speed = song_playback.speed;
+                if ui.add(egui::Slider::new(&mut speed, playback::MIN_SPEED..=playback::MAX_SPEED).text("Speed")).changed() {
+                    song_playback.speed = speed;
+                }
+                let mut count_in_beats = song_playback.count_in_beats;
+                if ui.add(egui::Slider::new(&mut count_in_beats, 0..=8).text("Loop count-in (beats)")).changed() {
+                    song_playback.count_in_beats = count_in_beats;
+                }
+                let mut wait_for_input = practice_settings.wait_for_input;
+                if ui.checkbox(&mut wait_for_input, "Wait for each chord when practicing").on_hover_text("Playback waits until the chord's keys are played, instead of counting them missed").changed() {
+                    practice_settings.wait_for_input = wait_for_input;
+                }
+                let mut waterfall = waterfall_settings.clone();
+                ui.add(egui::Slider::new(&mut waterfall.look_ahead, waterfall::LOOK_AHEAD).text("Look-ahead (s)"));
+                ui.checkbox(&mut waterfall.beat_lines, "Measure and beat lines");
+                if waterfall != *waterfall_settings {
+                    *waterfall_settings = waterfall;
+                }
+                let mut trails = trail_settings.clone();
+                ui.checkbox(&mut trails.enabled, "Note trails").on_hover_text("Played notes rise from their keys and fade, like a waterfall in reverse");
+                ui.add_enabled(trails.enabled, egui::Slider::new(&mut trails.speed, MIN_TRAIL_SPEED..=600.0).text("Trail speed (mm/s)"));
+                if trails != *trail_settings {
+                    *trail_settings = trails;
+                }
+                let mut clutter = clutter_settings.clone();
+                ui.checkbox(&mut clutter.enabled, "Thin out dense passages").on_hover_text("Merges repeated notes and dims trails in tremolos and glissandi");
+                ui.add_enabled(clutter.enabled, egui::Slider::new(&mut clutter.max_notes_per_second, 5.0..=100.0).text("Max notes per second"));
+                if clutter != *clutter_settings {
+                    *clutter_settings = clutter;
+                }
+                let mut finger_hints = finger_hint_settings.mode;
+                ui.horizontal(|ui| {
+                    ui.label("Finger numbers").on_hover_text("From the song's .synthesia, .fingering.json, or .musicxml file");
+                    ui.radio_value(&mut finger_hints, FingerHintMode::Off, "Off");
+                    ui.radio_value(&mut finger_hints, FingerHintMode::Practice, "While practicing");
+                    ui.radio_value(&mut finger_hints, FingerHintMode::Always, "Always");
+                });
+                if finger_hints != finger_hint_settings.mode {
+                    finger_hint_settings.mode = finger_hints;
+                }
+            });
+
+            egui::CollapsingHeader::new("Colors").show(ui, |ui| {
+                let mut path = theme_settings.path.clone();
+                egui::ComboBox::from_label("Theme")
+                    .selected_text(path.as_deref().map_or("Default".to_string(), |_| active_theme.name.clone()))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut path, None, "Default");
+                        for theme_path in &panel.themes {
+                            ui.selectable_value(&mut path, Some(theme_path.clone()), theme_path);
+                        }
+                    });
+                if ui.button("Refresh themes").on_hover_text(format!("Lists the .json files in {}", theme::THEME_DIR)).clicked() {
+                    panel.themes = theme::available_themes();
+                }
+                if path != theme_settings.path {
+                    theme_settings.path = path;
+                }
+                if active_theme.mode == ColorMode::Hand {
+                    let mut split_key = active_theme.split_key;
+                    if ui.add(egui::Slider::new(&mut split_key, 0..=127).text("Split key")).on_hover_text("Notes below this key are the left hand's, unless the song says otherwise").changed() {
+                        active_theme.split_key = split_key;
+                    }
+                }
+
+                ui.separator();
+                let mut hands = hand_settings.clone();
+                ui.checkbox(&mut hands.enabled, "Color by hand").on_hover_text("Colors the waterfall and key highlights by the hand playing each note, over the theme");
+                ui.add_enabled_ui(hands.enabled, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut hands.detection, HandDetection::Split, "Split key");
+                        ui.radio_value(&mut hands.detection, HandDetection::Channel, "Left hand channel");
+                    });
+                    let detection = match hands.detection {
+                        HandDetection::Split => ui.add(egui::Slider::new(&mut hands.split_key, 0..=127).text("Split key")),
+                        HandDetection::Channel => ui.add(egui::Slider::new(&mut hands.left_channel, 0..=15).text("Left hand channel"))
+                    };
+                    detection.on_hover_text("Used for notes the song doesn't give a hand");
+                    ui.horizontal(|ui| {
+                        ui.label("Practice");
+                        ui.radio_value(&mut hands.focus, None, "Both hands");
+                        ui.radio_value(&mut hands.focus, Some(Hand::Left), "Left");
+                        ui.radio_value(&mut hands.focus, Some(Hand::Right), "Right");
+                    });
+                });
+                if hands != *hand_settings {
+                    *hand_settings = hands;
+                }
+
+                ui.separator();
+                let mut zones = zone_settings.clone();
+                ui.checkbox(&mut zones.enabled, "Register zones").on_hover_text("Marks the zones behind the keys, colors notes the theme doesn't by zone, and scales their trails by its intensity");
+                ui.add_enabled_ui(zones.enabled, |ui| {
+                    for zone in &mut zones.zones {
+                        let label = format!("{} ({}-{})", zone.name, zone.lowest_key, zone.highest_key);
+                        ui.add(egui::Slider::new(&mut zone.intensity, 0.0..=3.0).text(label));
+                    }
+                });
+                if zones != *zone_settings {
+                    *zone_settings = zones;
+                }
+            });
+
+            egui::CollapsingHeader::new(format!("Scale and chord guide: {}", guide_settings.describe())).id_salt("guide").show(ui, |ui| {
+                let mut guide = guide_settings.clone();
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut guide.mode, GuideMode::Off, "Off");
+                    ui.radio_value(&mut guide.mode, GuideMode::Scale, "Scale");
+                    ui.radio_value(&mut guide.mode, GuideMode::Chord, "Chord");
+                });
+                ui.add_enabled_ui(guide.mode != GuideMode::Off, |ui| {
+                    egui::ComboBox::from_label("Root")
+                        .selected_text(chord::PITCH_CLASS_NAMES[guide.root as usize % 12])
+                        .show_ui(ui, |ui| {
+                            for (pitch_class, name) in chord::PITCH_CLASS_NAMES.iter().enumerate() {
+                                ui.selectable_value(&mut guide.root, pitch_class as u8, *name);
+                            }
+                        });
+                    match guide.mode {
+                        GuideMode::Chord => {
+                            egui::ComboBox::from_label("Chord")
+                                .selected_text(guide.chord.label())
+                                .show_ui(ui, |ui| {
+                                    for shape in ChordShape::ALL {
+                                        ui.selectable_value(&mut guide.chord, shape, shape.label());
+                                    }
+                                });
+                            ui.add(egui::Slider::new(&mut guide.octave, 0..=8).text("Octave")).on_hover_text("Octave 4 starts at middle C");
+                        }
+                        _ => {
+                            egui::ComboBox::from_label("Scale")
+                                .selected_text(guide.scale.label())
+                                .show_ui(ui, |ui| {
+                                    for scale in Scale::ALL {
+                                        ui.selectable_value(&mut guide.scale, scale, scale.label());
+                                    }
+                                });
+                        }
+                    }
+                });
+                if guide != *guide_settings {
+                    *guide_settings = guide;
+                }
+            });
+
+            egui::CollapsingHeader::new("Synthesizer").show(ui, |ui| {
+                let mut synth = synth_settings.clone();
+                ui.checkbox(&mut synth.enabled, "Play through a soundfont");
+                egui::ComboBox::from_label("Soundfont")
+                    .selected_text(synth.soundfont.as_deref().unwrap_or("None"))
+                    .show_ui(ui, |ui| {
+                        for soundfont in &panel.soundfonts {
+                            ui.selectable_value(&mut synth.soundfont, Some(soundfont.clone()), soundfont);
+                        }
+                    });
+                if ui.button("Refresh soundfonts").on_hover_text(format!("Lists the .sf2 files in {}", audio::SOUNDFONT_DIR)).clicked() {
+                    panel.soundfonts = audio::available_soundfonts();
+                }
+                ui.checkbox(&mut synth.play_song, "Play the song");
+                ui.checkbox(&mut synth.play_live_input, "Play the keyboard");
+                ui.add(egui::Slider::new(&mut synth.volume, 0.0..=1.0).text("Volume"));
+                egui::CollapsingHeader::new("Programs").show(ui, |ui| {
+                    for (channel, program) in synth.programs.iter_mut().enumerate() {
+                        ui.horizontal(|ui| {
+                            let mut fixed = program.is_some();
+                            if ui.checkbox(&mut fixed, format!("Channel {}", channel)).changed() {
+                                *program = fixed.then_some(0);
+                            }
+                            if let Some(program) = program {
+                                ui.add(egui::DragValue::new(program).range(0..=127));
+                            }
+                        });
+                    }
+                });
+                if synth != *synth_settings {
+                    *synth_settings = synth;
+                }
+            });
+
+            egui::CollapsingHeader::new("MIDI input").show(ui, |ui| {
+                let mut port_name = midi_settings.port_name.clone();
+                egui::ComboBox::from_label("Device")
+                    .selected_text(port_name.as_deref().unwrap_or("First available"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut port_name, None, "First available");
+                        for port in &panel.midi_ports {
+                            ui.selectable_value(&mut port_name, Some(port.clone()), port);
+                        }
+                    });
+                if ui.button("Refresh devices").clicked() {
+                    panel.midi_ports = midi::available_midi_ports();
+                }
+                if port_name != midi_settings.port_name {
+                    midi_settings.port_name = port_name;
+                }
+
+                ui.separator();
+                ui.label(format!("Audio latency: {:.1} ms", latency_compensation.audio_output * 1000.0))
+                    .on_hover_text("Practice expects key presses this much early, so the notes sound on the beat");
+                if latency_measurement.is_running() {
+                    ui.label(format!("Measuring... {} notes heard", latency_measurement.trials.len()));
+                } else if ui.button("Measure with loopback").on_hover_text("Plays notes on the MIDI output and listens for them on the audio input").clicked() {
+                    latency_measurement.start(midi_settings.port_name.clone());
+                }
+
+                let mut video_delay = latency_compensation.video * 1000.0;
+                if ui.add(egui::Slider::new(&mut video_delay, 0.0..=500.0).text("Video delay (ms)")).on_hover_text("Delays the waterfall so notes land when the keys are seen going down").changed() {
+                    latency_compensation.video = video_delay / 1000.0;
+                }
+                if av_sync_calibration.is_running() {
+                    ui.label(format!("Strike single keys sharply... {} presses seen", av_sync_calibration.trials.len()));
+                    if ui.button("Cancel").clicked() {
+                        av_sync_calibration.cancel();
+                    }
+                } else if ui.button("Calibrate video delay").on_hover_text("Times how long key presses take to show up in the camera image").clicked() {
+                    av_sync_calibration.start();
+                }
+
+                ui.separator();
+                if midi_recorder.recording {
+                    if ui.button(format!("Stop recording ({} events)", midi_recorder.recorded_events())).clicked() {
+                        midi_recorder.recording = false;
+                    }
+                } else if ui.button("Record to a MIDI file").on_hover_text("Saves what's played to recordings/take-<time>.mid. F12 also starts and stops it").clicked() {
+                    midi_recorder.recording = true;
+                }
+            });
+
+            egui::CollapsingHeader::new("MIDI output").show(ui, |ui| {
+                let mut midi_output = midi_output_settings.clone();
+                ui.checkbox(&mut midi_output.enabled, "Play the song on the instrument").on_hover_text("Sends the song to the MIDI output as it plays, to listen before practicing");
+                egui::ComboBox::from_label("Output device")
+                    .selected_text(midi_output.port_name.as_deref().unwrap_or("First available"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut midi_output.port_name, None, "First available");
+                        for port in &panel.midi_output_ports {
+                            ui.selectable_value(&mut midi_output.port_name, Some(port.clone()), port);
+                        }
+                    });
+                if ui.button("Refresh output devices").clicked() {
+                    panel.midi_output_ports = output::available_midi_output_ports();
+                }
+                if midi_output != *midi_output_settings {
+                    *midi_output_settings = midi_output;
+                }
+            });
+
+            egui::CollapsingHeader::new("Diagnostics").default_open(true).show(ui, |ui| {
+                let fps = diagnostics_store.get(&FrameTimeDiagnosticsPlugin::FPS).and_then(|fps| fps.smoothed());
+                ui.label(format!("FPS: {}", fps.map(|fps| format!("{:.1}", fps)).unwrap_or_else(|| "-".to_string())));
+                ui.label(format!("Camera: {}", capture_connection.status()));
+                if let Some(mode) = &capture_connection.mode {
+                    ui.label(format!("Capture mode: {}", mode));
+                }
+                let (queued, capacity) = capture_connection.queued_frames();
+                ui.label(format!("Dropped frames: {} (queued {}/{}, {:?})", capture_connection.dropped_frames(), queued, capacity, capture_connection.drop_policy()));
+                ui.label(format!("Tracking: {:?}", *tracking_state));
+                ui.label(format!("Markers detected: {}", tracking_data.detected_count()));
+                ui.label(format!("Rejected candidates: {}", tracking_data.rejected_count()));
+                ui.label(format!("Reprojection error: {}", tracking_data.reprojection_details().map(|error| format!("{:.2} px RMS, {:.2} px max over {} corners", error.rms, error.max, error.corners)).unwrap_or_else(|| "-".to_string())));
+                ui.label(format!("Tracked memory: {:.1} MiB", memory_tracker.total_current() as f64 / (1024.0 * 1024.0)));
+                ui.label(format!("Errors: {}", recent_errors.total));
+                if let Some((_, message)) = recent_errors.errors.back() {
+                    ui.label(egui::RichText::new(message).small());
+                }
+            });
+        });
+    };
+
+    let ctx = contexts.ctx_mut();
+    match side {
+        PanelSide::Left => egui::SidePanel::left("settings_panel").resizable(true).show(ctx, panel_contents),
+        PanelSide::Right => egui::SidePanel::right("settings_panel").resizable(true).show(ctx, panel_contents)
+    };
+}
+
+pub struct SettingsPanelPlugin;
+
+impl Plugin for SettingsPanelPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+        if !app.is_plugin_added::<FrameTimeDiagnosticsPlugin>() {
+            app.add_plugins(FrameTimeDiagnosticsPlugin::default());
+        }
+
+        app
+            .init_resource::<SettingsPanel>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (toggle_settings_panel, draw_settings_panel).chain().after(command::run_app_commands));
+    }
+}
@@ -0,0 +1,282 @@
+//! Setup bundles: one zip file with everything a working setup needs, for moving it to another machine or sharing
+//! it with someone who has the same camera and markers. `--export-setup` writes one and `--import-setup` installs one.
+//!
+//! A bundle holds:
+//! - `manifest.json`: the bundle format version, and the app version and time it was made with.
+//! - `config.toml`: the config, with its fiducial layout path pointed at the bundled layout.
+//! - `calibration.json`: the camera calibration.
+//! - `fiducials.json`: the fiducial layout, if there's a layout file.
+//! - `markers/*.pdf`: the printable marker sheets from `MARKER_SHEET_DIR`.
+//!
+//! Everything is checked before anything is installed, and files that would be overwritten with different contents
+//! are kept with a `.bak` suffix.
+
+use std::{error::Error, fs, io::{Read, Seek, Write}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde_json::json;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{config::{Config, CONFIG_PATH}, video::{aruco_camera::{CalibrationFile, FiducialLayout}, layout_tuning::DEFAULT_LAYOUT_PATH}};
+
+/// The version of the bundle layout. Bundles from newer versions are refused rather than half understood.
+const BUNDLE_FORMAT: u64 = 1;
+/// Where the printable marker sheets are kept.
+static MARKER_SHEET_DIR: &str = "resources";
+static MANIFEST_ENTRY: &str = "manifest.json";
+static CONFIG_ENTRY: &str = "config.toml";
+static CALIBRATION_ENTRY: &str = "calibration.json";
+static LAYOUT_ENTRY: &str = "fiducials.json";
+static MARKER_SHEET_PREFIX: &str = "markers/";
+
+/// The files of a setup, read from disk or from a bundle.
+#[derive(Debug, Default, PartialEq)]
+pub struct SetupBundle {
+    config: Option<String>,
+    calibration: Option<Vec<u8>>,
+    layout: Option<Vec<u8>>,
+    /// The file names and contents of the marker sheets.
+    marker_sheets: Vec<(String, Vec<u8>)>
+}
+
+/// Reads a file if it exists.
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("Failed to read {}: {}", path.display(), err).into())
+    }
+}
+
+/// Points the config's fiducial layout at `DEFAULT_LAYOUT_PATH`, where the bundled layout is installed. The config is
+/// only rewritten, losing its comments, if it names a different layout file.
+fn point_layout_at_bundle(config: &str) -> Result<String, Box<dyn Error>> {
+    let mut table: toml::Table = config.parse()?;
+    let Some(fiducials) = table.get_mut("fiducials").and_then(|fiducials| fiducials.as_table_mut()) else { return Ok(config.to_string()) };
+    match fiducials.get("layout").and_then(|layout| layout.as_str()) {
+        Some(layout) if layout != DEFAULT_LAYOUT_PATH => {
+            fiducials.insert("layout".to_string(), toml::Value::String(DEFAULT_LAYOUT_PATH.to_string()));
+            Ok(toml::to_string(&table)?)
+        }
+        _ => Ok(config.to_string())
+    }
+}
+
+/// Whether a zip entry is a marker sheet, and its file name if so. Anything with a directory in its name is left out,
+/// so a bundle can't write outside `MARKER_SHEET_DIR`.
+fn marker_sheet_name(entry: &str) -> Option<&str> {
+    let name = entry.strip_prefix(MARKER_SHEET_PREFIX)?;
+    let is_pdf = Path::new(name).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"));
+    (is_pdf && !name.contains(['/', '\\'])).then_some(name)
+}
+
+/// Writes the file, keeping what was there with a `.bak` suffix if it's different. Returns whether anything changed.
+fn install_file(path: &Path, contents: &[u8]) -> Result<bool, Box<dyn Error>> {
+    if let Some(existing) = read_optional(path)? {
+        if existing == contents {
+            return Ok(false);
+        }
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        fs::copy(path, &backup).map_err(|err| format!("Failed to back up {}: {}", path.display(), err))?;
+    }
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents).map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+    Ok(true)
+}
+
+impl SetupBundle {
+    /// Reads the setup the app would use from the working directory, with the given calibration file.
+    pub fn gather(calibration: &Path) -> Result<Self, Box<dyn Error>> {
+        let config = read_optional(Path::new(CONFIG_PATH))?.map(String::from_utf8).transpose()?;
+        let layout_path = match &config {
+            Some(_) => Config::load(Path::new(CONFIG_PATH))?.fiducials.layout.unwrap_or_else(|| DEFAULT_LAYOUT_PATH.to_string()),
+            None => DEFAULT_LAYOUT_PATH.to_string()
+        };
+
+        let mut marker_sheets = Vec::new();
+        if let Ok(entries) = fs::read_dir(MARKER_SHEET_DIR) {
+            for path in entries.flatten().map(|entry| entry.path()) {
+                let Some(name) = path.file_name().and_then(|name| name.to_str()) else { continue };
+                if marker_sheet_name(&format!("{}{}", MARKER_SHEET_PREFIX, name)).is_some() {
+                    marker_sheets.push((name.to_string(), fs::read(&path)?));
+                }
+            }
+        }
+        marker_sheets.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(Self {
+            config: config.as_deref().map(point_layout_at_bundle).transpose()?,
+            calibration: read_optional(calibration)?,
+            layout: read_optional(Path::new(&layout_path))?,
+            marker_sheets
+        })
+    }
+
+    pub fn write(&self, writer: impl Write + Seek) -> Result<(), Box<dyn Error>> {
+        let mut zip = ZipWriter::new(writer);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        zip.start_file(MANIFEST_ENTRY, options)?;
+        zip.write_all(serde_json::to_string_pretty(&json!({
+            "format": BUNDLE_FORMAT,
+            "app_version": env!("CARGO_PKG_VERSION"),
+            "created": created
+        }))?.as_bytes())?;
+
+        let files = [(CONFIG_ENTRY, self.config.as_ref().map(String::as_bytes)), (CALIBRATION_ENTRY, self.calibration.as_deref()), (LAYOUT_ENTRY, self.layout.as_deref())];
+        for (name, contents) in files {
+            if let Some(contents) = contents {
+                zip.start_file(name, options)?;
+                zip.write_all(contents)?;
+            }
+        }
+        // PDFs are already compressed
+        for (name, contents) in &self.marker_sheets {
+            zip.start_file(format!("{}{}", MARKER_SHEET_PREFIX, name), SimpleFileOptions::default().compression_method(CompressionMethod::Stored))?;
+            zip.write_all(contents)?;
+        }
+
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// Reads a bundle and checks that its files are ones this version of the app can use.
+    pub fn read(reader: impl Read + Seek) -> Result<Self, Box<dyn Error>> {
+        let mut zip = ZipArchive::new(reader)?;
+        let mut read_entry = |name: &str| -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            match zip.by_name(name) {
+                Ok(mut entry) => {
+                    let mut contents = Vec::new();
+                    entry.read_to_end(&mut contents)?;
+                    Ok(Some(contents))
+                }
+                Err(zip::result::ZipError::FileNotFound) => Ok(None),
+                Err(err) => Err(err.into())
+            }
+        };
+
+        let manifest: serde_json::Value = serde_json::from_slice(&read_entry(MANIFEST_ENTRY)?.ok_or("not a setup bundle: it has no manifest")?)?;
+        let format = manifest.get("format").and_then(|format| format.as_u64()).ok_or("the manifest has no format version")?;
+        if format > BUNDLE_FORMAT {
+            return Err(format!("the bundle was made by a newer version of the app (format {}, this version reads up to {})", format, BUNDLE_FORMAT).into());
+        }
+
+        let config = read_entry(CONFIG_ENTRY)?.map(String::from_utf8).transpose()?;
+        if let Some(config) = &config {
+            toml::from_str::<Config>(config).map_err(|err| format!("its {} is invalid: {}", CONFIG_ENTRY, err))?;
+        }
+        let calibration = read_entry(CALIBRATION_ENTRY)?;
+        if let Some(calibration) = &calibration {
+            let parsed: serde_json::Value = serde_json::from_slice(calibration).map_err(|err| format!("its {} is invalid: {}", CALIBRATION_ENTRY, err))?;
+            if parsed.get("camera_matrix").is_none() {
+                return Err(format!("its {} has no camera matrix", CALIBRATION_ENTRY).into());
+            }
+        }
+        let layout = read_entry(LAYOUT_ENTRY)?;
+        if let Some(layout) = &layout {
+            serde_json::from_slice::<FiducialLayout>(layout).map_err(|err| format!("its {} is invalid: {}", LAYOUT_ENTRY, err))?;
+        }
+
+        let names: Vec<String> = zip.file_names().filter_map(marker_sheet_name).map(str::to_string).collect();
+        let mut marker_sheets = Vec::new();
+        for name in names {
+            let mut contents = Vec::new();
+            zip.by_name(&format!("{}{}", MARKER_SHEET_PREFIX, name))?.read_to_end(&mut contents)?;
+            marker_sheets.push((name, contents));
+        }
+        marker_sheets.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(Self { config, calibration, layout, marker_sheets })
+    }
+
+    /// Writes the bundle's files where the app reads them, with the calibration at the given path.
+    /// Returns the files that changed.
+    pub fn install(&self, calibration: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut files: Vec<(PathBuf, &[u8])> = Vec::new();
+        if let Some(config) = &self.config {
+            files.push((PathBuf::from(CONFIG_PATH), config.as_bytes()));
+        }
+        if let Some(contents) = &self.calibration {
+            files.push((calibration.to_path_buf(), contents));
+        }
+        if let Some(contents) = &self.layout {
+            files.push((PathBuf::from(DEFAULT_LAYOUT_PATH), contents));
+        }
+        for (name, contents) in &self.marker_sheets {
+            files.push((Path::new(MARKER_SHEET_DIR).join(name), contents));
+        }
+
+        let mut changed = Vec::new();
+        for (path, contents) in files {
+            if install_file(&path, contents)? {
+                changed.push(path);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// A one-line list of what the bundle holds.
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        let present = [(CONFIG_ENTRY, self.config.is_some()), (CALIBRATION_ENTRY, self.calibration.is_some()), (LAYOUT_ENTRY, self.layout.is_some())];
+        parts.extend(present.iter().filter(|(_, present)| *present).map(|(name, _)| name.to_string()));
+        if !self.marker_sheets.is_empty() {
+            parts.push(format!("{} marker sheets", self.marker_sheets.len()));
+        }
+        if parts.is_empty() { "nothing".to_string() } else { parts.join(", ") }
+    }
+}
+
+/// Writes the current setup to a bundle at `path`.
+pub fn export(path: &Path, calibration: &CalibrationFile) -> Result<(), Box<dyn Error>> {
+    let bundle = SetupBundle::gather(Path::new(&calibration.0))?;
+    if bundle.calibration.is_none() {
+        eprintln!("{} wasn't found, so the bundle has no calibration", calibration.0);
+    }
+    bundle.write(fs::File::create(path)?)?;
+    println!("Saved the setup to {}: {}", path.display(), bundle.describe());
+    Ok(())
+}
+
+/// Installs the bundle at `path` into the working directory.
+pub fn import(path: &Path, calibration: &CalibrationFile) -> Result<(), Box<dyn Error>> {
+    let bundle = SetupBundle::read(fs::File::open(path)?).map_err(|err| format!("Can't use {}: {}", path.display(), err))?;
+    let changed = bundle.install(Path::new(&calibration.0))?;
+    println!("Imported {} from {}", bundle.describe(), path.display());
+    for file in changed {
+        println!("  Wrote {}", file.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn bundles_round_trip_with_the_layout_pointed_at_the_bundle() {
+        let config = point_layout_at_bundle("[fiducials]\nlayout = \"/home/someone/piano/fiducials-upright.json\"\n").unwrap();
+        assert_eq!(toml::from_str::<Config>(&config).unwrap().fiducials.layout.as_deref(), Some(DEFAULT_LAYOUT_PATH));
+        let unchanged = "# Comments are kept when nothing changes\n[song]\nspeed = 0.5\n";
+        assert_eq!(point_layout_at_bundle(unchanged).unwrap(), unchanged);
+
+        let bundle = SetupBundle {
+            config: Some(config),
+            calibration: Some(br#"{"camera": "test", "camera_matrix": [[1, 0, 0], [0, 1, 0], [0, 0, 1]]}"#.to_vec()),
+            layout: Some(serde_json::to_vec(&FiducialLayout::default()).unwrap()),
+            marker_sheets: vec![("aprilTags.pdf".to_string(), b"%PDF-1.4".to_vec())]
+        };
+        let mut written = Cursor::new(Vec::new());
+        bundle.write(&mut written).unwrap();
+        assert_eq!(SetupBundle::read(Cursor::new(written.into_inner())).unwrap(), bundle);
+
+        assert_eq!(marker_sheet_name("markers/../config.toml"), None);
+        assert_eq!(marker_sheet_name("markers/sub/aprilTags.pdf"), None);
+        assert_eq!(marker_sheet_name("markers/aprilTags.pdf"), Some("aprilTags.pdf"));
+    }
+}
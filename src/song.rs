@@ -0,0 +1,611 @@
+use std::{collections::HashMap, error::Error, fs, path::{Path, PathBuf}, thread};
+
+use bevy::{app::{App, Plugin, Startup, Update}, ecs::{event::{Event, EventReader, EventWriter}, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Res, ResMut}}};
+use crossbeam_channel::{Receiver, TryRecvError};
+use midly::{num::{u15, u24, u28, u4, u7}, Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+pub mod automation;
+pub mod fingering;
+pub mod generator;
+pub mod library;
+pub mod musicxml;
+pub mod playback;
+pub mod preset;
+pub mod synthesia;
+pub mod waterfall;
+
+use crate::{diagnostics::profiling::profile_scope, keyboard::theme, startup::{StartupProgress, StartupTask}, status::{AppError, ErrorSource}};
+
+/// The song loaded at startup, if it exists. Insert this before adding `SongPlugin` to load a different file.
+/// Sidecar metadata (e.g. `song.synthesia`) next to it is imported automatically.
+#[derive(Resource, Clone, Debug)]
+pub struct SongFile(pub PathBuf);
+
+impl Default for SongFile {
+    fn default() -> Self {
+        SongFile(PathBuf::from("assets/song.mid"))
+    }
+}
+
+/// The startup song being loaded on another thread, so a large file doesn't hold up the window opening.
+#[derive(Resource)]
+struct PendingSong(Receiver<Result<Song, String>>);
+
+/// The directory where generated and recorded songs are saved.
+pub static LIBRARY_DIR: &str = "assets/songs";
+
+/// Which hand a note is meant to be played with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hand {
+    Left,
+    Right
+}
+
+#[derive(Debug, Clone)]
+pub struct SongNote {
+    /// The MIDI key number.
+    pub key: u8,
+    pub velocity: u8,
+    pub channel: u8,
+    /// The index of the SMF track this note came from.
+    pub track: usize,
+    /// The start time in seconds from the beginning of the song.
+    pub start: f64,
+    /// The duration in seconds.
+    pub duration: f64,
+    pub hand: Option<Hand>,
+    /// The suggested finger, 1 (thumb) through 5 (little finger).
+    pub finger: Option<u8>
+}
+
+impl SongNote {
+    pub fn end(&self) -> f64 {
+        self.start + self.duration
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSignature {
+    pub tick: u64,
+    pub numerator: u8,
+    pub denominator: u8
+}
+
+/// Converts between SMF ticks, seconds, and measures.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    pub ticks_per_beat: u16,
+    /// Tempo changes as (tick, microseconds per beat), sorted by tick.
+    pub tempos: Vec<(u64, u32)>,
+    /// Time signature changes, sorted by tick.
+    pub time_signatures: Vec<TimeSignature>
+}
+
+impl Default for TempoMap {
+    fn default() -> Self {
+        Self {
+            ticks_per_beat: 480,
+            tempos: Vec::new(),
+            time_signatures: Vec::new()
+        }
+    }
+}
+
+impl TempoMap {
+    pub fn tick_to_seconds(&self, tick: u64) -> f64 {
+        let mut seconds = 0.0;
+        let mut last_tick = 0;
+        // SMF default tempo is 120 BPM
+        let mut microseconds_per_beat = 500_000;
+        for &(change_tick, tempo) in &self.tempos {
+            if change_tick >= tick {
+                break;
+            }
+            seconds += self.ticks_to_duration(change_tick - last_tick, microseconds_per_beat);
+            last_tick = change_tick;
+            microseconds_per_beat = tempo;
+        }
+        seconds + self.ticks_to_duration(tick - last_tick, microseconds_per_beat)
+    }
+
+    pub fn seconds_to_tick(&self, seconds: f64) -> u64 {
+        let mut elapsed = 0.0;
+        let mut last_tick = 0;
+        let mut microseconds_per_beat = 500_000;
+        for &(change_tick, tempo) in &self.tempos {
+            let change_time = elapsed + self.ticks_to_duration(change_tick - last_tick, microseconds_per_beat);
+            if change_time >= seconds {
+                break;
+            }
+            elapsed = change_time;
+            last_tick = change_tick;
+            microseconds_per_beat = tempo;
+        }
+        let seconds_per_tick = microseconds_per_beat as f64 / 1_000_000.0 / self.ticks_per_beat as f64;
+        last_tick + ((seconds - elapsed).max(0.0) / seconds_per_tick).round() as u64
+    }
+
+    fn ticks_to_duration(&self, ticks: u64, microseconds_per_beat: u32) -> f64 {
+        ticks as f64 * microseconds_per_beat as f64 / 1_000_000.0 / self.ticks_per_beat as f64
+    }
+
+    pub fn ticks_per_measure(&self, numerator: u8, denominator: u8) -> u64 {
+        self.ticks_per_beat as u64 * 4 * numerator as u64 / denominator.max(1) as u64
+    }
+
+    /// Returns the tick at which the given 1-based measure starts.
+    /// Time signature changes are assumed to fall on bar lines.
+    pub fn measure_to_tick(&self, measure: u32) -> u64 {
+        let mut tick = 0;
+        let mut signature = (4, 4);
+        let mut signatures = self.time_signatures.iter().peekable();
+        for _ in 1..measure.max(1) {
+            while let Some(next) = signatures.next_if(|next| next.tick <= tick) {
+                signature = (next.numerator, next.denominator);
+            }
+            tick += self.ticks_per_measure(signature.0, signature.1);
+        }
+        tick
+    }
+
+    pub fn measure_to_seconds(&self, measure: u32) -> f64 {
+        self.tick_to_seconds(self.measure_to_tick(measure))
+    }
+
+    /// Returns the 1-based measure containing the given tick, with the same assumptions as `measure_to_tick`.
+    pub fn measure_at_tick(&self, target: u64) -> u32 {
+        let mut tick = 0;
+        let mut measure = 1;
+        let mut signature = (4, 4);
+        let mut signatures = self.time_signatures.iter().peekable();
+        loop {
+            while let Some(next) = signatures.next_if(|next| next.tick <= tick) {
+                signature = (next.numerator, next.denominator);
+            }
+            let next_tick = tick + self.ticks_per_measure(signature.0, signature.1);
+            // A malformed tempo map could have empty measures
+            if next_tick > target || next_tick == tick {
+                return measure;
+            }
+            tick = next_tick;
+            measure += 1;
+        }
+    }
+
+    pub fn measure_at_seconds(&self, seconds: f64) -> u32 {
+        self.measure_at_tick(self.seconds_to_tick(seconds))
+    }
+
+    /// The length in seconds of the beat at the given time, counted in the time signature's beats (eighths in 6/8).
+    pub fn beat_length_at(&self, seconds: f64) -> f64 {
+        let tick = self.seconds_to_tick(seconds);
+        let denominator = self.time_signatures.iter().take_while(|signature| signature.tick <= tick).last().map_or(4, |signature| signature.denominator);
+        let beat_ticks = self.ticks_per_beat as u64 * 4 / denominator.max(1) as u64;
+        self.tick_to_seconds(tick + beat_ticks) - self.tick_to_seconds(tick)
+    }
+
+    /// Returns the beats from `start` to `end` seconds as (tick, seconds, whether it starts a measure),
+    /// with the same assumptions as `measure_to_tick`.
+    pub fn beats_between(&self, start: f64, end: f64) -> Vec<(u64, f64, bool)> {
+        let (start_tick, end_tick) = (self.seconds_to_tick(start.max(0.0)), self.seconds_to_tick(end.max(0.0)));
+        let mut beats = Vec::new();
+        // Signature changes fall on bar lines, so the measure grid can pick up from the last one before `start`
+        let first = self.time_signatures.partition_point(|signature| signature.tick <= start_tick);
+        let (mut tick, mut signature) = match first.checked_sub(1).map(|index| &self.time_signatures[index]) {
+            Some(change) => (change.tick, (change.numerator, change.denominator)),
+            None => (0, (4, 4))
+        };
+        let measure_ticks = self.ticks_per_measure(signature.0, signature.1);
+        if measure_ticks > 0 {
+            tick += (start_tick - tick) / measure_ticks * measure_ticks;
+        }
+        let mut signatures = self.time_signatures[first..].iter().peekable();
+        // Beats come in order, so the tempo changes are walked once alongside them, as (tick, seconds, microseconds per beat)
+        let mut tempos = self.tempos.iter().peekable();
+        let mut tempo = (0, 0.0, 500_000);
+        while tick <= end_tick {
+            while let Some(next) = signatures.next_if(|next| next.tick <= tick) {
+                signature = (next.numerator, next.denominator);
+            }
+            let measure_ticks = self.ticks_per_measure(signature.0, signature.1);
+            if measure_ticks == 0 {
+                break;
+            }
+            if tick + measure_ticks >= start_tick {
+                let beat_ticks = measure_ticks / signature.0.max(1) as u64;
+                for beat in 0..signature.0.max(1) as u64 {
+                    let beat_tick = tick + beat * beat_ticks;
+                    if (start_tick..=end_tick).contains(&beat_tick) {
+                        while let Some(&(change_tick, microseconds_per_beat)) = tempos.next_if(|(change_tick, _)| *change_tick < beat_tick) {
+                            tempo = (change_tick, tempo.1 + self.ticks_to_duration(change_tick - tempo.0, tempo.2), microseconds_per_beat);
+                        }
+                        beats.push((beat_tick, tempo.1 + self.ticks_to_duration(beat_tick - tempo.0, tempo.2), beat == 0));
+                    }
+                }
+            }
+            tick += measure_ticks;
+        }
+        beats
+    }
+}
+
+/// A section of the song to repeat, in seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct LoopRegion {
+    pub start: f64,
+    pub end: f64
+}
+
+impl LoopRegion {
+    /// Whether a note starting at this time is part of the loop.
+    pub fn contains(&self, time: f64) -> bool {
+        (self.start..self.end).contains(&time)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bookmark {
+    pub time: f64,
+    pub label: String
+}
+
+#[derive(Resource, Default, Clone)]
+pub struct Song {
+    pub title: String,
+    pub path: Option<PathBuf>,
+    /// All notes, sorted by start time.
+    pub notes: Vec<SongNote>,
+    pub tempo_map: TempoMap,
+    /// Lyrics as (time in seconds, syllable), from `.kar` files or lyric meta events.
+    pub lyrics: Vec<(f64, String)>,
+    pub bookmarks: Vec<Bookmark>,
+    pub loop_region: Option<LoopRegion>,
+    /// Setting overrides from the song's `.preset.json` file.
+    pub preset: Option<preset::SongPreset>,
+    /// The length of the song in seconds.
+    pub duration: f64
+}
+
+impl Song {
+    /// Loads a Standard MIDI File (`.mid`, `.midi`, or karaoke `.kar`), or a MusicXML score (see `musicxml`).
+    pub fn load(path: &Path) -> Result<Song, Box<dyn Error>> {
+        if musicxml::is_score(path) {
+            return musicxml::load(path);
+        }
+        let bytes = fs::read(path)?;
+        let smf = Smf::parse(&bytes)?;
+
+        let ticks_per_beat = match smf.header.timing {
+            Timing::Metrical(ticks) => ticks.as_int(),
+            Timing::Timecode(..) => return Err("SMPTE timecode MIDI files are not supported".into())
+        };
+
+        let is_karaoke = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("kar"));
+
+        let mut tempo_map = TempoMap { ticks_per_beat, ..Default::default() };
+        let mut title = None;
+        let mut lyric_ticks = Vec::new();
+        let mut marker_ticks = Vec::new();
+        // (track, absolute tick, channel, message)
+        let mut midi_events = Vec::new();
+
+        for (track_index, track) in smf.tracks.iter().enumerate() {
+            let mut tick = 0u64;
+            for event in track {
+                tick += event.delta.as_int() as u64;
+                match event.kind {
+                    TrackEventKind::Midi { channel, message } => {
+                        midi_events.push((track_index, tick, channel.as_int(), message));
+                    }
+                    TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => tempo_map.tempos.push((tick, tempo.as_int())),
+                    TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator_power, _, _)) => {
+                        tempo_map.time_signatures.push(TimeSignature {
+                            tick,
+                            numerator,
+                            denominator: 1u8.checked_shl(denominator_power as u32).unwrap_or(4)
+                        });
+                    }
+                    TrackEventKind::Meta(MetaMessage::TrackName(name)) if track_index == 0 && title.is_none() => {
+                        title = Some(String::from_utf8_lossy(name).trim().to_string());
+                    }
+                    TrackEventKind::Meta(MetaMessage::Lyric(text)) => {
+                        lyric_ticks.push((tick, String::from_utf8_lossy(text).to_string()));
+                    }
+                    // Karaoke files store lyrics as text events; '@' marks header fields
+                    TrackEventKind::Meta(MetaMessage::Text(text)) if is_karaoke && !text.starts_with(b"@") => {
+                        lyric_ticks.push((tick, String::from_utf8_lossy(text).to_string()));
+                    }
+                    TrackEventKind::Meta(MetaMessage::Marker(text) | MetaMessage::CuePoint(text)) => {
+                        marker_ticks.push((tick, String::from_utf8_lossy(text).trim().to_string()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        tempo_map.tempos.sort_by_key(|&(tick, _)| tick);
+        tempo_map.time_signatures.sort_by_key(|signature| signature.tick);
+        midi_events.sort_by_key(|&(track, tick, _, _)| (tick, track));
+
+        // Pair note-ons with their note-offs. Keys held multiple times are closed in first-in, first-out order.
+        let mut open_notes: HashMap<(usize, u8, u8), Vec<(u64, u8)>> = HashMap::new();
+        let mut notes = Vec::new();
+        for (track, tick, channel, message) in midi_events {
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                    open_notes.entry((track, channel, key.as_int())).or_default().push((tick, vel.as_int()));
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    let key = key.as_int();
+                    if let Some(open) = open_notes.get_mut(&(track, channel, key)) {
+                        if !open.is_empty() {
+                            let (start_tick, velocity) = open.remove(0);
+                            let start = tempo_map.tick_to_seconds(start_tick);
+                            notes.push(SongNote {
+                                key, velocity, channel, track, start,
+                                duration: tempo_map.tick_to_seconds(tick) - start,
+                                hand: None,
+                                finger: None
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.key.cmp(&b.key)));
+
+        let duration = notes.iter().map(SongNote::end).fold(0.0, f64::max);
+        let lyrics = lyric_ticks.into_iter()
+            .map(|(tick, text)| (tempo_map.tick_to_seconds(tick), text))
+            .collect();
+        // Marker and cue point events become bookmarks
+        let mut bookmarks: Vec<Bookmark> = marker_ticks.into_iter()
+            .map(|(tick, label)| Bookmark { time: tempo_map.tick_to_seconds(tick), label })
+            .collect();
+        bookmarks.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        Ok(Song {
+            title: title.filter(|title| !title.is_empty()).unwrap_or_else(|| {
+                path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+            }),
+            path: Some(path.to_path_buf()),
+            notes,
+            tempo_map,
+            lyrics,
+            bookmarks,
+            loop_region: None,
+            preset: None,
+            duration
+        })
+    }
+
+    /// The loop playback repeats, if one is set and isn't empty.
+    pub fn active_loop(&self) -> Option<LoopRegion> {
+        self.loop_region.filter(|region| region.end > region.start)
+    }
+
+    /// Saves the song as a format 1 Standard MIDI File.
+    /// Track 0 holds the tempo map; notes keep their track indices.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let track_count = self.notes.iter().map(|note| note.track + 1).max().unwrap_or(1);
+        // (tick, sort order, event) per track. Note-offs sort before note-ons on the same tick.
+        let mut track_events: Vec<Vec<(u64, u8, TrackEventKind)>> = vec![Vec::new(); track_count];
+
+        track_events[0].push((0, 0, TrackEventKind::Meta(MetaMessage::TrackName(self.title.as_bytes()))));
+        for &(tick, tempo) in &self.tempo_map.tempos {
+            track_events[0].push((tick, 0, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo)))));
+        }
+        for signature in &self.tempo_map.time_signatures {
+            let denominator_power = signature.denominator.max(1).trailing_zeros() as u8;
+            track_events[0].push((signature.tick, 0, TrackEventKind::Meta(MetaMessage::TimeSignature(signature.numerator, denominator_power, 24, 8))));
+        }
+
+        for note in &self.notes {
+            let channel = u4::new(note.channel);
+            let key = u7::new(note.key);
+            track_events[note.track].push((self.tempo_map.seconds_to_tick(note.start), 2, TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn { key, vel: u7::new(note.velocity.max(1)) }
+            }));
+            track_events[note.track].push((self.tempo_map.seconds_to_tick(note.end()), 1, TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOff { key, vel: u7::new(0) }
+            }));
+        }
+
+        let tracks = track_events.into_iter()
+            .map(|mut events| {
+                events.sort_by_key(|&(tick, order, _)| (tick, order));
+                let mut last_tick = 0;
+                let mut track: Vec<TrackEvent> = events.into_iter()
+                    .map(|(tick, _, kind)| {
+                        let delta = u28::new((tick - last_tick) as u32);
+                        last_tick = tick;
+                        TrackEvent { delta, kind }
+                    })
+                    .collect();
+                track.push(TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+                track
+            })
+            .collect();
+
+        let smf = Smf {
+            header: Header::new(Format::Parallel, Timing::Metrical(u15::new(self.tempo_map.ticks_per_beat))),
+            tracks
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        smf.save(path)?;
+        Ok(())
+    }
+
+    /// Loads a song and imports any Synthesia metadata and preset found next to it.
+    pub fn load_with_metadata(path: &Path) -> Result<Song, Box<dyn Error>> {
+        let mut song = Song::load(path)?;
+
+        let metadata_path = path.with_extension("synthesia");
+        if metadata_path.exists() {
+            match synthesia::SynthesiaMetadata::load(&metadata_path) {
+                Ok(metadata) => metadata.apply(&mut song),
+                Err(err) => eprintln!("Failed to import metadata from {}: {}", metadata_path.display(), err)
+            }
+        }
+
+        // A fingering sidecar is more specific than the metadata's per-track hints, so it's applied over them
+        match fingering::load_sidecar(path, &song.tempo_map) {
+            Some(Ok(annotations)) => {
+                let applied = fingering::apply_fingering(&mut song, &annotations);
+                if applied < annotations.len() {
+                    eprintln!("{} of {} fingerings for {} didn't match a note", annotations.len() - applied, annotations.len(), path.display());
+                }
+            }
+            Some(Err(err)) => eprintln!("Failed to load the fingering {}", err),
+            None => {}
+        }
+
+        let preset_path = preset::SongPreset::path_for(path);
+        if preset_path.exists() {
+            match preset::SongPreset::load(&preset_path) {
+                Ok(preset) => song.preset = Some(preset),
+                Err(err) => eprintln!("Failed to load the song preset {}: {}", preset_path.display(), err)
+            }
+        }
+
+        Ok(song)
+    }
+}
+
+/// Loads the song at this path, replacing the current one.
+#[derive(Event, Debug, Clone)]
+pub struct LoadSong(pub PathBuf);
+
+/// Returns the MIDI files and MusicXML scores in `LIBRARY_DIR`.
+pub fn library_songs() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(LIBRARY_DIR) else { return Vec::new() };
+    let mut songs: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| musicxml::is_score(path) || path.extension().is_some_and(|extension| ["mid", "midi", "kar"].iter().any(|known| extension.eq_ignore_ascii_case(known))))
+        .collect();
+    songs.sort();
+    songs
+}
+
+fn load_requested_songs(mut requests: EventReader<LoadSong>, mut song: ResMut<Song>, mut errors: EventWriter<AppError>) {
+    // Only the last request matters if several arrive at once
+    let Some(LoadSong(path)) = requests.read().last() else { return };
+    match Song::load_with_metadata(path) {
+        Ok(loaded) => {
+            println!("Loaded song '{}' with {} notes", loaded.title, loaded.notes.len());
+            *song = loaded;
+        }
+        Err(err) => {
+            errors.write(AppError::new(ErrorSource::Song, format!("Failed to load song {}: {}", path.display(), err)));
+        }
+    }
+}
+
+fn load_song(mut commands: Commands, song_file: Res<SongFile>, mut progress: ResMut<StartupProgress>) {
+    let path = song_file.0.clone();
+    if !path.exists() {
+        return;
+    }
+
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let spawned = thread::Builder::new()
+        .name("song loading".to_string())
+        .spawn(move || {
+            profile_scope!("load song");
+            let _ = sender.send(Song::load_with_metadata(&path).map_err(|err| format!("Failed to load song {}: {}", path.display(), err)));
+        });
+    match spawned {
+        Ok(_) => {
+            progress.begin(StartupTask::Song);
+            commands.insert_resource(PendingSong(receiver));
+        }
+        Err(err) => eprintln!("Failed to start loading the song: {}", err)
+    }
+}
+
+/// Uses the startup song once it's loaded, unless another song was loaded in the meantime.
+fn receive_startup_song(
+    mut commands: Commands,
+    pending: Option<Res<PendingSong>>,
+    mut song: ResMut<Song>,
+    mut progress: ResMut<StartupProgress>
+) {
+    let Some(pending) = pending else { return };
+    let loaded = match pending.0.try_recv() {
+        Ok(loaded) => loaded,
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => Err("The song loading thread stopped without loading the song".to_string())
+    };
+    commands.remove_resource::<PendingSong>();
+    progress.finish(StartupTask::Song);
+    match loaded {
+        Ok(loaded) if song.path.is_none() => {
+            println!("Loaded song '{}' with {} notes", loaded.title, loaded.notes.len());
+            *song = loaded;
+        }
+        Ok(_) => {}
+        Err(err) => eprintln!("{}", err)
+    }
+}
+
+pub struct SongPlugin;
+
+impl Plugin for SongPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(Song::default())
+            .init_resource::<SongFile>()
+            .init_resource::<StartupProgress>()
+            .init_resource::<playback::SongPlayback>()
+            .add_event::<generator::GenerateExercise>()
+            .add_event::<LoadSong>()
+            .init_resource::<preset::OverriddenSettings>()
+            .init_resource::<automation::SongAutomation>()
+            .init_resource::<automation::AutomationBaseline>()
+            .init_resource::<automation::SceneBloom>()
+            .add_plugins((waterfall::WaterfallPlugin, fingering::FingerHintPlugin))
+            .add_systems(Startup, load_song)
+            .add_systems(Update, (
+                receive_startup_song.before(load_requested_songs),
+                preset::apply_song_preset.before(theme::update_theme),
+                (generator::exercise_commands, generator::generate_exercises).chain(),
+                load_requested_songs.before(preset::apply_song_preset),
+                playback::advance_playback.after(generator::generate_exercises),
+                automation::load_song_automation.after(load_requested_songs).before(preset::apply_song_preset),
+                (automation::apply_song_automation, automation::apply_scene_bloom).chain().after(playback::advance_playback).after(preset::apply_song_preset)
+            ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beats_follow_tempo_and_signature_changes() {
+        // 4/4 at 120 BPM, then 3/4 at 60 BPM from measure 2
+        let tempo_map = TempoMap {
+            ticks_per_beat: 480,
+            tempos: vec![(0, 500_000), (1920, 1_000_000)],
+            time_signatures: vec![
+                TimeSignature { tick: 0, numerator: 4, denominator: 4 },
+                TimeSignature { tick: 1920, numerator: 3, denominator: 4 }
+            ]
+        };
+        assert_eq!(tempo_map.beats_between(1.5, 5.0), vec![
+            (1440, 1.5, false),
+            (1920, 2.0, true),
+            (2400, 3.0, false),
+            (2880, 4.0, false),
+            (3360, 5.0, true)
+        ]);
+        assert_eq!(tempo_map.beats_between(5.5, 7.0), vec![(3840, 6.0, false), (4320, 7.0, false)]);
+    }
+}
@@ -0,0 +1,337 @@
+//! Visual automation along the song's timeline, for choreographed performances. Each lane keyframes one visual
+//! parameter, like how fast the notes fall or how dim the backdrop is, and is played back in sync with the song:
+//! between keyframes the value is interpolated linearly, and before the first and after the last it's held.
+//!
+//! A song's automation is kept next to it, `song.mid` using `song.automation.json`, and edited in the automation window
+//! (see `editor`). Automated settings go back to what they were when another song is loaded or their lane is removed.
+
+pub mod editor;
+
+use std::{collections::HashMap, error::Error, fs, path::{Path, PathBuf}};
+
+use bevy::{color::{Color, Hsla, Hue, Srgba}, core_pipeline::{bloom::Bloom, core_3d::Camera3d}, ecs::{change_detection::DetectChanges, entity::Entity, query::{Added, With}, resource::Resource, system::{Commands, Query, Res, ResMut, SystemParam}}, render::camera::Camera};
+use serde::{Deserialize, Serialize};
+
+use crate::{background::{replacement::BackgroundReplacementSettings, style::BackgroundStyleSettings}, keyboard::{trails::NoteTrailSettings, KeyPalette}, render_layers::OutputCamera};
+
+use super::{playback::SongPlayback, waterfall::{self, WaterfallSettings}, Song};
+
+/// A visual parameter automation can drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutomatedParameter {
+    /// The waterfall's look-ahead in seconds. Less look-ahead makes notes fall faster.
+    NoteSpeed,
+    /// The brightness outside the keyboard in the spotlight style.
+    BackdropDim,
+    /// How dark the outlines are in the ink outline style.
+    EdgeStrength,
+    /// The hue of the key highlights, in degrees.
+    KeyHue,
+    /// How fast note trails rise, in mm/s.
+    TrailSpeed,
+    /// The hue of the solid backdrop color shown by background replacement, in degrees.
+    BackdropHue,
+    /// How strongly bright parts of the scene glow. See `SceneBloom`.
+    Bloom
+}
+
+impl AutomatedParameter {
+    pub const ALL: [AutomatedParameter; 7] = [
+        AutomatedParameter::NoteSpeed,
+        AutomatedParameter::BackdropDim,
+        AutomatedParameter::EdgeStrength,
+        AutomatedParameter::KeyHue,
+        AutomatedParameter::TrailSpeed,
+        AutomatedParameter::BackdropHue,
+        AutomatedParameter::Bloom
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AutomatedParameter::NoteSpeed => "Note look-ahead (s)",
+            AutomatedParameter::BackdropDim => "Backdrop brightness",
+            AutomatedParameter::EdgeStrength => "Outline strength",
+            AutomatedParameter::KeyHue => "Key hue (°)",
+            AutomatedParameter::TrailSpeed => "Trail speed (mm/s)",
+            AutomatedParameter::BackdropHue => "Backdrop hue (°)",
+            AutomatedParameter::Bloom => "Bloom intensity"
+        }
+    }
+
+    /// Whether the parameter is an angle, which goes the short way around between keyframes.
+    pub fn is_hue(self) -> bool {
+        matches!(self, AutomatedParameter::KeyHue | AutomatedParameter::BackdropHue)
+    }
+
+    /// The values the editor allows.
+    pub fn range(self) -> (f32, f32) {
+        match self {
+            AutomatedParameter::NoteSpeed => (*waterfall::LOOK_AHEAD.start() as f32, *waterfall::LOOK_AHEAD.end() as f32),
+            AutomatedParameter::BackdropDim => (0.0, 1.0),
+            AutomatedParameter::EdgeStrength => (0.0, 3.0),
+            AutomatedParameter::KeyHue => (0.0, 360.0),
+            AutomatedParameter::TrailSpeed => (20.0, 600.0),
+            AutomatedParameter::BackdropHue => (0.0, 360.0),
+            AutomatedParameter::Bloom => (0.0, 1.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    /// The song position in seconds.
+    pub time: f64,
+    pub value: f32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AutomationLane {
+    pub parameter: AutomatedParameter,
+    /// Sorted by time.
+    pub keyframes: Vec<Keyframe>
+}
+
+impl AutomationLane {
+    /// The lane's value at a song position, or `None` if it has no keyframes.
+    pub fn value_at(&self, time: f64) -> Option<f32> {
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time <= time);
+        match (next.checked_sub(1).map(|index| self.keyframes[index]), self.keyframes.get(next)) {
+            (Some(before), Some(after)) => {
+                let t = ((time - before.time) / (after.time - before.time).max(f64::EPSILON)) as f32;
+                if self.parameter.is_hue() {
+                    // From 350° to 10° is 20° through red, not 340° through every other hue
+                    let change = (after.value - before.value + 180.0).rem_euclid(360.0) - 180.0;
+                    Some((before.value + change * t).rem_euclid(360.0))
+                } else {
+                    Some(before.value + (after.value - before.value) * t)
+                }
+            }
+            (Some(keyframe), None) | (None, Some(keyframe)) => Some(keyframe.value),
+            (None, None) => None
+        }
+    }
+
+    pub fn sort(&mut self) {
+        self.keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+    }
+}
+
+/// The current song's automation.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SongAutomation {
+    pub lanes: Vec<AutomationLane>
+}
+
+impl SongAutomation {
+    /// The automation file for a song: `song.mid` uses `song.automation.json`.
+    pub fn path_for(song: &Path) -> PathBuf {
+        song.with_extension("automation.json")
+    }
+
+    pub fn load(path: &Path) -> Result<SongAutomation, Box<dyn Error>> {
+        let mut automation: SongAutomation = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for lane in &mut automation.lanes {
+            lane.sort();
+        }
+        Ok(automation)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The lane for a parameter, added if there isn't one yet.
+    pub fn lane_mut(&mut self, parameter: AutomatedParameter) -> &mut AutomationLane {
+        let index = match self.lanes.iter().position(|lane| lane.parameter == parameter) {
+            Some(index) => index,
+            None => {
+                self.lanes.push(AutomationLane { parameter, keyframes: Vec::new() });
+                self.lanes.len() - 1
+            }
+        };
+        &mut self.lanes[index]
+    }
+}
+
+/// Converts `BackgroundReplacementSettings::backdrop_color`, which is RGB in 0-255.
+fn backdrop_color([r, g, b]: [f64; 3]) -> Color {
+    Color::srgb((r / 255.0) as f32, (g / 255.0) as f32, (b / 255.0) as f32)
+}
+
+/// The bloom intensity of the cameras showing the scene. It's only set by automation, and stays at 0, which leaves bloom
+/// off, in songs without a bloom lane.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SceneBloom {
+    pub intensity: f32
+}
+
+/// The values automated settings had before automation took them over, to go back to.
+#[derive(Resource, Default)]
+pub struct AutomationBaseline(HashMap<AutomatedParameter, f32>);
+
+/// The settings automation can drive.
+#[derive(SystemParam)]
+pub struct AutomationTargets<'w> {
+    waterfall: ResMut<'w, WaterfallSettings>,
+    style: ResMut<'w, BackgroundStyleSettings>,
+    palette: ResMut<'w, KeyPalette>,
+    trails: ResMut<'w, NoteTrailSettings>,
+    replacement: ResMut<'w, BackgroundReplacementSettings>,
+    bloom: ResMut<'w, SceneBloom>
+}
+
+impl AutomationTargets<'_> {
+    pub fn get(&self, parameter: AutomatedParameter) -> f32 {
+        match parameter {
+            AutomatedParameter::NoteSpeed => self.waterfall.look_ahead as f32,
+            AutomatedParameter::BackdropDim => self.style.spotlight_dim,
+            AutomatedParameter::EdgeStrength => self.style.edge_strength,
+            AutomatedParameter::KeyHue => Hsla::from(self.palette.pressed).hue,
+            AutomatedParameter::TrailSpeed => self.trails.speed,
+            AutomatedParameter::BackdropHue => Hsla::from(backdrop_color(self.replacement.backdrop_color)).hue,
+            AutomatedParameter::Bloom => self.bloom.intensity
+        }
+    }
+
+    /// Sets a parameter, without touching the setting if it already has the value, so it only changes when it must.
+    fn set(&mut self, parameter: AutomatedParameter, value: f32) {
+        if (self.get(parameter) - value).abs() < 1e-4 {
+            return;
+        }
+        match parameter {
+            AutomatedParameter::NoteSpeed => self.waterfall.look_ahead = (value as f64).clamp(*waterfall::LOOK_AHEAD.start(), *waterfall::LOOK_AHEAD.end()),
+            AutomatedParameter::BackdropDim => self.style.spotlight_dim = value.clamp(0.0, 1.0),
+            AutomatedParameter::EdgeStrength => self.style.edge_strength = value.max(0.0),
+            AutomatedParameter::KeyHue => {
+                // Both palette colors turn together, so sustained notes stay a darker shade of pressed ones
+                let palette = self.palette.as_mut();
+                palette.pressed = Color::from(Hsla::from(palette.pressed).with_hue(value.rem_euclid(360.0)));
+                palette.sustained = Color::from(Hsla::from(palette.sustained).with_hue(value.rem_euclid(360.0)));
+            }
+            AutomatedParameter::TrailSpeed => self.trails.speed = value.max(1.0),
+            AutomatedParameter::BackdropHue => {
+                let color = Srgba::from(Hsla::from(backdrop_color(self.replacement.backdrop_color)).with_hue(value.rem_euclid(360.0)));
+                let color = [color.red, color.green, color.blue].map(|channel| (channel * 255.0).round() as f64);
+                // The 0-255 channels can't hold every hue, so leave the setting alone when rounding gives the same color
+                if self.replacement.backdrop_color != color {
+                    self.replacement.backdrop_color = color;
+                }
+            }
+            AutomatedParameter::Bloom => self.bloom.intensity = value.max(0.0)
+        }
+    }
+}
+
+/// Gives the automated settings back and loads the new song's automation when the song changes. Runs before the song
+/// preset is applied, so the settings are handed back in the opposite order they were taken.
+pub fn load_song_automation(
+    song: Res<Song>,
+    mut automation: ResMut<SongAutomation>,
+    mut baseline: ResMut<AutomationBaseline>,
+    mut targets: AutomationTargets
+) {
+    if !song.is_changed() {
+        return;
+    }
+    for (parameter, value) in baseline.0.drain() {
+        targets.set(parameter, value);
+    }
+
+    let loaded = song.path.as_deref()
+        .map(SongAutomation::path_for)
+        .filter(|path| path.exists())
+        .and_then(|path| match SongAutomation::load(&path) {
+            Ok(loaded) => Some(loaded),
+            Err(err) => {
+                eprintln!("Failed to load the song automation {}: {}", path.display(), err);
+                None
+            }
+        });
+    *automation = loaded.unwrap_or_default();
+}
+
+/// Sets each automated parameter to its lane's value at the playback position.
+pub fn apply_song_automation(
+    automation: Res<SongAutomation>,
+    playback: Res<SongPlayback>,
+    mut baseline: ResMut<AutomationBaseline>,
+    mut targets: AutomationTargets
+) {
+    // Parameters whose lanes were removed or emptied go back to where they were
+    if automation.is_changed() {
+        let automated = |parameter: &AutomatedParameter| automation.lanes.iter().any(|lane| lane.parameter == *parameter && !lane.keyframes.is_empty());
+        let released: Vec<(AutomatedParameter, f32)> = baseline.0.iter().filter(|(parameter, _)| !automated(parameter)).map(|(parameter, value)| (*parameter, *value)).collect();
+        for (parameter, value) in released {
+            baseline.0.remove(&parameter);
+            targets.set(parameter, value);
+        }
+    }
+
+    for lane in &automation.lanes {
+        let Some(value) = lane.value_at(playback.position) else { continue };
+        let parameter = lane.parameter;
+        baseline.0.entry(parameter).or_insert_with(|| targets.get(parameter));
+        targets.set(parameter, value);
+    }
+}
+
+/// Gives the cameras showing the scene `SceneBloom`'s bloom, including cameras spawned later, like the recorder's.
+/// Bloom needs HDR, so it's turned on and off with it.
+pub fn apply_scene_bloom(
+    mut commands: Commands,
+    bloom: Res<SceneBloom>,
+    mut cameras: Query<(Entity, &mut Camera, &OutputCamera, Option<&mut Bloom>), With<Camera3d>>,
+    added: Query<(), Added<OutputCamera>>
+) {
+    if !bloom.is_changed() && added.is_empty() {
+        return;
+    }
+    for (entity, mut camera, output, camera_bloom) in cameras.iter_mut() {
+        // The clean feed is the camera image alone
+        if *output == OutputCamera::CleanFeed {
+            continue;
+        }
+        match camera_bloom {
+            Some(mut camera_bloom) if bloom.intensity > 0.0 => camera_bloom.intensity = bloom.intensity,
+            Some(_) => {
+                camera.hdr = false;
+                commands.entity(entity).remove::<Bloom>();
+            }
+            None if bloom.intensity > 0.0 => {
+                camera.hdr = true;
+                commands.entity(entity).insert(Bloom { intensity: bloom.intensity, ..Bloom::NATURAL });
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lanes_interpolate_between_keyframes_and_hold_at_the_ends() {
+        let lane = AutomationLane {
+            parameter: AutomatedParameter::NoteSpeed,
+            keyframes: vec![Keyframe { time: 2.0, value: 1.0 }, Keyframe { time: 4.0, value: 3.0 }]
+        };
+        assert_eq!(lane.value_at(0.0), Some(1.0));
+        assert_eq!(lane.value_at(3.0), Some(2.0));
+        assert_eq!(lane.value_at(4.0), Some(3.0));
+        assert_eq!(lane.value_at(10.0), Some(3.0));
+        assert_eq!(AutomationLane { parameter: AutomatedParameter::KeyHue, keyframes: Vec::new() }.value_at(1.0), None);
+    }
+
+    #[test]
+    fn hue_lanes_take_the_short_way_around() {
+        let lane = AutomationLane {
+            parameter: AutomatedParameter::KeyHue,
+            keyframes: vec![Keyframe { time: 0.0, value: 350.0 }, Keyframe { time: 2.0, value: 10.0 }]
+        };
+        assert_eq!(lane.value_at(0.5), Some(355.0));
+        assert_eq!(lane.value_at(1.5), Some(5.0));
+    }
+}
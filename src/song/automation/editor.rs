@@ -0,0 +1,199 @@
+//! The automation editor, opened from the command palette. It shows one lane at a time as a curve over the whole song
+//! with the playhead, where clicking adds a keyframe and right-clicking one removes it. Keyframes can also be added at
+//! the playhead with the parameter's current value, and fine-tuned in the list below the curve.
+
+use bevy::{app::{App, Plugin, Update}, ecs::{event::EventReader, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use crate::{command::{self, AppCommand}, song::{playback::SongPlayback, Song}};
+
+use super::{AutomatedParameter, AutomationTargets, Keyframe, SongAutomation};
+
+const CURVE_HEIGHT: f32 = 120.0;
+/// How close a right-click has to be to a keyframe to remove it, in points.
+const PICK_RADIUS: f32 = 8.0;
+
+#[derive(Resource)]
+struct AutomationEditor {
+    open: bool,
+    parameter: AutomatedParameter,
+    /// The result of the last save.
+    status: Option<String>
+}
+
+impl Default for AutomationEditor {
+    fn default() -> Self {
+        Self {
+            open: false,
+            parameter: AutomatedParameter::NoteSpeed,
+            status: None
+        }
+    }
+}
+
+fn toggle_automation_editor(
+    mut commands: EventReader<AppCommand>,
+    mut editor: ResMut<AutomationEditor>
+) {
+    for command in commands.read() {
+        if *command == AppCommand::ToggleAutomationEditor {
+            editor.open = !editor.open;
+        }
+    }
+}
+
+/// Draws the lane's curve over the song, and returns a keyframe to add or the index of one to remove.
+fn draw_curve(ui: &mut egui::Ui, keyframes: &[Keyframe], parameter: AutomatedParameter, duration: f64, position: f64) -> (Option<Keyframe>, Option<usize>) {
+    let (min, max) = parameter.range();
+    let size = egui::vec2(ui.available_width().max(400.0), CURVE_HEIGHT);
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::click());
+    let rect = response.rect;
+    let to_screen = |time: f64, value: f32| egui::pos2(
+        rect.left() + (time / duration) as f32 * rect.width(),
+        rect.bottom() - (value - min) / (max - min) * rect.height()
+    );
+
+    painter.rect_filled(rect, 2.0, egui::Color32::from_gray(24));
+    let points: Vec<egui::Pos2> = keyframes.iter().map(|keyframe| to_screen(keyframe.time, keyframe.value)).collect();
+    // The value is held before the first keyframe and after the last
+    if let (Some(first), Some(last)) = (points.first(), points.last()) {
+        let mut line = vec![egui::pos2(rect.left(), first.y)];
+        line.extend(&points);
+        line.push(egui::pos2(rect.right(), last.y));
+        painter.add(egui::Shape::line(line, egui::Stroke::new(1.5, egui::Color32::from_rgb(77, 230, 128))));
+    }
+    for point in &points {
+        painter.circle_filled(*point, 4.0, egui::Color32::WHITE);
+    }
+    let playhead = to_screen(position, min).x;
+    painter.line_segment([egui::pos2(playhead, rect.top()), egui::pos2(playhead, rect.bottom())], egui::Stroke::new(1.0, egui::Color32::from_rgb(255, 200, 60)));
+
+    let Some(pointer) = response.interact_pointer_pos() else { return (None, None) };
+    if response.secondary_clicked() {
+        let nearest = points.iter().enumerate()
+            .map(|(index, point)| (index, point.distance(pointer)))
+            .filter(|(_, distance)| *distance <= PICK_RADIUS)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        return (None, nearest.map(|(index, _)| index));
+    }
+    if response.clicked() {
+        let time = ((pointer.x - rect.left()) / rect.width()) as f64 * duration;
+        let value = min + (rect.bottom() - pointer.y) / rect.height() * (max - min);
+        return (Some(Keyframe { time: time.clamp(0.0, duration), value: value.clamp(min, max) }), None);
+    }
+    (None, None)
+}
+
+fn draw_automation_editor(
+    mut contexts: EguiContexts,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    mut editor: ResMut<AutomationEditor>,
+    mut automation: ResMut<SongAutomation>,
+    targets: AutomationTargets
+) {
+    if !editor.open {
+        return;
+    }
+    let editor = editor.as_mut();
+    let mut open = true;
+
+    egui::Window::new("Automation")
+        .open(&mut open)
+        .default_width(600.0)
+        .show(contexts.ctx_mut(), |ui| {
+            let Some(song_path) = song.path.clone() else {
+                ui.label("Load a song to automate its visuals.");
+                return;
+            };
+
+            egui::ComboBox::from_label("Parameter")
+                .selected_text(editor.parameter.label())
+                .show_ui(ui, |ui| {
+                    for parameter in AutomatedParameter::ALL {
+                        ui.selectable_value(&mut editor.parameter, parameter, parameter.label());
+                    }
+                });
+
+            // Only take the automation mutably when it's edited, so playback isn't re-applied every frame
+            let parameter = editor.parameter;
+            let keyframes = automation.lanes.iter().find(|lane| lane.parameter == parameter).map(|lane| lane.keyframes.clone()).unwrap_or_default();
+            let duration = song.duration.max(1.0);
+            let (added, removed) = draw_curve(ui, &keyframes, parameter, duration, playback.position);
+            ui.label("Click to add a keyframe, right-click one to remove it.");
+
+            let mut edited = keyframes.clone();
+            if let Some(keyframe) = added {
+                edited.push(keyframe);
+            }
+            if let Some(index) = removed {
+                edited.remove(index);
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button("Add at playhead").on_hover_text("Adds a keyframe with the parameter's current value").clicked() {
+                    edited.push(Keyframe { time: playback.position, value: targets.get(parameter) });
+                }
+                if ui.button("Clear lane").clicked() {
+                    edited.clear();
+                }
+            });
+
+            let (min, max) = parameter.range();
+            let mut deleted = None;
+            egui::ScrollArea::vertical().max_height(160.0).show(ui, |ui| {
+                for (index, keyframe) in edited.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut keyframe.time).range(0.0..=duration).speed(0.05).suffix(" s"));
+                        ui.add(egui::DragValue::new(&mut keyframe.value).range(min..=max).speed((max - min) / 200.0));
+                        if ui.small_button("Remove").clicked() {
+                            deleted = Some(index);
+                        }
+                    });
+                }
+            });
+            if let Some(index) = deleted {
+                edited.remove(index);
+            }
+
+            if edited != keyframes {
+                let lane = automation.lane_mut(parameter);
+                lane.keyframes = edited;
+                lane.sort();
+                editor.status = None;
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    let path = SongAutomation::path_for(&song_path);
+                    // Lanes without keyframes aren't worth keeping in the file
+                    let mut saved = automation.clone();
+                    saved.lanes.retain(|lane| !lane.keyframes.is_empty());
+                    editor.status = Some(match saved.save(&path) {
+                        Ok(()) => format!("Saved to {}", path.display()),
+                        Err(err) => format!("Failed to save {}: {}", path.display(), err)
+                    });
+                }
+                if let Some(status) = &editor.status {
+                    ui.label(status);
+                }
+            });
+        });
+
+    editor.open = open;
+}
+
+pub struct AutomationEditorPlugin;
+
+impl Plugin for AutomationEditorPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+
+        app
+            .init_resource::<AutomationEditor>()
+            .add_systems(Update, (toggle_automation_editor, draw_automation_editor).chain().after(command::run_app_commands));
+    }
+}
@@ -0,0 +1,306 @@
+//! Finger number hints. Fingerings come from Synthesia metadata (see `synthesia`), or from a sidecar next to the song:
+//! `song.fingering.json` listing `{ "time": seconds, "key": 60, "finger": 1 }` entries, or the song's score as
+//! `song.musicxml` with `<fingering>` marks. Each annotation goes to the song note on its key closest to its time.
+//!
+//! MusicXML positions are in beats, converted with the MIDI file's tempo map, so the score has to be the one the MIDI
+//! file was made from. Repeats aren't expanded.
+//!
+//! The numbers are drawn as small cards at the bottom of each waterfall bar and on keys while they're held, during
+//! practice runs or all the time. The cards are part of the scene, turned to face the main window's camera.
+
+use std::{collections::HashMap, error::Error, fs, path::{Path, PathBuf}};
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle, RenderAssetUsages}, color::Color, core_pipeline::core_3d::Camera3d, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, image::Image, math::{primitives::Rectangle, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::{mesh::{Mesh, Mesh3d}, render_resource::{Extent3d, TextureDimension, TextureFormat}}, transform::components::{GlobalTransform, Transform}};
+use serde::Deserialize;
+
+use crate::{keyboard::{self, KeyboardLayout, KeyboardRoot}, midi::latency::LatencyCompensation, practice::PracticeSession, render_layers::OutputCamera, video::tracking::FadeWithTracking};
+
+use super::{musicxml, playback::{self, SongPlayback}, waterfall::WaterfallSettings, Song, TempoMap};
+
+/// How far an annotation can be from a note's start and still be given to it, in seconds.
+const MATCH_TOLERANCE: f64 = 0.05;
+/// The width and height of a hint card, in mm.
+const HINT_SIZE: f32 = 14.0;
+/// Each finger's digit in a 3x5 grid, top row first, drawn on the cards.
+static DIGITS: [[&str; 5]; 5] = [
+    [".#.", "##.", ".#.", ".#.", "###"],
+    ["###", "..#", "###", "#..", "###"],
+    ["###", "..#", "###", "..#", "###"],
+    ["#.#", "#.#", "###", "..#", "..#"],
+    ["###", "#..", "###", "..#", "###"]
+];
+/// How many image pixels each cell of a digit takes up.
+const DIGIT_CELL_PIXELS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FingerHintMode {
+    Off,
+    /// Only during practice runs.
+    #[default]
+    Practice,
+    Always
+}
+
+#[derive(Resource, Clone, PartialEq, Default)]
+pub struct FingerHintSettings {
+    pub mode: FingerHintMode
+}
+
+/// A finger for the note played on a key at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct FingerAnnotation {
+    /// Seconds from the start of the song.
+    pub time: f64,
+    pub key: u8,
+    /// 1 (thumb) through 5 (little finger).
+    pub finger: u8
+}
+
+/// The fingering sidecars of a song, in the order they're tried.
+fn sidecar_paths(song: &Path) -> [PathBuf; 2] {
+    [song.with_extension("fingering.json"), song.with_extension("musicxml")]
+}
+
+/// Loads the fingering from the first sidecar next to the song, if there is one.
+pub fn load_sidecar(song: &Path, tempo_map: &TempoMap) -> Option<Result<Vec<FingerAnnotation>, Box<dyn Error>>> {
+    // A score loaded as the song already has its fingering
+    let path = sidecar_paths(song).into_iter().find(|path| path.exists() && path != song)?;
+    let load = || -> Result<Vec<FingerAnnotation>, Box<dyn Error>> {
+        let text = fs::read_to_string(&path)?;
+        if path.extension().is_some_and(|extension| extension == "musicxml") {
+            Ok(musicxml_fingerings(&text)?.into_iter()
+                .map(|(beats, key, finger)| FingerAnnotation {
+                    time: tempo_map.tick_to_seconds((beats * tempo_map.ticks_per_beat as f64).round().max(0.0) as u64),
+                    key,
+                    finger
+                })
+                .collect())
+        } else {
+            Ok(serde_json::from_str(&text)?)
+        }
+    };
+    Some(load().map_err(|err| format!("{}: {}", path.display(), err).into()))
+}
+
+/// Gives each annotation's finger to the note on its key that starts closest to it. Returns how many notes got one.
+pub fn apply_fingering(song: &mut Song, annotations: &[FingerAnnotation]) -> usize {
+    let mut applied = 0;
+    for annotation in annotations.iter().filter(|annotation| (1..=5).contains(&annotation.finger)) {
+        let first = song.notes.partition_point(|note| note.start < annotation.time - MATCH_TOLERANCE);
+        let closest = song.notes[first..].iter_mut()
+            .take_while(|note| note.start <= annotation.time + MATCH_TOLERANCE)
+            .filter(|note| note.key == annotation.key)
+            .min_by(|a, b| (a.start - annotation.time).abs().total_cmp(&(b.start - annotation.time).abs()));
+        if let Some(note) = closest {
+            note.finger = Some(annotation.finger);
+            applied += 1;
+        }
+    }
+    applied
+}
+
+/// Reads the fingered notes of a MusicXML score as (start in beats, MIDI key, finger), from every part.
+pub fn musicxml_fingerings(text: &str) -> Result<Vec<(f64, u8, u8)>, Box<dyn Error>> {
+    Ok(musicxml::Score::parse(text)?.notes.into_iter()
+        .filter_map(|note| Some((note.start, note.key, note.finger?)))
+        .collect())
+}
+
+/// A card showing a note's finger, by index into `Song::notes` and whether it's on the key rather than the waterfall.
+#[derive(Resource, Default)]
+struct FingerHints(HashMap<(usize, bool), Entity>);
+
+#[derive(Component)]
+struct FingerHint;
+
+#[derive(Resource)]
+struct FingerHintAssets {
+    mesh: Handle<Mesh>,
+    /// One per finger, from the thumb.
+    materials: Vec<Handle<StandardMaterial>>
+}
+
+/// Draws a finger's digit in white on a dark square, with a border of one cell.
+fn digit_image(finger: u8) -> Image {
+    let rows = DIGITS[finger as usize - 1];
+    // 7 cells square, so the 3x5 digit is centered with a border
+    let size = 7 * DIGIT_CELL_PIXELS;
+    let mut data = [24, 24, 24, 255].repeat(size * size);
+    for (row, cells) in rows.iter().enumerate() {
+        for (column, cell) in cells.chars().enumerate() {
+            if cell != '#' {
+                continue;
+            }
+            for y in (row + 1) * DIGIT_CELL_PIXELS..(row + 2) * DIGIT_CELL_PIXELS {
+                let start = (y * size + (column + 2) * DIGIT_CELL_PIXELS) * 4;
+                data[start..start + DIGIT_CELL_PIXELS * 4].fill(255);
+            }
+        }
+    }
+
+    let size = Extent3d {
+        width: size as u32,
+        height: size as u32,
+        depth_or_array_layers: 1
+    };
+    Image::new(size, TextureDimension::D2, data, TextureFormat::Rgba8UnormSrgb, RenderAssetUsages::default())
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut images: ResMut<Assets<Image>>
+) {
+    commands.insert_resource(FingerHintAssets {
+        mesh: meshes.add(Rectangle::new(HINT_SIZE, HINT_SIZE)),
+        materials: (1..=5).map(|finger| materials.add(StandardMaterial {
+            base_color: Color::WHITE,
+            base_color_texture: Some(images.add(digit_image(finger))),
+            unlit: true,
+            ..Default::default()
+        })).collect()
+    });
+}
+
+/// Places a card at the bottom of each visible fingered waterfall bar, and on the keys of fingered notes being held.
+#[allow(clippy::too_many_arguments)]
+fn update_finger_hints(
+    mut commands: Commands,
+    settings: Res<FingerHintSettings>,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    practice: Res<PracticeSession>,
+    waterfall: Res<WaterfallSettings>,
+    latency: Res<LatencyCompensation>,
+    keyboard_layout: Res<KeyboardLayout>,
+    assets: Res<FingerHintAssets>,
+    root: Single<(Entity, &GlobalTransform), With<KeyboardRoot>>,
+    cameras: Query<(&GlobalTransform, &OutputCamera), With<Camera3d>>,
+    mut hints: ResMut<FingerHints>,
+    mut transforms: Query<&mut Transform, With<FingerHint>>
+) {
+    if song.is_changed() {
+        for (_, entity) in hints.0.drain() {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    let shown = match settings.mode {
+        FingerHintMode::Off => false,
+        FingerHintMode::Practice => practice.active,
+        FingerHintMode::Always => true
+    };
+    let camera = cameras.iter().find(|(_, output)| **output == OutputCamera::MainWindow);
+    let (Some((camera_transform, _)), true) = (camera, shown) else {
+        for (_, entity) in hints.0.drain() {
+            commands.entity(entity).despawn();
+        }
+        return;
+    };
+    // The cards are in the keyboard's frame, so the camera they turn to face is brought into it
+    let (root, root_transform) = *root;
+    let camera_position = root_transform.affine().inverse().transform_point3(camera_transform.translation());
+
+    // The same window of the song the waterfall shows
+    let span = waterfall.visible_span(playback.speed);
+    let mm_per_second = waterfall.height as f64 / span;
+    let position = playback.position - latency.video * playback.speed;
+    let last = song.notes.partition_point(|note| note.start < position + span);
+
+    let mut kept = HashMap::new();
+    for (index, note) in song.notes[..last].iter().enumerate() {
+        let Some(finger) = note.finger.filter(|finger| (1..=5).contains(finger)) else { continue };
+        if note.end() <= position || !keyboard_layout.keys().contains(&note.key) {
+            continue;
+        }
+        let base = if keyboard::is_black_key(note.key) { 12.0 } else { 2.0 };
+        let center = keyboard_layout.key_center(note.key);
+        let held = note.start <= position;
+        let anchor = if held {
+            // On the front of the key, below where the bar meets it
+            let (_, length) = keyboard_layout.key_size(note.key);
+            center.with_y(base + HINT_SIZE / 2.0).with_z(center.z + length * 0.3)
+        } else {
+            center.with_y(base + ((note.start - position) * mm_per_second) as f32 + HINT_SIZE / 2.0)
+        };
+        // The card's front faces +z, so its -z points away from the camera
+        let transform = Transform::from_translation(anchor).looking_to(anchor - camera_position, Vec3::Y);
+
+        let label = (index, held);
+        let entity = match hints.0.remove(&label) {
+            Some(entity) => {
+                if let Ok(mut existing) = transforms.get_mut(entity) {
+                    *existing = transform;
+                }
+                entity
+            }
+            None => commands.spawn((
+                Mesh3d(assets.mesh.clone()),
+                MeshMaterial3d(assets.materials[finger as usize - 1].clone()),
+                transform,
+                FingerHint,
+                FadeWithTracking,
+                ChildOf(root)
+            )).id()
+        };
+        kept.insert(label, entity);
+    }
+    for (_, entity) in hints.0.drain() {
+        commands.entity(entity).despawn();
+    }
+    hints.0 = kept;
+}
+
+pub struct FingerHintPlugin;
+
+impl Plugin for FingerHintPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<FingerHintSettings>()
+            .init_resource::<FingerHints>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, update_finger_hints.after(playback::advance_playback));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::SongNote;
+
+    #[test]
+    fn musicxml_fingerings_follow_chords_backups_and_ties() {
+        let score = r#"<score-partwise><part id="P1"><measure number="1">
+            <attributes><divisions>2</divisions></attributes>
+            <note><pitch><step>C</step><octave>4</octave></pitch><duration>2</duration>
+                <notations><technical><fingering>1</fingering></technical></notations></note>
+            <note><chord/><pitch><step>E</step><octave>4</octave></pitch><duration>2</duration>
+                <notations><technical><fingering>3</fingering></technical></notations></note>
+            <note><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch><duration>4</duration><tie type="start"/>
+                <notations><technical><fingering>4</fingering></technical></notations></note>
+            <backup><duration>6</duration></backup>
+            <note><pitch><step>C</step><octave>3</octave></pitch><duration>4</duration>
+                <notations><technical><fingering>5</fingering></technical></notations></note>
+            </measure><measure number="2">
+            <note><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch><duration>4</duration><tie type="stop"/>
+                <notations><technical><fingering>4</fingering></technical></notations></note>
+        </measure></part></score-partwise>"#;
+        assert_eq!(musicxml_fingerings(score).unwrap(), vec![(0.0, 60, 1), (0.0, 64, 3), (1.0, 66, 4), (0.0, 48, 5)]);
+    }
+
+    #[test]
+    fn annotations_go_to_the_closest_note_on_their_key() {
+        let note = |key: u8, start: f64| SongNote { key, velocity: 100, channel: 0, track: 0, start, duration: 0.5, hand: None, finger: None };
+        let mut song = Song { notes: vec![note(60, 0.0), note(64, 0.0), note(60, 0.5), note(60, 1.0)], ..Default::default() };
+        let applied = apply_fingering(&mut song, &[
+            FingerAnnotation { time: 0.52, key: 60, finger: 2 },
+            FingerAnnotation { time: 0.01, key: 64, finger: 3 },
+            FingerAnnotation { time: 2.0, key: 60, finger: 1 }
+        ]);
+        assert_eq!(applied, 2);
+        let fingers: Vec<Option<u8>> = song.notes.iter().map(|note| note.finger).collect();
+        assert_eq!(fingers, vec![None, Some(3), Some(2), None]);
+    }
+}
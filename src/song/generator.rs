@@ -0,0 +1,223 @@
+//! Generators for scale and sight-reading exercises.
+//! Generated exercises are saved into the song library so they can be revisited or shared.
+
+use std::{path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use bevy::ecs::{event::{Event, EventReader, EventWriter}, system::{Local, Res, ResMut}};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
+
+use crate::{chord::PITCH_CLASS_NAMES, command::AppCommand, seed::RandomSeed};
+
+use super::{Hand, Song, SongNote, TempoMap, TimeSignature, LIBRARY_DIR};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleKind {
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    Chromatic
+}
+
+impl ScaleKind {
+    /// Semitone offsets from the tonic within one octave.
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            ScaleKind::Major => &[0, 2, 4, 5, 7, 9, 11],
+            ScaleKind::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            ScaleKind::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            ScaleKind::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ScaleKind::Major => "major",
+            ScaleKind::NaturalMinor => "natural-minor",
+            ScaleKind::HarmonicMinor => "harmonic-minor",
+            ScaleKind::Chromatic => "chromatic"
+        }
+    }
+
+    /// Returns the scale's keys between `low` and `high`, inclusive.
+    pub fn keys_in_range(&self, tonic: u8, low: u8, high: u8) -> Vec<u8> {
+        (low..=high)
+            .filter(|key| self.intervals().contains(&((key + 12 - tonic % 12) % 12)))
+            .collect()
+    }
+}
+
+#[derive(Event, Debug, Clone)]
+pub enum GenerateExercise {
+    Scale {
+        /// The MIDI key of the right hand's starting note.
+        tonic: u8,
+        kind: ScaleKind,
+        octaves: u8,
+        hands_together: bool,
+        bpm: f64
+    },
+    SightReading {
+        tonic: u8,
+        kind: ScaleKind,
+        measures: u32,
+        bpm: f64
+    }
+}
+
+fn tempo_map(bpm: f64) -> TempoMap {
+    TempoMap {
+        ticks_per_beat: 480,
+        tempos: vec![(0, (60_000_000.0 / bpm) as u32)],
+        time_signatures: vec![TimeSignature { tick: 0, numerator: 4, denominator: 4 }]
+    }
+}
+
+fn exercise_note(key: u8, track: usize, hand: Hand, start: f64, duration: f64) -> SongNote {
+    SongNote {
+        key,
+        velocity: 80,
+        channel: 0,
+        track,
+        start,
+        duration,
+        hand: Some(hand),
+        finger: None
+    }
+}
+
+/// Generates a scale played in eighth notes, ascending then descending.
+/// The left hand plays an octave below the right when `hands_together` is set.
+pub fn scale_exercise(tonic: u8, kind: ScaleKind, octaves: u8, hands_together: bool, bpm: f64) -> Song {
+    let step = 60.0 / bpm / 2.0;
+    let high = tonic.saturating_add(octaves * 12).min(127);
+    let ascending = kind.keys_in_range(tonic, tonic, high);
+    let keys: Vec<u8> = ascending.iter().chain(ascending.iter().rev().skip(1)).copied().collect();
+
+    let mut notes = Vec::new();
+    for (i, &key) in keys.iter().enumerate() {
+        let start = i as f64 * step;
+        notes.push(exercise_note(key, 1, Hand::Right, start, step * 0.9));
+        if hands_together && key >= 12 {
+            notes.push(exercise_note(key - 12, 2, Hand::Left, start, step * 0.9));
+        }
+    }
+
+    let title = format!("{} {} scale", PITCH_CLASS_NAMES[tonic as usize % 12], kind.name());
+    exercise_song(title, notes, bpm)
+}
+
+/// Generates a random right hand melody in 4/4 using notes from the given scale,
+/// over a left hand holding the tonic for each measure.
+pub fn sight_reading_exercise(tonic: u8, kind: ScaleKind, measures: u32, bpm: f64, rng: &mut impl Rng) -> Song {
+    let beat = 60.0 / bpm;
+    // Stay within about an octave above the tonic so the melody remains readable
+    let keys = kind.keys_in_range(tonic, tonic, tonic.saturating_add(12).min(127));
+    let mut notes = Vec::new();
+
+    let mut current = 0;
+    for measure in 0..measures {
+        let measure_start = measure as f64 * 4.0 * beat;
+        if tonic >= 12 {
+            notes.push(exercise_note(tonic - 12, 2, Hand::Left, measure_start, 4.0 * beat * 0.95));
+        }
+
+        let mut position = 0.0;
+        while position < 4.0 {
+            let remaining = 4.0 - position;
+            let lengths: Vec<f64> = [0.5, 1.0, 1.0, 2.0].into_iter().filter(|&length| length <= remaining).collect();
+            let length = lengths.choose(rng).copied().unwrap_or(remaining);
+
+            // Favor stepwise motion with occasional leaps
+            let step: i32 = if rng.gen_bool(0.75) { rng.gen_range(-1..=1) } else { rng.gen_range(-3..=3) };
+            current = (current as i32 + step).clamp(0, keys.len() as i32 - 1) as usize;
+
+            notes.push(exercise_note(keys[current], 1, Hand::Right, measure_start + position * beat, length * beat * 0.9));
+            position += length;
+        }
+    }
+
+    let title = format!("{} {} sight reading", PITCH_CLASS_NAMES[tonic as usize % 12], kind.name());
+    exercise_song(title, notes, bpm)
+}
+
+fn exercise_song(title: String, mut notes: Vec<SongNote>, bpm: f64) -> Song {
+    notes.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.key.cmp(&b.key)));
+    let duration = notes.iter().map(SongNote::end).fold(0.0, f64::max);
+    Song {
+        title,
+        notes,
+        tempo_map: tempo_map(bpm),
+        duration,
+        ..Default::default()
+    }
+}
+
+pub fn exercise_commands(
+    mut commands: EventReader<AppCommand>,
+    mut events: EventWriter<GenerateExercise>
+) {
+    for command in commands.read() {
+        match command {
+            AppCommand::GenerateScaleExercise => {
+                events.write(GenerateExercise::Scale { tonic: 60, kind: ScaleKind::Major, octaves: 2, hands_together: true, bpm: 90.0 });
+            }
+            AppCommand::GenerateSightReading => {
+                events.write(GenerateExercise::SightReading { tonic: 60, kind: ScaleKind::Major, measures: 8, bpm: 80.0 });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Generates requested exercises, saves them into the library, and loads them as the current song.
+/// Exercises are drawn from the seeded "exercises" stream, so the same seed generates the same sequence of exercises.
+pub fn generate_exercises(
+    mut events: EventReader<GenerateExercise>,
+    mut song: ResMut<Song>,
+    seed: Res<RandomSeed>,
+    mut rng: Local<Option<(RandomSeed, StdRng)>>
+) {
+    if rng.as_ref().is_none_or(|(rng_seed, _)| rng_seed != &*seed) {
+        *rng = Some((*seed, seed.rng("exercises")));
+    }
+    let Some((_, rng)) = rng.as_mut() else { return };
+
+    for event in events.read() {
+        let mut generated = match *event {
+            GenerateExercise::Scale { tonic, kind, octaves, hands_together, bpm } => {
+                scale_exercise(tonic, kind, octaves, hands_together, bpm)
+            }
+            GenerateExercise::SightReading { tonic, kind, measures, bpm } => {
+                sight_reading_exercise(tonic, kind, measures, bpm, rng)
+            }
+        };
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default();
+        let file_name = format!("{}-{}.mid", generated.title.to_lowercase().replace(' ', "-"), timestamp);
+        let path = Path::new(LIBRARY_DIR).join(file_name);
+        match generated.save(&path) {
+            Ok(()) => {
+                println!("Saved exercise '{}' to {}", generated.title, path.display());
+                generated.path = Some(path);
+            }
+            Err(err) => eprintln!("Failed to save exercise to {}: {}", path.display(), err)
+        }
+
+        *song = generated;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_generates_same_exercise() {
+        let generate = |seed: u64| {
+            let song = sight_reading_exercise(60, ScaleKind::Major, 4, 80.0, &mut RandomSeed(seed).rng("exercises"));
+            song.notes.iter().map(|note| (note.key, note.start, note.duration)).collect::<Vec<_>>()
+        };
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7), generate(8));
+    }
+}
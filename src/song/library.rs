@@ -0,0 +1,289 @@
+//! The song library browser, opened with L or the command palette. It lists the MIDI files and MusicXML scores in
+//! `LIBRARY_DIR` with their titles, lengths, and estimated difficulty. Typing filters the list by title, the arrow keys
+//! or the mouse choose a song, and Enter or a double click loads it. Escape closes the browser.
+//!
+//! The library is read in the background every time the browser opens, so songs added while the app runs show up.
+
+use std::{path::PathBuf, thread};
+
+use bevy::{app::{App, Plugin, PreUpdate, Update}, ecs::{event::{EventReader, EventWriter}, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}, input::{keyboard::KeyCode, ButtonInput, InputSystem}};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+use crossbeam_channel::Receiver;
+
+use crate::command::{self, AppCommand};
+
+use super::{library_songs, LoadSong, Song, LIBRARY_DIR};
+
+/// Notes starting within this many seconds of each other count as played together.
+const CHORD_WINDOW: f64 = 0.03;
+
+#[derive(Debug, Clone)]
+struct LibraryEntry {
+    path: PathBuf,
+    title: String,
+    /// The length in seconds.
+    duration: f64,
+    difficulty: f32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum SortOrder {
+    #[default]
+    Title,
+    Difficulty,
+    Length
+}
+
+impl SortOrder {
+    const ALL: [SortOrder; 3] = [SortOrder::Title, SortOrder::Difficulty, SortOrder::Length];
+
+    fn label(self) -> &'static str {
+        match self {
+            SortOrder::Title => "Title",
+            SortOrder::Difficulty => "Difficulty",
+            SortOrder::Length => "Length"
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct SongLibrary {
+    open: bool,
+    query: String,
+    sort: SortOrder,
+    /// The index of the chosen song among the ones matching the query.
+    selected: usize,
+    entries: Vec<LibraryEntry>,
+    /// The library being read, while it is.
+    reading: Option<Receiver<Vec<LibraryEntry>>>
+}
+
+/// A rough difficulty from 1 to 10: how many times a second notes are struck, how many are struck together, and how far
+/// the hands jump between them.
+fn estimate_difficulty(song: &Song) -> f32 {
+    // The notes are sorted by start time, so chords are runs of notes starting close together
+    let mut onsets: Vec<(f64, Vec<u8>)> = Vec::new();
+    for note in &song.notes {
+        match onsets.last_mut() {
+            Some((start, keys)) if note.start - *start <= CHORD_WINDOW => keys.push(note.key),
+            _ => onsets.push((note.start, vec![note.key]))
+        }
+    }
+    let (Some(first), Some(last)) = (onsets.first(), onsets.last()) else { return 1.0 };
+
+    let playing_time = (last.0 - first.0).max(1.0);
+    let onsets_per_second = onsets.len() as f64 / playing_time;
+    let notes_per_onset = song.notes.len() as f64 / onsets.len() as f64;
+    let center = |keys: &[u8]| keys.iter().map(|&key| key as f64).sum::<f64>() / keys.len() as f64;
+    let leap = onsets.windows(2).map(|pair| (center(&pair[1].1) - center(&pair[0].1)).abs()).sum::<f64>() / (onsets.len() - 1).max(1) as f64;
+    (1.0 + onsets_per_second * 0.7 + (notes_per_onset - 1.0) * 1.2 + leap / 5.0).clamp(1.0, 10.0) as f32
+}
+
+fn difficulty_label(difficulty: f32) -> &'static str {
+    match difficulty {
+        difficulty if difficulty < 3.0 => "Beginner",
+        difficulty if difficulty < 6.0 => "Intermediate",
+        difficulty if difficulty < 8.0 => "Advanced",
+        _ => "Expert"
+    }
+}
+
+/// Loads every song in the library to describe it. Songs that fail to load are left out.
+fn read_library() -> Vec<LibraryEntry> {
+    library_songs().into_iter()
+        .filter_map(|path| match Song::load_with_metadata(&path) {
+            Ok(song) => Some(LibraryEntry {
+                title: song.title.clone(),
+                duration: song.duration,
+                difficulty: estimate_difficulty(&song),
+                path
+            }),
+            Err(err) => {
+                eprintln!("Failed to read {} for the library: {}", path.display(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+fn toggle_library(
+    mut commands: EventReader<AppCommand>,
+    mut library: ResMut<SongLibrary>
+) {
+    for command in commands.read() {
+        if *command != AppCommand::ToggleLibrary {
+            continue;
+        }
+        library.open = !library.open;
+        if library.open && library.reading.is_none() {
+            let (sender, receiver) = crossbeam_channel::bounded(1);
+            let _ = thread::Builder::new()
+                .name("song library".to_string())
+                .spawn(move || {
+                    let _ = sender.send(read_library());
+                });
+            library.reading = Some(receiver);
+            library.query.clear();
+            library.selected = 0;
+        }
+    }
+}
+
+/// Clears the keyboard input while the browser is open, before any hotkeys see it, so typing a search doesn't trigger them.
+fn block_hotkeys(
+    library: Res<SongLibrary>,
+    mut keys: ResMut<ButtonInput<KeyCode>>
+) {
+    if library.open {
+        keys.reset_all();
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn draw_library(
+    mut contexts: EguiContexts,
+    mut library: ResMut<SongLibrary>,
+    mut load_requests: EventWriter<LoadSong>
+) {
+    if !library.open {
+        return;
+    }
+    let library = library.as_mut();
+    if let Some(entries) = library.reading.as_ref().and_then(|reading| reading.try_recv().ok()) {
+        library.entries = entries;
+        library.reading = None;
+    }
+    let ctx = contexts.ctx_mut();
+
+    // Taken before the search field sees them, so they move the selection instead of the cursor
+    let (up, down, enter, escape) = ctx.input_mut(|input| (
+        input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+        input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+        input.consume_key(egui::Modifiers::NONE, egui::Key::Enter),
+        input.consume_key(egui::Modifiers::NONE, egui::Key::Escape)
+    ));
+
+    let query = library.query.to_lowercase();
+    let mut matching: Vec<&LibraryEntry> = library.entries.iter()
+        .filter(|entry| query.split_whitespace().all(|word| entry.title.to_lowercase().contains(word)))
+        .collect();
+    match library.sort {
+        SortOrder::Title => matching.sort_by_key(|entry| entry.title.to_lowercase()),
+        SortOrder::Difficulty => matching.sort_by(|a, b| a.difficulty.total_cmp(&b.difficulty)),
+        SortOrder::Length => matching.sort_by(|a, b| a.duration.total_cmp(&b.duration))
+    }
+    if down {
+        library.selected += 1;
+    }
+    if up {
+        library.selected = library.selected.saturating_sub(1);
+    }
+    library.selected = library.selected.min(matching.len().saturating_sub(1));
+
+    let mut open = true;
+    let mut chosen = None;
+    let mut clicked = None;
+    egui::Window::new("Song library")
+        .open(&mut open)
+        .default_width(520.0)
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let search = ui.add(egui::TextEdit::singleline(&mut library.query).hint_text("Search by title").desired_width(280.0));
+                search.request_focus();
+                if search.changed() {
+                    library.selected = 0;
+                }
+                egui::ComboBox::from_label("Sort by")
+                    .selected_text(library.sort.label())
+                    .show_ui(ui, |ui| {
+                        for sort in SortOrder::ALL {
+                            ui.selectable_value(&mut library.sort, sort, sort.label());
+                        }
+                    });
+            });
+            ui.separator();
+
+            if library.reading.is_some() {
+                ui.label("Reading the library...");
+            } else if library.entries.is_empty() {
+                ui.label(format!("No songs found. Put MIDI files or MusicXML scores in {}", LIBRARY_DIR));
+            } else if matching.is_empty() {
+                ui.label("No matching songs");
+            }
+            egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                egui::Grid::new("library").num_columns(3).striped(true).show(ui, |ui| {
+                    for (index, entry) in matching.iter().enumerate() {
+                        let row = ui.selectable_label(index == library.selected, &entry.title);
+                        if index == library.selected && (up || down) {
+                            row.scroll_to_me(None);
+                        }
+                        if row.clicked() {
+                            clicked = Some(index);
+                        }
+                        if row.double_clicked() {
+                            chosen = Some(entry.path.clone());
+                        }
+                        ui.label(format_duration(entry.duration));
+                        ui.label(format!("{} ({:.1})", difficulty_label(entry.difficulty), entry.difficulty));
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+
+    if enter {
+        chosen = matching.get(library.selected).map(|entry| entry.path.clone());
+    }
+    if let Some(index) = clicked {
+        library.selected = index;
+    }
+    if let Some(path) = chosen {
+        load_requests.write(LoadSong(path));
+        library.open = false;
+    }
+    if escape || !open {
+        library.open = false;
+    }
+}
+
+pub struct SongLibraryPlugin;
+
+impl Plugin for SongLibraryPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+
+        app
+            .init_resource::<SongLibrary>()
+            .add_systems(PreUpdate, block_hotkeys.after(InputSystem))
+            .add_systems(Update, (toggle_library, draw_library).chain().after(command::run_app_commands));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::song::SongNote;
+
+    use super::*;
+
+    #[test]
+    fn fast_chords_rate_harder_than_a_slow_scale() {
+        let note = |key: u8, start: f64| SongNote { key, velocity: 80, channel: 0, track: 0, start, duration: 0.4, hand: None, finger: None };
+        let scale = Song { notes: [60, 62, 64, 65, 67, 69, 71, 72].iter().enumerate().map(|(index, &key)| note(key, index as f64 * 0.6)).collect(), ..Default::default() };
+        let chords = Song {
+            notes: (0..32).flat_map(|index| {
+                let root = if index % 2 == 0 { 48 } else { 67 };
+                [root, root + 4, root + 7].map(|key| note(key, index as f64 * 0.125))
+            }).collect(),
+            ..Default::default()
+        };
+        assert!(estimate_difficulty(&scale) < 3.0);
+        assert!(estimate_difficulty(&chords) > 8.0);
+        assert_eq!(estimate_difficulty(&Song::default()), 1.0);
+    }
+}
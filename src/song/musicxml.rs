@@ -0,0 +1,298 @@
+//! MusicXML import, so lesson material exported from notation software loads like a MIDI file. Uncompressed partwise
+//! scores (`.musicxml` or `.xml`) are read into the song's note timeline:
+//! - each voice of each part becomes a track, in the order they first appear
+//! - the staves of a two-staff part become the right and left hands
+//! - `<fingering>` marks become finger hints
+//! - time signatures and `<sound tempo>` marks become the tempo map, and rehearsal marks become bookmarks
+//!
+//! Tied notes are joined into one note and grace notes are skipped. Repeats aren't expanded, and measures are counted
+//! from the time signatures, so a pickup measure moves the bar lines.
+
+use std::{collections::HashMap, error::Error, fs, path::Path};
+
+use roxmltree::Node;
+
+use super::{Bookmark, Hand, Song, SongNote, TempoMap, TimeSignature};
+
+/// The file extensions of the scores that can be loaded.
+pub static EXTENSIONS: [&str; 2] = ["musicxml", "xml"];
+const TICKS_PER_BEAT: u16 = 480;
+/// The velocity of notes before any `<sound dynamics>` mark, about mezzo-forte.
+const DEFAULT_VELOCITY: u8 = 80;
+
+/// A note of a score. Times are in beats, which are quarter notes like in MIDI files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoreNote {
+    pub start: f64,
+    pub duration: f64,
+    pub key: u8,
+    pub velocity: u8,
+    /// The index of the part the note is in.
+    pub part: usize,
+    pub voice: String,
+    /// 1 for the top staff.
+    pub staff: u8,
+    pub finger: Option<u8>
+}
+
+#[derive(Debug, Default)]
+pub struct Score {
+    pub title: Option<String>,
+    /// Every part's notes in the order they're written, parts one after another.
+    pub notes: Vec<ScoreNote>,
+    /// The MIDI channel and number of staves of each part.
+    pub parts: Vec<(u8, u8)>,
+    /// Tempo changes as (beat, quarter notes per minute), sorted by beat.
+    pub tempos: Vec<(f64, f64)>,
+    /// Time signature changes as (beat, numerator, denominator), from the first part.
+    pub time_signatures: Vec<(f64, u8, u8)>,
+    /// Rehearsal marks as (beat, text), from the first part.
+    pub rehearsals: Vec<(f64, String)>
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|child| child.text()).map(str::trim)
+}
+
+fn number(node: Node, name: &str) -> Option<f64> {
+    text(node, name).and_then(|text| text.parse().ok())
+}
+
+/// The MIDI key of a `<pitch>`.
+fn pitch_key(pitch: Node) -> Option<u8> {
+    let step = match text(pitch, "step")? {
+        "C" => 0,
+        "D" => 2,
+        "E" => 4,
+        "F" => 5,
+        "G" => 7,
+        "A" => 9,
+        "B" => 11,
+        _ => return None
+    };
+    let key = (number(pitch, "octave")? as i32 + 1) * 12 + step + number(pitch, "alter").unwrap_or(0.0).round() as i32;
+    u8::try_from(key).ok().map(|key| key.min(127))
+}
+
+impl Score {
+    pub fn parse(xml: &str) -> Result<Score, Box<dyn Error>> {
+        let document = roxmltree::Document::parse(xml)?;
+        let root = document.root_element();
+        if !root.has_tag_name("score-partwise") {
+            return Err(format!("only partwise scores are supported, not <{}>", root.tag_name().name()).into());
+        }
+
+        let mut score = Score {
+            title: child(root, "work").and_then(|work| text(work, "work-title")).or_else(|| text(root, "movement-title"))
+                .filter(|title| !title.is_empty())
+                .map(str::to_string),
+            ..Default::default()
+        };
+        // MIDI channels are 1-based in MusicXML
+        let channels: HashMap<&str, u8> = root.descendants()
+            .filter(|node| node.has_tag_name("score-part"))
+            .filter_map(|part| Some((part.attribute("id")?, part.descendants().find(|node| node.has_tag_name("midi-channel"))?.text()?.trim().parse::<u8>().ok()?.checked_sub(1)?)))
+            .collect();
+
+        for (index, part) in root.children().filter(|node| node.has_tag_name("part")).enumerate() {
+            let channel = part.attribute("id").and_then(|id| channels.get(id).copied()).unwrap_or(index.min(15) as u8);
+            let mut staves = 1;
+            let mut divisions = 1.0;
+            let mut velocity = DEFAULT_VELOCITY;
+            // In beats: where the next note starts, and where the last note that wasn't part of a chord started
+            let mut position: f64 = 0.0;
+            let mut chord_start = 0.0;
+            // The note each key's unfinished tie started on
+            let mut open_ties: HashMap<u8, usize> = HashMap::new();
+
+            for element in part.children().filter(|node| node.has_tag_name("measure")).flat_map(|measure| measure.children()) {
+                match element.tag_name().name() {
+                    "attributes" => {
+                        divisions = number(element, "divisions").filter(|divisions| *divisions > 0.0).unwrap_or(divisions);
+                        staves = number(element, "staves").map_or(staves, |staves| staves as u8);
+                        let signature = child(element, "time").and_then(|time| Some((text(time, "beats")?.parse().ok()?, text(time, "beat-type")?.parse().ok()?)));
+                        if let (0, Some((numerator, denominator))) = (index, signature) {
+                            score.time_signatures.push((position, numerator, denominator));
+                        }
+                    }
+                    "direction" | "sound" => {
+                        for sound in element.descendants().filter(|node| node.has_tag_name("sound")) {
+                            if let Some(tempo) = sound.attribute("tempo").and_then(|tempo| tempo.parse::<f64>().ok()).filter(|tempo| *tempo > 0.0) {
+                                score.tempos.push((position, tempo));
+                            }
+                            if let Some(dynamics) = sound.attribute("dynamics").and_then(|dynamics| dynamics.parse::<f64>().ok()) {
+                                velocity = (dynamics * 0.9).round().clamp(1.0, 127.0) as u8;
+                            }
+                        }
+                        let rehearsal = element.descendants().find(|node| node.has_tag_name("rehearsal")).and_then(|rehearsal| rehearsal.text());
+                        if let (0, Some(rehearsal)) = (index, rehearsal) {
+                            score.rehearsals.push((position, rehearsal.trim().to_string()));
+                        }
+                    }
+                    "backup" => position = (position - number(element, "duration").unwrap_or(0.0) / divisions).max(0.0),
+                    "forward" => position += number(element, "duration").unwrap_or(0.0) / divisions,
+                    "note" => {
+                        // Chord notes start with the note before them
+                        let duration = number(element, "duration").unwrap_or(0.0) / divisions;
+                        let start = if child(element, "chord").is_some() { chord_start } else { position };
+                        if child(element, "chord").is_none() {
+                            chord_start = position;
+                            position += duration;
+                        }
+
+                        // Grace notes have no duration of their own, and cue notes aren't played
+                        if child(element, "grace").is_some() || child(element, "cue").is_some() {
+                            continue;
+                        }
+                        let Some(key) = child(element, "pitch").and_then(pitch_key) else { continue };
+                        let tie = |kind: &str| element.children().any(|tie| tie.has_tag_name("tie") && tie.attribute("type") == Some(kind));
+                        if tie("stop") {
+                            if let Some(&tied) = open_ties.get(&key) {
+                                let note = &mut score.notes[tied];
+                                note.duration = start + duration - note.start;
+                                if !tie("start") {
+                                    open_ties.remove(&key);
+                                }
+                                continue;
+                            }
+                        }
+
+                        let finger = element.descendants()
+                            .find(|node| node.has_tag_name("fingering"))
+                            .and_then(|fingering| fingering.text())
+                            .and_then(|text| text.trim().chars().next())
+                            .and_then(|digit| digit.to_digit(10))
+                            .filter(|finger| (1..=5).contains(finger));
+                        if tie("start") {
+                            open_ties.insert(key, score.notes.len());
+                        }
+                        score.notes.push(ScoreNote {
+                            start,
+                            duration,
+                            key,
+                            velocity,
+                            part: index,
+                            voice: text(element, "voice").unwrap_or("1").to_string(),
+                            staff: number(element, "staff").map_or(1, |staff| staff as u8),
+                            finger: finger.map(|finger| finger as u8)
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            score.parts.push((channel, staves));
+        }
+
+        score.tempos.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(score)
+    }
+
+    /// Converts the score into a song, as if it had been loaded from a MIDI file at the path.
+    pub fn into_song(self, path: &Path) -> Song {
+        let tick = |beat: f64| (beat * TICKS_PER_BEAT as f64).round().max(0.0) as u64;
+        let mut tempo_map = TempoMap {
+            ticks_per_beat: TICKS_PER_BEAT,
+            tempos: self.tempos.iter().map(|&(beat, tempo)| (tick(beat), (60_000_000.0 / tempo).round() as u32)).collect(),
+            time_signatures: self.time_signatures.iter()
+                .map(|&(beat, numerator, denominator)| TimeSignature { tick: tick(beat), numerator, denominator })
+                .collect()
+        };
+        // Parts often repeat the same marks, and only one tempo can start on a tick
+        tempo_map.tempos.dedup_by_key(|(tick, _)| *tick);
+        tempo_map.time_signatures.dedup_by_key(|signature| signature.tick);
+
+        // Track 0 is left for the tempo map, like in the MIDI files songs are saved as
+        let mut voices: Vec<(usize, &str)> = Vec::new();
+        let mut notes: Vec<SongNote> = self.notes.iter()
+            .map(|note| {
+                let track = match voices.iter().position(|&voice| voice == (note.part, note.voice.as_str())) {
+                    Some(track) => track + 1,
+                    None => {
+                        voices.push((note.part, &note.voice));
+                        voices.len()
+                    }
+                };
+                let (channel, staves) = self.parts[note.part];
+                let start = tempo_map.tick_to_seconds(tick(note.start));
+                SongNote {
+                    key: note.key,
+                    velocity: note.velocity,
+                    channel,
+                    track,
+                    start,
+                    duration: tempo_map.tick_to_seconds(tick(note.start + note.duration)) - start,
+                    hand: (staves >= 2).then_some(if note.staff >= 2 { Hand::Left } else { Hand::Right }),
+                    finger: note.finger
+                }
+            })
+            .collect();
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.key.cmp(&b.key)));
+
+        let bookmarks = self.rehearsals.iter()
+            .map(|(beat, label)| Bookmark { time: tempo_map.tick_to_seconds(tick(*beat)), label: label.clone() })
+            .collect();
+        Song {
+            title: self.title.unwrap_or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()),
+            path: Some(path.to_path_buf()),
+            duration: notes.iter().map(SongNote::end).fold(0.0, f64::max),
+            notes,
+            tempo_map,
+            lyrics: Vec::new(),
+            bookmarks,
+            loop_region: None,
+            preset: None
+        }
+    }
+}
+
+/// Whether the file is a score that `load` can read, by its extension.
+pub fn is_score(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| EXTENSIONS.iter().any(|known| extension.eq_ignore_ascii_case(known)))
+}
+
+pub fn load(path: &Path) -> Result<Song, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    Ok(Score::parse(&text)?.into_song(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voices_become_tracks_and_staves_hands() {
+        let score = r#"<score-partwise>
+            <work><work-title>Lesson 3</work-title></work>
+            <part-list><score-part id="P1"><midi-instrument id="P1-I1"><midi-channel>2</midi-channel></midi-instrument></score-part></part-list>
+            <part id="P1"><measure number="1">
+                <attributes><divisions>2</divisions><time><beats>3</beats><beat-type>4</beat-type></time><staves>2</staves></attributes>
+                <direction><direction-type><rehearsal>A</rehearsal></direction-type><sound tempo="60"/></direction>
+                <note><pitch><step>E</step><octave>4</octave></pitch><duration>4</duration><voice>1</voice><staff>1</staff><tie type="start"/></note>
+                <note><pitch><step>G</step><octave>4</octave></pitch><duration>2</duration><voice>1</voice><staff>1</staff>
+                    <notations><technical><fingering>5</fingering></technical></notations></note>
+                <backup><duration>6</duration></backup>
+                <note><pitch><step>C</step><octave>3</octave></pitch><duration>6</duration><voice>5</voice><staff>2</staff></note>
+            </measure><measure number="2">
+                <note><pitch><step>E</step><octave>4</octave></pitch><duration>2</duration><voice>1</voice><staff>1</staff><tie type="stop"/></note>
+            </measure></part>
+        </score-partwise>"#;
+        let song = Score::parse(score).unwrap().into_song(Path::new("lesson.musicxml"));
+
+        assert_eq!(song.title, "Lesson 3");
+        assert_eq!(song.tempo_map.tempos, vec![(0, 1_000_000)]);
+        assert_eq!(song.tempo_map.measure_to_seconds(2), 3.0);
+        assert_eq!(song.bookmarks[0].label, "A");
+        let summary: Vec<(u8, usize, u8, Option<Hand>, Option<u8>, f64, f64)> = song.notes.iter()
+            .map(|note| (note.key, note.track, note.channel, note.hand, note.finger, note.start, note.duration))
+            .collect();
+        assert_eq!(summary, vec![
+            (48, 2, 1, Some(Hand::Left), None, 0.0, 3.0),
+            (64, 1, 1, Some(Hand::Right), None, 0.0, 4.0),
+            (67, 1, 1, Some(Hand::Right), Some(5), 2.0, 1.0)
+        ]);
+    }
+}
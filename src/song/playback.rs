@@ -0,0 +1,164 @@
+//! The song clock that the practice mode and visuals follow.
+
+use bevy::{ecs::{change_detection::DetectChanges, resource::Resource, system::{Local, Res, ResMut}}, time::Time};
+
+use crate::midi::{bus::{NoteBus, NoteSource}, input_clock, MidiEventKind};
+
+use super::{LoopRegion, Song};
+
+/// How far past the last note playback continues before stopping, in seconds.
+const END_PADDING: f64 = 1.0;
+/// The range of playback speeds, and the step the speed hotkeys change it by.
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 2.0;
+const SPEED_STEP: f64 = 0.25;
+/// A playback jump bigger than this, in seconds, is treated as a seek rather than a frame of playback,
+/// so the notes in between aren't all triggered at once.
+const MAX_STEP: f64 = 0.5;
+
+#[derive(Resource)]
+pub struct SongPlayback {
+    /// The current position in the song in seconds.
+    pub position: f64,
+    pub playing: bool,
+    /// The playback rate, where 1 is the song's original tempo.
+    pub speed: f64,
+    /// How many beats to count in before each repetition of the loop. The notes before the loop aren't played then.
+    pub count_in_beats: u32,
+    /// A position playback waits at rather than passing, while step practice waits for the player to play a note there.
+    pub hold_at: Option<f64>
+}
+
+impl Default for SongPlayback {
+    fn default() -> Self {
+        Self {
+            position: 0.0,
+            playing: false,
+            speed: 1.0,
+            count_in_beats: 0,
+            hold_at: None
+        }
+    }
+}
+
+impl SongPlayback {
+    pub fn restart(&mut self) {
+        self.position = 0.0;
+        self.playing = true;
+    }
+
+    /// Changes the speed by the given number of steps, staying within `MIN_SPEED` and `MAX_SPEED`.
+    pub fn step_speed(&mut self, steps: f64) {
+        self.speed = (self.speed + steps * SPEED_STEP).clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /// How long the count-in before the loop is, in seconds of song time.
+    pub fn count_in_length(&self, song: &Song, region: LoopRegion) -> f64 {
+        self.count_in_beats as f64 * song.tempo_map.beat_length_at(region.start)
+    }
+
+    /// The beat of the count-in playback is on, from 0, while it's counting in to the loop.
+    pub fn count_in_beat(&self, song: &Song) -> Option<u32> {
+        let region = song.active_loop().filter(|_| self.count_in_beats > 0)?;
+        let into_count_in = self.position - (region.start - self.count_in_length(song, region));
+        (into_count_in >= 0.0 && self.position < region.start).then(|| (into_count_in / song.tempo_map.beat_length_at(region.start)) as u32)
+    }
+}
+
+pub fn advance_playback(
+    song: Res<Song>,
+    mut playback: ResMut<SongPlayback>,
+    time: Res<Time>
+) {
+    if song.is_changed() {
+        playback.position = 0.0;
+        playback.playing = false;
+        playback.hold_at = None;
+    }
+
+    if !playback.playing {
+        return;
+    }
+
+    let before = playback.position;
+    playback.position += time.delta_secs_f64() * playback.speed;
+    // A hold that playback was already moved past, by seeking, doesn't pull it back
+    if let Some(hold) = playback.hold_at.filter(|&hold| before <= hold) {
+        playback.position = playback.position.min(hold);
+    }
+    // Reaching the end of the loop, or playing outside it, goes back to the count-in before its start
+    if let Some(region) = song.active_loop() {
+        let count_in = playback.count_in_length(&song, region);
+        if playback.position >= region.end || playback.position < region.start - count_in {
+            playback.position = region.start - count_in;
+        }
+    }
+    if playback.position > song.duration + END_PADDING {
+        playback.playing = false;
+    }
+}
+
+/// The song notes sounding as playback passes over them.
+#[derive(Default)]
+pub struct SongVoices {
+    /// The playback position last frame, or `None` if the song wasn't being played.
+    last_position: Option<f64>,
+    /// (channel, key, end time) of each sounding note.
+    sounding: Vec<(u8, u8, f64)>
+}
+
+impl SongVoices {
+    /// Sends the song notes that playback passed over this frame, and releases the ones that ended.
+    /// Pausing, seeking, or changing the song releases everything that was sounding.
+    fn advance(&mut self, song: &Song, song_changed: bool, playback: &SongPlayback, delta: f64, mut send: impl FnMut(u8, MidiEventKind)) {
+        let position = playback.position;
+        let active = playback.playing;
+        let continuing = self.last_position.is_some_and(|last| position >= last && position - last < MAX_STEP) && !song_changed;
+
+        if !active || !continuing {
+            for (channel, key, _) in self.sounding.drain(..) {
+                send(channel, MidiEventKind::NoteOff { key });
+            }
+        }
+        if !active {
+            self.last_position = None;
+            return;
+        }
+
+        // After starting or seeking, play what playback moved through this frame, so a note right at the start isn't skipped
+        let from = match self.last_position {
+            Some(last) if continuing => last,
+            _ => position - delta * playback.speed
+        };
+        self.last_position = Some(position);
+
+        self.sounding.retain(|&(channel, key, end)| {
+            if end > position {
+                return true;
+            }
+            send(channel, MidiEventKind::NoteOff { key });
+            false
+        });
+
+        // While looping, the notes around the loop are left out, including the ones under the count-in
+        let region = song.active_loop();
+        let first = song.notes.partition_point(|note| note.start < from);
+        let last = song.notes.partition_point(|note| note.start < position);
+        for note in song.notes[first..last.max(first)].iter().filter(|note| region.is_none_or(|region| region.contains(note.start))) {
+            send(note.channel, MidiEventKind::NoteOn { key: note.key, velocity: note.velocity });
+            self.sounding.push((note.channel, note.key, note.end()));
+        }
+    }
+}
+
+/// Publishes the song notes that playback passed over this frame to the note bus.
+pub fn publish_song_notes(
+    time: Res<Time>,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    mut voices: Local<SongVoices>,
+    mut bus: ResMut<NoteBus>
+) {
+    let (now, timestamp) = (time.elapsed_secs_f64(), input_clock());
+    voices.advance(&song, song.is_changed(), &playback, time.delta_secs_f64(), |channel, kind| bus.publish(now, timestamp, NoteSource::Song, channel, kind));
+}
@@ -0,0 +1,138 @@
+//! Per-song presets, so performance pieces can carry their own look. A `.preset.json` file next to a song, like
+//! `{ "theme": "assets/themes/fire.json", "look_ahead": 2.0, "replacement": "motion_mask", "backdrop": "assets/hall.jpg",
+//! "style": "spotlight", "lighting": { "dmx": true, "base_level": 0.3 } }`, overrides those settings while the song is loaded.
+//! Every field is optional. When another song is loaded, the overridden settings go back to what they were before,
+//! including any changes made to them while the song was loaded.
+
+use std::{error::Error, fs, path::{Path, PathBuf}};
+
+use bevy::ecs::{change_detection::DetectChanges, resource::Resource, system::{Res, ResMut}};
+use serde::Deserialize;
+
+use crate::{background::{framing::AutoFramingSettings, replacement::{BackgroundReplacementSettings, ReplacementMode}, style::{BackgroundStyleSettings, StyleMode}}, keyboard::theme::ThemeSettings, lighting::{dmx::DmxSettings, led_strip::LedStripSettings}};
+
+use super::{waterfall::{self, WaterfallSettings}, Song};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SongPreset {
+    /// The path of the color theme. See `keyboard::theme`.
+    pub theme: Option<String>,
+    /// Seconds of upcoming notes shown in the waterfall, which sets how fast the notes fall.
+    pub look_ahead: Option<f64>,
+    pub replacement: Option<ReplacementMode>,
+    /// The image shown in place of the replaced backdrop.
+    pub backdrop: Option<String>,
+    pub style: Option<StyleMode>,
+    pub auto_frame: Option<bool>,
+    pub lighting: Option<LightingScene>
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LightingScene {
+    pub dmx: Option<bool>,
+    /// The brightness the DMX fixtures rest at when nothing is playing, from 0 to 1.
+    pub base_level: Option<f32>,
+    /// How quickly the fixtures fade after notes, as the fraction of energy remaining after one second.
+    pub energy_decay: Option<f32>,
+    pub led_strip: Option<bool>
+}
+
+impl SongPreset {
+    /// The preset file for a song: `song.mid` uses `song.preset.json`.
+    pub fn path_for(song: &Path) -> PathBuf {
+        song.with_extension("preset.json")
+    }
+
+    pub fn load(path: &Path) -> Result<SongPreset, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// The values of the settings the current song's preset overrode, from before it was loaded.
+#[derive(Resource, Default)]
+pub struct OverriddenSettings {
+    theme: Option<ThemeSettings>,
+    waterfall: Option<WaterfallSettings>,
+    replacement: Option<BackgroundReplacementSettings>,
+    style: Option<BackgroundStyleSettings>,
+    framing: Option<AutoFramingSettings>,
+    dmx: Option<DmxSettings>,
+    led_strip: Option<LedStripSettings>
+}
+
+/// Changes a setting, remembering its value from before the preset if it hasn't been overridden yet.
+fn override_setting<T: Resource + Clone>(saved: &mut Option<T>, setting: &mut ResMut<T>, edit: impl FnOnce(&mut T)) {
+    if saved.is_none() {
+        *saved = Some((**setting).clone());
+    }
+    edit(setting);
+}
+
+fn restore_setting<T: Resource>(saved: &mut Option<T>, setting: &mut ResMut<T>) {
+    if let Some(value) = saved.take() {
+        **setting = value;
+    }
+}
+
+/// Reverts the last song's preset and applies the new song's when the song changes.
+#[allow(clippy::too_many_arguments)]
+pub fn apply_song_preset(
+    song: Res<Song>,
+    mut overridden: ResMut<OverriddenSettings>,
+    mut theme: ResMut<ThemeSettings>,
+    mut waterfall: ResMut<WaterfallSettings>,
+    mut replacement: ResMut<BackgroundReplacementSettings>,
+    mut style: ResMut<BackgroundStyleSettings>,
+    mut framing: ResMut<AutoFramingSettings>,
+    lighting: (ResMut<DmxSettings>, ResMut<LedStripSettings>)
+) {
+    if !song.is_changed() {
+        return;
+    }
+    let (mut dmx, mut led_strip) = lighting;
+    let overridden = overridden.as_mut();
+
+    restore_setting(&mut overridden.theme, &mut theme);
+    restore_setting(&mut overridden.waterfall, &mut waterfall);
+    restore_setting(&mut overridden.replacement, &mut replacement);
+    restore_setting(&mut overridden.style, &mut style);
+    restore_setting(&mut overridden.framing, &mut framing);
+    restore_setting(&mut overridden.dmx, &mut dmx);
+    restore_setting(&mut overridden.led_strip, &mut led_strip);
+
+    let Some(preset) = &song.preset else { return };
+    println!("Applying the preset for {}", song.title);
+
+    if let Some(path) = &preset.theme {
+        override_setting(&mut overridden.theme, &mut theme, |theme| theme.path = Some(path.clone()));
+    }
+    if let Some(look_ahead) = preset.look_ahead {
+        override_setting(&mut overridden.waterfall, &mut waterfall, |waterfall| waterfall.look_ahead = look_ahead.clamp(*waterfall::LOOK_AHEAD.start(), *waterfall::LOOK_AHEAD.end()));
+    }
+    if let Some(mode) = preset.replacement {
+        override_setting(&mut overridden.replacement, &mut replacement, |replacement| replacement.mode = mode);
+    }
+    if let Some(backdrop) = &preset.backdrop {
+        override_setting(&mut overridden.replacement, &mut replacement, |replacement| replacement.backdrop_path = backdrop.clone());
+    }
+    if let Some(mode) = preset.style {
+        override_setting(&mut overridden.style, &mut style, |style| style.mode = mode);
+    }
+    if let Some(enabled) = preset.auto_frame {
+        override_setting(&mut overridden.framing, &mut framing, |framing| framing.enabled = enabled);
+    }
+    if let Some(scene) = &preset.lighting {
+        if scene.dmx.is_some() || scene.base_level.is_some() || scene.energy_decay.is_some() {
+            override_setting(&mut overridden.dmx, &mut dmx, |dmx| {
+                dmx.enabled = scene.dmx.unwrap_or(dmx.enabled);
+                dmx.base_level = scene.base_level.map_or(dmx.base_level, |level| level.clamp(0.0, 1.0));
+                dmx.energy_decay = scene.energy_decay.map_or(dmx.energy_decay, |decay| decay.clamp(0.0, 1.0));
+            });
+        }
+        if let Some(enabled) = scene.led_strip {
+            override_setting(&mut overridden.led_strip, &mut led_strip, |led_strip| led_strip.enabled = enabled);
+        }
+    }
+}
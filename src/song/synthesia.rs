@@ -0,0 +1,137 @@
+//! Import of Synthesia-style `.synthesia` metadata files, so existing annotated song libraries
+//! keep their hand assignments, finger hints, bookmarks, and loops.
+//!
+//! A metadata file looks like:
+//! ```xml
+//! <SynthesiaMetadata Version="1">
+//!   <Songs>
+//!     <Song Title="..." HandParts="t1:R; t2:L" FingerHints="t1: 1 2 3 1 2; t2: 5 4 3" Bookmarks="1,Intro; 17,Chorus" Loop="17-24" />
+//!   </Songs>
+//! </SynthesiaMetadata>
+//! ```
+//! Tracks are referenced as `t<index>`, measures are 1-based.
+
+use std::{collections::HashMap, error::Error, fs, path::Path};
+
+use super::{Bookmark, Hand, LoopRegion, Song};
+
+#[derive(Debug, Default)]
+pub struct SynthesiaMetadata {
+    pub title: Option<String>,
+    /// The hand assigned to each track. Tracks not listed keep no hand.
+    pub track_hands: HashMap<usize, Hand>,
+    /// Finger hints per track, applied to that track's notes in order. `None` leaves a note unassigned.
+    pub finger_hints: HashMap<usize, Vec<Option<u8>>>,
+    /// Bookmarks as (1-based measure, label).
+    pub bookmarks: Vec<(u32, String)>,
+    /// The loop as an inclusive range of 1-based measures.
+    pub loop_measures: Option<(u32, u32)>
+}
+
+impl SynthesiaMetadata {
+    pub fn load(path: &Path) -> Result<SynthesiaMetadata, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text, path.file_stem().and_then(|stem| stem.to_str()))
+    }
+
+    /// Parses a metadata file. If it describes several songs, the one whose title matches `title` is preferred.
+    pub fn parse(text: &str, title: Option<&str>) -> Result<SynthesiaMetadata, Box<dyn Error>> {
+        let document = roxmltree::Document::parse(text)?;
+        let songs: Vec<_> = document.descendants().filter(|node| node.has_tag_name("Song")).collect();
+        let song = songs.iter()
+            .find(|node| title.is_some() && node.attribute("Title") == title)
+            .or(songs.first())
+            .ok_or("Metadata file contains no songs")?;
+
+        let mut metadata = SynthesiaMetadata {
+            title: song.attribute("Title").map(str::to_string),
+            ..Default::default()
+        };
+
+        for (track, value) in song.attribute("HandParts").or(song.attribute("Parts")).map(parse_track_list).unwrap_or_default() {
+            let hand = match value.trim() {
+                "L" | "l" => Hand::Left,
+                "R" | "r" => Hand::Right,
+                // "B" (both) and "-" (background) aren't assigned to either hand
+                _ => continue
+            };
+            metadata.track_hands.insert(track, hand);
+        }
+
+        for (track, value) in song.attribute("FingerHints").map(parse_track_list).unwrap_or_default() {
+            let hints = value.chars()
+                .filter(|c| !c.is_whitespace())
+                .map(|c| c.to_digit(10).filter(|finger| (1..=5).contains(finger)).map(|finger| finger as u8))
+                .collect();
+            metadata.finger_hints.insert(track, hints);
+        }
+
+        if let Some(bookmarks) = song.attribute("Bookmarks") {
+            for entry in bookmarks.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+                let (measure, label) = entry.split_once(',').unwrap_or((entry, ""));
+                match measure.trim().parse() {
+                    Ok(measure) => metadata.bookmarks.push((measure, label.trim().to_string())),
+                    Err(_) => eprintln!("Ignoring invalid bookmark '{}'", entry)
+                }
+            }
+        }
+
+        if let Some(loop_range) = song.attribute("Loop") {
+            let parsed = loop_range.split_once('-')
+                .and_then(|(start, end)| Some((start.trim().parse().ok()?, end.trim().parse().ok()?)));
+            match parsed {
+                Some((start, end)) if start <= end => metadata.loop_measures = Some((start, end)),
+                _ => eprintln!("Ignoring invalid loop '{}'", loop_range)
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn apply(&self, song: &mut Song) {
+        if let Some(title) = &self.title {
+            song.title = title.clone();
+        }
+
+        for note in song.notes.iter_mut() {
+            if let Some(hand) = self.track_hands.get(&note.track) {
+                note.hand = Some(*hand);
+            }
+        }
+
+        // Finger hints are listed in note order within each track
+        let mut hint_indices: HashMap<usize, usize> = HashMap::new();
+        for note in song.notes.iter_mut() {
+            let Some(hints) = self.finger_hints.get(&note.track) else { continue };
+            let index = hint_indices.entry(note.track).or_default();
+            note.finger = hints.get(*index).copied().flatten();
+            *index += 1;
+        }
+
+        // Without bookmarks of its own, the song keeps the markers from its MIDI file
+        if !self.bookmarks.is_empty() {
+            song.bookmarks = self.bookmarks.iter()
+                .map(|(measure, label)| Bookmark {
+                    time: song.tempo_map.measure_to_seconds(*measure),
+                    label: label.clone()
+                })
+                .collect();
+        }
+
+        song.loop_region = self.loop_measures.map(|(start, end)| LoopRegion {
+            start: song.tempo_map.measure_to_seconds(start),
+            end: song.tempo_map.measure_to_seconds(end + 1)
+        });
+    }
+}
+
+/// Parses `t<track>:<value>` entries separated by semicolons.
+fn parse_track_list(list: &str) -> Vec<(usize, &str)> {
+    list.split(';')
+        .filter_map(|entry| {
+            let (track, value) = entry.split_once(':')?;
+            let track = track.trim().trim_start_matches('t').parse().ok()?;
+            Some((track, value))
+        })
+        .collect()
+}
@@ -0,0 +1,309 @@
+//! The note waterfall: upcoming song notes fall toward their keys, reaching the key tops as they're due.
+
+use std::collections::{HashMap, HashSet};
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle}, color::{Color, Mix}, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, math::{primitives::Cuboid, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::mesh::{Mesh, Mesh3d}, transform::components::Transform};
+
+use crate::{keyboard::{self, clutter::{self, ClutterSettings, NoteBar}, hands::HandSplitSettings, theme::{self, Theme, ThemeMaterials}, zones::RegisterZoneSettings, KeyboardLayout, KeyboardRoot}, midi::latency::LatencyCompensation, video::tracking::FadeWithTracking};
+
+use super::{playback::{self, SongPlayback}, Song};
+
+/// The depth of the note bars in mm.
+const BAR_DEPTH: f32 = 10.0;
+/// The thickness of the measure and beat lines in mm.
+const MEASURE_LINE_THICKNESS: f32 = 2.0;
+const BEAT_LINE_THICKNESS: f32 = 0.8;
+/// The bar colors for notes the theme doesn't color.
+const WHITE_KEY_COLOR: Color = Color::srgb(0.3, 0.9, 0.5);
+const BLACK_KEY_COLOR: Color = Color::srgb(0.15, 0.55, 0.3);
+/// The allowed look-ahead in seconds; shorter gives the eye too little warning, longer crowds the bars together.
+pub const LOOK_AHEAD: std::ops::RangeInclusive<f64> = 0.5..=10.0;
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct WaterfallSettings {
+    /// How many seconds before it's due a note appears at the top of the waterfall. This is real time,
+    /// so slowing playback down shows less of the song rather than making notes fall slower.
+    pub look_ahead: f64,
+    /// The height of the waterfall above the keys in mm.
+    pub height: f32,
+    /// Draw lines across the waterfall at each measure and beat.
+    pub beat_lines: bool
+}
+
+impl Default for WaterfallSettings {
+    fn default() -> Self {
+        Self {
+            look_ahead: 3.0,
+            height: 400.0,
+            beat_lines: true
+        }
+    }
+}
+
+impl WaterfallSettings {
+    /// The seconds of song time visible above the keys at the given playback speed.
+    pub fn visible_span(&self, speed: f64) -> f64 {
+        self.look_ahead.clamp(*LOOK_AHEAD.start(), *LOOK_AHEAD.end()) * speed
+    }
+}
+
+#[derive(Component)]
+struct WaterfallBar;
+
+#[derive(Component)]
+struct BeatLine;
+
+#[derive(Resource)]
+struct WaterfallAssets {
+    /// A unit cube, scaled to each note's size.
+    mesh: Handle<Mesh>,
+    white_key: Handle<StandardMaterial>,
+    black_key: Handle<StandardMaterial>,
+    measure_line: Handle<StandardMaterial>,
+    beat_line: Handle<StandardMaterial>
+}
+
+/// The bar spawned for each visible note, by index into `Song::notes` and whether it's the loop's next repetition.
+#[derive(Resource, Default)]
+struct WaterfallBars(HashMap<(usize, bool), Entity>);
+
+/// The bar drawn for each of the song's notes, from `clutter::merge_dense_notes`.
+#[derive(Resource, Default)]
+struct MergedNotes {
+    bars: Vec<Option<NoteBar>>,
+    /// The start of the loop the bars were merged for.
+    loop_start: Option<f64>
+}
+
+/// The line spawned for each visible beat, by tick.
+#[derive(Resource, Default)]
+struct BeatLines(HashMap<u64, Entity>);
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+) {
+    let bar_material = |color: Color| StandardMaterial {
+        base_color: color,
+        unlit: true,
+        ..Default::default()
+    };
+
+    commands.insert_resource(WaterfallAssets {
+        mesh: meshes.add(Cuboid::new(1.0, 1.0, 1.0)),
+        white_key: materials.add(bar_material(WHITE_KEY_COLOR)),
+        black_key: materials.add(bar_material(BLACK_KEY_COLOR)),
+        measure_line: materials.add(bar_material(Color::srgb(0.7, 0.7, 0.75))),
+        beat_line: materials.add(bar_material(Color::srgb(0.3, 0.3, 0.35)))
+    });
+}
+
+/// Merges the notes of dense passages again whenever the song, the limit, or the loop changes.
+fn merge_notes(
+    song: Res<Song>,
+    clutter: Res<ClutterSettings>,
+    mut merged: ResMut<MergedNotes>
+) {
+    // Setting the loop doesn't mark the song as changed, so playback carries on
+    let loop_start = song.active_loop().map(|region| region.start);
+    if song.is_changed() || clutter.is_changed() || merged.loop_start != loop_start {
+        *merged = MergedNotes { bars: clutter::merge_dense_notes(&song.notes, &clutter, loop_start), loop_start };
+    }
+}
+
+/// Places a bar for every note within the look-ahead window. Positions are in song time, so bars stay aligned
+/// with their keys at any playback speed; the scale stretches so the window always fills the waterfall's height.
+/// While looping, only the loop's notes are shown, followed by its next repetition after the count-in, so the notes
+/// coming back around are already falling when playback jumps back.
+#[allow(clippy::too_many_arguments)]
+fn update_waterfall(
+    mut commands: Commands,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    settings: Res<WaterfallSettings>,
+    latency: Res<LatencyCompensation>,
+    keyboard_layout: Res<KeyboardLayout>,
+    assets: Res<WaterfallAssets>,
+    theme: Res<Theme>,
+    hands: Res<HandSplitSettings>,
+    zones: Res<RegisterZoneSettings>,
+    merged: Res<MergedNotes>,
+    mut theme_materials: ResMut<ThemeMaterials>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    mut bars: ResMut<WaterfallBars>,
+    mut transforms: Query<&mut Transform, With<WaterfallBar>>
+) {
+    if song.is_changed() || keyboard_layout.is_changed() || theme.is_changed() || hands.is_changed() || zones.is_changed() || merged.is_changed() {
+        for (_, entity) in bars.0.drain() {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    let span = settings.visible_span(playback.speed);
+    let mm_per_second = settings.height as f64 / span;
+    // Playback time is song time, so the real-time video delay is scaled by the speed
+    let position = playback.position - latency.video * playback.speed;
+
+    // Notes are sorted by start time, so nothing after the top of the window is visible
+    let last = song.notes.partition_point(|note| note.start < position + span);
+    let region = song.active_loop();
+    // (index, whether it's the next repetition, how much later than the note that is)
+    let mut candidates: Vec<(usize, bool, f64)> = (0..last).map(|index| (index, false, 0.0)).collect();
+    if let Some(region) = region {
+        let period = region.end - region.start + playback.count_in_length(&song, region);
+        let first = song.notes.partition_point(|note| note.start < region.start);
+        let end = song.notes.partition_point(|note| note.start < region.end);
+        candidates.extend((first..end).map(|index| (index, true, period)));
+    }
+
+    let mut visible = HashSet::new();
+    for (index, repeated, offset) in candidates {
+        let note = &song.notes[index];
+        // Notes merged into an earlier bar aren't drawn on their own
+        let Some(NoteBar { end, brightness }) = merged.bars.get(index).copied().unwrap_or(Some(NoteBar { end: note.end(), brightness: 1.0 })) else { continue };
+        if region.is_some_and(|region| !region.contains(note.start)) || !keyboard_layout.keys().contains(&note.key) {
+            continue;
+        }
+        // Notes held past the end of the loop are released there
+        let end = region.map_or(end, |region| end.min(region.end)) + offset;
+        if end <= position {
+            continue;
+        }
+
+        // Notes that are sounding are cut off at the key tops
+        let bottom = ((note.start + offset - position) * mm_per_second).max(0.0) as f32;
+        let top = (((end - position) * mm_per_second) as f32).min(settings.height);
+        if top <= bottom {
+            continue;
+        }
+        let key = (index, repeated);
+        visible.insert(key);
+
+        let (width, _) = keyboard_layout.key_size(note.key);
+        let black = keyboard::is_black_key(note.key);
+        let hand = hands.enabled.then(|| hands.hand(note.key, note.channel, note.hand));
+        // The hand that isn't being practiced gets thin bars, so the other hand's stand out
+        let width = if hand.is_some_and(|hand| hands.is_unfocused(hand)) { width * 0.35 } else { width * 0.8 };
+        // Black keys stand above the white keys, like the key highlights
+        let base = if black { 12.0 } else { 2.0 };
+        let transform = Transform::from_translation(keyboard_layout.key_center(note.key).with_y(base + (bottom + top) / 2.0))
+            .with_scale(Vec3::new(width, top - bottom, BAR_DEPTH));
+
+        if let Some(mut existing) = bars.0.get(&key).and_then(|&entity| transforms.get_mut(entity).ok()) {
+            *existing = transform;
+            continue;
+        }
+        let color = hand.map(|hand| hands.color(hand)).or_else(|| theme.note_color(note.key, note.channel, note.hand)).or_else(|| zones.accent(note.key));
+        // Dense passages are dimmed in quarter steps, so they share a few materials
+        let dimming = 1.0 - (brightness * 4.0).ceil() / 4.0;
+        let color = match color {
+            _ if dimming <= 0.0 => color,
+            Some(color) => Some(color.mix(&Color::BLACK, dimming)),
+            None => Some((if black { BLACK_KEY_COLOR } else { WHITE_KEY_COLOR }).mix(&Color::BLACK, dimming))
+        };
+        let material = match color {
+            Some(color) => theme_materials.get(&mut materials, color),
+            None if black => assets.black_key.clone(),
+            None => assets.white_key.clone()
+        };
+        let entity = commands.spawn((
+            Mesh3d(assets.mesh.clone()),
+            MeshMaterial3d(material),
+            transform,
+            WaterfallBar,
+            FadeWithTracking,
+            ChildOf(*root)
+        )).id();
+        bars.0.insert(key, entity);
+    }
+
+    bars.0.retain(|key, entity| {
+        if visible.contains(key) {
+            return true;
+        }
+        commands.entity(*entity).despawn();
+        false
+    });
+}
+
+/// Places a line across the waterfall at each measure and beat, moving with the notes.
+/// The lines sit at the back of the keys, behind the note bars.
+#[allow(clippy::too_many_arguments)]
+fn update_beat_lines(
+    mut commands: Commands,
+    song: Res<Song>,
+    playback: Res<SongPlayback>,
+    settings: Res<WaterfallSettings>,
+    latency: Res<LatencyCompensation>,
+    keyboard_layout: Res<KeyboardLayout>,
+    assets: Res<WaterfallAssets>,
+    root: Single<Entity, With<KeyboardRoot>>,
+    mut lines: ResMut<BeatLines>,
+    mut transforms: Query<&mut Transform, With<BeatLine>>
+) {
+    if song.is_changed() || keyboard_layout.is_changed() || !settings.beat_lines {
+        for (_, entity) in lines.0.drain() {
+            commands.entity(entity).despawn();
+        }
+    }
+    // Without notes there's no song, just the default tempo map
+    if !settings.beat_lines || song.notes.is_empty() {
+        return;
+    }
+
+    let span = settings.visible_span(playback.speed);
+    let mm_per_second = settings.height as f64 / span;
+    let position = playback.position - latency.video * playback.speed;
+
+    // The lines stop at the end of the loop, like its notes
+    let end = song.active_loop().map_or(song.duration, |region| region.end.min(song.duration));
+    let beats = song.tempo_map.beats_between(position, (position + span).min(end));
+    for &(tick, time, measure) in &beats {
+        let thickness = if measure { MEASURE_LINE_THICKNESS } else { BEAT_LINE_THICKNESS };
+        let height = ((time - position) * mm_per_second) as f32;
+        let transform = Transform::from_xyz(0.0, 2.0 + height, keyboard_layout.geometry.key_back_z)
+            .with_scale(Vec3::new(keyboard_layout.width(), thickness, 1.0));
+
+        if let Some(mut existing) = lines.0.get(&tick).and_then(|&entity| transforms.get_mut(entity).ok()) {
+            *existing = transform;
+            continue;
+        }
+        let material = if measure { assets.measure_line.clone() } else { assets.beat_line.clone() };
+        let entity = commands.spawn((
+            Mesh3d(assets.mesh.clone()),
+            MeshMaterial3d(material),
+            transform,
+            BeatLine,
+            FadeWithTracking,
+            ChildOf(*root)
+        )).id();
+        lines.0.insert(tick, entity);
+    }
+
+    lines.0.retain(|tick, entity| {
+        if beats.iter().any(|(visible, _, _)| visible == tick) {
+            return true;
+        }
+        commands.entity(*entity).despawn();
+        false
+    });
+}
+
+pub struct WaterfallPlugin;
+
+impl Plugin for WaterfallPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<WaterfallSettings>()
+            .init_resource::<WaterfallBars>()
+            .init_resource::<MergedNotes>()
+            .init_resource::<BeatLines>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (
+                (merge_notes, update_waterfall, keyboard::hide_without_manuals::<WaterfallBar>).chain().after(theme::update_theme),
+                (update_beat_lines, keyboard::hide_without_manuals::<BeatLine>).chain()
+            ).after(playback::advance_playback));
+    }
+}
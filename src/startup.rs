@@ -0,0 +1,239 @@
+//! Startup progress. The slow parts of starting up (loading the calibration, the song, and the soundfont, opening the
+//! camera, and building the marker detector) run in the background instead of in plugin `build()` calls, so the window
+//! opens right away, even when a network stream is slow to open. Until they're done, a loading screen shows what's
+//! still going. Once they are, how long each took is printed, and each also shows up as a span in the profilers (see
+//! `diagnostics::profiling`).
+
+use std::time::{Duration, Instant};
+
+use bevy::{app::{App, Plugin, PostUpdate, Startup}, color::Color, ecs::{component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, system::{Commands, Query, ResMut, Single}}, text::{TextColor, TextFont}, ui::{widget::Text, AlignItems, BackgroundColor, FlexDirection, JustifyContent, Node, PositionType, Val}, utils::default};
+
+const TITLE_FONT_SIZE: f32 = 28.0;
+const TASK_FONT_SIZE: f32 = 16.0;
+const BAR_WIDTH: f32 = 320.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupTask {
+    Calibration,
+    Detector,
+    Camera,
+    Song,
+    Soundfont
+}
+
+impl StartupTask {
+    pub fn label(&self) -> &'static str {
+        match self {
+            StartupTask::Calibration => "Loading the calibration",
+            StartupTask::Detector => "Building the marker detector",
+            StartupTask::Camera => "Opening the camera",
+            StartupTask::Song => "Loading the song",
+            StartupTask::Soundfont => "Loading the soundfont"
+        }
+    }
+}
+
+struct TaskTiming {
+    task: StartupTask,
+    started: Instant,
+    took: Option<Duration>
+}
+
+#[derive(Resource)]
+pub struct StartupProgress {
+    started: Instant,
+    tasks: Vec<TaskTiming>,
+    /// How long startup took, once everything started during it has finished. Tasks begun after that aren't tracked.
+    took: Option<Duration>
+}
+
+impl Default for StartupProgress {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            tasks: Vec::new(),
+            took: None
+        }
+    }
+}
+
+impl StartupProgress {
+    /// Starts timing a task, unless it's already been started or startup is over.
+    pub fn begin(&mut self, task: StartupTask) {
+        if self.took.is_none() && !self.tasks.iter().any(|timing| timing.task == task) {
+            self.tasks.push(TaskTiming { task, started: Instant::now(), took: None });
+        }
+    }
+
+    /// Stops timing a task. Tasks that weren't started are ignored.
+    pub fn finish(&mut self, task: StartupTask) {
+        for timing in self.tasks.iter_mut().filter(|timing| timing.task == task && timing.took.is_none()) {
+            timing.took = Some(timing.started.elapsed());
+        }
+    }
+
+    pub fn is_running(&self, task: StartupTask) -> bool {
+        self.tasks.iter().any(|timing| timing.task == task && timing.took.is_none())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.tasks.iter().all(|timing| timing.took.is_some())
+    }
+
+    /// The share of the tasks started that have finished, from 0 to 1.
+    fn fraction(&self) -> f32 {
+        if self.tasks.is_empty() {
+            return 1.0;
+        }
+        self.tasks.iter().filter(|timing| timing.took.is_some()).count() as f32 / self.tasks.len() as f32
+    }
+
+    /// Like "Loading the soundfont (1.2s)", one line for each task still running.
+    fn running(&self) -> Vec<String> {
+        self.tasks.iter()
+            .filter(|timing| timing.took.is_none())
+            .map(|timing| format!("{} ({:.1}s)", timing.task.label(), timing.started.elapsed().as_secs_f32()))
+            .collect()
+    }
+
+    /// Like "Started in 2.31s: Loading the song 0.40s, Opening the camera 2.12s".
+    fn summary(&self) -> String {
+        let tasks: Vec<String> = self.tasks.iter()
+            .filter_map(|timing| timing.took.map(|took| format!("{} {:.2}s", timing.task.label(), took.as_secs_f32())))
+            .collect();
+        format!("Started in {:.2}s: {}", self.took.unwrap_or_default().as_secs_f32(), tasks.join(", "))
+    }
+}
+
+#[derive(Component)]
+struct LoadingScreen;
+
+#[derive(Component)]
+struct LoadingBar;
+
+#[derive(Component)]
+struct LoadingTasks;
+
+fn setup(mut commands: Commands) {
+    let screen = commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            width: Val::Percent(100.0),
+            height: Val::Percent(100.0),
+            flex_direction: FlexDirection::Column,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            row_gap: Val::Px(12.0),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.85)),
+        LoadingScreen
+    )).id();
+
+    commands.spawn((
+        Text::new("Starting up"),
+        TextFont {
+            font_size: TITLE_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        ChildOf(screen)
+    ));
+    let track = commands.spawn((
+        Node {
+            width: Val::Px(BAR_WIDTH),
+            height: Val::Px(8.0),
+            ..default()
+        },
+        BackgroundColor(Color::srgb(0.25, 0.25, 0.25)),
+        ChildOf(screen)
+    )).id();
+    commands.spawn((
+        Node {
+            width: Val::Percent(0.0),
+            height: Val::Percent(100.0),
+            ..default()
+        },
+        BackgroundColor(Color::srgb(0.3, 0.9, 0.5)),
+        LoadingBar,
+        ChildOf(track)
+    ));
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: TASK_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::srgb(0.8, 0.8, 0.8)),
+        LoadingTasks,
+        ChildOf(screen)
+    ));
+}
+
+/// Shows what's still loading, and removes the loading screen once everything is done. Runs after `Update`, so tasks
+/// begun by systems on the first frame are counted before startup can be considered over.
+fn update_loading_screen(
+    mut commands: Commands,
+    mut progress: ResMut<StartupProgress>,
+    screen: Option<Single<Entity, With<LoadingScreen>>>,
+    mut bars: Query<&mut Node, With<LoadingBar>>,
+    mut texts: Query<&mut Text, With<LoadingTasks>>
+) {
+    let Some(screen) = screen else { return };
+
+    if progress.is_done() {
+        progress.took = Some(progress.started.elapsed());
+        println!("{}", progress.summary());
+        commands.entity(*screen).despawn();
+        return;
+    }
+
+    for mut bar in bars.iter_mut() {
+        bar.width = Val::Percent(progress.fraction() * 100.0);
+    }
+    let running = progress.running().join("\n");
+    for mut text in texts.iter_mut() {
+        text.0 = running.clone();
+    }
+}
+
+pub struct StartupPlugin;
+
+impl Plugin for StartupPlugin {
+    fn build(&self, app: &mut App) {
+        // Other plugins may have begun tasks already, so the progress isn't replaced if it exists
+        app
+            .init_resource::<StartupProgress>()
+            .add_systems(Startup, setup)
+            .add_systems(PostUpdate, update_loading_screen);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startup_is_done_once_every_started_task_finishes() {
+        let mut progress = StartupProgress::default();
+        assert!(progress.is_done());
+
+        progress.begin(StartupTask::Song);
+        progress.begin(StartupTask::Camera);
+        progress.finish(StartupTask::Soundfont);
+        assert!(progress.is_running(StartupTask::Song));
+        assert!(!progress.is_running(StartupTask::Soundfont));
+        assert!(!progress.is_done());
+
+        progress.finish(StartupTask::Song);
+        assert_eq!(progress.fraction(), 0.5);
+        assert_eq!(progress.running().len(), 1);
+
+        progress.finish(StartupTask::Camera);
+        assert!(progress.is_done());
+        progress.took = Some(Duration::from_secs(1));
+        // Tasks begun after startup don't bring the loading screen back
+        progress.begin(StartupTask::Soundfont);
+        assert!(progress.is_done());
+    }
+}
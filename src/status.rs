@@ -0,0 +1,172 @@
+//! Recoverable errors and an on-screen status overlay. Systems send an `AppError` instead of panicking,
+//! so a camera glitch or missing file shows a message while the rest of the app keeps running.
+
+use std::collections::BTreeMap;
+
+use bevy::{app::{App, Plugin, Startup, Update}, color::Color, ecs::{change_detection::DetectChanges, component::Component, event::{Event, EventReader}, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut}}, render::view::Visibility, text::{TextColor, TextFont}, time::Time, ui::{widget::Text, BackgroundColor, Node, PositionType, UiRect, Val}, utils::default};
+
+use crate::diagnostics::RecentErrors;
+
+/// How long a message stays on screen after the error was last seen, in seconds.
+const STATUS_DURATION: f64 = 5.0;
+const STATUS_FONT_SIZE: f32 = 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorSource {
+    Capture,
+    Calibration,
+    Detection,
+    Midi,
+    Audio,
+    Replay,
+    Recording,
+    VirtualCamera,
+    Remote,
+    Decorations,
+    Song
+}
+
+impl ErrorSource {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ErrorSource::Capture => "Camera",
+            ErrorSource::Calibration => "Calibration",
+            ErrorSource::Detection => "Tracking",
+            ErrorSource::Midi => "MIDI",
+            ErrorSource::Audio => "Audio",
+            ErrorSource::Replay => "Replay",
+            ErrorSource::Recording => "Recording",
+            ErrorSource::VirtualCamera => "Virtual camera",
+            ErrorSource::Remote => "Remote control",
+            ErrorSource::Decorations => "Decorations",
+            ErrorSource::Song => "Song"
+        }
+    }
+}
+
+/// A failure the app recovered from. Errors that repeat every frame are only logged once while they persist.
+#[derive(Event, Debug, Clone)]
+pub struct AppError {
+    pub source: ErrorSource,
+    pub message: String
+}
+
+impl AppError {
+    pub fn new(source: ErrorSource, message: impl Into<String>) -> Self {
+        Self {
+            source,
+            message: message.into()
+        }
+    }
+}
+
+/// Sends an error while plugins are being built, before any systems run. It's shown once the app starts.
+pub fn report_startup_error(app: &mut App, error: AppError) {
+    app.add_event::<AppError>();
+    app.world_mut().send_event(error);
+}
+
+struct ActiveError {
+    message: String,
+    last_seen: f64,
+    /// How many times the error was sent since it was first shown.
+    count: u32
+}
+
+/// The latest error from each source that's still being shown.
+#[derive(Resource, Default)]
+pub struct AppStatus {
+    active: BTreeMap<ErrorSource, ActiveError>
+}
+
+impl AppStatus {
+    pub fn is_ok(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// One line per source, like "Camera: Failed to read frame (x20)".
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.active.iter().map(|(source, error)| match error.count {
+            1 => format!("{}: {}", source.label(), error.message),
+            count => format!("{}: {} (x{})", source.label(), error.message, count)
+        })
+    }
+}
+
+fn collect_app_errors(
+    time: Res<Time>,
+    mut errors: EventReader<AppError>,
+    mut status: ResMut<AppStatus>,
+    mut recent_errors: ResMut<RecentErrors>
+) {
+    let now = time.elapsed_secs_f64();
+
+    for error in errors.read() {
+        match status.active.get_mut(&error.source) {
+            Some(active) if active.message == error.message => {
+                active.last_seen = now;
+                active.count += 1;
+            }
+            _ => {
+                recent_errors.report(format!("{}: {}", error.source.label(), error.message));
+                status.active.insert(error.source, ActiveError { message: error.message.clone(), last_seen: now, count: 1 });
+            }
+        }
+    }
+
+    // Only touch the map when something expires, so the overlay isn't rebuilt every frame
+    if status.active.values().any(|active| now - active.last_seen > STATUS_DURATION) {
+        status.active.retain(|_, active| now - active.last_seen <= STATUS_DURATION);
+    }
+}
+
+#[derive(Component)]
+struct StatusOverlay;
+
+fn setup(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            left: Val::Px(8.0),
+            padding: UiRect::all(Val::Px(6.0)),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.4, 0.0, 0.0, 0.75)),
+        Text::new(""),
+        TextFont {
+            font_size: STATUS_FONT_SIZE,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        Visibility::Hidden,
+        StatusOverlay
+    ));
+}
+
+fn update_status_overlay(
+    status: Res<AppStatus>,
+    mut overlays: Query<(&mut Text, &mut Visibility), With<StatusOverlay>>
+) {
+    if !status.is_changed() {
+        return;
+    }
+
+    let text = status.lines().collect::<Vec<_>>().join("\n");
+    for (mut overlay_text, mut visibility) in overlays.iter_mut() {
+        overlay_text.0 = text.clone();
+        *visibility = if status.is_ok() { Visibility::Hidden } else { Visibility::Inherited };
+    }
+}
+
+pub struct StatusPlugin;
+
+impl Plugin for StatusPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<AppError>()
+            .init_resource::<AppStatus>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (collect_app_errors, update_status_overlay).chain());
+    }
+}
@@ -0,0 +1,179 @@
+//! Large buttons along the screen edges for a touch monitor mounted at the piano, so songs can be started, slowed
+//! down, and looped without reaching for a keyboard or mouse. Transport is on the left edge and tempo and looping on
+//! the right, clear of the keyboard in the middle. Enable with `[touch] enabled = true` in the config.
+
+use bevy::{app::{App, Plugin, Update}, color::Color, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, event::EventWriter, hierarchy::{ChildOf, Children}, query::{Changed, With}, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res}}, text::{TextColor, TextFont}, ui::{widget::{Button, Text}, AlignItems, BackgroundColor, BorderRadius, FlexDirection, Interaction, JustifyContent, Node, PositionType, UiRect, Val}, utils::default};
+
+use crate::{command::{self, AppCommand, PendingLoopStart}, song::{playback::SongPlayback, Song}};
+
+const BUTTON_COLOR: Color = Color::srgba(0.0, 0.0, 0.0, 0.55);
+const PRESSED_COLOR: Color = Color::srgba(0.3, 0.5, 0.9, 0.8);
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct TouchControlSettings {
+    pub enabled: bool,
+    /// The width and height of each button, in logical pixels. Fingertips need at least 60 or so.
+    pub button_size: f32
+}
+
+impl Default for TouchControlSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            button_size: 96.0
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+enum TouchAction {
+    Restart,
+    PlayPause,
+    Slower,
+    Faster,
+    /// Marks the start of a loop, then its end, then clears it.
+    Loop
+}
+
+impl TouchAction {
+    fn label(&self, playback: &SongPlayback, song: &Song, loop_start: Option<f64>) -> String {
+        match self {
+            TouchAction::Restart => "Restart".to_string(),
+            TouchAction::PlayPause if playback.playing => "Pause".to_string(),
+            TouchAction::PlayPause => "Play".to_string(),
+            TouchAction::Slower => "Slower".to_string(),
+            TouchAction::Faster => format!("Faster\n{:.0}%", playback.speed * 100.0),
+            TouchAction::Loop if song.loop_region.is_some() => "Clear loop".to_string(),
+            TouchAction::Loop if loop_start.is_some() => "Loop end".to_string(),
+            TouchAction::Loop => "Loop start".to_string()
+        }
+    }
+}
+
+/// The root of the touch controls, holding one column per screen edge.
+#[derive(Component)]
+struct TouchControls;
+
+/// Shows or hides the controls when the settings change.
+fn spawn_touch_controls(
+    mut commands: Commands,
+    settings: Res<TouchControlSettings>,
+    existing: Query<Entity, With<TouchControls>>
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+    if !settings.enabled {
+        return;
+    }
+
+    let root = commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            width: Val::Percent(100.0),
+            height: Val::Percent(100.0),
+            justify_content: JustifyContent::SpaceBetween,
+            align_items: AlignItems::Center,
+            padding: UiRect::all(Val::Px(12.0)),
+            ..default()
+        },
+        TouchControls
+    )).id();
+
+    let size = settings.button_size.max(40.0);
+    for actions in [[TouchAction::Restart, TouchAction::PlayPause, TouchAction::Loop], [TouchAction::Faster, TouchAction::Slower, TouchAction::Loop]] {
+        let column = commands.spawn((
+            Node {
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(size / 4.0),
+                ..default()
+            },
+            ChildOf(root)
+        )).id();
+        // Loop is on both edges, so it's in reach of either hand
+        for action in actions {
+            let button = commands.spawn((
+                Button,
+                Node {
+                    width: Val::Px(size),
+                    height: Val::Px(size),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                BorderRadius::all(Val::Px(size / 6.0)),
+                BackgroundColor(BUTTON_COLOR),
+                action,
+                ChildOf(column)
+            )).id();
+            commands.spawn((
+                Text::new(""),
+                TextFont {
+                    font_size: size / 5.0,
+                    ..default()
+                },
+                TextColor(Color::WHITE),
+                ChildOf(button)
+            ));
+        }
+    }
+}
+
+/// Runs each button's action when it's touched.
+fn press_touch_controls(
+    mut buttons: Query<(&Interaction, &TouchAction, &mut BackgroundColor), Changed<Interaction>>,
+    mut commands: EventWriter<AppCommand>
+) {
+    for (interaction, action, mut color) in buttons.iter_mut() {
+        color.0 = if *interaction == Interaction::Pressed { PRESSED_COLOR } else { BUTTON_COLOR };
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        match action {
+            TouchAction::Restart => {
+                commands.write(AppCommand::Restart);
+            }
+            TouchAction::PlayPause => {
+                commands.write(AppCommand::TogglePlayback);
+            }
+            TouchAction::Slower => {
+                commands.write(AppCommand::Slower);
+            }
+            TouchAction::Faster => {
+                commands.write(AppCommand::Faster);
+            }
+            TouchAction::Loop => {
+                commands.write(AppCommand::CycleLoop);
+            }
+        }
+    }
+}
+
+fn update_touch_labels(
+    buttons: Query<(&TouchAction, &Children)>,
+    mut texts: Query<&mut Text>,
+    playback: Res<SongPlayback>,
+    song: Res<Song>,
+    loop_start: Res<PendingLoopStart>
+) {
+    for (action, children) in buttons.iter() {
+        let Some(mut text) = children.first().and_then(|&child| texts.get_mut(child).ok()) else { continue };
+        let label = action.label(&playback, &song, loop_start.0);
+        if text.0 != label {
+            text.0 = label;
+        }
+    }
+}
+
+pub struct TouchControlsPlugin;
+
+impl Plugin for TouchControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<TouchControlSettings>()
+            .add_systems(Update, (spawn_touch_controls, press_touch_controls, update_touch_labels).chain().before(command::run_app_commands));
+    }
+}
@@ -0,0 +1,269 @@
+//! An opt-in check for new releases. With `[updates] check = true`, the latest release is fetched from the project's
+//! release feed at startup, and if it's newer than this build a notice shows its changelog, with a button to download
+//! the release's file for this platform into `DOWNLOAD_DIR`. Nothing is installed; the download is left for the user.
+
+use std::{error::Error, fs::{self, File}, io, path::{Path, PathBuf}, thread};
+
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::DetectChanges, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+use crossbeam_channel::{Receiver, Sender};
+use serde::Deserialize;
+
+static DOWNLOAD_DIR: &str = "downloads";
+/// GitHub rejects API requests without a user agent.
+static USER_AGENT: &str = concat!("ARPianoVisualizer/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct UpdateCheckSettings {
+    pub enabled: bool,
+    /// The URL of the latest release, in the format of GitHub's releases API.
+    pub feed: String
+}
+
+impl Default for UpdateCheckSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            feed: "https://api.github.com/repos/Glitch752/ARPianoVisualizer/releases/latest".to_string()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    name: Option<String>,
+    /// The changelog, in Markdown.
+    #[serde(default)]
+    body: Option<String>,
+    html_url: String,
+    #[serde(default)]
+    assets: Vec<ReleaseAsset>
+}
+
+/// The (major, minor, patch) of a version like "v1.2.3" or "1.2", ignoring anything after a `-` or `+`.
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|part| part.parse::<u32>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+    let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+    Some((major, minor, patch))
+}
+
+fn is_newer(tag: &str, current: &str) -> bool {
+    match (parse_version(tag), parse_version(current)) {
+        (Some(tag), Some(current)) => tag > current,
+        _ => false
+    }
+}
+
+/// The release file for this platform, going by the names it's usually published under.
+fn platform_asset<'a>(assets: &'a [ReleaseAsset], os: &str) -> Option<&'a ReleaseAsset> {
+    let names: &[&str] = match os {
+        "windows" => &["windows", "win64"],
+        "macos" => &["macos", "darwin", "mac"],
+        os => &[os]
+    };
+    assets.iter().find(|asset| {
+        let name = asset.name.to_lowercase();
+        names.iter().any(|platform| name.contains(platform))
+    })
+}
+
+enum UpdateMessage {
+    /// A newer release than this build.
+    Available(Release),
+    Downloaded(PathBuf),
+    Failed(String)
+}
+
+#[derive(Resource, Default)]
+struct UpdateChecker {
+    available: Option<Release>,
+    dismissed: bool,
+    downloading: bool,
+    /// Where the release was downloaded to, or why it couldn't be.
+    download_result: Option<Result<PathBuf, String>>,
+    messages: Option<Receiver<UpdateMessage>>
+}
+
+fn fetch_latest_release(feed: &str) -> Result<Release, Box<dyn Error>> {
+    let response = ureq::get(feed).set("User-Agent", USER_AGENT).set("Accept", "application/vnd.github+json").call()?;
+    Ok(serde_json::from_reader(response.into_reader())?)
+}
+
+fn download_asset(asset: &ReleaseAsset) -> Result<PathBuf, Box<dyn Error>> {
+    fs::create_dir_all(DOWNLOAD_DIR)?;
+    // Only the file name is used, so a strange asset name can't write outside the download directory
+    let file_name = Path::new(&asset.name).file_name().ok_or("The release file has no name")?;
+    let path = Path::new(DOWNLOAD_DIR).join(file_name);
+    let response = ureq::get(&asset.browser_download_url).set("User-Agent", USER_AGENT).call()?;
+    io::copy(&mut response.into_reader(), &mut File::create(&path)?)?;
+    Ok(path)
+}
+
+fn spawn_task(name: &str, task: impl FnOnce(Sender<UpdateMessage>) + Send + 'static) -> Option<Receiver<UpdateMessage>> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    match thread::Builder::new().name(name.to_string()).spawn(move || task(sender)) {
+        Ok(_) => Some(receiver),
+        Err(err) => {
+            eprintln!("Failed to start the {} thread: {}", name, err);
+            None
+        }
+    }
+}
+
+/// Checks for a new release on a background thread when checking is turned on, which is usually at startup.
+fn check_for_updates(
+    settings: Res<UpdateCheckSettings>,
+    mut checker: ResMut<UpdateChecker>
+) {
+    if !settings.is_changed() || !settings.enabled {
+        return;
+    }
+    let feed = settings.feed.clone();
+    checker.messages = spawn_task("update check", move |sender| {
+        let message = match fetch_latest_release(&feed) {
+            Ok(release) if is_newer(&release.tag_name, env!("CARGO_PKG_VERSION")) => UpdateMessage::Available(release),
+            Ok(_) => return,
+            Err(err) => UpdateMessage::Failed(format!("Failed to check for updates: {}", err))
+        };
+        let _ = sender.send(message);
+    });
+}
+
+fn receive_update_messages(mut checker: ResMut<UpdateChecker>) {
+    let Some(messages) = &checker.messages else { return };
+    let received: Vec<UpdateMessage> = messages.try_iter().collect();
+    for message in received {
+        match message {
+            UpdateMessage::Available(release) => {
+                println!("Version {} is available: {}", release.tag_name, release.html_url);
+                checker.available = Some(release);
+            }
+            UpdateMessage::Downloaded(path) => {
+                println!("Downloaded the update to {}", path.display());
+                checker.downloading = false;
+                checker.download_result = Some(Ok(path));
+            }
+            // A failed check isn't worth interrupting anyone for
+            UpdateMessage::Failed(message) if !checker.downloading => eprintln!("{}", message),
+            UpdateMessage::Failed(message) => {
+                eprintln!("{}", message);
+                checker.downloading = false;
+                checker.download_result = Some(Err(message));
+            }
+        }
+    }
+}
+
+fn draw_update_notice(
+    mut contexts: EguiContexts,
+    mut checker: ResMut<UpdateChecker>
+) {
+    if checker.dismissed {
+        return;
+    }
+    let Some(release) = checker.available.clone() else { return };
+    let checker = checker.as_mut();
+
+    egui::Window::new("Update available")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-16.0, -16.0])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.strong(format!("{} is available (this is {})", release.name.as_deref().unwrap_or(&release.tag_name), env!("CARGO_PKG_VERSION")));
+            if let Some(changelog) = release.body.as_deref().filter(|body| !body.trim().is_empty()) {
+                egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                    ui.label(changelog);
+                });
+            }
+            ui.hyperlink_to("Release page", &release.html_url);
+
+            match &checker.download_result {
+                Some(Ok(path)) => {
+                    ui.label(format!("Downloaded to {}", path.display()));
+                }
+                Some(Err(err)) => {
+                    ui.colored_label(egui::Color32::LIGHT_RED, err);
+                }
+                None => {}
+            }
+
+            ui.horizontal(|ui| {
+                match platform_asset(&release.assets, std::env::consts::OS) {
+                    Some(asset) if checker.download_result.is_none() => {
+                        if ui.add_enabled(!checker.downloading, egui::Button::new(format!("Download {}", asset.name))).clicked() {
+                            checker.downloading = true;
+                            let asset = asset.clone();
+                            checker.messages = spawn_task("update download", move |sender| {
+                                let _ = sender.send(match download_asset(&asset) {
+                                    Ok(path) => UpdateMessage::Downloaded(path),
+                                    Err(err) => UpdateMessage::Failed(format!("Failed to download {}: {}", asset.name, err))
+                                });
+                            });
+                        }
+                        if checker.downloading {
+                            ui.spinner();
+                        }
+                    }
+                    Some(_) => {}
+                    None => {
+                        ui.label("No download for this platform");
+                    }
+                }
+                if ui.button("Dismiss").clicked() {
+                    checker.dismissed = true;
+                }
+            });
+        });
+}
+
+pub struct UpdateCheckPlugin;
+
+impl Plugin for UpdateCheckPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin { enable_multipass_for_primary_context: false });
+        }
+
+        app
+            .init_resource::<UpdateCheckSettings>()
+            .init_resource::<UpdateChecker>()
+            .add_systems(Update, (check_for_updates, receive_update_messages, draw_update_notice).chain());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert_eq!(parse_version("v1.2.3"), Some((1, 2, 3)));
+        assert_eq!(parse_version("0.10"), Some((0, 10, 0)));
+        assert_eq!(parse_version("2.0.0-beta.1"), Some((2, 0, 0)));
+        assert_eq!(parse_version("nightly"), None);
+        assert!(is_newer("v0.10.0", "0.9.1"));
+        assert!(!is_newer("v0.1.0", "0.1.0"));
+        assert!(!is_newer("nightly", "0.1.0"));
+    }
+
+    #[test]
+    fn assets_are_matched_to_the_platform() {
+        let asset = |name: &str| ReleaseAsset { name: name.to_string(), browser_download_url: String::new() };
+        let assets = [asset("ARPianoVisualizer-Linux-x86_64.tar.gz"), asset("ARPianoVisualizer-win64.zip")];
+        assert_eq!(platform_asset(&assets, "linux").map(|asset| asset.name.as_str()), Some("ARPianoVisualizer-Linux-x86_64.tar.gz"));
+        assert_eq!(platform_asset(&assets, "windows").map(|asset| asset.name.as_str()), Some("ARPianoVisualizer-win64.zip"));
+        assert!(platform_asset(&assets, "macos").is_none());
+    }
+}
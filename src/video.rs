@@ -1,51 +1,466 @@
-use std::sync::Mutex;
+use std::fmt;
 
-use bevy::{app::{App, Plugin, Update}, ecs::{resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}};
-use opencv::{core::{Mat, MatTraitConst}, videoio::{self, VideoCaptureTrait, VideoCaptureTraitConst}};
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::DetectChanges, event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}, time::Time};
+use opencv::{core::{Mat, Vector}, videoio::{self, VideoCaptureTrait, VideoCaptureTraitConst}};
+use serde::Deserialize;
 
-use crate::VideoCaptureSystems;
+use crate::{replay::ReplayRecorder, startup::{StartupProgress, StartupTask}, status::{AppError, ErrorSource}, VideoCaptureSystems};
+
+use self::capture::{CaptureMessage, CaptureWorker};
 
 pub mod aruco_camera;
+pub mod av_sync;
+pub mod capture;
+pub mod gpu_prefilter;
+pub mod layout_tuning;
+pub mod tracking;
 
 static MJPEG_STREAM_URL: &str = "http://192.168.68.116:8080/video";
+/// How many reads in a row can fail before the source is considered dropped and reopened.
+const FAILURES_BEFORE_RECONNECT: u32 = 30;
+/// The delay before the first reconnect attempt in seconds. It doubles after every failed attempt, up to the maximum.
+const INITIAL_RECONNECT_DELAY: f64 = 0.5;
+const MAX_RECONNECT_DELAY: f64 = 30.0;
+/// Passed to OpenCV so opens and reads give up on their own where the backend supports it (FFmpeg and GStreamer do).
+const OPEN_TIMEOUT_MS: i32 = 5000;
+const READ_TIMEOUT_MS: i32 = 3000;
 
-#[derive(Resource)]
-pub struct VideoCapture(pub Mutex<videoio::VideoCapture>);
+/// Frames held for the app by default. Only reached when keeping every frame, or when the app stalls.
+const DEFAULT_FRAME_QUEUE_CAPACITY: usize = 4;
+
+/// Resolutions tried, largest first, when the device doesn't accept the requested one.
+static FALLBACK_RESOLUTIONS: [(u32, u32); 4] = [(1920, 1080), (1280, 720), (800, 600), (640, 480)];
+
+/// Where frames come from. Insert this before adding `VideoCapturePlugin` to override the default stream.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub enum VideoSource {
+    /// A network stream or device URL, or the index of a local camera like "0".
+    Stream(String),
+    /// A recorded video file, played at its own frame rate and optionally restarted when it ends.
+    File { path: String, looping: bool }
+}
+
+impl Default for VideoSource {
+    fn default() -> Self {
+        VideoSource::Stream(MJPEG_STREAM_URL.to_string())
+    }
+}
+
+/// The capture mode requested from the device. Unset values are left at the device's defaults.
+/// Network streams and video files ignore these; they only apply to local cameras.
+#[derive(Resource, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct VideoSourceConfig {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f64>,
+    /// Ask for MJPEG, which many USB cameras need for high resolutions at full frame rate.
+    pub mjpeg: bool
+}
+
+/// What happens to captured frames when the app can't keep up with the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Skip to the newest frame and drop the rest, for the lowest latency.
+    #[default]
+    KeepLatest,
+    /// Hand over every frame in order, making the capture thread wait when the queue is full.
+    KeepAll
+}
+
+impl DropPolicy {
+    pub const ALL: [DropPolicy; 2] = [DropPolicy::KeepLatest, DropPolicy::KeepAll];
+}
+
+/// The queue between the capture thread and the app. Changes apply without reopening the source.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct FrameQueueSettings {
+    pub policy: DropPolicy,
+    /// The most frames waiting for the app at once.
+    pub capacity: usize,
+    /// Keep every frame while a replay is recording, whatever the policy, so the recording has no gaps.
+    pub keep_all_while_recording: bool
+}
+
+impl Default for FrameQueueSettings {
+    fn default() -> Self {
+        Self {
+            policy: DropPolicy::KeepLatest,
+            capacity: DEFAULT_FRAME_QUEUE_CAPACITY,
+            keep_all_while_recording: true
+        }
+    }
+}
+
+/// The mode the device actually delivers, read back after opening.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureMode {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    pub fourcc: String
+}
+
+impl CaptureMode {
+    fn read(cam: &videoio::VideoCapture) -> opencv::Result<CaptureMode> {
+        let fourcc = cam.get(videoio::CAP_PROP_FOURCC)? as u32;
+        Ok(CaptureMode {
+            width: cam.get(videoio::CAP_PROP_FRAME_WIDTH)? as u32,
+            height: cam.get(videoio::CAP_PROP_FRAME_HEIGHT)? as u32,
+            fps: cam.get(videoio::CAP_PROP_FPS)?,
+            fourcc: fourcc.to_le_bytes().iter().filter(|byte| byte.is_ascii_graphic()).map(|&byte| byte as char).collect()
+        })
+    }
+}
+
+impl fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{} @ {:.0} fps", self.width, self.height, self.fps)?;
+        if !self.fourcc.is_empty() {
+            write!(f, " {}", self.fourcc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies the requested mode to a local camera, stepping down through common resolutions until the device accepts one.
+fn negotiate_mode(cam: &mut videoio::VideoCapture, config: &VideoSourceConfig) -> opencv::Result<CaptureMode> {
+    if config.mjpeg {
+        cam.set(videoio::CAP_PROP_FOURCC, videoio::VideoWriter::fourcc('M', 'J', 'P', 'G')? as f64)?;
+    }
+
+    if let (Some(width), Some(height)) = (config.width, config.height) {
+        let requested = (width, height);
+        let candidates = std::iter::once(requested)
+            // In u64, since a large configured size could overflow u32
+            .chain(FALLBACK_RESOLUTIONS.iter().copied().filter(|&(w, h)| (w as u64) * (h as u64) < (width as u64) * (height as u64)));
+        let mut accepted = false;
+        for (w, h) in candidates {
+            cam.set(videoio::CAP_PROP_FRAME_WIDTH, w as f64)?;
+            cam.set(videoio::CAP_PROP_FRAME_HEIGHT, h as f64)?;
+            let mode = CaptureMode::read(cam)?;
+            if (mode.width, mode.height) == (w, h) {
+                accepted = true;
+                break;
+            }
+            println!("Camera didn't accept {}x{} (got {}x{})", w, h, mode.width, mode.height);
+        }
+        // If nothing matched exactly, ask for the original size again and take the device's closest match
+        if !accepted {
+            cam.set(videoio::CAP_PROP_FRAME_WIDTH, width as f64)?;
+            cam.set(videoio::CAP_PROP_FRAME_HEIGHT, height as f64)?;
+        }
+    }
+
+    // Some backends reset the frame rate when the size changes, so set it last
+    if let Some(fps) = config.fps {
+        cam.set(videoio::CAP_PROP_FPS, fps)?;
+    }
+
+    let mode = CaptureMode::read(cam)?;
+    let fps_matches = config.fps.is_none_or(|fps| (mode.fps - fps).abs() < 1.0);
+    let size_matches = config.width.is_none_or(|width| width == mode.width) && config.height.is_none_or(|height| height == mode.height);
+    if !fps_matches || !size_matches {
+        println!("Camera is using {} instead of the configured mode", mode);
+    }
+    Ok(mode)
+}
+
+impl VideoSource {
+    /// The first local camera.
+    pub fn local_camera() -> VideoSource {
+        VideoSource::Stream("0".to_string())
+    }
 
+    /// The URL or file path OpenCV opens.
+    pub fn path(&self) -> &str {
+        match self {
+            VideoSource::Stream(url) => url,
+            VideoSource::File { path, .. } => path
+        }
+    }
+
+    /// Opens the source and, for local cameras, applies the configured mode. Returns the mode actually obtained.
+    pub fn open(&self, config: &VideoSourceConfig) -> opencv::Result<(videoio::VideoCapture, CaptureMode)> {
+        let params = Vector::from_slice(&[
+            videoio::CAP_PROP_OPEN_TIMEOUT_MSEC, OPEN_TIMEOUT_MS,
+            videoio::CAP_PROP_READ_TIMEOUT_MSEC, READ_TIMEOUT_MS
+        ]);
+        let device = match self {
+            VideoSource::Stream(url) => url.parse::<i32>().ok(),
+            VideoSource::File { .. } => None
+        };
+        let mut cam = match device {
+            Some(index) => videoio::VideoCapture::new_with_params(index, videoio::CAP_ANY, &params)?,
+            None => videoio::VideoCapture::from_file_with_params(self.path(), videoio::CAP_ANY, &params)?
+        };
+        if !cam.is_opened()? {
+            return Err(opencv::Error::new(opencv::core::StsError, format!("Unable to open video source {}", self.path())));
+        }
+
+        // Network streams and files send whatever mode they were encoded in, so only devices are asked for one
+        let mode = match device {
+            Some(_) => negotiate_mode(&mut cam, config)?,
+            None => CaptureMode::read(&cam)?
+        };
+        Ok((cam, mode))
+    }
+}
+
+/// The latest camera frame and its number from the capture thread. The number changes with every new frame,
+/// unlike change detection, which also fires when a system only borrows the frame mutably.
 #[derive(Resource, Default)]
-pub struct WebcamFrame(pub Mat);
+pub struct WebcamFrame(pub Mat, pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// The capture thread is opening the source.
+    Connecting { attempt: u32 },
+    Connected,
+    /// The source dropped or couldn't be opened, and will be retried. `retry_at` is in seconds since startup.
+    Waiting { attempt: u32, retry_at: f64 }
+}
+
+/// Tracks whether the video source is delivering frames, and reopens it with backoff when it stops.
+#[derive(Resource)]
+pub struct CaptureConnection {
+    pub state: ConnectionState,
+    /// The mode reported by the source when it was last opened.
+    pub mode: Option<CaptureMode>,
+    consecutive_failures: u32,
+    /// The queue settings in effect, which new workers start with.
+    queue: FrameQueueSettings,
+    worker: Option<CaptureWorker>,
+    /// A worker opening a newly selected source. It replaces the current one once it opens,
+    /// so a typo in the settings panel doesn't drop a working camera.
+    switching_to: Option<CaptureWorker>
+}
+
+impl CaptureConnection {
+    fn connect(source: &VideoSource, config: &VideoSourceConfig, queue: FrameQueueSettings, attempt: u32) -> Self {
+        Self {
+            state: ConnectionState::Connecting { attempt },
+            mode: None,
+            consecutive_failures: 0,
+            worker: Some(CaptureWorker::spawn(source.clone(), config.clone(), &queue)),
+            queue,
+            switching_to: None
+        }
+    }
+
+    /// Drops the current worker and schedules the given attempt.
+    fn retry(&mut self, attempt: u32, now: f64) {
+        self.worker = None;
+        self.consecutive_failures = 0;
+        self.state = ConnectionState::Waiting { attempt, retry_at: now + reconnect_delay(attempt) };
+    }
+
+    /// A short description for the UI.
+    pub fn status(&self) -> String {
+        match self.state {
+            ConnectionState::Connecting { attempt: 1 } => "Connecting".to_string(),
+            ConnectionState::Connecting { attempt } => format!("Reconnecting (attempt {})", attempt),
+            ConnectionState::Connected => "Connected".to_string(),
+            ConnectionState::Waiting { attempt, .. } => format!("Disconnected (retrying, attempt {})", attempt)
+        }
+    }
+
+    /// The drop policy in effect, which may differ from the settings while recording.
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.queue.policy
+    }
+
+    /// Frames captured since the source was opened but never shown, because the app fell behind.
+    pub fn dropped_frames(&self) -> u64 {
+        self.worker.as_ref().map_or(0, CaptureWorker::dropped_frames)
+    }
+
+    /// Frames captured and waiting for the app, out of the queue's capacity.
+    pub fn queued_frames(&self) -> (usize, usize) {
+        (self.worker.as_ref().map_or(0, CaptureWorker::queued_frames), self.queue.capacity)
+    }
+}
+
+/// The delay before the given reconnect attempt, in seconds. The first attempt after a drop happens right away.
+fn reconnect_delay(attempt: u32) -> f64 {
+    match attempt {
+        0 | 1 => 0.0,
+        attempt => (INITIAL_RECONNECT_DELAY * 2f64.powi((attempt - 2).min(16) as i32)).min(MAX_RECONNECT_DELAY)
+    }
+}
 
 pub struct VideoCapturePlugin;
 
+/// Handles messages from the capture thread, then takes the next frame from its queue.
 fn capture_background_image(
+    time: Res<Time>,
     mut webcam_frame: ResMut<WebcamFrame>,
-    cam: Res<VideoCapture>
+    source: Res<VideoSource>,
+    mut connection: ResMut<CaptureConnection>,
+    mut errors: EventWriter<AppError>
 ) {
-    let frame = &mut webcam_frame.0;
+    let now = time.elapsed_secs_f64();
+    let connection = connection.as_mut();
+    if let Some(message) = connection.worker.as_ref().and_then(CaptureWorker::try_recv) {
+        handle_capture_message(message, now, &source, connection, &mut errors);
+    }
+
+    if let Some((number, frame)) = connection.worker.as_ref().and_then(CaptureWorker::try_recv_frame) {
+        webcam_frame.0 = frame;
+        webcam_frame.1 = number;
+        connection.consecutive_failures = 0;
+    }
+}
 
-    cam.0.lock().expect("Failed to lock video capture mutex").read(frame).expect("Failed to read frame from video capture");
-    if frame.empty() {
-        eprintln!("No frame captured from webcam");
+fn handle_capture_message(message: CaptureMessage, now: f64, source: &VideoSource, connection: &mut CaptureConnection, errors: &mut EventWriter<AppError>) {
+    match message {
+        CaptureMessage::Opened(mode) => {
+            match connection.state {
+                ConnectionState::Connecting { attempt } if attempt > 1 => println!("Reconnected to {} ({})", source.path(), mode),
+                _ => println!("Opened {} ({})", source.path(), mode)
+            }
+            connection.mode = Some(mode);
+            connection.state = ConnectionState::Connected;
+        }
+        CaptureMessage::OpenFailed(err) => {
+            let attempt = match connection.state {
+                ConnectionState::Connecting { attempt } | ConnectionState::Waiting { attempt, .. } => attempt,
+                ConnectionState::Connected => 1
+            };
+            let delay = reconnect_delay(attempt + 1);
+            errors.write(AppError::new(ErrorSource::Capture, format!("{}. Retrying in {:.0}s", err, delay)));
+            connection.retry(attempt + 1, now);
+        }
+        CaptureMessage::ReadFailed(err) => {
+            connection.consecutive_failures += 1;
+            if connection.consecutive_failures < FAILURES_BEFORE_RECONNECT {
+                errors.write(AppError::new(ErrorSource::Capture, err));
+            } else {
+                errors.write(AppError::new(ErrorSource::Capture, format!("Lost connection to {}; reconnecting", source.path())));
+                connection.retry(1, now);
+            }
+        }
+        // Keep showing the last frame
+        CaptureMessage::Ended => {}
+    }
+}
+
+/// Reopens the source once the backoff delay has passed.
+fn reconnect_video_source(
+    time: Res<Time>,
+    source: Res<VideoSource>,
+    source_config: Res<VideoSourceConfig>,
+    mut connection: ResMut<CaptureConnection>
+) {
+    let ConnectionState::Waiting { attempt, retry_at } = connection.state else { return };
+    if time.elapsed_secs_f64() >= retry_at {
+        let queue = connection.queue.clone();
+        *connection = CaptureConnection::connect(&source, &source_config, queue, attempt);
+    }
+}
+
+/// Applies queue changes to the running capture threads, switching to keeping every frame while a replay records.
+fn apply_frame_queue_settings(
+    settings: Res<FrameQueueSettings>,
+    recorder: Option<Res<ReplayRecorder>>,
+    mut connection: ResMut<CaptureConnection>
+) {
+    let mut queue = settings.clone();
+    if queue.keep_all_while_recording && recorder.is_some_and(|recorder| recorder.is_recording()) {
+        queue.policy = DropPolicy::KeepAll;
+    }
+    if queue == connection.queue {
         return;
     }
+
+    for worker in connection.worker.iter().chain(&connection.switching_to) {
+        worker.configure_queue(&queue);
+    }
+    connection.queue = queue;
+}
+
+/// Abandons capture threads stuck in an open or read, which OpenCV can't always interrupt.
+fn watch_capture_thread(
+    time: Res<Time>,
+    source: Res<VideoSource>,
+    mut connection: ResMut<CaptureConnection>,
+    mut errors: EventWriter<AppError>
+) {
+    if connection.switching_to.as_ref().is_some_and(CaptureWorker::is_stalled) {
+        errors.write(AppError::new(ErrorSource::Capture, format!("Failed to switch video source: {} stopped responding", source.path())));
+        connection.switching_to = None;
+    }
+
+    if connection.worker.as_ref().is_some_and(CaptureWorker::is_stalled) {
+        errors.write(AppError::new(ErrorSource::Capture, format!("{} stopped responding; reopening it", source.path())));
+        let attempt = match connection.state {
+            ConnectionState::Connecting { attempt } => attempt + 1,
+            _ => 1
+        };
+        connection.retry(attempt, time.elapsed_secs_f64());
+    }
+}
+
+/// Switches to the new source when `VideoSource` or its mode changes at runtime. Keeps the old source if the new one can't be opened.
+fn reopen_video_source(
+    source: Res<VideoSource>,
+    source_config: Res<VideoSourceConfig>,
+    mut connection: ResMut<CaptureConnection>,
+    mut errors: EventWriter<AppError>
+) {
+    let source_changed = source.is_changed() && !source.is_added();
+    let config_changed = source_config.is_changed() && !source_config.is_added();
+    if source_changed || config_changed {
+        if connection.state == ConnectionState::Connected {
+            connection.switching_to = Some(CaptureWorker::spawn(source.clone(), source_config.clone(), &connection.queue));
+        } else {
+            // Nothing working to keep; start over with the new source
+            let queue = connection.queue.clone();
+            *connection = CaptureConnection::connect(&source, &source_config, queue, 1);
+        }
+    }
+
+    let Some(message) = connection.switching_to.as_ref().and_then(CaptureWorker::try_recv) else { return };
+    match message {
+        CaptureMessage::OpenFailed(err) => {
+            errors.write(AppError::new(ErrorSource::Capture, format!("Failed to switch video source: {}", err)));
+            connection.switching_to = None;
+        }
+        CaptureMessage::Opened(mode) => {
+            println!("Switched video source to {} ({})", source.path(), mode);
+            connection.mode = Some(mode);
+            connection.worker = connection.switching_to.take();
+            connection.consecutive_failures = 0;
+            connection.state = ConnectionState::Connected;
+        }
+        // Nothing else is sent before the source opens
+        _ => {}
+    }
+}
+
+/// Counts opening the camera as part of startup until the first attempt opens it or fails. Retries happen in the
+/// background, so a camera that's off doesn't hold up the loading screen.
+fn finish_camera_startup(connection: Res<CaptureConnection>, mut progress: ResMut<StartupProgress>) {
+    if progress.is_running(StartupTask::Camera) && connection.state != (ConnectionState::Connecting { attempt: 1 }) {
+        progress.finish(StartupTask::Camera);
+    }
 }
 
 impl Plugin for VideoCapturePlugin {
     fn build(&self, app: &mut App) {
-        let cam = videoio::VideoCapture::from_file(MJPEG_STREAM_URL, videoio::CAP_ANY)
-            .expect("Failed to create video capture from MJPEG stream");
-        // Temporary: Use the local camera for testing instead
-        // let cam = videoio::VideoCapture::new(0, videoio::CAP_ANY)
-        //     .expect("Failed to create video capture from camera");
-        let opened = videoio::VideoCapture::is_opened(&cam)
-            .expect("Failed to check if video capture is opened");
-        if !opened {
-            panic!("Unable to open camera stream");
-        }
-        
+        let source = app.world().get_resource::<VideoSource>().cloned().unwrap_or_default();
+        let source_config = app.world_mut().get_resource_or_init::<VideoSourceConfig>().clone();
+        let queue = app.world_mut().get_resource_or_init::<FrameQueueSettings>().clone();
+        // Opening happens on the capture thread, so a missing camera doesn't delay startup
+        let connection = CaptureConnection::connect(&source, &source_config, queue, 1);
+        app.world_mut().get_resource_or_init::<StartupProgress>().begin(StartupTask::Camera);
+
         app
-            .insert_resource(VideoCapture(Mutex::new(cam)))
-            .insert_resource(WebcamFrame(Mat::default()))
-            .add_systems(Update, capture_background_image.in_set(VideoCaptureSystems));
+            .insert_resource(source)
+            .insert_resource(connection)
+            .insert_resource(WebcamFrame::default())
+            .add_plugins(av_sync::AvSyncPlugin)
+            .add_systems(Update, (apply_frame_queue_settings, reopen_video_source, watch_capture_thread, reconnect_video_source, capture_background_image, finish_camera_startup).chain().in_set(VideoCaptureSystems));
     }
-}
\ No newline at end of file
+}
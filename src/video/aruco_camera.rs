@@ -1,52 +1,297 @@
-use std::{fs, sync::Mutex};
+use std::{collections::VecDeque, error::Error, fs, thread, time::{Duration, Instant}};
 
-use bevy::{app::{App, Plugin, Startup, Update}, asset::Assets, color::{palettes::css::{GREEN, SILVER}, Color}, core_pipeline::core_3d::Camera3d, ecs::{resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut}}, math::{primitives::{Plane3d, Sphere}, Mat3, Quat, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::mesh::{Mesh, Mesh3d, Meshable}, transform::components::Transform};
-use opencv::{boxed_ref::BoxedRef, calib3d, core::{AlgorithmHint, DataType, Mat, MatTraitConst, MatTraitConstManual, Point2f, Point2i, Point3d, Scalar, Vector}, objdetect::{self, ArucoDetector, RefineParameters}, prelude::ArucoDetectorTraitConst};
-use serde::Deserialize;
-use crate::{video::WebcamFrame, VideoUpdateSystems};
+use bevy::{app::{App, Plugin, Startup, Update}, asset::Assets, color::{palettes::css::{GREEN, SILVER, YELLOW}, Color}, core_pipeline::core_3d::Camera3d, diagnostic::Diagnostics, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, event::{Event, EventReader, EventWriter}, query::{With, Without}, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut}}, gizmos::gizmos::Gizmos, math::{primitives::{Plane3d, Sphere}, DMat3, DVec3, Mat3, Quat, Vec3}, pbr::{MeshMaterial3d, StandardMaterial}, render::{mesh::{Mesh, Mesh3d, Meshable}, view::RenderLayers}, time::Time, transform::components::Transform};
+use opencv::{boxed_ref::BoxedRef, calib3d, core::{AlgorithmHint, DataType, Mat, MatTraitConst, MatTraitConstManual, Point2f, Point2i, Point3d, Point3f, Rect, Scalar, Size, TermCriteria, TermCriteria_Type, Vector}, objdetect::{self, ArucoDetector, Board, Dictionary, PredefinedDictionaryType, RefineParameters}, prelude::{ArucoDetectorTraitConst, BoardTraitConst}};
+use crossbeam_channel::{Receiver, Sender, TryRecvError};
+use serde::{Deserialize, Serialize};
+use crate::{camera_cuts::ShotCamera, diagnostics::{profiling::profile_scope, stage_timing::PipelineStage}, status::{self, AppError, ErrorSource}, render_layers::{OutputCamera, DEBUG_LAYER}, startup::{StartupProgress, StartupTask}, video::{gpu_prefilter::{self, GpuCandidates, GpuPrefilterSettings}, tracking::{smoothing::PoseFilter, TrackingSettings}, WebcamFrame}, VideoUpdateSystems};
 
 static DEBUG_POINTS: bool = false;
+/// How many recent poses the average reprojection error covers.
+const REPROJECTION_ERROR_HISTORY: usize = 30;
+/// How far each time-sliced strip extends into its neighbours, as a fraction of the frame width, so a marker on the
+/// boundary is whole in at least one of them.
+const SLICE_OVERLAP: f32 = 0.05;
+/// How far around a tracked marker time-sliced detection looks for it again, in multiples of its size.
+const TRACKED_REGION_MARGIN: f32 = 1.0;
 
 pub struct ArUcoCameraPlugin;
 
+/// The camera calibration to load. Insert this before adding `ArUcoCameraPlugin` to use a different file.
+#[derive(Resource, Clone, Debug)]
+pub struct CalibrationFile(pub String);
+
+impl Default for CalibrationFile {
+    fn default() -> Self {
+        CalibrationFile("assets/calibration.json".to_string())
+    }
+}
+
+/// The calibrated camera. Missing if the calibration couldn't be loaded, in which case tracking is disabled.
 #[derive(Resource)]
 pub struct CameraIntrinsics {
     pub camera_matrix: Mat,
     pub dist_coeffs: Mat
 }
 
+impl CameraIntrinsics {
+    /// Loads a calibration file in CalibDB's JSON format.
+    pub fn load(path: &str) -> Result<CameraIntrinsics, Box<dyn Error>> {
+        let file_data = fs::read_to_string(path)?;
+        let calibration_data: CalibrationData = serde_json::from_str(&file_data)?;
+
+        let camera_matrix = Mat::from_slice_2d(&calibration_data.camera_matrix)?;
+        let dist_coeffs = Mat::from_slice(&calibration_data.distortion_coefficients)?.try_clone()?;
+        Ok(CameraIntrinsics {
+            camera_matrix,
+            dist_coeffs
+        })
+    }
+}
+
+/// The algorithm `solvePnP` uses to find the pose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PnpMethod {
+    /// Levenberg-Marquardt minimization of the reprojection error. Works with any points, but can settle on the wrong
+    /// pose when there are only a few.
+    Iterative,
+    Epnp,
+    Sqpnp,
+    /// Any set of coplanar points.
+    Ippe,
+    /// Only the four corners of a single square marker, where it's the most stable choice.
+    IppeSquare
+}
+
+impl PnpMethod {
+    pub const ALL: [PnpMethod; 5] = [PnpMethod::Iterative, PnpMethod::Epnp, PnpMethod::Sqpnp, PnpMethod::Ippe, PnpMethod::IppeSquare];
+
+    fn flag(self) -> i32 {
+        match self {
+            PnpMethod::Iterative => calib3d::SOLVEPNP_ITERATIVE,
+            PnpMethod::Epnp => calib3d::SOLVEPNP_EPNP,
+            PnpMethod::Sqpnp => calib3d::SOLVEPNP_SQPNP,
+            PnpMethod::Ippe => calib3d::SOLVEPNP_IPPE,
+            PnpMethod::IppeSquare => calib3d::SOLVEPNP_IPPE_SQUARE
+        }
+    }
+}
+
+/// How the camera pose is solved from the detected corners.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PnpSettings {
+    /// The solver used when only one marker of the layout is detected.
+    pub single_marker_method: PnpMethod,
+    /// The solver used when several markers are detected. `IppeSquare` only works for one marker, so `Ippe` is used instead.
+    pub board_method: PnpMethod,
+    /// Rejects outlying corners with RANSAC when solving from several markers.
+    pub ransac: bool,
+    pub ransac_iterations: i32,
+    /// How far in pixels a corner can be from where the candidate pose projects it and still count as an inlier.
+    pub ransac_reprojection_error: f32,
+    /// The probability that RANSAC finds a good pose, from 0 to 1.
+    pub ransac_confidence: f64
+}
+
+impl Default for PnpSettings {
+    fn default() -> Self {
+        // OpenCV's own RANSAC defaults
+        Self {
+            single_marker_method: PnpMethod::Iterative,
+            board_method: PnpMethod::Iterative,
+            ransac: true,
+            ransac_iterations: 100,
+            ransac_reprojection_error: 8.0,
+            ransac_confidence: 0.99
+        }
+    }
+}
+
+/// Controls how much work marker detection does per frame.
+#[derive(Resource, Clone, PartialEq)]
+pub struct DetectionSettings {
+    /// Markers are detected on the greyscale frame scaled by this factor, then corners are scaled back up before solving the pose.
+    pub scale: f64,
+    /// Detection only runs every this many frames. The camera keeps its last pose in between.
+    pub interval: u32,
+    /// Solves each marker's pose on its own and draws its axes next to where the layout says it should be,
+    /// which shows when one marker's physical placement doesn't match the layout.
+    pub marker_axes: bool,
+    /// Refines the detected corners with `cornerSubPix` on the full-resolution greyscale frame before solving the pose,
+    /// which reduces jitter, especially when detecting at a reduced scale.
+    pub subpixel_refinement: bool,
+    /// Half the side length of the refinement search window, in pixels. Larger windows handle more scaling error,
+    /// but can wander onto neighbouring features on small markers.
+    pub subpixel_window: i32,
+    /// On frames at least `slice_min_width` wide, scans only one of `slices` vertical strips of the frame per detection,
+    /// plus the areas around the markers found last time. Keeps detection time bounded on 4K streams while the tracked
+    /// markers still update the pose every time; markers entering the view are found within `slices` detections.
+    pub time_slicing: bool,
+    pub slices: usize,
+    pub slice_min_width: i32,
+    pub pnp: PnpSettings
+}
+
+impl Default for DetectionSettings {
+    fn default() -> Self {
+        Self {
+            scale: 0.5,
+            interval: 2,
+            marker_axes: false,
+            subpixel_refinement: false,
+            subpixel_window: 5,
+            time_slicing: false,
+            slices: 2,
+            slice_min_width: 2560,
+            pnp: PnpSettings::default()
+        }
+    }
+}
+
+/// How far the detected corners are from the fiducial corners projected back into the image with the solved pose.
+/// Large errors mean a bad calibration, or markers that aren't where the layout places them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReprojectionError {
+    /// The root mean square distance in pixels.
+    pub rms: f64,
+    /// The largest distance in pixels.
+    pub max: f64,
+    /// The number of corners compared.
+    pub corners: usize
+}
+
+impl ReprojectionError {
+    fn measure(projected: &Vector<Point2f>, detected: &Vector<Point2f>) -> Option<ReprojectionError> {
+        let distances: Vec<f64> = projected.iter().zip(detected.iter())
+            .map(|(projected, detected)| ((projected.x - detected.x) as f64).hypot((projected.y - detected.y) as f64))
+            .collect();
+        if distances.is_empty() {
+            return None;
+        }
+        Some(ReprojectionError {
+            rms: (distances.iter().map(|distance| distance * distance).sum::<f64>() / distances.len() as f64).sqrt(),
+            max: distances.iter().copied().fold(0.0, f64::max),
+            corners: distances.len()
+        })
+    }
+}
+
 #[derive(Resource)]
 pub struct ArucoTrackingData {
     greyscale_image: Mat,
+    downscaled_image: Mat,
+    frame_counter: u32,
 
     ids: Vector<i32>,
     corners: Vector<Vector<Point2f>>,
     rejected_img_points: Vector<Vector<Point2f>>,
+    /// The strip the next time-sliced detection scans.
+    next_slice: usize,
 
     latest_rotation: Mat,
-    latest_translation: Mat
+    latest_translation: Mat,
+    /// When the latest pose was solved, in seconds since startup.
+    last_pose_time: Option<f64>,
+    reprojection_error: Option<ReprojectionError>,
+    /// The RMS reprojection errors of the last few poses, for comparing detection settings.
+    recent_reprojection_errors: VecDeque<f64>,
+    /// Each detected marker's own pose in the board frame, if `DetectionSettings::marker_axes` is enabled.
+    marker_poses: Vec<(i32, Transform)>
 }
 
 impl Default for ArucoTrackingData {
     fn default() -> Self {
         Self {
             greyscale_image: Mat::default(),
+            downscaled_image: Mat::default(),
+            frame_counter: 0,
             ids: Vector::new(),
             corners: Vector::new(),
             rejected_img_points: Vector::new(),
+            next_slice: 0,
             latest_rotation: Mat::from_slice(&[0.0, 0.0, 0.0]).expect("Failed to create default rotation vector").try_clone().expect("Failed to clone default rotation vector"),
-            latest_translation: Mat::from_slice(&[0.0, 0.0, 0.0]).expect("Failed to create default translation vector").try_clone().expect("Failed to clone default translation vector")
+            latest_translation: Mat::from_slice(&[0.0, 0.0, 0.0]).expect("Failed to create default translation vector").try_clone().expect("Failed to clone default translation vector"),
+            last_pose_time: None,
+            reprojection_error: None,
+            recent_reprojection_errors: VecDeque::new(),
+            marker_poses: Vec::new()
         }
     }
 }
 
-#[derive(Resource)]
-pub struct FiducialDetector(Mutex<ArucoDetector>);
+impl ArucoTrackingData {
+    /// The IDs of the markers detected in the latest frame.
+    pub fn detected_ids(&self) -> Vec<i32> {
+        self.ids.to_vec()
+    }
+
+    /// The number of markers detected in the latest frame.
+    pub fn detected_count(&self) -> usize {
+        self.ids.len()
+    }
 
-struct FiducialPosition {
-    id: i32,
+    /// When the latest pose was solved, in seconds since startup, or `None` if no pose has been solved yet.
+    pub fn last_pose_time(&self) -> Option<f64> {
+        self.last_pose_time
+    }
+
+    /// The RMS reprojection error of the latest pose in pixels, or `None` if no pose has been solved yet.
+    pub fn reprojection_error(&self) -> Option<f64> {
+        self.reprojection_error.map(|error| error.rms)
+    }
+
+    /// The full reprojection error of the latest pose, or `None` if no pose has been solved yet.
+    pub fn reprojection_details(&self) -> Option<ReprojectionError> {
+        self.reprojection_error
+    }
+
+    /// The mean RMS reprojection error of the last `REPROJECTION_ERROR_HISTORY` poses in pixels, which is steadier than
+    /// the latest one for telling whether a setting helps.
+    pub fn average_reprojection_error(&self) -> Option<f64> {
+        (!self.recent_reprojection_errors.is_empty())
+            .then(|| self.recent_reprojection_errors.iter().sum::<f64>() / self.recent_reprojection_errors.len() as f64)
+    }
+
+    /// The given marker's own pose in the board frame, if `DetectionSettings::marker_axes` is enabled and it was detected.
+    pub fn marker_pose(&self, id: i32) -> Option<Transform> {
+        self.marker_poses.iter().find(|(marker_id, _)| *marker_id == id).map(|(_, pose)| *pose)
+    }
+
+    /// The number of marker candidates rejected in the latest frame.
+    pub fn rejected_count(&self) -> usize {
+        self.rejected_img_points.len()
+    }
+
+    /// The latest frame in greyscale and the marker candidates rejected in it, in full-resolution pixels. Only
+    /// available between a detection finishing and the next one starting, since the frame is lent to the detection thread.
+    pub fn rejected_candidates(&self) -> Option<(&Mat, &Vector<Vector<Point2f>>)> {
+        (!self.greyscale_image.empty()).then_some((&self.greyscale_image, &self.rejected_img_points))
+    }
+
+    /// The bytes held by the greyscale and downscaled image buffers.
+    pub fn buffer_bytes(&self) -> u64 {
+        crate::diagnostics::memory::mat_bytes(&self.greyscale_image) + crate::diagnostics::memory::mat_bytes(&self.downscaled_image)
+    }
+
+    /// The latest solved pose as OpenCV (rotation vector, translation vector).
+    pub fn latest_pose(&self) -> (Vec<f64>, Vec<f64>) {
+        let to_vec = |mat: &Mat| mat.data_typed::<f64>().map(|data| data.to_vec()).unwrap_or_default();
+        (to_vec(&self.latest_rotation), to_vec(&self.latest_translation))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FiducialPosition {
+    pub id: i32,
     /** The offset from the center of the keyboard to the center of the fiducial in mm. Rightward is positive. */
-    x_offset: f64
+    pub x_offset: f64,
+    /** The height of the fiducial in mm. Markers at different heights or depths tilt the keyboard plane fitted through them. */
+    #[serde(default)]
+    pub y_offset: f64,
+    /** The offset toward the player in mm. */
+    #[serde(default)]
+    pub z_offset: f64
 }
 
 static TEST_COLORS: &[[f32; 3]] = &[
@@ -67,105 +312,909 @@ static TEST_COLORS: &[[f32; 3]] = &[
 ];
 
 impl FiducialPosition {
-    fn get_corners(&self) -> [Point3d; 4] {
-        let half_size = FIDUCIAL_SIZE / 2.0;
-        [
-            // OpenCV returns corners in the order of bottom-right, bottom-left, top-left, top-right
-            // Positive z is toward the camera
-            Point3d::new(self.x_offset + half_size, 0.0, half_size),  // Bottom-right
-            Point3d::new(self.x_offset - half_size, 0.0, half_size),  // Bottom-left
-            Point3d::new(self.x_offset - half_size, 0.0, -half_size), // Top-left
-            Point3d::new(self.x_offset + half_size, 0.0, -half_size)  // Top-right
-        ]
-    }
-}
-
-/** The size of the fiducial markers in mm. */
-static FIDUCIAL_SIZE: f64 = 82.5;
-static FIDUCIAL_POSITIONS: &[FiducialPosition] = &[
-    FiducialPosition { id: 0, x_offset: -105.0 - 280.0 - FIDUCIAL_SIZE / 2.0 },
-    FiducialPosition { id: 1, x_offset: -105.0 - FIDUCIAL_SIZE / 2.0 },
-    FiducialPosition { id: 2, x_offset: 105.0 + FIDUCIAL_SIZE / 2.0 },
-    FiducialPosition { id: 3, x_offset: 105.0 + 280.0 + FIDUCIAL_SIZE / 2.0 },
-];
+    pub fn new(id: i32, x_offset: f64) -> Self {
+        Self { id, x_offset, y_offset: 0.0, z_offset: 0.0 }
+    }
 
-fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>
-) {
+    /// The center of the fiducial in world coordinates.
+    pub fn center(&self) -> Vec3 {
+        Vec3::new(self.x_offset as f32, self.y_offset as f32, self.z_offset as f32)
+    }
+
+    fn get_corners(&self, size: f64) -> [Point3d; 4] {
+        marker_corners(size).map(|corner| Point3d::new(corner.x + self.x_offset, corner.y + self.y_offset, corner.z + self.z_offset))
+    }
+}
+
+/// The corners of a marker centered on the origin, with the same axes as the board.
+fn marker_corners(size: f64) -> [Point3d; 4] {
+    let half_size = size / 2.0;
+    [
+        // OpenCV returns corners in the order of bottom-right, bottom-left, top-left, top-right
+        // Positive z is toward the camera
+        Point3d::new(half_size, 0.0, half_size),  // Bottom-right
+        Point3d::new(-half_size, 0.0, half_size),  // Bottom-left
+        Point3d::new(-half_size, 0.0, -half_size), // Top-left
+        Point3d::new(half_size, 0.0, -half_size)  // Top-right
+    ]
+}
+
+/// The rotation from a marker's own frame, as `SOLVEPNP_IPPE_SQUARE` expects it, to the board frame.
+/// Markers lie flat facing up, so the marker's x is the board's -x, its y is the board's z, and its normal is the board's y.
+const MARKER_TO_BOARD: DMat3 = DMat3::from_cols(DVec3::NEG_X, DVec3::Z, DVec3::Y);
+
+/** The size of the fiducial markers in mm, unless a layout file says otherwise. */
+static DEFAULT_FIDUCIAL_SIZE: f64 = 82.5;
+
+/// The family of markers to detect. ArUco dictionaries are named for their bit grid and marker count; larger
+/// dictionaries allow more ids but are more easily confused with each other. AprilTag families resist false detections best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MarkerDictionary {
+    #[serde(rename = "4x4_50")]
+    Aruco4x4_50,
+    #[serde(rename = "4x4_100")]
+    Aruco4x4_100,
+    #[serde(rename = "4x4_250")]
+    Aruco4x4_250,
+    #[serde(rename = "4x4_1000")]
+    Aruco4x4_1000,
+    #[serde(rename = "5x5_50")]
+    Aruco5x5_50,
+    #[serde(rename = "5x5_100")]
+    Aruco5x5_100,
+    #[serde(rename = "5x5_250")]
+    Aruco5x5_250,
+    #[serde(rename = "5x5_1000")]
+    Aruco5x5_1000,
+    #[serde(rename = "6x6_50")]
+    Aruco6x6_50,
+    #[serde(rename = "6x6_100")]
+    Aruco6x6_100,
+    #[serde(rename = "6x6_250")]
+    Aruco6x6_250,
+    #[serde(rename = "6x6_1000")]
+    Aruco6x6_1000,
+    #[serde(rename = "aruco_original")]
+    ArucoOriginal,
+    #[serde(rename = "apriltag_16h5")]
+    AprilTag16h5,
+    /// The markers this project has always used.
+    #[default]
+    #[serde(rename = "apriltag_25h9")]
+    AprilTag25h9,
+    #[serde(rename = "apriltag_36h10")]
+    AprilTag36h10,
+    #[serde(rename = "apriltag_36h11")]
+    AprilTag36h11
+}
+
+impl MarkerDictionary {
+    pub const ALL: [MarkerDictionary; 17] = [MarkerDictionary::Aruco4x4_50, MarkerDictionary::Aruco4x4_100, MarkerDictionary::Aruco4x4_250, MarkerDictionary::Aruco4x4_1000, MarkerDictionary::Aruco5x5_50, MarkerDictionary::Aruco5x5_100, MarkerDictionary::Aruco5x5_250, MarkerDictionary::Aruco5x5_1000, MarkerDictionary::Aruco6x6_50, MarkerDictionary::Aruco6x6_100, MarkerDictionary::Aruco6x6_250, MarkerDictionary::Aruco6x6_1000, MarkerDictionary::ArucoOriginal, MarkerDictionary::AprilTag16h5, MarkerDictionary::AprilTag25h9, MarkerDictionary::AprilTag36h10, MarkerDictionary::AprilTag36h11];
+
+    fn predefined(self) -> PredefinedDictionaryType {
+        match self {
+            MarkerDictionary::Aruco4x4_50 => PredefinedDictionaryType::DICT_4X4_50,
+            MarkerDictionary::Aruco4x4_100 => PredefinedDictionaryType::DICT_4X4_100,
+            MarkerDictionary::Aruco4x4_250 => PredefinedDictionaryType::DICT_4X4_250,
+            MarkerDictionary::Aruco4x4_1000 => PredefinedDictionaryType::DICT_4X4_1000,
+            MarkerDictionary::Aruco5x5_50 => PredefinedDictionaryType::DICT_5X5_50,
+            MarkerDictionary::Aruco5x5_100 => PredefinedDictionaryType::DICT_5X5_100,
+            MarkerDictionary::Aruco5x5_250 => PredefinedDictionaryType::DICT_5X5_250,
+            MarkerDictionary::Aruco5x5_1000 => PredefinedDictionaryType::DICT_5X5_1000,
+            MarkerDictionary::Aruco6x6_50 => PredefinedDictionaryType::DICT_6X6_50,
+            MarkerDictionary::Aruco6x6_100 => PredefinedDictionaryType::DICT_6X6_100,
+            MarkerDictionary::Aruco6x6_250 => PredefinedDictionaryType::DICT_6X6_250,
+            MarkerDictionary::Aruco6x6_1000 => PredefinedDictionaryType::DICT_6X6_1000,
+            MarkerDictionary::ArucoOriginal => PredefinedDictionaryType::DICT_ARUCO_ORIGINAL,
+            MarkerDictionary::AprilTag16h5 => PredefinedDictionaryType::DICT_APRILTAG_16h5,
+            MarkerDictionary::AprilTag25h9 => PredefinedDictionaryType::DICT_APRILTAG_25h9,
+            MarkerDictionary::AprilTag36h10 => PredefinedDictionaryType::DICT_APRILTAG_36h10,
+            MarkerDictionary::AprilTag36h11 => PredefinedDictionaryType::DICT_APRILTAG_36h11
+        }
+    }
+
+    pub fn dictionary(self) -> opencv::Result<Dictionary> {
+        objdetect::get_predefined_dictionary(self.predefined())
+    }
+
+    fn detector(self) -> opencv::Result<ArucoDetector> {
+        ArucoDetector::new(
+            &self.dictionary()?,
+            &objdetect::DetectorParameters::default()?,
+            RefineParameters::new(10.0, 3.0, true)?
+        )
+    }
+}
+
+/// Where the fiducial markers are placed along the back of the keyboard.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FiducialLayout {
+    /// The size of the fiducial markers in mm.
+    pub size: f64,
+    /// The dictionary the markers were printed from. Changing it rebuilds the detector.
+    #[serde(default)]
+    pub dictionary: MarkerDictionary,
+    pub fiducials: Vec<FiducialPosition>
+}
+
+impl Default for FiducialLayout {
+    fn default() -> Self {
+        let size = DEFAULT_FIDUCIAL_SIZE;
+        Self {
+            size,
+            dictionary: MarkerDictionary::default(),
+            fiducials: vec![
+                FiducialPosition::new(0, -105.0 - 280.0 - size / 2.0),
+                FiducialPosition::new(1, -105.0 - size / 2.0),
+                FiducialPosition::new(2, 105.0 + size / 2.0),
+                FiducialPosition::new(3, 105.0 + 280.0 + size / 2.0)
+            ]
+        }
+    }
+}
+
+impl FiducialLayout {
+    /// Loads a layout from a JSON file like `{ "size": 82.5, "dictionary": "apriltag_25h9", "fiducials": [{ "id": 0, "x_offset": -426.25 }, ...] }`.
+    /// `dictionary` is optional and defaults to AprilTag 25h9, and `y_offset` and `z_offset` default to zero.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Writes the layout in the format `load` reads.
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// An OpenCV board of the layout's markers, for matching detected corners to their positions.
+    fn board(&self, dictionary: &Dictionary) -> opencv::Result<Board> {
+        let marker_points: Vector<Vector<Point3f>> = self.fiducials.iter()
+            .map(|fiducial| fiducial.get_corners(self.size).iter().map(|corner| Point3f::new(corner.x as f32, corner.y as f32, corner.z as f32)).collect())
+            .collect();
+        let ids: Vector<i32> = self.fiducials.iter().map(|fiducial| fiducial.id).collect();
+        Board::new(&marker_points, dictionary, &ids)
+    }
+
+    /// Returns the 3D corners of the given fiducials, in the same order OpenCV reports the detected corners.
+    pub fn object_points(&self, ids: &Vector<i32>) -> Vector<Point3d> {
+        ids.iter()
+            .filter_map(|id| {
+                // It's not a big deal that this is O(n^2) since there are only a few fiducials
+                self.fiducials.iter().find(|fiducial| fiducial.id == id)
+                    .map(|fiducial| fiducial.get_corners(self.size))
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// The corners of every fiducial in the layout.
+    pub fn corner_positions(&self) -> Vec<Vec3> {
+        self.fiducials.iter()
+            .flat_map(|fiducial| fiducial.get_corners(self.size))
+            .map(|corner| Vec3::new(corner.x as f32, corner.y as f32, corner.z as f32))
+            .collect()
+    }
+
+    /// The corners of the given fiducials. Ids that aren't in the layout are skipped.
+    pub fn corner_positions_of(&self, ids: &[i32]) -> Vec<Vec3> {
+        self.fiducials.iter()
+            .filter(|fiducial| ids.contains(&fiducial.id))
+            .flat_map(|fiducial| fiducial.get_corners(self.size))
+            .map(|corner| Vec3::new(corner.x as f32, corner.y as f32, corner.z as f32))
+            .collect()
+    }
+}
+
+/// The debug planes showing where each fiducial should be.
+#[derive(Component)]
+struct FiducialPlane;
+
+fn setup(mut commands: Commands) {
     // Spawn camera
     commands.spawn((
         Camera3d::default(),
         Transform::from_xyz(0.0, 500.0, 500.0).looking_at(Vec3::ZERO, Vec3::Y),
+        OutputCamera::MainWindow
     ));
 
-    // Spawn a plane for each fiducial marker
-    for fiducial in FIDUCIAL_POSITIONS {
+}
+
+/// Spawns a plane for each fiducial marker, replacing the old ones whenever the layout changes.
+fn spawn_fiducial_planes(
+    mut commands: Commands,
+    layout: Res<FiducialLayout>,
+    existing: Query<Entity, With<FiducialPlane>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+) {
+    if !layout.is_changed() {
+        return;
+    }
+    for entity in existing.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    let size = layout.size as f32;
+    for fiducial in &layout.fiducials {
         commands.spawn((
-            Mesh3d(meshes.add(Plane3d::default().mesh().size(FIDUCIAL_SIZE as f32, FIDUCIAL_SIZE as f32))),
+            Mesh3d(meshes.add(Plane3d::default().mesh().size(size, size))),
             MeshMaterial3d(materials.add(Color::from(SILVER))),
-            Transform::from_xyz(fiducial.x_offset as f32, 0.0, 0.0)
+            Transform::from_translation(fiducial.center()),
+            RenderLayers::layer(DEBUG_LAYER),
+            FiducialPlane
         ));
 
         // Add another plane upside down to visualize the fiducial
         commands.spawn((
-            Mesh3d(meshes.add(Plane3d::default().mesh().size(FIDUCIAL_SIZE as f32, FIDUCIAL_SIZE as f32))),
+            Mesh3d(meshes.add(Plane3d::default().mesh().size(size, size))),
             MeshMaterial3d(materials.add(Color::from(GREEN))),
-            Transform::from_xyz(fiducial.x_offset as f32, 0.0, 0.0).with_rotation(Quat::from_rotation_x(std::f32::consts::PI))
+            Transform::from_translation(fiducial.center()).with_rotation(Quat::from_rotation_x(std::f32::consts::PI)),
+            RenderLayers::layer(DEBUG_LAYER),
+            FiducialPlane
         ));
     }
 }
 
-fn track_aruco_targets(
-    fiducial_detector: Res<FiducialDetector>,
-    mut webcam_frame: ResMut<WebcamFrame>,
-    mut tracking_data: ResMut<ArucoTrackingData>,
-    camera_intrinsics: Res<CameraIntrinsics>,
-    mut camera_query: Query<(
-        &mut Camera3d,
-        &mut Transform
-    )>,
+/// The result of detecting markers and solving the pose for one frame on the detection thread.
+struct DetectionResult {
+    /// The image buffers are handed back so they can be reused for the next frame.
+    greyscale_image: Mat,
+    downscaled_image: Mat,
 
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>
-) {
-    let frame = &mut webcam_frame.0;
+    ids: Vector<i32>,
+    corners: Vector<Vector<Point2f>>,
+    rejected_img_points: Vector<Vector<Point2f>>,
+
+    /// The solved (rotation vector, translation vector), if a pose was found.
+    pose: Option<(Mat, Mat)>,
+    /// Each marker's own (id, rotation vector, translation vector), if requested.
+    marker_poses: Vec<(i32, Mat, Mat)>,
+    reprojection_error: Option<ReprojectionError>,
+    times: DetectionTimes,
+    errors: Vec<String>
+}
+
+impl DetectionResult {
+    /// An empty result that detection fills in, reusing the given image buffers.
+    fn new(greyscale_image: Mat, downscaled_image: Mat) -> Self {
+        Self {
+            greyscale_image,
+            downscaled_image,
+            ids: Vector::new(),
+            corners: Vector::new(),
+            rejected_img_points: Vector::new(),
+            pose: None,
+            marker_poses: Vec::new(),
+            reprojection_error: None,
+            times: DetectionTimes::default(),
+            errors: Vec::new()
+        }
+    }
+}
+
+/// How long each stage of detecting one frame took on the detection thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DetectionTimes {
+    /// Downscaling and finding the markers.
+    pub detect: Duration,
+    /// Subpixel corner refinement, if enabled.
+    pub refine: Duration,
+    /// Solving the pose and measuring its reprojection error.
+    pub pnp: Duration
+}
+
+/// Everything the detection thread needs to process one frame.
+struct DetectionRequest {
+    result: DetectionResult,
+    layout: FiducialLayout,
+    scale: f64,
+    marker_axes: bool,
+    /// The `cornerSubPix` window half size, if corners should be refined.
+    refinement_window: Option<i32>,
+    /// The parts of the frame to scan, in full-resolution pixels, if detection is time-sliced.
+    regions: Option<Vec<Rect>>,
+    pnp: PnpSettings,
+    camera_matrix: Mat,
+    dist_coeffs: Mat
+}
+
+impl DetectionRequest {
+    fn new(result: DetectionResult, layout: FiducialLayout, settings: &DetectionSettings, regions: Option<Vec<Rect>>, intrinsics: &CameraIntrinsics) -> opencv::Result<Self> {
+        Ok(Self {
+            result,
+            layout,
+            scale: settings.scale.clamp(0.1, 1.0),
+            marker_axes: settings.marker_axes,
+            refinement_window: settings.subpixel_refinement.then_some(settings.subpixel_window.clamp(2, 10)),
+            regions,
+            pnp: settings.pnp,
+            camera_matrix: intrinsics.camera_matrix.try_clone()?,
+            dist_coeffs: intrinsics.dist_coeffs.try_clone()?
+        })
+    }
+}
+
+/// The thread that owns the ArUco detector. Frames go to it and results come back over channels,
+/// so the detector is never shared or locked.
+#[derive(Resource)]
+pub struct DetectionWorker {
+    requests: Sender<DetectionRequest>,
+    results: Receiver<DetectionResult>,
+    /// Whether a frame has been sent and its result not yet collected. Only one frame is in flight at a time.
+    busy: bool,
+    /// Sent once the detector for the starting layout is built, or has failed to build.
+    ready: Receiver<()>
+}
+
+/// A detector for a layout's dictionary, and the board matching detected corners to the layout's markers.
+struct LayoutDetector {
+    layout: FiducialLayout,
+    detector: ArucoDetector,
+    board: Board
+}
+
+impl LayoutDetector {
+    fn new(layout: &FiducialLayout) -> opencv::Result<Self> {
+        let detector = layout.dictionary.detector()?;
+        let board = layout.board(&detector.get_dictionary()?)?;
+        Ok(Self { layout: layout.clone(), detector, board })
+    }
+
+    /// Rebuilds the board if the layout has changed, and the detector too if its dictionary has.
+    fn update(mut self, layout: &FiducialLayout) -> opencv::Result<Self> {
+        if layout.dictionary != self.layout.dictionary {
+            return LayoutDetector::new(layout);
+        }
+        if *layout != self.layout {
+            self.board = layout.board(&self.detector.get_dictionary()?)?;
+            self.layout = layout.clone();
+        }
+        Ok(self)
+    }
+}
+
+impl DetectionWorker {
+    /// Starts the detection thread, building the detector for `layout` before the first frame arrives.
+    fn spawn(layout: FiducialLayout) -> DetectionWorker {
+        let (requests, request_receiver) = crossbeam_channel::unbounded::<DetectionRequest>();
+        let (result_sender, results) = crossbeam_channel::unbounded();
+        let (ready_sender, ready) = crossbeam_channel::bounded(1);
+
+        thread::Builder::new()
+            .name("marker detection".to_string())
+            .spawn(move || {
+                // Rebuilt whenever the layout changes. If building fails here, the first request tries again and reports the error
+                let mut detector = {
+                    profile_scope!("build marker detector");
+                    LayoutDetector::new(&layout).ok()
+                };
+                let _ = ready_sender.send(());
+
+                // Runs until the app drops the worker
+                for mut request in request_receiver {
+                    let built = match detector.take() {
+                        Some(current) => current.update(&request.layout),
+                        None => LayoutDetector::new(&request.layout)
+                    };
+                    detector = match built {
+                        Ok(built) => Some(built),
+                        Err(err) => {
+                            request.result.errors.push(format!("Failed to create a detector for {:?}: {}", request.layout.dictionary, err));
+                            None
+                        }
+                    };
+
+                    if let Some(detector) = &detector {
+                        if let Err(err) = detect_and_solve(detector, &mut request) {
+                            request.result.errors.push(format!("Marker detection failed: {}", err));
+                        }
+                    }
+                    if result_sender.send(request.result).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Failed to spawn the marker detection thread");
+
+        DetectionWorker {
+            requests,
+            results,
+            busy: false,
+            ready
+        }
+    }
+}
+
+/// Sent when the detection pipeline solves a new camera pose.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CameraPoseUpdated {
+    /// The camera's pose in the fiducial frame, in Bevy's coordinate system.
+    pub transform: Transform
+}
+
+fn vec3_from_mat(vector: &Mat) -> opencv::Result<Vec3> {
+    Ok(Vec3::from_slice(vector.data_typed::<f64>()?.iter().map(|&x| x as f32).collect::<Vec<_>>().as_slice()))
+}
+
+/// Converts an OpenCV rotation vector into the inverse of the rotation it describes.
+fn inverse_rotation_from_vector(rotation: &Mat) -> opencv::Result<Mat3> {
+    let mut rotation_matrix = Mat::default();
+    calib3d::rodrigues_def(rotation, &mut rotation_matrix)?;
+
+    // OpenCV matrices are row-major, so reading the data as columns gives the transpose, which is the inverse rotation
+    Ok(Mat3::from_cols_slice(rotation_matrix.data_typed::<f64>()?.iter().map(|&x| x as f32).collect::<Vec<_>>().as_slice()))
+}
 
-    let (greyscale, corners, ids, rejected_img_points, latest_rotation, latest_translation) = {
-        let data = tracking_data.as_mut();
-        (&mut data.greyscale_image, &mut data.corners, &mut data.ids, &mut data.rejected_img_points, &mut data.latest_rotation, &mut data.latest_translation)
+/// Converts an OpenCV board pose (the board's rotation and translation in camera space) into the camera's transform in the board's frame.
+/// The board frame is the same as Bevy's world frame, since the fiducial corners are given in world coordinates.
+fn camera_transform_from_pose(rotation: &Mat, translation: &Mat) -> opencv::Result<Transform> {
+    let translation = vec3_from_mat(translation)?;
+    // The inverse rotation takes camera space to the board frame
+    let rotation_inverse = inverse_rotation_from_vector(rotation)?;
+    // The camera's position in the board frame: -R^T * t
+    let camera_position = -(rotation_inverse * translation);
+
+    // OpenCV cameras look down +z with +y pointing down the image, while Bevy cameras look down -z with +y up,
+    // so flip the camera's local y and z axes
+    let camera_axes = rotation_inverse * Mat3::from_diagonal(Vec3::new(1.0, -1.0, -1.0));
+
+    Ok(Transform::from_translation(camera_position).with_rotation(Quat::from_mat3(&camera_axes).normalize()))
+}
+
+/// Converts a single marker's pose in camera space into its transform in the board's frame, using the board's pose.
+/// A marker that's placed where the layout says has the board's rotation and sits at its configured offset.
+fn marker_transform_from_pose(board_pose: (&Mat, &Mat), marker_pose: (&Mat, &Mat)) -> opencv::Result<Transform> {
+    let board_inverse = inverse_rotation_from_vector(board_pose.0)?;
+    let marker_rotation = inverse_rotation_from_vector(marker_pose.0)?.transpose();
+    let position = board_inverse * (vec3_from_mat(marker_pose.1)? - vec3_from_mat(board_pose.1)?);
+
+    Ok(Transform::from_translation(position).with_rotation(Quat::from_mat3(&(board_inverse * marker_rotation)).normalize()))
+}
+
+/// Converts an OpenCV rotation vector into the rotation matrix it describes.
+fn rotation_from_vector(rotation: &Mat) -> opencv::Result<DMat3> {
+    let mut rotation_matrix = Mat::default();
+    calib3d::rodrigues_def(rotation, &mut rotation_matrix)?;
+    // OpenCV matrices are row-major, so reading the data as columns gives the transpose
+    Ok(DMat3::from_cols_slice(rotation_matrix.data_typed::<f64>()?).transpose())
+}
+
+/// Solves the board's pose from one marker's corners, given where the marker's center is in the board frame.
+fn solve_single_marker(center: DVec3, size: f64, corners: &Vector<Point2f>, method: PnpMethod, camera_matrix: &Mat, dist_coeffs: &Mat) -> opencv::Result<Option<(Mat, Mat)>> {
+    let mut rotation = Mat::default();
+    let mut translation = Mat::default();
+    if method != PnpMethod::IppeSquare {
+        let object_points: Vector<Point3d> = marker_corners(size).into_iter()
+            .map(|corner| Point3d::new(corner.x + center.x, corner.y + center.y, corner.z + center.z))
+            .collect();
+        let solved = calib3d::solve_pnp(&object_points, corners, camera_matrix, dist_coeffs, &mut rotation, &mut translation, false, method.flag())?;
+        return Ok(solved.then_some((rotation, translation)));
+    }
+
+    // IPPE_SQUARE needs the corners in the marker's own frame, in the order OpenCV detects them
+    let half_size = size / 2.0;
+    let square_points: Vector<Point3d> = [(-half_size, half_size), (half_size, half_size), (half_size, -half_size), (-half_size, -half_size)]
+        .into_iter()
+        .map(|(x, y)| Point3d::new(x, y, 0.0))
+        .collect();
+    if !calib3d::solve_pnp(&square_points, corners, camera_matrix, dist_coeffs, &mut rotation, &mut translation, false, method.flag())? {
+        return Ok(None);
+    }
+
+    // The solved pose takes the marker frame to camera space, so take the board frame to the marker frame first
+    let board_rotation = rotation_from_vector(&rotation)? * MARKER_TO_BOARD.transpose();
+    let board_translation = DVec3::from_slice(translation.data_typed::<f64>()?) - board_rotation * center;
+    let mut board_rotation_vector = Mat::default();
+    calib3d::rodrigues_def(&Mat::from_slice_2d(&board_rotation.transpose().to_cols_array_2d())?, &mut board_rotation_vector)?;
+    Ok(Some((board_rotation_vector, Mat::from_slice(&board_translation.to_array())?.try_clone()?)))
+}
+
+/// Solves the board's pose from the corners of several markers.
+fn solve_board(object_points: &Vector<Point3f>, image_points: &Vector<Point2f>, pnp: &PnpSettings, camera_matrix: &Mat, dist_coeffs: &Mat) -> opencv::Result<Option<(Mat, Mat)>> {
+    let method = match pnp.board_method {
+        PnpMethod::IppeSquare => PnpMethod::Ippe,
+        method => method
+    };
+    let mut rotation = Mat::default();
+    let mut translation = Mat::default();
+    let solved = if pnp.ransac {
+        let mut inliers: Vector<i32> = Vector::new();
+        calib3d::solve_pnp_ransac(
+            object_points,
+            image_points,
+            camera_matrix,
+            dist_coeffs,
+            &mut rotation,
+            &mut translation,
+            false,
+            pnp.ransac_iterations.max(1),
+            pnp.ransac_reprojection_error.max(0.1),
+            pnp.ransac_confidence.clamp(0.0, 1.0),
+            &mut inliers,
+            method.flag()
+        )?
+    } else {
+        calib3d::solve_pnp(object_points, image_points, camera_matrix, dist_coeffs, &mut rotation, &mut translation, false, method.flag())?
+    };
+    Ok(solved.then_some((rotation, translation)))
+}
+
+/// Solves each detected marker's pose from its own four corners, ignoring the rest of the layout.
+fn solve_marker_poses(layout: &FiducialLayout, camera_matrix: &Mat, dist_coeffs: &Mat, result: &mut DetectionResult) -> opencv::Result<()> {
+    let object_points: Vector<Point3d> = marker_corners(layout.size).into_iter().collect();
+    for (id, corners) in result.ids.iter().zip(result.corners.iter()) {
+        let mut rotation = Mat::default();
+        let mut translation = Mat::default();
+        if calib3d::solve_pnp_def(&object_points, &corners, camera_matrix, dist_coeffs, &mut rotation, &mut translation)? {
+            result.marker_poses.push((id, rotation, translation));
+        }
+    }
+    Ok(())
+}
+
+/// Moves each marker's corners to the nearest subpixel corner in the full-resolution greyscale image.
+fn refine_corners(greyscale_image: &Mat, markers: &mut Vector<Vector<Point2f>>, window: i32) -> opencv::Result<()> {
+    let mut corners: Vector<Point2f> = markers.iter().flatten().collect();
+    let criteria = TermCriteria::new(TermCriteria_Type::COUNT as i32 | TermCriteria_Type::EPS as i32, 30, 0.01)?;
+    opencv::imgproc::corner_sub_pix(greyscale_image, &mut corners, Size::new(window, window), Size::new(-1, -1), criteria)?;
+    *markers = corners.to_vec().chunks(4).map(Vector::from_slice).collect();
+    Ok(())
+}
+
+/// The parts of a frame time-sliced detection scans: the area around each marker found last time, then one of `slices`
+/// vertical strips, which cycle so the whole frame is covered every `slices` detections.
+fn detection_regions(width: i32, height: i32, tracked: &Vector<Vector<Point2f>>, slice: usize, slices: usize) -> Vec<Rect> {
+    let mut regions = Vec::new();
+    for marker in tracked {
+        let (min_x, min_y, max_x, max_y) = marker.iter().fold((f32::MAX, f32::MAX, f32::MIN, f32::MIN), |(min_x, min_y, max_x, max_y), point| {
+            (min_x.min(point.x), min_y.min(point.y), max_x.max(point.x), max_y.max(point.y))
+        });
+        let margin = (max_x - min_x).max(max_y - min_y) * TRACKED_REGION_MARGIN;
+        let (left, top) = (((min_x - margin) as i32).max(0), ((min_y - margin) as i32).max(0));
+        let (right, bottom) = (((max_x + margin) as i32).min(width), ((max_y + margin) as i32).min(height));
+        if right > left && bottom > top {
+            regions.push(Rect::new(left, top, right - left, bottom - top));
+        }
+    }
+
+    let slices = slices.max(1);
+    let index = (slice % slices) as i32;
+    let overlap = (width as f32 * SLICE_OVERLAP) as i32;
+    let left = (index * width / slices as i32 - overlap).max(0);
+    let right = ((index + 1) * width / slices as i32 + overlap).min(width);
+    regions.push(Rect::new(left, 0, right - left, height));
+    regions
+}
+
+/// Detects markers in parts of the image, with the corners offset back to whole-image coordinates.
+/// A marker found in more than one region is kept from the first.
+fn detect_in_regions(
+    detector: &ArucoDetector,
+    image: &Mat,
+    regions: &[Rect],
+    scale: f64,
+    (found_corners, found_ids, found_rejected): (&mut Vector<Vector<Point2f>>, &mut Vector<i32>, &mut Vector<Vector<Point2f>>)
+) -> opencv::Result<()> {
+    found_corners.clear();
+    found_ids.clear();
+    found_rejected.clear();
+
+    let mut ids: Vector<i32> = Vector::new();
+    let mut corners: Vector<Vector<Point2f>> = Vector::new();
+    let mut rejected: Vector<Vector<Point2f>> = Vector::new();
+    for region in regions {
+        // The regions are in full-resolution pixels, and the image may be downscaled
+        let left = ((region.x as f64 * scale) as i32).clamp(0, image.cols());
+        let top = ((region.y as f64 * scale) as i32).clamp(0, image.rows());
+        let right = (((region.x + region.width) as f64 * scale) as i32).clamp(left, image.cols());
+        let bottom = (((region.y + region.height) as f64 * scale) as i32).clamp(top, image.rows());
+        if right - left < 2 || bottom - top < 2 {
+            continue;
+        }
+
+        let roi: BoxedRef<Mat> = Mat::roi(image, Rect::new(left, top, right - left, bottom - top))?;
+        detector.detect_markers(&roi, &mut corners, &mut ids, &mut rejected)?;
+        let offset = |marker: Vector<Point2f>| -> Vector<Point2f> {
+            marker.iter().map(|point| Point2f::new(point.x + left as f32, point.y + top as f32)).collect()
+        };
+        for (id, marker) in ids.iter().zip(corners.iter()) {
+            if !found_ids.iter().any(|found| found == id) {
+                found_ids.push(id);
+                found_corners.push(offset(marker));
+            }
+        }
+        for marker in rejected.iter() {
+            found_rejected.push(offset(marker));
+        }
+    }
+    Ok(())
+}
+
+/// Detects markers and solves the camera pose. Runs on the detection thread.
+fn detect_and_solve(LayoutDetector { detector, board, .. }: &LayoutDetector, request: &mut DetectionRequest) -> opencv::Result<()> {
+    let DetectionRequest { result, layout, scale, marker_axes, refinement_window, regions, pnp, camera_matrix, dist_coeffs } = request;
+    let scale = *scale;
+    let started = Instant::now();
+
+    // Detecting at full resolution is the main bottleneck, so detect on a downscaled copy
+    let detection_image = if scale < 1.0 {
+        opencv::imgproc::resize(&result.greyscale_image, &mut result.downscaled_image, Size::new(0, 0), scale, scale, opencv::imgproc::INTER_AREA)?;
+        &result.downscaled_image
+    } else {
+        &result.greyscale_image
+    };
+
+    // Detect ArUco markers in the greyscale frame
+    {
+        profile_scope!("detect");
+        match regions {
+            Some(regions) => detect_in_regions(detector, detection_image, regions, scale, (&mut result.corners, &mut result.ids, &mut result.rejected_img_points))?,
+            None => detector.detect_markers(detection_image, &mut result.corners, &mut result.ids, &mut result.rejected_img_points)?
+        }
+    }
+
+    if scale < 1.0 {
+        let rescale = |markers: &Vector<Vector<Point2f>>| -> Vector<Vector<Point2f>> {
+            markers.iter()
+                .map(|marker| marker.iter().map(|point| Point2f::new(point.x / scale as f32, point.y / scale as f32)).collect())
+                .collect()
+        };
+        result.corners = rescale(&result.corners);
+        result.rejected_img_points = rescale(&result.rejected_img_points);
+    }
+    result.times.detect = started.elapsed();
+
+    if result.ids.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(window) = *refinement_window {
+        profile_scope!("refine corners");
+        let started = Instant::now();
+        refine_corners(&result.greyscale_image, &mut result.corners, window)?;
+        result.times.refine = started.elapsed();
+    }
+
+    profile_scope!("pnp");
+    let started = Instant::now();
+    let solved = solve_detected_pose(board, layout, *marker_axes, pnp, camera_matrix, dist_coeffs, result);
+    result.times.pnp = started.elapsed();
+    solved
+}
+
+/// Solves the camera pose from the detected markers, and each marker's own pose if `marker_axes` is set.
+fn solve_detected_pose(
+    board: &Board,
+    layout: &FiducialLayout,
+    marker_axes: bool,
+    pnp: &PnpSettings,
+    camera_matrix: &Mat,
+    dist_coeffs: &Mat,
+    result: &mut DetectionResult
+) -> opencv::Result<()> {
+    if marker_axes {
+        solve_marker_poses(layout, camera_matrix, dist_coeffs, result)?;
+    }
+
+    // The board pairs each detected corner with its place in the layout, skipping markers the layout doesn't have
+    let mut object_points: Vector<Point3f> = Vector::new();
+    let mut image_points: Vector<Point2f> = Vector::new();
+    board.match_image_points(&result.corners, &result.ids, &mut object_points, &mut image_points)?;
+    if object_points.is_empty() {
+        return Ok(());
+    }
+
+    // Use SolvePnP to determine the pose of the camera relative to the known markers
+    let pose = if object_points.len() == 4 {
+        let center = object_points.iter().map(|point| DVec3::new(point.x as f64, point.y as f64, point.z as f64)).sum::<DVec3>() / 4.0;
+        solve_single_marker(center, layout.size, &image_points, pnp.single_marker_method, camera_matrix, dist_coeffs)?
+    } else {
+        solve_board(&object_points, &image_points, pnp, camera_matrix, dist_coeffs)?
+    };
+    let Some((rotation, translation)) = pose else {
+        result.errors.push("Failed to solve PnP for ArUco markers".to_string());
+        return Ok(());
     };
 
+    let mut projected: Vector<Point2f> = Vector::new();
+    calib3d::project_points_def(&object_points, &rotation, &translation, camera_matrix, dist_coeffs, &mut projected)?;
+    result.reprojection_error = ReprojectionError::measure(&projected, &image_points);
+
+    result.pose = Some((rotation, translation));
+    Ok(())
+}
+
+/// What detecting one frame with a `FrameDetector` found, and how long it took.
+pub struct FrameDetection {
+    pub markers: usize,
+    pub solved: bool,
+    pub reprojection_error: Option<ReprojectionError>,
+    pub times: DetectionTimes,
+    /// Each detected marker's id and corners in pixels, clockwise from the top left.
+    pub detected: Vec<(i32, [[f32; 2]; 4])>,
+    /// The fiducial frame's pose as OpenCV's (rotation vector, translation vector in mm) in camera space, if solved.
+    pub pose: Option<([f64; 3], [f64; 3])>,
+    /// The camera's transform in the fiducial frame, the way the app places its 3D camera, if solved.
+    pub camera: Option<Transform>
+}
+
+/// Detects markers and solves the pose on the calling thread, one frame at a time, with the same steps as the
+/// detection thread. For measuring detection outside the app, like in benchmarks.
+pub struct FrameDetector {
+    detector: Option<LayoutDetector>,
+    downscaled_image: Mat
+}
+
+impl FrameDetector {
+    pub fn new(layout: &FiducialLayout) -> opencv::Result<Self> {
+        Ok(Self { detector: Some(LayoutDetector::new(layout)?), downscaled_image: Mat::default() })
+    }
+
+    /// Detects markers in a greyscale frame. Time slicing isn't used, so every frame is scanned in full.
+    pub fn detect(&mut self, greyscale_image: Mat, layout: &FiducialLayout, settings: &DetectionSettings, intrinsics: &CameraIntrinsics) -> opencv::Result<FrameDetection> {
+        let detector = match self.detector.take() {
+            Some(detector) => detector.update(layout)?,
+            None => LayoutDetector::new(layout)?
+        };
+        let detector = self.detector.insert(detector);
+        let result = DetectionResult::new(greyscale_image, std::mem::take(&mut self.downscaled_image));
+        let mut request = DetectionRequest::new(result, layout.clone(), settings, None, intrinsics)?;
+        detect_and_solve(detector, &mut request)?;
+
+        let result = request.result;
+        let detected = result.ids.iter().zip(result.corners.iter())
+            .map(|(id, corners)| {
+                let mut points = [[0.0; 2]; 4];
+                for (point, corner) in points.iter_mut().zip(corners.iter()) {
+                    *point = [corner.x, corner.y];
+                }
+                (id, points)
+            })
+            .collect();
+        let pose = match &result.pose {
+            Some((rotation, translation)) => {
+                let vector = |mat: &Mat| -> opencv::Result<[f64; 3]> {
+                    let values = mat.data_typed::<f64>()?;
+                    Ok([values[0], values[1], values[2]])
+                };
+                Some(((vector(rotation)?, vector(translation)?), camera_transform_from_pose(rotation, translation)?))
+            }
+            None => None
+        };
+        self.downscaled_image = result.downscaled_image;
+        Ok(FrameDetection {
+            markers: result.ids.len(),
+            solved: result.pose.is_some(),
+            reprojection_error: result.reprojection_error,
+            times: result.times,
+            detected,
+            pose: pose.map(|(pose, _)| pose),
+            camera: pose.map(|(_, camera)| camera)
+        })
+    }
+}
+
+/// Starts detection on the latest frame if the previous detection has finished.
+#[allow(clippy::too_many_arguments)]
+pub fn start_marker_detection(
+    fiducial_layout: Res<FiducialLayout>,
+    detection_settings: Res<DetectionSettings>,
+    webcam_frame: Res<WebcamFrame>,
+    mut tracking_data: ResMut<ArucoTrackingData>,
+    camera_intrinsics: Option<Res<CameraIntrinsics>>,
+    mut detection_worker: ResMut<DetectionWorker>,
+    mut gpu_candidates: ResMut<GpuCandidates>,
+    prefilter_settings: Res<GpuPrefilterSettings>,
+    mut diagnostics: Diagnostics,
+    mut errors: EventWriter<AppError>
+) {
+    let frame = &webcam_frame.0;
+
+    // Without a calibration there's no way to solve the pose; the error was reported at startup
+    let Some(camera_intrinsics) = camera_intrinsics else { return };
+    if detection_worker.busy {
+        return;
+    }
+
+    // Start the average over, so it reflects only the new settings
+    if detection_settings.is_changed() {
+        tracking_data.recent_reprojection_errors.clear();
+    }
+
+    tracking_data.frame_counter = tracking_data.frame_counter.wrapping_add(1);
+    if tracking_data.frame_counter % detection_settings.interval.max(1) != 0 {
+        return;
+    }
+
+    // Capture reports missing frames itself
     if frame.empty() {
-        eprintln!("No frame captured from webcam");
         return;
     }
 
     // Convert the frame to greyscale
-    opencv::imgproc::cvt_color(frame, greyscale, opencv::imgproc::COLOR_BGR2GRAY, 0, AlgorithmHint::ALGO_HINT_DEFAULT).expect("Failed to convert frame to greyscale");
+    let started = Instant::now();
+    if let Err(err) = opencv::imgproc::cvt_color(frame, &mut tracking_data.greyscale_image, opencv::imgproc::COLOR_BGR2GRAY, 0, AlgorithmHint::ALGO_HINT_DEFAULT) {
+        errors.write(AppError::new(ErrorSource::Detection, format!("Failed to convert the frame to greyscale: {}", err)));
+        return;
+    }
+    PipelineStage::Convert.record(&mut diagnostics, started.elapsed());
 
-    // Detect ArUco markers in the greyscale frame
-    fiducial_detector.0.lock()
-        .expect("Failed to lock fiducial detector mutex")
-        .detect_markers(greyscale, corners, ids, rejected_img_points)
-        .expect("Failed to detect ArUco markers");
+    // Move the buffers to the detection thread; they come back with the result
+    let result = DetectionResult::new(std::mem::take(&mut tracking_data.greyscale_image), std::mem::take(&mut tracking_data.downscaled_image));
+
+    // The GPU prefilter's crops already bound the work, so time slicing only applies without them
+    let regions = if let Some(candidates) = gpu_candidates.next_regions(&prefilter_settings, !tracking_data.corners.is_empty()) {
+        Some(candidates)
+    } else if detection_settings.time_slicing && frame.cols() >= detection_settings.slice_min_width {
+        let slice = tracking_data.next_slice;
+        tracking_data.next_slice = slice.wrapping_add(1);
+        Some(detection_regions(frame.cols(), frame.rows(), &tracking_data.corners, slice, detection_settings.slices))
+    } else {
+        None
+    };
+    let request = match DetectionRequest::new(result, fiducial_layout.clone(), &detection_settings, regions, &camera_intrinsics) {
+        Ok(request) => request,
+        Err(err) => {
+            errors.write(AppError::new(ErrorSource::Detection, format!("Failed to copy the camera calibration: {}", err)));
+            return;
+        }
+    };
 
-    if ids.len() == 0 {
-        eprintln!("No ArUco markers detected");
+    if detection_worker.requests.send(request).is_err() {
+        errors.write(AppError::new(ErrorSource::Detection, "The marker detection thread stopped"));
         return;
     }
+    detection_worker.busy = true;
+}
 
-    let flat_corners: Vector<Point2f> = corners.iter().flatten().collect();
+/// Collects finished detections and publishes the new camera pose.
+#[allow(clippy::too_many_arguments)]
+pub fn finish_marker_detection(
+    time: Res<Time>,
+    fiducial_layout: Res<FiducialLayout>,
+    mut detection_worker: ResMut<DetectionWorker>,
+    mut tracking_data: ResMut<ArucoTrackingData>,
+    mut webcam_frame: ResMut<WebcamFrame>,
+    mut errors: EventWriter<AppError>,
+    mut pose_events: EventWriter<CameraPoseUpdated>,
+    mut diagnostics: Diagnostics,
 
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+) {
+    let result = match detection_worker.results.try_recv() {
+        Ok(result) => result,
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => {
+            // The thread panicked; clear the flag so the next request reports it
+            detection_worker.busy = false;
+            return;
+        }
+    };
+    detection_worker.busy = false;
+
+    PipelineStage::Detect.record(&mut diagnostics, result.times.detect + result.times.refine);
+    PipelineStage::Pnp.record(&mut diagnostics, result.times.pnp);
+
+    let data = tracking_data.as_mut();
+    data.greyscale_image = result.greyscale_image;
+    data.downscaled_image = result.downscaled_image;
+    data.ids = result.ids;
+    data.corners = result.corners;
+    data.rejected_img_points = result.rejected_img_points;
+
+    for error in result.errors {
+        errors.write(AppError::new(ErrorSource::Detection, error));
+    }
     // objdetect::draw_detected_markers(frame, corners, ids, Scalar::new(0.0, 255.0, 0.0, 255.0))
     //     .expect("Failed to draw detected markers on frame");
     if DEBUG_POINTS {
         // Manually highlight the fiducial corners on the frame with a circle
-        for (i, point) in flat_corners.iter().enumerate() {
+        let frame = &mut webcam_frame.0;
+        for (i, point) in data.corners.iter().flatten().enumerate() {
             let color = TEST_COLORS.get(i % TEST_COLORS.len()).unwrap_or(&[1.0, 1.0, 1.0]);
-            opencv::imgproc::circle(
+            let _ = opencv::imgproc::circle(
                 frame,
                 Point2i::new(point.x as i32, point.y as i32),
                 10,
@@ -173,24 +1222,11 @@ fn track_aruco_targets(
                 -1,
                 opencv::imgproc::LINE_AA,
                 0
-            ).expect("Failed to draw circle on frame");
+            );
         }
-    }
 
-    // Generate only the fiducial corners for the found fiducials
-    let fiducial_corners: Vector<Point3d> = ids.iter()
-        .filter_map(|id| {
-            // It's not a big deal that this is O(n^2) since there are only a few fiducials
-            FIDUCIAL_POSITIONS.iter().find(|fiducial| fiducial.id == id)
-                .map(|fiducial| fiducial.get_corners())
-        })
-        .flatten()
-        .map(|point| Point3d::new(point.x, point.y, point.z))
-        .collect();
-
-    if DEBUG_POINTS {
         // Draw the fiducial corners in the world for debugging
-        for (i, corner) in fiducial_corners.iter().enumerate() {
+        for (i, corner) in fiducial_layout.object_points(&data.ids).iter().enumerate() {
             let position = Vec3::new(corner.x as f32, corner.y as f32, corner.z as f32);
             // Spawn a small sphere at the fiducial corner position
             let color = TEST_COLORS.get(i % TEST_COLORS.len()).unwrap_or(&[1.0, 1.0, 1.0]);
@@ -202,53 +1238,84 @@ fn track_aruco_targets(
                     ..Default::default()
                 })),
                 Transform::from_translation(position),
+                RenderLayers::layer(DEBUG_LAYER),
                 crate::testing::DeleteAfterOneFrame
             ));
         }
     }
 
-    if fiducial_corners.len() != flat_corners.len() {
-        eprintln!("Number of fiducial corners ({}) does not match number of detected corners ({})", fiducial_corners.len(), corners.len());
-        return;
+    let Some((rotation, translation)) = result.pose else { return };
+
+    data.marker_poses.clear();
+    for (id, marker_rotation, marker_translation) in &result.marker_poses {
+        match marker_transform_from_pose((&rotation, &translation), (marker_rotation, marker_translation)) {
+            Ok(transform) => data.marker_poses.push((*id, transform)),
+            Err(err) => {
+                errors.write(AppError::new(ErrorSource::Detection, format!("Failed to convert marker {}'s pose: {}", id, err)));
+            }
+        }
     }
 
-    // Use SolvePnP to determine the pose of the camera relative to the known markers
-    if !calib3d::solve_pnp_ransac_def(
-        &fiducial_corners,
-        &flat_corners,
-        &camera_intrinsics.camera_matrix,
-        &camera_intrinsics.dist_coeffs,
-        latest_rotation,
-        latest_translation
-    ).expect("Failed to solve PnP for ArUco markers") {
-        eprintln!("Failed to solve PnP for ArUco markers");
+    match camera_transform_from_pose(&rotation, &translation) {
+        Ok(transform) => {
+            pose_events.write(CameraPoseUpdated { transform });
+        }
+        Err(err) => {
+            errors.write(AppError::new(ErrorSource::Detection, format!("Failed to convert the solved pose: {}", err)));
+        }
+    }
+    data.latest_rotation = rotation;
+    data.latest_translation = translation;
+    data.last_pose_time = Some(time.elapsed_secs_f64());
+    data.reprojection_error = result.reprojection_error;
+    if let Some(error) = result.reprojection_error {
+        if data.recent_reprojection_errors.len() >= REPROJECTION_ERROR_HISTORY {
+            data.recent_reprojection_errors.pop_front();
+        }
+        data.recent_reprojection_errors.push_back(error.rms);
+    }
+}
+
+/// Draws each marker's own solved axes next to the axes where the layout places it, joined by a line
+/// so a misplaced marker stands out. The expected axes are shorter so the two can be told apart.
+fn draw_marker_axes(
+    detection_settings: Res<DetectionSettings>,
+    fiducial_layout: Res<FiducialLayout>,
+    tracking_data: Res<ArucoTrackingData>,
+    mut gizmos: Gizmos
+) {
+    if !detection_settings.marker_axes {
         return;
     }
-    
-    // Update the camera transform based on the latest rotation and translation
-    for (_camera, mut transform) in camera_query.iter_mut() {
-        let translation = Vec3::from_slice(latest_translation.data_typed::<f64>().expect("Failed to get translation data").iter().map(|&x| x as f32).collect::<Vec<_>>().as_slice());
 
-        let mut rotation_matrix = Mat::default();
-        calib3d::rodrigues_def(latest_rotation, &mut rotation_matrix).expect("Failed to convert rotation vector to rotation matrix");
+    let size = fiducial_layout.size as f32;
+    for fiducial in &fiducial_layout.fiducials {
+        let expected = Transform::from_translation(fiducial.center());
+        gizmos.axes(expected, size * 0.5);
 
-        let rotation_matrix: Mat3 = Mat3::from_cols_slice(rotation_matrix.data_typed::<f64>().expect("Failed to get rotation matrix data").iter().map(|&x| x as f32).collect::<Vec<_>>().as_slice());
+        let Some(detected) = tracking_data.marker_pose(fiducial.id) else { continue };
+        gizmos.axes(detected, size);
+        gizmos.line(expected.translation, detected.translation, YELLOW);
+    }
+}
 
-        // Update the camera transform based on the inverse of the rotation and translation
-        let rotation_matrix_inverse = rotation_matrix.transpose();
-        let rotation_inverse = Quat::from_mat3(&rotation_matrix_inverse);
-        // Invert translation: -R.T * tvec
-        let translation_inverse = -rotation_matrix_inverse * translation;
-        
-        // Convert OpenCV's coordinate system to Bevy's
-        let translation_inverse = Vec3::new(translation_inverse.x, -translation_inverse.y, translation_inverse.z);
+/// Moves the cameras to the latest solved pose, smoothed. Shot cameras are placed relative to the keyboard instead.
+fn apply_camera_pose(
+    time: Res<Time>,
+    tracking_settings: Res<TrackingSettings>,
+    mut pose_filter: ResMut<PoseFilter>,
+    mut pose_events: EventReader<CameraPoseUpdated>,
+    mut camera_query: Query<(
+        &mut Camera3d,
+        &mut Transform
+    ), Without<ShotCamera>>
+) {
+    let Some(pose) = pose_events.read().last() else { return };
+    let smoothed = pose_filter.update(time.elapsed_secs_f64(), pose.transform, &tracking_settings);
 
+    for (_camera, mut transform) in camera_query.iter_mut() {
         if !DEBUG_POINTS {
-            transform.translation = translation_inverse;
-            transform.rotation = rotation_inverse;
-   
-            // Temporary: make the camera look at the origin
-            transform.look_at(Vec3::ZERO, Vec3::Y);
+            *transform = smoothed;
         }
 
         println!("Camera transform updated: translation = {:?}, rotation = {:?}", transform.translation, transform.rotation);
@@ -268,38 +1335,179 @@ struct CalibrationData {
     calibration_time: String
 }
 
+/// The calibration being loaded on another thread. Tracking starts once it's inserted as `CameraIntrinsics`.
+#[derive(Resource)]
+struct PendingCalibration(Receiver<Result<CameraIntrinsics, String>>);
+
+fn start_loading_calibration(app: &mut App) {
+    let calibration_file = app.world_mut().get_resource_or_init::<CalibrationFile>().clone();
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let spawned = thread::Builder::new()
+        .name("calibration loading".to_string())
+        .spawn(move || {
+            profile_scope!("load calibration");
+            let _ = sender.send(CameraIntrinsics::load(&calibration_file.0).map_err(|err| format!("Failed to load {}: {}", calibration_file.0, err)));
+        });
+    match spawned {
+        Ok(_) => {
+            app.world_mut().get_resource_or_init::<StartupProgress>().begin(StartupTask::Calibration);
+            app.insert_resource(PendingCalibration(receiver));
+        }
+        Err(err) => status::report_startup_error(app, AppError::new(
+            ErrorSource::Calibration,
+            format!("Failed to start loading the calibration: {}. Tracking is disabled", err)
+        ))
+    }
+}
+
+fn receive_calibration(
+    mut commands: Commands,
+    pending: Option<Res<PendingCalibration>>,
+    mut progress: ResMut<StartupProgress>,
+    mut errors: EventWriter<AppError>
+) {
+    let Some(pending) = pending else { return };
+    let loaded = match pending.0.try_recv() {
+        Ok(loaded) => loaded,
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => Err("The calibration loading thread stopped without loading it".to_string())
+    };
+    commands.remove_resource::<PendingCalibration>();
+    progress.finish(StartupTask::Calibration);
+    match loaded {
+        Ok(camera_intrinsics) => commands.insert_resource(camera_intrinsics),
+        // Keep running without tracking so the camera feed and MIDI visuals still work
+        Err(err) => {
+            errors.write(AppError::new(ErrorSource::Calibration, format!("{}. Tracking is disabled", err)));
+        }
+    }
+}
+
+fn receive_detector_ready(detection_worker: Res<DetectionWorker>, mut progress: ResMut<StartupProgress>) {
+    if progress.is_running(StartupTask::Detector) && detection_worker.ready.try_recv().is_ok() {
+        progress.finish(StartupTask::Detector);
+    }
+}
+
 impl Plugin for ArUcoCameraPlugin {
     fn build(&self, app: &mut App) {
-        // Load assets/calibration.json
-        let file_data = fs::read_to_string("assets/calibration.json")
-            .expect("Failed to read calibration file");
-        let calibration_data: CalibrationData = serde_json::from_str(&file_data)
-            .expect("Failed to parse calibration data");
-
-        // Create the camera intrinsics from the calibration data
-        let camera_matrix = Mat::from_slice_2d(&calibration_data.camera_matrix)
-            .expect("Failed to create camera matrix from calibration data");
-        let dist_coeffs = Mat::from_slice(&calibration_data.distortion_coefficients)
-            .expect("Failed to create distortion coefficients from calibration data")
-            .try_clone()
-            .expect("Failed to clone distortion coefficients");
-
-        let camera_intrinsics = CameraIntrinsics {
-            camera_matrix,
-            dist_coeffs
-        };
-        
+        start_loading_calibration(app);
+
+        let layout = app.world_mut().get_resource_or_init::<FiducialLayout>().clone();
+        app.world_mut().get_resource_or_init::<StartupProgress>().begin(StartupTask::Detector);
+
         app
-            .insert_resource(FiducialDetector(Mutex::new(
-                ArucoDetector::new(
-                    &objdetect::get_predefined_dictionary(objdetect::PredefinedDictionaryType::DICT_APRILTAG_25h9).expect("Failed to get predefined dictionary"),
-                    &objdetect::DetectorParameters::default().expect("Failed to create detector parameters"),
-                    RefineParameters::new(10.0, 3.0, true).expect("Failed to create refine parameters")
-                ).expect("Failed to create ArUco detector")
-            )))
-            .insert_resource(camera_intrinsics)
+            .insert_resource(DetectionWorker::spawn(layout))
+            .init_resource::<DetectionSettings>()
             .insert_resource(ArucoTrackingData::default())
+            .add_event::<CameraPoseUpdated>()
+            .add_plugins((super::tracking::TrackingPlugin, super::layout_tuning::LayoutTuningPlugin, gpu_prefilter::GpuPrefilterPlugin))
+            .init_resource::<FiducialLayout>()
             .add_systems(Startup, setup)
-            .add_systems(Update, track_aruco_targets.in_set(VideoUpdateSystems));
+            .add_systems(Update, (spawn_fiducial_planes, draw_marker_axes, receive_calibration, receive_detector_ready))
+            .add_systems(Update, (finish_marker_detection, start_marker_detection, apply_camera_pose).chain().in_set(VideoUpdateSystems));
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the OpenCV board pose (rotation vector, translation vector) seen by a Bevy camera with the given transform.
+    fn board_pose_for_camera(camera: &Transform) -> (Mat, Mat) {
+        // The board-to-camera rotation is the inverse of the camera's axes, with OpenCV's y and z flipped
+        let camera_axes = Mat3::from_quat(camera.rotation) * Mat3::from_diagonal(Vec3::new(1.0, -1.0, -1.0));
+        let board_to_camera = camera_axes.transpose();
+        let board_translation = -(board_to_camera * camera.translation);
+
+        let rows: Vec<Vec<f64>> = (0..3).map(|i| board_to_camera.row(i).to_array().iter().map(|&x| x as f64).collect()).collect();
+        let rotation_matrix = Mat::from_slice_2d(&rows).unwrap();
+        let mut rotation = Mat::default();
+        calib3d::rodrigues_def(&rotation_matrix, &mut rotation).unwrap();
+        let translation = Mat::from_slice(&board_translation.to_array().map(|x| x as f64)).unwrap().try_clone().unwrap();
+        (rotation, translation)
+    }
+
+    fn test_camera_matrix() -> Mat {
+        Mat::from_slice_2d(&[
+            [900.0, 0.0, 640.0],
+            [0.0, 900.0, 360.0],
+            [0.0, 0.0, 1.0]
+        ]).unwrap()
+    }
+
+    /// Projects the fiducial corners through a known camera, solves the pose from the projections,
+    /// and checks that the solved pose converts back to the same Bevy camera transform.
+    #[test]
+    fn solved_pose_matches_synthetic_camera() {
+        let expected = Transform::from_xyz(120.0, 450.0, 650.0).looking_at(Vec3::new(0.0, 0.0, 100.0), Vec3::Y);
+        let (rotation, translation) = board_pose_for_camera(&expected);
+        let camera_matrix = test_camera_matrix();
+        let dist_coeffs = Mat::default();
+
+        let layout = FiducialLayout::default();
+        let ids: Vector<i32> = layout.fiducials.iter().map(|fiducial| fiducial.id).collect();
+        let object_points = layout.object_points(&ids);
+        let mut image_points: Vector<Point2f> = Vector::new();
+        calib3d::project_points_def(&object_points, &rotation, &translation, &camera_matrix, &dist_coeffs, &mut image_points).unwrap();
+
+        let mut solved_rotation = Mat::default();
+        let mut solved_translation = Mat::default();
+        assert!(calib3d::solve_pnp_def(&object_points, &image_points, &camera_matrix, &dist_coeffs, &mut solved_rotation, &mut solved_translation).unwrap());
+
+        let solved = camera_transform_from_pose(&solved_rotation, &solved_translation).unwrap();
+        assert!(solved.translation.distance(expected.translation) < 1.0, "expected {:?}, got {:?}", expected.translation, solved.translation);
+        assert!(solved.rotation.angle_between(expected.rotation) < 0.01, "expected {:?}, got {:?}", expected.rotation, solved.rotation);
+    }
+
+    /// Solves one marker on its own from a synthetic view and checks that it lands where the layout places it.
+    #[test]
+    fn marker_pose_matches_layout() {
+        let camera = Transform::from_xyz(-80.0, 500.0, 600.0).looking_at(Vec3::ZERO, Vec3::Y);
+        let (rotation, translation) = board_pose_for_camera(&camera);
+        let camera_matrix = test_camera_matrix();
+        let dist_coeffs = Mat::default();
+
+        let layout = FiducialLayout::default();
+        let fiducial = &layout.fiducials[1];
+        let object_points: Vector<Point3d> = fiducial.get_corners(layout.size).into_iter().collect();
+        let mut image_points: Vector<Point2f> = Vector::new();
+        calib3d::project_points_def(&object_points, &rotation, &translation, &camera_matrix, &dist_coeffs, &mut image_points).unwrap();
+
+        let mut result = DetectionResult {
+            ids: Vector::from_slice(&[fiducial.id]),
+            corners: Vector::from_iter([image_points]),
+            ..DetectionResult::new(Mat::default(), Mat::default())
+        };
+        solve_marker_poses(&layout, &camera_matrix, &dist_coeffs, &mut result).unwrap();
+        let (id, marker_rotation, marker_translation) = &result.marker_poses[0];
+        assert_eq!(*id, fiducial.id);
+
+        let marker = marker_transform_from_pose((&rotation, &translation), (marker_rotation, marker_translation)).unwrap();
+        let expected = fiducial.center();
+        assert!(marker.translation.distance(expected) < 1.0, "expected {:?}, got {:?}", expected, marker.translation);
+        assert!(marker.rotation.angle_between(Quat::IDENTITY) < 0.01, "expected no rotation, got {:?}", marker.rotation);
+    }
+
+    #[test]
+    fn reprojection_error_is_rms_of_corner_distances() {
+        let detected: Vector<Point2f> = Vector::from_slice(&[Point2f::new(0.0, 0.0), Point2f::new(10.0, 10.0)]);
+        let projected: Vector<Point2f> = Vector::from_slice(&[Point2f::new(3.0, 4.0), Point2f::new(10.0, 10.0)]);
+
+        let error = ReprojectionError::measure(&projected, &detected).unwrap();
+        assert!((error.rms - 12.5f64.sqrt()).abs() < 1e-9, "got {}", error.rms);
+        assert_eq!(error.max, 5.0);
+        assert_eq!(error.corners, 2);
+        assert!(ReprojectionError::measure(&Vector::new(), &Vector::new()).is_none());
+    }
+
+    #[test]
+    fn time_sliced_regions_cover_tracked_markers_and_cycle_strips() {
+        let marker: Vector<Point2f> = Vector::from_slice(&[Point2f::new(200.0, 200.0), Point2f::new(100.0, 200.0), Point2f::new(100.0, 100.0), Point2f::new(200.0, 100.0)]);
+        let tracked: Vector<Vector<Point2f>> = Vector::from_iter([marker]);
+
+        let first = detection_regions(3840, 2160, &tracked, 0, 2);
+        assert_eq!(first, vec![Rect::new(0, 0, 300, 300), Rect::new(0, 0, 2112, 2160)]);
+        let second = detection_regions(3840, 2160, &Vector::new(), 1, 2);
+        assert_eq!(second, vec![Rect::new(1728, 0, 2112, 2160)]);
+    }
+}
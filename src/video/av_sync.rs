@@ -0,0 +1,159 @@
+//! Audio/visual sync calibration. The camera image arrives later than MIDI, so the waterfall reaches a key before the
+//! key is seen going down. Calibration times how long after each note on the pressed key visibly changes in the
+//! camera image, and delays the waterfall by the median.
+//!
+//! Strike single keys sharply from just above them: a finger hovering over the key changes the image before the
+//! press and makes the delay look shorter than it is.
+
+use std::time::Duration;
+
+use bevy::{app::{App, Plugin, Update}, ecs::{event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Res, ResMut}}, time::Time};
+use opencv::core::{self, AlgorithmHint, Mat, MatTraitConst, Point, Scalar, Vector, CV_8UC1};
+use opencv::imgproc;
+
+use crate::{background::KeyboardProjection, midi::{bus::{NoteBus, NoteSource}, latency::LatencyCompensation, MidiEventKind}, status::{AppError, ErrorSource}, VideoUpdateSystems};
+
+use super::WebcamFrame;
+
+const TRIALS: usize = 8;
+/// How long to wait for a press to show up in the image before giving up on it.
+const ONSET_TIMEOUT: f64 = 1.0;
+/// The mean change in brightness (0-255) over the key that counts as it moving.
+const CHANGE_THRESHOLD: f64 = 12.0;
+
+struct PendingPress {
+    /// When the note on arrived, in seconds since startup.
+    time: f64,
+    /// The greyscale frame from just before the note on.
+    reference: Mat,
+    /// The key's outline in the image.
+    mask: Mat
+}
+
+#[derive(Resource, Default)]
+pub struct AvSyncCalibration {
+    active: bool,
+    pending: Option<PendingPress>,
+    /// The delay measured for each press in the current or last calibration.
+    pub trials: Vec<Duration>,
+    /// The latest frame in greyscale, kept as the reference for the next press.
+    greyscale: Mat,
+    /// The number of the latest frame checked, so each captured frame is compared once.
+    last_frame: u64,
+    difference: Mat
+}
+
+impl AvSyncCalibration {
+    pub fn is_running(&self) -> bool {
+        self.active
+    }
+
+    pub fn start(&mut self) {
+        self.active = true;
+        self.pending = None;
+        self.trials.clear();
+    }
+
+    pub fn cancel(&mut self) {
+        self.active = false;
+        self.pending = None;
+    }
+
+    pub fn median(&self) -> Option<Duration> {
+        let mut trials = self.trials.clone();
+        trials.sort();
+        trials.get(trials.len() / 2).copied()
+    }
+
+    /// Starts timing a press of the given key, if it can be seen.
+    fn begin_press(&mut self, projection: &KeyboardProjection, key: u8, time: f64) -> opencv::Result<()> {
+        if self.greyscale.empty() {
+            return Ok(());
+        }
+        let Some(region) = projection.key_region(key)? else { return Ok(()) };
+        let region: Vector<Point> = region.iter().map(|point| Point::new(point.x as i32, point.y as i32)).collect();
+        let mut mask = Mat::new_size_with_default(self.greyscale.size()?, CV_8UC1, Scalar::all(0.0))?;
+        imgproc::fill_convex_poly_def(&mut mask, &region, Scalar::all(255.0))?;
+        if core::count_non_zero(&mask)? == 0 {
+            // The key is out of view
+            return Ok(());
+        }
+
+        self.pending = Some(PendingPress { time, reference: self.greyscale.try_clone()?, mask });
+        Ok(())
+    }
+
+    /// Converts a new frame, and returns whether the pending press has shown up in it.
+    fn check_frame(&mut self, frame: &Mat) -> opencv::Result<bool> {
+        imgproc::cvt_color(frame, &mut self.greyscale, imgproc::COLOR_BGR2GRAY, 0, AlgorithmHint::ALGO_HINT_DEFAULT)?;
+
+        let Some(pending) = &self.pending else { return Ok(false) };
+        if pending.reference.size()? != self.greyscale.size()? {
+            self.pending = None;
+            return Ok(false);
+        }
+        core::absdiff(&pending.reference, &self.greyscale, &mut self.difference)?;
+        let change = core::mean(&self.difference, &pending.mask)?;
+        Ok(change[0] >= CHANGE_THRESHOLD)
+    }
+}
+
+fn measure_av_sync(
+    time: Res<Time>,
+    webcam_frame: Res<WebcamFrame>,
+    projection: KeyboardProjection,
+    mut calibration: ResMut<AvSyncCalibration>,
+    mut compensation: ResMut<LatencyCompensation>,
+    bus: Res<NoteBus>,
+    mut errors: EventWriter<AppError>
+) {
+    if !calibration.active {
+        return;
+    }
+    let now = time.elapsed_secs_f64();
+
+    // Presses while one is already being timed are ignored, since their changes would overlap
+    for event in bus.from_source(NoteSource::Live) {
+        let MidiEventKind::NoteOn { key, .. } = event.kind else { continue };
+        if calibration.pending.is_none() {
+            if let Err(err) = calibration.begin_press(&projection, key, now) {
+                errors.write(AppError::new(ErrorSource::Detection, format!("Failed to find the pressed key in the image: {}", err)));
+            }
+        }
+    }
+
+    if webcam_frame.1 != calibration.last_frame && !webcam_frame.0.empty() {
+        calibration.last_frame = webcam_frame.1;
+        match calibration.check_frame(&webcam_frame.0) {
+            Ok(true) => {
+                if let Some(pending) = calibration.pending.take() {
+                    calibration.trials.push(Duration::from_secs_f64(now - pending.time));
+                }
+            }
+            Ok(false) => {}
+            Err(err) => errors.write(AppError::new(ErrorSource::Detection, format!("Failed to compare camera frames: {}", err)))
+        }
+    }
+    if calibration.pending.as_ref().is_some_and(|pending| now - pending.time > ONSET_TIMEOUT) {
+        println!("A key press wasn't seen in the camera image; try striking the key more sharply");
+        calibration.pending = None;
+    }
+
+    if calibration.trials.len() >= TRIALS {
+        calibration.active = false;
+        if let Some(median) = calibration.median() {
+            println!("Measured video delay: {:.0} ms", median.as_secs_f64() * 1000.0);
+            compensation.video = median.as_secs_f64();
+        }
+    }
+}
+
+pub struct AvSyncPlugin;
+
+impl Plugin for AvSyncPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<AvSyncCalibration>()
+            .add_systems(Update, measure_av_sync.in_set(VideoUpdateSystems));
+    }
+}
@@ -0,0 +1,315 @@
+//! Opens the video source and reads frames on a dedicated thread, so a slow read never stalls the app.
+//! A dying network stream can hang inside OpenCV indefinitely, so the thread records when each open or read starts,
+//! and the app abandons the thread if one takes too long.
+//! Video files are paced to play back in real time, like a live camera would deliver them.
+//!
+//! Frames reach the app through a bounded `FrameQueue`. When the app falls behind, the queue either drops the oldest
+//! frames, so the app always gets the freshest one, or makes the capture thread wait, so a recording gets every frame.
+
+use std::{collections::VecDeque, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Condvar, Mutex}, thread, time::{Duration, Instant}};
+
+use crossbeam_channel::{Receiver, Sender};
+use opencv::{core::{Mat, MatTraitConst}, videoio::{self, VideoCaptureTrait}};
+
+use crate::diagnostics::profiling::profile_scope;
+
+use super::{CaptureMode, DropPolicy, FrameQueueSettings, VideoSource, VideoSourceConfig};
+
+/// The watchdog's limit for a single open or read. Longer than the timeouts passed to OpenCV,
+/// so backends that support those get the chance to fail cleanly first.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(8);
+/// Used for video files that don't report a frame rate.
+const DEFAULT_FILE_FPS: f64 = 30.0;
+/// How often a capture thread waiting for room in the queue checks whether it was abandoned.
+const ABANDON_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shared by every capture thread, so frame numbers never repeat across reconnects.
+static FRAME_NUMBER: AtomicU64 = AtomicU64::new(0);
+
+/// Numbers a new frame. Numbers start at 1, so 0 can stand for no frame yet.
+pub fn next_frame_number() -> u64 {
+    FRAME_NUMBER.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+pub enum CaptureMessage {
+    Opened(CaptureMode),
+    OpenFailed(String),
+    ReadFailed(String),
+    /// A video file that doesn't loop reached its end. The thread exits after sending this.
+    Ended
+}
+
+/// The frames waiting between a capture thread and the app.
+struct FrameQueue {
+    /// Each frame with its number from `next_frame_number`.
+    frames: Mutex<VecDeque<(u64, Mat)>>,
+    /// Signalled when the app takes frames, for a capture thread waiting on a full queue.
+    taken: Condvar,
+    capacity: AtomicUsize,
+    keep_all: AtomicBool,
+    /// Frames captured but never handed to the app.
+    dropped: AtomicU64,
+    /// Set when the app drops the worker, so a capture thread waiting for room gives up.
+    abandoned: AtomicBool
+}
+
+impl FrameQueue {
+    fn new(settings: &FrameQueueSettings) -> FrameQueue {
+        let queue = FrameQueue {
+            frames: Mutex::new(VecDeque::new()),
+            taken: Condvar::new(),
+            capacity: AtomicUsize::new(1),
+            keep_all: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            abandoned: AtomicBool::new(false)
+        };
+        queue.configure(settings.capacity, settings.policy);
+        queue
+    }
+
+    fn configure(&self, capacity: usize, policy: DropPolicy) {
+        self.capacity.store(capacity.max(1), Ordering::Relaxed);
+        self.keep_all.store(policy == DropPolicy::KeepAll, Ordering::Relaxed);
+        // A thread waiting for room may not need to anymore
+        self.taken.notify_all();
+    }
+
+    /// Adds a captured frame, dropping the oldest or waiting for room when the queue is full.
+    /// Returns false once the app has abandoned the thread.
+    fn push(&self, frame: Mat) -> bool {
+        let mut frames = self.frames.lock().expect("Failed to lock the frame queue");
+        while frames.len() >= self.capacity.load(Ordering::Relaxed) {
+            if self.abandoned.load(Ordering::Relaxed) {
+                return false;
+            }
+            if self.keep_all.load(Ordering::Relaxed) {
+                frames = self.taken.wait_timeout(frames, ABANDON_CHECK_INTERVAL).expect("Failed to lock the frame queue").0;
+            } else {
+                frames.pop_front();
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        frames.push_back((next_frame_number(), frame));
+        !self.abandoned.load(Ordering::Relaxed)
+    }
+
+    /// Takes the oldest frame when keeping every frame, or the newest one otherwise, dropping the rest.
+    fn pop(&self) -> Option<(u64, Mat)> {
+        let mut frames = self.frames.lock().expect("Failed to lock the frame queue");
+        let frame = if self.keep_all.load(Ordering::Relaxed) {
+            frames.pop_front()
+        } else {
+            let newest = frames.pop_back();
+            self.dropped.fetch_add(frames.len() as u64, Ordering::Relaxed);
+            frames.clear();
+            newest
+        };
+        self.taken.notify_all();
+        frame
+    }
+}
+
+/// A capture thread for one source. Dropping this abandons the thread; it exits as soon as its current read returns.
+pub struct CaptureWorker {
+    messages: Receiver<CaptureMessage>,
+    frames: Arc<FrameQueue>,
+    /// When the current open or read started, in milliseconds since `started` plus one, or zero when idle.
+    busy_since: Arc<AtomicU64>,
+    started: Instant
+}
+
+impl CaptureWorker {
+    pub fn spawn(source: VideoSource, config: VideoSourceConfig, queue: &FrameQueueSettings) -> CaptureWorker {
+        // Holding one message at a time paces failing reads to one per app update, like reading on the main thread did
+        let (sender, messages) = crossbeam_channel::bounded(1);
+        let frames = Arc::new(FrameQueue::new(queue));
+        let busy_since = Arc::new(AtomicU64::new(0));
+        let started = Instant::now();
+
+        let thread_frames = frames.clone();
+        let thread_busy_since = busy_since.clone();
+        thread::Builder::new()
+            .name("video capture".to_string())
+            .spawn(move || run_capture(source, config, sender, thread_frames, thread_busy_since, started))
+            .expect("Failed to spawn the video capture thread");
+
+        CaptureWorker {
+            messages,
+            frames,
+            busy_since,
+            started
+        }
+    }
+
+    /// Takes the next message about the source, like it opening or a read failing. Frames come from `try_recv_frame`.
+    pub fn try_recv(&self) -> Option<CaptureMessage> {
+        self.messages.try_recv().ok()
+    }
+
+    pub fn try_recv_frame(&self) -> Option<(u64, Mat)> {
+        self.frames.pop()
+    }
+
+    /// Changes the queue's size and drop policy without reopening the source.
+    pub fn configure_queue(&self, settings: &FrameQueueSettings) {
+        self.frames.configure(settings.capacity, settings.policy);
+    }
+
+    /// The frames captured but never handed to the app.
+    pub fn dropped_frames(&self) -> u64 {
+        self.frames.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn queued_frames(&self) -> usize {
+        self.frames.frames.lock().expect("Failed to lock the frame queue").len()
+    }
+
+    /// Whether the current open or read has taken longer than `STALL_TIMEOUT`.
+    pub fn is_stalled(&self) -> bool {
+        match self.busy_since.load(Ordering::Relaxed) {
+            0 => false,
+            since => self.started.elapsed().saturating_sub(Duration::from_millis(since - 1)) > STALL_TIMEOUT
+        }
+    }
+}
+
+impl Drop for CaptureWorker {
+    fn drop(&mut self) {
+        self.frames.abandoned.store(true, Ordering::Relaxed);
+        self.frames.taken.notify_all();
+    }
+}
+
+/// Schedules video file frames at the file's frame rate, measured from when playback (re)started.
+struct FramePacer {
+    frame_duration: Duration,
+    started: Instant,
+    /// Frames read since playback started.
+    frames: u32
+}
+
+impl FramePacer {
+    fn new(fps: f64) -> FramePacer {
+        let fps = if fps.is_finite() && fps > 0.0 { fps } else { DEFAULT_FILE_FPS };
+        FramePacer {
+            frame_duration: Duration::from_secs_f64(1.0 / fps),
+            started: Instant::now(),
+            frames: 0
+        }
+    }
+
+    fn restart(&mut self) {
+        self.started = Instant::now();
+        self.frames = 0;
+    }
+
+    fn next_frame_due(&self) -> Instant {
+        self.started + self.frame_duration * self.frames
+    }
+
+    /// How many whole frames the next frame is overdue by. These are skipped so playback stays in real time when the app falls behind.
+    fn frames_behind(&self) -> u32 {
+        (self.next_frame_due().elapsed().as_secs_f64() / self.frame_duration.as_secs_f64()) as u32
+    }
+
+    /// Sleeps until the frame just read is due.
+    fn wait(&mut self) {
+        let due = self.next_frame_due();
+        self.frames += 1;
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+}
+
+/// Reads the next frame, rewinding looping files when they end. Leaves the frame empty if nothing was captured.
+/// Returns whether the file was rewound.
+fn read_frame(cam: &mut videoio::VideoCapture, source: &VideoSource, frame: &mut Mat) -> opencv::Result<bool> {
+    profile_scope!("capture");
+    cam.read(frame)?;
+    if frame.empty() {
+        if let VideoSource::File { looping: true, .. } = *source {
+            // Rewind to the start of the file and try again
+            cam.set(videoio::CAP_PROP_POS_FRAMES, 0.0)?;
+            cam.read(frame)?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Skips overdue frames without decoding them.
+fn skip_frames(cam: &mut videoio::VideoCapture, pacer: &mut FramePacer) -> opencv::Result<()> {
+    for _ in 0..pacer.frames_behind() {
+        // Stops at the end of the file; the next read handles rewinding
+        if !cam.grab()? {
+            break;
+        }
+        pacer.frames += 1;
+    }
+    Ok(())
+}
+
+fn run_capture(source: VideoSource, config: VideoSourceConfig, sender: Sender<CaptureMessage>, frames: Arc<FrameQueue>, busy_since: Arc<AtomicU64>, started: Instant) {
+    let set_busy = |busy: bool| {
+        let since = if busy { started.elapsed().as_millis() as u64 + 1 } else { 0 };
+        busy_since.store(since, Ordering::Relaxed);
+    };
+
+    set_busy(true);
+    let opened = source.open(&config);
+    set_busy(false);
+    let (mut cam, mode) = match opened {
+        Ok(opened) => opened,
+        Err(err) => {
+            let _ = sender.send(CaptureMessage::OpenFailed(err.to_string()));
+            return;
+        }
+    };
+    let mut pacer = match source {
+        VideoSource::File { .. } => Some(FramePacer::new(mode.fps)),
+        VideoSource::Stream(_) => None
+    };
+    if sender.send(CaptureMessage::Opened(mode)).is_err() {
+        return;
+    }
+
+    loop {
+        let mut frame = Mat::default();
+        set_busy(true);
+        let result = match pacer.as_mut() {
+            Some(pacer) => skip_frames(&mut cam, pacer).and_then(|()| read_frame(&mut cam, &source, &mut frame)),
+            None => read_frame(&mut cam, &source, &mut frame)
+        };
+        set_busy(false);
+
+        if let Some(pacer) = pacer.as_mut() {
+            if let Ok(true) = result {
+                pacer.restart();
+            }
+            pacer.wait();
+        }
+
+        let message = match result {
+            Err(err) => CaptureMessage::ReadFailed(format!("Failed to read a frame: {}", err)),
+            Ok(_) if frame.empty() => match source {
+                VideoSource::File { looping: false, .. } => {
+                    let _ = sender.send(CaptureMessage::Ended);
+                    return;
+                }
+                _ => CaptureMessage::ReadFailed(format!("No frame captured from {}", source.path()))
+            },
+            Ok(_) => {
+                if !frames.push(frame) {
+                    return;
+                }
+                continue;
+            }
+        };
+        // Fails once the app has dropped this worker
+        if sender.send(message).is_err() {
+            return;
+        }
+    }
+}
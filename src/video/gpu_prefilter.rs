@@ -0,0 +1,400 @@
+//! A compute-shader pre-pass that finds the parts of the frame that could hold markers, so OpenCV only searches a few
+//! small crops instead of the whole frame. It runs on the background texture already uploaded for drawing: each tile of
+//! the frame is marked as a candidate if it has strong contrast with a roughly even mix of dark and bright pixels, the
+//! flags are read back, and touching candidate tiles are merged into regions. The readback arrives a frame or two late,
+//! so each region is grown by a tile to cover marker movement in between. Detection searches the raw frame, so when the
+//! background is undistorted the regions are taken back through the lens distortion first.
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::{Assets, Handle}, ecs::{component::Component, entity::Entity, observer::Trigger, query::With, resource::Resource, system::{Commands, Query, Res, ResMut}, world::{FromWorld, World}}, render::{extract_resource::{ExtractResource, ExtractResourcePlugin}, gpu_readback::{Readback, ReadbackComplete}, graph::CameraDriverLabel, render_asset::RenderAssets, render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel}, render_resource::{BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntry, BindingType, BufferBindingType, BufferInitDescriptor, BufferUsages, ComputePassDescriptor, ComputePipeline, PipelineLayoutDescriptor, RawComputePipelineDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, TextureSampleType, TextureView, TextureViewDimension}, renderer::{RenderContext, RenderDevice}, storage::{GpuShaderStorageBuffer, ShaderStorageBuffer}, RenderApp}};
+use bytemuck::{Pod, Zeroable};
+use opencv::core::Rect;
+
+use crate::{background::undistort::{self, UndistortSettings}, video::aruco_camera::CameraIntrinsics};
+
+/// The most tiles the readback buffer holds. Tiles grow past `GpuPrefilterSettings::tile_size` on frames that would need more.
+const MAX_TILES: u32 = 16384;
+/// The values before the tile flags. Must match `HEADER_LEN` in prefilterShader.wgsl.
+const HEADER_LEN: usize = 4;
+/// The size of the compute workgroups, which each cover one tile.
+const WORKGROUP_SIZE: u32 = 8;
+
+#[derive(Resource, Clone, PartialEq, ExtractResource)]
+pub struct GpuPrefilterSettings {
+    pub enabled: bool,
+    /// The side length of a tile in pixels. Smaller tiles give tighter crops but more of them.
+    pub tile_size: u32,
+    /// The difference between a tile's darkest and brightest pixels needed for a candidate, from 0 to 1.
+    pub min_contrast: f32,
+    /// The share of a tile darker than its midpoint needed for a candidate. Markers are roughly half dark.
+    pub min_dark_fraction: f32,
+    pub max_dark_fraction: f32,
+    /// Above this share of the frame in candidates, cropping saves nothing, so the whole frame is searched.
+    pub max_coverage: f32,
+    /// Every this many detections, the whole frame is searched anyway, so markers the prefilter misses are still found.
+    pub full_frame_interval: u32
+}
+
+impl Default for GpuPrefilterSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            tile_size: 32,
+            min_contrast: 0.35,
+            min_dark_fraction: 0.2,
+            max_dark_fraction: 0.8,
+            max_coverage: 0.5,
+            full_frame_interval: 30
+        }
+    }
+}
+
+/// The regions of the frame the latest readback found candidates in, in full-resolution pixels of the raw frame.
+#[derive(Resource, Default)]
+pub struct GpuCandidates {
+    regions: Option<Vec<Rect>>,
+    /// The detections searched in regions since the last one that searched the whole frame.
+    since_full_frame: u32
+}
+
+impl GpuCandidates {
+    /// The regions the next detection should search, or `None` if it should search the whole frame. That's also the case
+    /// every `full_frame_interval` detections and whenever the last detection found no markers, so a marker the prefilter
+    /// can't see (say, under glare) is found again rather than lost for good.
+    pub fn next_regions(&mut self, settings: &GpuPrefilterSettings, markers_found: bool) -> Option<Vec<Rect>> {
+        let regions = self.regions.as_ref().filter(|_| markers_found && self.since_full_frame + 1 < settings.full_frame_interval);
+        match regions {
+            Some(regions) => {
+                self.since_full_frame += 1;
+                Some(regions.clone())
+            }
+            None => {
+                self.since_full_frame = 0;
+                None
+            }
+        }
+    }
+}
+
+/// The buffer the compute pass writes the tile flags into.
+#[derive(Resource, Clone, ExtractResource)]
+struct PrefilterBuffer(Handle<ShaderStorageBuffer>);
+
+/// The background texture uploaded this frame, shared by the background pass.
+#[derive(Resource)]
+pub(crate) struct UploadedFrame {
+    pub view: TextureView,
+    pub width: u32,
+    pub height: u32
+}
+
+#[derive(Component)]
+struct PrefilterReadback;
+
+// Must match Params in prefilterShader.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct PrefilterParams {
+    tile_size: u32,
+    tiles_x: u32,
+    tiles_y: u32,
+    min_contrast: f32,
+    min_dark_fraction: f32,
+    max_dark_fraction: f32,
+    _padding: [u32; 2]
+}
+
+/// Merges touching candidate tiles into regions, each grown by a tile on every side.
+/// Returns `None` when there are no candidates, or more than `max_coverage` of the tiles are.
+fn candidate_regions(flags: &[u32], tiles_x: usize, tiles_y: usize, tile_size: i32, max_coverage: f32) -> Option<Vec<Rect>> {
+    let tiles = tiles_x * tiles_y;
+    if tiles == 0 || flags.len() < tiles {
+        return None;
+    }
+    let candidates = flags[..tiles].iter().filter(|&&flag| flag != 0).count();
+    if candidates == 0 || candidates as f32 > tiles as f32 * max_coverage {
+        return None;
+    }
+
+    let mut visited = vec![false; tiles];
+    let mut regions = Vec::new();
+    for start in 0..tiles {
+        if flags[start] == 0 || visited[start] {
+            continue;
+        }
+        // Flood fill the group of touching tiles, tracking its bounds
+        let (mut left, mut top, mut right, mut bottom) = (tiles_x, tiles_y, 0, 0);
+        let mut stack = vec![start];
+        visited[start] = true;
+        while let Some(tile) = stack.pop() {
+            let (x, y) = (tile % tiles_x, tile / tiles_x);
+            (left, top, right, bottom) = (left.min(x), top.min(y), right.max(x), bottom.max(y));
+            for neighbour_y in y.saturating_sub(1)..=(y + 1).min(tiles_y - 1) {
+                for neighbour_x in x.saturating_sub(1)..=(x + 1).min(tiles_x - 1) {
+                    let neighbour = neighbour_y * tiles_x + neighbour_x;
+                    if flags[neighbour] != 0 && !visited[neighbour] {
+                        visited[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+
+        let (left, top) = (left.saturating_sub(1) as i32, top.saturating_sub(1) as i32);
+        let (right, bottom) = ((right + 2).min(tiles_x) as i32, (bottom + 2).min(tiles_y) as i32);
+        regions.push(Rect::new(left * tile_size, top * tile_size, (right - left) * tile_size, (bottom - top) * tile_size));
+    }
+    Some(regions)
+}
+
+fn setup(
+    mut commands: Commands,
+    mut buffers: ResMut<Assets<ShaderStorageBuffer>>
+) {
+    let mut buffer = ShaderStorageBuffer::from(vec![0u32; HEADER_LEN + MAX_TILES as usize]);
+    buffer.buffer_description.usage |= BufferUsages::COPY_SRC;
+    commands.insert_resource(PrefilterBuffer(buffers.add(buffer)));
+}
+
+/// Reads the flags back only while the prefilter is enabled, since the readback costs a GPU sync every frame.
+fn toggle_readback(
+    mut commands: Commands,
+    settings: Res<GpuPrefilterSettings>,
+    buffer: Res<PrefilterBuffer>,
+    readbacks: Query<Entity, With<PrefilterReadback>>,
+    mut candidates: ResMut<GpuCandidates>
+) {
+    match (settings.enabled, readbacks.iter().next()) {
+        (true, None) => {
+            commands.spawn((Readback::buffer(buffer.0.clone()), PrefilterReadback)).observe(receive_candidates);
+        }
+        (false, Some(entity)) => {
+            commands.entity(entity).despawn();
+            candidates.regions = None;
+        }
+        _ => {}
+    }
+}
+
+fn receive_candidates(
+    trigger: Trigger<ReadbackComplete>,
+    settings: Res<GpuPrefilterSettings>,
+    undistort_settings: Option<Res<UndistortSettings>>,
+    intrinsics: Option<Res<CameraIntrinsics>>,
+    mut candidates: ResMut<GpuCandidates>
+) {
+    let data: Vec<u32> = trigger.event().to_shader_type();
+    // The last field of the header is only set once the pass has run
+    if data.len() < HEADER_LEN || data[3] == 0 {
+        return;
+    }
+    let (tiles_x, tiles_y, tile_size) = (data[0] as usize, data[1] as usize, data[2] as i32);
+    let regions = candidate_regions(&data[HEADER_LEN..], tiles_x, tiles_y, tile_size, settings.max_coverage);
+
+    // The prefilter ran on the uploaded background, which may have been undistorted
+    let undistorted = undistort_settings.is_some_and(|settings| settings.active(intrinsics.as_deref()));
+    let intrinsics = intrinsics.as_deref().filter(|_| undistorted);
+    candidates.regions = match (regions, intrinsics) {
+        (Some(regions), Some(intrinsics)) => {
+            // Search the whole frame rather than the wrong places if a region can't be mapped
+            regions.into_iter().map(|region| undistort::distorted_region(intrinsics, region)).collect::<opencv::Result<_>>().ok()
+        }
+        (regions, _) => regions
+    };
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct PrefilterNodeLabel;
+
+#[derive(Resource)]
+struct PrefilterPipeline {
+    layout: BindGroupLayout,
+    pipeline: ComputePipeline
+}
+
+impl FromWorld for PrefilterPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+        let shader = device.create_and_validate_shader_module(ShaderModuleDescriptor {
+            label: Some("Marker Prefilter Shader"),
+            source: ShaderSource::Wgsl(include_str!("prefilterShader.wgsl").into())
+        });
+
+        let layout = device.create_bind_group_layout(
+            "marker_prefilter_bind_group_layout",
+            &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false
+                    },
+                    count: None
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None
+                    },
+                    count: None
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None
+                    },
+                    count: None
+                }
+            ]
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("Marker Prefilter Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[]
+        });
+        let pipeline = device.create_compute_pipeline(&RawComputePipelineDescriptor {
+            label: Some("Marker Prefilter Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: Some("find_candidates"),
+            compilation_options: Default::default(),
+            cache: None
+        });
+
+        Self { layout, pipeline }
+    }
+}
+
+/// Dispatches the prefilter on the latest uploaded frame. Runs once per frame, before any camera renders.
+#[derive(Default)]
+struct PrefilterNode {
+    /// The bind group for this frame and the number of tiles across and down.
+    dispatch: Option<(BindGroup, u32, u32)>
+}
+
+impl Node for PrefilterNode {
+    fn update(&mut self, world: &mut World) {
+        self.dispatch = None;
+        let (Some(settings), Some(frame), Some(buffer)) = (world.get_resource::<GpuPrefilterSettings>(), world.get_resource::<UploadedFrame>(), world.get_resource::<PrefilterBuffer>()) else { return };
+        if !settings.enabled || frame.width == 0 || frame.height == 0 {
+            return;
+        }
+        let Some(buffer) = world.resource::<RenderAssets<GpuShaderStorageBuffer>>().get(&buffer.0) else { return };
+
+        // Grow the tiles until the frame fits in the buffer, keeping them a multiple of the workgroup size
+        let mut tile_size = settings.tile_size.max(WORKGROUP_SIZE).next_multiple_of(WORKGROUP_SIZE);
+        while frame.width.div_ceil(tile_size) * frame.height.div_ceil(tile_size) > MAX_TILES {
+            tile_size += WORKGROUP_SIZE;
+        }
+        let params = PrefilterParams {
+            tile_size,
+            tiles_x: frame.width.div_ceil(tile_size),
+            tiles_y: frame.height.div_ceil(tile_size),
+            min_contrast: settings.min_contrast,
+            min_dark_fraction: settings.min_dark_fraction,
+            max_dark_fraction: settings.max_dark_fraction,
+            _padding: [0; 2]
+        };
+
+        let device = world.resource::<RenderDevice>();
+        let params_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("marker_prefilter_params"),
+            contents: bytemuck::bytes_of(&params),
+            usage: BufferUsages::UNIFORM
+        });
+        let pipeline = world.resource::<PrefilterPipeline>();
+        let bind_group = device.create_bind_group(
+            Some("marker_prefilter_bind_group"),
+            &pipeline.layout,
+            &BindGroupEntries::sequential((&frame.view, params_buffer.as_entire_binding(), buffer.buffer.as_entire_binding()))
+        );
+        self.dispatch = Some((bind_group, params.tiles_x, params.tiles_y));
+    }
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World
+    ) -> Result<(), NodeRunError> {
+        let Some((bind_group, tiles_x, tiles_y)) = &self.dispatch else { return Ok(()) };
+        let pipeline = world.resource::<PrefilterPipeline>();
+
+        let mut pass = render_context.command_encoder().begin_compute_pass(&ComputePassDescriptor {
+            label: Some("marker_prefilter_pass"),
+            timestamp_writes: None
+        });
+        pass.set_pipeline(&pipeline.pipeline);
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.dispatch_workgroups(*tiles_x, *tiles_y, 1);
+        Ok(())
+    }
+}
+
+pub struct GpuPrefilterPlugin;
+
+impl Plugin for GpuPrefilterPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<GpuPrefilterSettings>()
+            .init_resource::<GpuCandidates>()
+            .add_plugins((
+                ExtractResourcePlugin::<GpuPrefilterSettings>::default(),
+                ExtractResourcePlugin::<PrefilterBuffer>::default()
+            ))
+            .add_systems(Startup, setup)
+            .add_systems(Update, toggle_readback);
+
+        // There's no render app when running headless, and the prefilter never turns on
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(PrefilterNodeLabel, PrefilterNode::default());
+        render_graph.add_node_edge(PrefilterNodeLabel, CameraDriverLabel);
+    }
+
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app.init_resource::<PrefilterPipeline>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touching_tiles_merge_into_grown_regions() {
+        #[rustfmt::skip]
+        let flags = [
+            0, 0, 0, 0, 0, 0,
+            0, 1, 1, 0, 0, 0,
+            0, 0, 1, 0, 0, 0,
+            0, 0, 0, 0, 0, 1
+        ];
+        let regions = candidate_regions(&flags, 6, 4, 10, 0.5).unwrap();
+        assert_eq!(regions, vec![Rect::new(0, 0, 40, 40), Rect::new(40, 20, 20, 20)]);
+        assert!(candidate_regions(&[1, 1, 1, 0], 2, 2, 10, 0.5).is_none());
+        assert!(candidate_regions(&[0, 0, 0, 0], 2, 2, 10, 0.5).is_none());
+    }
+
+    #[test]
+    fn whole_frame_is_searched_periodically_and_after_losing_the_markers() {
+        let settings = GpuPrefilterSettings { full_frame_interval: 3, ..Default::default() };
+        let mut candidates = GpuCandidates { regions: Some(vec![Rect::new(0, 0, 10, 10)]), since_full_frame: 0 };
+        let searched: Vec<bool> = (0..6).map(|_| candidates.next_regions(&settings, true).is_some()).collect();
+        assert_eq!(searched, [true, true, false, true, true, false]);
+        assert!(candidates.next_regions(&settings, false).is_none());
+    }
+}
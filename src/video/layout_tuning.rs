@@ -0,0 +1,183 @@
+//! Layout tuning mode: select a fiducial and nudge its offsets with the arrow keys while watching the reprojection error
+//! and the overlay alignment update live, then save the layout for the config to load.
+//!
+//! F10 toggles the mode. Tab selects the next marker, the arrow keys move it along x and z, Page Up/Down along y,
+//! and holding Shift takes bigger steps. Enter saves.
+
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::{app::{App, Plugin, Startup, Update}, color::{palettes::css::ORANGE, Color}, ecs::{change_detection::DetectChanges, component::Component, entity::Entity, event::{EventReader, EventWriter}, hierarchy::ChildOf, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Query, Res, ResMut, Single}}, gizmos::gizmos::Gizmos, input::{keyboard::KeyCode, ButtonInput}, math::{Isometry3d, Quat, Vec2, Vec3}, ui::{widget::Text, Node, UiRect, Val}};
+
+use crate::{command::{self, AppCommand}, config::ConfigWatcher, decorations::DecorationPlacement, hud::{self, HudStack}, recovery::SessionRecovery, status::{AppError, ErrorSource}, video::aruco_camera::{ArucoTrackingData, DetectionSettings, FiducialLayout}};
+
+/// Where the layout is saved when `config.toml` doesn't name a layout file.
+pub static DEFAULT_LAYOUT_PATH: &str = "fiducials.json";
+/// How far one key press moves the selected marker, in mm.
+const FINE_STEP: f64 = 0.5;
+const COARSE_STEP: f64 = 5.0;
+const HUD_FONT_SIZE: f32 = 16.0;
+
+#[derive(Resource, Default)]
+pub struct LayoutTuning {
+    pub active: bool,
+    /// The index into `FiducialLayout::fiducials` of the marker being moved.
+    selected: usize,
+    /// Whether per-marker axes were on before tuning turned them on, so they can be restored.
+    marker_axes_before: bool
+}
+
+fn layout_path(watcher: Option<&ConfigWatcher>) -> String {
+    watcher.and_then(|watcher| watcher.config.fiducials.layout.clone())
+        .unwrap_or_else(|| DEFAULT_LAYOUT_PATH.to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn tune_layout(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut commands: EventReader<AppCommand>,
+    placement: Res<DecorationPlacement>,
+    recovery: Option<Res<SessionRecovery>>,
+    mut tuning: ResMut<LayoutTuning>,
+    mut layout: ResMut<FiducialLayout>,
+    mut detection_settings: ResMut<DetectionSettings>,
+    config_watcher: Option<Res<ConfigWatcher>>,
+    mut errors: EventWriter<AppError>
+) {
+    for command in commands.read() {
+        if *command != AppCommand::ToggleLayoutTuning {
+            continue;
+        }
+        tuning.active = !tuning.active;
+        // Each marker's own pose shows which way it needs to move
+        if tuning.active {
+            tuning.marker_axes_before = detection_settings.marker_axes;
+            detection_settings.marker_axes = true;
+        } else {
+            detection_settings.marker_axes = tuning.marker_axes_before;
+        }
+    }
+    // Decoration placement uses some of the same keys, so they're left to it while both modes are on
+    if !tuning.active || placement.active || layout.fiducials.is_empty() {
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::Tab) {
+        tuning.selected = (tuning.selected + 1) % layout.fiducials.len();
+    }
+    let selected = tuning.selected.min(layout.fiducials.len() - 1);
+
+    let step = if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) { COARSE_STEP } else { FINE_STEP };
+    let mut nudge = [0.0; 3];
+    for (key, axis, direction) in [
+        (KeyCode::ArrowRight, 0, 1.0),
+        (KeyCode::ArrowLeft, 0, -1.0),
+        (KeyCode::PageUp, 1, 1.0),
+        (KeyCode::PageDown, 1, -1.0),
+        // Positive z is toward the player, so up moves the marker away
+        (KeyCode::ArrowDown, 2, 1.0),
+        (KeyCode::ArrowUp, 2, -1.0)
+    ] {
+        if keys.just_pressed(key) {
+            nudge[axis] += direction * step;
+        }
+    }
+    // Only take the layout mutably when something moves, since the planes are respawned whenever it changes
+    if nudge != [0.0; 3] {
+        let fiducial = &mut layout.fiducials[selected];
+        fiducial.x_offset += nudge[0];
+        fiducial.y_offset += nudge[1];
+        fiducial.z_offset += nudge[2];
+    }
+
+    // Enter also resumes an interrupted session, which shouldn't save the layout too
+    if keys.just_pressed(KeyCode::Enter) && !recovery.is_some_and(|recovery| recovery.is_prompting()) {
+        let path = layout_path(config_watcher.as_deref());
+        match layout.save(&path) {
+            Ok(()) => {
+                println!("Saved the fiducial layout to {}", path);
+                if config_watcher.is_none_or(|watcher| watcher.config.fiducials.layout.is_none()) {
+                    println!("Set layout = \"{}\" under [fiducials] in config.toml to load it on startup", path);
+                }
+            }
+            Err(err) => {
+                errors.write(AppError::new(ErrorSource::Detection, format!("Failed to save the fiducial layout to {}: {}", path, err)));
+            }
+        }
+    }
+}
+
+/// Outlines the selected marker where the layout currently places it.
+fn highlight_selected_marker(
+    tuning: Res<LayoutTuning>,
+    layout: Res<FiducialLayout>,
+    mut gizmos: Gizmos
+) {
+    if !tuning.active {
+        return;
+    }
+    let Some(fiducial) = layout.fiducials.get(tuning.selected) else { return };
+
+    let size = layout.size as f32;
+    // Rectangles are drawn in the XY plane, so lay it flat on the board
+    gizmos.rect(Isometry3d::new(fiducial.center(), Quat::from_rotation_x(FRAC_PI_2)), Vec2::splat(size * 1.1), ORANGE);
+}
+
+#[derive(Component)]
+struct LayoutTuningHud;
+
+fn setup(mut commands: Commands, stack: Single<Entity, With<HudStack>>) {
+    commands.spawn((
+        hud::panel(HUD_FONT_SIZE, UiRect::all(Val::Px(6.0)), Color::srgba(0.0, 0.0, 0.0, 0.75)),
+        LayoutTuningHud,
+        ChildOf(*stack)
+    ));
+}
+
+fn update_layout_tuning_hud(
+    tuning: Res<LayoutTuning>,
+    layout: Res<FiducialLayout>,
+    tracking_data: Res<ArucoTrackingData>,
+    mut huds: Query<(&mut Node, &mut Text), With<LayoutTuningHud>>
+) {
+    if !tuning.is_changed() && !layout.is_changed() && !tracking_data.is_changed() {
+        return;
+    }
+
+    let text = layout.fiducials.get(tuning.selected).filter(|_| tuning.active).map(|fiducial| {
+        let measured = tracking_data.marker_pose(fiducial.id)
+            .map(|pose| format_offsets(pose.translation))
+            .unwrap_or_else(|| "not visible".to_string());
+        format!(
+            "Layout tuning: marker {} ({} of {})\nOffsets: {}\nMeasured: {}\nReprojection error: {}\nTab: next marker, arrows/Page Up/Down: nudge (Shift: {} mm), Enter: save",
+            fiducial.id,
+            tuning.selected + 1,
+            layout.fiducials.len(),
+            format_offsets(fiducial.center()),
+            measured,
+            tracking_data.reprojection_error().map(|error| format!("{:.2} px", error)).unwrap_or_else(|| "-".to_string()),
+            COARSE_STEP
+        )
+    });
+
+    for (mut node, mut hud_text) in huds.iter_mut() {
+        if let Some(text) = &text {
+            hud_text.0 = text.clone();
+        }
+        hud::show_panel(&mut node, text.is_some());
+    }
+}
+
+fn format_offsets(offsets: Vec3) -> String {
+    format!("x {:.1}, y {:.1}, z {:.1} mm", offsets.x, offsets.y, offsets.z)
+}
+
+pub struct LayoutTuningPlugin;
+
+impl Plugin for LayoutTuningPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<LayoutTuning>()
+            .add_systems(Startup, setup)
+            .add_systems(Update, (tune_layout, highlight_selected_marker, update_layout_tuning_hud).chain().after(command::run_app_commands));
+    }
+}
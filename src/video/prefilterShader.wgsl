@@ -0,0 +1,93 @@
+// Marks the tiles of the frame that could hold part of a marker, so marker detection can skip the rest.
+// A marker tile has both very dark and very bright pixels, in roughly even amounts.
+
+// Must match PrefilterParams in video/gpu_prefilter.rs
+struct Params {
+    tile_size: u32,
+    tiles_x: u32,
+    tiles_y: u32,
+    min_contrast: f32,
+    min_dark_fraction: f32,
+    max_dark_fraction: f32,
+    _padding: vec2<u32>,
+};
+
+// Must match HEADER_LEN in video/gpu_prefilter.rs
+const HEADER_LEN: u32 = 4u;
+const WORKGROUP_SIZE: u32 = 8u;
+
+@group(0) @binding(0)
+var frame: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> params: Params;
+// The header (tiles_x, tiles_y, tile_size, 1), then one flag per tile
+@group(0) @binding(2)
+var<storage, read_write> tiles: array<u32>;
+
+var<workgroup> darkest: atomic<u32>;
+var<workgroup> brightest: atomic<u32>;
+var<workgroup> dark_pixels: atomic<u32>;
+var<workgroup> pixels: atomic<u32>;
+
+fn luminance(pixel: vec2<u32>) -> u32 {
+    return u32(dot(textureLoad(frame, pixel, 0).rgb, vec3f(0.299, 0.587, 0.114)) * 255.0);
+}
+
+// One workgroup per tile. Each invocation covers its own square of the tile.
+@compute @workgroup_size(8, 8, 1)
+fn find_candidates(
+    @builtin(workgroup_id) tile: vec3<u32>,
+    @builtin(local_invocation_id) local: vec3<u32>,
+    @builtin(local_invocation_index) index: u32
+) {
+    if (index == 0u) {
+        atomicStore(&darkest, 255u);
+        atomicStore(&brightest, 0u);
+        atomicStore(&dark_pixels, 0u);
+        atomicStore(&pixels, 0u);
+    }
+    workgroupBarrier();
+
+    let size = textureDimensions(frame);
+    let step = max(params.tile_size / WORKGROUP_SIZE, 1u);
+    let origin = tile.xy * params.tile_size + local.xy * step;
+    for (var y = 0u; y < step; y++) {
+        for (var x = 0u; x < step; x++) {
+            let pixel = origin + vec2<u32>(x, y);
+            if (pixel.x < size.x && pixel.y < size.y) {
+                let value = luminance(pixel);
+                atomicMin(&darkest, value);
+                atomicMax(&brightest, value);
+            }
+        }
+    }
+    workgroupBarrier();
+
+    let middle = (atomicLoad(&darkest) + atomicLoad(&brightest)) / 2u;
+    for (var y = 0u; y < step; y++) {
+        for (var x = 0u; x < step; x++) {
+            let pixel = origin + vec2<u32>(x, y);
+            if (pixel.x < size.x && pixel.y < size.y) {
+                atomicAdd(&pixels, 1u);
+                if (luminance(pixel) < middle) {
+                    atomicAdd(&dark_pixels, 1u);
+                }
+            }
+        }
+    }
+    workgroupBarrier();
+
+    if (index != 0u) {
+        return;
+    }
+    if (tile.x == 0u && tile.y == 0u) {
+        tiles[0] = params.tiles_x;
+        tiles[1] = params.tiles_y;
+        tiles[2] = params.tile_size;
+        tiles[3] = 1u;
+    }
+    let contrast = f32(atomicLoad(&brightest) - atomicLoad(&darkest)) / 255.0;
+    let dark_fraction = f32(atomicLoad(&dark_pixels)) / f32(max(atomicLoad(&pixels), 1u));
+    let candidate = contrast >= params.min_contrast && dark_fraction >= params.min_dark_fraction && dark_fraction <= params.max_dark_fraction;
+    tiles[HEADER_LEN + tile.y * params.tiles_x + tile.x] = select(0u, 1u, candidate);
+}
@@ -0,0 +1,152 @@
+//! Tracking-loss handling. The camera holds its last good pose while the markers are out of view,
+//! and AR content can fade out until tracking comes back. See `quality` for how good the pose is while tracking, and
+//! `smoothing` for how it's steadied.
+
+pub mod quality;
+pub mod smoothing;
+
+use std::collections::HashSet;
+
+use bevy::{app::{App, Plugin, Startup, Update}, asset::Assets, color::Alpha, ecs::{change_detection::DetectChangesMut, component::Component, query::With, resource::Resource, schedule::IntoScheduleConfigs, system::{Query, Res, ResMut}}, pbr::{MeshMaterial3d, StandardMaterial}, render::alpha::AlphaMode, time::Time};
+
+use crate::{video::aruco_camera::{self, ArucoTrackingData}, VideoUpdateSystems};
+
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrackingState {
+    /// Enough markers are visible and the pose is fresh.
+    Tracking,
+    /// A pose was solved recently, but from too few markers or not on the latest detection.
+    Degraded,
+    /// No pose has been solved for a while. The camera holds the last good pose.
+    #[default]
+    Lost
+}
+
+#[derive(Resource, Clone)]
+pub struct TrackingSettings {
+    /// Fewer visible markers than this counts as degraded tracking.
+    pub min_markers: usize,
+    /// Seconds without a new pose before tracking counts as degraded.
+    pub degraded_after: f64,
+    /// Seconds without a new pose before tracking counts as lost.
+    pub lost_after: f64,
+    /// Whether to fade AR content out while tracking is lost.
+    pub fade_when_lost: bool,
+    /// How long a full fade in or out takes, in seconds.
+    pub fade_duration: f32,
+    /// Whether to show the tracking quality indicator.
+    pub show_quality: bool,
+    /// RMS reprojection errors below this many pixels count as good tracking.
+    pub good_error: f64,
+    /// RMS reprojection errors above this many pixels count as poor tracking.
+    pub poor_error: f64,
+    /// Whether to smooth the pose more while the camera is still than while it moves. See `smoothing`.
+    pub adaptive_smoothing: bool,
+    /// The smoothing filter's cutoff frequency in Hz while the camera is still. Lower hides more jitter.
+    pub still_cutoff: f32,
+    /// The cutoff frequency in Hz while the camera moves. Higher lags less.
+    pub moving_cutoff: f32,
+    /// Moving at this many mm/s or degrees/s gets the moving cutoff, and slower motion a cutoff in between.
+    pub moving_speed: f32,
+    pub moving_angular_speed: f32
+}
+
+impl Default for TrackingSettings {
+    fn default() -> Self {
+        Self {
+            min_markers: 2,
+            degraded_after: 0.2,
+            lost_after: 1.0,
+            fade_when_lost: true,
+            fade_duration: 0.5,
+            show_quality: true,
+            good_error: 1.0,
+            poor_error: 3.0,
+            adaptive_smoothing: true,
+            still_cutoff: 0.5,
+            moving_cutoff: 8.0,
+            moving_speed: 150.0,
+            moving_angular_speed: 30.0
+        }
+    }
+}
+
+/// Marks AR content whose material fades out while tracking is lost. The material is assumed to be opaque otherwise.
+#[derive(Component)]
+pub struct FadeWithTracking;
+
+/// The current opacity applied to `FadeWithTracking` content, from 0 to 1.
+#[derive(Resource)]
+pub struct TrackingFade(pub f32);
+
+impl Default for TrackingFade {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+fn update_tracking_state(
+    time: Res<Time>,
+    settings: Res<TrackingSettings>,
+    tracking_data: Res<ArucoTrackingData>,
+    mut state: ResMut<TrackingState>
+) {
+    let since_pose = tracking_data.last_pose_time().map(|pose_time| time.elapsed_secs_f64() - pose_time);
+    let new_state = match since_pose {
+        Some(since_pose) if since_pose <= settings.degraded_after && tracking_data.detected_count() >= settings.min_markers => TrackingState::Tracking,
+        Some(since_pose) if since_pose <= settings.lost_after => TrackingState::Degraded,
+        _ => TrackingState::Lost
+    };
+
+    if *state != new_state {
+        match new_state {
+            TrackingState::Lost => eprintln!("Tracking lost; holding the last pose"),
+            TrackingState::Degraded => println!("Tracking degraded"),
+            TrackingState::Tracking => println!("Tracking")
+        }
+    }
+    state.set_if_neq(new_state);
+}
+
+fn fade_tracked_content(
+    time: Res<Time>,
+    settings: Res<TrackingSettings>,
+    state: Res<TrackingState>,
+    mut fade: ResMut<TrackingFade>,
+    content: Query<&MeshMaterial3d<StandardMaterial>, With<FadeWithTracking>>,
+    mut materials: ResMut<Assets<StandardMaterial>>
+) {
+    let target = if settings.fade_when_lost && *state == TrackingState::Lost { 0.0 } else { 1.0 };
+    let step = time.delta_secs() / settings.fade_duration.max(0.001);
+    let opacity = if fade.0 < target { (fade.0 + step).min(target) } else { (fade.0 - step).max(target) };
+    if fade.0 != opacity {
+        fade.0 = opacity;
+    }
+
+    // Materials are shared between entities, so only touch each one once, and only when it actually changes
+    let handles: HashSet<_> = content.iter().map(|material| material.0.id()).collect();
+    for handle in handles {
+        let Some(material) = materials.get(handle) else { continue };
+        if material.base_color.alpha() == opacity {
+            continue;
+        }
+        let Some(material) = materials.get_mut(handle) else { continue };
+        material.base_color.set_alpha(opacity);
+        material.alpha_mode = if opacity < 1.0 { AlphaMode::Blend } else { AlphaMode::Opaque };
+    }
+}
+
+pub struct TrackingPlugin;
+
+impl Plugin for TrackingPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<TrackingSettings>()
+            .init_resource::<TrackingState>()
+            .init_resource::<TrackingFade>()
+            .init_resource::<quality::TrackingQuality>()
+            .init_resource::<smoothing::PoseFilter>()
+            .add_systems(Startup, quality::spawn_quality_indicator)
+            .add_systems(Update, (update_tracking_state, fade_tracked_content, quality::update_tracking_quality, quality::update_quality_indicator).chain().after(aruco_camera::finish_marker_detection).in_set(VideoUpdateSystems));
+    }
+}
@@ -0,0 +1,117 @@
+//! How trustworthy the solved pose is, from how closely the fiducial corners reprojected with it land on the detected
+//! corners. A persistently poor grade usually means a bad camera calibration, or markers that have moved from where the
+//! layout places them. A small indicator in the corner of the window shows the grade.
+
+use bevy::{color::Color, ecs::{component::Component, entity::Entity, hierarchy::ChildOf, query::With, resource::Resource, system::{Commands, Res, ResMut, Single}}, text::TextColor, ui::{widget::Text, Node, UiRect, Val}};
+
+use crate::{hud::{self, HudStack}, video::aruco_camera::ArucoTrackingData};
+
+use super::{TrackingSettings, TrackingState};
+
+const INDICATOR_FONT_SIZE: f32 = 14.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QualityGrade {
+    Good,
+    Fair,
+    /// Tracking, but the overlay is likely visibly off.
+    Poor,
+    /// No recent pose to judge.
+    #[default]
+    Lost
+}
+
+impl QualityGrade {
+    fn label(self) -> &'static str {
+        match self {
+            QualityGrade::Good => "good",
+            QualityGrade::Fair => "fair",
+            QualityGrade::Poor => "poor",
+            QualityGrade::Lost => "lost"
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            QualityGrade::Good => Color::srgb(0.3, 0.9, 0.3),
+            QualityGrade::Fair => Color::srgb(0.95, 0.8, 0.2),
+            QualityGrade::Poor => Color::srgb(1.0, 0.45, 0.2),
+            QualityGrade::Lost => Color::srgb(0.9, 0.2, 0.2)
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone, Default, PartialEq)]
+pub struct TrackingQuality {
+    pub grade: QualityGrade,
+    /// The RMS reprojection error of the latest pose, in pixels.
+    pub rms_error: Option<f64>,
+    /// The RMS reprojection error averaged over the last few poses, which the grade is based on.
+    pub average_rms_error: Option<f64>,
+    /// The worst corner of the latest pose, in pixels.
+    pub max_error: Option<f64>,
+    /// The number of corners the latest pose was solved from.
+    pub corners: usize
+}
+
+#[derive(Component)]
+pub(super) struct QualityIndicator;
+
+pub(super) fn update_tracking_quality(
+    settings: Res<TrackingSettings>,
+    state: Res<TrackingState>,
+    tracking_data: Res<ArucoTrackingData>,
+    mut quality: ResMut<TrackingQuality>
+) {
+    let details = tracking_data.reprojection_details();
+    let average = tracking_data.average_reprojection_error();
+    let grade = match (*state, average) {
+        (TrackingState::Lost, _) | (_, None) => QualityGrade::Lost,
+        (_, Some(error)) if error > settings.poor_error => QualityGrade::Poor,
+        // Too few markers or a stale pose can't count as good, however low the error
+        (TrackingState::Degraded, _) => QualityGrade::Fair,
+        (_, Some(error)) if error > settings.good_error => QualityGrade::Fair,
+        _ => QualityGrade::Good
+    };
+
+    let new_quality = TrackingQuality {
+        grade,
+        rms_error: details.map(|details| details.rms),
+        average_rms_error: average,
+        max_error: details.map(|details| details.max),
+        corners: details.map_or(0, |details| details.corners)
+    };
+    if *quality != new_quality {
+        *quality = new_quality;
+    }
+}
+
+pub(super) fn spawn_quality_indicator(mut commands: Commands, stack: Single<Entity, With<HudStack>>) {
+    commands.spawn((
+        hud::panel(INDICATOR_FONT_SIZE, UiRect::axes(Val::Px(6.0), Val::Px(3.0)), Color::srgba(0.0, 0.0, 0.0, 0.5)),
+        QualityIndicator,
+        ChildOf(*stack)
+    ));
+}
+
+pub(super) fn update_quality_indicator(
+    settings: Res<TrackingSettings>,
+    quality: Res<TrackingQuality>,
+    indicator: Single<(&mut Node, &mut Text, &mut TextColor), With<QualityIndicator>>
+) {
+    let (mut node, mut text, mut color) = indicator.into_inner();
+    hud::show_panel(&mut node, settings.show_quality);
+
+    let label = match (quality.grade, quality.average_rms_error) {
+        (QualityGrade::Poor, Some(error)) => format!("Tracking poor ({:.1} px): check the calibration and marker placement", error),
+        (grade, Some(error)) if grade != QualityGrade::Lost => format!("Tracking {} ({:.1} px)", grade.label(), error),
+        _ => "Tracking lost".to_string()
+    };
+    if text.0 != label {
+        text.0 = label;
+    }
+    let grade_color = quality.grade.color();
+    if color.0 != grade_color {
+        color.0 = grade_color;
+    }
+}
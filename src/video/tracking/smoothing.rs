@@ -0,0 +1,104 @@
+//! Adaptive pose smoothing. Each solved pose is blended into the last smoothed one with a low-pass filter whose cutoff
+//! is scheduled on how fast the camera is moving, estimated from the recent raw poses: a camera on a tripod gets heavy
+//! smoothing that hides corner jitter, and a camera being moved gets light smoothing so the content doesn't lag behind.
+
+use std::{collections::VecDeque, f32::consts::TAU};
+
+use bevy::{ecs::resource::Resource, transform::components::Transform};
+
+use super::TrackingSettings;
+
+/// How far back the velocity estimate looks, in seconds. Long enough to average out jitter between single detections.
+const HISTORY_WINDOW: f64 = 0.3;
+/// A gap between poses longer than this restarts the filter, so the camera jumps straight to where tracking resumes.
+const RESET_GAP: f64 = 0.5;
+
+/// The recent raw poses and when they were solved, for estimating how fast the camera moves.
+#[derive(Default)]
+pub struct PoseHistory {
+    poses: VecDeque<(f64, Transform)>
+}
+
+impl PoseHistory {
+    pub fn push(&mut self, time: f64, pose: Transform) {
+        self.poses.push_back((time, pose));
+        while self.poses.len() > 2 && self.poses.front().is_some_and(|&(oldest, _)| oldest < time - HISTORY_WINDOW) {
+            self.poses.pop_front();
+        }
+    }
+
+    /// The camera's speed in mm/s and angular speed in degrees/s, between the oldest and newest poses.
+    pub fn velocity(&self) -> Option<(f32, f32)> {
+        let (&(first_time, first), &(last_time, last)) = (self.poses.front()?, self.poses.back()?);
+        let elapsed = (last_time - first_time) as f32;
+        if elapsed <= 0.0 {
+            return None;
+        }
+        Some((first.translation.distance(last.translation) / elapsed, first.rotation.angle_between(last.rotation).to_degrees() / elapsed))
+    }
+}
+
+/// Smooths the camera pose between detections.
+#[derive(Resource, Default)]
+pub struct PoseFilter {
+    history: PoseHistory,
+    /// The last smoothed pose and when its raw pose was solved.
+    smoothed: Option<(f64, Transform)>
+}
+
+impl PoseFilter {
+    /// Takes a newly solved pose and returns where to put the camera.
+    pub fn update(&mut self, time: f64, pose: Transform, settings: &TrackingSettings) -> Transform {
+        let previous = self.smoothed.filter(|&(previous_time, _)| time - previous_time <= RESET_GAP);
+        if previous.is_none() {
+            self.history = PoseHistory::default();
+        }
+        self.history.push(time, pose);
+
+        let smoothed = match previous {
+            Some((previous_time, previous)) if settings.adaptive_smoothing => {
+                let (speed, angular_speed) = self.history.velocity().unwrap_or_default();
+                // 0 while still, up to 1 once the camera moves at the moving speed in either sense
+                let motion = (speed / settings.moving_speed.max(0.001)).max(angular_speed / settings.moving_angular_speed.max(0.001)).min(1.0);
+                let cutoff = settings.still_cutoff + (settings.moving_cutoff - settings.still_cutoff) * motion;
+                let blend = 1.0 - (-TAU * cutoff * (time - previous_time) as f32).exp();
+                Transform {
+                    translation: previous.translation.lerp(pose.translation, blend),
+                    rotation: previous.rotation.slerp(pose.rotation, blend),
+                    scale: pose.scale
+                }
+            }
+            _ => pose
+        };
+        self.smoothed = Some((time, smoothed));
+        smoothed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3;
+
+    use super::*;
+
+    #[test]
+    fn still_cameras_are_smoothed_more_than_moving_ones() {
+        let settings = TrackingSettings::default();
+        let mut still = PoseFilter::default();
+        let mut moving = PoseFilter::default();
+        let (mut still_error, mut moving_error) = (0.0f32, 0.0f32);
+        for frame in 0..30 {
+            let time = frame as f64 / 15.0;
+            // A millimeter of jitter, and a camera sweeping along the keyboard at 300 mm/s
+            let jitter = if frame % 2 == 0 { 1.0 } else { -1.0 };
+            let still_pose = still.update(time, Transform::from_xyz(jitter, 300.0, 400.0), &settings);
+            let moving_pose = moving.update(time, Transform::from_xyz(time as f32 * 300.0, 300.0, 400.0), &settings);
+            if frame >= 10 {
+                still_error = still_error.max(still_pose.translation.distance(Vec3::new(0.0, 300.0, 400.0)));
+                moving_error = moving_error.max(moving_pose.translation.distance(Vec3::new(time as f32 * 300.0, 300.0, 400.0)));
+            }
+        }
+        assert!(still_error < 0.5, "still camera jittered by {} mm", still_error);
+        assert!(moving_error < 5.0, "moving camera lagged by {} mm", moving_error);
+    }
+}
@@ -0,0 +1,159 @@
+//! Sends the composited output to a virtual webcam, so streamers can pick the AR piano view as a camera in OBS, Zoom,
+//! or any other video app. Like a recording, it comes from an offscreen `Recorder` output camera read back from the
+//! GPU, and is sent to the device on a background thread. Enable it with `[virtual_camera] enabled = true`, or with the
+//! command palette.
+//!
+//! On Linux, frames are written to a v4l2loopback device, created with e.g.
+//! `sudo modprobe v4l2loopback video_nr=10 card_label="AR Piano" exclusive_caps=1` for `/dev/video10`.
+//! On Windows, frames go to softcam, which needs a build with `--features softcam`, `softcam.lib` and `softcam.dll`
+//! from https://github.com/tshino/softcam, and its DirectShow filter registered with `RegisterSoftcam.bat`.
+
+#[cfg(target_os = "linux")]
+mod v4l2;
+#[cfg(target_os = "linux")]
+use v4l2::VirtualCameraDevice;
+#[cfg(all(windows, feature = "softcam"))]
+mod softcam;
+#[cfg(all(windows, feature = "softcam"))]
+use softcam::VirtualCameraDevice;
+
+use std::{error::Error, thread};
+
+use bevy::{app::{App, Plugin, Update}, asset::Assets, ecs::{change_detection::DetectChanges, entity::Entity, event::EventWriter, observer::Trigger, resource::Resource, system::{Commands, Res, ResMut}}, image::Image, render::gpu_readback::{Readback, ReadbackComplete}};
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::{recording, status::{AppError, ErrorSource}};
+
+/// Frames waiting to be sent before new ones are dropped. Viewers want the latest frame, so this is kept short.
+const MAX_QUEUED_FRAMES: usize = 2;
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct VirtualCameraSettings {
+    pub enabled: bool,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    /// The v4l2loopback device to write to. Only used on Linux.
+    pub device: String
+}
+
+impl Default for VirtualCameraSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            width: 1280,
+            height: 720,
+            fps: 30.0,
+            device: "/dev/video10".to_string()
+        }
+    }
+}
+
+/// Without a virtual camera backend for this platform, opening one always fails.
+#[cfg(not(any(target_os = "linux", all(windows, feature = "softcam"))))]
+enum VirtualCameraDevice {}
+
+#[cfg(not(any(target_os = "linux", all(windows, feature = "softcam"))))]
+impl VirtualCameraDevice {
+    fn open(_settings: &VirtualCameraSettings, _width: u32, _height: u32) -> Result<Self, Box<dyn Error>> {
+        Err("this build has no virtual camera support. On Windows, rebuild with --features softcam".into())
+    }
+
+    fn send(&mut self, _frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        match *self {}
+    }
+}
+
+struct ActiveVirtualCamera {
+    /// The output camera and the readback of its target, despawned when the virtual camera stops.
+    entities: [Entity; 2],
+    sender: Sender<Vec<u8>>,
+    /// Failures reported by the sending thread.
+    errors: Receiver<String>
+}
+
+#[derive(Resource, Default)]
+pub struct VirtualCamera {
+    active: Option<ActiveVirtualCamera>
+}
+
+impl VirtualCamera {
+    fn start(&mut self, settings: &VirtualCameraSettings, commands: &mut Commands, images: &mut Assets<Image>) -> Result<(), Box<dyn Error>> {
+        // Most video apps expect even sizes for their YUV formats
+        let (width, height) = (settings.width.max(16) & !1, settings.height.max(16) & !1);
+        // Open the device before spawning anything, so a missing driver is reported right away
+        let mut device = VirtualCameraDevice::open(settings, width, height)?;
+
+        let (sender, frames) = crossbeam_channel::bounded::<Vec<u8>>(MAX_QUEUED_FRAMES);
+        let (error_sender, errors) = crossbeam_channel::unbounded();
+        thread::Builder::new()
+            .name("virtual camera".to_string())
+            .spawn(move || {
+                // Runs until the virtual camera is stopped and the sender dropped, which closes the device
+                for frame in frames {
+                    if let Err(err) = device.send(&frame) {
+                        let _ = error_sender.send(err.to_string());
+                        return;
+                    }
+                }
+            })?;
+
+        let (camera, target) = recording::spawn_offscreen_output(commands, images, width, height);
+        let readback = commands.spawn(Readback::texture(target)).observe(receive_virtual_camera_frame).id();
+
+        println!("Sending the output to a {}x{} virtual camera at {} fps", width, height, settings.fps);
+        self.active = Some(ActiveVirtualCamera { entities: [camera, readback], sender, errors });
+        Ok(())
+    }
+
+    fn stop(&mut self, commands: &mut Commands) {
+        if let Some(active) = self.active.take() {
+            for entity in active.entities {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
+fn receive_virtual_camera_frame(
+    trigger: Trigger<ReadbackComplete>,
+    virtual_camera: Res<VirtualCamera>
+) {
+    let Some(active) = &virtual_camera.active else { return };
+    // Dropping a frame when the device is slow is better than falling behind
+    let _ = active.sender.try_send(trigger.event().0.clone());
+}
+
+/// Starts or stops the virtual camera when the settings change, and stops it if sending fails.
+fn update_virtual_camera(
+    mut commands: Commands,
+    settings: Res<VirtualCameraSettings>,
+    mut images: ResMut<Assets<Image>>,
+    mut virtual_camera: ResMut<VirtualCamera>,
+    mut errors: EventWriter<AppError>
+) {
+    if settings.is_changed() {
+        virtual_camera.stop(&mut commands);
+        let started = if settings.enabled { virtual_camera.start(&settings, &mut commands, &mut images) } else { Ok(()) };
+        if let Err(err) = started {
+            errors.write(AppError::new(ErrorSource::VirtualCamera, format!("Failed to start the virtual camera: {}", err)));
+        }
+    }
+
+    let failure = virtual_camera.active.as_ref().and_then(|active| active.errors.try_recv().ok());
+    if let Some(err) = failure {
+        errors.write(AppError::new(ErrorSource::VirtualCamera, format!("Virtual camera stopped: {}", err)));
+        virtual_camera.stop(&mut commands);
+    }
+}
+
+pub struct VirtualCameraPlugin;
+
+impl Plugin for VirtualCameraPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<VirtualCameraSettings>()
+            .init_resource::<VirtualCamera>()
+            .add_systems(Update, update_virtual_camera);
+    }
+}
@@ -0,0 +1,50 @@
+//! Sends frames to softcam's DirectShow virtual camera, as 24-bit BGR.
+
+use std::{error::Error, ffi::{c_int, c_void}};
+
+use opencv::{core::{Mat, MatTraitConst}, imgproc};
+
+use crate::recording;
+
+use super::VirtualCameraSettings;
+
+#[link(name = "softcam")]
+unsafe extern "C" {
+    fn scCreateCamera(width: c_int, height: c_int, framerate: f32) -> *mut c_void;
+    fn scDeleteCamera(camera: *mut c_void);
+    fn scSendFrame(camera: *mut c_void, image_bits: *const c_void);
+}
+
+pub struct VirtualCameraDevice {
+    camera: *mut c_void,
+    width: u32,
+    height: u32,
+    bgr: Mat
+}
+
+// The camera handle is only used by the thread that owns it
+unsafe impl Send for VirtualCameraDevice {}
+
+impl VirtualCameraDevice {
+    pub fn open(settings: &VirtualCameraSettings, width: u32, height: u32) -> Result<Self, Box<dyn Error>> {
+        let camera = unsafe { scCreateCamera(width as c_int, height as c_int, settings.fps) };
+        if camera.is_null() {
+            return Err("softcam couldn't create the camera. Is another app already using it?".into());
+        }
+        Ok(Self { camera, width, height, bgr: Mat::default() })
+    }
+
+    /// Blocks until the frame is due, so frames are sent at the camera's frame rate.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        if recording::convert_readback_frame(frame, self.width, self.height, imgproc::COLOR_RGBA2BGR, &mut self.bgr)? {
+            unsafe { scSendFrame(self.camera, self.bgr.data_bytes()?.as_ptr().cast()) };
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtualCameraDevice {
+    fn drop(&mut self) {
+        unsafe { scDeleteCamera(self.camera) };
+    }
+}
@@ -0,0 +1,61 @@
+//! Writes frames to a v4l2loopback device as I420, which OBS, browsers, and video call apps all read.
+
+use std::{error::Error, fs::{File, OpenOptions}, io::Write, time::{Duration, Instant}};
+
+use opencv::{core::{Mat, MatTraitConst}, imgproc};
+use v4l::{video::{output::Parameters, Output}, Device, Format, FourCC};
+
+use crate::recording;
+
+use super::VirtualCameraSettings;
+
+pub struct VirtualCameraDevice {
+    /// Kept open so the format set on it stays in place.
+    _device: Device,
+    file: File,
+    width: u32,
+    height: u32,
+    /// The time between frames at the configured frame rate. The device doesn't pace writes itself.
+    frame_interval: Duration,
+    next_frame: Instant,
+    i420: Mat
+}
+
+impl VirtualCameraDevice {
+    pub fn open(settings: &VirtualCameraSettings, width: u32, height: u32) -> Result<Self, Box<dyn Error>> {
+        let device = Device::with_path(&settings.device)
+            .map_err(|err| format!("{}: {}. Is the v4l2loopback module loaded?", settings.device, err))?;
+        let format = Output::set_format(&device, &Format::new(width, height, FourCC::new(b"YU12")))?;
+        if format.width != width || format.height != height || format.fourcc != FourCC::new(b"YU12") {
+            return Err(format!("{} doesn't accept {}x{} I420 frames, and offered {}x{} {}", settings.device, width, height, format.width, format.height, format.fourcc).into());
+        }
+        let fps = settings.fps.round().max(1.0) as u32;
+        Output::set_params(&device, &Parameters::with_fps(fps))?;
+
+        let file = OpenOptions::new().write(true).open(&settings.device)?;
+        Ok(Self {
+            _device: device,
+            file,
+            width,
+            height,
+            frame_interval: Duration::from_secs(1) / fps,
+            next_frame: Instant::now(),
+            i420: Mat::default()
+        })
+    }
+
+    /// Skips frames that arrive before the next one is due, so frames are written at the camera's frame rate.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+        let now = Instant::now();
+        if now < self.next_frame {
+            return Ok(());
+        }
+        // Keep to the frame rate's schedule, but don't try to catch up after falling behind
+        self.next_frame = (self.next_frame + self.frame_interval).max(now);
+
+        if recording::convert_readback_frame(frame, self.width, self.height, imgproc::COLOR_RGBA2YUV_I420, &mut self.i420)? {
+            self.file.write_all(self.i420.data_bytes()?)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Offline voice commands, since both hands are on the keys while practicing. Phrases like "slower", "loop this bar",
+//! and "from the top" are recognized with a small local Vosk model listening only for the phrases in `PHRASES`, and
+//! sent as the same `AppCommand`s as the hotkeys.
+//!
+//! Voice commands need the `voice` feature, which links against libvosk, and a model such as `vosk-model-small-en-us`
+//! from https://alphacephei.com/vosk/models unpacked at the configured path. Enable them with `[voice] enabled = true`.
+
+#[cfg(feature = "voice")]
+mod listener;
+#[cfg(feature = "voice")]
+use listener::start_listening;
+
+use bevy::{app::{App, Plugin, Update}, ecs::{change_detection::DetectChanges, event::EventWriter, resource::Resource, schedule::IntoScheduleConfigs, system::{Commands, Res, ResMut}, world::World}};
+use crossbeam_channel::Receiver;
+
+use crate::{command::{self, AppCommand}, status::{AppError, ErrorSource}};
+
+/// The phrases listened for, and the commands they give.
+static PHRASES: [(&str, AppCommand); 15] = [
+    ("play", AppCommand::Play),
+    ("pause", AppCommand::Pause),
+    ("stop", AppCommand::Pause),
+    ("from the top", AppCommand::Restart),
+    ("start over", AppCommand::Restart),
+    ("slower", AppCommand::Slower),
+    ("faster", AppCommand::Faster),
+    ("loop this bar", AppCommand::LoopMeasure),
+    ("loop this measure", AppCommand::LoopMeasure),
+    ("loop from here", AppCommand::SetLoopStart),
+    ("loop to here", AppCommand::SetLoopEnd),
+    ("stop looping", AppCommand::ClearLoop),
+    ("no loop", AppCommand::ClearLoop),
+    ("next song", AppCommand::NextSong),
+    ("next theme", AppCommand::NextTheme)
+];
+
+/// The command for a recognized phrase, if it's one of `PHRASES`.
+fn parse_command(text: &str) -> Option<AppCommand> {
+    let text = text.trim().to_lowercase();
+    PHRASES.iter().find(|(phrase, _)| *phrase == text).map(|(_, command)| *command)
+}
+
+#[derive(Resource, Clone, PartialEq)]
+pub struct VoiceCommandSettings {
+    pub enabled: bool,
+    /// The directory of the Vosk model.
+    pub model: String
+}
+
+impl Default for VoiceCommandSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            model: "assets/vosk-model-small-en-us".to_string()
+        }
+    }
+}
+
+/// The microphone stream feeding the recognizer. A non-send resource, since cpal streams can't move between threads.
+struct VoiceInput {
+    _stream: cpal::Stream
+}
+
+/// Phrases recognized on the audio thread, while listening.
+#[derive(Resource, Default)]
+struct RecognizedPhrases(Option<Receiver<String>>);
+
+#[cfg(not(feature = "voice"))]
+fn start_listening(_model: &str, _phrases: crossbeam_channel::Sender<String>) -> Result<cpal::Stream, Box<dyn std::error::Error>> {
+    Err("this build doesn't include voice commands. Rebuild with --features voice".into())
+}
+
+/// Starts or stops listening when the settings change.
+fn update_voice_input(
+    mut commands: Commands,
+    settings: Res<VoiceCommandSettings>,
+    mut phrases: ResMut<RecognizedPhrases>
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    phrases.0 = None;
+    let model = settings.model.clone();
+    let enabled = settings.enabled;
+    commands.queue(move |world: &mut World| {
+        world.remove_non_send_resource::<VoiceInput>();
+        if !enabled {
+            return;
+        }
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        match start_listening(&model, sender) {
+            Ok(stream) => {
+                world.insert_non_send_resource(VoiceInput { _stream: stream });
+                world.resource_mut::<RecognizedPhrases>().0 = Some(receiver);
+            }
+            Err(err) => {
+                world.send_event(AppError::new(ErrorSource::Audio, format!("Failed to start voice commands: {}", err)));
+            }
+        }
+    });
+}
+
+fn send_voice_commands(
+    phrases: Res<RecognizedPhrases>,
+    mut commands: EventWriter<AppCommand>
+) {
+    let Some(receiver) = &phrases.0 else { return };
+    for phrase in receiver.try_iter() {
+        // The recognizer hears "[unk]" for anything that isn't a command
+        let Some(command) = parse_command(&phrase) else { continue };
+        println!("Voice command \"{}\": {:?}", phrase, command);
+        commands.write(command);
+    }
+}
+
+pub struct VoiceCommandPlugin;
+
+impl Plugin for VoiceCommandPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<VoiceCommandSettings>()
+            .init_resource::<RecognizedPhrases>()
+            .add_systems(Update, (update_voice_input, send_voice_commands).chain().before(command::run_app_commands));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognized_phrases_map_to_commands() {
+        assert_eq!(parse_command("from the top"), Some(AppCommand::Restart));
+        assert_eq!(parse_command(" Loop this bar "), Some(AppCommand::LoopMeasure));
+        assert_eq!(parse_command("[unk]"), None);
+        assert_eq!(parse_command(""), None);
+    }
+}
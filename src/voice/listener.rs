@@ -0,0 +1,59 @@
+//! Listens to the default microphone and recognizes the command phrases with Vosk, on the audio thread.
+
+use std::error::Error;
+
+use cpal::{traits::{DeviceTrait, HostTrait, StreamTrait}, FromSample, SampleFormat, SizedSample};
+use crossbeam_channel::Sender;
+use vosk::{DecodingState, Model, Recognizer};
+
+use super::PHRASES;
+
+/// Matches anything that isn't one of the phrases, so noise isn't forced into the nearest command.
+static UNKNOWN_PHRASE: &str = "[unk]";
+
+fn build_input_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, mut recognizer: Recognizer, phrases: Sender<String>) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    i16: FromSample<T>
+{
+    let channels = config.channels.max(1) as usize;
+    let mut mono = Vec::new();
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            // The recognizer takes mono 16-bit samples, and one channel is plenty for speech
+            mono.clear();
+            mono.extend(data.chunks(channels).map(|frame| i16::from_sample(frame[0])));
+            if let Ok(DecodingState::Finalized) = recognizer.accept_waveform(&mono) {
+                if let Some(result) = recognizer.result().single() {
+                    let _ = phrases.send(result.text.to_string());
+                }
+            }
+        },
+        |err| eprintln!("Microphone error: {}", err),
+        None
+    )
+}
+
+/// Loads the model and starts listening on the default microphone, sending each phrase heard.
+pub fn start_listening(model_path: &str, phrases: Sender<String>) -> Result<cpal::Stream, Box<dyn Error>> {
+    let model = Model::new(model_path).ok_or_else(|| format!("Failed to load the speech model {}", model_path))?;
+
+    let device = cpal::default_host().default_input_device().ok_or("No microphone found")?;
+    let supported_config = device.default_input_config()?;
+    let config = supported_config.config();
+    // Listening only for the command phrases is much faster and more accurate than open dictation
+    let grammar: Vec<&str> = PHRASES.iter().map(|(phrase, _)| *phrase).chain([UNKNOWN_PHRASE]).collect();
+    let recognizer = Recognizer::new_with_grammar(&model, config.sample_rate.0 as f32, &grammar).ok_or("Failed to create the speech recognizer")?;
+
+    let stream = match supported_config.sample_format() {
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &config, recognizer, phrases)?,
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &config, recognizer, phrases)?,
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &config, recognizer, phrases)?,
+        format => return Err(format!("Unsupported microphone format {}", format).into())
+    };
+    stream.play()?;
+
+    println!("Listening for voice commands on {}", device.name().unwrap_or_default());
+    Ok(stream)
+}